@@ -0,0 +1,160 @@
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+
+use rspc::internal::jsonrpc::{
+	handle_json_rpc, Request, RequestId, Response, Sender, SubscriptionMap,
+};
+use sd_core::{api::Router, Node, NodeError};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+	sync::{mpsc::unbounded_channel, oneshot, Mutex},
+	time::sleep,
+};
+
+/// How often the jobs are checked on while waiting for them
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub enum CliError {
+	#[error("Failed to start the node: {0}")]
+	Node(#[from] NodeError),
+	#[error("'{path}' failed: {message}")]
+	Procedure { path: String, message: String },
+	#[error("'{0}' returned something that isn't a response")]
+	InvalidResponse(String),
+	#[error("There's no library yet, create one in the app first")]
+	NoLibrary,
+	#[error("There's more than one library, pick one with --library")]
+	AmbiguousLibrary,
+	#[error("There's no library named or with the id '{0}'")]
+	UnknownLibrary(String),
+	#[error("Failed to find '{0}' on disk: {1}")]
+	Path(String, std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+	Query,
+	Mutation,
+}
+
+/// A node running in the process, driven through the same procedures as the apps
+pub struct Client {
+	node: Arc<Node>,
+	router: Arc<Router>,
+	subscriptions: Mutex<HashMap<RequestId, oneshot::Sender<()>>>,
+}
+
+impl Client {
+	pub async fn new(data_dir: impl AsRef<Path>) -> Result<Self, CliError> {
+		let (node, router) = Node::new(data_dir).await?;
+
+		Ok(Self {
+			node,
+			router,
+			subscriptions: Mutex::new(HashMap::new()),
+		})
+	}
+
+	/// exec runs a procedure with the given input, returning what it returned
+	pub async fn exec(&self, kind: Kind, path: &str, input: Value) -> Result<Value, CliError> {
+		let request = serde_json::from_value::<Request>(json!({
+			"jsonrpc": "2.0",
+			"id": 0,
+			"method": match kind {
+				Kind::Query => "query",
+				Kind::Mutation => "mutation",
+			},
+			"params": { "path": path, "input": input },
+		}))
+		.map_err(|_| CliError::InvalidResponse(path.to_string()))?;
+
+		let (mut channel, _events) = unbounded_channel::<Response>();
+		let mut sender = Sender::ResponseAndChannel(None, &mut channel);
+		handle_json_rpc(
+			self.node.get_request_context(),
+			request,
+			&self.router,
+			&mut sender,
+			&mut SubscriptionMap::Mutex(&self.subscriptions),
+		)
+		.await;
+
+		let response = match sender {
+			Sender::ResponseAndChannel(Some(response), _) => serde_json::to_value(response)
+				.map_err(|_| CliError::InvalidResponse(path.to_string()))?,
+			_ => return Err(CliError::InvalidResponse(path.to_string())),
+		};
+
+		let result = &response["result"];
+		match result["type"].as_str() {
+			Some("response") => Ok(result["data"].clone()),
+			Some("error") => Err(CliError::Procedure {
+				path: path.to_string(),
+				message: result["data"]["message"]
+					.as_str()
+					.unwrap_or("unknown error")
+					.to_string(),
+			}),
+			_ => Err(CliError::InvalidResponse(path.to_string())),
+		}
+	}
+
+	/// library_exec runs a procedure of a library
+	pub async fn library_exec(
+		&self,
+		kind: Kind,
+		library_id: &str,
+		path: &str,
+		arg: Value,
+	) -> Result<Value, CliError> {
+		self.exec(kind, path, json!({ "library_id": library_id, "arg": arg }))
+			.await
+	}
+
+	/// library_id finds the id of the library named or with the id given, or of the only library
+	pub async fn library_id(&self, library: Option<&str>) -> Result<String, CliError> {
+		let libraries = self.exec(Kind::Query, "library.list", Value::Null).await?;
+		let libraries = libraries.as_array().cloned().unwrap_or_default();
+
+		let found = match library {
+			Some(library) => libraries
+				.iter()
+				.find(|l| l["uuid"] == library || l["config"]["name"] == library)
+				.ok_or_else(|| CliError::UnknownLibrary(library.to_string()))?,
+			None => match libraries.as_slice() {
+				[] => return Err(CliError::NoLibrary),
+				[library] => library,
+				_ => return Err(CliError::AmbiguousLibrary),
+			},
+		};
+
+		Ok(found["uuid"].as_str().unwrap_or_default().to_string())
+	}
+
+	/// wait_for_jobs returns once the library has no job running or queued. Jobs are started one
+	/// after the other, so it's only idle when it was on two checks in a row.
+	pub async fn wait_for_jobs(&self, library_id: &str) -> Result<(), CliError> {
+		let mut idle_checks = 0;
+		while idle_checks < 2 {
+			sleep(JOB_POLL_INTERVAL).await;
+
+			let running = self
+				.library_exec(Kind::Query, library_id, "jobs.isRunning", Value::Null)
+				.await?;
+			let queue = self
+				.library_exec(Kind::Query, library_id, "jobs.getQueue", Value::Null)
+				.await?;
+
+			let is_idle =
+				!running.as_bool().unwrap_or(true) && queue.as_array().map_or(true, Vec::is_empty);
+			idle_checks = if is_idle { idle_checks + 1 } else { 0 };
+		}
+
+		Ok(())
+	}
+
+	pub async fn shutdown(&self) {
+		self.node.shutdown().await;
+	}
+}
@@ -0,0 +1,307 @@
+use std::{fs, path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+
+use client::{CliError, Client, Kind};
+
+mod client;
+
+/// Drives a Spacedrive node from the command line, for scripts and headless servers
+#[derive(Parser, Debug)]
+#[clap(name = "sd", version)]
+struct Cli {
+	/// the node's data directory, the desktop app's by default
+	#[clap(long, env = "DATA_DIR", global = true)]
+	data_dir: Option<PathBuf>,
+	/// the library to work in, by name or id, needed when there's more than one
+	#[clap(long, short, global = true)]
+	library: Option<String>,
+	/// print the results as JSON, the way the procedures return them
+	#[clap(long, global = true)]
+	json: bool,
+	#[clap(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	#[clap(subcommand)]
+	Library(LibraryCommand),
+	#[clap(subcommand)]
+	Location(LocationCommand),
+	/// index a location again, waiting for it to be done
+	Index {
+		location_id: i32,
+		/// return once the indexing started
+		#[clap(long)]
+		no_wait: bool,
+	},
+	/// find the files whose name contains the given one
+	Search {
+		name: String,
+		#[clap(long)]
+		location: Option<i32>,
+		#[clap(long, default_value_t = 100)]
+		limit: i32,
+	},
+	#[clap(subcommand)]
+	Jobs(JobsCommand),
+	/// list the files with more than one copy, the largest first
+	Duplicates {
+		#[clap(long)]
+		location: Option<i32>,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum LibraryCommand {
+	List,
+}
+
+#[derive(Subcommand, Debug)]
+enum LocationCommand {
+	/// add a directory as a location and index it, waiting for it to be done
+	Add {
+		path: PathBuf,
+		/// index it once to be kept offline
+		#[clap(long)]
+		catalog: bool,
+		/// return once the indexing started
+		#[clap(long)]
+		no_wait: bool,
+	},
+	List,
+}
+
+#[derive(Subcommand, Debug)]
+enum JobsCommand {
+	/// the jobs running and queued, and the ones that ran before
+	List,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+	let cli = Cli::parse();
+
+	let data_dir = cli.data_dir.clone().unwrap_or_else(|| {
+		dirs_next::data_dir()
+			.unwrap_or_else(|| PathBuf::from("./"))
+			.join("spacedrive")
+	});
+
+	let client = match Client::new(data_dir).await {
+		Ok(client) => client,
+		Err(e) => {
+			eprintln!("{e}");
+			return ExitCode::FAILURE;
+		}
+	};
+
+	let result = run(&client, &cli).await;
+	client.shutdown().await;
+
+	match result {
+		Ok(output) => {
+			if cli.json {
+				println!(
+					"{}",
+					serde_json::to_string_pretty(&output).unwrap_or_default()
+				);
+			}
+			ExitCode::SUCCESS
+		}
+		Err(e) => {
+			eprintln!("{e}");
+			ExitCode::FAILURE
+		}
+	}
+}
+
+/// run runs the command, printing its results unless they're to be printed as JSON, which is
+/// returned for that
+async fn run(client: &Client, cli: &Cli) -> Result<Value, CliError> {
+	if let Command::Library(LibraryCommand::List) = cli.command {
+		let libraries = client
+			.exec(Kind::Query, "library.list", Value::Null)
+			.await?;
+		if !cli.json {
+			for library in as_slice(&libraries) {
+				println!(
+					"{}\t{}",
+					text(&library["uuid"]),
+					text(&library["config"]["name"])
+				);
+			}
+		}
+		return Ok(libraries);
+	}
+
+	let library_id = client.library_id(cli.library.as_deref()).await?;
+
+	match &cli.command {
+		Command::Library(_) => unreachable!(),
+		Command::Location(LocationCommand::Add {
+			path,
+			catalog,
+			no_wait,
+		}) => {
+			let path = fs::canonicalize(path)
+				.map_err(|e| CliError::Path(path.display().to_string(), e))?;
+
+			client
+				.library_exec(
+					Kind::Mutation,
+					&library_id,
+					"locations.create",
+					json!({ "path": path, "indexer_rules_ids": [], "catalog": catalog }),
+				)
+				.await?;
+			if !no_wait {
+				client.wait_for_jobs(&library_id).await?;
+			}
+
+			let locations = client
+				.library_exec(Kind::Query, &library_id, "locations.list", Value::Null)
+				.await?;
+			let location = as_slice(&locations)
+				.iter()
+				.find(|location| location["local_path"] == path.to_string_lossy().as_ref())
+				.cloned()
+				.unwrap_or_default();
+
+			if !cli.json {
+				println!("Added location {} at {}", location["id"], path.display());
+			}
+			Ok(location)
+		}
+		Command::Location(LocationCommand::List) => {
+			let locations = client
+				.library_exec(Kind::Query, &library_id, "locations.list", Value::Null)
+				.await?;
+			if !cli.json {
+				for location in as_slice(&locations) {
+					println!(
+						"{}\t{}\t{}",
+						location["id"],
+						text(&location["name"]),
+						text(&location["local_path"])
+					);
+				}
+			}
+			Ok(locations)
+		}
+		Command::Index {
+			location_id,
+			no_wait,
+		} => {
+			client
+				.library_exec(
+					Kind::Mutation,
+					&library_id,
+					"locations.fullRescan",
+					json!(location_id),
+				)
+				.await?;
+			if !no_wait {
+				client.wait_for_jobs(&library_id).await?;
+			}
+
+			if !cli.json {
+				println!("Indexed location {location_id}");
+			}
+			Ok(Value::Null)
+		}
+		Command::Search {
+			name,
+			location,
+			limit,
+		} => {
+			let file_paths = client
+				.library_exec(
+					Kind::Query,
+					&library_id,
+					"files.search",
+					json!({ "name": name, "location_id": location, "take": limit }),
+				)
+				.await?;
+			if !cli.json {
+				for file_path in as_slice(&file_paths) {
+					println!("{}", full_path(file_path));
+				}
+			}
+			Ok(file_paths)
+		}
+		Command::Jobs(JobsCommand::List) => {
+			let mut jobs = vec![];
+			for path in ["jobs.getRunning", "jobs.getQueue", "jobs.getHistory"] {
+				let found = client
+					.library_exec(Kind::Query, &library_id, path, Value::Null)
+					.await?;
+				jobs.extend(as_slice(&found).iter().cloned());
+			}
+
+			if !cli.json {
+				for job in &jobs {
+					println!(
+						"{}\t{}\t{}\t{}/{}",
+						job["id"].as_str().unwrap_or_default(),
+						text(&job["name"]),
+						text(&job["status"]),
+						job["completed_task_count"],
+						job["task_count"]
+					);
+				}
+			}
+			Ok(Value::Array(jobs))
+		}
+		Command::Duplicates { location } => {
+			let objects = client
+				.library_exec(
+					Kind::Query,
+					&library_id,
+					"files.getDuplicates",
+					json!(location),
+				)
+				.await?;
+			if !cli.json {
+				for object in as_slice(&objects) {
+					let file_paths = as_slice(&object["file_paths"]);
+					println!(
+						"{} bytes, {} copies",
+						text(&object["size_in_bytes"]),
+						file_paths.len()
+					);
+					for file_path in file_paths {
+						println!(
+							"\tlocation {}: {}",
+							file_path["location_id"],
+							full_path(file_path)
+						);
+					}
+				}
+			}
+			Ok(objects)
+		}
+	}
+}
+
+fn as_slice(value: &Value) -> &[Value] {
+	value.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+fn text(value: &Value) -> &str {
+	value.as_str().unwrap_or_default()
+}
+
+/// full_path returns where a file path is, on disk when its location was fetched with it
+fn full_path(file_path: &Value) -> String {
+	let materialized_path = text(&file_path["materialized_path"]);
+	match file_path["location"]["local_path"].as_str() {
+		Some(local_path) => PathBuf::from(local_path)
+			.join(materialized_path)
+			.display()
+			.to_string(),
+		None => materialized_path.to_string(),
+	}
+}
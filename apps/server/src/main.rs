@@ -3,11 +3,12 @@ use std::{env, net::SocketAddr, path::Path};
 use axum::{
 	extract,
 	handler::Handler,
-	http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+	http::{header::CONTENT_TYPE, HeaderMap, Request, StatusCode},
+	middleware::{self, Next},
 	routing::get,
 };
 use sd_core::Node;
-use tracing::info;
+use tracing::{error, info};
 
 mod utils;
 
@@ -36,9 +37,17 @@ async fn main() {
 	let (node, router) = Node::new(data_dir).await.expect("Unable to create node");
 	let signal = utils::axum_shutdown_signal(node.clone());
 
+	// Clients need a token to connect, the first one is made on the first start and only shown then
+	match node.ensure_api_token("Server").await {
+		Ok(Some(token)) => info!(
+			"Created the API token clients connect with, it won't be shown again: {}",
+			token
+		),
+		Ok(None) => {}
+		Err(e) => error!("Failed to create an API token: {:#?}", e),
+	}
+
 	let app = axum::Router::new()
-		.route("/", get(|| async { "Spacedrive Server!" }))
-		.route("/health", get(|| async { "OK" }))
 		.route("/spacedrive/:id", {
 			let node = node.clone();
 			get(|extract::Path(path): extract::Path<String>| async move {
@@ -56,10 +65,24 @@ async fn main() {
 				)
 			})
 		})
-		.route(
-			"/rspc/:id",
-			router.endpoint(move || node.get_request_context()).axum(),
-		)
+		.route("/rspc/:id", {
+			let node = node.clone();
+			router.endpoint(move || node.get_request_context()).axum()
+		})
+		// the routes above reach the node's data, the ones below are left open for health checks
+		.route_layer(middleware::from_fn(
+			move |req: Request<_>, next: Next<_>| {
+				let node = node.clone();
+				async move {
+					match utils::request_token(&req) {
+						Some(token) if node.authorize(token).await => Ok(next.run(req).await),
+						_ => Err(StatusCode::UNAUTHORIZED),
+					}
+				}
+			},
+		))
+		.route("/", get(|| async { "Spacedrive Server!" }))
+		.route("/health", get(|| async { "OK" }))
 		.fallback((|| async { "404 Not Found: We're past the event horizon..." }).into_service());
 
 	let mut addr = "[::]:8080".parse::<SocketAddr>().unwrap(); // This listens on IPv6 and IPv4
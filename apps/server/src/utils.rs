@@ -1,5 +1,6 @@
 use std::sync::Arc;
 
+use axum::http::{header::AUTHORIZATION, Request};
 use sd_core::Node;
 use tokio::signal;
 
@@ -30,3 +31,19 @@ pub async fn axum_shutdown_signal(node: Arc<Node>) {
 	println!("signal received, starting graceful shutdown");
 	node.shutdown().await;
 }
+
+/// request_token returns the API token a request was made with. It's sent as a bearer token, or as
+/// a `token` query parameter by clients that can't set headers, like browsers opening websockets.
+pub fn request_token<B>(req: &Request<B>) -> Option<&str> {
+	if let Some(header) = req.headers().get(AUTHORIZATION) {
+		return header.to_str().ok()?.strip_prefix("Bearer ");
+	}
+
+	req.uri()
+		.query()?
+		.split('&')
+		.find_map(|pair| match pair.split_once('=') {
+			Some(("token", token)) => Some(token),
+			_ => None,
+		})
+}
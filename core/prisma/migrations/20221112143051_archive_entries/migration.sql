@@ -0,0 +1,16 @@
+-- CreateTable
+CREATE TABLE "archive_entry" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "file_path_id" INTEGER NOT NULL,
+    "path" TEXT NOT NULL,
+    "name" TEXT NOT NULL,
+    "extension" TEXT,
+    "is_dir" BOOLEAN NOT NULL DEFAULT false,
+    "size_in_bytes" TEXT NOT NULL,
+    "date_modified" DATETIME,
+    CONSTRAINT "archive_entry_location_id_file_path_id_fkey" FOREIGN KEY ("location_id", "file_path_id") REFERENCES "file_path" ("location_id", "id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "archive_entry_location_id_file_path_id_path_key" ON "archive_entry"("location_id", "file_path_id", "path");
@@ -0,0 +1,18 @@
+-- CreateTable
+CREATE TABLE "trash_item" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" BLOB NOT NULL,
+    "location_id" INTEGER,
+    "materialized_path" TEXT NOT NULL,
+    "original_path" TEXT NOT NULL,
+    "name" TEXT NOT NULL,
+    "is_dir" BOOLEAN NOT NULL DEFAULT false,
+    "size_in_bytes" TEXT NOT NULL,
+    "object_id" INTEGER,
+    "node_id" INTEGER NOT NULL,
+    "date_deleted" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "trash_item_node_id_fkey" FOREIGN KEY ("node_id") REFERENCES "node" ("id") ON DELETE RESTRICT ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "trash_item_pub_id_key" ON "trash_item"("pub_id");
@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "volume_capacity" TEXT;
+ALTER TABLE "location" ADD COLUMN "volume_serial" TEXT;
@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "network_key" TEXT;
+ALTER TABLE "location" ADD COLUMN "network_protocol" INTEGER;
+ALTER TABLE "location" ADD COLUMN "network_remote" TEXT;
+ALTER TABLE "location" ADD COLUMN "network_username" TEXT;
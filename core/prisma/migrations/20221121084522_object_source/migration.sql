@@ -0,0 +1,15 @@
+-- CreateTable
+CREATE TABLE "object_source" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "object_id" INTEGER NOT NULL,
+    "url" TEXT NOT NULL,
+    "kind" INTEGER NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "object_source_object_id_fkey" FOREIGN KEY ("object_id") REFERENCES "object" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "object_source_url_idx" ON "object_source"("url");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "object_source_object_id_url_key" ON "object_source"("object_id", "url");
@@ -0,0 +1,28 @@
+-- RedefineTables
+PRAGMA foreign_keys=OFF;
+DROP TABLE "sync_event";
+CREATE TABLE "sync_event" (
+    "id" BLOB NOT NULL PRIMARY KEY,
+    "node_id" INTEGER NOT NULL,
+    "timestamp" BIGINT NOT NULL,
+    "model" TEXT NOT NULL,
+    "record_id" BLOB NOT NULL,
+    "kind" INTEGER NOT NULL,
+    "column" TEXT,
+    "data" TEXT NOT NULL,
+    CONSTRAINT "sync_event_node_id_fkey" FOREIGN KEY ("node_id") REFERENCES "node" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
+
+-- CreateIndex
+CREATE INDEX "sync_event_model_record_id_idx" ON "sync_event"("model", "record_id");
+
+-- CreateIndex
+CREATE INDEX "sync_event_node_id_timestamp_idx" ON "sync_event"("node_id", "timestamp");
+
+-- AlterTable
+ALTER TABLE "object" ADD COLUMN "pub_id" BLOB;
+
+-- CreateIndex
+CREATE UNIQUE INDEX "object_pub_id_key" ON "object"("pub_id");
@@ -0,0 +1,11 @@
+-- CreateTable
+CREATE TABLE "location_share" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "peer_id" TEXT NOT NULL,
+    "date_shared" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "location_share_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "location_share_location_id_peer_id_key" ON "location_share"("location_id", "peer_id");
@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "media_data" ADD COLUMN "dominant_colors" TEXT;
+ALTER TABLE "media_data" ADD COLUMN "detected_text" TEXT;
@@ -0,0 +1,14 @@
+-- CreateTable
+CREATE TABLE "job_error" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "job_id" BLOB NOT NULL,
+    "location_id" INTEGER,
+    "file_path_id" INTEGER,
+    "path" TEXT NOT NULL,
+    "message" TEXT NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "job_error_job_id_fkey" FOREIGN KEY ("job_id") REFERENCES "job" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "job_error_job_id_idx" ON "job_error"("job_id");
@@ -0,0 +1,17 @@
+-- CreateTable
+CREATE TABLE "directory_size" (
+    "location_id" INTEGER NOT NULL,
+    "materialized_path" TEXT NOT NULL,
+    "parent_path" TEXT,
+    "name" TEXT NOT NULL,
+    "bytes" TEXT NOT NULL,
+    "files" INTEGER NOT NULL,
+    "directories" INTEGER NOT NULL,
+    "date_computed" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY ("location_id", "materialized_path"),
+    CONSTRAINT "directory_size_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "directory_size_location_id_parent_path_idx" ON "directory_size"("location_id", "parent_path");
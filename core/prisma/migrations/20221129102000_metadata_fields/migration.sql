@@ -0,0 +1,31 @@
+-- CreateTable
+CREATE TABLE "metadata_field" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "name" TEXT NOT NULL,
+    "kind" INTEGER NOT NULL,
+    "required" BOOLEAN NOT NULL DEFAULT false,
+    "choices" TEXT,
+    "position" INTEGER NOT NULL DEFAULT 0,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "metadata_field_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "metadata_value" (
+    "field_id" INTEGER NOT NULL,
+    "location_id" INTEGER NOT NULL,
+    "file_path_id" INTEGER NOT NULL,
+    "value" TEXT NOT NULL,
+    "date_modified" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY ("field_id", "file_path_id"),
+    CONSTRAINT "metadata_value_field_id_fkey" FOREIGN KEY ("field_id") REFERENCES "metadata_field" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "metadata_value_location_id_file_path_id_fkey" FOREIGN KEY ("location_id", "file_path_id") REFERENCES "file_path" ("location_id", "id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "metadata_field_location_id_name_key" ON "metadata_field"("location_id", "name");
+
+-- CreateIndex
+CREATE INDEX "metadata_value_location_id_file_path_id_idx" ON "metadata_value"("location_id", "file_path_id");
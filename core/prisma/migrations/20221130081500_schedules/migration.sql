@@ -0,0 +1,17 @@
+-- CreateTable
+CREATE TABLE "schedule" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "name" TEXT NOT NULL,
+    "action" INTEGER NOT NULL,
+    "location_id" INTEGER NOT NULL,
+    "recurrence" TEXT NOT NULL,
+    "enabled" BOOLEAN NOT NULL DEFAULT true,
+    "next_run" DATETIME NOT NULL,
+    "last_run" DATETIME,
+    "last_skipped" TEXT,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "schedule_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "schedule_next_run_idx" ON "schedule"("next_run");
@@ -0,0 +1,11 @@
+-- CreateTable
+CREATE TABLE "video_chapter" (
+    "object_id" INTEGER NOT NULL,
+    "position" INTEGER NOT NULL,
+    "timestamp_ms" INTEGER NOT NULL,
+    "scene_change" BOOLEAN NOT NULL DEFAULT false,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY ("object_id", "position"),
+    CONSTRAINT "video_chapter_object_id_fkey" FOREIGN KEY ("object_id") REFERENCES "object" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
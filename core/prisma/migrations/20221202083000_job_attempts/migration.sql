@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "job" ADD COLUMN "attempts" INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,16 @@
+-- CreateTable
+CREATE TABLE "db_health_sample" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "date_captured" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "kind" INTEGER NOT NULL,
+    "name" TEXT NOT NULL,
+    "table_name" TEXT NOT NULL,
+    "rows" INTEGER,
+    "rows_per_key" INTEGER,
+    "pages" INTEGER,
+    "bytes" TEXT,
+    "free_pages" INTEGER
+);
+
+-- CreateIndex
+CREATE INDEX "db_health_sample_date_captured_idx" ON "db_health_sample"("date_captured");
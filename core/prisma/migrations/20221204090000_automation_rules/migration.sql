@@ -0,0 +1,32 @@
+-- CreateTable
+CREATE TABLE "automation_rule" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "name" TEXT NOT NULL,
+    "location_id" INTEGER NOT NULL,
+    "glob" TEXT NOT NULL,
+    "actions" TEXT NOT NULL,
+    "enabled" BOOLEAN NOT NULL DEFAULT true,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "automation_rule_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "automation_run" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "rule_id" INTEGER NOT NULL,
+    "file_path_id" INTEGER NOT NULL,
+    "path" TEXT NOT NULL,
+    "action" TEXT NOT NULL,
+    "error" TEXT,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "automation_run_rule_id_fkey" FOREIGN KEY ("rule_id") REFERENCES "automation_rule" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "automation_rule_location_id_idx" ON "automation_rule"("location_id");
+
+-- CreateIndex
+CREATE INDEX "automation_run_rule_id_idx" ON "automation_run"("rule_id");
+
+-- CreateIndex
+CREATE INDEX "automation_run_date_created_idx" ON "automation_run"("date_created");
@@ -0,0 +1,18 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "keep_version_copies" BOOLEAN NOT NULL DEFAULT false;
+
+-- CreateTable
+CREATE TABLE "file_version" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "file_path_id" INTEGER NOT NULL,
+    "cas_id" TEXT NOT NULL,
+    "size_in_bytes" TEXT NOT NULL,
+    "date_modified" DATETIME NOT NULL,
+    "has_copy" BOOLEAN NOT NULL DEFAULT false,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "file_version_location_id_file_path_id_fkey" FOREIGN KEY ("location_id", "file_path_id") REFERENCES "file_path" ("location_id", "id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "file_version_location_id_file_path_id_idx" ON "file_version"("location_id", "file_path_id");
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "paired_peer" ADD COLUMN "trust" INTEGER NOT NULL DEFAULT 0;
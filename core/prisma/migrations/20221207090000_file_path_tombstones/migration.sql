@@ -0,0 +1,18 @@
+-- CreateTable
+CREATE TABLE "file_path_tombstone" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "file_path_id" INTEGER NOT NULL,
+    "materialized_path" TEXT NOT NULL,
+    "name" TEXT NOT NULL,
+    "extension" TEXT,
+    "is_dir" BOOLEAN NOT NULL,
+    "cas_id" TEXT,
+    "size_in_bytes" TEXT,
+    "date_modified" DATETIME NOT NULL,
+    "date_deleted" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "file_path_tombstone_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "file_path_tombstone_location_id_date_deleted_idx" ON "file_path_tombstone"("location_id", "date_deleted");
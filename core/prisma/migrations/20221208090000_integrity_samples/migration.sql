@@ -0,0 +1,13 @@
+-- CreateTable
+CREATE TABLE "integrity_sample" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "volume_serial" TEXT,
+    "location_id" INTEGER,
+    "sampled" INTEGER NOT NULL,
+    "corrupted" INTEGER NOT NULL,
+    "unreadable" INTEGER NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateIndex
+CREATE INDEX "integrity_sample_date_created_idx" ON "integrity_sample"("date_created");
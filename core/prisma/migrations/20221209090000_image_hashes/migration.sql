@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "media_data" ADD COLUMN "perceptual_hash" TEXT;
+ALTER TABLE "media_data" ADD COLUMN "difference_hash" TEXT;
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "tag" ADD COLUMN "is_system" BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,29 @@
+-- AlterTable
+ALTER TABLE "object" ADD COLUMN "faces_scanned" BOOLEAN NOT NULL DEFAULT false;
+
+-- CreateTable
+CREATE TABLE "face" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "object_id" INTEGER NOT NULL,
+    "person_id" INTEGER,
+    "x" REAL NOT NULL,
+    "y" REAL NOT NULL,
+    "width" REAL NOT NULL,
+    "height" REAL NOT NULL,
+    "confidence" REAL NOT NULL,
+    "embedding" BLOB NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "face_object_id_fkey" FOREIGN KEY ("object_id") REFERENCES "object" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "face_person_id_fkey" FOREIGN KEY ("person_id") REFERENCES "person" ("id") ON DELETE SET NULL ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "person" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "name" TEXT,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "date_modified" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateIndex
+CREATE INDEX "face_person_id_idx" ON "face"("person_id");
@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "media_data" ADD COLUMN "geohash" TEXT;
+
+-- CreateIndex
+CREATE INDEX "media_data_geohash_idx" ON "media_data"("geohash");
@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "is_catalog" BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE "location" ADD COLUMN "date_cataloged" DATETIME;
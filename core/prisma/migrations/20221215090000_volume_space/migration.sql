@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE "volume_space_sample" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "volume" TEXT NOT NULL,
+    "mount_point" TEXT NOT NULL,
+    "total_bytes" BIGINT NOT NULL,
+    "available_bytes" BIGINT NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateIndex
+CREATE INDEX "volume_space_sample_volume_date_created_idx" ON "volume_space_sample"("volume", "date_created");
@@ -0,0 +1,18 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "pipeline_stages" TEXT;
+
+-- CreateTable
+CREATE TABLE "location_pipeline" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "stages" TEXT NOT NULL,
+    "current_stage" INTEGER NOT NULL DEFAULT 0,
+    "job_id" BLOB,
+    "status" INTEGER NOT NULL DEFAULT 0,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "date_modified" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "location_pipeline_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "location_pipeline_job_id_idx" ON "location_pipeline"("job_id");
@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "owner_uid" INTEGER;
+ALTER TABLE "file_path" ADD COLUMN "owner_gid" INTEGER;
+ALTER TABLE "file_path" ADD COLUMN "permissions" INTEGER;
+ALTER TABLE "file_path" ADD COLUMN "xattrs" BLOB;
@@ -0,0 +1,21 @@
+-- RedefineTables
+PRAGMA foreign_keys=OFF;
+CREATE TABLE "new_schedule" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "name" TEXT NOT NULL,
+    "action" INTEGER NOT NULL,
+    "location_id" INTEGER,
+    "recurrence" TEXT NOT NULL,
+    "enabled" BOOLEAN NOT NULL DEFAULT true,
+    "next_run" DATETIME NOT NULL,
+    "last_run" DATETIME,
+    "last_skipped" TEXT,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "schedule_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+INSERT INTO "new_schedule" ("action", "date_created", "enabled", "id", "last_run", "last_skipped", "location_id", "name", "next_run", "recurrence") SELECT "action", "date_created", "enabled", "id", "last_run", "last_skipped", "location_id", "name", "next_run", "recurrence" FROM "schedule";
+DROP TABLE "schedule";
+ALTER TABLE "new_schedule" RENAME TO "schedule";
+CREATE INDEX "schedule_next_run_idx" ON "schedule"("next_run");
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
@@ -0,0 +1,6 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "generate_thumbnails" BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE "location" ADD COLUMN "hash_full_contents" BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE "location" ADD COLUMN "max_hash_size" BIGINT;
+ALTER TABLE "location" ADD COLUMN "index_hidden_files" BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE "location" ADD COLUMN "watch_changes" BOOLEAN NOT NULL DEFAULT true;
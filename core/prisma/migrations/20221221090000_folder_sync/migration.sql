@@ -0,0 +1,38 @@
+-- CreateTable
+CREATE TABLE "folder_sync" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "left_location_id" INTEGER NOT NULL,
+    "right_location_id" INTEGER NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "last_synced" DATETIME,
+    CONSTRAINT "folder_sync_left_location_id_fkey" FOREIGN KEY ("left_location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "folder_sync_right_location_id_fkey" FOREIGN KEY ("right_location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "folder_sync_entry" (
+    "folder_sync_id" INTEGER NOT NULL,
+    "path" TEXT NOT NULL,
+    "cas_id" TEXT NOT NULL,
+
+    PRIMARY KEY ("folder_sync_id", "path"),
+    CONSTRAINT "folder_sync_entry_folder_sync_id_fkey" FOREIGN KEY ("folder_sync_id") REFERENCES "folder_sync" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "folder_sync_conflict" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "folder_sync_id" INTEGER NOT NULL,
+    "path" TEXT NOT NULL,
+    "left_cas_id" TEXT,
+    "right_cas_id" TEXT,
+    "resolution" INTEGER,
+    "date_detected" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "folder_sync_conflict_folder_sync_id_fkey" FOREIGN KEY ("folder_sync_id") REFERENCES "folder_sync" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "folder_sync_left_location_id_right_location_id_key" ON "folder_sync"("left_location_id", "right_location_id");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "folder_sync_conflict_folder_sync_id_path_key" ON "folder_sync_conflict"("folder_sync_id", "path");
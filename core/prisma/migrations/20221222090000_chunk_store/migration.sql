@@ -0,0 +1,35 @@
+-- CreateTable
+CREATE TABLE "chunk" (
+    "hash" TEXT NOT NULL PRIMARY KEY,
+    "size" INTEGER NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateTable
+CREATE TABLE "file_manifest" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "file_path_id" INTEGER NOT NULL,
+    "path" TEXT NOT NULL,
+    "cas_id" TEXT,
+    "checksum" TEXT NOT NULL,
+    "size_in_bytes" BIGINT NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateTable
+CREATE TABLE "manifest_chunk" (
+    "manifest_id" INTEGER NOT NULL,
+    "position" INTEGER NOT NULL,
+    "chunk_hash" TEXT NOT NULL,
+
+    PRIMARY KEY ("manifest_id", "position"),
+    CONSTRAINT "manifest_chunk_manifest_id_fkey" FOREIGN KEY ("manifest_id") REFERENCES "file_manifest" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "manifest_chunk_chunk_hash_fkey" FOREIGN KEY ("chunk_hash") REFERENCES "chunk" ("hash") ON DELETE RESTRICT ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "file_manifest_location_id_file_path_id_idx" ON "file_manifest"("location_id", "file_path_id");
+
+-- CreateIndex
+CREATE INDEX "manifest_chunk_chunk_hash_idx" ON "manifest_chunk"("chunk_hash");
@@ -0,0 +1,43 @@
+-- CreateTable
+CREATE TABLE "backup_target" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "name" TEXT NOT NULL,
+    "storage_config" TEXT NOT NULL,
+    "repository_key" TEXT NOT NULL,
+    "prune_policy" TEXT,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "last_backup" DATETIME
+);
+
+-- CreateTable
+CREATE TABLE "backup_location" (
+    "backup_target_id" INTEGER NOT NULL,
+    "location_id" INTEGER NOT NULL,
+
+    PRIMARY KEY ("backup_target_id", "location_id"),
+    CONSTRAINT "backup_location_backup_target_id_fkey" FOREIGN KEY ("backup_target_id") REFERENCES "backup_target" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "backup_location_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "backup_snapshot" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "backup_target_id" INTEGER NOT NULL,
+    "pub_id" TEXT NOT NULL,
+    "files" INTEGER NOT NULL,
+    "size_in_bytes" BIGINT NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "backup_snapshot_backup_target_id_fkey" FOREIGN KEY ("backup_target_id") REFERENCES "backup_target" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "backup_chunk" (
+    "backup_target_id" INTEGER NOT NULL,
+    "chunk_hash" TEXT NOT NULL,
+
+    PRIMARY KEY ("backup_target_id", "chunk_hash"),
+    CONSTRAINT "backup_chunk_backup_target_id_fkey" FOREIGN KEY ("backup_target_id") REFERENCES "backup_target" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "backup_snapshot_backup_target_id_pub_id_key" ON "backup_snapshot"("backup_target_id", "pub_id");
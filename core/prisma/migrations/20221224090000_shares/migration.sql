@@ -0,0 +1,17 @@
+-- CreateTable
+CREATE TABLE "share" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" TEXT NOT NULL,
+    "name" TEXT NOT NULL,
+    "relay_url" TEXT NOT NULL,
+    "files" INTEGER NOT NULL,
+    "size_in_bytes" BIGINT NOT NULL,
+    "access_count" INTEGER NOT NULL DEFAULT 0,
+    "last_access" DATETIME,
+    "date_expires" DATETIME NOT NULL,
+    "date_revoked" DATETIME,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "share_pub_id_key" ON "share"("pub_id");
@@ -0,0 +1,19 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "hash_git_internals" BOOLEAN NOT NULL DEFAULT true;
+
+-- CreateTable
+CREATE TABLE "git_repository" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "materialized_path" TEXT NOT NULL,
+    "branch" TEXT,
+    "head_commit" TEXT,
+    "remote_url" TEXT,
+    "is_dirty" BOOLEAN,
+    "size_in_bytes" BIGINT NOT NULL,
+    "date_indexed" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "git_repository_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "git_repository_location_id_materialized_path_key" ON "git_repository"("location_id", "materialized_path");
@@ -0,0 +1,6 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "max_identify_size" BIGINT;
+ALTER TABLE "location" ADD COLUMN "unhashed_extensions" TEXT;
+
+-- AlterTable
+ALTER TABLE "object" ADD COLUMN "identified_by_path" BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,15 @@
+-- CreateTable
+CREATE TABLE "audit_entry" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "action" INTEGER NOT NULL,
+    "name" TEXT NOT NULL,
+    "parameters" TEXT,
+    "outcome" INTEGER NOT NULL,
+    "error" TEXT,
+    "job_id" BLOB,
+    "date_started" DATETIME,
+    "date_finished" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateIndex
+CREATE INDEX "audit_entry_date_finished_idx" ON "audit_entry"("date_finished");
@@ -0,0 +1,29 @@
+use rspc::Type;
+use serde::Deserialize;
+
+use crate::library::{audit_entries, export_audit, AuditExportFormat, AuditFilter};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.library_query("list", |t| {
+			t(|_, filter: AuditFilter, library| async move {
+				Ok(audit_entries(&library, filter).await?)
+			})
+		})
+		// the entries as a JSON or CSV document, for the client to save
+		.library_query("export", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct AuditExportArgs {
+				pub filter: AuditFilter,
+				pub format: AuditExportFormat,
+			}
+
+			t(|_, args: AuditExportArgs, library| async move {
+				let entries = audit_entries(&library, args.filter).await?;
+
+				Ok(export_audit(&entries, args.format))
+			})
+		})
+}
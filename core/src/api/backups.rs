@@ -0,0 +1,104 @@
+use rspc::Type;
+use serde::Deserialize;
+
+use crate::{
+	invalidate_query,
+	job::Job,
+	object::backup::{
+		set_locations, set_prune_policy, snapshots, BackupError, BackupJob, BackupJobInit,
+		BackupTargetCreateArgs, PrunePolicy, RestoreBackupJob, RestoreBackupJobInit,
+	},
+	prisma::backup_target,
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.library_query("listTargets", |t| {
+			t(|_, _: (), library| async move {
+				Ok(library
+					.db
+					.backup_target()
+					.find_many(vec![])
+					.with(backup_target::locations::fetch(vec![]))
+					.exec()
+					.await?)
+			})
+		})
+		// makes a repository on the storage, or connects to the one already there with its password
+		.library_mutation("createTarget", |t| {
+			t(
+				|_, args: BackupTargetCreateArgs, library| async move {
+					Ok(args.create(&library).await?)
+				},
+			)
+		})
+		// forgets the target, what was uploaded to it stays there to be connected to again
+		.library_mutation("deleteTarget", |t| {
+			t(|_, target_id: i32, library| async move {
+				library
+					.db
+					.backup_target()
+					.delete(backup_target::id::equals(target_id))
+					.exec()
+					.await?;
+
+				invalidate_query!(library, "backups.listTargets");
+
+				Ok(())
+			})
+		})
+		.library_mutation("setLocations", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct SetLocationsArgs {
+				pub target_id: i32,
+				pub location_ids: Vec<i32>,
+			}
+
+			t(|_, args: SetLocationsArgs, library| async move {
+				Ok(set_locations(&library, args.target_id, args.location_ids).await?)
+			})
+		})
+		.library_mutation("setPrunePolicy", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct SetPrunePolicyArgs {
+				pub target_id: i32,
+				pub policy: Option<PrunePolicy>,
+			}
+
+			t(|_, args: SetPrunePolicyArgs, library| async move {
+				Ok(set_prune_policy(&library, args.target_id, args.policy).await?)
+			})
+		})
+		.library_mutation("run", |t| {
+			t(|_, args: BackupJobInit, library| async move {
+				library
+					.db
+					.backup_target()
+					.find_unique(backup_target::id::equals(args.backup_target_id))
+					.exec()
+					.await?
+					.ok_or(BackupError::NotFound(args.backup_target_id))?;
+
+				library
+					.spawn_job(Job::new(args, Box::new(BackupJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+		// the snapshots of a target, the latest first
+		.library_query("getSnapshots", |t| {
+			t(|_, target_id: i32, library| async move { Ok(snapshots(&library, target_id).await?) })
+		})
+		.library_mutation("restore", |t| {
+			t(|_, args: RestoreBackupJobInit, library| async move {
+				library
+					.spawn_job(Job::new(args, Box::new(RestoreBackupJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+}
@@ -0,0 +1,37 @@
+use crate::{
+	job::Job,
+	object::preview::{
+		preview_cache_stats, PreviewCacheCleanerJob, PreviewCacheCleanerJobInit, PreviewCacheLimit,
+	},
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.library_query("stats", |t| {
+			t(|_, _: (), library| async move { Ok(preview_cache_stats(&library).await?) })
+		})
+		.library_mutation("setLimit", |t| {
+			t(|ctx, limit: PreviewCacheLimit, library| async move {
+				Ok(ctx
+					.library_manager
+					.set_preview_cache_limit(library.id, limit)
+					.await?)
+			})
+		})
+		// removes the previews of objects in offline or deleted locations, then evicts the ones
+		// over the library's limit
+		.library_mutation("clear", |t| {
+			t(|_, _: (), library| async move {
+				library
+					.spawn_job(Job::new(
+						PreviewCacheCleanerJobInit {},
+						Box::new(PreviewCacheCleanerJob {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
+}
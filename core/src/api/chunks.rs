@@ -0,0 +1,80 @@
+use rspc::Type;
+use serde::Deserialize;
+
+use crate::{
+	invalidate_query,
+	job::Job,
+	object::chunks::{
+		chunk_store_stats, delete_manifest, file_manifests, manifest_content, missing_chunks,
+		restore_manifest, ChunkError, ChunkStoreJob, ChunkStoreJobInit, RestoreManifestArgs,
+	},
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.library_query("stats", |t| {
+			t(|_, _: (), library| async move { Ok(chunk_store_stats(&library).await?) })
+		})
+		.library_mutation("setEnabled", |t| {
+			t(|ctx, enabled: bool, library| async move {
+				Ok(ctx
+					.library_manager
+					.set_chunk_store_enabled(library.id, enabled)
+					.await?)
+			})
+		})
+		// stores the files of a location, or of a directory in it, that changed since they were
+		// last stored
+		.library_mutation("store", |t| {
+			t(|_, args: ChunkStoreJobInit, library| async move {
+				if !library.config.chunk_store {
+					return Err(ChunkError::Disabled.into());
+				}
+
+				library
+					.spawn_job(Job::new(args, Box::new(ChunkStoreJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+		// the stored versions of a file, the latest first
+		.library_query("getManifests", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct GetManifestsArgs {
+				pub location_id: i32,
+				pub file_path_id: i32,
+			}
+
+			t(|_, args: GetManifestsArgs, library| async move {
+				Ok(file_manifests(&library, args.location_id, args.file_path_id).await?)
+			})
+		})
+		.library_query("getManifest", |t| {
+			t(|_, manifest_id: i32, library| async move {
+				Ok(manifest_content(&library, manifest_id).await?)
+			})
+		})
+		// which of the given chunks aren't stored yet, the ones to fetch from a peer
+		.library_query("missing", |t| {
+			t(|_, hashes: Vec<String>, library| async move {
+				Ok(missing_chunks(&library, hashes).await?)
+			})
+		})
+		// rebuilds a stored version of a file, returning where it was put
+		.library_mutation("restore", |t| {
+			t(|_, args: RestoreManifestArgs, library| async move {
+				Ok(restore_manifest(&library, args).await?)
+			})
+		})
+		.library_mutation("deleteManifest", |t| {
+			t(|_, manifest_id: i32, library| async move {
+				delete_manifest(&library, manifest_id).await?;
+				invalidate_query!(library, "chunks.getManifests");
+				invalidate_query!(library, "chunks.stats");
+				Ok(())
+			})
+		})
+}
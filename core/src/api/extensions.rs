@@ -0,0 +1,80 @@
+use rspc::Type;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+	extension::{ExtensionJobInit, ExtensionJobRunner, Permission},
+	job::Job,
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+#[derive(Deserialize, Type, Debug)]
+pub struct SetExtensionEnabledArgs {
+	pub id: String,
+	pub enabled: bool,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct GrantPermissionsArgs {
+	pub id: String,
+	/// replaces the permissions granted before
+	pub granted: Vec<Permission>,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct RunExtensionJobArgs {
+	pub extension_id: String,
+	pub job: String,
+	pub args: Value,
+}
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.query("list", |t| {
+			t(|ctx, _: ()| async move {
+				let settings = ctx.config.get().await.extensions;
+				Ok(ctx.extensions.list(&settings))
+			})
+		})
+		.mutation("setEnabled", |t| {
+			t(|ctx, args: SetExtensionEnabledArgs| async move {
+				ctx.config
+					.write(|mut config| {
+						config.extensions.entry(args.id).or_default().enabled = args.enabled
+					})
+					.await?;
+				Ok(())
+			})
+		})
+		// an extension is only ever given the permissions it asked for, whatever it's granted
+		.mutation("grantPermissions", |t| {
+			t(|ctx, args: GrantPermissionsArgs| async move {
+				ctx.config
+					.write(|mut config| {
+						config.extensions.entry(args.id).or_default().granted = args.granted
+					})
+					.await?;
+				Ok(())
+			})
+		})
+		.library_mutation("runJob", |t| {
+			t(|ctx, args: RunExtensionJobArgs, library| async move {
+				// fails early when the job doesn't exist, rather than once it's running
+				ctx.extensions.job(&args.extension_id, &args.job)?;
+
+				library
+					.spawn_job(Job::new(
+						ExtensionJobInit {
+							extension_id: args.extension_id,
+							job: args.job,
+							args: args.args,
+						},
+						Box::new(ExtensionJobRunner {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
+}
@@ -1,25 +1,213 @@
 use crate::{
 	invalidate_query,
 	job::Job,
-	location::fetch_location,
+	library::{
+		audit_operation, content_status, missing_pinned_content, AuditAction, DIRECTORY_QUERY,
+		DUPLICATES_QUERY,
+	},
+	location::{
+		fetch_location,
+		listing::{list_directory, DirectoryListArgs},
+		LocationError,
+	},
 	object::fs::{
+		archive::{ArchiveJob, ArchiveJobInit},
+		archive_reader::{extract_entry, index_archive},
+		batch::{BatchArgs, BatchJob, BatchJobInit},
+		copy::{FileCopierJob, FileCopierJobInit},
 		decrypt::{FileDecryptorJob, FileDecryptorJobInit},
 		encrypt::{FileEncryptorJob, FileEncryptorJobInit},
+		ingest::{IngestError, IngestJob, IngestJobInit},
+		organize::{plan_organize, validate_pattern, OrganizerJob, OrganizerJobInit},
+		rename::{plan_rename, RenameArgs, RenamerJob, RenamerJobInit},
+		restore::{find_recoverable, FileRestorerJob, FileRestorerJobInit},
+		trash::{
+			clean_trash, move_to_trash, restore_from_trash, TrashCleanerJob, TrashCleanerJobInit,
+		},
+		upload::{
+			finish_upload, upload_status, write_chunk, FinishUploadArgs, UploadChunkArgs,
+			UploadTarget,
+		},
+	},
+	object::{
+		garbage_collector::{GarbageCollectorJob, GarbageCollectorJobInit},
+		geo::{map_markers, nearby_photos, MapMarkersArgs, NearbyPhotosArgs},
+		preview::{stream_preview, THUMBNAIL_CACHE_DIR_NAME},
+		search::{duplicate_objects, search_archive_entries, search_files, FileSearchArgs},
+		similar::{similar_images, SimilarImagesArgs},
+		timeline::{timeline, TimelineArgs},
+		versions::{file_history, restore_version},
+	},
+	prisma::{
+		archive_entry, file_path, media_data, object, object_source, tag, tag_on_object,
+		trash_item, video_chapter,
 	},
-	prisma::object,
+	sync::models::{uuid_from_pub_id, OBJECT},
+	util::os_path::resolve_materialized_path,
 };
 
+use prisma_client_rust::Direction;
 use rspc::{ErrorCode, Type};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::{
+	fs::{self, OpenOptions},
+	path::PathBuf,
+};
+use tokio::task::block_in_place;
+use tracing::error;
 
 use super::{utils::LibraryRequest, RouterBuilder};
 
 pub(crate) fn mount() -> RouterBuilder {
 	<RouterBuilder>::new()
+		// what's known about an object's media, including the description made with its preview
 		.library_query("readMetadata", |t| {
-			t(|_, _id: i32, _| async move {
-				#[allow(unreachable_code)]
-				Ok(todo!())
+			t(|_, id: i32, library| async move {
+				Ok(library
+					.db
+					.media_data()
+					.find_unique(media_data::id::equals(id))
+					.exec()
+					.await?)
+			})
+		})
+		// whether the objects' content is on this node or only known from other nodes
+		.library_query("getContentStatus", |t| {
+			t(|_, object_ids: Vec<i32>, library| async move {
+				Ok(content_status(&library, object_ids).await?)
+			})
+		})
+		.library_query("getMissingPinned", |t| {
+			t(|_, _: (), library| async move { Ok(missing_pinned_content(&library).await?) })
+		})
+		// where a downloaded object came from, for the file details
+		.library_query("getSources", |t| {
+			t(|_, object_id: i32, library| async move {
+				Ok(library
+					.db
+					.object_source()
+					.find_many(vec![object_source::object_id::equals(object_id)])
+					.order_by(object_source::kind::order(Direction::Asc))
+					.exec()
+					.await?)
+			})
+		})
+		// the frames of a video's preview strip, in order, each served as `chapter/<cas_id>/<position>`
+		.library_query("getChapters", |t| {
+			t(|_, object_id: i32, library| async move {
+				Ok(library
+					.db
+					.video_chapter()
+					.find_many(vec![video_chapter::object_id::equals(object_id)])
+					.order_by(video_chapter::position::order(Direction::Asc))
+					.exec()
+					.await?)
+			})
+		})
+		// the preview of an object by its cas_id, streamed in parts: the rendered first page of a PDF,
+		// the waveform of audio, a snippet of a text file or the thumbnail of anything else
+		.library_subscription("getPreview", |t| {
+			t(|ctx, cas_id: String, _| {
+				stream_preview(
+					ctx.config.data_directory().join(THUMBNAIL_CACHE_DIR_NAME),
+					cas_id,
+				)
+			})
+		})
+		// files bucketed by the day, month or year they were taken or modified, with a page of one bucket
+		.library_query("timeline", |t| {
+			t(|_, args: TimelineArgs, library| async move { Ok(timeline(&library, args).await?) })
+		})
+		// the photos on a box of the map, clustered unless the map is zoomed in
+		.library_query("getMapMarkers", |t| {
+			t(
+				|_, args: MapMarkersArgs, library| async move { Ok(map_markers(&library, args).await?) },
+			)
+		})
+		// the photos taken near a place, the closest first
+		.library_query("getNearby", |t| {
+			t(|_, args: NearbyPhotosArgs, library| async move {
+				Ok(nearby_photos(&library, args).await?)
+			})
+		})
+		// images that look alike, clustered, optionally only the cluster of the given object
+		.library_query("getSimilarImages", |t| {
+			t(|_, args: SimilarImagesArgs, library| async move {
+				Ok(similar_images(&library, args).await?)
+			})
+		})
+		// a page of the children of a directory, sorted, with how many children it has in total
+		.library_query("list", |t| {
+			t(|_, args: DirectoryListArgs, library| async move {
+				Ok(library
+					.query_cache
+					.get_or_insert(
+						DIRECTORY_QUERY,
+						&args,
+						Some(args.location_id),
+						list_directory(&library, args.clone()),
+					)
+					.await?)
+			})
+		})
+		// file paths whose name contains the searched one
+		.library_query("search", |t| {
+			t(
+				|_, args: FileSearchArgs, library| async move {
+					Ok(search_files(&library, args).await?)
+				},
+			)
+		})
+		// what's inside indexed archives and disk images, matched by name like `search`
+		.library_query("searchArchiveEntries", |t| {
+			t(|_, args: FileSearchArgs, library| async move {
+				Ok(search_archive_entries(&library, args).await?)
+			})
+		})
+		// objects with more than one copy, optionally only those with a copy in the given location
+		.library_query("getDuplicates", |t| {
+			t(|_, location_id: Option<i32>, library| async move {
+				// other copies may be in other locations, so it's cached for the whole library
+				Ok(library
+					.query_cache
+					.get_or_insert(
+						DUPLICATES_QUERY,
+						&location_id,
+						None,
+						duplicate_objects(&library, location_id),
+					)
+					.await?)
+			})
+		})
+		// objects downloaded from a url containing `search`, e.g. a site's domain
+		.library_query("searchBySource", |t| {
+			t(|_, search: String, library| async move {
+				Ok(library
+					.db
+					.object()
+					.find_many(vec![object::sources::some(vec![
+						object_source::url::contains(search),
+					])])
+					.include(object::include!({ sources }))
+					.exec()
+					.await?)
+			})
+		})
+		// images labeled on device with a label containing `search`, e.g. "dog"
+		.library_query("searchByLabel", |t| {
+			t(|_, search: String, library| async move {
+				Ok(library
+					.db
+					.object()
+					.find_many(vec![object::tags::some(vec![tag_on_object::tag::is(
+						vec![
+							tag::is_system::equals(true),
+							tag::name::contains(search.to_lowercase()),
+						],
+					)])])
+					.include(object::include!({ tags: include { tag } }))
+					.exec()
+					.await?)
 			})
 		})
 		.library_mutation("setNote", |t| {
@@ -30,7 +218,7 @@ pub(crate) fn mount() -> RouterBuilder {
 			}
 
 			t(|_, args: SetNoteArgs, library| async move {
-				library
+				let object = library
 					.db
 					.object()
 					.update(
@@ -40,6 +228,17 @@ pub(crate) fn mount() -> RouterBuilder {
 					.exec()
 					.await?;
 
+				let pub_id = library.sync.ensure_object_pub_id(&object).await?;
+				library
+					.sync
+					.write_ops(vec![library.sync.shared_update(
+						OBJECT,
+						pub_id,
+						"note",
+						&object.note,
+					)])
+					.await?;
+
 				invalidate_query!(library, "locations.getExplorerData");
 
 				Ok(())
@@ -53,7 +252,7 @@ pub(crate) fn mount() -> RouterBuilder {
 			}
 
 			t(|_, args: SetFavoriteArgs, library| async move {
-				library
+				let object = library
 					.db
 					.object()
 					.update(
@@ -63,6 +262,17 @@ pub(crate) fn mount() -> RouterBuilder {
 					.exec()
 					.await?;
 
+				let pub_id = library.sync.ensure_object_pub_id(&object).await?;
+				library
+					.sync
+					.write_ops(vec![library.sync.shared_update(
+						OBJECT,
+						pub_id,
+						"favorite",
+						object.favorite,
+					)])
+					.await?;
+
 				invalidate_query!(library, "locations.getExplorerData");
 
 				Ok(())
@@ -70,12 +280,30 @@ pub(crate) fn mount() -> RouterBuilder {
 		})
 		.library_mutation("delete", |t| {
 			t(|_, id: i32, library| async move {
-				library
+				let result = library
 					.db
 					.object()
 					.delete(object::id::equals(id))
 					.exec()
-					.await?;
+					.await;
+				audit_operation(
+					&library,
+					AuditAction::Delete,
+					"files.delete",
+					&id,
+					result.as_ref().err(),
+				)
+				.await;
+				let object = result?;
+
+				if let Some(pub_id) = &object.pub_id {
+					library
+						.sync
+						.write_ops(vec![library
+							.sync
+							.shared_delete(OBJECT, uuid_from_pub_id(pub_id))])
+						.await?;
+				}
 
 				invalidate_query!(library, "locations.getExplorerData");
 				Ok(())
@@ -120,6 +348,403 @@ pub(crate) fn mount() -> RouterBuilder {
 					.await;
 				invalidate_query!(library, "locations.getExplorerData");
 
+				Ok(())
+			})
+		})
+		.library_mutation("copyFiles", |t| {
+			t(|_, args: FileCopierJobInit, library| async move {
+				for location_id in [args.source_location_id, args.target_location_id] {
+					match fetch_location(&library, location_id).exec().await? {
+						None => {
+							return Err(rspc::Error::new(
+								ErrorCode::NotFound,
+								"Location not found".into(),
+							))
+						}
+						// catalogs are never written to
+						Some(location)
+							if location.is_catalog && location_id == args.target_location_id =>
+						{
+							return Err(LocationError::ReadOnlyCatalog(location_id).into())
+						}
+						_ => {}
+					}
+				}
+
+				library
+					.spawn_job(Job::new(args, Box::new(FileCopierJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+		// files missing from disk whose content can still be found in another location or on another node
+		.library_query("getRecoverable", |t| {
+			t(|_, location_id: i32, library| async move {
+				Ok(find_recoverable(&library, location_id, None).await?)
+			})
+		})
+		.library_mutation("restoreMissing", |t| {
+			t(|_, args: FileRestorerJobInit, library| async move {
+				library
+					.spawn_job(Job::new(args, Box::new(FileRestorerJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+		// the content a file had before each time it was found to have changed, the latest first
+		.library_query("getHistory", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct GetHistoryArgs {
+				pub location_id: i32,
+				pub file_path_id: i32,
+			}
+
+			t(|_, args: GetHistoryArgs, library| async move {
+				Ok(file_history(&library, args.location_id, args.file_path_id).await?)
+			})
+		})
+		// restores the kept copy of a file version next to the file, returning where it was put
+		.library_mutation("restoreVersion", |t| {
+			t(|_, version_id: i32, library| async move {
+				Ok(restore_version(&library, version_id).await?)
+			})
+		})
+		// where organizing a location by date would move its files, without moving them
+		.library_query("planOrganize", |t| {
+			t(|_, args: OrganizerJobInit, library| async move {
+				Ok(plan_organize(&library, &args).await?)
+			})
+		})
+		.library_mutation("organizeFiles", |t| {
+			t(|_, args: OrganizerJobInit, library| async move {
+				validate_pattern(&args.pattern)?;
+
+				library
+					.spawn_job(Job::new(args, Box::new(OrganizerJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+		// copies what the library doesn't have yet from a camera card into a location
+		.library_mutation("ingest", |t| {
+			t(|_, args: IngestJobInit, library| async move {
+				validate_pattern(&args.pattern)?;
+				if !args.source_path.is_dir() {
+					return Err(IngestError::InvalidSource(args.source_path).into());
+				}
+
+				library
+					.spawn_job(Job::new(args, Box::new(IngestJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+		// the new names of the selected files, with the ones left alone because of a conflict
+		.library_query("planRename", |t| {
+			t(|_, args: RenameArgs, library| async move {
+				let items = args.target.items(&library)?;
+				Ok(plan_rename(&library, &args.pattern, &items).await?)
+			})
+		})
+		.library_mutation("renameFiles", |t| {
+			t(|_, args: RenameArgs, library| async move {
+				args.pattern.validate()?;
+				let items = args.target.items(&library)?;
+
+				library
+					.spawn_job(Job::new(
+						RenamerJobInit {
+							items,
+							pattern: args.pattern,
+						},
+						Box::new(RenamerJob {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
+		// tags, moves, trashes or encrypts every file path of a selection
+		.library_mutation("batch", |t| {
+			t(|_, args: BatchArgs, library| async move {
+				args.command.validate(&library).await?;
+				let items = args.target.items(&library)?;
+
+				library
+					.spawn_job(Job::new(
+						BatchJobInit {
+							items,
+							command: args.command,
+						},
+						Box::new(BatchJob {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
+		.library_mutation("compress", |t| {
+			t(|_, args: ArchiveJobInit, library| async move {
+				if fetch_location(&library, args.location_id)
+					.exec()
+					.await?
+					.is_none()
+				{
+					return Err(rspc::Error::new(
+						ErrorCode::NotFound,
+						"Location not found".into(),
+					));
+				}
+
+				library
+					.spawn_job(Job::new(args, Box::new(ArchiveJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+		// lists the entries inside an archive, the children of `parent` or every match of `search`
+		.library_query("getArchiveEntries", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct GetArchiveEntriesArgs {
+				pub location_id: i32,
+				pub file_path_id: i32,
+				pub parent: Option<String>,
+				pub search: Option<String>,
+			}
+
+			t(|_, args: GetArchiveEntriesArgs, library| async move {
+				let mut params = vec![
+					archive_entry::location_id::equals(args.location_id),
+					archive_entry::file_path_id::equals(args.file_path_id),
+				];
+
+				if let Some(search) = &args.search {
+					params.push(archive_entry::name::contains(search.clone()));
+				} else if let Some(parent) = &args.parent {
+					params.push(archive_entry::path::starts_with(format!("{parent}/")));
+				}
+
+				let entries = library.db.archive_entry().find_many(params).exec().await?;
+
+				if args.search.is_some() {
+					return Ok(entries);
+				}
+
+				// only keep direct children of the requested folder
+				let depth = args
+					.parent
+					.as_ref()
+					.map(|parent| parent.matches('/').count() + 1)
+					.unwrap_or(0);
+
+				Ok(entries
+					.into_iter()
+					.filter(|entry| entry.path.matches('/').count() == depth)
+					.collect::<Vec<_>>())
+			})
+		})
+		.library_mutation("extractArchiveEntry", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct ExtractArchiveEntryArgs {
+				pub location_id: i32,
+				pub file_path_id: i32,
+				pub entry_path: String,
+				/// defaults to a file next to the archive, named after the entry
+				pub output_path: Option<PathBuf>,
+			}
+
+			t(|_, args: ExtractArchiveEntryArgs, library| async move {
+				let location = fetch_location(&library, args.location_id)
+					.exec()
+					.await?
+					.ok_or_else(|| {
+						rspc::Error::new(ErrorCode::NotFound, "Location not found".into())
+					})?;
+
+				let location_path = location.local_path.map(PathBuf::from).ok_or_else(|| {
+					rspc::Error::new(ErrorCode::BadRequest, "Location has no local path".into())
+				})?;
+
+				let file_path = library
+					.db
+					.file_path()
+					.find_unique(file_path::location_id_id(
+						args.location_id,
+						args.file_path_id,
+					))
+					.exec()
+					.await?
+					.ok_or_else(|| {
+						rspc::Error::new(ErrorCode::NotFound, "File path not found".into())
+					})?;
+
+				let archive_path = resolve_materialized_path(
+					&location_path,
+					&file_path.materialized_path,
+					file_path.raw_path.as_deref(),
+				);
+				let output_path = args.output_path.unwrap_or_else(|| {
+					let entry_name = PathBuf::from(&args.entry_path);
+					archive_path.with_file_name(entry_name.file_name().unwrap_or_default())
+				});
+
+				block_in_place(|| {
+					let mut output = OpenOptions::new()
+						.write(true)
+						.create_new(true)
+						.open(&output_path)
+						.map_err(|e| {
+							rspc::Error::with_cause(
+								ErrorCode::Conflict,
+								format!("Couldn't create '{}'", output_path.display()),
+								e,
+							)
+						})?;
+
+					let extracted = extract_entry(&archive_path, &args.entry_path, &mut output);
+					drop(output);
+
+					// a partial file would pass for the entry
+					if extracted.is_err() {
+						if let Err(e) = fs::remove_file(&output_path) {
+							error!("Failed to remove '{}': {:#?}", output_path.display(), e);
+						}
+					}
+
+					extracted.map_err(rspc::Error::from)
+				})?;
+
+				invalidate_query!(library, "locations.getExplorerData");
+
+				Ok(output_path)
+			})
+		})
+		// re-reads the contents of an archive, for when it was modified after being identified
+		.library_mutation("reindexArchive", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct ReindexArchiveArgs {
+				pub location_id: i32,
+				pub file_path_id: i32,
+			}
+
+			t(|_, args: ReindexArchiveArgs, library| async move {
+				let location = fetch_location(&library, args.location_id)
+					.exec()
+					.await?
+					.ok_or_else(|| {
+						rspc::Error::new(ErrorCode::NotFound, "Location not found".into())
+					})?;
+
+				let file_path = library
+					.db
+					.file_path()
+					.find_unique(file_path::location_id_id(
+						args.location_id,
+						args.file_path_id,
+					))
+					.exec()
+					.await?
+					.ok_or_else(|| {
+						rspc::Error::new(ErrorCode::NotFound, "File path not found".into())
+					})?;
+
+				let count = index_archive(
+					&library,
+					location.local_path.unwrap_or_default(),
+					&file_path,
+				)
+				.await?;
+
+				invalidate_query!(library, "files.getArchiveEntries");
+
+				Ok(count)
+			})
+		})
+		.library_mutation("moveToTrash", |t| {
+			#[derive(Type, Serialize, Deserialize)]
+			pub struct MoveToTrashArgs {
+				pub location_id: i32,
+				pub file_path_id: i32,
+			}
+
+			t(|_, args: MoveToTrashArgs, library| async move {
+				let result = move_to_trash(&library, args.location_id, args.file_path_id).await;
+				audit_operation(
+					&library,
+					AuditAction::Delete,
+					"files.moveToTrash",
+					&args,
+					result.as_ref().err(),
+				)
+				.await;
+				let item = result?;
+
+				// the trash might be over its size cap now
+				clean_trash(&library).await;
+
+				Ok(item)
+			})
+		})
+		.library_query("getTrash", |t| {
+			t(|_, _: (), library| async move {
+				Ok(library
+					.db
+					.trash_item()
+					.find_many(vec![])
+					.order_by(trash_item::date_deleted::order(Direction::Desc))
+					.exec()
+					.await?)
+			})
+		})
+		.library_mutation("restoreFromTrash", |t| {
+			t(|_, id: i32, library| async move { Ok(restore_from_trash(&library, id).await?) })
+		})
+		// resumable uploads for clients that can't write to the node's filesystem themselves
+		.library_query("getUploadStatus", |t| {
+			t(|_, target: UploadTarget, library| async move {
+				Ok(upload_status(&library, &target).await?)
+			})
+		})
+		.library_mutation("uploadChunk", |t| {
+			t(
+				|_, args: UploadChunkArgs, library| async move {
+					Ok(write_chunk(&library, args).await?)
+				},
+			)
+		})
+		.library_mutation("finishUpload", |t| {
+			t(|_, args: FinishUploadArgs, library| async move {
+				Ok(finish_upload(&library, args).await?)
+			})
+		})
+		.library_mutation("emptyTrash", |t| {
+			t(|_, _: (), library| async move {
+				library
+					.spawn_job(Job::new(
+						TrashCleanerJobInit { empty_all: true },
+						Box::new(TrashCleanerJob {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
+		// removes the objects left without files, and their previews. Forcing it also removes the
+		// ones that were tagged, noted or commented on.
+		.library_mutation("collectGarbage", |t| {
+			t(|_, force: bool, library| async move {
+				library
+					.spawn_job(Job::new(
+						GarbageCollectorJobInit { force },
+						Box::new(GarbageCollectorJob {}),
+					))
+					.await;
+
 				Ok(())
 			})
 		})
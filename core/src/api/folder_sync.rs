@@ -0,0 +1,81 @@
+use crate::{
+	invalidate_query,
+	job::Job,
+	object::fs::sync::{
+		resolve_conflict, FolderSyncCreateArgs, FolderSyncError, FolderSyncJob, FolderSyncJobInit,
+		ResolveConflictArgs,
+	},
+	prisma::{folder_sync, folder_sync_conflict},
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.library_query("list", |t| {
+			t(|_, _: (), library| async move {
+				Ok(library.db.folder_sync().find_many(vec![]).exec().await?)
+			})
+		})
+		.library_mutation("create", |t| {
+			t(|_, args: FolderSyncCreateArgs, library| async move {
+				let folder_sync = args.create(&library).await?;
+				invalidate_query!(library, "folderSync.list");
+				Ok(folder_sync)
+			})
+		})
+		// the files stay as they are on both sides
+		.library_mutation("delete", |t| {
+			t(|_, id: i32, library| async move {
+				let deleted = library
+					.db
+					.folder_sync()
+					.delete_many(vec![folder_sync::id::equals(id)])
+					.exec()
+					.await?;
+				if deleted == 0 {
+					return Err(FolderSyncError::NotFound(id).into());
+				}
+
+				invalidate_query!(library, "folderSync.list");
+				Ok(())
+			})
+		})
+		.library_mutation("run", |t| {
+			t(|_, id: i32, library| async move {
+				library
+					.db
+					.folder_sync()
+					.find_unique(folder_sync::id::equals(id))
+					.exec()
+					.await?
+					.ok_or(FolderSyncError::NotFound(id))?;
+
+				library
+					.spawn_job(Job::new(
+						FolderSyncJobInit { folder_sync_id: id },
+						Box::new(FolderSyncJob {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
+		.library_query("getConflicts", |t| {
+			t(|_, id: i32, library| async move {
+				Ok(library
+					.db
+					.folder_sync_conflict()
+					.find_many(vec![folder_sync_conflict::folder_sync_id::equals(id)])
+					.exec()
+					.await?)
+			})
+		})
+		.library_mutation("resolveConflict", |t| {
+			t(|_, args: ResolveConflictArgs, library| async move {
+				resolve_conflict(&library, args).await?;
+				invalidate_query!(library, "folderSync.getConflicts");
+				Ok(())
+			})
+		})
+}
@@ -0,0 +1,17 @@
+use crate::library::{redo, undo};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+pub(crate) fn mount() -> RouterBuilder {
+	RouterBuilder::new()
+		.library_query("list", |t| {
+			t(|_, _: (), library| async move { Ok(library.history.entries()) })
+		})
+		// reverts the latest batch of operations, returning null if there's nothing to undo
+		.library_mutation("undo", |t| {
+			t(|_, _: (), library| async move { Ok(undo(&library).await?) })
+		})
+		.library_mutation("redo", |t| {
+			t(|_, _: (), library| async move { Ok(redo(&library).await?) })
+		})
+}
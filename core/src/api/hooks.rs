@@ -0,0 +1,94 @@
+use rspc::Type;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::node::{Hook, HookAction, HookError, HookTrigger};
+
+use super::RouterBuilder;
+
+#[derive(Deserialize, Type, Debug)]
+pub struct HookCreateArgs {
+	pub name: String,
+	pub library_id: Option<Uuid>,
+	pub trigger: HookTrigger,
+	pub action: HookAction,
+	pub payload: Option<String>,
+	pub min_interval_secs: u32,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct SetHookEnabledArgs {
+	pub id: Uuid,
+	pub enabled: bool,
+}
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.query("list", |t| {
+			t(|ctx, _: ()| async move { Ok(ctx.config.get().await.hooks) })
+		})
+		.mutation("create", |t| {
+			t(|ctx, args: HookCreateArgs| async move {
+				let hook = Hook {
+					id: Uuid::new_v4(),
+					name: args.name,
+					enabled: true,
+					library_id: args.library_id,
+					trigger: args.trigger,
+					action: args.action,
+					payload: args.payload,
+					min_interval_secs: args.min_interval_secs,
+				};
+				hook.validate()?;
+
+				let created = hook.clone();
+				ctx.config
+					.write(|mut config| config.hooks.push(created))
+					.await?;
+
+				Ok(hook)
+			})
+		})
+		.mutation("setEnabled", |t| {
+			t(|ctx, args: SetHookEnabledArgs| async move {
+				if !ctx
+					.config
+					.get()
+					.await
+					.hooks
+					.iter()
+					.any(|hook| hook.id == args.id)
+				{
+					return Err(HookError::NotFound(args.id).into());
+				}
+
+				ctx.config
+					.write(|mut config| {
+						for hook in config.hooks.iter_mut().filter(|hook| hook.id == args.id) {
+							hook.enabled = args.enabled;
+						}
+					})
+					.await?;
+				Ok(())
+			})
+		})
+		.mutation("delete", |t| {
+			t(|ctx, id: Uuid| async move {
+				if !ctx
+					.config
+					.get()
+					.await
+					.hooks
+					.iter()
+					.any(|hook| hook.id == id)
+				{
+					return Err(HookError::NotFound(id).into());
+				}
+
+				ctx.config
+					.write(|mut config| config.hooks.retain(|hook| hook.id != id))
+					.await?;
+				Ok(())
+			})
+		})
+}
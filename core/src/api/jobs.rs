@@ -1,31 +1,115 @@
 use crate::{
-	job::{Job, JobManager},
+	invalidate_query,
+	job::{
+		list_schedules, Job, JobManager, ScheduleCreateArgs, ScheduleError, ScheduleUpdateArgs,
+		ThrottleLimits, TransferLimits,
+	},
+	library::{MaintenanceJob, MaintenanceJobInit},
 	location::{fetch_location, LocationError},
 	object::{
-		identifier_job::{FileIdentifierJob, FileIdentifierJobInit},
+		faces::{FaceGrouperJob, FaceGrouperJobInit},
+		identifier_job::{
+			decide_duplicates, DuplicateDecision, FileIdentifierJob, FileIdentifierJobInit,
+		},
+		labeler::{ImageLabelerJob, ImageLabelerJobInit},
 		preview::{ThumbnailJob, ThumbnailJobInit},
-		validation::validator_job::{ObjectValidatorJob, ObjectValidatorJobInit},
+		validation::{
+			checksum_file::{
+				read_checksum_file, ChecksumExportJob, ChecksumExportJobInit, ChecksumVerifyJob,
+				ChecksumVerifyJobInit,
+			},
+			validator_job::{ObjectValidatorJob, ObjectValidatorJobInit},
+		},
 	},
-	prisma::location,
+	prisma::{location, schedule},
+	util::logging,
 };
 
 use rspc::{ErrorCode, Type};
 use serde::Deserialize;
 use std::path::PathBuf;
+use uuid::Uuid;
 
 use super::{utils::LibraryRequest, CoreEvent, RouterBuilder};
 
 pub(crate) fn mount() -> RouterBuilder {
 	<RouterBuilder>::new()
 		.library_query("getRunning", |t| {
-			t(|ctx, _: (), _| async move { Ok(ctx.jobs.get_running().await) })
+			t(|ctx, _: (), library| async move { Ok(ctx.jobs.get_running(&library).await) })
 		})
 		.library_query("isRunning", |t| {
-			t(|ctx, _: (), _| async move { Ok(!ctx.jobs.get_running().await.is_empty()) })
+			t(
+				|ctx, _: (), library| async move {
+					Ok(!ctx.jobs.get_running(&library).await.is_empty())
+				},
+			)
+		})
+		.library_query("getQueue", |t| {
+			t(|ctx, _: (), library| async move { Ok(ctx.jobs.get_queue(&library).await?) })
+		})
+		// how much background jobs can read and hash at once, which applies to jobs already running
+		.query("getThrottle", |t| {
+			t(|ctx, _: ()| async move { Ok(ctx.jobs.governor().limits()) })
+		})
+		.mutation("setThrottle", |t| {
+			t(|ctx, limits: ThrottleLimits| async move {
+				ctx.config
+					.write(|mut config| config.job_throttle = limits)
+					.await?;
+				ctx.jobs.governor().set_limits(limits);
+				Ok(limits)
+			})
+		})
+		// bandwidth caps, concurrency limits and transfer windows of what goes over the network
+		.query("getTransferLimits", |t| {
+			t(|ctx, _: ()| async move { Ok(ctx.jobs.transfers().limits()) })
+		})
+		.mutation("setTransferLimits", |t| {
+			t(|ctx, limits: TransferLimits| async move {
+				ctx.config
+					.write(|mut config| config.transfer_limits = limits.clone())
+					.await?;
+				ctx.jobs.transfers().set_limits(limits.clone());
+				Ok(limits)
+			})
 		})
 		.library_query("getHistory", |t| {
 			t(|_, _: (), library| async move { Ok(JobManager::get_history(&library).await?) })
 		})
+		// the files a job couldn't process, and why
+		.library_query("getFileErrors", |t| {
+			t(|_, job_id: Uuid, library| async move {
+				Ok(JobManager::get_file_errors(&library, job_id).await?)
+			})
+		})
+		// the last lines a job logged, for debugging it
+		.library_query("getLogs", |t| {
+			t(
+				|_, job_id: Uuid, library| async move {
+					Ok(logging::job_logs(library.id, job_id).await?)
+				},
+			)
+		})
+		// runs a job that was dead-lettered after running out of attempts again, from where it stopped
+		.library_mutation("requeue", |t| {
+			t(
+				|ctx, job_id: Uuid, library| async move {
+					Ok(ctx.jobs.requeue(&library, job_id).await?)
+				},
+			)
+		})
+		// goes on with a job that stopped as it was adding mostly files the library already has
+		.library_mutation("decideDuplicates", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct DecideDuplicatesArgs {
+				pub job_id: Uuid,
+				pub decision: DuplicateDecision,
+			}
+
+			t(|ctx, args: DecideDuplicatesArgs, library| async move {
+				Ok(decide_duplicates(&library, ctx.jobs, args.job_id, args.decision).await?)
+			})
+		})
 		.library_mutation("generateThumbsForLocation", |t| {
 			#[derive(Type, Deserialize)]
 			pub struct GenerateThumbsForLocationArgs {
@@ -51,6 +135,7 @@ pub(crate) fn mount() -> RouterBuilder {
 								location_id: args.id,
 								path: PathBuf::new(),
 								background: true,
+								budget: None,
 							},
 							Box::new(ThumbnailJob {}),
 						))
@@ -89,6 +174,44 @@ pub(crate) fn mount() -> RouterBuilder {
 				Ok(())
 			})
 		})
+		// writes a `sha256sum` or `md5sum` file of a location's files
+		.library_mutation("exportChecksums", |t| {
+			t(|_, args: ChecksumExportJobInit, library| async move {
+				if fetch_location(&library, args.location_id)
+					.exec()
+					.await?
+					.is_none()
+				{
+					return Err(LocationError::IdNotFound(args.location_id).into());
+				}
+
+				library
+					.spawn_job(Job::new(args, Box::new(ChecksumExportJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
+		// checks a location against a checksum file, reporting mismatches in the job's metadata
+		.library_mutation("verifyChecksums", |t| {
+			t(|_, args: ChecksumVerifyJobInit, library| async move {
+				if fetch_location(&library, args.location_id)
+					.exec()
+					.await?
+					.is_none()
+				{
+					return Err(LocationError::IdNotFound(args.location_id).into());
+				}
+				// turns down a file that can't be read or has no checksums before the job starts
+				read_checksum_file(&args.path)?;
+
+				library
+					.spawn_job(Job::new(args, Box::new(ChecksumVerifyJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
 		.library_mutation("identifyUniqueFiles", |t| {
 			#[derive(Type, Deserialize)]
 			pub struct IdentifyUniqueFilesArgs {
@@ -117,6 +240,59 @@ pub(crate) fn mount() -> RouterBuilder {
 				Ok(())
 			})
 		})
+		// tags the images of a location with what the on-device model finds in them
+		.library_mutation("labelImages", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct LabelImagesArgs {
+				pub id: i32,
+				pub min_confidence: Option<f32>,
+			}
+
+			t(|_, args: LabelImagesArgs, library| async move {
+				if fetch_location(&library, args.id).exec().await?.is_none() {
+					return Err(LocationError::IdNotFound(args.id).into());
+				}
+
+				library
+					.spawn_job(Job::new(
+						ImageLabelerJobInit {
+							location_id: args.id,
+							min_confidence: args.min_confidence,
+						},
+						Box::new(ImageLabelerJob {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
+		// finds the faces in the images of a location and groups them into people
+		.library_mutation("groupFaces", |t| {
+			t(|_, id: i32, library| async move {
+				if fetch_location(&library, id).exec().await?.is_none() {
+					return Err(LocationError::IdNotFound(id).into());
+				}
+
+				library
+					.spawn_job(Job::new(
+						FaceGrouperJobInit { location_id: id },
+						Box::new(FaceGrouperJob {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
+		// checks and repairs the library's database, then compacts it
+		.library_mutation("runMaintenance", |t| {
+			t(|_, _: (), library| async move {
+				library
+					.spawn_job(Job::new(MaintenanceJobInit {}, Box::new(MaintenanceJob {})))
+					.await;
+
+				Ok(())
+			})
+		})
 		.library_subscription("newThumbnail", |t| {
 			t(|ctx, _: (), _| {
 				// TODO: Only return event for the library that was subscribed to
@@ -132,4 +308,42 @@ pub(crate) fn mount() -> RouterBuilder {
 				}
 			})
 		})
+		.merge("schedules.", mount_schedule_routes())
+}
+
+fn mount_schedule_routes() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.library_query("list", |t| {
+			t(|_, _: (), library| async move { Ok(list_schedules(&library).await?) })
+		})
+		.library_mutation("create", |t| {
+			t(|_, args: ScheduleCreateArgs, library| async move {
+				let schedule = args.create(&library).await?;
+				invalidate_query!(library, "jobs.schedules.list");
+				Ok(schedule)
+			})
+		})
+		.library_mutation("update", |t| {
+			t(|_, args: ScheduleUpdateArgs, library| async move {
+				let schedule = args.update(&library).await?;
+				invalidate_query!(library, "jobs.schedules.list");
+				Ok(schedule)
+			})
+		})
+		.library_mutation("delete", |t| {
+			t(|_, schedule_id: i32, library| async move {
+				let deleted = library
+					.db
+					.schedule()
+					.delete_many(vec![schedule::id::equals(schedule_id)])
+					.exec()
+					.await?;
+				if deleted == 0 {
+					return Err(ScheduleError::NotFound(schedule_id).into());
+				}
+
+				invalidate_query!(library, "jobs.schedules.list");
+				Ok(())
+			})
+		})
 }
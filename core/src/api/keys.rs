@@ -10,7 +10,7 @@ use sd_crypto::{
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
-use crate::{invalidate_query, prisma::key};
+use crate::{invalidate_query, library::KeyLock, prisma::key};
 
 use super::{utils::LibraryRequest, RouterBuilder};
 
@@ -76,6 +76,7 @@ pub(crate) fn mount() -> RouterBuilder {
 		.library_query("getKey", |t| {
 			t(|_, key_uuid: uuid::Uuid, library| async move {
 				let key = library.key_manager.get_key(key_uuid)?;
+				library.key_lock.touch();
 
 				let key_string = String::from_utf8(key.expose().clone()).map_err(|_| {
 					rspc::Error::new(
@@ -90,6 +91,7 @@ pub(crate) fn mount() -> RouterBuilder {
 		.library_mutation("mount", |t| {
 			t(|_, key_uuid: uuid::Uuid, library| async move {
 				library.key_manager.mount(key_uuid)?;
+				library.key_lock.touch();
 				// we also need to dispatch jobs that automatically decrypt preview media and metadata here
 				invalidate_query!(library, "keys.listMounted");
 				Ok(())
@@ -118,6 +120,24 @@ pub(crate) fn mount() -> RouterBuilder {
 				Ok(())
 			})
 		})
+		// locks the library's keys, they can be unlocked again through `setMasterPassword`
+		.library_mutation("lock", |t| {
+			t(|_, _: (), library| async move { Ok(KeyLock::lock(&library)?) })
+		})
+		// seconds the keys may stay unlocked without being used, `None` if they are never locked automatically
+		.library_query("getAutoLockTimeout", |t| {
+			t(|_, _: (), library| async move {
+				Ok(library.key_lock.timeout().map(|timeout| timeout.as_secs()))
+			})
+		})
+		.library_mutation("setAutoLockTimeout", |t| {
+			t(|ctx, timeout: Option<u64>, library| async move {
+				Ok(ctx
+					.library_manager
+					.set_key_auto_lock_timeout(library.id, timeout)
+					.await?)
+			})
+		})
 		.library_mutation("clearMasterPassword", |t| {
 			t(|_, _: (), library| async move {
 				library.key_manager.clear_master_password()?;
@@ -191,6 +211,7 @@ pub(crate) fn mount() -> RouterBuilder {
 					Protected::new(args.password),
 					Protected::new(args.secret_key),
 				)?;
+				library.key_lock.touch();
 
 				let automount = library
 					.db
@@ -1,15 +1,22 @@
 use crate::{
-	library::LibraryConfig,
+	invalidate_query,
+	library::{
+		capture_db_health, capture_statistics, db_health, export_library, LibraryConfig,
+		SparseCheckout, STATISTICS_QUERY,
+	},
+	object::{
+		fs::trash::TrashRetention,
+		preview::{ProcessingBudget, VideoChapters},
+		validation::integrity::{integrity_trends, sample_integrity},
+	},
 	prisma::statistics,
-	volume::{get_volumes, save_volume},
+	vfs,
 };
 
-use super::{utils::LibraryRequest, RouterBuilder};
-use chrono::Utc;
-use fs_extra::dir::get_size; // TODO: Remove this dependency as it is sync instead of async
+use super::{utils::LibraryRequest, CoreEvent, RouterBuilder};
 use rspc::Type;
-use serde::Deserialize;
-use tokio::fs;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use uuid::Uuid;
 
 pub(crate) fn mount() -> RouterBuilder {
@@ -17,61 +24,72 @@ pub(crate) fn mount() -> RouterBuilder {
 		.query("list", |t| {
 			t(|ctx, _: ()| async move { ctx.library_manager.get_all_libraries_config().await })
 		})
-		.library_query("getStatistics", |t| {
-			t(|_, _: (), library| async move {
-				let _statistics = library
-					.db
-					.statistics()
-					.find_unique(statistics::id::equals(library.node_local_id))
-					.exec()
-					.await?;
+		.query("listWithStatistics", |t| {
+			#[derive(Type, Serialize)]
+			pub struct LibraryWithStatistics {
+				pub uuid: Uuid,
+				pub config: LibraryConfig,
+				/// the last statistics captured for the library, if they were ever captured
+				pub statistics: Option<statistics::Data>,
+				pub running_jobs: usize,
+			}
 
-				// TODO: get from database, not sys
-				let volumes = get_volumes();
-				save_volume(&library).await?;
+			t(|ctx, _: ()| async move {
+				let mut libraries = vec![];
+				for library in ctx.library_manager.get_all_libraries_ctx().await {
+					let statistics = library
+						.db
+						.statistics()
+						.find_unique(statistics::id::equals(1))
+						.exec()
+						.await?;
 
-				let mut available_capacity: u64 = 0;
-				let mut total_capacity: u64 = 0;
-				if volumes.is_ok() {
-					for volume in volumes? {
-						total_capacity += volume.total_capacity;
-						available_capacity += volume.available_capacity;
-					}
+					libraries.push(LibraryWithStatistics {
+						uuid: library.id,
+						running_jobs: ctx.jobs.get_running(&library).await.len(),
+						config: library.config,
+						statistics,
+					});
 				}
 
-				let library_db_size = match fs::metadata(library.config().data_directory()).await {
-					Ok(metadata) => metadata.len(),
-					Err(_) => 0,
-				};
-
-				let thumbnail_folder_size =
-					get_size(library.config().data_directory().join("thumbnails"));
-
-				use statistics::*;
-				let params = vec![
-					id::set(1), // Each library is a database so only one of these ever exists
-					date_captured::set(Utc::now().into()),
-					total_object_count::set(0),
-					library_db_size::set(library_db_size.to_string()),
-					total_bytes_used::set(0.to_string()),
-					total_bytes_capacity::set(total_capacity.to_string()),
-					total_unique_bytes::set(0.to_string()),
-					total_bytes_free::set(available_capacity.to_string()),
-					preview_media_bytes::set(thumbnail_folder_size.unwrap_or(0).to_string()),
-				];
-
+				Ok(libraries)
+			})
+		})
+		// whether this process holds the library, or only has it open read-only
+		.library_query("getAccess", |t| {
+			t(|_, _: (), library| async move { Ok(library.process_lock.access()) })
+		})
+		// asks the process holding the library to hand it over to this one
+		.mutation("takeOver", |t| {
+			t(|ctx, id: Uuid| async move { Ok(ctx.library_manager.take_over(id).await?) })
+		})
+		.library_query("getStatistics", |t| {
+			t(|_, _: (), library| async move {
 				Ok(library
-					.db
-					.statistics()
-					.upsert(
-						statistics::id::equals(1), // Each library is a database so only one of these ever exists
-						params.clone(),
-						params,
-					)
-					.exec()
+					.query_cache
+					.get_or_insert(STATISTICS_QUERY, &(), None, capture_statistics(&library))
 					.await?)
 			})
 		})
+		// table sizes, index selectivity and page counts over the last `days` days, for diagnostics
+		.library_query("getDbHealth", |t| {
+			t(|_, days: Option<u32>, library| async move { Ok(db_health(&library, days).await?) })
+		})
+		.library_mutation("captureDbHealth", |t| {
+			t(|_, _: (), library| async move {
+				capture_db_health(&library).await?;
+				invalidate_query!(library, "library.getDbHealth");
+				Ok(())
+			})
+		})
+		// what integrity sampling found on each volume over time, and which volumes are going bad
+		.library_query("getIntegrity", |t| {
+			t(|_, _: (), library| async move { Ok(integrity_trends(&library).await?) })
+		})
+		// samples the integrity of the library now, returns false when no file has a checksum to check
+		.library_mutation("sampleIntegrity", |t| {
+			t(|_, _: (), library| async move { Ok(sample_integrity(&library).await?) })
+		})
 		.mutation("create", |t| {
 			t(|ctx, name: String| async move {
 				Ok(ctx
@@ -89,16 +107,76 @@ pub(crate) fn mount() -> RouterBuilder {
 				pub id: Uuid,
 				pub name: Option<String>,
 				pub description: Option<String>,
+				pub processing_budget: Option<ProcessingBudget>,
+				pub trash_retention: Option<TrashRetention>,
+				pub video_chapters: Option<VideoChapters>,
 			}
 
 			t(|ctx, args: EditLibraryArgs| async move {
 				Ok(ctx
 					.library_manager
-					.edit(args.id, args.name, args.description)
+					.edit(
+						args.id,
+						args.name,
+						args.description,
+						args.processing_budget,
+						args.trash_retention,
+						args.video_chapters,
+					)
+					.await?)
+			})
+		})
+		.library_query("getSparseCheckout", |t| {
+			t(|_, _: (), library| async move { Ok(library.config.sparse_checkout) })
+		})
+		.library_mutation("setSparseCheckout", |t| {
+			t(|ctx, sparse_checkout: SparseCheckout, library| async move {
+				Ok(ctx
+					.library_manager
+					.set_sparse_checkout(library.id, sparse_checkout)
 					.await?)
 			})
 		})
+		// serves the library as a drive, with its locations, tags, spaces and views as directories
+		.library_mutation("mountVfs", |t| {
+			t(|_, _: (), library| async move { Ok(vfs::mount(&library).await?) })
+		})
+		.library_mutation("unmountVfs", |t| {
+			t(|_, _: (), library| async move { Ok(vfs::unmount(&library).await?) })
+		})
+		.library_query("getVfsMount", |t| {
+			t(|_, _: (), library| async move { Ok(vfs::mount_info(&library)) })
+		})
+		// checks if content is already in any of the node's libraries, eg. before importing a download
+		.query("findByCasId", |t| {
+			t(|ctx, cas_id: String| async move {
+				Ok(ctx.library_manager.find_by_cas_id(&cas_id).await?)
+			})
+		})
+		// writes the library's database, settings and thumbnails to a single file, for backups or moving to another machine
+		.library_mutation("export", |t| {
+			t(|_, path: PathBuf, library| async move { Ok(export_library(&library, &path).await?) })
+		})
+		.mutation("import", |t| {
+			t(|ctx, path: PathBuf| async move { Ok(ctx.library_manager.import(&path).await?) })
+		})
 		.mutation("delete", |t| {
 			t(|ctx, id: Uuid| async move { Ok(ctx.library_manager.delete_library(id).await?) })
 		})
+		// changes to the library as they happen, so clients can update live instead of polling
+		.library_subscription("events", |t| {
+			t(|ctx, _: (), library_id| {
+				let mut event_bus_rx = ctx.event_bus.subscribe();
+				async_stream::stream! {
+					while let Ok(event) = event_bus_rx.recv().await {
+						match event {
+							CoreEvent::Library { library_id: id, event } if id == library_id => {
+								yield event
+							}
+							_ => {}
+						}
+					}
+				}
+			})
+		})
 }
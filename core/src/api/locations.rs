@@ -1,20 +1,70 @@
 use crate::{
 	invalidate_query,
+	job::Job,
+	library::LISTING_QUERY,
 	location::{
+		automation::{
+			list_automation_runs, list_automations, AutomationError, AutomationRuleCreateArgs,
+			AutomationRuleUpdateArgs, AutomationRunsArgs,
+		},
+		catalog::{availability, set_catalog, LocationAvailability, SetCatalogArgs},
+		custom_metadata::{
+			get_metadata, location_fields, search_metadata, set_metadata, MetadataFieldCreateArgs,
+			MetadataFieldUpdateArgs, MetadataSearchArgs, SetMetadataArgs,
+		},
+		eraser::{LocationEraserJob, LocationEraserJobInit},
 		fetch_location,
-		indexer::{indexer_job::indexer_job_location, rules::IndexerRuleCreateArgs},
-		scan_location, LocationCreateArgs, LocationError, LocationUpdateArgs,
+		health::{check_location, repair_location},
+		indexer::{
+			indexer_job::indexer_job_location, rescan_plan::plan_rescan,
+			rules::IndexerRuleCreateArgs,
+		},
+		listing::{file_path_with_object, list_directory, DirectoryListArgs},
+		network::NetworkLocationCreateArgs,
+		pipeline::pipeline_report,
+		rescan_path,
+		rule_bundle::{export_rules, import_rules, ImportRulesArgs},
+		scan_location,
+		storage::StorageLocationCreateArgs,
+		sync_storage_location,
+		tombstone::{clear_deleted, recently_deleted, RecentlyDeletedArgs},
+		treemap::{treemap, TreemapArgs},
+		LocationCreateArgs, LocationError, LocationUpdateArgs,
+	},
+	object::importer::{ImportMetadataJob, ImportMetadataJobInit},
+	prisma::{
+		automation_rule, git_repository, indexer_rule, indexer_rules_in_location, location,
+		metadata_field, object, tag,
 	},
-	object::preview::THUMBNAIL_CACHE_DIR_NAME,
-	prisma::{file_path, indexer_rule, indexer_rules_in_location, location, object, tag},
 };
 
 use rspc::{self, internal::MiddlewareBuilderLike, ErrorCode, Type};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use std::path::PathBuf;
 
 use super::{utils::LibraryRequest, Ctx, RouterBuilder};
 
+#[derive(Deserialize, Type, Debug)]
+pub struct RepairLocationArgs {
+	pub location_id: i32,
+	/// where the location's files are now, the one its health check found when not given
+	pub new_root: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct SyncStorageArgs {
+	pub location_id: i32,
+	/// list the whole storage instead of reading its change feed
+	pub full: bool,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct RescanPathArgs {
+	pub location_id: i32,
+	/// the directory to rescan, relative to the location
+	pub sub_path: String,
+}
+
 #[derive(Serialize, Deserialize, Type, Debug)]
 #[serde(tag = "type")]
 pub enum ExplorerContext {
@@ -33,11 +83,14 @@ pub enum ExplorerItem {
 #[derive(Serialize, Deserialize, Type, Debug)]
 pub struct ExplorerData {
 	pub context: ExplorerContext,
+	/// whether the items can be opened, when they're all of one location
+	pub availability: Option<LocationAvailability>,
 	pub items: Vec<ExplorerItem>,
+	/// where to read the next page of items from, if there's more of them
+	pub next_cursor: Option<String>,
 }
 
-file_path::include!(file_path_with_object { object });
-object::include!(object_with_file_paths { file_paths });
+object::include!(object_with_file_paths { file_paths media_data });
 
 // TODO(@Oscar): This return type sucks. Add an upstream rspc solution.
 pub(crate) fn mount() -> rspc::RouterBuilder<
@@ -87,50 +140,36 @@ pub(crate) fn mount() -> rspc::RouterBuilder<
 						rspc::Error::new(ErrorCode::NotFound, "Location not found".into())
 					})?;
 
-				let directory = library
-					.db
-					.file_path()
-					.find_first(vec![
-						file_path::location_id::equals(location.id),
-						file_path::materialized_path::equals(args.path),
-						file_path::is_dir::equals(true),
-					])
-					.exec()
-					.await?
-					.ok_or_else(|| {
-						rspc::Error::new(ErrorCode::NotFound, "Directory not found".into())
-					})?;
-
-				let file_paths = library
-					.db
-					.file_path()
-					.find_many(vec![
-						file_path::location_id::equals(location.id),
-						file_path::parent_id::equals(Some(directory.id)),
-					])
-					.include(file_path_with_object::include())
-					.exec()
+				let page = library
+					.query_cache
+					.get_or_insert(
+						LISTING_QUERY,
+						&args,
+						Some(location.id),
+						list_directory(
+							&library,
+							DirectoryListArgs {
+								location_id: location.id,
+								path: args.path.clone(),
+								sort: None,
+								descending: false,
+								collation: None,
+								cursor: args.cursor.clone(),
+								take: u16::try_from(args.limit).ok(),
+							},
+						),
+					)
 					.await?;
 
 				Ok(ExplorerData {
+					availability: Some((&location).into()),
 					context: ExplorerContext::Location(location),
-					items: file_paths
+					items: page
+						.items
 						.into_iter()
-						.map(|mut file_path| {
-							if let Some(object) = &mut file_path.object.as_mut() {
-								// TODO: Use helper function to build this url as as the Rust file loading layer
-								let thumb_path = library
-									.config()
-									.data_directory()
-									.join(THUMBNAIL_CACHE_DIR_NAME)
-									.join(&object.cas_id)
-									.with_extension("webp");
-
-								object.has_thumbnail = thumb_path.try_exists().unwrap();
-							}
-							ExplorerItem::Path(Box::new(file_path))
-						})
+						.map(|file_path| ExplorerItem::Path(Box::new(file_path)))
 						.collect(),
+					next_cursor: page.next_cursor,
 				})
 			})
 		})
@@ -141,6 +180,38 @@ pub(crate) fn mount() -> rspc::RouterBuilder<
 				Ok(())
 			})
 		})
+		.library_mutation("createNetwork", |t| {
+			t(|_, args: NetworkLocationCreateArgs, library| async move {
+				let location = args.create(&library).await?;
+				scan_location(&library, location).await?;
+				Ok(())
+			})
+		})
+		.library_mutation("createStorage", |t| {
+			t(|_, args: StorageLocationCreateArgs, library| async move {
+				let location = args.create(&library).await?;
+				scan_location(&library, location).await?;
+				Ok(())
+			})
+		})
+		// whether the files of each location can be opened, to tell apart results from offline ones
+		.library_query("getAvailability", |t| {
+			t(|_, _: (), library| async move { Ok(availability(&library).await?) })
+		})
+		// makes a location a catalog, indexed in full once to be browsed offline, or a regular one again
+		.library_mutation("setCatalog", |t| {
+			t(|_, args: SetCatalogArgs, library| async move {
+				set_catalog(&library, args).await?;
+				invalidate_query!(library, "locations.getAvailability");
+				Ok(())
+			})
+		})
+		// the stages of the location's last pipeline, and how far along it is
+		.library_query("getPipeline", |t| {
+			t(|ctx, location_id: i32, library| async move {
+				Ok(pipeline_report(&library, &ctx.jobs, location_id).await?)
+			})
+		})
 		.library_mutation("update", |t| {
 			t(|_, args: LocationUpdateArgs, library| async move {
 				args.update(&library).await.map_err(Into::into)
@@ -148,36 +219,35 @@ pub(crate) fn mount() -> rspc::RouterBuilder<
 		})
 		.library_mutation("delete", |t| {
 			t(|_, location_id: i32, library| async move {
-				library
-					.db
-					.file_path()
-					.delete_many(vec![file_path::location_id::equals(location_id)])
-					.exec()
-					.await?;
-
-				library
-					.db
-					.indexer_rules_in_location()
-					.delete_many(vec![indexer_rules_in_location::location_id::equals(
-						location_id,
-					)])
+				fetch_location(&library, location_id)
 					.exec()
-					.await?;
+					.await?
+					.ok_or(LocationError::IdNotFound(location_id))?;
 
+				// the location is removed once everything that belongs to it has been cleaned up
 				library
-					.db
-					.location()
-					.delete(location::id::equals(location_id))
-					.exec()
-					.await?;
-
-				invalidate_query!(library, "locations.list");
-
-				info!("Location {} deleted", location_id);
+					.spawn_job(Job::new(
+						LocationEraserJobInit { location_id },
+						Box::new(LocationEraserJob {}),
+					))
+					.await;
 
 				Ok(())
 			})
 		})
+		// looks for the location's root and a sample of its files, and for where they are now when
+		// the location was moved
+		.library_query("check", |t| {
+			t(|_, location_id: i32, library| async move {
+				Ok(check_location(&library, location_id).await?)
+			})
+		})
+		// points a moved location at where its files are now, without reindexing it
+		.library_mutation("repair", |t| {
+			t(|_, args: RepairLocationArgs, library| async move {
+				Ok(repair_location(&library, args.location_id, args.new_root).await?)
+			})
+		})
 		.library_mutation("fullRescan", |t| {
 			t(|_, location_id: i32, library| async move {
 				scan_location(
@@ -192,6 +262,90 @@ pub(crate) fn mount() -> rspc::RouterBuilder<
 				.map_err(Into::into)
 			})
 		})
+		// indexes and identifies a single directory of the location again
+		.library_mutation("rescanPath", |t| {
+			t(|_, args: RescanPathArgs, library| async move {
+				rescan_path(
+					&library,
+					fetch_location(&library, args.location_id)
+						.include(indexer_job_location::include())
+						.exec()
+						.await?
+						.ok_or(LocationError::IdNotFound(args.location_id))?,
+					&args.sub_path,
+				)
+				.await
+				.map_err(Into::into)
+			})
+		})
+		.library_query("planRescan", |t| {
+			t(|_, location_id: i32, library| async move {
+				let location = fetch_location(&library, location_id)
+					.include(indexer_job_location::include())
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(location_id))?;
+				let location_path = location
+					.local_path
+					.clone()
+					.ok_or(LocationError::MissingLocalPath(location_id))?;
+
+				Ok(plan_rescan(&library, &location, location_path.as_ref()).await?)
+			})
+		})
+		.library_query("getTreemap", |t| {
+			t(|_, args: TreemapArgs, library| async move { Ok(treemap(&library, args).await?) })
+		})
+		// the git repositories found in a location when it was last indexed
+		.library_query("getGitRepositories", |t| {
+			t(|_, location_id: i32, library| async move {
+				Ok(library
+					.db
+					.git_repository()
+					.find_many(vec![git_repository::location_id::equals(location_id)])
+					.exec()
+					.await?)
+			})
+		})
+		.library_mutation("syncStorage", |t| {
+			t(|_, args: SyncStorageArgs, library| async move {
+				sync_storage_location(
+					&library,
+					fetch_location(&library, args.location_id)
+						.include(indexer_job_location::include())
+						.exec()
+						.await?
+						.ok_or(LocationError::IdNotFound(args.location_id))?,
+					args.full,
+				)
+				.await
+				.map_err(Into::into)
+			})
+		})
+		// what scans found gone from disk, the latest first
+		.library_query("recentlyDeleted", |t| {
+			t(|_, args: RecentlyDeletedArgs, library| async move {
+				Ok(recently_deleted(&library, args).await?)
+			})
+		})
+		.library_mutation("clearDeleted", |t| {
+			t(|_, location_id: Option<i32>, library| async move {
+				Ok(clear_deleted(&library, location_id).await?)
+			})
+		})
+		// the automation and indexer rules of a location, or of the library, as JSON to share them
+		.library_query("exportRules", |t| {
+			t(|_, location_id: Option<i32>, library| async move {
+				Ok(export_rules(&library, location_id).await?)
+			})
+		})
+		.library_mutation("importRules", |t| {
+			t(
+				|_, args: ImportRulesArgs, library| async move {
+					Ok(import_rules(&library, args).await?)
+				},
+			)
+		})
 		.library_mutation("quickRescan", |t| {
 			t(|_, _: (), _| async move {
 				#[allow(unreachable_code)]
@@ -199,6 +353,145 @@ pub(crate) fn mount() -> rspc::RouterBuilder<
 			})
 		})
 		.merge("indexer_rules.", mount_indexer_rule_routes())
+		.merge("metadata.", mount_metadata_routes())
+		.merge("automations.", mount_automation_routes())
+}
+
+fn mount_automation_routes() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.library_query("list", |t| {
+			t(|_, location_id: Option<i32>, library| async move {
+				Ok(list_automations(&library, location_id).await?)
+			})
+		})
+		.library_mutation("create", |t| {
+			t(|_, args: AutomationRuleCreateArgs, library| async move {
+				let rule = args.create(&library).await?;
+				invalidate_query!(library, "locations.automations.list");
+				Ok(rule)
+			})
+		})
+		.library_mutation("update", |t| {
+			t(|_, args: AutomationRuleUpdateArgs, library| async move {
+				let rule = args.update(&library).await?;
+				invalidate_query!(library, "locations.automations.list");
+				Ok(rule)
+			})
+		})
+		.library_mutation("delete", |t| {
+			t(|_, rule_id: i32, library| async move {
+				let deleted = library
+					.db
+					.automation_rule()
+					.delete_many(vec![automation_rule::id::equals(rule_id)])
+					.exec()
+					.await?;
+				if deleted == 0 {
+					return Err(AutomationError::NotFound(rule_id).into());
+				}
+
+				invalidate_query!(library, "locations.automations.list");
+				invalidate_query!(library, "locations.automations.listRuns");
+				Ok(())
+			})
+		})
+		// the audit log of what rules did to files
+		.library_query("listRuns", |t| {
+			t(|_, args: AutomationRunsArgs, library| async move {
+				Ok(list_automation_runs(&library, args).await?)
+			})
+		})
+}
+
+fn mount_metadata_routes() -> RouterBuilder {
+	#[derive(Deserialize, Type, Debug)]
+	pub struct GetMetadataArgs {
+		pub location_id: i32,
+		pub file_path_ids: Vec<i32>,
+	}
+
+	#[derive(Deserialize, Type, Debug)]
+	pub struct ImportMetadataArgs {
+		pub location_id: i32,
+		/// import again into a location that was imported before
+		pub force: bool,
+	}
+
+	<RouterBuilder>::new()
+		.library_query("listFields", |t| {
+			t(|_, location_id: i32, library| async move {
+				Ok(location_fields(&library, location_id).await?)
+			})
+		})
+		.library_mutation("createField", |t| {
+			t(|_, args: MetadataFieldCreateArgs, library| async move {
+				let field = args.create(&library).await?;
+				invalidate_query!(library, "locations.metadata.listFields");
+				Ok(field)
+			})
+		})
+		.library_mutation("updateField", |t| {
+			t(|_, args: MetadataFieldUpdateArgs, library| async move {
+				let field = args.update(&library).await?;
+				invalidate_query!(library, "locations.metadata.listFields");
+				Ok(field)
+			})
+		})
+		.library_mutation("deleteField", |t| {
+			t(|_, field_id: i32, library| async move {
+				library
+					.db
+					.metadata_field()
+					.delete(metadata_field::id::equals(field_id))
+					.exec()
+					.await?;
+
+				invalidate_query!(library, "locations.metadata.listFields");
+				invalidate_query!(library, "locations.metadata.get");
+				Ok(())
+			})
+		})
+		.library_query("get", |t| {
+			t(|_, args: GetMetadataArgs, library| async move {
+				Ok(get_metadata(&library, args.location_id, args.file_path_ids).await?)
+			})
+		})
+		.library_mutation("set", |t| {
+			t(|_, args: SetMetadataArgs, library| async move {
+				set_metadata(&library, args).await?;
+				invalidate_query!(library, "locations.metadata.get");
+				Ok(())
+			})
+		})
+		.library_query("search", |t| {
+			t(|_, args: MetadataSearchArgs, library| async move {
+				Ok(search_metadata(&library, args).await?)
+			})
+		})
+		// imports the tags and metadata other tools left on the location's files, once
+		.library_mutation("import", |t| {
+			t(|_, args: ImportMetadataArgs, library| async move {
+				if fetch_location(&library, args.location_id)
+					.exec()
+					.await?
+					.is_none()
+				{
+					return Err(LocationError::IdNotFound(args.location_id).into());
+				}
+
+				library
+					.spawn_job(Job::new(
+						ImportMetadataJobInit {
+							location_id: args.location_id,
+							force: args.force,
+						},
+						Box::new(ImportMetadataJob {}),
+					))
+					.await;
+
+				Ok(())
+			})
+		})
 }
 
 fn mount_indexer_rule_routes() -> RouterBuilder {
@@ -3,14 +3,19 @@ use std::{
 	time::{Duration, Instant},
 };
 
+use once_cell::sync::OnceCell;
 use rspc::{Config, Type};
 use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast;
+use uuid::Uuid;
 
 use crate::{
-	job::JobManager,
+	extension::ExtensionRegistry,
+	job::{JobManager, JobStatus},
 	library::LibraryManager,
+	location::space_monitor::SpaceAlertReason,
 	node::{NodeConfig, NodeConfigManager},
+	util::{conflict::ConflictNaming, logging, message::Message},
 };
 
 use utils::{InvalidRequests, InvalidateOperationEvent};
@@ -18,12 +23,94 @@ use utils::{InvalidRequests, InvalidateOperationEvent};
 pub type Router = rspc::Router<Ctx>;
 pub(crate) type RouterBuilder = rspc::RouterBuilder<Ctx>;
 
+/// The version of the API, bumped whenever a procedure is removed or changes its arguments or result
+/// in a way existing clients can't handle. New procedures don't need a bump, clients find them in
+/// the procedures listed by the handshake.
+pub const API_VERSION: u32 = 1;
+/// The oldest API version a client can be built against and still work with this core
+pub const MIN_CLIENT_API_VERSION: u32 = 1;
+
+/// The procedures mounted on the router, filled in once it's built so the handshake can list them
+static PROCEDURES: OnceCell<Procedures> = OnceCell::new();
+
 /// Represents an internal core event, these are exposed to client via a rspc subscription.
 #[derive(Debug, Clone, Serialize, Type)]
 pub enum CoreEvent {
-	NewThumbnail { cas_id: String },
+	NewThumbnail {
+		cas_id: String,
+	},
 	InvalidateOperation(InvalidateOperationEvent),
 	InvalidateOperationDebounced(InvalidateOperationEvent),
+	#[cfg(feature = "p2p")]
+	P2P(crate::p2p::P2PEvent),
+	Library {
+		library_id: Uuid,
+		event: LibraryEvent,
+	},
+}
+
+/// A change to the state of a library, streamed to clients by the `library.events` subscription so
+/// they can update live instead of polling.
+#[derive(Debug, Clone, Serialize, Type)]
+#[serde(tag = "type")]
+pub enum LibraryEvent {
+	JobStarted {
+		job_id: Uuid,
+		name: String,
+	},
+	JobProgress {
+		job_id: Uuid,
+		task_count: i32,
+		completed_task_count: i32,
+		message: Option<Message>,
+	},
+	/// a job stopped running, `status` tells whether it completed, failed or was paused and
+	/// `message` why it failed
+	JobCompleted {
+		job_id: Uuid,
+		name: String,
+		status: JobStatus,
+		message: Option<Message>,
+	},
+	/// a batch of file paths was written to the index of a location
+	FilesIndexed {
+		location_id: i32,
+		count: usize,
+	},
+	LocationAdded {
+		location_id: i32,
+	},
+	LocationUpdated {
+		location_id: i32,
+	},
+	LocationRemoved {
+		location_id: i32,
+	},
+	/// a tag was created, changed or assigned to or removed from an object
+	TagUpdated {
+		tag_id: i32,
+	},
+	TagRemoved {
+		tag_id: i32,
+	},
+	/// integrity sampling found a volume failing more and more, which is how a disk going bad shows up.
+	/// Volumes without a fingerprint are told apart by their location.
+	IntegrityAlert {
+		volume_serial: Option<String>,
+		location_id: Option<i32>,
+		sampled: i32,
+		corrupted: i32,
+		unreadable: i32,
+	},
+	/// a volume backing a location is running out of space, or something planned to be written to it
+	/// wouldn't fit. Volumes without a serial are told apart by their mount point.
+	SpaceAlert {
+		volume: String,
+		mount_point: String,
+		total_bytes: u64,
+		available_bytes: u64,
+		reason: SpaceAlertReason,
+	},
 }
 
 /// Is provided when executing the router from the request.
@@ -32,14 +119,33 @@ pub struct Ctx {
 	pub config: Arc<NodeConfigManager>,
 	pub jobs: Arc<JobManager>,
 	pub event_bus: broadcast::Sender<CoreEvent>,
+	pub extensions: Arc<ExtensionRegistry>,
+	/// `None` when p2p networking failed to start
+	#[cfg(feature = "p2p")]
+	pub p2p: Option<Arc<crate::p2p::P2PManager>>,
 }
 
+mod audit;
+mod backups;
+mod cache;
+mod chunks;
+mod extensions;
 mod files;
+mod folder_sync;
+mod history;
+mod hooks;
 mod jobs;
 mod keys;
 mod libraries;
 mod locations;
+mod nodes;
 mod normi;
+#[cfg(feature = "p2p")]
+mod p2p;
+mod people;
+mod selections;
+mod shares;
+mod sync;
 mod tags;
 pub mod utils;
 pub mod volumes;
@@ -51,6 +157,64 @@ struct NodeState {
 	data_path: String,
 }
 
+#[derive(Serialize, Type, Debug, Clone, Default)]
+struct Procedures {
+	queries: Vec<String>,
+	mutations: Vec<String>,
+	subscriptions: Vec<String>,
+}
+
+/// Optional functionality that depends on how the core was built or the platform it runs on
+#[derive(Serialize, Type, Debug)]
+enum Capability {
+	P2P,
+	VideoThumbnails,
+	NetworkLocations,
+}
+
+#[derive(Deserialize, Type, Debug)]
+struct HandshakeArgs {
+	/// the API version the client was built against
+	api_version: u32,
+}
+
+/// The result of the handshake clients make when connecting, telling them what this core supports.
+/// Clients newer than the core compare `api_version` themselves and check `procedures` before
+/// calling anything added after it.
+#[derive(Serialize, Type, Debug)]
+struct Handshake {
+	api_version: u32,
+	min_client_api_version: u32,
+	/// false if the client is too old for this core, in which case it should ask to be updated
+	compatible: bool,
+	capabilities: Vec<Capability>,
+	procedures: Procedures,
+}
+
+impl Handshake {
+	fn new(client_api_version: u32) -> Self {
+		let mut capabilities = vec![];
+		if cfg!(feature = "p2p") {
+			capabilities.push(Capability::P2P);
+		}
+		if cfg!(feature = "ffmpeg") {
+			capabilities.push(Capability::VideoThumbnails);
+		}
+		// mounting shares relies on the platform's mount tools, which aren't there on mobile
+		if !cfg!(feature = "mobile") {
+			capabilities.push(Capability::NetworkLocations);
+		}
+
+		Self {
+			api_version: API_VERSION,
+			min_client_api_version: MIN_CLIENT_API_VERSION,
+			compatible: client_api_version >= MIN_CLIENT_API_VERSION,
+			capabilities,
+			procedures: PROCEDURES.get().cloned().unwrap_or_default(),
+		}
+	}
+}
+
 pub(crate) fn mount() -> Arc<Router> {
 	let config = Config::new().set_ts_bindings_header("/* eslint-disable */");
 
@@ -73,6 +237,9 @@ pub(crate) fn mount() -> Arc<Router> {
 				commit: env!("GIT_HASH"),
 			})
 		})
+		.query("handshake", |t| {
+			t(|_, args: HandshakeArgs| Handshake::new(args.api_version))
+		})
 		.query("nodeState", |t| {
 			t(|ctx, _: ()| async move {
 				Ok(NodeState {
@@ -82,14 +249,47 @@ pub(crate) fn mount() -> Arc<Router> {
 				})
 			})
 		})
+		.query("logLevel", |t| {
+			t(|_, _: ()| async move { Ok(logging::log_level()?) })
+		})
+		// takes directives like `RUST_LOG`, e.g. `warn,sd_core::job=trace`
+		.mutation("setLogLevel", |t| {
+			t(|_, directives: String| async move { Ok(logging::set_log_level(&directives)?) })
+		})
+		// how copies of files are named when a file by the same name is already where they go
+		.mutation("setConflictNaming", |t| {
+			t(|ctx, conflict_naming: ConflictNaming| async move {
+				conflict_naming.validate()?;
+				Ok(ctx
+					.config
+					.write(|mut config| config.conflict_naming = conflict_naming)
+					.await?)
+			})
+		})
 		.merge("normi.", normi::mount())
+		.merge("nodes.", nodes::mount())
 		.merge("library.", libraries::mount())
 		.merge("volumes.", volumes::mount())
 		.merge("tags.", tags::mount())
 		.merge("keys.", keys::mount())
 		.merge("locations.", locations::mount())
 		.merge("files.", files::mount())
+		.merge("folderSync.", folder_sync::mount())
+		.merge("chunks.", chunks::mount())
+		.merge("backups.", backups::mount())
+		.merge("people.", people::mount())
 		.merge("jobs.", jobs::mount())
+		.merge("selections.", selections::mount())
+		.merge("shares.", shares::mount())
+		.merge("history.", history::mount())
+		.merge("sync.", sync::mount())
+		.merge("extensions.", extensions::mount())
+		.merge("hooks.", hooks::mount())
+		.merge("cache.", cache::mount())
+		.merge("audit.", audit::mount());
+	#[cfg(feature = "p2p")]
+	let r = r.merge("p2p.", p2p::mount());
+	let r = r
 		// TODO: Scope the invalidate queries to a specific library (filtered server side)
 		.subscription("invalidateQuery", |t| {
 			t(|ctx, _: ()| {
@@ -116,9 +316,21 @@ pub(crate) fn mount() -> Arc<Router> {
 		.arced();
 	InvalidRequests::validate(r.clone()); // This validates all invalidation calls.
 
+	PROCEDURES.get_or_init(|| Procedures {
+		queries: sorted_keys(r.queries().keys()),
+		mutations: sorted_keys(r.mutations().keys()),
+		subscriptions: sorted_keys(r.subscriptions().keys()),
+	});
+
 	r
 }
 
+fn sorted_keys(keys: impl Iterator<Item = impl ToString>) -> Vec<String> {
+	let mut keys = keys.map(|key| key.to_string()).collect::<Vec<_>>();
+	keys.sort();
+	keys
+}
+
 #[cfg(test)]
 mod tests {
 	/// This test will ensure the rspc router and all calls to `invalidate_query` are valid and also export an updated version of the Typescript bindings.
@@ -126,4 +338,17 @@ mod tests {
 	fn test_and_export_rspc_bindings() {
 		super::mount();
 	}
+
+	#[test]
+	fn handshake_lists_procedures() {
+		super::mount();
+
+		let handshake = super::Handshake::new(super::API_VERSION);
+		assert!(handshake.compatible);
+		assert!(handshake
+			.procedures
+			.queries
+			.contains(&"handshake".to_string()));
+		assert!(!super::Handshake::new(super::MIN_CLIENT_API_VERSION - 1).compatible);
+	}
 }
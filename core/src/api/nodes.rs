@@ -0,0 +1,49 @@
+use rspc::ErrorCode;
+use uuid::Uuid;
+
+use crate::node::ApiToken;
+
+use super::RouterBuilder;
+
+pub(crate) fn mount() -> RouterBuilder {
+	RouterBuilder::new()
+		// the tokens clients connecting over the network use, only their hashes are kept
+		.query("apiTokens", |t| {
+			t(|ctx, _: ()| async move { Ok(ctx.config.get().await.api_tokens) })
+		})
+		// the token is only ever returned here, it should be shown once and then forgotten
+		.mutation("createApiToken", |t| {
+			t(|ctx, name: String| async move {
+				let created = ApiToken::generate(name);
+				let token = created.token.clone();
+				ctx.config
+					.write(|mut config| config.api_tokens.push(token))
+					.await?;
+
+				Ok(created)
+			})
+		})
+		.mutation("revokeApiToken", |t| {
+			t(|ctx, id: Uuid| async move {
+				if !ctx
+					.config
+					.get()
+					.await
+					.api_tokens
+					.iter()
+					.any(|token| token.id == id)
+				{
+					return Err(rspc::Error::new(
+						ErrorCode::NotFound,
+						format!("API token <id={id}> not found"),
+					));
+				}
+
+				ctx.config
+					.write(|mut config| config.api_tokens.retain(|token| token.id != id))
+					.await?;
+
+				Ok(())
+			})
+		})
+}
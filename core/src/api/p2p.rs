@@ -1,78 +1,320 @@
-// use std::collections::HashMap;
-
-// use p2p::PeerId;
-// use rspc::Type;
-// use serde::Deserialize;
-
-// use super::{LibraryArgs, RouterBuilder};
-
-// #[derive(Type, Deserialize)]
-// pub struct AcceptPairingRequestArgs {
-// 	pub peer_id: PeerId,
-// 	pub preshared_key: String,
-// }
-
-// pub(crate) fn mount() -> RouterBuilder {
-// 	RouterBuilder::new()
-// 		.query("getNodes", |ctx, arg: LibraryArgs<()>| async move {
-// 			let (_, library) = arg.get_library(&ctx).await?;
-
-// 			Ok(
-// 				library.db.node().find_many(vec![]).exec().await?, // TODO: Make this work
-// 				                                                   // .into_iter()
-// 				                                                   // .filter_map(|v| {
-// 				                                                   // 	if v.id == ctx.node_local_id {
-// 				                                                   // 		None
-// 				                                                   // 	} else {
-// 				                                                   // 		Some(v.into())
-// 				                                                   // 	}
-// 				                                                   // })
-// 				                                                   // .collect::<Vec<LibraryNode>>()
-// 			)
-// 		})
-// 		.query("connectedPeers", |ctx, _: ()| async move {
-// 			ctx.p2p
-// 				.nm
-// 				.connected_peers()
-// 				.into_iter()
-// 				.map(|(_, v)| (v.id, v.metadata))
-// 				.collect::<HashMap<_, _>>()
-// 		})
-// 		.query("discoveredPeers", |ctx, _: ()| async move {
-// 			ctx.p2p
-// 				.nm
-// 				.discovered_peers()
-// 				.into_iter()
-// 				// TODO: Make this better
-// 				.map(|(_, v)| v)
-// 				.collect::<Vec<_>>()
-// 		})
-// 		.mutation("pairNode", |ctx, arg: LibraryArgs<PeerId>| async move {
-// 			let (peer_id, library) = arg.get_library(&ctx).await?;
-
-// 			let preshared_key = ctx.p2p.pair(&library, peer_id).await.unwrap();
-
-// 			// TODO: These aren't library queries so they can't be invalidated with the current system. We can fix this with the normalised cache!
-// 			// invalidate_query!(ctx, "p2p.discoveredPeers": (), ());
-// 			// invalidate_query!(ctx, "p2p.connectedPeers": (), ());
-
-// 			Ok(preshared_key)
-// 		})
-// 		.mutation(
-// 			"unpairNode",
-// 			|_, _: LibraryArgs<PeerId>| async move { todo!() },
-// 		)
-// 		.mutation(
-// 			"acceptPairingRequest",
-// 			|ctx, arg: AcceptPairingRequestArgs| async move {
-// 				ctx.p2p
-// 					.pairing_requests
-// 					.lock()
-// 					.unwrap()
-// 					.remove(&arg.peer_id)
-// 					.unwrap()
-// 					.send(Ok(arg.preshared_key))
-// 					.unwrap(); // TODO: Remove unwrap
-// 			},
-// 		)
-// }
+use std::{path::PathBuf, sync::Arc};
+
+use int_enum::IntEnum;
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	location::fetch_location,
+	p2p::{P2PError, P2PManager, PeerTrust},
+	prisma::{file_path, location, location_share, paired_peer},
+	util::os_path::resolve_materialized_path,
+};
+
+use super::{utils::LibraryRequest, CoreEvent, Ctx, RouterBuilder};
+
+#[derive(Type, Serialize)]
+pub struct P2PState {
+	pub peer_id: String,
+}
+
+#[derive(Type, Deserialize)]
+pub struct PairArgs {
+	pub peer_id: String,
+	/// what the peer can do once paired, full sync if not given
+	#[serde(default)]
+	pub trust: PeerTrust,
+}
+
+#[derive(Type, Deserialize)]
+pub struct AcceptPairingArgs {
+	pub id: Uuid,
+	/// the code shown on the other node, as typed by the user
+	pub code: Option<String>,
+	/// the payload of the QR code shown on the other node, instead of `code`
+	pub qr_payload: Option<String>,
+	/// what the peer can do once paired, full sync if not given
+	#[serde(default)]
+	pub trust: PeerTrust,
+}
+
+#[derive(Type, Deserialize)]
+pub struct SetPeerTrustArgs {
+	pub peer_id: String,
+	pub trust: PeerTrust,
+}
+
+#[derive(Type, Deserialize)]
+pub struct SpacedropArgs {
+	pub peer_id: String,
+	pub location_id: i32,
+	/// files to send, directories are skipped
+	pub path_ids: Vec<i32>,
+}
+
+#[derive(Type, Deserialize)]
+pub struct AcceptSpacedropArgs {
+	pub id: Uuid,
+	/// directory to receive the files into
+	pub target_path: PathBuf,
+}
+
+#[derive(Type, Deserialize)]
+pub struct ShareLocationArgs {
+	pub location_id: i32,
+	pub peer_id: String,
+	/// whether the peer can browse the location, false stops sharing it
+	pub shared: bool,
+}
+
+#[derive(Type, Deserialize)]
+pub struct RemoteBrowseArgs {
+	pub peer_id: String,
+	pub library_id: Uuid,
+	pub location_id: i32,
+	/// materialized path of the directory, the root of the location if empty
+	pub path: String,
+}
+
+#[derive(Type, Deserialize)]
+pub struct RemoteSearchArgs {
+	pub peer_id: String,
+	pub query: String,
+}
+
+#[derive(Type, Deserialize)]
+pub struct RemoteFetchArgs {
+	pub peer_id: String,
+	pub library_id: Uuid,
+	pub location_id: i32,
+	pub file_path_id: i32,
+	/// where to save the file on this node
+	pub target_path: PathBuf,
+}
+
+#[derive(Type, Deserialize)]
+pub struct RemoteLibraryArgs {
+	pub peer_id: String,
+	pub library_id: Uuid,
+}
+
+fn p2p(ctx: &Ctx) -> Result<Arc<P2PManager>, P2PError> {
+	ctx.p2p.clone().ok_or(P2PError::NotRunning)
+}
+
+pub(crate) fn mount() -> RouterBuilder {
+	RouterBuilder::new()
+		.query("state", |t| {
+			t(|ctx, _: ()| async move {
+				Ok(P2PState {
+					peer_id: p2p(&ctx)?.peer_id(),
+				})
+			})
+		})
+		.query("discovered", |t| {
+			t(|ctx, _: ()| async move { Ok(p2p(&ctx)?.discovered_peers().await?) })
+		})
+		.library_query("paired", |t| {
+			t(|_, _: (), library| async move {
+				Ok(library.db.paired_peer().find_many(vec![]).exec().await?)
+			})
+		})
+		.mutation("pair", |t| {
+			t(
+				|ctx, args: PairArgs| async move {
+					Ok(p2p(&ctx)?.pair(args.peer_id, args.trust).await?)
+				},
+			)
+		})
+		.mutation("acceptPairing", |t| {
+			t(|ctx, args: AcceptPairingArgs| async move {
+				Ok(p2p(&ctx)?.accept_pairing(args.id, args.code, args.qr_payload, args.trust)?)
+			})
+		})
+		// changes what a paired peer can do in the library, checked on its next request
+		.library_mutation("setPeerTrust", |t| {
+			t(|_, args: SetPeerTrustArgs, library| async move {
+				library
+					.db
+					.paired_peer()
+					.update(
+						paired_peer::peer_id::equals(args.peer_id),
+						vec![paired_peer::trust::set(args.trust.int_value())],
+					)
+					.exec()
+					.await?;
+
+				invalidate_query!(library, "p2p.paired");
+				Ok(())
+			})
+		})
+		.mutation("rejectPairing", |t| {
+			t(|ctx, id: Uuid| async move { Ok(p2p(&ctx)?.reject_pairing(id)?) })
+		})
+		.mutation("unpair", |t| {
+			t(|ctx, peer_id: String| async move { Ok(p2p(&ctx)?.unpair(peer_id).await?) })
+		})
+		.library_mutation("spacedrop", |t| {
+			t(|ctx, args: SpacedropArgs, library| async move {
+				let location = fetch_location(&library, args.location_id)
+					.exec()
+					.await?
+					.ok_or_else(|| {
+						rspc::Error::new(ErrorCode::NotFound, "Location not found".into())
+					})?;
+
+				let location_path = location.local_path.map(PathBuf::from).ok_or_else(|| {
+					rspc::Error::new(ErrorCode::BadRequest, "Location has no local path".into())
+				})?;
+
+				let paths = library
+					.db
+					.file_path()
+					.find_many(vec![
+						file_path::location_id::equals(args.location_id),
+						file_path::id::in_vec(args.path_ids),
+						file_path::is_dir::equals(false),
+					])
+					.exec()
+					.await?
+					.into_iter()
+					.map(|file_path| {
+						resolve_materialized_path(
+							&location_path,
+							&file_path.materialized_path,
+							file_path.raw_path.as_deref(),
+						)
+					})
+					.collect::<Vec<_>>();
+
+				if paths.is_empty() {
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"No files to send".into(),
+					));
+				}
+
+				Ok(p2p(&ctx)?.spacedrop(args.peer_id, paths).await?)
+			})
+		})
+		.mutation("acceptSpacedrop", |t| {
+			t(|ctx, args: AcceptSpacedropArgs| async move {
+				Ok(p2p(&ctx)?.accept_spacedrop(args.id, args.target_path)?)
+			})
+		})
+		.mutation("rejectSpacedrop", |t| {
+			t(|ctx, id: Uuid| async move { Ok(p2p(&ctx)?.reject_spacedrop(id)?) })
+		})
+		.library_query("locationShares", |t| {
+			t(|_, location_id: i32, library| async move {
+				Ok(library
+					.db
+					.location_share()
+					.find_many(vec![location_share::location_id::equals(location_id)])
+					.exec()
+					.await?)
+			})
+		})
+		.library_mutation("shareLocation", |t| {
+			t(|_, args: ShareLocationArgs, library| async move {
+				if !args.shared {
+					library
+						.db
+						.location_share()
+						.delete_many(vec![
+							location_share::location_id::equals(args.location_id),
+							location_share::peer_id::equals(args.peer_id),
+						])
+						.exec()
+						.await?;
+					return Ok(());
+				}
+
+				// only paired peers can connect, so sharing with anyone else would do nothing
+				library
+					.db
+					.paired_peer()
+					.find_unique(paired_peer::peer_id::equals(args.peer_id.clone()))
+					.exec()
+					.await?
+					.ok_or_else(|| {
+						rspc::Error::new(ErrorCode::BadRequest, "Peer isn't paired".into())
+					})?;
+
+				library
+					.db
+					.location_share()
+					.upsert(
+						location_share::location_id_peer_id(args.location_id, args.peer_id.clone()),
+						(args.peer_id, location::id::equals(args.location_id), vec![]),
+						vec![],
+					)
+					.exec()
+					.await?;
+
+				Ok(())
+			})
+		})
+		.query("remoteLocations", |t| {
+			t(|ctx, peer_id: String| async move { Ok(p2p(&ctx)?.remote_locations(peer_id).await?) })
+		})
+		.query("remoteBrowse", |t| {
+			t(|ctx, args: RemoteBrowseArgs| async move {
+				Ok(p2p(&ctx)?
+					.remote_browse(args.peer_id, args.library_id, args.location_id, args.path)
+					.await?)
+			})
+		})
+		.query("remoteSearch", |t| {
+			t(|ctx, args: RemoteSearchArgs| async move {
+				Ok(p2p(&ctx)?.remote_search(args.peer_id, args.query).await?)
+			})
+		})
+		.mutation("remoteFetch", |t| {
+			t(|ctx, args: RemoteFetchArgs| async move {
+				Ok(p2p(&ctx)?
+					.remote_fetch(
+						args.peer_id,
+						args.library_id,
+						args.location_id,
+						args.file_path_id,
+						args.target_path,
+					)
+					.await?)
+			})
+		})
+		// the libraries a peer can set this node up with
+		.query("remoteLibraries", |t| {
+			t(|ctx, peer_id: String| async move { Ok(p2p(&ctx)?.remote_libraries(peer_id).await?) })
+		})
+		// sets this node up with a library from a peer, instead of indexing everything again
+		.mutation("cloneLibrary", |t| {
+			t(|ctx, args: RemoteLibraryArgs| async move {
+				Ok(p2p(&ctx)?
+					.clone_library(args.peer_id, args.library_id)
+					.await?)
+			})
+		})
+		// pulls what changed in a library from a peer, returning how many operations were ingested
+		.mutation("syncLibrary", |t| {
+			t(|ctx, args: RemoteLibraryArgs| async move {
+				Ok(p2p(&ctx)?
+					.sync_library(args.peer_id, args.library_id)
+					.await?)
+			})
+		})
+		.subscription("events", |t| {
+			t(|ctx, _: ()| {
+				let mut event_bus_rx = ctx.event_bus.subscribe();
+				async_stream::stream! {
+					while let Ok(event) = event_bus_rx.recv().await {
+						if let CoreEvent::P2P(event) = event {
+							yield event;
+						}
+					}
+				}
+			})
+		})
+}
@@ -0,0 +1,94 @@
+use crate::{
+	invalidate_query,
+	object::faces::{
+		list_people, merge_people, rename_person, split_person, FaceInfo, MergePeopleArgs,
+		RenamePersonArgs,
+	},
+	prisma::{face, object, person},
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.library_query("list", |t| {
+			t(|_, _: (), library| async move { Ok(list_people(&library).await?) })
+		})
+		// the faces of a person, to crop out of their objects' thumbnails
+		.library_query("getFaces", |t| {
+			t(|_, person_id: i32, library| async move {
+				Ok(library
+					.db
+					.face()
+					.find_many(vec![face::person_id::equals(Some(person_id))])
+					.exec()
+					.await?
+					.into_iter()
+					.map(FaceInfo::from)
+					.collect::<Vec<_>>())
+			})
+		})
+		.library_query("getForObject", |t| {
+			t(|_, object_id: i32, library| async move {
+				Ok(library
+					.db
+					.face()
+					.find_many(vec![face::object_id::equals(object_id)])
+					.exec()
+					.await?
+					.into_iter()
+					.map(FaceInfo::from)
+					.collect::<Vec<_>>())
+			})
+		})
+		// the photos a person is in
+		.library_query("getObjects", |t| {
+			t(|_, person_id: i32, library| async move {
+				Ok(library
+					.db
+					.object()
+					.find_many(vec![object::faces::some(vec![face::person_id::equals(
+						Some(person_id),
+					)])])
+					.exec()
+					.await?)
+			})
+		})
+		// the photos of people whose name contains `search`
+		.library_query("search", |t| {
+			t(|_, search: String, library| async move {
+				Ok(library
+					.db
+					.object()
+					.find_many(vec![object::faces::some(vec![face::person::is(vec![
+						person::name::contains(search),
+					])])])
+					.exec()
+					.await?)
+			})
+		})
+		.library_mutation("rename", |t| {
+			t(|_, args: RenamePersonArgs, library| async move {
+				rename_person(&library, args).await?;
+				invalidate_query!(library, "people.list");
+				Ok(())
+			})
+		})
+		.library_mutation("merge", |t| {
+			t(|_, args: MergePeopleArgs, library| async move {
+				merge_people(&library, args).await?;
+				invalidate_query!(library, "people.list");
+				invalidate_query!(library, "people.getFaces");
+				Ok(())
+			})
+		})
+		// moves faces that aren't of their person to a new one, returning its id
+		.library_mutation("split", |t| {
+			t(|_, face_ids: Vec<i32>, library| async move {
+				let person_id = split_person(&library, face_ids).await?;
+				invalidate_query!(library, "people.list");
+				invalidate_query!(library, "people.getFaces");
+				Ok(person_id)
+			})
+		})
+}
@@ -0,0 +1,63 @@
+use rspc::Type;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+	library::{selection_info, summarise, SelectionItem, SelectionTarget},
+	object::search::{search_items, FileSearchArgs},
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+#[derive(Type, Deserialize)]
+pub struct SelectionItemsArgs {
+	pub id: Uuid,
+	pub items: Vec<SelectionItem>,
+}
+
+pub(crate) fn mount() -> RouterBuilder {
+	RouterBuilder::new()
+		.library_mutation("create", |t| {
+			t(|_, _: (), library| async move { Ok(library.selections.create()) })
+		})
+		.library_mutation("createFromItems", |t| {
+			t(|_, items: Vec<SelectionItem>, library| async move {
+				Ok(library.selections.create_with(items))
+			})
+		})
+		// a selection of every file path the search finds, so the results never go to the client
+		.library_mutation("createFromSearch", |t| {
+			t(|_, args: FileSearchArgs, library| async move {
+				let items = search_items(&library, args).await?;
+				Ok(library.selections.create_with(items))
+			})
+		})
+		.library_mutation("add", |t| {
+			t(|_, args: SelectionItemsArgs, library| async move {
+				Ok(library.selections.add(args.id, args.items)?)
+			})
+		})
+		.library_mutation("remove", |t| {
+			t(|_, args: SelectionItemsArgs, library| async move {
+				Ok(library.selections.remove(args.id, args.items)?)
+			})
+		})
+		.library_mutation("clear", |t| {
+			t(|_, id: Uuid, library| async move { Ok(library.selections.clear(id)?) })
+		})
+		.library_mutation("delete", |t| {
+			t(|_, id: Uuid, library| async move { Ok(library.selections.delete(id)) })
+		})
+		.library_query("getCount", |t| {
+			t(|_, id: Uuid, library| async move { Ok(library.selections.count(id)?) })
+		})
+		.library_query("getSummary", |t| {
+			t(|_, id: Uuid, library| async move { Ok(summarise(&library, id).await?) })
+		})
+		// size, kinds, dates and tags of the selected file paths together, for the inspector
+		.library_query("getInfo", |t| {
+			t(|_, target: SelectionTarget, library| async move {
+				Ok(selection_info(&library, target).await?)
+			})
+		})
+}
@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use rspc::Type;
+use serde::Deserialize;
+
+use crate::object::share::{
+	create_share, list_shares, open_share, revoke_share, ShareCreateArgs, ShareRelay,
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+pub(crate) fn mount() -> RouterBuilder {
+	<RouterBuilder>::new()
+		.query("getRelay", |t| {
+			t(|ctx, _: ()| async move { Ok(ctx.config.get().await.share_relay) })
+		})
+		.mutation("setRelay", |t| {
+			t(|ctx, relay: Option<ShareRelay>| async move {
+				if let Some(relay) = &relay {
+					relay.validate()?;
+				}
+
+				ctx.config
+					.write(|mut config| config.share_relay = relay.clone())
+					.await?;
+
+				Ok(relay)
+			})
+		})
+		// the shares of the library, with their access counts as the relay has them now
+		.library_query("list", |t| {
+			t(|_, _: (), library| async move { Ok(list_shares(&library).await?) })
+		})
+		// uploads the files and returns the share with its link, which can't be had again later
+		.library_mutation("create", |t| {
+			t(|ctx, args: ShareCreateArgs, library| async move {
+				Ok(create_share(&library, &ctx.jobs.transfers(), args).await?)
+			})
+		})
+		.library_mutation("revoke", |t| {
+			t(|_, share_id: i32, library| async move {
+				revoke_share(&library, share_id).await?;
+				Ok(())
+			})
+		})
+		// downloads the files of a share link, returning where they were put
+		.mutation("open", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct OpenShareArgs {
+				pub link: String,
+				pub target_dir: PathBuf,
+			}
+
+			t(|ctx, args: OpenShareArgs| async move {
+				let config = ctx.config.get().await;
+				Ok(
+					open_share(&config, &ctx.jobs.transfers(), &args.link, &args.target_dir)
+						.await?,
+				)
+			})
+		})
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	invalidate_query,
+	job::Job,
+	object::preview::{
+		plan_preview_warming, read_thumbnails, write_thumbnails, PreviewWarmerJob,
+		PreviewWarmerJobInit,
+	},
+	sync::{SyncError, SYNC_BATCH_SIZE},
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+#[derive(Type, Deserialize)]
+pub struct GetOperationsArgs {
+	/// the clocks of the node asking, as returned by its `sync.getClocks`
+	pub clocks: String,
+}
+
+#[derive(Type, Serialize)]
+pub struct IngestResult {
+	pub ingested: usize,
+	/// cas ids of synced objects with no copy on this node, whose thumbnails should be fetched
+	/// from the node the batch came from with `sync.getThumbnails`
+	pub missing_thumbnails: Vec<String>,
+}
+
+// Clocks and batches are JSON the client doesn't need to understand, which keeps the 64 bit
+// timestamps in them from losing precision in javascript
+pub(crate) fn mount() -> RouterBuilder {
+	RouterBuilder::new()
+		.library_query("getClocks", |t| {
+			t(|_, _: (), library| async move {
+				Ok(
+					serde_json::to_string(&library.sync.clocks().await?)
+						.map_err(SyncError::from)?,
+				)
+			})
+		})
+		.library_query("getOperations", |t| {
+			t(|_, args: GetOperationsArgs, library| async move {
+				let clocks = serde_json::from_str(&args.clocks).map_err(SyncError::from)?;
+				let batch = library.sync.operations(&clocks, SYNC_BATCH_SIZE).await?;
+
+				Ok(serde_json::to_string(&batch).map_err(SyncError::from)?)
+			})
+		})
+		.library_mutation("ingest", |t| {
+			t(|_, batch: String, library| async move {
+				let batch = serde_json::from_str(&batch).map_err(SyncError::from)?;
+				let ingested = library.sync.ingest(batch).await?;
+
+				if ingested.operations > 0 {
+					invalidate_query!(library, "tags.list");
+					invalidate_query!(library, "locations.list");
+				}
+
+				// warm the previews of what the batch brought so it doesn't show up as placeholders
+				let warming = plan_preview_warming(&library, &ingested.objects).await?;
+				if !warming.generate.is_empty() {
+					library
+						.spawn_job(Job::new(
+							PreviewWarmerJobInit {
+								steps: warming.generate,
+							},
+							Box::new(PreviewWarmerJob {}),
+						))
+						.await;
+				}
+
+				Ok(IngestResult {
+					ingested: ingested.operations,
+					missing_thumbnails: warming.fetch,
+				})
+			})
+		})
+		.library_query("getThumbnails", |t| {
+			t(|_, cas_ids: Vec<String>, library| async move {
+				let thumbnails = read_thumbnails(&library, &cas_ids)
+					.await
+					.map_err(SyncError::from)?
+					.into_iter()
+					.map(|(cas_id, thumbnail)| (cas_id, base64::encode(thumbnail)))
+					.collect::<HashMap<_, _>>();
+
+				Ok(serde_json::to_string(&thumbnails).map_err(SyncError::from)?)
+			})
+		})
+		.library_mutation("ingestThumbnails", |t| {
+			t(|_, thumbnails: String, library| async move {
+				let thumbnails = serde_json::from_str::<HashMap<String, String>>(&thumbnails)
+					.map_err(SyncError::from)?
+					.into_iter()
+					.filter_map(|(cas_id, thumbnail)| {
+						base64::decode(thumbnail)
+							.ok()
+							.map(|thumbnail| (cas_id, thumbnail))
+					})
+					.collect();
+
+				let written = write_thumbnails(&library, thumbnails)
+					.await
+					.map_err(SyncError::from)?;
+				if written > 0 {
+					invalidate_query!(library, "locations.getExplorerData");
+				}
+
+				Ok(written)
+			})
+		})
+}
@@ -6,11 +6,13 @@ use uuid::Uuid;
 use crate::{
 	api::locations::{object_with_file_paths, ExplorerContext, ExplorerData, ExplorerItem},
 	invalidate_query,
-	object::preview::THUMBNAIL_CACHE_DIR_NAME,
+	library::{audit_operation, record_operations, AuditAction, Operation},
+	object::{attributes::import_file_tags, preview::THUMBNAIL_CACHE_DIR_NAME},
 	prisma::{object, tag, tag_on_object},
+	sync::models::{uuid_from_pub_id, TagData, TAG, TAG_ON_OBJECT},
 };
 
-use super::{utils::LibraryRequest, RouterBuilder};
+use super::{utils::LibraryRequest, LibraryEvent, RouterBuilder};
 
 pub(crate) fn mount() -> RouterBuilder {
 	RouterBuilder::new()
@@ -71,7 +73,10 @@ pub(crate) fn mount() -> RouterBuilder {
 
 				Ok(ExplorerData {
 					context: ExplorerContext::Tag(tag),
+					// tagged objects can be in any location
+					availability: None,
 					items: objects,
+					next_cursor: None,
 				})
 			})
 		})
@@ -118,7 +123,19 @@ pub(crate) fn mount() -> RouterBuilder {
 					.exec()
 					.await?;
 
+				library
+					.sync
+					.write_ops(vec![library.sync.shared_create(
+						TAG,
+						uuid_from_pub_id(&created_tag.pub_id),
+						&TagData::from(&created_tag),
+					)])
+					.await?;
+
 				invalidate_query!(library, "tags.list");
+				library.emit_event(LibraryEvent::TagUpdated {
+					tag_id: created_tag.id,
+				});
 
 				Ok(created_tag)
 			})
@@ -132,6 +149,33 @@ pub(crate) fn mount() -> RouterBuilder {
 			}
 
 			t(|_, args: TagAssignArgs, library| async move {
+				let tag = library
+					.db
+					.tag()
+					.find_unique(tag::id::equals(args.tag_id))
+					.exec()
+					.await?
+					.ok_or_else(|| {
+						rspc::Error::new(
+							ErrorCode::NotFound,
+							format!("Tag <id={}> not found", args.tag_id),
+						)
+					})?;
+				let object = library
+					.db
+					.object()
+					.find_unique(object::id::equals(args.object_id))
+					.exec()
+					.await?
+					.ok_or_else(|| {
+						rspc::Error::new(
+							ErrorCode::NotFound,
+							format!("Object <id={}> not found", args.object_id),
+						)
+					})?;
+				let object_pub_id = library.sync.ensure_object_pub_id(&object).await?;
+				let tag_pub_id = uuid_from_pub_id(&tag.pub_id);
+
 				if args.unassign {
 					library
 						.db
@@ -139,6 +183,15 @@ pub(crate) fn mount() -> RouterBuilder {
 						.delete(tag_on_object::tag_id_object_id(args.tag_id, args.object_id))
 						.exec()
 						.await?;
+
+					library
+						.sync
+						.write_ops(vec![library.sync.relation_delete(
+							TAG_ON_OBJECT,
+							object_pub_id,
+							tag_pub_id,
+						)])
+						.await?;
 				} else {
 					library
 						.db
@@ -150,9 +203,35 @@ pub(crate) fn mount() -> RouterBuilder {
 						)
 						.exec()
 						.await?;
+
+					library
+						.sync
+						.write_ops(vec![library.sync.relation_create(
+							TAG_ON_OBJECT,
+							object_pub_id,
+							tag_pub_id,
+						)])
+						.await?;
 				}
 
+				record_operations(
+					&library,
+					if args.unassign {
+						"Untag a file"
+					} else {
+						"Tag a file"
+					},
+					vec![Operation::Tag {
+						tag_id: args.tag_id,
+						object_id: args.object_id,
+						assigned: !args.unassign,
+					}],
+				);
+
 				invalidate_query!(library, "tags.getForObject");
+				library.emit_event(LibraryEvent::TagUpdated {
+					tag_id: args.tag_id,
+				});
 
 				Ok(())
 			})
@@ -166,7 +245,7 @@ pub(crate) fn mount() -> RouterBuilder {
 			}
 
 			t(|_, args: TagUpdateArgs, library| async move {
-				library
+				let tag = library
 					.db
 					.tag()
 					.update(
@@ -176,23 +255,56 @@ pub(crate) fn mount() -> RouterBuilder {
 					.exec()
 					.await?;
 
+				let pub_id = uuid_from_pub_id(&tag.pub_id);
+				library
+					.sync
+					.write_ops(vec![
+						library.sync.shared_update(TAG, pub_id, "name", &tag.name),
+						library.sync.shared_update(TAG, pub_id, "color", &tag.color),
+					])
+					.await?;
+
 				invalidate_query!(library, "tags.list");
+				library.emit_event(LibraryEvent::TagUpdated { tag_id: args.id });
 
 				Ok(())
 			})
 		})
 		.library_mutation("delete", |t| {
 			t(|_, tag_id: i32, library| async move {
-				library
+				let result = library
 					.db
 					.tag()
 					.delete(tag::id::equals(tag_id))
 					.exec()
+					.await;
+				audit_operation(
+					&library,
+					AuditAction::Delete,
+					"tags.delete",
+					&tag_id,
+					result.as_ref().err(),
+				)
+				.await;
+				let tag = result?;
+
+				library
+					.sync
+					.write_ops(vec![library
+						.sync
+						.shared_delete(TAG, uuid_from_pub_id(&tag.pub_id))])
 					.await?;
 
 				invalidate_query!(library, "tags.list");
+				library.emit_event(LibraryEvent::TagRemoved { tag_id });
 
 				Ok(())
 			})
 		})
+		// tags the objects of a location with the tags their files have in the file manager
+		.library_mutation("importFromFiles", |t| {
+			t(|_, location_id: i32, library| async move {
+				Ok(import_file_tags(&library, location_id).await?)
+			})
+		})
 }
@@ -25,6 +25,11 @@ impl InvalidateOperationEvent {
 	pub fn dangerously_create(key: &'static str, arg: Value) -> Self {
 		Self { key, arg }
 	}
+
+	/// key returns the key of the invalidated query
+	pub(crate) fn key(&self) -> &'static str {
+		self.key
+	}
 }
 
 /// a request to invalidate a specific resource
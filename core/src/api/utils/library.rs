@@ -148,6 +148,14 @@ where
 							)
 						})?;
 
+					if library.process_lock.is_read_only() {
+						return Err(rspc::Error::new(
+							ErrorCode::Forbidden,
+							"This library is held by another process, it can only be read."
+								.to_string(),
+						));
+					}
+
 					Ok(resolver(ctx, arg.arg, library)
 						.into_request_future()?
 						.exec()
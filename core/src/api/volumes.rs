@@ -1,7 +1,46 @@
-use crate::volume::get_volumes;
+use rspc::{ErrorCode, Type};
+use serde::Deserialize;
 
-use super::RouterBuilder;
+use crate::{
+	location::space_monitor::{space_history, SpaceThresholds},
+	volume::get_volumes,
+};
+
+use super::{utils::LibraryRequest, RouterBuilder};
+
+#[derive(Deserialize, Type, Debug)]
+pub struct SpaceHistoryArgs {
+	/// the serial of the volume, or its mount point if it has none
+	pub volume: String,
+	pub days: u32,
+}
 
 pub(crate) fn mount() -> RouterBuilder {
-	RouterBuilder::new().query("list", |t| t(|_, _: ()| Ok(get_volumes()?)))
+	RouterBuilder::new()
+		.query("list", |t| t(|_, _: ()| Ok(get_volumes()?)))
+		// the free space of a volume over time, and when it's forecast to be full
+		.library_query("getSpaceHistory", |t| {
+			t(|_, args: SpaceHistoryArgs, library| async move {
+				Ok(space_history(&library, args.volume, args.days).await?)
+			})
+		})
+		.query("getSpaceThresholds", |t| {
+			t(|ctx, _: ()| async move { Ok(ctx.config.get().await.space_alerts) })
+		})
+		// when volumes are alerted about for running out of space
+		.mutation("setSpaceThresholds", |t| {
+			t(|ctx, thresholds: SpaceThresholds| async move {
+				if !(0.0..1.0).contains(&thresholds.min_free_ratio) {
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"min_free_ratio must be between 0 and 1".into(),
+					));
+				}
+
+				ctx.config
+					.write(|mut config| config.space_alerts = thresholds)
+					.await?;
+				Ok(thresholds)
+			})
+		})
 }
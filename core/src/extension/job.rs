@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use crate::job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext};
+
+use super::ExtensionContext;
+
+pub const EXTENSION_JOB_NAME: &str = "extension_job";
+
+/// Runs the jobs of extensions, which are picked up by their extension's id and their name so they
+/// can be resumed once the extension is registered again
+pub struct ExtensionJobRunner {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExtensionJobInit {
+	pub extension_id: String,
+	pub job: String,
+	pub args: Value,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ExtensionJobRunner {
+	type Init = ExtensionJobInit;
+	type Data = ();
+	type Step = Value;
+
+	fn name(&self) -> &'static str {
+		EXTENSION_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let init = &state.init;
+
+		let job = library.extensions().job(&init.extension_id, &init.job)?;
+		let extension_ctx = ExtensionContext::new(&library, &init.extension_id).await?;
+		state.steps = job.init(&extension_ctx, init.args.clone()).await?.into();
+
+		info!(
+			"Running job '{}' of extension {} in {} steps",
+			init.job,
+			init.extension_id,
+			state.steps.len()
+		);
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let init = &state.init;
+
+		let job = library.extensions().job(&init.extension_id, &init.job)?;
+		let extension_ctx = ExtensionContext::new(&library, &init.extension_id).await?;
+		job.execute_step(&extension_ctx, state.steps[0].clone())
+			.await?;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let init = &state.init;
+
+		let job = library.extensions().job(&init.extension_id, &init.job)?;
+		let extension_ctx = ExtensionContext::new(&library, &init.extension_id).await?;
+
+		Ok(job.finalize(&extension_ctx).await?)
+	}
+}
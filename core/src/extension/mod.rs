@@ -0,0 +1,509 @@
+//! Extensions add jobs and metadata extractors to the core without changing it. They're Rust crates
+//! built against the traits here, registered with the node when it's made with
+//! [`crate::Node::with_extensions`], and the traits only change with [`EXTENSION_API_VERSION`].
+//!
+//! Extensions only reach the library through the [`ExtensionContext`] they're given, which only
+//! reads and writes files inside the library's locations, and only once the user granted the
+//! extension the [`Permission`] to.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	io,
+	path::{Path, PathBuf},
+	sync::{Arc, RwLock},
+};
+
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tokio::{
+	fs::{File, OpenOptions},
+	io::AsyncWriteExt,
+};
+use uuid::Uuid;
+
+use crate::{library::LibraryContext, prisma::location};
+
+mod job;
+
+pub use crate::location::custom_metadata::MetadataFieldKind;
+pub use job::*;
+
+/// The version of the traits extensions implement, bumped when they change in a way extensions
+/// built against an older version can't handle
+pub const EXTENSION_API_VERSION: u32 = 1;
+
+/// What an extension is allowed to do, once the user granted it
+#[derive(
+	Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, Type,
+)]
+pub enum Permission {
+	/// reading the files of the library's locations
+	ReadFiles,
+	/// writing files in the library's locations
+	WriteFiles,
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct ExtensionManifest {
+	/// unique among extensions, e.g. `com.example.raw-previews`
+	pub id: String,
+	pub name: String,
+	pub version: String,
+	/// the [`EXTENSION_API_VERSION`] the extension was built against
+	pub api_version: u32,
+	/// the permissions the extension asks for, it can't be granted any other
+	pub permissions: Vec<Permission>,
+}
+
+pub trait Extension: Send + Sync {
+	fn manifest(&self) -> ExtensionManifest;
+
+	fn jobs(&self) -> Vec<Arc<dyn ExtensionJob>> {
+		vec![]
+	}
+
+	fn extractors(&self) -> Vec<Arc<dyn MetadataExtractor>> {
+		vec![]
+	}
+}
+
+/// A job an extension runs in the background like the core's, one step at a time. Steps are kept
+/// with the job's state, so a paused job picks up at the step it stopped at.
+#[async_trait::async_trait]
+pub trait ExtensionJob: Send + Sync {
+	/// unique among the extension's jobs
+	fn name(&self) -> &'static str;
+
+	/// init returns the steps of the job run with the given arguments
+	async fn init(&self, ctx: &ExtensionContext, args: Value)
+		-> Result<Vec<Value>, ExtensionError>;
+
+	async fn execute_step(&self, ctx: &ExtensionContext, step: Value)
+		-> Result<(), ExtensionError>;
+
+	/// finalize returns what the job reports once it's done
+	async fn finalize(&self, _ctx: &ExtensionContext) -> Result<Option<Value>, ExtensionError> {
+		Ok(None)
+	}
+}
+
+/// Reads metadata out of files, run when a location's metadata is imported. The fields it returns
+/// are set as the custom metadata of the files, unless they already have a value.
+#[async_trait::async_trait]
+pub trait MetadataExtractor: Send + Sync {
+	fn name(&self) -> &'static str;
+
+	/// the file extensions read, lowercase and without the dot
+	fn extensions(&self) -> &'static [&'static str];
+
+	async fn extract(
+		&self,
+		ctx: &ExtensionContext,
+		path: &Path,
+	) -> Result<Vec<ExtractedField>, ExtensionError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtractedField {
+	pub name: String,
+	pub kind: MetadataFieldKind,
+	pub value: String,
+}
+
+/// What the user allowed an extension to do, kept in the node's config
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct ExtensionSettings {
+	pub enabled: bool,
+	pub granted: Vec<Permission>,
+}
+
+impl Default for ExtensionSettings {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			granted: vec![],
+		}
+	}
+}
+
+#[derive(Error, Debug)]
+pub enum ExtensionError {
+	#[error("Extension <id='{0}'> isn't registered")]
+	NotFound(String),
+	#[error("Extension <id='{0}'> is already registered")]
+	AlreadyRegistered(String),
+	#[error(
+		"Extension <id='{id}'> was built for extension API {api_version}, not {}",
+		EXTENSION_API_VERSION
+	)]
+	IncompatibleApi { id: String, api_version: u32 },
+	#[error("Extension <id='{0}'> is disabled")]
+	Disabled(String),
+	#[error("Extension <id='{extension_id}'> has no job named '{job}'")]
+	JobNotFound { extension_id: String, job: String },
+	#[error("Extension <id='{extension_id}'> wasn't granted {permission:?}")]
+	PermissionDenied {
+		extension_id: String,
+		permission: Permission,
+	},
+	#[error("Extensions can only reach files in the library's locations: {0}")]
+	OutsideLocations(PathBuf),
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	/// an error of the extension itself
+	#[error("{0}")]
+	Failed(String),
+}
+
+impl From<ExtensionError> for rspc::Error {
+	fn from(err: ExtensionError) -> Self {
+		match err {
+			ExtensionError::NotFound(_) | ExtensionError::JobNotFound { .. } => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			ExtensionError::AlreadyRegistered(_)
+			| ExtensionError::IncompatibleApi { .. }
+			| ExtensionError::Disabled(_)
+			| ExtensionError::PermissionDenied { .. }
+			| ExtensionError::OutsideLocations(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// An extension as clients see it
+#[derive(Debug, Serialize, Type)]
+pub struct ExtensionInfo {
+	pub manifest: ExtensionManifest,
+	pub jobs: Vec<String>,
+	pub extractors: Vec<String>,
+	pub settings: ExtensionSettings,
+}
+
+/// The extensions registered with the node
+#[derive(Default)]
+pub struct ExtensionRegistry {
+	extensions: RwLock<BTreeMap<String, Arc<dyn Extension>>>,
+}
+
+impl ExtensionRegistry {
+	/// register adds an extension, unless one with the same id was already or it was built for
+	/// another version of the extension API
+	pub fn register(&self, extension: Arc<dyn Extension>) -> Result<(), ExtensionError> {
+		let manifest = extension.manifest();
+		if manifest.api_version != EXTENSION_API_VERSION {
+			return Err(ExtensionError::IncompatibleApi {
+				id: manifest.id,
+				api_version: manifest.api_version,
+			});
+		}
+
+		let mut extensions = self
+			.extensions
+			.write()
+			.expect("extension registry poisoned");
+		if extensions.contains_key(&manifest.id) {
+			return Err(ExtensionError::AlreadyRegistered(manifest.id));
+		}
+		extensions.insert(manifest.id, extension);
+
+		Ok(())
+	}
+
+	fn get(&self, id: &str) -> Result<Arc<dyn Extension>, ExtensionError> {
+		self.extensions
+			.read()
+			.expect("extension registry poisoned")
+			.get(id)
+			.cloned()
+			.ok_or_else(|| ExtensionError::NotFound(id.to_string()))
+	}
+
+	/// list returns the registered extensions, with what the user allowed them to do
+	pub fn list(&self, settings: &HashMap<String, ExtensionSettings>) -> Vec<ExtensionInfo> {
+		self.extensions
+			.read()
+			.expect("extension registry poisoned")
+			.iter()
+			.map(|(id, extension)| ExtensionInfo {
+				manifest: extension.manifest(),
+				jobs: extension
+					.jobs()
+					.iter()
+					.map(|job| job.name().to_string())
+					.collect(),
+				extractors: extension
+					.extractors()
+					.iter()
+					.map(|extractor| extractor.name().to_string())
+					.collect(),
+				settings: settings.get(id).cloned().unwrap_or_default(),
+			})
+			.collect()
+	}
+
+	/// job returns the job of an extension with the given name
+	pub fn job(
+		&self,
+		extension_id: &str,
+		name: &str,
+	) -> Result<Arc<dyn ExtensionJob>, ExtensionError> {
+		self.get(extension_id)?
+			.jobs()
+			.into_iter()
+			.find(|job| job.name() == name)
+			.ok_or_else(|| ExtensionError::JobNotFound {
+				extension_id: extension_id.to_string(),
+				job: name.to_string(),
+			})
+	}
+
+	/// extractors returns the metadata extractors of the enabled extensions reading files with the
+	/// given extension, along with the id of their extension
+	pub fn extractors(
+		&self,
+		settings: &HashMap<String, ExtensionSettings>,
+		file_extension: &str,
+	) -> Vec<(String, Arc<dyn MetadataExtractor>)> {
+		let file_extension = file_extension.to_lowercase();
+
+		self.extensions
+			.read()
+			.expect("extension registry poisoned")
+			.iter()
+			.filter(|(id, _)| settings.get(*id).cloned().unwrap_or_default().enabled)
+			.flat_map(|(id, extension)| {
+				extension
+					.extractors()
+					.into_iter()
+					.filter(|extractor| extractor.extensions().contains(&file_extension.as_str()))
+					.map(|extractor| (id.clone(), extractor))
+					.collect::<Vec<_>>()
+			})
+			.collect()
+	}
+}
+
+/// What an extension is given to work with the library, only reaching what it was allowed to
+pub struct ExtensionContext {
+	extension_id: String,
+	granted: Vec<Permission>,
+	library: LibraryContext,
+	/// the paths of the library's locations on this node
+	roots: Vec<PathBuf>,
+}
+
+impl ExtensionContext {
+	/// new makes the context of an extension in a library, failing if the extension is disabled.
+	/// It's only granted the permissions it asked for.
+	pub(crate) async fn new(
+		library: &LibraryContext,
+		extension_id: &str,
+	) -> Result<Self, ExtensionError> {
+		let extension = library.extensions().get(extension_id)?;
+		let settings = library
+			.config()
+			.get()
+			.await
+			.extensions
+			.get(extension_id)
+			.cloned()
+			.unwrap_or_default();
+		if !settings.enabled {
+			return Err(ExtensionError::Disabled(extension_id.to_string()));
+		}
+
+		let requested = extension.manifest().permissions;
+		let granted = settings
+			.granted
+			.into_iter()
+			.filter(|permission| requested.contains(permission))
+			.collect();
+
+		let roots = library
+			.db
+			.location()
+			.find_many(vec![location::node_id::equals(library.node_local_id)])
+			.exec()
+			.await?
+			.into_iter()
+			.filter_map(|location| location.local_path)
+			// locations that can't be found now can't be reached either
+			.filter_map(|path| std::fs::canonicalize(path).ok())
+			.collect();
+
+		Ok(Self {
+			extension_id: extension_id.to_string(),
+			granted,
+			library: library.clone(),
+			roots,
+		})
+	}
+
+	pub fn library_id(&self) -> Uuid {
+		self.library.id
+	}
+
+	/// read_file reads a file in one of the library's locations
+	pub async fn read_file(&self, path: &Path) -> Result<Vec<u8>, ExtensionError> {
+		let path = self.allowed(Permission::ReadFiles, path)?;
+		Ok(tokio::fs::read(path).await?)
+	}
+
+	/// read_dir lists the entries of a directory in one of the library's locations
+	pub async fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, ExtensionError> {
+		let path = self.allowed(Permission::ReadFiles, path)?;
+
+		let mut entries = vec![];
+		let mut read_dir = tokio::fs::read_dir(path).await?;
+		while let Some(entry) = read_dir.next_entry().await? {
+			entries.push(entry.path());
+		}
+		Ok(entries)
+	}
+
+	/// write_file writes a file in one of the library's locations, replacing it if it exists
+	pub async fn write_file(&self, path: &Path, contents: &[u8]) -> Result<(), ExtensionError> {
+		let path = self.allowed(Permission::WriteFiles, path)?;
+
+		let mut file = open_for_write(&path).await?;
+		file.write_all(contents).await?;
+		Ok(file.flush().await?)
+	}
+
+	/// allowed returns the path with its links resolved, if the extension was granted the
+	/// permission and the path is in one of the library's locations
+	fn allowed(&self, permission: Permission, path: &Path) -> Result<PathBuf, ExtensionError> {
+		if !self.granted.contains(&permission) {
+			return Err(ExtensionError::PermissionDenied {
+				extension_id: self.extension_id.clone(),
+				permission,
+			});
+		}
+
+		let resolved = resolve(path)?;
+		if !is_within(&self.roots, &resolved) {
+			return Err(ExtensionError::OutsideLocations(path.to_path_buf()));
+		}
+		Ok(resolved)
+	}
+}
+
+/// resolve makes a path absolute with its links and `..` resolved, so it can't point out of where
+/// it seems to. Files that don't exist yet are resolved through their directory.
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+	match std::fs::canonicalize(path) {
+		Ok(path) => Ok(path),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => match (path.parent(), path.file_name()) {
+			// something that can't be resolved but is there is a dangling link, which would be
+			// followed out of the directory once written to
+			_ if std::fs::symlink_metadata(path).is_ok() => Err(e),
+			(Some(parent), Some(name)) if name != ".." => {
+				Ok(std::fs::canonicalize(parent)?.join(name))
+			}
+			_ => Err(e),
+		},
+		Err(e) => Err(e),
+	}
+}
+
+/// open_for_write opens a resolved path to be written without following links, so a link put in
+/// its place after it was resolved can't redirect the write
+async fn open_for_write(path: &Path) -> io::Result<File> {
+	let mut options = OpenOptions::new();
+	options.write(true);
+
+	match tokio::fs::symlink_metadata(path).await {
+		Ok(_) => {
+			options.truncate(true);
+			#[cfg(unix)]
+			options.custom_flags(nix::libc::O_NOFOLLOW);
+		}
+		// creating a new file fails on anything already there, links included
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {
+			options.create_new(true);
+		}
+		Err(e) => return Err(e),
+	}
+
+	options.open(path).await
+}
+
+/// is_within tells if a resolved path is in one of the roots
+fn is_within(roots: &[PathBuf], path: &Path) -> bool {
+	roots.iter().any(|root| path.starts_with(root))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct TestExtension(u32);
+
+	impl Extension for TestExtension {
+		fn manifest(&self) -> ExtensionManifest {
+			ExtensionManifest {
+				id: "com.example.test".to_string(),
+				name: "Test".to_string(),
+				version: "1.0.0".to_string(),
+				api_version: self.0,
+				permissions: vec![Permission::ReadFiles],
+			}
+		}
+	}
+
+	#[test]
+	fn registers_compatible_extensions_once() {
+		let registry = ExtensionRegistry::default();
+
+		assert!(matches!(
+			registry.register(Arc::new(TestExtension(EXTENSION_API_VERSION + 1))),
+			Err(ExtensionError::IncompatibleApi { .. })
+		));
+		assert!(registry
+			.register(Arc::new(TestExtension(EXTENSION_API_VERSION)))
+			.is_ok());
+		assert!(matches!(
+			registry.register(Arc::new(TestExtension(EXTENSION_API_VERSION))),
+			Err(ExtensionError::AlreadyRegistered(_))
+		));
+		assert_eq!(registry.list(&HashMap::new()).len(), 1);
+	}
+
+	#[test]
+	fn keeps_paths_in_locations() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = std::fs::canonicalize(dir.path()).unwrap();
+		let roots = vec![root.join("location")];
+		std::fs::create_dir(&roots[0]).unwrap();
+
+		let inside = resolve(&roots[0].join("new.txt")).unwrap();
+		assert!(is_within(&roots, &inside));
+
+		let escaping = resolve(&roots[0].join("..").join("other.txt")).unwrap();
+		assert!(!is_within(&roots, &escaping));
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn refuses_dangling_links_out_of_locations() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = std::fs::canonicalize(dir.path()).unwrap();
+		let location = root.join("location");
+		std::fs::create_dir(&location).unwrap();
+
+		let outside = root.join("outside.txt");
+		let link = location.join("link.txt");
+		std::os::unix::fs::symlink(&outside, &link).unwrap();
+
+		assert!(resolve(&link).is_err());
+		assert!(open_for_write(&link).await.is_err());
+		assert!(!outside.exists());
+	}
+}
@@ -0,0 +1,201 @@
+//! Throttling of background jobs, so identifying and thumbnailing a big location doesn't take over a
+//! laptop's disk and battery. Jobs take a hasher slot from the node's [`Governor`] before they process
+//! a file and tell it how much they read, and it holds them back when they go over its limits. The
+//! limits are in the node config and can be changed while jobs are running.
+
+use std::{
+	sync::{Mutex, RwLock},
+	time::Duration,
+};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::{
+	sync::Notify,
+	time::{sleep_until, Instant},
+};
+
+use crate::util::power::on_battery_power;
+
+/// How fast jobs read in low power mode, in MB/s, if they aren't held to less already
+const LOW_POWER_READ_MB_PER_SEC: u32 = 10;
+/// How long the power source is trusted for before it's checked again
+const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When jobs are held to a single hasher and a slow read rate, to save battery
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Type, PartialEq, Eq)]
+pub enum LowPowerMode {
+	Off,
+	OnBattery,
+	Always,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Type, PartialEq, Eq)]
+pub struct ThrottleLimits {
+	/// how many MB jobs read per second, all of them together, none for as fast as the disk goes
+	pub max_read_mb_per_sec: Option<u32>,
+	/// how many files are hashed or thumbnailed at once, by all jobs together
+	pub max_concurrent_hashers: u32,
+	pub low_power: LowPowerMode,
+}
+
+impl Default for ThrottleLimits {
+	fn default() -> Self {
+		Self {
+			max_read_mb_per_sec: None,
+			max_concurrent_hashers: 4,
+			low_power: LowPowerMode::OnBattery,
+		}
+	}
+}
+
+/// Governor holds jobs to the node's [`ThrottleLimits`], it's shared by every job through their
+/// [`super::WorkerContext`]
+pub struct Governor {
+	limits: RwLock<ThrottleLimits>,
+	/// how many hasher slots are taken
+	hashers: Mutex<u32>,
+	released: Notify,
+	/// when what was read so far is paid for at the read rate
+	read_until: Mutex<Instant>,
+	/// whether the device was on battery, and when that was checked
+	on_battery: Mutex<Option<(Instant, bool)>>,
+}
+
+/// A hasher slot, given back when dropped
+pub struct HasherPermit<'a> {
+	governor: &'a Governor,
+}
+
+impl Drop for HasherPermit<'_> {
+	fn drop(&mut self) {
+		*self.governor.hashers.lock().unwrap() -= 1;
+		self.governor.released.notify_waiters();
+	}
+}
+
+impl Governor {
+	pub fn new(limits: ThrottleLimits) -> Self {
+		Self {
+			limits: RwLock::new(limits),
+			hashers: Mutex::new(0),
+			released: Notify::new(),
+			read_until: Mutex::new(Instant::now()),
+			on_battery: Mutex::new(None),
+		}
+	}
+
+	pub fn limits(&self) -> ThrottleLimits {
+		*self.limits.read().unwrap()
+	}
+
+	/// set_limits changes the limits, jobs that are waiting go by the new ones right away
+	pub fn set_limits(&self, limits: ThrottleLimits) {
+		*self.limits.write().unwrap() = limits;
+		self.released.notify_waiters();
+	}
+
+	fn low_power(&self, mode: LowPowerMode) -> bool {
+		match mode {
+			LowPowerMode::Off => false,
+			LowPowerMode::Always => true,
+			LowPowerMode::OnBattery => {
+				let mut on_battery = self.on_battery.lock().unwrap();
+				match *on_battery {
+					Some((checked, on_battery)) if checked.elapsed() < POWER_CHECK_INTERVAL => {
+						on_battery
+					}
+					_ => {
+						let battery = on_battery_power();
+						*on_battery = Some((Instant::now(), battery));
+						battery
+					}
+				}
+			}
+		}
+	}
+
+	/// effective returns how many hashers can run at once and how many bytes can be read per second,
+	/// with low power mode taken into account
+	fn effective(&self) -> (u32, Option<u64>) {
+		let limits = self.limits();
+		let (hashers, read_mb_per_sec) = match self.low_power(limits.low_power) {
+			true => (
+				1,
+				Some(
+					limits
+						.max_read_mb_per_sec
+						.map_or(LOW_POWER_READ_MB_PER_SEC, |max| {
+							max.min(LOW_POWER_READ_MB_PER_SEC)
+						}),
+				),
+			),
+			false => (limits.max_concurrent_hashers, limits.max_read_mb_per_sec),
+		};
+
+		(
+			hashers.max(1),
+			read_mb_per_sec.map(|mb| mb.max(1) as u64 * 1024 * 1024),
+		)
+	}
+
+	/// hasher waits for a hasher slot, which is held until the returned permit is dropped
+	pub async fn hasher(&self) -> HasherPermit<'_> {
+		loop {
+			// registered before checking, so a slot given back in between isn't missed
+			let released = self.released.notified();
+			{
+				let max = self.effective().0;
+				let mut hashers = self.hashers.lock().unwrap();
+				if *hashers < max {
+					*hashers += 1;
+					return HasherPermit { governor: self };
+				}
+			}
+			released.await;
+		}
+	}
+
+	/// throttle_read is called after reading `bytes`, and waits for as long as it takes to keep jobs
+	/// to the read rate
+	pub async fn throttle_read(&self, bytes: u64) {
+		let bytes_per_sec = match self.effective().1 {
+			Some(bytes_per_sec) => bytes_per_sec,
+			None => return,
+		};
+
+		let until = {
+			let mut read_until = self.read_until.lock().unwrap();
+			*read_until = (*read_until).max(Instant::now())
+				+ Duration::from_secs_f64(bytes as f64 / bytes_per_sec as f64);
+			*read_until
+		};
+
+		sleep_until(until).await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::time::timeout;
+
+	#[tokio::test]
+	async fn limits_concurrent_hashers() {
+		let governor = Governor::new(ThrottleLimits {
+			max_concurrent_hashers: 1,
+			low_power: LowPowerMode::Off,
+			..Default::default()
+		});
+
+		let permit = governor.hasher().await;
+		assert!(timeout(Duration::from_millis(50), governor.hasher())
+			.await
+			.is_err());
+
+		drop(permit);
+		assert!(timeout(Duration::from_millis(50), governor.hasher())
+			.await
+			.is_ok());
+	}
+}
@@ -1,12 +1,56 @@
 use crate::{
-	job::{worker::Worker, DynJob, Job, JobError},
-	library::LibraryContext,
-	location::indexer::indexer_job::{IndexerJob, INDEXER_JOB_NAME},
+	extension::{ExtensionJobRunner, EXTENSION_JOB_NAME},
+	invalidate_query,
+	job::{
+		worker::Worker, DynJob, FileError, Governor, Job, JobError, ThrottleLimits,
+		TransferGovernor, TransferLimits,
+	},
+	library::{LibraryContext, MaintenanceJob, MAINTENANCE_JOB_NAME},
+	location::{
+		eraser::{LocationEraserJob, LOCATION_ERASER_JOB_NAME},
+		indexer::{
+			indexer_job::{IndexerJob, INDEXER_JOB_NAME},
+			storage_sync_job::{StorageSyncJob, STORAGE_SYNC_JOB_NAME},
+			sweep_job::{SweepJob, SWEEP_JOB_NAME},
+		},
+		pipeline::on_job_stopped,
+	},
 	object::{
+		backup::{BackupJob, RestoreBackupJob, BACKUP_JOB_NAME, RESTORE_BACKUP_JOB_NAME},
+		chunks::{ChunkStoreJob, CHUNK_STORE_JOB_NAME},
+		faces::{FaceGrouperJob, FACE_GROUPER_JOB_NAME},
+		fs::{
+			archive::{ArchiveJob, ARCHIVE_JOB_NAME},
+			batch::{BatchJob, BATCH_JOB_NAME},
+			copy::{FileCopierJob, COPY_JOB_NAME},
+			decrypt::{FileDecryptorJob, DECRYPT_JOB_NAME},
+			encrypt::{FileEncryptorJob, ENCRYPT_JOB_NAME},
+			ingest::{IngestJob, INGEST_JOB_NAME},
+			organize::{OrganizerJob, ORGANIZER_JOB_NAME},
+			rename::{RenamerJob, RENAMER_JOB_NAME},
+			restore::{FileRestorerJob, RESTORE_JOB_NAME},
+			sync::{FolderSyncJob, FOLDER_SYNC_JOB_NAME},
+			trash::{TrashCleanerJob, TRASH_CLEANER_JOB_NAME},
+		},
+		garbage_collector::{GarbageCollectorJob, GARBAGE_COLLECTOR_JOB_NAME},
 		identifier_job::{FileIdentifierJob, IDENTIFIER_JOB_NAME},
-		preview::{ThumbnailJob, THUMBNAIL_JOB_NAME},
+		importer::{ImportMetadataJob, IMPORT_METADATA_JOB_NAME},
+		labeler::{ImageLabelerJob, IMAGE_LABELER_JOB_NAME},
+		preview::{
+			PreviewCacheCleanerJob, PreviewWarmerJob, ThumbnailJob, PREVIEW_CACHE_CLEANER_JOB_NAME,
+			PREVIEW_WARMER_JOB_NAME, THUMBNAIL_JOB_NAME,
+		},
+		validation::{
+			checksum_file::{
+				ChecksumExportJob, ChecksumVerifyJob, CHECKSUM_EXPORT_JOB_NAME,
+				CHECKSUM_VERIFY_JOB_NAME,
+			},
+			sampler_job::{IntegritySamplerJob, INTEGRITY_SAMPLER_JOB_NAME},
+			validator_job::{ObjectValidatorJob, VALIDATOR_JOB_NAME},
+		},
 	},
-	prisma::{job, node},
+	prisma::{job, job_error, node},
+	util::message::Message,
 };
 
 use int_enum::IntEnum;
@@ -18,39 +62,51 @@ use std::{
 	fmt::Debug,
 	fmt::{Display, Formatter},
 	sync::Arc,
-	time::Duration,
+	time::{Duration, Instant},
 };
 use tokio::sync::{mpsc, Mutex, RwLock};
 use tokio::{sync::broadcast, time::sleep};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 // db is single threaded, nerd
 const MAX_WORKERS: usize = 1;
+/// How long the node waits for its running jobs to pause when it shuts down
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
 
 pub enum JobManagerEvent {
 	IngestJob(LibraryContext, Box<dyn DynJob>),
 }
 
+/// The jobs waiting for a worker in a single library, along with the library they must run in
+struct LibraryQueue {
+	library: LibraryContext,
+	jobs: VecDeque<Box<dyn DynJob>>,
+}
+
 /// JobManager handles queueing and executing jobs using the `DynJob`
 /// Handling persisting JobReports to the database, pause/resuming, and
 ///
 pub struct JobManager {
-	job_queue: RwLock<VecDeque<Box<dyn DynJob>>>,
+	job_queues: RwLock<HashMap<Uuid, LibraryQueue>>,
 	running_workers: RwLock<HashMap<Uuid, Arc<Mutex<Worker>>>>,
 	internal_sender: mpsc::UnboundedSender<JobManagerEvent>,
 	shutdown_tx: Arc<broadcast::Sender<()>>,
+	governor: Arc<Governor>,
+	transfers: Arc<TransferGovernor>,
 }
 
 impl JobManager {
-	pub fn new() -> Arc<Self> {
+	pub fn new(throttle: ThrottleLimits, transfer_limits: TransferLimits) -> Arc<Self> {
 		let (shutdown_tx, _shutdown_rx) = broadcast::channel(1);
 		let (internal_sender, mut internal_receiver) = mpsc::unbounded_channel();
 		let this = Arc::new(Self {
-			job_queue: RwLock::new(VecDeque::new()),
+			job_queues: RwLock::new(HashMap::new()),
 			running_workers: RwLock::new(HashMap::new()),
 			internal_sender,
 			shutdown_tx: Arc::new(shutdown_tx),
+			governor: Arc::new(Governor::new(throttle)),
+			transfers: Arc::new(TransferGovernor::new(transfer_limits)),
 		});
 
 		let this2 = this.clone();
@@ -67,6 +123,10 @@ impl JobManager {
 	}
 
 	pub async fn ingest(self: Arc<Self>, ctx: &LibraryContext, mut job: Box<dyn DynJob>) {
+		if is_held_elsewhere(ctx, job.as_ref()) {
+			return;
+		}
+
 		// create worker to process job
 		let mut running_workers = self.running_workers.write().await;
 		if running_workers.len() < MAX_WORKERS {
@@ -79,7 +139,7 @@ impl JobManager {
 
 			let job_id = job_report.id;
 
-			let worker = Worker::new(job, job_report);
+			let worker = Worker::new(job, job_report, ctx.id);
 
 			let wrapped_worker = Arc::new(Mutex::new(worker));
 
@@ -91,39 +151,193 @@ impl JobManager {
 				running_workers.insert(job_id, wrapped_worker);
 			}
 		} else {
-			self.job_queue.write().await.push_back(job);
+			self.enqueue(ctx, job).await;
 		}
 	}
 
-	pub async fn ingest_queue(&self, _ctx: &LibraryContext, job: Box<dyn DynJob>) {
-		self.job_queue.write().await.push_back(job);
+	pub async fn ingest_queue(&self, ctx: &LibraryContext, job: Box<dyn DynJob>) {
+		if is_held_elsewhere(ctx, job.as_ref()) {
+			return;
+		}
+
+		self.enqueue(ctx, job).await;
+	}
+
+	async fn enqueue(&self, ctx: &LibraryContext, job: Box<dyn DynJob>) {
+		let mut job_queues = self.job_queues.write().await;
+		let queue = job_queues.entry(ctx.id).or_insert_with(|| LibraryQueue {
+			library: ctx.clone(),
+			jobs: VecDeque::new(),
+		});
+		// keep the most recent context, as the library config could have been edited since the queue was created
+		queue.library = ctx.clone();
+		queue.jobs.push_back(job);
+
+		invalidate_query!(ctx, "jobs.getQueue");
 	}
 
 	pub async fn complete(self: Arc<Self>, ctx: &LibraryContext, job_id: Uuid) {
 		// remove worker from running workers
 		self.running_workers.write().await.remove(&job_id);
-		// continue queue
-		let job = self.job_queue.write().await.pop_front();
-		if let Some(job) = job {
+		// the next stage of a location's pipeline runs before the jobs queued after it started
+		match on_job_stopped(ctx, job_id).await {
+			Ok(Some(job)) => {
+				let mut job_queues = self.job_queues.write().await;
+				let queue = job_queues.entry(ctx.id).or_insert_with(|| LibraryQueue {
+					library: ctx.clone(),
+					jobs: VecDeque::new(),
+				});
+				queue.jobs.push_front(job);
+			}
+			Ok(None) => {}
+			Err(e) => error!("Failed to move pipeline of job {} along: {:#?}", job_id, e),
+		}
+		// continue queue, taking turns between libraries so one library can't starve the others
+		let next = {
+			let mut job_queues = self.job_queues.write().await;
+			let mut library_ids = job_queues
+				.iter()
+				.filter(|(_, queue)| !queue.jobs.is_empty())
+				.map(|(library_id, _)| *library_id)
+				.collect::<Vec<_>>();
+			library_ids.sort();
+
+			library_ids
+				.iter()
+				.find(|library_id| **library_id > ctx.id)
+				.or_else(|| library_ids.first())
+				.and_then(|library_id| job_queues.get_mut(library_id))
+				.and_then(|queue| Some((queue.library.clone(), queue.jobs.pop_front()?)))
+		};
+
+		if let Some((library, job)) = next {
+			invalidate_query!(library, "jobs.getQueue");
 			// We can't directly execute `self.ingest` here because it would cause an async cycle.
 			self.internal_sender
-				.send(JobManagerEvent::IngestJob(ctx.clone(), job))
+				.send(JobManagerEvent::IngestJob(library, job))
 				.unwrap_or_else(|_| {
 					error!("Failed to ingest job!");
 				});
 		}
 	}
 
-	pub async fn get_running(&self) -> Vec<JobReport> {
+	/// Drops the queued jobs of a library, used when the library is removed from the node
+	pub async fn clear_queue(&self, library_id: Uuid) {
+		self.job_queues.write().await.remove(&library_id);
+	}
+
+	/// has_job tells if a job named `name` is queued or running for the library, for jobs that only
+	/// need to run once however many times they're asked for
+	pub async fn has_job(&self, ctx: &LibraryContext, name: &str) -> bool {
+		for worker in self.running_workers.read().await.values() {
+			let worker = worker.lock().await;
+			if worker.library_id() == ctx.id && worker.report().name == name {
+				return true;
+			}
+		}
+
+		self.job_queues
+			.read()
+			.await
+			.get(&ctx.id)
+			.map_or(false, |queue| {
+				queue.jobs.iter().any(|job| job.name() == name)
+			})
+	}
+
+	pub async fn get_running(&self, ctx: &LibraryContext) -> Vec<JobReport> {
 		let mut ret = vec![];
 
 		for worker in self.running_workers.read().await.values() {
 			let worker = worker.lock().await;
-			ret.push(worker.report());
+			if worker.library_id() == ctx.id {
+				ret.push(worker.report());
+			}
 		}
 		ret
 	}
 
+	/// Returns the jobs waiting in the queue, in the order they will be run, along with why they are waiting
+	/// and a rough estimate of how long they will take based on previous runs of the same kind of job.
+	pub async fn get_queue(
+		&self,
+		ctx: &LibraryContext,
+	) -> Result<Vec<QueuedJob>, prisma_client_rust::QueryError> {
+		let running_workers = self.running_workers.read().await;
+		let blocked_by = running_workers.keys().copied().collect::<Vec<_>>();
+		let mut estimated_start_seconds = Some(0);
+		for worker in running_workers.values() {
+			let report = worker.lock().await.report();
+			estimated_start_seconds = estimated_start_seconds
+				.zip(estimate_remaining_seconds(&report))
+				.map(|(start, seconds)| start + seconds);
+		}
+		drop(running_workers);
+
+		let average_durations = Self::get_average_durations(ctx).await?;
+
+		let mut queued = vec![];
+		let mut job_queues = self.job_queues.write().await;
+		let jobs = match job_queues.get_mut(&ctx.id) {
+			Some(queue) => &mut queue.jobs,
+			None => return Ok(queued),
+		};
+		for (position, job) in jobs.iter_mut().enumerate() {
+			let init = job.init_json();
+			let report = match job.report().clone() {
+				Some(report) => report,
+				None => continue,
+			};
+			let estimated_seconds = average_durations.get(&report.name).copied();
+
+			queued.push(QueuedJob {
+				report,
+				position,
+				init,
+				estimated_seconds,
+				estimated_start_seconds,
+				blocked_by: blocked_by.clone(),
+			});
+
+			// If we don't know how long a job ahead in the queue will take, we can't estimate the ones behind it
+			estimated_start_seconds = estimated_start_seconds
+				.zip(estimated_seconds)
+				.map(|(start, seconds)| start + seconds);
+		}
+
+		Ok(queued)
+	}
+
+	/// Average duration in seconds of the recently completed jobs, by job name
+	async fn get_average_durations(
+		ctx: &LibraryContext,
+	) -> Result<HashMap<String, i32>, prisma_client_rust::QueryError> {
+		let completed_jobs = ctx
+			.db
+			.job()
+			.find_many(vec![job::status::equals(JobStatus::Completed.int_value())])
+			.order_by(job::date_created::order(Direction::Desc))
+			.take(100)
+			.exec()
+			.await?;
+
+		let mut durations_per_name = HashMap::<_, Vec<_>>::new();
+		for completed_job in completed_jobs {
+			durations_per_name
+				.entry(completed_job.name)
+				.or_default()
+				.push(completed_job.seconds_elapsed);
+		}
+
+		Ok(durations_per_name
+			.into_iter()
+			.map(|(name, durations)| {
+				let average = durations.iter().sum::<i32>() / durations.len() as i32;
+				(name, average)
+			})
+			.collect())
+	}
+
 	pub async fn get_history(
 		ctx: &LibraryContext,
 	) -> Result<Vec<JobReport>, prisma_client_rust::QueryError> {
@@ -136,14 +350,66 @@ impl JobManager {
 			.exec()
 			.await?;
 
-		Ok(jobs.into_iter().map(Into::into).collect())
+		let mut error_counts = HashMap::<Vec<u8>, i32>::new();
+		for error in ctx
+			.db
+			.job_error()
+			.find_many(vec![job_error::job_id::in_vec(
+				jobs.iter().map(|job| job.id.clone()).collect(),
+			)])
+			.exec()
+			.await?
+		{
+			*error_counts.entry(error.job_id).or_default() += 1;
+		}
+
+		Ok(jobs
+			.into_iter()
+			.map(|job| {
+				let error_count = error_counts.get(&job.id).copied().unwrap_or(0);
+				JobReport {
+					error_count,
+					..JobReport::from(job)
+				}
+			})
+			.collect())
+	}
+
+	/// get_file_errors returns the files a job couldn't process, and why
+	pub async fn get_file_errors(
+		ctx: &LibraryContext,
+		job_id: Uuid,
+	) -> Result<Vec<FileError>, prisma_client_rust::QueryError> {
+		Ok(ctx
+			.db
+			.job_error()
+			.find_many(vec![job_error::job_id::equals(job_id.as_bytes().to_vec())])
+			.order_by(job_error::id::order(Direction::Asc))
+			.exec()
+			.await?
+			.into_iter()
+			.map(Into::into)
+			.collect())
 	}
 
 	pub fn shutdown_tx(&self) -> Arc<broadcast::Sender<()>> {
 		Arc::clone(&self.shutdown_tx)
 	}
 
-	pub async fn pause(&self) {
+	/// governor returns what holds jobs to the node's throttling limits
+	pub fn governor(&self) -> Arc<Governor> {
+		Arc::clone(&self.governor)
+	}
+
+	/// transfers returns what holds transfers over the network to the node's limits
+	pub fn transfers(&self) -> Arc<TransferGovernor> {
+		Arc::clone(&self.transfers)
+	}
+
+	/// shutdown tells the running jobs to pause, giving them until [`SHUTDOWN_DEADLINE`] to save
+	/// their state. The jobs still running then are recorded as interrupted, to go on from their
+	/// last checkpoint the next time the node starts.
+	pub async fn shutdown(&self, libraries: &[LibraryContext]) {
 		let running_workers_read_guard = self.running_workers.read().await;
 		if !running_workers_read_guard.is_empty() {
 			self.shutdown_tx
@@ -153,19 +419,67 @@ impl JobManager {
 		// Dropping our handle so jobs can finish
 		drop(running_workers_read_guard);
 
-		loop {
+		let deadline = Instant::now() + SHUTDOWN_DEADLINE;
+		while Instant::now() < deadline {
 			sleep(Duration::from_millis(50)).await;
 			if self.running_workers.read().await.is_empty() {
-				break;
+				return;
+			}
+		}
+
+		for worker in self
+			.running_workers
+			.write()
+			.await
+			.drain()
+			.map(|(_, worker)| worker)
+		{
+			let mut worker = worker.lock().await;
+			if let Some(library) = libraries.iter().find(|lib| lib.id == worker.library_id()) {
+				worker.interrupt(library).await;
 			}
 		}
 	}
 
 	pub async fn resume_jobs(self: Arc<Self>, ctx: &LibraryContext) -> Result<(), JobError> {
+		// the jobs of another process holding the library aren't this node's to pick up
+		if ctx.process_lock.is_read_only() {
+			return Ok(());
+		}
+
+		// jobs this node left running were cut off without shutting down
+		ctx.db
+			.job()
+			.update_many(
+				vec![
+					job::status::equals(JobStatus::Running.int_value()),
+					job::node_id::equals(ctx.node_local_id),
+				],
+				vec![job::status::set(JobStatus::Interrupted.int_value())],
+			)
+			.exec()
+			.await?;
+
+		// interrupted jobs that never checkpointed have nothing to go on from
+		ctx.db
+			.job()
+			.update_many(
+				vec![
+					job::status::equals(JobStatus::Interrupted.int_value()),
+					job::data::equals(None),
+				],
+				vec![job::status::set(JobStatus::Failed.int_value())],
+			)
+			.exec()
+			.await?;
+
 		let paused_jobs = ctx
 			.db
 			.job()
-			.find_many(vec![job::status::equals(JobStatus::Paused.int_value())])
+			.find_many(vec![job::status::in_vec(vec![
+				JobStatus::Paused.int_value(),
+				JobStatus::Interrupted.int_value(),
+			])])
 			.exec()
 			.await?;
 
@@ -173,45 +487,147 @@ impl JobManager {
 			let paused_job = JobReport::from(paused_job_data);
 
 			info!("Resuming job: {}, id: {}", paused_job.name, paused_job.id);
-			match paused_job.name.as_str() {
-				THUMBNAIL_JOB_NAME => {
-					Arc::clone(&self)
-						.ingest(ctx, Job::resume(paused_job, Box::new(ThumbnailJob {}))?)
-						.await;
-				}
-				INDEXER_JOB_NAME => {
-					Arc::clone(&self)
-						.ingest(ctx, Job::resume(paused_job, Box::new(IndexerJob {}))?)
-						.await;
-				}
-				IDENTIFIER_JOB_NAME => {
-					Arc::clone(&self)
-						.ingest(
-							ctx,
-							Job::resume(paused_job, Box::new(FileIdentifierJob {}))?,
-						)
-						.await;
-				}
-				_ => {
-					error!(
-						"Unknown job type: {}, id: {}",
-						paused_job.name, paused_job.id
-					);
-					return Err(JobError::UnknownJobName(paused_job.id, paused_job.name));
-				}
-			};
+			Arc::clone(&self).ingest(ctx, resume_job(paused_job)?).await;
 		}
 
 		Ok(())
 	}
+
+	/// requeue runs a dead-lettered job again from where it stopped, with all of its attempts
+	pub async fn requeue(
+		self: Arc<Self>,
+		ctx: &LibraryContext,
+		job_id: Uuid,
+	) -> Result<(), JobError> {
+		let mut report = ctx
+			.db
+			.job()
+			.find_first(vec![
+				job::id::equals(job_id.as_bytes().to_vec()),
+				job::status::equals(JobStatus::DeadLetter.int_value()),
+			])
+			.exec()
+			.await?
+			.map(JobReport::from)
+			.ok_or(JobError::NotDeadLettered(job_id))?;
+
+		info!("Requeuing job: {}, id: {}", report.name, report.id);
+		report.status = JobStatus::Paused;
+		report.attempts = 0;
+		report.update(ctx).await?;
+
+		self.resume(ctx, report).await
+	}
+
+	/// resume runs a stopped job again from the state saved in its report
+	pub async fn resume(
+		self: Arc<Self>,
+		ctx: &LibraryContext,
+		report: JobReport,
+	) -> Result<(), JobError> {
+		self.ingest(ctx, resume_job(report)?).await;
+
+		invalidate_query!(ctx, "jobs.getHistory");
+
+		Ok(())
+	}
+}
+
+/// is_held_elsewhere tells if the job's library is held by another process, which runs its jobs
+fn is_held_elsewhere(ctx: &LibraryContext, job: &dyn DynJob) -> bool {
+	if ctx.process_lock.is_read_only() {
+		warn!(
+			"Not running job {} as library '{}' is held by another process",
+			job.name(),
+			ctx.id
+		);
+	}
+
+	ctx.process_lock.is_read_only()
+}
+
+/// resume_job picks a job up again from the state saved in its report
+fn resume_job(report: JobReport) -> Result<Box<dyn DynJob>, JobError> {
+	let job: Box<dyn DynJob> = match report.name.as_str() {
+		THUMBNAIL_JOB_NAME => Job::resume(report, Box::new(ThumbnailJob {}))?,
+		INDEXER_JOB_NAME => Job::resume(report, Box::new(IndexerJob {}))?,
+		SWEEP_JOB_NAME => Job::resume(report, Box::new(SweepJob {}))?,
+		STORAGE_SYNC_JOB_NAME => Job::resume(report, Box::new(StorageSyncJob {}))?,
+		IDENTIFIER_JOB_NAME => Job::resume(report, Box::new(FileIdentifierJob {}))?,
+		ENCRYPT_JOB_NAME => Job::resume(report, Box::new(FileEncryptorJob {}))?,
+		DECRYPT_JOB_NAME => Job::resume(report, Box::new(FileDecryptorJob {}))?,
+		ARCHIVE_JOB_NAME => Job::resume(report, Box::new(ArchiveJob {}))?,
+		COPY_JOB_NAME => Job::resume(report, Box::new(FileCopierJob {}))?,
+		RESTORE_JOB_NAME => Job::resume(report, Box::new(FileRestorerJob {}))?,
+		ORGANIZER_JOB_NAME => Job::resume(report, Box::new(OrganizerJob {}))?,
+		RENAMER_JOB_NAME => Job::resume(report, Box::new(RenamerJob {}))?,
+		BATCH_JOB_NAME => Job::resume(report, Box::new(BatchJob {}))?,
+		FOLDER_SYNC_JOB_NAME => Job::resume(report, Box::new(FolderSyncJob {}))?,
+		CHUNK_STORE_JOB_NAME => Job::resume(report, Box::new(ChunkStoreJob {}))?,
+		BACKUP_JOB_NAME => Job::resume(report, Box::new(BackupJob {}))?,
+		RESTORE_BACKUP_JOB_NAME => Job::resume(report, Box::new(RestoreBackupJob {}))?,
+		INGEST_JOB_NAME => Job::resume(report, Box::new(IngestJob {}))?,
+		LOCATION_ERASER_JOB_NAME => Job::resume(report, Box::new(LocationEraserJob {}))?,
+		TRASH_CLEANER_JOB_NAME => Job::resume(report, Box::new(TrashCleanerJob {}))?,
+		PREVIEW_WARMER_JOB_NAME => Job::resume(report, Box::new(PreviewWarmerJob {}))?,
+		INTEGRITY_SAMPLER_JOB_NAME => Job::resume(report, Box::new(IntegritySamplerJob {}))?,
+		VALIDATOR_JOB_NAME => Job::resume(report, Box::new(ObjectValidatorJob {}))?,
+		CHECKSUM_EXPORT_JOB_NAME => Job::resume(report, Box::new(ChecksumExportJob {}))?,
+		CHECKSUM_VERIFY_JOB_NAME => Job::resume(report, Box::new(ChecksumVerifyJob {}))?,
+		IMAGE_LABELER_JOB_NAME => Job::resume(report, Box::new(ImageLabelerJob {}))?,
+		FACE_GROUPER_JOB_NAME => Job::resume(report, Box::new(FaceGrouperJob {}))?,
+		IMPORT_METADATA_JOB_NAME => Job::resume(report, Box::new(ImportMetadataJob {}))?,
+		EXTENSION_JOB_NAME => Job::resume(report, Box::new(ExtensionJobRunner {}))?,
+		MAINTENANCE_JOB_NAME => Job::resume(report, Box::new(MaintenanceJob {}))?,
+		PREVIEW_CACHE_CLEANER_JOB_NAME => Job::resume(report, Box::new(PreviewCacheCleanerJob {}))?,
+		GARBAGE_COLLECTOR_JOB_NAME => Job::resume(report, Box::new(GarbageCollectorJob {}))?,
+		_ => {
+			error!("Unknown job type: {}, id: {}", report.name, report.id);
+			return Err(JobError::UnknownJobName(report.id, report.name));
+		}
+	};
+
+	Ok(job)
+}
+
+/// A job waiting in the [`JobManager`] queue
+#[derive(Debug, Serialize, Type, Clone)]
+pub struct QueuedJob {
+	pub report: JobReport,
+	/// position in the queue, 0 is the next job to run
+	pub position: usize,
+	/// the arguments the job was created with, e.g. which location it will work on
+	pub init: Option<serde_json::Value>,
+	/// how long jobs with the same name took on average, if any ran before
+	pub estimated_seconds: Option<i32>,
+	/// how long until this job is expected to start, if every job ahead of it can be estimated
+	pub estimated_start_seconds: Option<i32>,
+	/// ids of the running jobs this job is waiting on, as only `MAX_WORKERS` jobs can run at once
+	pub blocked_by: Vec<Uuid>,
+}
+
+/// Estimates how long a running job still needs from its elapsed time and completed task ratio,
+/// there's no estimate until the job has completed a task
+fn estimate_remaining_seconds(report: &JobReport) -> Option<i32> {
+	if report.completed_task_count <= 0 {
+		return None;
+	}
+	if report.task_count <= report.completed_task_count {
+		return Some(0);
+	}
+
+	let seconds_per_task = report.seconds_elapsed as f64 / report.completed_task_count as f64;
+	Some((seconds_per_task * (report.task_count - report.completed_task_count) as f64) as i32)
 }
 
 #[derive(Debug)]
 pub enum JobReportUpdate {
 	TaskCount(usize),
 	CompletedTaskCount(usize),
-	Message(String),
+	Message(Message),
 	SecondsElapsed(u64),
+	/// a file couldn't be processed, see `WorkerContext::record_file_error`
+	FileFailed,
 }
 
 #[derive(Debug, Serialize, Deserialize, Type, Clone)]
@@ -228,7 +644,11 @@ pub struct JobReport {
 	pub task_count: i32,
 	pub completed_task_count: i32,
 
-	pub message: String,
+	pub message: Option<Message>,
+	/// how many files the job couldn't process
+	pub error_count: i32,
+	/// how many times the job failed and was retried, see [`super::RetryPolicy`]
+	pub attempts: i32,
 	// pub percentage_complete: f64,
 	// #[ts(type = "string")] // TODO: Make this work with specta
 	pub seconds_elapsed: i32,
@@ -263,7 +683,9 @@ impl From<job::Data> for JobReport {
 					None
 				})
 			}),
-			message: String::new(),
+			message: None,
+			error_count: 0,
+			attempts: data.attempts,
 			seconds_elapsed: data.seconds_elapsed,
 		}
 	}
@@ -282,7 +704,9 @@ impl JobReport {
 			data: None,
 			metadata: None,
 			completed_task_count: 0,
-			message: String::new(),
+			message: None,
+			error_count: 0,
+			attempts: 0,
 			seconds_elapsed: 0,
 		}
 	}
@@ -314,6 +738,7 @@ impl JobReport {
 					job::completed_task_count::set(self.completed_task_count),
 					job::date_modified::set(chrono::Utc::now().into()),
 					job::seconds_elapsed::set(self.seconds_elapsed),
+					job::attempts::set(self.attempts),
 				],
 			)
 			.exec()
@@ -331,4 +756,11 @@ pub enum JobStatus {
 	Canceled = 3,
 	Failed = 4,
 	Paused = 5,
+	/// failed again and again with errors that should have gone away, until it ran out of attempts.
+	/// Its state is kept so it can be requeued.
+	DeadLetter = 6,
+	/// stopped to ask the user what to do before going on, e.g. with files the library already has
+	AwaitingDecision = 7,
+	/// was still running when the node stopped, it goes on from the state it last checkpointed
+	Interrupted = 8,
 }
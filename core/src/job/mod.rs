@@ -1,16 +1,48 @@
-use crate::location::{indexer::IndexerError, LocationError};
+use crate::{
+	extension::ExtensionError,
+	location::{indexer::IndexerError, LocationError},
+	object::{
+		backup::BackupError,
+		chunks::ChunkError,
+		faces::FaceError,
+		fs::{
+			batch::BatchError, ingest::IngestError, organize::OrganizeError, rename::RenameError,
+			sync::FolderSyncError, trash::TrashError,
+		},
+		importer::ImportError,
+		labeler::LabelerError,
+		preview::PreviewCacheError,
+		validation::checksum_file::ChecksumFileError,
+	},
+	prisma::{file_path, job_error},
+	util::message::Message,
+	volume::InsufficientSpace,
+};
 use sd_crypto::Error as CryptoError;
 
 use rmp_serde::{decode::Error as DecodeError, encode::Error as EncodeError};
+use rspc::{ErrorCode, Type};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{collections::VecDeque, fmt::Debug};
+use std::{
+	collections::VecDeque,
+	fmt::Debug,
+	time::{Duration, Instant},
+};
 use thiserror::Error;
 use uuid::Uuid;
 
+mod governor;
 mod job_manager;
+mod retry;
+mod scheduler;
+mod transfer;
 mod worker;
 
+pub use governor::*;
 pub use job_manager::*;
+pub use retry::*;
+pub use scheduler::*;
+pub use transfer::*;
 pub use worker::*;
 
 #[derive(Error, Debug)]
@@ -41,10 +73,99 @@ pub enum JobError {
 	CryptoError(#[from] CryptoError),
 	#[error("Data needed for job execution not found: job <name='{0}'>")]
 	JobDataNotFound(String),
+	#[error("{0}")]
+	InsufficientSpace(#[from] InsufficientSpace),
+	#[error("Compression error: {0}")]
+	CodecError(#[from] sd_codec::Error),
+	#[error("Only dead-lettered jobs can be requeued: job <uuid='{0}'>")]
+	NotDeadLettered(Uuid),
+	#[error("{duplicates} of {total} files are already in the library")]
+	DuplicateImport { duplicates: usize, total: usize },
+	#[error("Job isn't waiting on a decision: job <uuid='{0}'>")]
+	NotAwaitingDecision(Uuid),
+	#[error("Organizer error: {0}")]
+	OrganizeError(#[from] OrganizeError),
+	#[error("Renamer error: {0}")]
+	RenameError(#[from] RenameError),
+	#[error("Checksum file error: {0}")]
+	ChecksumFileError(#[from] ChecksumFileError),
+	#[error("Labeler error: {0}")]
+	LabelerError(#[from] LabelerError),
+	#[error("Face error: {0}")]
+	FaceError(#[from] FaceError),
+	#[error("Import error: {0}")]
+	ImportError(#[from] ImportError),
+	#[error("Extension error: {0}")]
+	ExtensionError(#[from] ExtensionError),
+	#[error("Preview cache error: {0}")]
+	PreviewCacheError(#[from] PreviewCacheError),
+	#[error("Trash error: {0}")]
+	TrashError(#[from] TrashError),
+	#[error("Batch error: {0}")]
+	BatchError(#[from] BatchError),
+	#[error("Folder sync error: {0}")]
+	FolderSyncError(#[from] FolderSyncError),
+	#[error("Chunk store error: {0}")]
+	ChunkError(#[from] ChunkError),
+	#[error("Backup error: {0}")]
+	BackupError(#[from] BackupError),
+	#[error("Ingest error: {0}")]
+	IngestError(#[from] IngestError),
 	#[error("Job paused")]
 	Paused(Vec<u8>),
 }
 
+impl From<JobError> for rspc::Error {
+	fn from(err: JobError) -> Self {
+		match err {
+			JobError::NotDeadLettered(_) | JobError::NotAwaitingDecision(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// A file a job couldn't process. Jobs record these with [`WorkerContext::record_file_error`] and
+/// carry on with their other files, and they're listed with the job's report.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct FileError {
+	pub location_id: Option<i32>,
+	pub file_path_id: Option<i32>,
+	/// relative to the location
+	pub path: String,
+	pub message: Message,
+}
+
+impl FileError {
+	pub fn new(file_path: &file_path::Data, message: impl Into<Message>) -> Self {
+		Self {
+			location_id: Some(file_path.location_id),
+			file_path_id: Some(file_path.id),
+			path: file_path.materialized_path.clone(),
+			message: message.into(),
+		}
+	}
+}
+
+impl From<job_error::Data> for FileError {
+	fn from(data: job_error::Data) -> Self {
+		// errors are stored as messages in JSON, anything else is shown as it is
+		let message =
+			serde_json::from_str(&data.message).unwrap_or(Message::Error { text: data.message });
+
+		Self {
+			location_id: data.location_id,
+			file_path_id: data.file_path_id,
+			path: data.path,
+			message,
+		}
+	}
+}
+
+/// How often a running job saves its state, to go on from there if the node stops before it pauses
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
 pub type JobResult = Result<JobMetadata, JobError>;
 pub type JobMetadata = Option<serde_json::Value>;
 
@@ -78,6 +199,10 @@ pub trait StatefulJob: Send + Sync {
 pub trait DynJob: Send + Sync {
 	fn report(&mut self) -> &mut Option<JobReport>;
 	fn name(&self) -> &'static str;
+	/// The arguments this job was created with, used to show what a queued job is going to work on.
+	fn init_json(&self) -> Option<serde_json::Value>;
+	/// The job's state, so it can be picked up again where it stopped
+	fn state(&self) -> Result<Vec<u8>, JobError>;
 	async fn run(&mut self, ctx: WorkerContext) -> JobResult;
 }
 
@@ -158,6 +283,14 @@ where
 		self.stateful_job.name()
 	}
 
+	fn init_json(&self) -> Option<serde_json::Value> {
+		serde_json::to_value(&self.state.init).ok()
+	}
+
+	fn state(&self) -> Result<Vec<u8>, JobError> {
+		Ok(rmp_serde::to_vec_named(&self.state)?)
+	}
+
 	async fn run(&mut self, ctx: WorkerContext) -> JobResult {
 		// Checking if we have a brand new job, or if we are resuming an old one.
 		if self.state.data.is_none() {
@@ -167,7 +300,11 @@ where
 		let mut shutdown_rx = ctx.shutdown_rx();
 		let shutdown_rx_fut = shutdown_rx.recv();
 		tokio::pin!(shutdown_rx_fut);
+		let mut release_rx = ctx.release_rx();
+		let release_rx_fut = release_rx.recv();
+		tokio::pin!(release_rx_fut);
 
+		let mut last_checkpoint = Instant::now();
 		while !self.state.steps.is_empty() {
 			tokio::select! {
 				step_result = self.stateful_job.execute_step(
@@ -178,14 +315,20 @@ where
 					self.state.steps.pop_front();
 				}
 				_ = &mut shutdown_rx_fut => {
-					return Err(
-						JobError::Paused(
-							rmp_serde::to_vec_named(&self.state)?
-						)
-					);
+					return Err(JobError::Paused(self.state()?));
+				}
+				_ = &mut release_rx_fut => {
+					return Err(JobError::Paused(self.state()?));
 				}
 			}
 			self.state.step_number += 1;
+
+			// the state is only consistent between steps, a job resumed from a checkpoint runs the
+			// step it was in again
+			if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+				ctx.checkpoint(self.state()?);
+				last_checkpoint = Instant::now();
+			}
 		}
 
 		self.stateful_job
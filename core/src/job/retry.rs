@@ -0,0 +1,117 @@
+//! Retrying jobs that fail for reasons that are likely to go away on their own, like the database being
+//! busy or a drive being briefly unavailable. A job is run again from the step it failed on, waiting
+//! twice as long after each failed attempt, and is dead-lettered once it runs out of attempts: it stops
+//! with its state kept, so it can be requeued once whatever was wrong is fixed.
+
+use std::{io, time::Duration};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::{library::LibraryConfig, location::LocationError};
+
+use super::JobError;
+
+/// How a kind of job is retried, set per job name in the library config
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Type, PartialEq, Eq)]
+pub struct RetryPolicy {
+	/// how many times the job runs before it's dead-lettered, 1 never retries it
+	pub max_attempts: u32,
+	/// how long to wait before the first retry
+	pub initial_backoff_secs: u64,
+	/// the longest to wait between two attempts
+	pub max_backoff_secs: u64,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			max_attempts: 4,
+			initial_backoff_secs: 5,
+			max_backoff_secs: 5 * 60,
+		}
+	}
+}
+
+impl RetryPolicy {
+	/// for_job returns the policy of the jobs with the given name, the default one if the library doesn't
+	/// set one for them
+	pub fn for_job(config: &LibraryConfig, job_name: &str) -> Self {
+		config
+			.job_retry_policies
+			.get(job_name)
+			.copied()
+			.unwrap_or_default()
+	}
+
+	/// should_retry returns whether a job that failed with `error` on its `attempt`th run, counting from
+	/// 1, runs again
+	pub fn should_retry(&self, attempt: u32, error: &JobError) -> bool {
+		attempt < self.max_attempts && error.is_transient()
+	}
+
+	/// backoff returns how long to wait after the `attempt`th run failed, counting from 1
+	pub fn backoff(&self, attempt: u32) -> Duration {
+		let secs = self
+			.initial_backoff_secs
+			.saturating_mul(1u64 << attempt.saturating_sub(1).min(32))
+			.min(self.max_backoff_secs);
+
+		Duration::from_secs(secs)
+	}
+}
+
+impl JobError {
+	/// is_transient returns whether the error is likely to go away if the job runs again a bit later
+	pub fn is_transient(&self) -> bool {
+		match self {
+			// SQLite reports a busy database as "database is locked"
+			Self::DatabaseError(e) => {
+				let e = e.to_string().to_lowercase();
+				e.contains("locked") || e.contains("busy") || e.contains("timed out")
+			}
+			Self::LocationError(LocationError::Offline(_)) => true,
+			Self::IOError(e) => matches!(
+				e.kind(),
+				io::ErrorKind::Interrupted
+					| io::ErrorKind::TimedOut
+					| io::ErrorKind::WouldBlock
+					| io::ErrorKind::NotConnected
+					| io::ErrorKind::ConnectionReset
+					| io::ErrorKind::ConnectionAborted
+					| io::ErrorKind::BrokenPipe
+			),
+			_ => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn backoff_doubles_up_to_the_max() {
+		let policy = RetryPolicy::default();
+
+		assert_eq!(policy.backoff(1), Duration::from_secs(5));
+		assert_eq!(policy.backoff(2), Duration::from_secs(10));
+		assert_eq!(policy.backoff(4), Duration::from_secs(40));
+		assert_eq!(policy.backoff(40), Duration::from_secs(5 * 60));
+	}
+
+	#[test]
+	fn retries_transient_errors_only() {
+		let policy = RetryPolicy::default();
+		let offline = JobError::LocationError(LocationError::Offline(1));
+		let timed_out = JobError::IOError(io::ErrorKind::TimedOut.into());
+
+		assert!(policy.should_retry(1, &offline));
+		assert!(policy.should_retry(3, &timed_out));
+		assert!(!policy.should_retry(4, &offline));
+		assert!(!policy.should_retry(
+			1,
+			&JobError::IOError(io::ErrorKind::PermissionDenied.into())
+		));
+	}
+}
@@ -0,0 +1,500 @@
+//! Jobs users set to run again and again, like rescanning a location every night, checking the
+//! integrity of its files every week or maintaining the library's database. Schedules are kept in
+//! the library's database with when they run next, so one that came due while the node was off runs
+//! once it's back. A run is skipped when the location's volume is offline, and the schedule waits
+//! for the next one.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Utc};
+use int_enum::IntEnum;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::interval;
+use tracing::{error, info};
+
+use crate::{
+	invalidate_query,
+	library::{LibraryContext, MaintenanceJob, MaintenanceJobInit},
+	location::{
+		fetch_location, indexer::indexer_job::indexer_job_location, scan_location, sweep_location,
+		LocationError,
+	},
+	object::validation::validator_job::{ObjectValidatorJob, ObjectValidatorJobInit},
+	prisma::schedule,
+};
+
+use super::Job;
+
+/// How often schedules are checked for runs that came due
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("{0}")]
+	LocationError(#[from] LocationError),
+	#[error("Schedule not found (id: {0})")]
+	NotFound(i32),
+	#[error("Invalid recurrence: {0}")]
+	InvalidRecurrence(&'static str),
+	#[error("Invalid schedule: {0}")]
+	InvalidSchedule(&'static str),
+	#[error("Invalid stored recurrence: {0}")]
+	Json(#[from] serde_json::Error),
+}
+
+impl From<ScheduleError> for rspc::Error {
+	fn from(err: ScheduleError) -> Self {
+		match err {
+			ScheduleError::NotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			ScheduleError::InvalidRecurrence(_) | ScheduleError::InvalidSchedule(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			ScheduleError::LocationError(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// What a schedule runs, on its location or on the whole library
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum ScheduledAction {
+	/// index the whole location again
+	FullRescan = 0,
+	/// catch up with what changed in the location since the last run
+	QuickRescan = 1,
+	/// check the files of the location still match their checksums
+	ValidateIntegrity = 2,
+	/// check and repair the library's database, then compact it
+	Maintenance = 3,
+}
+
+impl ScheduledAction {
+	fn on_location(&self) -> bool {
+		!matches!(self, Self::Maintenance)
+	}
+}
+
+/// When a schedule runs, in the node's local time
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, PartialEq, Eq)]
+#[serde(tag = "every", rename_all = "snake_case")]
+pub enum Recurrence {
+	Minutes {
+		minutes: u32,
+	},
+	Day {
+		hour: u32,
+		minute: u32,
+	},
+	/// `weekday` counts from 0 for Monday
+	Week {
+		weekday: u32,
+		hour: u32,
+		minute: u32,
+	},
+}
+
+impl Recurrence {
+	fn validate(&self) -> Result<(), ScheduleError> {
+		let (hour, minute) = match *self {
+			Self::Minutes { minutes } if minutes < 5 => {
+				return Err(ScheduleError::InvalidRecurrence(
+					"runs have to be at least 5 minutes apart",
+				))
+			}
+			Self::Minutes { .. } => return Ok(()),
+			Self::Week { weekday, .. } if weekday > 6 => {
+				return Err(ScheduleError::InvalidRecurrence(
+					"the weekday has to be from 0 to 6",
+				))
+			}
+			Self::Day { hour, minute } | Self::Week { hour, minute, .. } => (hour, minute),
+		};
+
+		match hour < 24 && minute < 60 {
+			true => Ok(()),
+			false => Err(ScheduleError::InvalidRecurrence("not a time of day")),
+		}
+	}
+
+	/// next_run returns the first time the schedule runs after `after`
+	pub fn next_run(&self, after: DateTime<Utc>) -> DateTime<Utc> {
+		let (weekday, hour, minute) = match *self {
+			Self::Minutes { minutes } => return after + chrono::Duration::minutes(minutes as i64),
+			Self::Day { hour, minute } => (None, hour, minute),
+			Self::Week {
+				weekday,
+				hour,
+				minute,
+			} => (Some(weekday), hour, minute),
+		};
+
+		let mut date = after.with_timezone(&Local).naive_local().date();
+		// a time skipped by a daylight saving change doesn't exist that day, so it's the next one
+		for _ in 0..15 {
+			let on_weekday = weekday.map_or(true, |weekday| {
+				date.weekday().num_days_from_monday() == weekday
+			});
+			if let Some(run) =
+				local_time(date, hour, minute).filter(|run| on_weekday && *run > after)
+			{
+				return run;
+			}
+			date = date + chrono::Duration::days(1);
+		}
+
+		after + chrono::Duration::days(1)
+	}
+}
+
+fn local_time(date: NaiveDate, hour: u32, minute: u32) -> Option<DateTime<Utc>> {
+	Local
+		.from_local_datetime(&date.and_hms_opt(hour, minute, 0)?)
+		.earliest()
+		.map(|time| time.with_timezone(&Utc))
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct Schedule {
+	pub id: i32,
+	pub name: String,
+	pub action: ScheduledAction,
+	pub location_id: Option<i32>,
+	pub recurrence: Recurrence,
+	pub enabled: bool,
+	pub next_run: DateTime<Utc>,
+	pub last_run: Option<DateTime<Utc>>,
+	pub last_skipped: Option<String>,
+}
+
+impl TryFrom<schedule::Data> for Schedule {
+	type Error = ScheduleError;
+
+	fn try_from(data: schedule::Data) -> Result<Self, Self::Error> {
+		Ok(Self {
+			id: data.id,
+			action: ScheduledAction::from_int(data.action).unwrap_or(ScheduledAction::QuickRescan),
+			location_id: data.location_id,
+			recurrence: serde_json::from_str(&data.recurrence)?,
+			enabled: data.enabled,
+			next_run: data.next_run.into(),
+			last_run: data.last_run.map(Into::into),
+			last_skipped: data.last_skipped,
+			name: data.name,
+		})
+	}
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct ScheduleCreateArgs {
+	pub name: String,
+	pub action: ScheduledAction,
+	/// required by the actions that run on a location
+	pub location_id: Option<i32>,
+	pub recurrence: Recurrence,
+}
+
+impl ScheduleCreateArgs {
+	pub async fn create(self, library: &LibraryContext) -> Result<Schedule, ScheduleError> {
+		self.recurrence.validate()?;
+		let location_id = match (self.action.on_location(), self.location_id) {
+			(true, None) => return Err(ScheduleError::InvalidSchedule("it needs a location")),
+			(false, Some(_)) => {
+				return Err(ScheduleError::InvalidSchedule(
+					"it runs on the whole library, not a location",
+				))
+			}
+			(_, location_id) => location_id,
+		};
+		if let Some(location_id) = location_id {
+			if fetch_location(library, location_id).exec().await?.is_none() {
+				return Err(LocationError::IdNotFound(location_id).into());
+			}
+		}
+
+		library
+			.db
+			.schedule()
+			.create_unchecked(
+				self.name,
+				self.action.int_value(),
+				serde_json::to_string(&self.recurrence)?,
+				self.recurrence.next_run(Utc::now()).into(),
+				vec![schedule::location_id::set(location_id)],
+			)
+			.exec()
+			.await?
+			.try_into()
+	}
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct ScheduleUpdateArgs {
+	pub id: i32,
+	pub name: Option<String>,
+	pub recurrence: Option<Recurrence>,
+	pub enabled: Option<bool>,
+}
+
+impl ScheduleUpdateArgs {
+	pub async fn update(self, library: &LibraryContext) -> Result<Schedule, ScheduleError> {
+		let current = Schedule::try_from(
+			library
+				.db
+				.schedule()
+				.find_unique(schedule::id::equals(self.id))
+				.exec()
+				.await?
+				.ok_or(ScheduleError::NotFound(self.id))?,
+		)?;
+
+		let mut params = vec![];
+		if let Some(name) = self.name {
+			params.push(schedule::name::set(name));
+		}
+		if let Some(recurrence) = self.recurrence {
+			recurrence.validate()?;
+			params.push(schedule::recurrence::set(serde_json::to_string(
+				&recurrence,
+			)?));
+		}
+		if let Some(enabled) = self.enabled {
+			params.push(schedule::enabled::set(enabled));
+		}
+
+		// a schedule turned back on waits for its next run instead of catching up on the ones it missed
+		if self.recurrence.is_some() || (self.enabled == Some(true) && !current.enabled) {
+			let recurrence = self.recurrence.unwrap_or(current.recurrence);
+			params.push(schedule::next_run::set(
+				recurrence.next_run(Utc::now()).into(),
+			));
+		}
+
+		library
+			.db
+			.schedule()
+			.update(schedule::id::equals(self.id), params)
+			.exec()
+			.await?
+			.try_into()
+	}
+}
+
+/// list_schedules returns the schedules of a library, the ones running soonest first
+pub async fn list_schedules(library: &LibraryContext) -> Result<Vec<Schedule>, ScheduleError> {
+	let mut schedules = library
+		.db
+		.schedule()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(Schedule::try_from)
+		.collect::<Result<Vec<_>, _>>()?;
+	schedules.sort_by_key(|schedule| schedule.next_run);
+
+	Ok(schedules)
+}
+
+/// run starts what a schedule runs, returning why it didn't when it was skipped
+async fn run(
+	library: &LibraryContext,
+	schedule: &Schedule,
+) -> Result<Option<String>, ScheduleError> {
+	if !schedule.action.on_location() {
+		library
+			.spawn_job(Job::new(MaintenanceJobInit {}, Box::new(MaintenanceJob {})))
+			.await;
+		return Ok(None);
+	}
+
+	let location_id = schedule
+		.location_id
+		.ok_or(ScheduleError::InvalidSchedule("it needs a location"))?;
+
+	let location = fetch_location(library, location_id)
+		.include(indexer_job_location::include())
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+
+	if !location.is_online {
+		return Ok(Some(LocationError::Offline(location.id).to_string()));
+	}
+
+	match schedule.action {
+		ScheduledAction::FullRescan => scan_location(library, location).await?,
+		ScheduledAction::QuickRescan => {
+			let since = schedule
+				.last_run
+				.unwrap_or_else(|| location.date_created.into());
+			sweep_location(library, location, since).await?
+		}
+		ScheduledAction::ValidateIntegrity => {
+			library
+				.spawn_job(Job::new(
+					ObjectValidatorJobInit {
+						location_id: location.id,
+						path: Default::default(),
+						background: true,
+					},
+					Box::new(ObjectValidatorJob {}),
+				))
+				.await
+		}
+		// started above, as it doesn't need the location to be online
+		ScheduledAction::Maintenance => {}
+	}
+
+	Ok(None)
+}
+
+/// run_due_schedules runs the schedules that came due and works out when they run next. Runs missed
+/// while the node was off happen once, not once for each time they were missed.
+pub async fn run_due_schedules(library: &LibraryContext) -> Result<(), ScheduleError> {
+	let now = Utc::now();
+	let due = library
+		.db
+		.schedule()
+		.find_many(vec![
+			schedule::enabled::equals(true),
+			schedule::next_run::lte(now.into()),
+		])
+		.exec()
+		.await?;
+
+	if due.is_empty() {
+		return Ok(());
+	}
+
+	for data in due {
+		let id = data.id;
+		let schedule = match Schedule::try_from(data) {
+			Ok(schedule) => schedule,
+			Err(e) => {
+				error!("Disabling schedule {id}: {e}");
+				library
+					.db
+					.schedule()
+					.update(
+						schedule::id::equals(id),
+						vec![schedule::enabled::set(false)],
+					)
+					.exec()
+					.await?;
+				continue;
+			}
+		};
+
+		let skipped = match run(library, &schedule).await {
+			Ok(skipped) => skipped,
+			Err(e) => Some(e.to_string()),
+		};
+		match &skipped {
+			Some(reason) => info!("Skipped schedule '{}': {reason}", schedule.name),
+			None => info!("Ran schedule '{}'", schedule.name),
+		}
+
+		let mut params = vec![
+			schedule::next_run::set(schedule.recurrence.next_run(now).into()),
+			schedule::last_skipped::set(skipped.clone()),
+		];
+		if skipped.is_none() {
+			params.push(schedule::last_run::set(Some(now.into())));
+		}
+
+		library
+			.db
+			.schedule()
+			.update(schedule::id::equals(schedule.id), params)
+			.exec()
+			.await?;
+	}
+
+	invalidate_query!(library, "jobs.schedules.list");
+
+	Ok(())
+}
+
+/// spawn_scheduler starts a task that runs the library's schedules as they come due. The task stops
+/// once the library is unloaded.
+pub fn spawn_scheduler(library: LibraryContext) {
+	tokio::spawn(async move {
+		let mut interval = interval(SCHEDULE_CHECK_INTERVAL);
+
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {}
+				_ = library.stopped() => break,
+			}
+
+			if library.process_lock.is_read_only() {
+				continue;
+			}
+
+			if let Err(e) = run_due_schedules(&library).await {
+				error!(
+					"Failed to run schedules of library '{}': {e:#?}",
+					library.id
+				);
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Timelike;
+
+	#[test]
+	fn finds_next_run() {
+		let now = Utc::now();
+
+		let every = Recurrence::Minutes { minutes: 30 };
+		assert_eq!(every.next_run(now), now + chrono::Duration::minutes(30));
+
+		let nightly = Recurrence::Day {
+			hour: 3,
+			minute: 15,
+		};
+		let next = nightly.next_run(now).with_timezone(&Local);
+		assert!(next > now && next <= now + chrono::Duration::hours(25));
+		assert_eq!((next.hour(), next.minute()), (3, 15));
+
+		let weekly = Recurrence::Week {
+			weekday: 6,
+			hour: 9,
+			minute: 0,
+		};
+		let next = weekly.next_run(now).with_timezone(&Local);
+		assert!(next > now && next <= now + chrono::Duration::days(8));
+		assert_eq!(next.weekday().num_days_from_monday(), 6);
+	}
+
+	#[test]
+	fn rejects_invalid_recurrence() {
+		assert!(Recurrence::Minutes { minutes: 1 }.validate().is_err());
+		assert!(Recurrence::Day {
+			hour: 24,
+			minute: 0
+		}
+		.validate()
+		.is_err());
+		assert!(Recurrence::Week {
+			weekday: 7,
+			hour: 0,
+			minute: 0
+		}
+		.validate()
+		.is_err());
+	}
+}
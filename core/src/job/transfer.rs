@@ -0,0 +1,315 @@
+//! Throttling of what goes over the network, so a backup to the cloud doesn't take over a home
+//! connection during the day. Transfers to and from storage locations and paired peers take a slot
+//! from the node's [`TransferGovernor`] and tell it how much they sent or received, and it holds
+//! them to bandwidth caps and concurrency limits, for all providers together and for each of them.
+//! Jobs also wait for the transfer windows to be open, so syncing can be kept to the night, while
+//! transfers a user is waiting on, like a Spacedrop, go through at any time.
+
+use std::{
+	collections::HashMap,
+	sync::{Mutex, RwLock},
+	time::Duration,
+};
+
+use chrono::{Local, NaiveTime, Timelike};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::{
+	select,
+	sync::Notify,
+	time::{sleep, sleep_until, Instant},
+};
+
+/// Where a transfer goes to or comes from
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Type, PartialEq, Eq, Hash)]
+pub enum TransferProvider {
+	S3,
+	Sftp,
+	GoogleDrive,
+	Dropbox,
+	WebDav,
+	/// the share relay
+	Relay,
+	P2P,
+}
+
+/// Hours of the day, in local time, transfers run within. A window that ends before it starts goes
+/// past midnight, e.g. from 22 to 6, and one that ends when it starts is always open.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Type, PartialEq, Eq)]
+pub struct TransferWindow {
+	pub start_hour: u8,
+	pub end_hour: u8,
+}
+
+impl TransferWindow {
+	fn is_open(&self, hour: u32) -> bool {
+		let (start, end) = (self.start_hour as u32 % 24, self.end_hour as u32 % 24);
+		match start.cmp(&end) {
+			std::cmp::Ordering::Less => (start..end).contains(&hour),
+			std::cmp::Ordering::Greater => hour >= start || hour < end,
+			std::cmp::Ordering::Equal => true,
+		}
+	}
+
+	/// until_open returns how long it is from `now` until the window opens, none if it's open
+	fn until_open(&self, now: NaiveTime) -> Option<Duration> {
+		if self.is_open(now.hour()) {
+			return None;
+		}
+
+		let start = (self.start_hour as i64 % 24) * 3600;
+		let wait = (start - now.num_seconds_from_midnight() as i64).rem_euclid(24 * 3600);
+		Some(Duration::from_secs(wait as u64))
+	}
+}
+
+/// Limits of a provider of its own, on top of the ones of all transfers together
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Type, PartialEq, Eq)]
+pub struct ProviderLimits {
+	pub provider: TransferProvider,
+	pub max_mb_per_sec: Option<u32>,
+	pub max_concurrent: Option<u32>,
+	pub window: Option<TransferWindow>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type, PartialEq, Eq, Default)]
+pub struct TransferLimits {
+	/// how many MB all transfers together send or receive per second, none for as fast as the
+	/// network goes
+	pub max_mb_per_sec: Option<u32>,
+	/// how many transfers run at once, all providers together, none for as many as asked for
+	pub max_concurrent: Option<u32>,
+	/// when jobs transfer files, none for any time
+	pub window: Option<TransferWindow>,
+	pub providers: Vec<ProviderLimits>,
+}
+
+impl TransferLimits {
+	fn provider(&self, provider: TransferProvider) -> Option<&ProviderLimits> {
+		self.providers
+			.iter()
+			.find(|limits| limits.provider == provider)
+	}
+
+	/// until_open returns how long it is until both the transfer window and the provider's are
+	/// open, none if they are
+	fn until_open(&self, provider: TransferProvider, now: NaiveTime) -> Option<Duration> {
+		[
+			self.window,
+			self.provider(provider).and_then(|limits| limits.window),
+		]
+		.iter()
+		.flatten()
+		.filter_map(|window| window.until_open(now))
+		.max()
+	}
+}
+
+#[derive(Default)]
+struct ActiveTransfers {
+	total: u32,
+	by_provider: HashMap<TransferProvider, u32>,
+}
+
+/// TransferGovernor holds transfers to the node's [`TransferLimits`], it's shared by every job
+/// through their [`super::WorkerContext`] and by p2p
+pub struct TransferGovernor {
+	limits: RwLock<TransferLimits>,
+	active: Mutex<ActiveTransfers>,
+	released: Notify,
+	/// when what was transferred so far is paid for at the rate of all transfers together
+	sent_until: Mutex<Instant>,
+	/// the same, for the providers with a rate of their own
+	provider_sent_until: Mutex<HashMap<TransferProvider, Instant>>,
+}
+
+/// A transfer slot, given back when dropped
+pub struct TransferPermit<'a> {
+	governor: &'a TransferGovernor,
+	provider: TransferProvider,
+}
+
+impl Drop for TransferPermit<'_> {
+	fn drop(&mut self) {
+		{
+			let mut active = self.governor.active.lock().unwrap();
+			active.total -= 1;
+			if let Some(count) = active.by_provider.get_mut(&self.provider) {
+				*count -= 1;
+			}
+		}
+		self.governor.released.notify_waiters();
+	}
+}
+
+impl TransferGovernor {
+	pub fn new(limits: TransferLimits) -> Self {
+		Self {
+			limits: RwLock::new(limits),
+			active: Mutex::new(ActiveTransfers::default()),
+			released: Notify::new(),
+			sent_until: Mutex::new(Instant::now()),
+			provider_sent_until: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn limits(&self) -> TransferLimits {
+		self.limits.read().unwrap().clone()
+	}
+
+	/// set_limits changes the limits, transfers that are waiting go by the new ones right away
+	pub fn set_limits(&self, limits: TransferLimits) {
+		*self.limits.write().unwrap() = limits;
+		self.released.notify_waiters();
+	}
+
+	/// is_open tells if jobs can transfer with the provider now, for the ones that would rather not
+	/// start than wait for the transfer window
+	pub fn is_open(&self, provider: TransferProvider) -> bool {
+		self.limits()
+			.until_open(provider, Local::now().time())
+			.is_none()
+	}
+
+	/// transfer waits for the transfer windows to be open and for a transfer slot of the provider,
+	/// which is held until the returned permit is dropped. It's what jobs transfer with.
+	pub async fn transfer(&self, provider: TransferProvider) -> TransferPermit<'_> {
+		loop {
+			let released = self.released.notified();
+			match self.limits().until_open(provider, Local::now().time()) {
+				// woken up early when the limits change
+				Some(wait) => select! {
+					_ = sleep(wait) => {},
+					_ = released => {},
+				},
+				None => return self.transfer_now(provider).await,
+			}
+		}
+	}
+
+	/// transfer_now waits for a transfer slot of the provider whether or not the transfer windows
+	/// are open, for transfers a user is waiting on
+	pub async fn transfer_now(&self, provider: TransferProvider) -> TransferPermit<'_> {
+		loop {
+			// registered before checking, so a slot given back in between isn't missed
+			let released = self.released.notified();
+			{
+				let limits = self.limits();
+				let provider_max = limits
+					.provider(provider)
+					.and_then(|limits| limits.max_concurrent);
+				let mut active = self.active.lock().unwrap();
+				let provider_active = active.by_provider.get(&provider).copied().unwrap_or(0);
+				if limits
+					.max_concurrent
+					.map_or(true, |max| active.total < max.max(1))
+					&& provider_max.map_or(true, |max| provider_active < max.max(1))
+				{
+					active.total += 1;
+					*active.by_provider.entry(provider).or_default() += 1;
+					return TransferPermit {
+						governor: self,
+						provider,
+					};
+				}
+			}
+			released.await;
+		}
+	}
+
+	/// throttle is called after sending or receiving `bytes` with the provider, and waits for as
+	/// long as it takes to keep transfers to the bandwidth caps
+	pub async fn throttle(&self, provider: TransferProvider, bytes: u64) {
+		let limits = self.limits();
+		let rate = |mb_per_sec: u32| mb_per_sec.max(1) as u64 * 1024 * 1024;
+		let pay = |until: &mut Instant, bytes_per_sec: u64| {
+			*until = (*until).max(Instant::now())
+				+ Duration::from_secs_f64(bytes as f64 / bytes_per_sec as f64);
+			*until
+		};
+
+		let mut until = None;
+		if let Some(mb_per_sec) = limits.max_mb_per_sec {
+			until = Some(pay(&mut *self.sent_until.lock().unwrap(), rate(mb_per_sec)));
+		}
+		if let Some(mb_per_sec) = limits
+			.provider(provider)
+			.and_then(|limits| limits.max_mb_per_sec)
+		{
+			let mut provider_sent_until = self.provider_sent_until.lock().unwrap();
+			let provider_until = pay(
+				provider_sent_until
+					.entry(provider)
+					.or_insert_with(Instant::now),
+				rate(mb_per_sec),
+			);
+			until = until.max(Some(provider_until));
+		}
+
+		if let Some(until) = until {
+			sleep_until(until).await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::time::timeout;
+
+	#[test]
+	fn windows_go_past_midnight() {
+		let night = TransferWindow {
+			start_hour: 22,
+			end_hour: 6,
+		};
+		assert!(night.is_open(23) && night.is_open(0) && night.is_open(5));
+		assert!(!night.is_open(6) && !night.is_open(12));
+
+		let noon = NaiveTime::from_hms_opt(12, 30, 0).unwrap();
+		assert_eq!(
+			night.until_open(noon),
+			Some(Duration::from_secs(9 * 3600 + 1800))
+		);
+		assert_eq!(
+			night.until_open(NaiveTime::from_hms_opt(23, 0, 0).unwrap()),
+			None
+		);
+	}
+
+	#[tokio::test]
+	async fn limits_concurrent_transfers_by_provider() {
+		let governor = TransferGovernor::new(TransferLimits {
+			providers: vec![ProviderLimits {
+				provider: TransferProvider::S3,
+				max_mb_per_sec: None,
+				max_concurrent: Some(1),
+				window: None,
+			}],
+			..Default::default()
+		});
+
+		let permit = governor.transfer_now(TransferProvider::S3).await;
+		assert!(timeout(
+			Duration::from_millis(50),
+			governor.transfer_now(TransferProvider::S3)
+		)
+		.await
+		.is_err());
+		// other providers aren't held back by it
+		assert!(timeout(
+			Duration::from_millis(50),
+			governor.transfer_now(TransferProvider::Sftp)
+		)
+		.await
+		.is_ok());
+
+		drop(permit);
+		assert!(timeout(
+			Duration::from_millis(50),
+			governor.transfer_now(TransferProvider::S3)
+		)
+		.await
+		.is_ok());
+	}
+}
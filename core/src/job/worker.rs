@@ -1,6 +1,12 @@
+use crate::api::LibraryEvent;
 use crate::invalidate_query;
-use crate::job::{DynJob, JobError, JobManager, JobReportUpdate, JobStatus};
-use crate::library::LibraryContext;
+use crate::job::{
+	DynJob, FileError, Governor, JobError, JobManager, JobReportUpdate, JobStatus, RetryPolicy,
+	TransferGovernor,
+};
+use crate::library::{audit_job, LibraryContext};
+use crate::prisma::{job, job_error};
+use crate::util::message::Message;
 use std::{sync::Arc, time::Duration};
 use tokio::sync::oneshot;
 use tokio::{
@@ -9,9 +15,10 @@ use tokio::{
 		mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
 		Mutex,
 	},
-	time::{interval_at, Instant},
+	time::{interval_at, sleep, Instant},
 };
-use tracing::{error, info, warn};
+use tracing::{error, info, info_span, warn, Instrument};
+use uuid::Uuid;
 
 use super::{JobMetadata, JobReport};
 
@@ -23,15 +30,29 @@ pub enum WorkerEvent {
 		debounce: bool,
 	},
 	Completed(oneshot::Sender<()>, JobMetadata),
-	Failed(oneshot::Sender<()>),
+	Failed(oneshot::Sender<()>, Message),
+	/// the job ran out of attempts, with its state if it could be saved
+	DeadLettered(oneshot::Sender<()>, Message, Option<Vec<u8>>),
+	/// the job failed and runs again once the delay is over, the message telling when
+	Retrying {
+		attempt: u32,
+		message: Message,
+	},
+	/// the job stopped to ask the user something, with its state to go on from once they answered
+	AwaitingDecision(oneshot::Sender<()>, Message, Vec<u8>),
 	Paused(Vec<u8>, oneshot::Sender<()>),
+	/// the job's state while it runs, saved in case the node stops before the job can pause
+	Checkpointed(Vec<u8>),
 }
 
 #[derive(Clone)]
 pub struct WorkerContext {
+	job_id: Uuid,
 	library_ctx: LibraryContext,
 	events_tx: UnboundedSender<WorkerEvent>,
 	shutdown_tx: Arc<broadcast::Sender<()>>,
+	governor: Arc<Governor>,
+	transfers: Arc<TransferGovernor>,
 }
 
 impl WorkerContext {
@@ -56,9 +77,64 @@ impl WorkerContext {
 		self.library_ctx.clone()
 	}
 
+	/// record_file_error stores why a file couldn't be processed with the job, so users can see which
+	/// files failed instead of them only showing up in the logs
+	pub async fn record_file_error(&self, error: FileError) {
+		warn!(
+			"job '{}' failed on {}: {}",
+			self.job_id, error.path, error.message
+		);
+
+		let message =
+			serde_json::to_string(&error.message).unwrap_or_else(|_| error.message.to_string());
+		if let Err(e) = self
+			.library_ctx
+			.db
+			.job_error()
+			.create(
+				error.path,
+				message,
+				job::id::equals(self.job_id.as_bytes().to_vec()),
+				vec![
+					job_error::location_id::set(error.location_id),
+					job_error::file_path_id::set(error.file_path_id),
+				],
+			)
+			.exec()
+			.await
+		{
+			error!("failed to record file error: {:#?}", e);
+		}
+
+		self.progress(vec![JobReportUpdate::FileFailed]);
+	}
+
 	pub fn shutdown_rx(&self) -> broadcast::Receiver<()> {
 		self.shutdown_tx.subscribe()
 	}
+
+	/// checkpoint saves the job's state while it runs
+	pub(super) fn checkpoint(&self, state: Vec<u8>) {
+		self.events_tx
+			.send(WorkerEvent::Checkpointed(state))
+			.expect("critical error: failed to send worker checkpoint event");
+	}
+
+	/// release_rx is signalled when the job's library is handed over to another process, its jobs
+	/// pause then like when the node shuts down
+	pub fn release_rx(&self) -> broadcast::Receiver<()> {
+		self.library_ctx.process_lock.release_rx()
+	}
+
+	/// governor returns the node's job throttling, which jobs that read a lot of files go through
+	pub fn governor(&self) -> &Governor {
+		&self.governor
+	}
+
+	/// transfers returns the node's network throttling, which jobs that transfer files go through
+	pub fn transfers(&self) -> Arc<TransferGovernor> {
+		Arc::clone(&self.transfers)
+	}
 }
 
 // a worker is a dedicated thread that runs a single job
@@ -66,17 +142,22 @@ impl WorkerContext {
 pub struct Worker {
 	job: Option<Box<dyn DynJob>>,
 	report: JobReport,
+	/// the arguments the job was created with, for the audit log
+	init: Option<serde_json::Value>,
+	library_id: Uuid,
 	worker_events_tx: UnboundedSender<WorkerEvent>,
 	worker_events_rx: Option<UnboundedReceiver<WorkerEvent>>,
 }
 
 impl Worker {
-	pub fn new(job: Box<dyn DynJob>, report: JobReport) -> Self {
+	pub fn new(job: Box<dyn DynJob>, report: JobReport, library_id: Uuid) -> Self {
 		let (worker_events_tx, worker_events_rx) = unbounded_channel();
 
 		Self {
+			init: job.init_json(),
 			job: Some(job),
 			report,
+			library_id,
 			worker_events_tx,
 			worker_events_rx: Some(worker_events_rx),
 		}
@@ -85,6 +166,21 @@ impl Worker {
 	pub fn report(&self) -> JobReport {
 		self.report.clone()
 	}
+
+	pub fn library_id(&self) -> Uuid {
+		self.library_id
+	}
+
+	/// interrupt records the job as interrupted when it didn't pause before the node shut down,
+	/// with the progress it made and the state it last checkpointed
+	pub(super) async fn interrupt(&mut self, library: &LibraryContext) {
+		self.report.status = JobStatus::Interrupted;
+		if let Err(e) = self.report.update(library).await {
+			error!("failed to update job report: {:#?}", e);
+		}
+
+		warn!("{}", self.report);
+	}
 	// spawns a thread and extracts channel sender to communicate with it
 	pub async fn spawn(
 		job_manager: Arc<JobManager>,
@@ -105,85 +201,191 @@ impl Worker {
 			.expect("critical error: missing job on worker");
 
 		let job_id = worker.report.id;
+		let retry_policy = RetryPolicy::for_job(&ctx.config, &worker.report.name);
+		let mut attempt = worker.report.attempts as u32;
 		let old_status = worker.report.status;
 		worker.report.status = JobStatus::Running;
 		if matches!(old_status, JobStatus::Queued) {
 			worker.report.create(&ctx).await?;
 		}
+		ctx.emit_event(LibraryEvent::JobStarted {
+			job_id,
+			name: worker.report.name.clone(),
+		});
+		// everything logged while the job runs goes in its span, and from there to the library's log file
+		let span = info_span!(
+			"job",
+			%job_id,
+			library_id = %ctx.id,
+			name = %worker.report.name
+		);
 		drop(worker);
 
 		invalidate_query!(ctx, "jobs.isRunning");
 		// spawn task to handle receiving events from the worker
 		let library_ctx = ctx.clone();
-		tokio::spawn(Worker::track_progress(
-			Arc::clone(&worker_mutex),
-			worker_events_rx,
-			library_ctx.clone(),
-		));
+		tokio::spawn(
+			Worker::track_progress(
+				Arc::clone(&worker_mutex),
+				worker_events_rx,
+				library_ctx.clone(),
+			)
+			.instrument(span.clone()),
+		);
 
 		// spawn task to handle running the job
-		tokio::spawn(async move {
-			let worker_ctx = WorkerContext {
-				library_ctx,
-				events_tx: worker_events_tx,
-				shutdown_tx: job_manager.shutdown_tx(),
-			};
-
-			// track time
-			let events_tx = worker_ctx.events_tx.clone();
-			tokio::spawn(async move {
-				let mut interval = interval_at(
-					Instant::now() + Duration::from_millis(1000),
-					Duration::from_millis(1000),
-				);
-				loop {
-					interval.tick().await;
-					if events_tx
-						.send(WorkerEvent::Progressed {
-							updates: vec![JobReportUpdate::SecondsElapsed(1)],
-							debounce: false,
-						})
-						.is_err() && events_tx.is_closed()
-					{
-						break;
+		tokio::spawn(
+			async move {
+				let worker_ctx = WorkerContext {
+					job_id,
+					library_ctx,
+					events_tx: worker_events_tx,
+					shutdown_tx: job_manager.shutdown_tx(),
+					governor: job_manager.governor(),
+					transfers: job_manager.transfers(),
+				};
+
+				// track time
+				let events_tx = worker_ctx.events_tx.clone();
+				tokio::spawn(async move {
+					let mut interval = interval_at(
+						Instant::now() + Duration::from_millis(1000),
+						Duration::from_millis(1000),
+					);
+					loop {
+						interval.tick().await;
+						if events_tx
+							.send(WorkerEvent::Progressed {
+								updates: vec![JobReportUpdate::SecondsElapsed(1)],
+								debounce: false,
+							})
+							.is_err() && events_tx.is_closed()
+						{
+							break;
+						}
+					}
+				});
+
+				let (done_tx, done_rx) = oneshot::channel();
+
+				let result = loop {
+					let result = job.run(worker_ctx.clone()).await;
+					attempt += 1;
+					match result {
+						Err(e) if retry_policy.should_retry(attempt, &e) => {
+							let delay = retry_policy.backoff(attempt);
+							warn!(
+								"job '{}' failed on attempt {} of {}, retrying in {:?}: {:#?}",
+								job_id, attempt, retry_policy.max_attempts, delay, e
+							);
+							worker_ctx
+								.events_tx
+								.send(WorkerEvent::Retrying {
+									attempt,
+									message: Message::Retrying {
+										attempt,
+										max_attempts: retry_policy.max_attempts,
+										delay_secs: delay.as_secs(),
+										reason: Message::from(&e).to_string(),
+									},
+								})
+								.expect("critical error: failed to send worker retry event");
+
+							// a job waiting to be retried is paused like a running one when the node shuts down
+							// or hands its library over
+							let mut shutdown_rx = worker_ctx.shutdown_rx();
+							let mut release_rx = worker_ctx.release_rx();
+							let paused = async {
+								tokio::select! {
+									_ = shutdown_rx.recv() => {}
+									_ = release_rx.recv() => {}
+								}
+							};
+							tokio::select! {
+								_ = sleep(delay) => {}
+								_ = paused => {
+									break Err(match job.state() {
+										Ok(state) => JobError::Paused(state),
+										Err(e) => e,
+									});
+								}
+							}
+						}
+						result => break result,
+					}
+				};
+
+				match result {
+					Ok(metadata) => {
+						// handle completion
+						worker_ctx
+							.events_tx
+							.send(WorkerEvent::Completed(done_tx, metadata))
+							.expect("critical error: failed to send worker complete event");
+					}
+					Err(JobError::Paused(state)) => {
+						worker_ctx
+							.events_tx
+							.send(WorkerEvent::Paused(state, done_tx))
+							.expect("critical error: failed to send worker pause event");
+					}
+					Err(e @ JobError::DuplicateImport { .. }) => {
+						info!("job '{}' is waiting on a decision: {}", job_id, e);
+						let event = match job.state() {
+							Ok(state) => {
+								WorkerEvent::AwaitingDecision(done_tx, Message::from(&e), state)
+							}
+							Err(e) => WorkerEvent::Failed(done_tx, Message::from(&e)),
+						};
+						worker_ctx
+							.events_tx
+							.send(event)
+							.expect("critical error: failed to send worker decision event");
+					}
+					Err(e) if attempt > 1 && e.is_transient() => {
+						error!(
+							"job '{}' dead-lettered after {} attempts: {:#?}",
+							job_id, attempt, e
+						);
+						worker_ctx
+							.events_tx
+							.send(WorkerEvent::DeadLettered(
+								done_tx,
+								Message::from(&e),
+								job.state().ok(),
+							))
+							.expect("critical error: failed to send worker dead letter event");
+					}
+					Err(e) => {
+						error!("job '{}' failed with error: {:#?}", job_id, e);
+						worker_ctx
+							.events_tx
+							.send(WorkerEvent::Failed(done_tx, Message::from(&e)))
+							.expect("critical error: failed to send worker fail event");
 					}
 				}
-			});
-
-			let (done_tx, done_rx) = oneshot::channel();
 
-			match job.run(worker_ctx.clone()).await {
-				Ok(metadata) => {
-					// handle completion
-					worker_ctx
-						.events_tx
-						.send(WorkerEvent::Completed(done_tx, metadata))
-						.expect("critical error: failed to send worker complete event");
+				if let Err(e) = done_rx.await {
+					error!("failed to wait for worker completion: {:#?}", e);
 				}
-				Err(JobError::Paused(state)) => {
-					worker_ctx
-						.events_tx
-						.send(WorkerEvent::Paused(state, done_tx))
-						.expect("critical error: failed to send worker pause event");
-				}
-				Err(e) => {
-					error!("job '{}' failed with error: {:#?}", job_id, e);
-					worker_ctx
-						.events_tx
-						.send(WorkerEvent::Failed(done_tx))
-						.expect("critical error: failed to send worker fail event");
-				}
-			}
-
-			if let Err(e) = done_rx.await {
-				error!("failed to wait for worker completion: {:#?}", e);
+				job_manager.complete(&ctx, job_id).await;
 			}
-			job_manager.complete(&ctx, job_id).await;
-		});
+			.instrument(span),
+		);
 
 		Ok(())
 	}
 
+	/// emit_stopped tells clients following the library that a job stopped running
+	fn emit_stopped(library: &LibraryContext, report: &JobReport) {
+		library.emit_event(LibraryEvent::JobCompleted {
+			job_id: report.id,
+			name: report.name.clone(),
+			status: report.status,
+			message: report.message.clone(),
+		});
+	}
+
 	async fn track_progress(
 		worker: Arc<Mutex<Self>>,
 		mut worker_events_rx: UnboundedReceiver<WorkerEvent>,
@@ -217,15 +419,24 @@ impl Worker {
 								worker.report.completed_task_count = completed_task_count as i32;
 							}
 							JobReportUpdate::Message(message) => {
-								worker.report.message = message;
+								worker.report.message = Some(message);
 							}
 							JobReportUpdate::SecondsElapsed(seconds) => {
 								worker.report.seconds_elapsed += seconds as i32;
 							}
+							JobReportUpdate::FileFailed => {
+								worker.report.error_count += 1;
+							}
 						}
 					}
 
 					invalidate_query!(library, "jobs.getRunning");
+					library.emit_event(LibraryEvent::JobProgress {
+						job_id: worker.report.id,
+						task_count: worker.report.task_count,
+						completed_task_count: worker.report.completed_task_count,
+						message: worker.report.message.clone(),
+					});
 				}
 				WorkerEvent::Completed(done_tx, metadata) => {
 					worker.report.status = JobStatus::Completed;
@@ -241,14 +452,18 @@ impl Worker {
 
 					info!("{}", worker.report);
 
+					Worker::emit_stopped(&library, &worker.report);
+					audit_job(&library, &worker.report, worker.init.as_ref()).await;
+
 					done_tx
 						.send(())
 						.expect("critical error: failed to send worker completion");
 
 					break;
 				}
-				WorkerEvent::Failed(done_tx) => {
+				WorkerEvent::Failed(done_tx, message) => {
 					worker.report.status = JobStatus::Failed;
+					worker.report.message = Some(message);
 					worker.report.data = None;
 					if let Err(e) = worker.report.update(&library).await {
 						error!("failed to update job report: {:#?}", e);
@@ -258,12 +473,85 @@ impl Worker {
 
 					warn!("{}", worker.report);
 
+					Worker::emit_stopped(&library, &worker.report);
+					audit_job(&library, &worker.report, worker.init.as_ref()).await;
+
 					done_tx
 						.send(())
 						.expect("critical error: failed to send worker completion");
 
 					break;
 				}
+				WorkerEvent::Retrying { attempt, message } => {
+					worker.report.attempts = attempt as i32;
+					worker.report.message = Some(message);
+					if let Err(e) = worker.report.update(&library).await {
+						error!("failed to update job report: {:#?}", e);
+					}
+
+					invalidate_query!(library, "jobs.getRunning");
+					library.emit_event(LibraryEvent::JobProgress {
+						job_id: worker.report.id,
+						task_count: worker.report.task_count,
+						completed_task_count: worker.report.completed_task_count,
+						message: worker.report.message.clone(),
+					});
+				}
+				WorkerEvent::DeadLettered(done_tx, message, state) => {
+					worker.report.status = JobStatus::DeadLetter;
+					worker.report.message = Some(message);
+					worker.report.data = state;
+					if let Err(e) = worker.report.update(&library).await {
+						error!("failed to update job report: {:#?}", e);
+					}
+
+					invalidate_query!(library, "jobs.isRunning");
+					invalidate_query!(library, "jobs.getRunning");
+					invalidate_query!(library, "jobs.getHistory");
+
+					warn!("{}", worker.report);
+
+					Worker::emit_stopped(&library, &worker.report);
+					audit_job(&library, &worker.report, worker.init.as_ref()).await;
+
+					done_tx
+						.send(())
+						.expect("critical error: failed to send worker completion");
+
+					break;
+				}
+				WorkerEvent::AwaitingDecision(done_tx, message, state) => {
+					worker.report.status = JobStatus::AwaitingDecision;
+					worker.report.message = Some(message);
+					worker.report.data = Some(state);
+					if let Err(e) = worker.report.update(&library).await {
+						error!("failed to update job report: {:#?}", e);
+					}
+
+					invalidate_query!(library, "jobs.isRunning");
+					invalidate_query!(library, "jobs.getRunning");
+					invalidate_query!(library, "jobs.getHistory");
+
+					info!("{}", worker.report);
+
+					Worker::emit_stopped(&library, &worker.report);
+
+					done_tx
+						.send(())
+						.expect("critical error: failed to send worker completion");
+
+					break;
+				}
+				WorkerEvent::Checkpointed(state) => {
+					if worker.report.status != JobStatus::Running {
+						continue;
+					}
+
+					worker.report.data = Some(state);
+					if let Err(e) = worker.report.update(&library).await {
+						error!("failed to update job report: {:#?}", e);
+					}
+				}
 				WorkerEvent::Paused(state, done_tx) => {
 					worker.report.status = JobStatus::Paused;
 					worker.report.data = Some(state);
@@ -275,6 +563,8 @@ impl Worker {
 
 					invalidate_query!(library, "jobs.getHistory");
 
+					Worker::emit_stopped(&library, &worker.report);
+
 					done_tx
 						.send(())
 						.expect("critical error: failed to send worker completion");
@@ -1,24 +1,35 @@
 use api::{CoreEvent, Ctx, Router};
-use job::JobManager;
-use library::LibraryManager;
-use node::NodeConfigManager;
-use std::{path::Path, sync::Arc};
+use extension::{Extension, ExtensionRegistry};
+use job::{Job, JobManager};
+use library::{checkpoint_wal, LibraryManager};
+use location::pipeline::resume_pipelines;
+use node::{ApiToken, HookRunner, NodeConfigManager};
+use object::fs::trash::{TrashCleanerJob, TrashCleanerJobInit};
+use once_cell::sync::OnceCell;
+use std::{
+	path::Path,
+	sync::{Arc, Weak},
+};
 use thiserror::Error;
 use tokio::{
 	fs::{self, File},
 	io::AsyncReadExt,
 	sync::broadcast,
 };
-use tracing::{error, info};
-use tracing_subscriber::{prelude::*, EnvFilter};
+use tracing::{error, info, warn};
 
 pub mod api;
+pub mod extension;
 pub(crate) mod job;
 pub(crate) mod library;
 pub(crate) mod location;
 pub(crate) mod node;
 pub(crate) mod object;
+#[cfg(feature = "p2p")]
+pub(crate) mod p2p;
+pub(crate) mod sync;
 pub(crate) mod util;
+pub(crate) mod vfs;
 pub(crate) mod volume;
 
 pub(crate) mod prisma;
@@ -28,6 +39,10 @@ pub struct NodeContext {
 	pub config: Arc<NodeConfigManager>,
 	pub jobs: Arc<JobManager>,
 	pub event_bus_tx: broadcast::Sender<CoreEvent>,
+	pub extensions: Arc<ExtensionRegistry>,
+	pub hooks: Arc<HookRunner>,
+	/// the node's libraries, once they're loaded. It's weak as the libraries hold this context.
+	pub library_manager: Arc<OnceCell<Weak<LibraryManager>>>,
 }
 
 pub struct Node {
@@ -35,84 +50,51 @@ pub struct Node {
 	library_manager: Arc<LibraryManager>,
 	jobs: Arc<JobManager>,
 	event_bus: (broadcast::Sender<CoreEvent>, broadcast::Receiver<CoreEvent>),
+	extensions: Arc<ExtensionRegistry>,
+	#[cfg(feature = "p2p")]
+	p2p: Option<Arc<p2p::P2PManager>>,
 }
 
-#[cfg(not(feature = "android"))]
-const CONSOLE_LOG_FILTER: tracing_subscriber::filter::LevelFilter = {
-	use tracing_subscriber::filter::LevelFilter;
-
-	match cfg!(debug_assertions) {
-		true => LevelFilter::DEBUG,
-		false => LevelFilter::INFO,
-	}
-};
-
 impl Node {
 	pub async fn new(data_dir: impl AsRef<Path>) -> Result<(Arc<Node>, Arc<Router>), NodeError> {
+		Self::with_extensions(data_dir, vec![]).await
+	}
+
+	/// with_extensions makes a node with the given extensions registered, before the jobs they
+	/// left paused are resumed
+	pub async fn with_extensions(
+		data_dir: impl AsRef<Path>,
+		extensions: Vec<Arc<dyn Extension>>,
+	) -> Result<(Arc<Node>, Arc<Router>), NodeError> {
 		let data_dir = data_dir.as_ref();
 		#[cfg(debug_assertions)]
 		let data_dir = data_dir.join("dev");
 		let _ = fs::create_dir_all(&data_dir).await; // This error is ignore because it throwing on mobile despite the folder existing.
 
-		// dbg!(get_object_kind_from_extension("png"));
-
-		// let (non_blocking, _guard) = tracing_appender::non_blocking(rolling::daily(
-		// 	Path::new(&data_dir).join("logs"),
-		// 	"log",
-		// ));
-		// TODO: Make logs automatically delete after x time https://github.com/tokio-rs/tracing/pull/2169
-
-		let subscriber = tracing_subscriber::registry().with(
-			EnvFilter::from_default_env()
-				.add_directive("warn".parse().expect("Error invalid tracing directive!"))
-				.add_directive(
-					"sd-core=debug"
-						.parse()
-						.expect("Error invalid tracing directive!"),
-				)
-				.add_directive(
-					"sd-core-mobile=debug"
-						.parse()
-						.expect("Error invalid tracing directive!"),
-				)
-				.add_directive(
-					"server=debug"
-						.parse()
-						.expect("Error invalid tracing directive!"),
-				)
-				.add_directive(
-					"desktop=debug"
-						.parse()
-						.expect("Error invalid tracing directive!"),
-				), // .add_directive(
-			    // 	"rspc=debug"
-			    // 		.parse()
-			    // 		.expect("Error invalid tracing directive!"),
-			    // ),
-		);
-		#[cfg(not(feature = "android"))]
-		let subscriber = subscriber.with(tracing_subscriber::fmt::layer().with_filter(CONSOLE_LOG_FILTER));
-		#[cfg(feature = "android")]
-		let subscriber = subscriber.with(tracing_android::layer("com.spacedrive.app").unwrap()); // TODO: This is not working
-		subscriber
-			// .with(
-			// 	Layer::default()
-			// 		.with_writer(non_blocking)
-			// 		.with_ansi(false)
-			// 		.with_filter(LevelFilter::DEBUG),
-			// )
-			.init();
+		util::logging::init(&data_dir);
 
 		let event_bus = broadcast::channel(1024);
 		let config = NodeConfigManager::new(data_dir.to_path_buf()).await?;
 
-		let jobs = JobManager::new();
+		let node_config = config.get().await;
+		let jobs = JobManager::new(node_config.job_throttle, node_config.transfer_limits);
+
+		let registry = Arc::new(ExtensionRegistry::default());
+		for extension in extensions {
+			if let Err(e) = registry.register(extension) {
+				error!("Failed to register extension: {:#?}", e);
+			}
+		}
+
 		let library_manager = LibraryManager::new(
 			data_dir.join("libraries"),
 			NodeContext {
 				config: Arc::clone(&config),
 				jobs: Arc::clone(&jobs),
 				event_bus_tx: event_bus.0.clone(),
+				extensions: Arc::clone(&registry),
+				hooks: Arc::new(HookRunner::default()),
+				library_manager: Default::default(),
 			},
 		)
 		.await?;
@@ -125,15 +107,46 @@ impl Node {
 				if let Err(e) = Arc::clone(&inner_jobs).resume_jobs(&library_ctx).await {
 					error!("Failed to resume jobs for library. {:#?}", e);
 				}
+				if let Err(e) = resume_pipelines(&library_ctx).await {
+					error!("Failed to resume pipelines for library. {:#?}", e);
+				}
+
+				// Enforce the trash retention settings
+				library_ctx
+					.spawn_job(Job::new(
+						TrashCleanerJobInit { empty_all: false },
+						Box::new(TrashCleanerJob {}),
+					))
+					.await;
 			}
 		});
 
+		// the node works without p2p, it just can't see other nodes
+		#[cfg(feature = "p2p")]
+		let p2p = match p2p::P2PManager::new(
+			&config,
+			Arc::clone(&library_manager),
+			event_bus.0.clone(),
+			jobs.transfers(),
+		)
+		.await
+		{
+			Ok(p2p) => Some(p2p),
+			Err(e) => {
+				error!("Failed to start p2p networking: {:#?}", e);
+				None
+			}
+		};
+
 		let router = api::mount();
 		let node = Node {
 			config,
 			library_manager,
 			jobs,
 			event_bus,
+			extensions: registry,
+			#[cfg(feature = "p2p")]
+			p2p,
 		};
 
 		Ok((Arc::new(node), router))
@@ -145,9 +158,32 @@ impl Node {
 			config: Arc::clone(&self.config),
 			jobs: Arc::clone(&self.jobs),
 			event_bus: self.event_bus.0.clone(),
+			extensions: Arc::clone(&self.extensions),
+			#[cfg(feature = "p2p")]
+			p2p: self.p2p.clone(),
 		}
 	}
 
+	/// authorize tells if a client connecting over the network with the given API token is let in
+	pub async fn authorize(&self, token: &str) -> bool {
+		self.config.get().await.authorizes(token)
+	}
+
+	/// ensure_api_token makes an API token with the given name when the node has none yet, so a
+	/// headless node can be connected to from the start. Returns the token if one was made.
+	pub async fn ensure_api_token(&self, name: &str) -> Result<Option<String>, NodeError> {
+		if !self.config.get().await.api_tokens.is_empty() {
+			return Ok(None);
+		}
+
+		let created = ApiToken::generate(name.to_string());
+		self.config
+			.write(|mut config| config.api_tokens.push(created.token))
+			.await?;
+
+		Ok(Some(created.secret))
+	}
+
 	// Note: this system doesn't use chunked encoding which could prove a problem with large files but I can't see an easy way to do chunked encoding with Tauri custom URIs.
 	pub async fn handle_custom_uri(
 		&self,
@@ -171,18 +207,28 @@ impl Node {
 					.join("thumbnails")
 					.join(path[1] /* file_cas_id */)
 					.with_extension("webp");
-				match File::open(&filename).await {
-					Ok(mut file) => {
-						let mut buf = match fs::metadata(&filename).await {
-							Ok(metadata) => Vec::with_capacity(metadata.len() as usize),
-							Err(_) => Vec::new(),
-						};
-
-						file.read_to_end(&mut buf).await.unwrap();
-						(200, "image/webp", buf)
+				object::preview::touch_preview(&filename);
+				read_webp(&filename).await
+			}
+			// a frame of a video's preview strip, as `chapter/<cas_id>/<position>`
+			Some("chapter") => {
+				let position = match path.get(2).map(|position| position.parse::<u32>()) {
+					Some(Ok(position)) if path.len() == 3 => position,
+					_ => {
+						return (
+							400,
+							"text/html",
+							b"Bad Request: Invalid number of parameters".to_vec(),
+						)
 					}
-					Err(_) => (404, "text/html", b"File Not Found".to_vec()),
-				}
+				};
+
+				let filename = Path::new(&self.config.data_directory())
+					.join(object::preview::CHAPTERS_CACHE_DIR_NAME)
+					.join(path[1] /* file_cas_id */)
+					.join(format!("{position}.webp"));
+				object::preview::touch_preview(&filename);
+				read_webp(&filename).await
 			}
 			_ => (
 				400,
@@ -194,11 +240,46 @@ impl Node {
 
 	pub async fn shutdown(&self) {
 		info!("Spacedrive shutting down...");
-		self.jobs.pause().await;
+		let libraries = self.library_manager.get_all_libraries_ctx().await;
+		self.jobs.shutdown(&libraries).await;
+		for library in &libraries {
+			library.unload();
+		}
+		for library in libraries
+			.iter()
+			.filter(|lib| !lib.process_lock.is_read_only())
+		{
+			match checkpoint_wal(&library.db).await {
+				Ok(true) => {}
+				Ok(false) => warn!("Database of library '{}' was busy on shutdown", library.id),
+				Err(e) => error!(
+					"Failed to flush database of library '{}': {e:#?}",
+					library.id
+				),
+			}
+		}
+		self.library_manager.release_locks().await;
+		vfs::unmount_all();
 		info!("Spacedrive Core shutdown successful!");
 	}
 }
 
+/// read_webp responds to a custom URI request with a webp image, if it exists
+async fn read_webp(filename: &Path) -> (u16, &'static str, Vec<u8>) {
+	match File::open(filename).await {
+		Ok(mut file) => {
+			let mut buf = match fs::metadata(filename).await {
+				Ok(metadata) => Vec::with_capacity(metadata.len() as usize),
+				Err(_) => Vec::new(),
+			};
+
+			file.read_to_end(&mut buf).await.unwrap();
+			(200, "image/webp", buf)
+		}
+		Err(_) => (404, "text/html", b"File Not Found".to_vec()),
+	}
+}
+
 /// Error type for Node related errors.
 #[derive(Error, Debug)]
 pub enum NodeError {
@@ -0,0 +1,310 @@
+//! The audit log: every job that ran in the library and every change to files made by hand that
+//! can't be taken back, like deleting them, with when it happened, what it was done with and how it
+//! went. Unlike the job history, entries are never cleared, so users who need to account for what
+//! happened to their archives can export them.
+
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+use int_enum::IntEnum;
+use prisma_client_rust::{Direction, QueryError};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	job::{JobReport, JobStatus},
+	location::eraser::LOCATION_ERASER_JOB_NAME,
+	object::{
+		fs::{
+			encrypt::ENCRYPT_JOB_NAME, organize::ORGANIZER_JOB_NAME, rename::RENAMER_JOB_NAME,
+			trash::TRASH_CLEANER_JOB_NAME,
+		},
+		garbage_collector::GARBAGE_COLLECTOR_JOB_NAME,
+	},
+	prisma::audit_entry,
+};
+
+use super::LibraryContext;
+
+/// What an audit entry is about
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum AuditAction {
+	/// a job that ran, which none of the other actions describe
+	Job = 0,
+	/// files, objects or tags were removed from the library or from disk
+	Delete = 1,
+	/// files were moved or renamed
+	Move = 2,
+	/// files were encrypted
+	Encrypt = 3,
+}
+
+impl AuditAction {
+	/// for_job returns what the job of the given name does
+	pub fn for_job(name: &str) -> Self {
+		match name {
+			LOCATION_ERASER_JOB_NAME | TRASH_CLEANER_JOB_NAME | GARBAGE_COLLECTOR_JOB_NAME => {
+				Self::Delete
+			}
+			ORGANIZER_JOB_NAME | RENAMER_JOB_NAME => Self::Move,
+			ENCRYPT_JOB_NAME => Self::Encrypt,
+			_ => Self::Job,
+		}
+	}
+}
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum AuditOutcome {
+	Succeeded = 0,
+	Failed = 1,
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct AuditEntry {
+	pub id: i32,
+	pub action: AuditAction,
+	pub name: String,
+	pub parameters: Option<serde_json::Value>,
+	pub outcome: AuditOutcome,
+	pub error: Option<String>,
+	pub job_id: Option<Uuid>,
+	pub date_started: Option<DateTime<Utc>>,
+	pub date_finished: DateTime<Utc>,
+}
+
+impl From<audit_entry::Data> for AuditEntry {
+	fn from(data: audit_entry::Data) -> Self {
+		Self {
+			id: data.id,
+			action: AuditAction::from_int(data.action).unwrap_or(AuditAction::Job),
+			name: data.name,
+			parameters: data
+				.parameters
+				.and_then(|parameters| serde_json::from_str(&parameters).ok()),
+			outcome: AuditOutcome::from_int(data.outcome).unwrap_or(AuditOutcome::Failed),
+			error: data.error,
+			job_id: data.job_id.and_then(|id| Uuid::from_slice(&id).ok()),
+			date_started: data.date_started.map(Into::into),
+			date_finished: data.date_finished.into(),
+		}
+	}
+}
+
+/// Which entries to list, all of them by default
+#[derive(Deserialize, Type, Debug, Default)]
+pub struct AuditFilter {
+	/// entries finished from this date on
+	pub from: Option<DateTime<Utc>>,
+	/// entries finished up to this date
+	pub to: Option<DateTime<Utc>>,
+	pub action: Option<AuditAction>,
+}
+
+#[derive(Deserialize, Type, Debug, Clone, Copy)]
+pub enum AuditExportFormat {
+	Json,
+	Csv,
+}
+
+async fn insert_entry(
+	library: &LibraryContext,
+	action: AuditAction,
+	name: &str,
+	parameters: Option<String>,
+	error: Option<String>,
+	mut params: Vec<audit_entry::SetParam>,
+) {
+	let outcome = match error {
+		Some(_) => AuditOutcome::Failed,
+		None => AuditOutcome::Succeeded,
+	};
+
+	params.extend([
+		audit_entry::parameters::set(parameters),
+		audit_entry::error::set(error),
+	]);
+
+	// the change already happened, failing to record it mustn't fail it
+	if let Err(e) = library
+		.db
+		.audit_entry()
+		.create(
+			action.int_value(),
+			name.to_string(),
+			outcome.int_value(),
+			params,
+		)
+		.exec()
+		.await
+	{
+		error!("Failed to record '{}' in the audit log: {:#?}", name, e);
+		return;
+	}
+
+	invalidate_query!(library, "audit.list");
+}
+
+/// audit_operation records a change to files made by hand, with the arguments it was made with and
+/// the error it failed with, if it did
+pub async fn audit_operation<E: Display>(
+	library: &LibraryContext,
+	action: AuditAction,
+	name: &str,
+	parameters: &impl Serialize,
+	error: Option<&E>,
+) {
+	insert_entry(
+		library,
+		action,
+		name,
+		serde_json::to_string(parameters).ok(),
+		error.map(ToString::to_string),
+		vec![],
+	)
+	.await;
+}
+
+/// audit_job records a job that stopped running for good, with the arguments it was created with
+pub(crate) async fn audit_job(
+	library: &LibraryContext,
+	report: &JobReport,
+	init: Option<&serde_json::Value>,
+) {
+	let error = match report.status {
+		JobStatus::Completed => None,
+		_ => Some(
+			report
+				.message
+				.as_ref()
+				.map(ToString::to_string)
+				.unwrap_or_else(|| format!("{:?}", report.status)),
+		),
+	};
+
+	insert_entry(
+		library,
+		AuditAction::for_job(&report.name),
+		&report.name,
+		init.map(ToString::to_string),
+		error,
+		vec![
+			audit_entry::job_id::set(Some(report.id.as_bytes().to_vec())),
+			audit_entry::date_started::set(Some(report.date_created.into())),
+		],
+	)
+	.await;
+}
+
+/// audit_entries lists the entries of the audit log, the latest first
+pub async fn audit_entries(
+	library: &LibraryContext,
+	filter: AuditFilter,
+) -> Result<Vec<AuditEntry>, QueryError> {
+	let mut params = vec![];
+	if let Some(from) = filter.from {
+		params.push(audit_entry::date_finished::gte(from.into()));
+	}
+	if let Some(to) = filter.to {
+		params.push(audit_entry::date_finished::lte(to.into()));
+	}
+	if let Some(action) = filter.action {
+		params.push(audit_entry::action::equals(action.int_value()));
+	}
+
+	Ok(library
+		.db
+		.audit_entry()
+		.find_many(params)
+		.order_by(audit_entry::date_finished::order(Direction::Desc))
+		.exec()
+		.await?
+		.into_iter()
+		.map(Into::into)
+		.collect())
+}
+
+/// csv_field quotes a field of a CSV row when it has to be
+fn csv_field(field: &str) -> String {
+	if field.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", field.replace('"', "\"\""))
+	} else {
+		field.to_string()
+	}
+}
+
+/// export_audit writes the entries out in the given format, for users to keep outside of the library
+pub fn export_audit(entries: &[AuditEntry], format: AuditExportFormat) -> String {
+	match format {
+		AuditExportFormat::Json => serde_json::to_string_pretty(entries).unwrap_or_default(),
+		AuditExportFormat::Csv => {
+			let mut csv = String::from(
+				"id,action,name,outcome,error,job_id,date_started,date_finished,parameters\r\n",
+			);
+			for entry in entries {
+				let row = [
+					entry.id.to_string(),
+					format!("{:?}", entry.action),
+					entry.name.clone(),
+					format!("{:?}", entry.outcome),
+					entry.error.clone().unwrap_or_default(),
+					entry.job_id.map(|id| id.to_string()).unwrap_or_default(),
+					entry
+						.date_started
+						.map(|date| date.to_rfc3339())
+						.unwrap_or_default(),
+					entry.date_finished.to_rfc3339(),
+					entry
+						.parameters
+						.as_ref()
+						.map(ToString::to_string)
+						.unwrap_or_default(),
+				];
+				csv.push_str(
+					&row.iter()
+						.map(|field| csv_field(field))
+						.collect::<Vec<_>>()
+						.join(","),
+				);
+				csv.push_str("\r\n");
+			}
+			csv
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+
+	use super::*;
+
+	#[test]
+	fn exports_csv() {
+		let entry = AuditEntry {
+			id: 1,
+			action: AuditAction::Delete,
+			name: "files.moveToTrash".to_string(),
+			parameters: Some(serde_json::json!({ "file_path_id": 2 })),
+			outcome: AuditOutcome::Failed,
+			error: Some("File \"a, b\" not found".to_string()),
+			job_id: None,
+			date_started: None,
+			date_finished: Utc.timestamp(0, 0),
+		};
+
+		let csv = export_audit(&[entry], AuditExportFormat::Csv);
+		let rows = csv.split("\r\n").collect::<Vec<_>>();
+
+		assert_eq!(rows.len(), 3);
+		assert_eq!(
+			rows[1],
+			"1,Delete,files.moveToTrash,Failed,\"File \"\"a, b\"\" not found\",,,\
+			1970-01-01T00:00:00+00:00,\"{\"\"file_path_id\"\":2}\""
+		);
+	}
+}
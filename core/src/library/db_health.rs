@@ -0,0 +1,440 @@
+//! Telemetry on a library's database: how many rows its tables have, how well its indexes narrow down
+//! rows, and how many pages they take up. It's sampled once a day and kept for a while, so it shows
+//! which tables grow and when a query pattern is about to need an index, before it gets slow.
+
+use std::{collections::HashSet, str::FromStr, time::Duration};
+
+use chrono::{Duration as ChronoDuration, Utc};
+use int_enum::IntEnum;
+use prisma_client_rust::{raw::Raw, Direction, QueryError};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::time::interval;
+use tracing::{error, info};
+
+use crate::{prisma::db_health_sample, util::db::raw_int};
+
+use super::LibraryContext;
+
+/// How often the database is sampled
+const DB_HEALTH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long samples are kept
+const DB_HEALTH_RETENTION_DAYS: i64 = 90;
+/// How far back the history goes when it isn't asked for a number of days
+const DEFAULT_HISTORY_DAYS: u32 = 30;
+/// Tables with more rows than this and no index are warned about, as every lookup reads all of them
+const UNINDEXED_TABLE_ROWS: i32 = 10_000;
+/// Indexes whose first column has more rows per value than this are warned about, as they narrow
+/// lookups down to too many rows
+const UNSELECTIVE_INDEX_ROWS_PER_KEY: i32 = 1_000;
+/// Share of free pages from which the database should be vacuumed
+const FREE_PAGES_RATIO: f64 = 0.2;
+
+/// What a sample is about
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum DbObjectKind {
+	Database = 0,
+	Table = 1,
+	Index = 2,
+}
+
+/// Something about the database that's likely to make it slow
+#[derive(Serialize, Type, Debug, PartialEq, Eq)]
+#[serde(tag = "kind")]
+pub enum DbHealthWarning {
+	UnindexedTable {
+		table: String,
+		rows: i32,
+	},
+	UnselectiveIndex {
+		index: String,
+		table: String,
+		rows_per_key: i32,
+	},
+	/// space left behind by deleted rows, which a VACUUM gives back
+	FreePages {
+		free_pages: i32,
+		pages: i32,
+	},
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct DbHealth {
+	/// the last sample of the database, its tables and indexes
+	pub latest: Vec<db_health_sample::Data>,
+	/// the samples of the database and its tables over time, oldest first
+	pub history: Vec<db_health_sample::Data>,
+	pub warnings: Vec<DbHealthWarning>,
+}
+
+#[derive(Deserialize)]
+struct SchemaObject {
+	#[serde(rename = "type")]
+	kind: String,
+	name: String,
+	tbl_name: String,
+}
+
+#[derive(Deserialize)]
+struct RowCount {
+	#[serde(deserialize_with = "raw_int")]
+	count: i64,
+}
+
+#[derive(Deserialize)]
+struct IndexStat {
+	idx: Option<String>,
+	stat: String,
+}
+
+#[derive(Deserialize)]
+struct PageUsage {
+	name: String,
+	#[serde(deserialize_with = "raw_int")]
+	pages: i64,
+	#[serde(deserialize_with = "raw_int")]
+	bytes: i64,
+}
+
+#[derive(Deserialize)]
+struct DatabasePages {
+	#[serde(deserialize_with = "raw_int")]
+	page_count: i64,
+	#[serde(deserialize_with = "raw_int")]
+	freelist_count: i64,
+	#[serde(deserialize_with = "raw_int")]
+	page_size: i64,
+}
+
+/// parse_index_stat reads how many entries an index has and how many rows share a value of its first
+/// column from its `sqlite_stat1` stat, e.g. "5000 250" for 5000 entries and 250 rows per value
+fn parse_index_stat(stat: &str) -> (Option<i32>, Option<i32>) {
+	let mut numbers = stat
+		.split_whitespace()
+		.map(|number| i32::from_str(number).ok());
+
+	(numbers.next().flatten(), numbers.next().flatten())
+}
+
+fn clamp<T: TryInto<i32>>(value: T) -> i32 {
+	value.try_into().unwrap_or(i32::MAX)
+}
+
+/// capture_db_health samples the database, its tables and indexes, and drops the samples that are too
+/// old to keep. Returns how many were taken.
+pub async fn capture_db_health(library: &LibraryContext) -> Result<usize, QueryError> {
+	let db = &library.db;
+
+	let objects: Vec<SchemaObject> = db
+		._query_raw(Raw::new(
+			"SELECT type, name, tbl_name FROM sqlite_master WHERE type IN ('table', 'index') \
+			AND tbl_name NOT LIKE 'sqlite_%' AND tbl_name NOT LIKE '_prisma_%'",
+			vec![],
+		))
+		.exec()
+		.await?;
+
+	// refreshes `sqlite_stat1`, which SQLite also plans queries with
+	db._execute_raw(Raw::new("ANALYZE", vec![])).exec().await?;
+	let index_stats: Vec<IndexStat> = db
+		._query_raw(Raw::new("SELECT idx, stat FROM sqlite_stat1", vec![]))
+		.exec()
+		.await?;
+
+	// dbstat is only there when SQLite was built with it
+	let page_usage: Vec<PageUsage> = db
+		._query_raw(Raw::new(
+			"SELECT name, COUNT(*) AS pages, SUM(pgsize) AS bytes FROM dbstat GROUP BY name",
+			vec![],
+		))
+		.exec()
+		.await
+		.unwrap_or_default();
+
+	let mut samples = vec![];
+	for object in &objects {
+		let pages = page_usage.iter().find(|usage| usage.name == object.name);
+		let mut params = vec![
+			db_health_sample::pages::set(pages.map(|usage| clamp(usage.pages))),
+			db_health_sample::bytes::set(pages.map(|usage| usage.bytes.to_string())),
+		];
+
+		let kind = match object.kind.as_str() {
+			"table" => {
+				let count: Vec<RowCount> = db
+					._query_raw(Raw::new(
+						&format!("SELECT COUNT(*) AS count FROM \"{}\"", object.name),
+						vec![],
+					))
+					.exec()
+					.await?;
+				params.push(db_health_sample::rows::set(
+					count.first().map(|count| clamp(count.count)),
+				));
+				DbObjectKind::Table
+			}
+			_ => {
+				let stat = index_stats
+					.iter()
+					.find(|stat| stat.idx.as_deref() == Some(object.name.as_str()))
+					.map(|stat| parse_index_stat(&stat.stat));
+				let (rows, rows_per_key) = stat.unwrap_or_default();
+				params.extend([
+					db_health_sample::rows::set(rows),
+					db_health_sample::rows_per_key::set(rows_per_key),
+				]);
+				DbObjectKind::Index
+			}
+		};
+
+		samples.push(db_health_sample::create_unchecked(
+			kind.int_value(),
+			object.name.clone(),
+			object.tbl_name.clone(),
+			params,
+		));
+	}
+
+	let pages: Vec<DatabasePages> = db
+		._query_raw(Raw::new(
+			"SELECT (SELECT page_count FROM pragma_page_count()) AS page_count, \
+			(SELECT freelist_count FROM pragma_freelist_count()) AS freelist_count, \
+			(SELECT page_size FROM pragma_page_size()) AS page_size",
+			vec![],
+		))
+		.exec()
+		.await?;
+	if let Some(pages) = pages.first() {
+		samples.push(db_health_sample::create_unchecked(
+			DbObjectKind::Database.int_value(),
+			String::new(),
+			String::new(),
+			vec![
+				db_health_sample::pages::set(Some(clamp(pages.page_count))),
+				db_health_sample::bytes::set(Some(
+					(pages.page_count * pages.page_size).to_string(),
+				)),
+				db_health_sample::free_pages::set(Some(clamp(pages.freelist_count))),
+			],
+		));
+	}
+
+	let count = samples.len();
+	db.db_health_sample().create_many(samples).exec().await?;
+	db.db_health_sample()
+		.delete_many(vec![db_health_sample::date_captured::lt(
+			(Utc::now() - ChronoDuration::days(DB_HEALTH_RETENTION_DAYS)).into(),
+		)])
+		.exec()
+		.await?;
+
+	info!(
+		"Captured the health of {count} database objects of library '{}'",
+		library.id
+	);
+
+	Ok(count)
+}
+
+/// warnings looks for what's likely to make the database slow in its last sample
+fn warnings(latest: &[db_health_sample::Data]) -> Vec<DbHealthWarning> {
+	let indexed_tables = latest
+		.iter()
+		.filter(|sample| sample.kind == DbObjectKind::Index.int_value())
+		.map(|sample| sample.table_name.as_str())
+		.collect::<HashSet<_>>();
+
+	latest
+		.iter()
+		.filter_map(|sample| match DbObjectKind::from_int(sample.kind).ok()? {
+			DbObjectKind::Table => {
+				let rows = sample.rows?;
+				(rows > UNINDEXED_TABLE_ROWS && !indexed_tables.contains(sample.name.as_str()))
+					.then(|| DbHealthWarning::UnindexedTable {
+						table: sample.name.clone(),
+						rows,
+					})
+			}
+			DbObjectKind::Index => {
+				let rows_per_key = sample.rows_per_key?;
+				(rows_per_key > UNSELECTIVE_INDEX_ROWS_PER_KEY).then(|| {
+					DbHealthWarning::UnselectiveIndex {
+						index: sample.name.clone(),
+						table: sample.table_name.clone(),
+						rows_per_key,
+					}
+				})
+			}
+			DbObjectKind::Database => {
+				let (pages, free_pages) = (sample.pages?, sample.free_pages?);
+				(free_pages as f64 > pages as f64 * FREE_PAGES_RATIO)
+					.then_some(DbHealthWarning::FreePages { free_pages, pages })
+			}
+		})
+		.collect()
+}
+
+/// db_health returns the last sample of the database with what looks wrong in it, and the samples of
+/// the last `days` days. The database is sampled first if it never was.
+pub async fn db_health(
+	library: &LibraryContext,
+	days: Option<u32>,
+) -> Result<DbHealth, QueryError> {
+	let db = &library.db;
+
+	let last = match db
+		.db_health_sample()
+		.find_first(vec![])
+		.order_by(db_health_sample::date_captured::order(Direction::Desc))
+		.exec()
+		.await?
+	{
+		Some(last) => last,
+		None => {
+			capture_db_health(library).await?;
+			match db
+				.db_health_sample()
+				.find_first(vec![])
+				.order_by(db_health_sample::date_captured::order(Direction::Desc))
+				.exec()
+				.await?
+			{
+				Some(last) => last,
+				None => {
+					return Ok(DbHealth {
+						latest: vec![],
+						history: vec![],
+						warnings: vec![],
+					})
+				}
+			}
+		}
+	};
+
+	// a sample is written all at once, so its rows share their date
+	let latest = db
+		.db_health_sample()
+		.find_many(vec![db_health_sample::date_captured::equals(
+			last.date_captured,
+		)])
+		.exec()
+		.await?;
+
+	let since = Utc::now() - ChronoDuration::days(days.unwrap_or(DEFAULT_HISTORY_DAYS) as i64);
+	let history = db
+		.db_health_sample()
+		.find_many(vec![
+			db_health_sample::date_captured::gte(since.into()),
+			db_health_sample::kind::not(DbObjectKind::Index.int_value()),
+		])
+		.order_by(db_health_sample::date_captured::order(Direction::Asc))
+		.exec()
+		.await?;
+
+	Ok(DbHealth {
+		warnings: warnings(&latest),
+		latest,
+		history,
+	})
+}
+
+/// spawn_db_health samples the database of a library once a day, for as long as the library is loaded
+pub fn spawn_db_health(library: LibraryContext) {
+	tokio::spawn(async move {
+		let mut interval = interval(DB_HEALTH_INTERVAL);
+
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {}
+				_ = library.stopped() => break,
+			}
+
+			if library.process_lock.is_read_only() {
+				continue;
+			}
+
+			if let Err(e) = capture_db_health(&library).await {
+				error!(
+					"Failed to capture the database health of library '{}': {e:#?}",
+					library.id
+				);
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sample(
+		kind: DbObjectKind,
+		name: &str,
+		table_name: &str,
+		rows: Option<i32>,
+		rows_per_key: Option<i32>,
+	) -> db_health_sample::Data {
+		db_health_sample::Data {
+			id: 0,
+			date_captured: Utc::now().into(),
+			kind: kind.int_value(),
+			name: name.to_string(),
+			table_name: table_name.to_string(),
+			rows,
+			rows_per_key,
+			pages: None,
+			bytes: None,
+			free_pages: None,
+		}
+	}
+
+	#[test]
+	fn parses_index_stats() {
+		assert_eq!(parse_index_stat("5000 250 1"), (Some(5000), Some(250)));
+		assert_eq!(parse_index_stat("12"), (Some(12), None));
+	}
+
+	#[test]
+	fn warns_about_unindexed_tables_and_unselective_indexes() {
+		let latest = vec![
+			sample(
+				DbObjectKind::Table,
+				"file_path",
+				"file_path",
+				Some(50_000),
+				None,
+			),
+			sample(
+				DbObjectKind::Index,
+				"file_path_location_id_idx",
+				"file_path",
+				Some(50_000),
+				Some(25_000),
+			),
+			sample(
+				DbObjectKind::Table,
+				"sync_event",
+				"sync_event",
+				Some(80_000),
+				None,
+			),
+			sample(DbObjectKind::Table, "tag", "tag", Some(20), None),
+		];
+
+		assert_eq!(
+			warnings(&latest),
+			vec![
+				DbHealthWarning::UnselectiveIndex {
+					index: "file_path_location_id_idx".to_string(),
+					table: "file_path".to_string(),
+					rows_per_key: 25_000,
+				},
+				DbHealthWarning::UnindexedTable {
+					table: "sync_event".to_string(),
+					rows: 80_000,
+				},
+			]
+		);
+	}
+}
@@ -0,0 +1,302 @@
+//! Undo and redo of what users do to their files by hand: tagging and untagging them, renaming and
+//! moving them. Each command is recorded as a batch of operations, so undoing a tag given to a
+//! thousand files takes it from all of them at once. Operations that no longer apply when they're
+//! replayed, like a move of a file that was moved again since, are skipped. The history only lives
+//! in memory and is lost when the library is closed.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{
+	invalidate_query,
+	location::LocationError,
+	object::fs::{
+		batch::{local_location, set_tag},
+		organize::{move_file_path, OrganizeError},
+	},
+	prisma::{file_path, object, tag},
+	sync::SyncError,
+};
+
+use super::LibraryContext;
+
+/// How many batches can be undone, older ones are forgotten
+const MAX_HISTORY: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("Sync error: {0}")]
+	SyncError(#[from] SyncError),
+	#[error("Organizer error: {0}")]
+	OrganizeError(#[from] OrganizeError),
+}
+
+impl From<HistoryError> for rspc::Error {
+	fn from(err: HistoryError) -> Self {
+		rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+	}
+}
+
+/// A change that can be undone, as it was made
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub enum Operation {
+	/// the tag was given to the object, or taken from it
+	Tag {
+		tag_id: i32,
+		object_id: i32,
+		assigned: bool,
+	},
+	/// the file path was moved or renamed, with materialized paths
+	Move {
+		location_id: i32,
+		file_path_id: i32,
+		from: String,
+		to: String,
+	},
+}
+
+impl Operation {
+	/// apply makes the change again, or reverts it, returning false if it no longer applies
+	async fn apply(&self, library: &LibraryContext, reverse: bool) -> Result<bool, HistoryError> {
+		match self {
+			Self::Tag {
+				tag_id,
+				object_id,
+				assigned,
+			} => {
+				let tag = library
+					.db
+					.tag()
+					.find_unique(tag::id::equals(*tag_id))
+					.exec()
+					.await?;
+				let object = library
+					.db
+					.object()
+					.find_unique(object::id::equals(*object_id))
+					.exec()
+					.await?;
+
+				match (tag, object) {
+					(Some(tag), Some(object)) => {
+						Ok(set_tag(library, &tag, &object, *assigned == reverse).await?)
+					}
+					_ => Ok(false),
+				}
+			}
+			Self::Move {
+				location_id,
+				file_path_id,
+				from,
+				to,
+			} => {
+				let (from, to) = match reverse {
+					true => (to, from),
+					false => (from, to),
+				};
+
+				// the file may have been moved, renamed or deleted again since
+				let is_at_from = library
+					.db
+					.file_path()
+					.find_unique(file_path::location_id_id(*location_id, *file_path_id))
+					.exec()
+					.await?
+					.map_or(false, |file_path| &file_path.materialized_path == from);
+				if !is_at_from {
+					return Ok(false);
+				}
+
+				let (location_path, location_pub_id) =
+					match local_location(library, *location_id).await {
+						Ok(location) => location,
+						Err(LocationError::DatabaseError(e)) => return Err(e.into()),
+						Err(e) => {
+							warn!("Not moving '{from}' to '{to}': {e}");
+							return Ok(false);
+						}
+					};
+				if !location_path.join(from).exists() || location_path.join(to).exists() {
+					return Ok(false);
+				}
+
+				move_file_path(
+					library,
+					*location_id,
+					&location_path,
+					&location_pub_id,
+					*file_path_id,
+					from,
+					to,
+				)
+				.await?;
+
+				Ok(true)
+			}
+		}
+	}
+}
+
+struct OperationBatch {
+	name: String,
+	date: DateTime<Utc>,
+	operations: Vec<Operation>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct HistoryEntry {
+	/// what the user did, e.g. "Tag 12 files"
+	pub name: String,
+	pub date: DateTime<Utc>,
+	pub operations: usize,
+}
+
+impl From<&OperationBatch> for HistoryEntry {
+	fn from(batch: &OperationBatch) -> Self {
+		Self {
+			name: batch.name.clone(),
+			date: batch.date,
+			operations: batch.operations.len(),
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct HistoryEntries {
+	/// what can be undone, the latest first
+	pub undo: Vec<HistoryEntry>,
+	/// what can be redone, the latest undone first
+	pub redo: Vec<HistoryEntry>,
+}
+
+/// What undoing or redoing a batch did
+#[derive(Serialize, Type, Debug)]
+pub struct ReplayReport {
+	pub name: String,
+	pub applied: usize,
+	/// operations that no longer applied
+	pub skipped: usize,
+}
+
+#[derive(Default)]
+struct HistoryStacks {
+	undo: VecDeque<OperationBatch>,
+	redo: Vec<OperationBatch>,
+}
+
+/// History holds the batches of operations of a library that can be undone, and the ones that were
+/// undone and can be redone until something else is done
+#[derive(Default)]
+pub struct History(Mutex<HistoryStacks>);
+
+impl History {
+	fn record(&self, name: String, operations: Vec<Operation>) {
+		let mut stacks = self.0.lock().unwrap();
+		stacks.undo.push_back(OperationBatch {
+			name,
+			date: Utc::now(),
+			operations,
+		});
+		if stacks.undo.len() > MAX_HISTORY {
+			stacks.undo.pop_front();
+		}
+		stacks.redo.clear();
+	}
+
+	pub fn entries(&self) -> HistoryEntries {
+		let stacks = self.0.lock().unwrap();
+		HistoryEntries {
+			undo: stacks.undo.iter().rev().map(Into::into).collect(),
+			redo: stacks.redo.iter().rev().map(Into::into).collect(),
+		}
+	}
+}
+
+/// record_operations adds what a command did to the library's history, unless it did nothing
+pub fn record_operations(
+	library: &LibraryContext,
+	name: impl Into<String>,
+	operations: Vec<Operation>,
+) {
+	if operations.is_empty() {
+		return;
+	}
+
+	library.history.record(name.into(), operations);
+	invalidate_query!(library, "history.list");
+}
+
+/// replay undoes the latest batch, or redoes the latest undone one, returning None if there isn't
+/// one. A batch that fails halfway is put back, so it can be tried again: the operations it had
+/// already replayed are skipped then.
+async fn replay(
+	library: &LibraryContext,
+	undo: bool,
+) -> Result<Option<ReplayReport>, HistoryError> {
+	let batch = {
+		let mut stacks = library.history.0.lock().unwrap();
+		match undo {
+			true => stacks.undo.pop_back(),
+			false => stacks.redo.pop(),
+		}
+	};
+	let batch = match batch {
+		Some(batch) => batch,
+		None => return Ok(None),
+	};
+
+	let mut report = ReplayReport {
+		name: batch.name.clone(),
+		applied: 0,
+		skipped: 0,
+	};
+	let mut result = Ok(());
+	let mut operations = batch.operations.iter().collect::<Vec<_>>();
+	// operations are undone in the reverse order they were made
+	if undo {
+		operations.reverse();
+	}
+	for operation in operations {
+		match operation.apply(library, undo).await {
+			Ok(true) => report.applied += 1,
+			Ok(false) => report.skipped += 1,
+			Err(e) => {
+				result = Err(e);
+				break;
+			}
+		}
+	}
+
+	{
+		let mut stacks = library.history.0.lock().unwrap();
+		match (undo, result.is_ok()) {
+			(true, true) | (false, false) => stacks.redo.push(batch),
+			(true, false) | (false, true) => stacks.undo.push_back(batch),
+		}
+	}
+
+	invalidate_query!(library, "history.list");
+	if report.applied > 0 {
+		invalidate_query!(library, "locations.getExplorerData");
+		invalidate_query!(library, "tags.getForObject");
+	}
+
+	result.map(|_| Some(report))
+}
+
+/// undo reverts the latest batch of operations
+pub async fn undo(library: &LibraryContext) -> Result<Option<ReplayReport>, HistoryError> {
+	replay(library, true).await
+}
+
+/// redo makes the latest undone batch of operations again
+pub async fn redo(library: &LibraryContext) -> Result<Option<ReplayReport>, HistoryError> {
+	replay(library, false).await
+}
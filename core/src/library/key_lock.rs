@@ -0,0 +1,88 @@
+use std::{
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use tokio::time::interval;
+use tracing::{error, info};
+
+use crate::invalidate_query;
+
+use super::LibraryContext;
+
+/// How often an unlocked library is checked for being idle
+const AUTO_LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// KeyLock keeps track of when a library's key manager was last used, so it can be locked again
+/// once it has been left unlocked and idle for longer than the library's auto lock timeout.
+pub struct KeyLock {
+	last_activity: Mutex<Instant>,
+	timeout: Mutex<Option<Duration>>,
+}
+
+impl KeyLock {
+	pub(super) fn new(timeout: Option<Duration>) -> Self {
+		Self {
+			last_activity: Mutex::new(Instant::now()),
+			timeout: Mutex::new(timeout),
+		}
+	}
+
+	/// touch marks the key manager as in use, postponing the auto lock
+	pub fn touch(&self) {
+		*self.last_activity.lock().unwrap() = Instant::now();
+	}
+
+	/// timeout returns how long the key manager may be idle before it is locked, `None` meaning never
+	pub fn timeout(&self) -> Option<Duration> {
+		*self.timeout.lock().unwrap()
+	}
+
+	pub(super) fn set_timeout(&self, timeout: Option<Duration>) {
+		*self.timeout.lock().unwrap() = timeout;
+		self.touch();
+	}
+
+	fn is_expired(&self) -> bool {
+		match self.timeout() {
+			Some(timeout) => self.last_activity.lock().unwrap().elapsed() >= timeout,
+			None => false,
+		}
+	}
+
+	/// lock forgets the master password and unmounts every key, so they can't be used until the library is unlocked again
+	pub fn lock(library: &LibraryContext) -> Result<(), sd_crypto::Error> {
+		library.key_manager.empty_keymount();
+		library.key_manager.clear_master_password()?;
+
+		invalidate_query!(library, "keys.hasMasterPassword");
+		invalidate_query!(library, "keys.listMounted");
+		Ok(())
+	}
+
+	/// spawn_watcher starts a task that locks the library once it is idle for too long.
+	/// The task stops once the library is unloaded.
+	pub(super) fn spawn_watcher(library: LibraryContext) {
+		tokio::spawn(async move {
+			let mut interval = interval(AUTO_LOCK_CHECK_INTERVAL);
+
+			loop {
+				tokio::select! {
+					_ = interval.tick() => {}
+					_ = library.stopped() => break,
+				}
+
+				if !library.key_lock.is_expired()
+					|| !library.key_manager.has_master_password().unwrap_or(false)
+				{
+					continue;
+				}
+
+				info!("Locking keys for library '{}' after inactivity", library.id);
+				if let Err(e) = Self::lock(&library) {
+					error!("Failed to lock keys for library '{}': {e:#?}", library.id);
+				}
+			}
+		});
+	}
+}
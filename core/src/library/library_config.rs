@@ -1,4 +1,5 @@
 use std::{
+	collections::HashMap,
 	fs::File,
 	io::{BufReader, Seek, SeekFrom},
 	path::PathBuf,
@@ -9,9 +10,16 @@ use serde::{Deserialize, Serialize};
 use std::io::Write;
 use uuid::Uuid;
 
-use crate::node::ConfigMetadata;
+use crate::{
+	job::RetryPolicy,
+	node::ConfigMetadata,
+	object::{
+		fs::trash::TrashRetention,
+		preview::{PreviewCacheLimit, ProcessingBudget, VideoChapters},
+	},
+};
 
-use super::LibraryManagerError;
+use super::{LibraryManagerError, SparseCheckout};
 
 /// LibraryConfig holds the configuration for a specific library. This is stored as a '{uuid}.sdlibrary' file.
 #[derive(Debug, Serialize, Deserialize, Clone, Type, Default)]
@@ -22,6 +30,30 @@ pub struct LibraryConfig {
 	pub name: String,
 	/// description is a user set description of the library. This is used in the UI and is set by the user.
 	pub description: String,
+	/// key_auto_lock_timeout is how many seconds the key manager may stay unlocked without being used. `None` disables auto locking.
+	#[serde(default)]
+	pub key_auto_lock_timeout: Option<u64>,
+	/// processing_budget limits the thumbnails generated after a location is scanned.
+	#[serde(default)]
+	pub processing_budget: ProcessingBudget,
+	/// trash_retention controls when items in the library's trash are permanently deleted.
+	#[serde(default)]
+	pub trash_retention: TrashRetention,
+	/// job_retry_policies sets how jobs are retried after failing, by job name. Jobs not in it use the default policy.
+	#[serde(default)]
+	pub job_retry_policies: HashMap<String, RetryPolicy>,
+	/// video_chapters controls which videos get a preview strip to navigate them by scene.
+	#[serde(default)]
+	pub video_chapters: VideoChapters,
+	/// sparse_checkout limits which content this node keeps a copy of, for devices with little storage.
+	#[serde(default)]
+	pub sparse_checkout: SparseCheckout,
+	/// preview_cache caps the space the previews of the library's objects take up on this node.
+	#[serde(default)]
+	pub preview_cache: PreviewCacheLimit,
+	/// chunk_store lets the library's files be backed up in a deduplicated store on this node.
+	#[serde(default)]
+	pub chunk_store: bool,
 }
 
 impl LibraryConfig {
@@ -1,12 +1,23 @@
-use crate::job::DynJob;
+use crate::job::{DynJob, TransferGovernor};
 use sd_crypto::keys::keymanager::KeyManager;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use tokio::sync::watch;
 use tracing::warn;
 use uuid::Uuid;
 
-use crate::{api::CoreEvent, node::NodeConfigManager, prisma::PrismaClient, NodeContext};
+use crate::{
+	api::{CoreEvent, LibraryEvent},
+	extension::ExtensionRegistry,
+	node::{HookRunner, NodeConfigManager},
+	prisma::PrismaClient,
+	sync::SyncManager,
+	NodeContext,
+};
 
-use super::LibraryConfig;
+use super::{
+	History, KeyLock, LibraryConfig, LibraryManager, ProcessLock, QueryCache, Selections,
+	Statistics,
+};
 
 /// LibraryContext holds context for a library which can be passed around the application.
 #[derive(Clone)]
@@ -19,10 +30,27 @@ pub struct LibraryContext {
 	pub db: Arc<PrismaClient>,
 	/// key manager that provides encryption keys to functions that require them
 	pub key_manager: Arc<KeyManager>,
+	/// key_lock tracks key manager activity so it can be automatically locked when left idle
+	pub key_lock: Arc<KeyLock>,
+	/// process_lock tells if this process holds the library or only has it open read-only
+	pub process_lock: Arc<ProcessLock>,
+	/// selections holds the file paths clients have selected for bulk operations
+	pub selections: Arc<Selections>,
+	/// history holds what users did to their files that can be undone
+	pub history: Arc<History>,
+	/// statistics keeps the aggregates over the library's files, counted again as jobs change them
+	pub statistics: Arc<Statistics>,
+	/// query_cache keeps the results of expensive queries until the rows they read change
+	pub query_cache: Arc<QueryCache>,
+	/// sync logs the library's changes and merges those made on its other nodes
+	pub sync: Arc<SyncManager>,
 	/// node_local_id holds the local ID of the node which is running the library.
 	pub node_local_id: i32,
 	/// node_context holds the node context for the node which this library is running on.
 	pub(super) node_context: NodeContext,
+	/// unloaded is set once the library is deleted or the node shuts down, for the library's
+	/// background tasks to stop
+	pub(super) unloaded: Arc<watch::Sender<bool>>,
 }
 
 impl LibraryContext {
@@ -34,6 +62,11 @@ impl LibraryContext {
 		self.node_context.jobs.ingest_queue(self, job).await;
 	}
 
+	/// has_job tells if a job named `name` is queued or running for this library
+	pub(crate) async fn has_job(&self, name: &str) -> bool {
+		self.node_context.jobs.has_job(self, name).await
+	}
+
 	pub(crate) fn emit(&self, event: CoreEvent) {
 		match self.node_context.event_bus_tx.send(event) {
 			Ok(_) => (),
@@ -43,7 +76,49 @@ impl LibraryContext {
 		}
 	}
 
+	/// emit_event tells clients following this library's events about a change to it
+	pub(crate) fn emit_event(&self, event: LibraryEvent) {
+		self.emit(CoreEvent::Library {
+			library_id: self.id,
+			event,
+		});
+	}
+
+	/// unload tells the library's background tasks to stop, as it's being deleted or the node shuts
+	/// down
+	pub(crate) fn unload(&self) {
+		self.unloaded.send_replace(true);
+	}
+
+	/// stopped resolves once the library is unloaded, for background tasks to select on
+	pub(crate) async fn stopped(&self) {
+		let mut unloaded = self.unloaded.subscribe();
+		if !*unloaded.borrow() {
+			unloaded.changed().await.ok();
+		}
+	}
+
 	pub(crate) fn config(&self) -> Arc<NodeConfigManager> {
 		self.node_context.config.clone()
 	}
+
+	pub(crate) fn extensions(&self) -> Arc<ExtensionRegistry> {
+		self.node_context.extensions.clone()
+	}
+
+	pub(crate) fn hooks(&self) -> Arc<HookRunner> {
+		self.node_context.hooks.clone()
+	}
+
+	pub(crate) fn transfers(&self) -> Arc<TransferGovernor> {
+		self.node_context.jobs.transfers()
+	}
+
+	/// library_manager returns the manager of the node's libraries, `None` while they're loading
+	pub(crate) fn library_manager(&self) -> Option<Arc<LibraryManager>> {
+		self.node_context
+			.library_manager
+			.get()
+			.and_then(Weak::upgrade)
+	}
 }
@@ -1,14 +1,29 @@
 use crate::{
 	invalidate_query,
+	job::spawn_scheduler,
+	library::spawn_db_health,
+	location::{
+		pipeline::resume_pipelines, space_monitor::spawn_space_monitor,
+		storage::spawn_storage_sync, sweep_locations, volume_watcher::spawn_volume_watcher,
+	},
 	node::Platform,
-	prisma::{key, node, PrismaClient},
+	object::{
+		fs::trash::TrashRetention,
+		preview::{PreviewCacheLimit, ProcessingBudget, VideoChapters, THUMBNAIL_CACHE_DIR_NAME},
+		validation::integrity::spawn_integrity_sampling,
+	},
+	prisma::{file_path, key, location, node, object, PrismaClient},
+	sync::SyncManager,
 	util::{
 		db::load_and_migrate,
+		os_path::resolve_materialized_path,
 		seeder::{indexer_rules_seeder, SeederError},
 	},
+	volume::InsufficientSpace,
 	NodeContext,
 };
 
+use rspc::Type;
 use sd_crypto::{
 	crypto::stream::Algorithm,
 	keys::{
@@ -17,17 +32,27 @@ use sd_crypto::{
 	},
 	primitives::to_array,
 };
+use serde::Serialize;
 use std::{
 	env, fs, io,
 	path::{Path, PathBuf},
 	str::FromStr,
 	sync::Arc,
+	time::Duration,
 };
 use thiserror::Error;
-use tokio::sync::RwLock;
+use tokio::{
+	sync::{watch, RwLock},
+	task::spawn_blocking,
+};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use super::{LibraryConfig, LibraryConfigWrapped, LibraryContext};
+use super::{
+	portable::{relink_locations, unpack_export},
+	History, KeyLock, LibraryConfig, LibraryConfigWrapped, LibraryContext, ProcessLock, QueryCache,
+	RelinkedLocation, Selections, SparseCheckout, Statistics,
+};
 
 /// LibraryManager is a singleton that manages all libraries for a node.
 pub struct LibraryManager {
@@ -59,6 +84,41 @@ pub enum LibraryManagerError {
 	Seeder(#[from] SeederError),
 	#[error("failed to initialise the key manager")]
 	KeyManager(#[from] sd_crypto::Error),
+	#[error("invalid library export: {0}")]
+	InvalidExport(String),
+	#[error("library '{0}' already exists on this node")]
+	LibraryAlreadyExists(Uuid),
+	#[error("{0}")]
+	InsufficientSpace(#[from] InsufficientSpace),
+	#[error("error compressing or decompressing a library export: {0}")]
+	Codec(#[from] sd_codec::Error),
+	#[error("library '{0}' wasn't handed over by the process holding it")]
+	HandoverTimedOut(Uuid),
+}
+
+/// The result of importing a library export, with where its locations were found on this node
+#[derive(Serialize, Debug, Type)]
+pub struct LibraryImport {
+	pub library: LibraryConfigWrapped,
+	pub locations: Vec<RelinkedLocation>,
+}
+
+/// An object found in one of the node's libraries when looking content up by its cas_id
+#[derive(Serialize, Debug, Type)]
+pub struct CasIdMatch {
+	pub library_id: Uuid,
+	pub library_name: String,
+	pub object_id: i32,
+	pub paths: Vec<CasIdMatchPath>,
+}
+
+#[derive(Serialize, Debug, Type)]
+pub struct CasIdMatchPath {
+	pub location_id: i32,
+	pub file_path_id: i32,
+	pub materialized_path: String,
+	/// the full path on this node, if the location is local
+	pub local_path: Option<PathBuf>,
 }
 
 impl From<LibraryManagerError> for rspc::Error {
@@ -185,14 +245,14 @@ impl LibraryManager {
 			{
 				Some(Some(Ok(id))) => id,
 				_ => {
-					println!("Attempted to load library from path '{}' but it has an invalid filename. Skipping...", config_path.display());
+					warn!("Attempted to load library from path '{}' but it has an invalid filename. Skipping...", config_path.display());
 					continue;
 				}
 			};
 
 			let db_path = config_path.clone().with_extension("db");
 			if !db_path.try_exists().unwrap() {
-				println!(
+				warn!(
 					"Found library '{}' but no matching database file was found. Skipping...",
 					config_path.display()
 				);
@@ -208,6 +268,10 @@ impl LibraryManager {
 			libraries_dir,
 			node_context,
 		});
+		this.node_context
+			.library_manager
+			.set(Arc::downgrade(&this))
+			.ok();
 
 		Ok(this)
 	}
@@ -233,11 +297,85 @@ impl LibraryManager {
 		.await?;
 
 		invalidate_query!(library, "library.list");
+		invalidate_query!(library, "library.listWithStatistics");
 
 		self.libraries.write().await.push(library);
 		Ok(LibraryConfigWrapped { uuid: id, config })
 	}
 
+	/// import restores a library from an export made with [`super::export_library`], keeping its id so
+	/// other nodes still recognise it. The library's locations are relinked to where they are on this node.
+	pub(crate) async fn import(&self, path: &Path) -> Result<LibraryImport, LibraryManagerError> {
+		let thumbnail_dir = self
+			.node_context
+			.config
+			.data_directory()
+			.join(THUMBNAIL_CACHE_DIR_NAME);
+
+		let manifest = {
+			let (path, libraries_dir) = (path.to_path_buf(), self.libraries_dir.clone());
+			spawn_blocking(move || unpack_export(&path, &libraries_dir, &thumbnail_dir))
+				.await
+				.map_err(|e| io::Error::new(io::ErrorKind::Other, e))??
+		};
+		let id = manifest.library_id;
+		let config_path = self.libraries_dir.join(format!("{id}.sdlibrary"));
+		let db_path = self.libraries_dir.join(format!("{id}.db"));
+
+		let mut loaded = None;
+		let result = async {
+			LibraryConfig::save(config_path, &manifest.config).await?;
+
+			let library = loaded.insert(
+				Self::load(
+					id,
+					&db_path,
+					manifest.config.clone(),
+					self.node_context.clone(),
+				)
+				.await?,
+			);
+
+			relink_locations(library, &manifest.locations).await
+		}
+		.await;
+
+		// a half imported library would be loaded again on the next start
+		let locations = match result {
+			Ok(locations) => locations,
+			Err(e) => {
+				if let Some(library) = loaded {
+					library.unload();
+					if let Err(e) = library.process_lock.release() {
+						error!("Failed to release the lock of library '{id}': {e:#?}");
+					}
+				}
+				for suffix in ["sdlibrary", "db", "db-wal", "db-shm"] {
+					let path = self.libraries_dir.join(format!("{id}.{suffix}"));
+					if let Err(e) = tokio::fs::remove_file(&path).await {
+						if e.kind() != io::ErrorKind::NotFound {
+							error!("Failed to remove '{}': {e:#?}", path.display());
+						}
+					}
+				}
+				return Err(e);
+			}
+		};
+		let library = loaded.expect("the library is loaded before its locations are relinked");
+
+		invalidate_query!(library, "library.list");
+		invalidate_query!(library, "library.listWithStatistics");
+
+		self.libraries.write().await.push(library);
+		Ok(LibraryImport {
+			library: LibraryConfigWrapped {
+				uuid: id,
+				config: manifest.config,
+			},
+			locations,
+		})
+	}
+
 	pub(crate) async fn get_all_libraries_config(&self) -> Vec<LibraryConfigWrapped> {
 		self.libraries
 			.read()
@@ -259,6 +397,9 @@ impl LibraryManager {
 		id: Uuid,
 		name: Option<String>,
 		description: Option<String>,
+		processing_budget: Option<ProcessingBudget>,
+		trash_retention: Option<TrashRetention>,
+		video_chapters: Option<VideoChapters>,
 	) -> Result<(), LibraryManagerError> {
 		// check library is valid
 		let mut libraries = self.libraries.write().await;
@@ -274,6 +415,15 @@ impl LibraryManager {
 		if let Some(description) = description {
 			library.config.description = description;
 		}
+		if let Some(processing_budget) = processing_budget {
+			library.config.processing_budget = processing_budget;
+		}
+		if let Some(trash_retention) = trash_retention {
+			library.config.trash_retention = trash_retention;
+		}
+		if let Some(video_chapters) = video_chapters {
+			library.config.video_chapters = video_chapters;
+		}
 
 		LibraryConfig::save(
 			Path::new(&self.libraries_dir).join(format!("{id}.sdlibrary")),
@@ -282,6 +432,112 @@ impl LibraryManager {
 		.await?;
 
 		invalidate_query!(library, "library.list");
+		invalidate_query!(library, "library.listWithStatistics");
+
+		Ok(())
+	}
+
+	/// set_sparse_checkout changes which content of the library this node keeps a copy of
+	pub(crate) async fn set_sparse_checkout(
+		&self,
+		id: Uuid,
+		sparse_checkout: SparseCheckout,
+	) -> Result<(), LibraryManagerError> {
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.sparse_checkout = sparse_checkout;
+
+		LibraryConfig::save(
+			Path::new(&self.libraries_dir).join(format!("{id}.sdlibrary")),
+			&library.config,
+		)
+		.await?;
+
+		invalidate_query!(library, "library.getSparseCheckout");
+		invalidate_query!(library, "files.getMissingPinned");
+
+		Ok(())
+	}
+
+	/// set_preview_cache_limit changes how much space the library's previews may take up
+	pub(crate) async fn set_preview_cache_limit(
+		&self,
+		id: Uuid,
+		limit: PreviewCacheLimit,
+	) -> Result<(), LibraryManagerError> {
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.preview_cache = limit;
+
+		LibraryConfig::save(
+			Path::new(&self.libraries_dir).join(format!("{id}.sdlibrary")),
+			&library.config,
+		)
+		.await?;
+
+		invalidate_query!(library, "cache.stats");
+
+		Ok(())
+	}
+
+	/// set_chunk_store_enabled turns the library's chunk store on or off, the chunks already stored
+	/// are kept either way
+	pub(crate) async fn set_chunk_store_enabled(
+		&self,
+		id: Uuid,
+		enabled: bool,
+	) -> Result<(), LibraryManagerError> {
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.chunk_store = enabled;
+
+		LibraryConfig::save(
+			Path::new(&self.libraries_dir).join(format!("{id}.sdlibrary")),
+			&library.config,
+		)
+		.await?;
+
+		invalidate_query!(library, "chunks.stats");
+
+		Ok(())
+	}
+
+	/// set_key_auto_lock_timeout changes how long the library's keys stay unlocked while unused
+	pub(crate) async fn set_key_auto_lock_timeout(
+		&self,
+		id: Uuid,
+		timeout: Option<u64>,
+	) -> Result<(), LibraryManagerError> {
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.key_auto_lock_timeout = timeout;
+		library
+			.key_lock
+			.set_timeout(timeout.map(Duration::from_secs));
+
+		LibraryConfig::save(
+			Path::new(&self.libraries_dir).join(format!("{id}.sdlibrary")),
+			&library.config,
+		)
+		.await?;
+
+		invalidate_query!(library, "keys.getAutoLockTimeout");
 
 		Ok(())
 	}
@@ -294,16 +550,153 @@ impl LibraryManager {
 			.find(|l| l.id == id)
 			.ok_or(LibraryManagerError::LibraryNotFound)?;
 
+		library.process_lock.release()?;
 		fs::remove_file(Path::new(&self.libraries_dir).join(format!("{}.db", library.id)))?;
 		fs::remove_file(Path::new(&self.libraries_dir).join(format!("{}.sdlibrary", library.id)))?;
 
 		invalidate_query!(library, "library.list");
+		invalidate_query!(library, "library.listWithStatistics");
 
+		self.node_context.jobs.clear_queue(id).await;
+		library.unload();
 		libraries.retain(|l| l.id != id);
 
 		Ok(())
 	}
 
+	/// take_over asks the process holding a library to hand it over, then resumes the jobs it
+	/// paused to do so
+	pub(crate) async fn take_over(&self, id: Uuid) -> Result<(), LibraryManagerError> {
+		let library = self
+			.get_ctx(id)
+			.await
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		if !library.process_lock.is_read_only() {
+			return Ok(());
+		}
+
+		if !library.process_lock.take_over().await? {
+			return Err(LibraryManagerError::HandoverTimedOut(id));
+		}
+		info!("Took library '{id}' over from another process");
+
+		if let Err(e) = Arc::clone(&self.node_context.jobs)
+			.resume_jobs(&library)
+			.await
+		{
+			error!("Failed to resume jobs of library '{id}': {e:#?}");
+		}
+		if let Err(e) = resume_pipelines(&library).await {
+			error!("Failed to resume pipelines of library '{id}': {e:#?}");
+		}
+
+		invalidate_query!(library, "library.getAccess");
+
+		Ok(())
+	}
+
+	/// release_locks lets go of the libraries this process holds, for when the node shuts down
+	pub(crate) async fn release_locks(&self) {
+		for library in self.libraries.read().await.iter() {
+			if let Err(e) = library.process_lock.release() {
+				error!(
+					"Failed to release the lock of library '{}': {e:#?}",
+					library.id
+				);
+			}
+		}
+	}
+
+	/// has_cas_id tells if a library loaded on this node has content with the given cas_id
+	pub(crate) async fn has_cas_id(
+		&self,
+		cas_id: &str,
+	) -> Result<bool, prisma_client_rust::QueryError> {
+		// the identifier only stores the first 16 characters of the cas_id
+		let cas_id = cas_id.chars().take(16).collect::<String>();
+
+		for library in self.libraries.read().await.iter() {
+			if library
+				.db
+				.object()
+				.count(vec![object::cas_id::equals(cas_id.clone())])
+				.exec()
+				.await? > 0
+			{
+				return Ok(true);
+			}
+		}
+
+		Ok(false)
+	}
+
+	/// find_by_cas_id looks for content with the given cas_id in every library loaded on this node
+	pub(crate) async fn find_by_cas_id(
+		&self,
+		cas_id: &str,
+	) -> Result<Vec<CasIdMatch>, LibraryManagerError> {
+		// the identifier only stores the first 16 characters of the cas_id
+		let cas_id = cas_id.chars().take(16).collect::<String>();
+
+		let mut matches = vec![];
+		for library in self.libraries.read().await.iter() {
+			let object = match library
+				.db
+				.object()
+				.find_unique(object::cas_id::equals(cas_id.clone()))
+				.exec()
+				.await?
+			{
+				Some(object) => object,
+				None => continue,
+			};
+
+			let file_paths = library
+				.db
+				.file_path()
+				.find_many(vec![file_path::object_id::equals(Some(object.id))])
+				.exec()
+				.await?;
+
+			let locations = library
+				.db
+				.location()
+				.find_many(vec![location::id::in_vec(
+					file_paths.iter().map(|path| path.location_id).collect(),
+				)])
+				.exec()
+				.await?;
+
+			matches.push(CasIdMatch {
+				library_id: library.id,
+				library_name: library.config.name.clone(),
+				object_id: object.id,
+				paths: file_paths
+					.into_iter()
+					.map(|file_path| CasIdMatchPath {
+						local_path: locations
+							.iter()
+							.find(|location| location.id == file_path.location_id)
+							.and_then(|location| location.local_path.as_ref())
+							.map(|local_path| {
+								resolve_materialized_path(
+									local_path,
+									&file_path.materialized_path,
+									file_path.raw_path.as_deref(),
+								)
+							}),
+						location_id: file_path.location_id,
+						file_path_id: file_path.id,
+						materialized_path: file_path.materialized_path,
+					})
+					.collect(),
+			});
+		}
+
+		Ok(matches)
+	}
+
 	// get_ctx will return the library context for the given library id.
 	pub(crate) async fn get_ctx(&self, library_id: Uuid) -> Option<LibraryContext> {
 		self.libraries
@@ -342,7 +735,17 @@ impl LibraryManager {
 			_ => Platform::Unknown,
 		};
 
-		let uuid_vec = id.as_bytes().to_vec();
+		let uuid_vec = node_config.id.as_bytes().to_vec();
+
+		// the node used to be stored under the library's id, which every node syncing the library
+		// would share
+		db.node()
+			.update_many(
+				vec![node::pub_id::equals(id.as_bytes().to_vec())],
+				vec![node::pub_id::set(uuid_vec.clone())],
+			)
+			.exec()
+			.await?;
 
 		let node_data = db
 			.node()
@@ -362,14 +765,59 @@ impl LibraryManager {
 		indexer_rules_seeder(&db).await?;
 
 		let key_manager = Arc::new(create_keymanager(&db).await?);
+		let key_lock = Arc::new(KeyLock::new(
+			config.key_auto_lock_timeout.map(Duration::from_secs),
+		));
+
+		let process_lock = Arc::new(ProcessLock::acquire(db_path)?);
+		if process_lock.is_read_only() {
+			info!("Library '{id}' is held by another process, opening it read-only");
+		}
+
+		let sync = Arc::new(SyncManager::new(db.clone(), node_config.id, node_data.id));
 
-		Ok(LibraryContext {
+		let library = LibraryContext {
 			id,
 			config,
 			db,
 			key_manager,
+			key_lock,
+			process_lock,
+			selections: Arc::new(Selections::default()),
+			history: Arc::new(History::default()),
+			statistics: Arc::new(Statistics::default()),
+			query_cache: Arc::new(QueryCache::default()),
+			sync,
 			node_local_id: node_data.id,
 			node_context,
-		})
+			unloaded: Arc::new(watch::channel(false).0),
+		};
+
+		KeyLock::spawn_watcher(library.clone());
+		ProcessLock::spawn_watcher(library.clone());
+		QueryCache::spawn_invalidation(library.clone());
+
+		// the process holding the library keeps it up to date
+		if !library.process_lock.is_read_only() {
+			// catch up on what changed in the locations while the node wasn't running, before the
+			// volume watcher starts marking the node as seen again
+			if let Err(e) = sweep_locations(&library, node_data.last_seen.into()).await {
+				error!("Failed to sweep locations of library '{id}': {e:#?}");
+			}
+
+			// log what changed without going through the sync engine, before other nodes ask for it
+			if let Err(e) = library.sync.seed().await {
+				error!("Failed to seed the sync log of library '{id}': {e:#?}");
+			}
+		}
+
+		spawn_volume_watcher(library.clone());
+		spawn_storage_sync(library.clone());
+		spawn_scheduler(library.clone());
+		spawn_db_health(library.clone());
+		spawn_integrity_sampling(library.clone());
+		spawn_space_monitor(library.clone());
+
+		Ok(library)
 	}
 }
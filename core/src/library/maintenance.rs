@@ -0,0 +1,282 @@
+//! Upkeep of a library's database. SQLite's own checks find corruption and rows whose foreign keys
+//! point at rows that are gone, which happens when a write is cut off or rows were deleted while
+//! foreign keys weren't enforced. Rows like that are repaired, then the query planner's statistics
+//! are refreshed and the space left behind by deleted rows is given back.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use prisma_client_rust::{raw::Raw, QueryError};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	prisma::PrismaClient,
+	util::db::raw_int,
+};
+
+pub const MAINTENANCE_JOB_NAME: &str = "maintenance";
+
+/// Rows pointing at rows that are gone, with the statement repairing them. File paths that lost
+/// their location or directory are removed, as indexing the location again brings them back.
+const REPAIRS: &[(&str, &str)] = &[
+	(
+		"file paths of missing locations",
+		"DELETE FROM file_path WHERE location_id NOT IN (SELECT id FROM location)",
+	),
+	(
+		"file paths of missing directories",
+		"DELETE FROM file_path WHERE parent_id IS NOT NULL AND NOT EXISTS \
+		(SELECT 1 FROM file_path AS parent \
+		WHERE parent.location_id = file_path.location_id AND parent.id = file_path.parent_id)",
+	),
+	// the files are identified again
+	(
+		"file paths of missing objects",
+		"UPDATE file_path SET object_id = NULL \
+		WHERE object_id IS NOT NULL AND object_id NOT IN (SELECT id FROM object)",
+	),
+	(
+		"tags of missing objects",
+		"DELETE FROM tag_on_object \
+		WHERE object_id NOT IN (SELECT id FROM object) OR tag_id NOT IN (SELECT id FROM tag)",
+	),
+	(
+		"media data of missing objects",
+		"DELETE FROM media_data WHERE id NOT IN (SELECT id FROM object)",
+	),
+];
+
+#[derive(Deserialize)]
+struct IntegrityCheck {
+	integrity_check: String,
+}
+
+#[derive(Deserialize)]
+struct ForeignKeyViolation {
+	table: String,
+	#[serde(deserialize_with = "raw_int")]
+	count: i64,
+}
+
+#[derive(Deserialize)]
+struct WalCheckpoint {
+	#[serde(deserialize_with = "raw_int")]
+	busy: i64,
+}
+
+#[derive(Deserialize)]
+struct DatabaseSize {
+	#[serde(deserialize_with = "raw_int")]
+	bytes: i64,
+}
+
+/// integrity_errors runs SQLite's integrity check, returning what it found wrong
+async fn integrity_errors(db: &PrismaClient) -> Result<Vec<String>, QueryError> {
+	let checks: Vec<IntegrityCheck> = db
+		._query_raw(Raw::new(
+			"SELECT integrity_check FROM pragma_integrity_check()",
+			vec![],
+		))
+		.exec()
+		.await?;
+
+	Ok(checks
+		.into_iter()
+		.map(|check| check.integrity_check)
+		.filter(|check| check != "ok")
+		.collect())
+}
+
+/// checkpoint_wal writes the pages in the database's write-ahead log back to it, so nothing is only
+/// in the log when the node exits. Returns false if another connection kept it from finishing.
+pub async fn checkpoint_wal(db: &PrismaClient) -> Result<bool, QueryError> {
+	let checkpoint: Vec<WalCheckpoint> = db
+		._query_raw(Raw::new("PRAGMA wal_checkpoint(TRUNCATE)", vec![]))
+		.exec()
+		.await?;
+
+	Ok(checkpoint
+		.first()
+		.map_or(true, |checkpoint| checkpoint.busy == 0))
+}
+
+async fn database_bytes(db: &PrismaClient) -> Result<i64, QueryError> {
+	let size: Vec<DatabaseSize> = db
+		._query_raw(Raw::new(
+			"SELECT (SELECT page_count FROM pragma_page_count()) * \
+			(SELECT page_size FROM pragma_page_size()) AS bytes",
+			vec![],
+		))
+		.exec()
+		.await?;
+
+	Ok(size.first().map(|size| size.bytes).unwrap_or_default())
+}
+
+/// MaintenanceJob checks the integrity of the library's database, repairs the rows pointing at rows
+/// that are gone, then analyzes and vacuums it. What it found and repaired is in its metadata.
+pub struct MaintenanceJob;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MaintenanceJobInit {}
+
+/// MaintenanceReport is what a maintenance run found and did
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MaintenanceReport {
+	/// what SQLite's integrity check found wrong, empty when the database is sound
+	pub integrity_errors: Vec<String>,
+	/// whether the indexes were rebuilt to get rid of the errors
+	pub reindexed: bool,
+	/// whether errors were still there after rebuilding the indexes
+	pub corrupted: bool,
+	/// rows whose foreign keys point at rows that are gone, by table
+	pub foreign_key_violations: BTreeMap<String, i64>,
+	/// how many rows were repaired, by what was wrong with them
+	pub repaired: BTreeMap<String, i64>,
+	pub bytes_before: i64,
+	pub bytes_after: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum MaintenanceJobStep {
+	CheckIntegrity,
+	CheckForeignKeys,
+	Repair(usize),
+	Analyze,
+	Vacuum,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for MaintenanceJob {
+	type Init = MaintenanceJobInit;
+	type Data = MaintenanceReport;
+	type Step = MaintenanceJobStep;
+
+	fn name(&self) -> &'static str {
+		MAINTENANCE_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		state.steps = [
+			MaintenanceJobStep::CheckIntegrity,
+			MaintenanceJobStep::CheckForeignKeys,
+		]
+		.into_iter()
+		.chain((0..REPAIRS.len()).map(MaintenanceJobStep::Repair))
+		.chain([MaintenanceJobStep::Analyze, MaintenanceJobStep::Vacuum])
+		.collect::<VecDeque<_>>();
+
+		state.data = Some(MaintenanceReport {
+			bytes_before: database_bytes(&library.db).await?,
+			..Default::default()
+		});
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let db = &library.db;
+		let report = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		match state.steps[0] {
+			MaintenanceJobStep::CheckIntegrity => {
+				report.integrity_errors = integrity_errors(db).await?;
+				// most corruption is in indexes, which can be rebuilt from their tables
+				if !report.integrity_errors.is_empty() {
+					warn!(
+						"Database of library '{}' failed its integrity check, reindexing it",
+						library.id
+					);
+					db._execute_raw(Raw::new("REINDEX", vec![])).exec().await?;
+					report.reindexed = true;
+					report.corrupted = !integrity_errors(db).await?.is_empty();
+				}
+			}
+			MaintenanceJobStep::CheckForeignKeys => {
+				let violations: Vec<ForeignKeyViolation> = db
+					._query_raw(Raw::new(
+						"SELECT \"table\", COUNT(*) AS count FROM pragma_foreign_key_check() \
+						GROUP BY \"table\"",
+						vec![],
+					))
+					.exec()
+					.await?;
+				report.foreign_key_violations = violations
+					.into_iter()
+					.map(|violation| (violation.table, violation.count))
+					.collect();
+			}
+			MaintenanceJobStep::Repair(repair) => {
+				let (name, sql) = REPAIRS[repair];
+				let count = db._execute_raw(Raw::new(sql, vec![])).exec().await?;
+				if count > 0 {
+					report.repaired.insert(name.to_string(), count);
+				}
+			}
+			MaintenanceJobStep::Analyze => {
+				db._execute_raw(Raw::new("ANALYZE", vec![])).exec().await?;
+			}
+			// a database that's still corrupted is left as it is, as rewriting it could lose more
+			MaintenanceJobStep::Vacuum if report.corrupted => {}
+			MaintenanceJobStep::Vacuum => {
+				db._execute_raw(Raw::new("VACUUM", vec![])).exec().await?;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let report = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+		report.bytes_after = database_bytes(&library.db).await?;
+
+		info!(
+			"Maintained database of library '{}': repaired {} rows, {} bytes given back",
+			library.id,
+			report.repaired.values().sum::<i64>(),
+			report.bytes_before - report.bytes_after
+		);
+		if report.corrupted {
+			warn!(
+				"Database of library '{}' is still corrupted: {:?}",
+				library.id, report.integrity_errors
+			);
+		}
+
+		if !report.repaired.is_empty() {
+			invalidate_query!(library, "locations.getExplorerData");
+			invalidate_query!(library, "library.getStatistics");
+		}
+
+		Ok(Some(serde_json::to_value(&*report)?))
+	}
+}
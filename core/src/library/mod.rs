@@ -1,7 +1,29 @@
+mod audit;
+mod db_health;
+mod history;
+mod key_lock;
 mod library_config;
 mod library_ctx;
 mod library_manager;
+mod maintenance;
+mod portable;
+mod process_lock;
+mod query_cache;
+mod selections;
+mod sparse;
+mod statistics;
 
+pub use audit::*;
+pub use db_health::*;
+pub use history::*;
+pub use key_lock::*;
 pub use library_config::*;
 pub use library_ctx::*;
 pub use library_manager::*;
+pub use maintenance::*;
+pub use portable::*;
+pub use process_lock::*;
+pub use query_cache::*;
+pub use selections::*;
+pub use sparse::*;
+pub use statistics::*;
@@ -0,0 +1,345 @@
+use std::{
+	fs::{self, File},
+	io::{self, BufRead, BufReader, BufWriter, Read},
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use prisma_client_rust::{prisma_models::PrismaValue, raw::Raw};
+use rspc::Type;
+use sd_codec::{Artifact, Decoder};
+use serde::{Deserialize, Serialize};
+use tokio::task::{block_in_place, spawn_blocking};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+	object::preview::THUMBNAIL_CACHE_DIR_NAME,
+	prisma::location,
+	volume::{ensure_space, find_volume, get_volumes, VolumeFingerprint},
+};
+
+use super::{LibraryConfig, LibraryContext, LibraryManagerError};
+
+/// Bumped whenever the layout of an export changes in a way older versions can't read
+pub const LIBRARY_EXPORT_VERSION: u32 = 2;
+
+const MANIFEST_ENTRY: &str = "manifest.json";
+const DATABASE_ENTRY: &str = "library.db";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// The first entry of an export, describing the library and where its locations lived
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportManifest {
+	pub version: u32,
+	pub library_id: Uuid,
+	pub config: LibraryConfig,
+	pub exported_at: DateTime<Utc>,
+	pub locations: Vec<ExportedLocation>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportedLocation {
+	pub pub_id: Vec<u8>,
+	pub local_path: Option<String>,
+	/// the volume the location was on, so it can be found again if it's mounted somewhere else
+	pub volume: Option<VolumeFingerprint>,
+	/// path of the location relative to the volume's mount point
+	pub relative_path: Option<PathBuf>,
+}
+
+/// Where an imported location ended up on this node
+#[derive(Serialize, Debug, Type)]
+pub struct RelinkedLocation {
+	pub location_id: i32,
+	pub name: Option<String>,
+	pub local_path: Option<String>,
+	pub is_online: bool,
+}
+
+/// export_library writes the library's database, settings and thumbnails to a single compressed tar at `path`
+pub async fn export_library(
+	library: &LibraryContext,
+	path: &Path,
+) -> Result<(), LibraryManagerError> {
+	let data_dir = library.config().data_directory();
+	let db_copy_path = data_dir.join(format!("{}.export.db", library.id));
+	if db_copy_path.exists() {
+		fs::remove_file(&db_copy_path)?;
+	}
+
+	// a consistent copy of the database, taken without stopping the library
+	library
+		.db
+		._execute_raw(Raw::new(
+			"VACUUM INTO {}",
+			vec![PrismaValue::String(
+				db_copy_path.to_string_lossy().to_string(),
+			)],
+		))
+		.exec()
+		.await?;
+
+	let volumes = get_volumes().unwrap_or_default();
+	let locations = library
+		.db
+		.location()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|location| {
+			let volume = location
+				.local_path
+				.as_ref()
+				.and_then(|local_path| find_volume(&volumes, Path::new(local_path)));
+
+			ExportedLocation {
+				pub_id: location.pub_id,
+				volume: volume
+					.as_ref()
+					.map(|(volume, _)| VolumeFingerprint::from(*volume)),
+				relative_path: volume.map(|(_, relative_path)| relative_path),
+				local_path: location.local_path,
+			}
+		})
+		.collect();
+
+	let manifest = ExportManifest {
+		version: LIBRARY_EXPORT_VERSION,
+		library_id: library.id,
+		config: library.config.clone(),
+		exported_at: Utc::now(),
+		locations,
+	};
+
+	let thumbnail_dir = data_dir.join(THUMBNAIL_CACHE_DIR_NAME);
+	let thumbnails = library
+		.db
+		.object()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|object| thumbnail_dir.join(&object.cas_id).with_extension("webp"))
+		.filter(|path| path.exists())
+		.collect::<Vec<_>>();
+
+	// the export is compressed, but it's never bigger than what goes into it
+	let required = thumbnails
+		.iter()
+		.chain([&db_copy_path])
+		.filter_map(|path| fs::metadata(path).ok())
+		.map(|metadata| metadata.len())
+		.sum();
+	if let Err(e) = ensure_space(path, required) {
+		fs::remove_file(&db_copy_path)?;
+		return Err(e.into());
+	}
+
+	let result = block_in_place(|| -> Result<(), LibraryManagerError> {
+		let mut builder = tar::Builder::new(
+			Artifact::LibraryExport
+				.codec()
+				.framed_encoder(BufWriter::new(File::create(path)?))?,
+		);
+
+		let manifest = serde_json::to_vec(&manifest)?;
+		let mut header = tar::Header::new_gnu();
+		header.set_size(manifest.len() as u64);
+		header.set_mode(0o644);
+		header.set_mtime(Utc::now().timestamp() as u64);
+		header.set_cksum();
+		builder.append_data(&mut header, MANIFEST_ENTRY, manifest.as_slice())?;
+
+		builder.append_path_with_name(&db_copy_path, DATABASE_ENTRY)?;
+
+		for thumbnail in &thumbnails {
+			if let Some(file_name) = thumbnail.file_name() {
+				builder.append_path_with_name(
+					thumbnail,
+					Path::new(THUMBNAIL_CACHE_DIR_NAME).join(file_name),
+				)?;
+			}
+		}
+
+		builder.into_inner()?.finish()?;
+		Ok(())
+	});
+
+	fs::remove_file(&db_copy_path)?;
+	result?;
+
+	info!(
+		"Exported library '{}' with {} thumbnails to {}",
+		library.id,
+		thumbnails.len(),
+		path.display()
+	);
+
+	Ok(())
+}
+
+/// unpack_export reads an export created by [`export_library`], writing the database into `libraries_dir`
+/// and the thumbnails into `thumbnail_dir`. The library's config is returned in the manifest, it isn't saved.
+pub(super) fn unpack_export(
+	path: &Path,
+	libraries_dir: &Path,
+	thumbnail_dir: &Path,
+) -> Result<ExportManifest, LibraryManagerError> {
+	// exports were gzipped before they were compressed with a codec
+	let mut reader = BufReader::new(File::open(path)?);
+	let reader: Box<dyn Read> = match reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+		true => Box::new(GzDecoder::new(reader)),
+		false => Box::new(Decoder::framed(reader)?),
+	};
+	let mut archive = tar::Archive::new(reader);
+	let mut entries = archive.entries()?;
+
+	let manifest: ExportManifest = match entries.next() {
+		Some(entry) => {
+			let mut entry = entry?;
+			if entry.path()?.as_os_str() != MANIFEST_ENTRY {
+				return Err(LibraryManagerError::InvalidExport(
+					"the export doesn't start with a manifest".to_string(),
+				));
+			}
+
+			let mut manifest = vec![];
+			entry.read_to_end(&mut manifest)?;
+			serde_json::from_slice(&manifest)?
+		}
+		None => {
+			return Err(LibraryManagerError::InvalidExport(
+				"the export is empty".to_string(),
+			))
+		}
+	};
+
+	if manifest.version > LIBRARY_EXPORT_VERSION {
+		return Err(LibraryManagerError::InvalidExport(format!(
+			"the export was made by a newer version of Spacedrive (version {})",
+			manifest.version
+		)));
+	}
+
+	let config_path = libraries_dir.join(format!("{}.sdlibrary", manifest.library_id));
+	let db_path = libraries_dir.join(format!("{}.db", manifest.library_id));
+	if config_path.exists() || db_path.exists() {
+		return Err(LibraryManagerError::LibraryAlreadyExists(
+			manifest.library_id,
+		));
+	}
+
+	// a database left behind by a failed unpack would be loaded as a library on the next start
+	if let Err(e) = unpack_entries(entries, &db_path, thumbnail_dir) {
+		if let Err(e) = fs::remove_file(&db_path) {
+			if e.kind() != io::ErrorKind::NotFound {
+				warn!("Failed to remove partially imported database: {e:#?}");
+			}
+		}
+		return Err(e);
+	}
+
+	Ok(manifest)
+}
+
+/// unpack_entries writes the database and thumbnails that follow an export's manifest
+fn unpack_entries<R: Read>(
+	entries: tar::Entries<'_, R>,
+	db_path: &Path,
+	thumbnail_dir: &Path,
+) -> Result<(), LibraryManagerError> {
+	fs::create_dir_all(thumbnail_dir)?;
+
+	let mut has_database = false;
+	for entry in entries {
+		let mut entry = entry?;
+		let entry_path = entry.path()?.to_path_buf();
+
+		if entry_path == Path::new(DATABASE_ENTRY) {
+			entry.unpack(db_path)?;
+			has_database = true;
+		} else if entry_path.parent() == Some(Path::new(THUMBNAIL_CACHE_DIR_NAME)) {
+			// thumbnails are shared between libraries, so one might already be here
+			if let Some(file_name) = entry_path.file_name() {
+				let thumbnail_path = thumbnail_dir.join(file_name);
+				if !thumbnail_path.exists() {
+					entry.unpack(thumbnail_path)?;
+				}
+			}
+		} else {
+			warn!(
+				"Skipping unknown entry '{}' in library export",
+				entry_path.display()
+			);
+		}
+	}
+
+	if !has_database {
+		return Err(LibraryManagerError::InvalidExport(
+			"the export doesn't contain a database".to_string(),
+		));
+	}
+
+	Ok(())
+}
+
+/// relink_locations points the imported library's locations at where they are on this node.
+/// Locations on a volume that's mounted somewhere else are moved to the new mount point, and
+/// locations that can't be found are marked as offline.
+pub(super) async fn relink_locations(
+	library: &LibraryContext,
+	exported_locations: &[ExportedLocation],
+) -> Result<Vec<RelinkedLocation>, LibraryManagerError> {
+	let volumes = spawn_blocking(get_volumes)
+		.await
+		.ok()
+		.and_then(Result::ok)
+		.unwrap_or_default();
+
+	let mut relinked = vec![];
+	for exported in exported_locations {
+		let moved_path = exported
+			.volume
+			.as_ref()
+			.zip(exported.relative_path.as_ref())
+			.and_then(|(fingerprint, relative_path)| {
+				volumes
+					.iter()
+					.find(|volume| VolumeFingerprint::from(*volume).matches(fingerprint))
+					.map(|volume| Path::new(&volume.mount_point).join(relative_path))
+			});
+
+		let local_path = moved_path
+			.map(|path| path.to_string_lossy().to_string())
+			.or_else(|| exported.local_path.clone());
+		let is_online = match &local_path {
+			Some(local_path) => tokio::fs::metadata(local_path).await.is_ok(),
+			None => false,
+		};
+
+		let location = library
+			.db
+			.location()
+			.update(
+				location::pub_id::equals(exported.pub_id.clone()),
+				vec![
+					location::local_path::set(local_path.clone()),
+					location::is_online::set(is_online),
+				],
+			)
+			.exec()
+			.await?;
+
+		relinked.push(RelinkedLocation {
+			location_id: location.id,
+			name: location.name,
+			local_path,
+			is_online,
+		});
+	}
+
+	Ok(relinked)
+}
@@ -0,0 +1,338 @@
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	path::{Path, PathBuf},
+	process,
+	sync::Mutex,
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
+use tokio::{
+	sync::broadcast,
+	time::{interval, sleep, Instant},
+};
+use tracing::{error, info};
+
+use crate::invalidate_query;
+
+use super::LibraryContext;
+
+const LOCK_EXTENSION: &str = "lock";
+const HANDOVER_EXTENSION: &str = "handover";
+
+/// How often the process holding a library checks if another one asked for it
+const HANDOVER_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a process asking for a library waits for the one holding it to let go
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the process holding a library waits for its jobs to pause before it keeps the library,
+/// well within [`HANDOVER_TIMEOUT`]
+const HANDOVER_JOBS_DEADLINE: Duration = Duration::from_secs(30);
+
+/// How this process has a library open
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibraryAccess {
+	/// this process holds the library, it runs the library's jobs and may change it
+	Exclusive,
+	/// another process holds the library, this one only reads it
+	ReadOnly,
+}
+
+/// A process holding a library, or asking for it, as written to the lock and handover files.
+/// Who holds the lock is up to the OS, the lock file's contents are only there for people.
+#[derive(Serialize, Deserialize, Debug)]
+struct LockHolder {
+	pid: u32,
+	hostname: String,
+	acquired_at: DateTime<Utc>,
+}
+
+fn current_hostname() -> String {
+	hostname::get()
+		.map(|hostname| hostname.to_string_lossy().into_owned())
+		.unwrap_or_default()
+}
+
+impl LockHolder {
+	fn current() -> Self {
+		Self {
+			pid: process::id(),
+			hostname: current_hostname(),
+			acquired_at: Utc::now(),
+		}
+	}
+
+	fn read(path: &Path) -> io::Result<Self> {
+		Ok(serde_json::from_slice(&fs::read(path)?)?)
+	}
+
+	/// is_alive tells if the process is still running. A process that started after the file was
+	/// written only got the same pid. Processes of other hosts, for a library on a shared drive,
+	/// can't be checked so they're taken as running.
+	fn is_alive(&self) -> bool {
+		if self.hostname != current_hostname() {
+			return true;
+		}
+
+		let pid = Pid::from_u32(self.pid);
+		let mut system = System::new();
+		if !system.refresh_process(pid) {
+			return false;
+		}
+
+		system.process(pid).map_or(false, |process| {
+			process.start_time() as i64 <= self.acquired_at.timestamp()
+		})
+	}
+}
+
+/// ProcessLock keeps two processes from running the same library at once, as they would both run
+/// its jobs. The process that locks the lock file next to the library's database holds the
+/// library, the others open it read-only and can ask for it by leaving a handover file next to it.
+/// The OS lets go of the lock when its process exits, so a crashed one doesn't leave it behind.
+pub struct ProcessLock {
+	lock_path: PathBuf,
+	handover_path: PathBuf,
+	access: Mutex<LibraryAccess>,
+	/// the lock file while this process holds the library, which stays locked while it's open
+	lock_file: Mutex<Option<File>>,
+	/// tells the library's running jobs to pause, for the library to be handed over
+	release_tx: broadcast::Sender<()>,
+}
+
+impl ProcessLock {
+	/// acquire takes the lock of the library whose database is at `db_path`, or opens the library
+	/// read-only when a running process already holds it
+	pub(super) fn acquire(db_path: &Path) -> io::Result<Self> {
+		let this = Self {
+			lock_path: db_path.with_extension(LOCK_EXTENSION),
+			handover_path: db_path.with_extension(HANDOVER_EXTENSION),
+			access: Mutex::new(LibraryAccess::ReadOnly),
+			lock_file: Mutex::new(None),
+			release_tx: broadcast::channel(1).0,
+		};
+		this.try_lock()?;
+
+		Ok(this)
+	}
+
+	/// access returns how this process has the library open
+	pub fn access(&self) -> LibraryAccess {
+		*self.access.lock().unwrap()
+	}
+
+	pub fn is_read_only(&self) -> bool {
+		self.access() == LibraryAccess::ReadOnly
+	}
+
+	/// release_rx is signalled when the library's jobs must pause for it to be handed over
+	pub fn release_rx(&self) -> broadcast::Receiver<()> {
+		self.release_tx.subscribe()
+	}
+
+	/// try_lock locks the lock file unless another process holds it
+	fn try_lock(&self) -> io::Result<bool> {
+		let mut lock_file = self.lock_file.lock().unwrap();
+		if lock_file.is_some() {
+			return Ok(true);
+		}
+
+		let mut file = match open_locked(&self.lock_path) {
+			Ok(file) => file,
+			Err(e) if is_held_elsewhere(&e) => return Ok(false),
+			Err(e) => return Err(e),
+		};
+		file.set_len(0)?;
+		file.write_all(&serde_json::to_vec(&LockHolder::current())?)?;
+
+		*lock_file = Some(file);
+		*self.access.lock().unwrap() = LibraryAccess::Exclusive;
+		Ok(true)
+	}
+
+	/// release gives up the lock if this process holds it, for another process to take the library
+	pub(crate) fn release(&self) -> io::Result<()> {
+		*self.access.lock().unwrap() = LibraryAccess::ReadOnly;
+		self.unlock();
+
+		Ok(())
+	}
+
+	/// unlock closes the lock file, which lets go of its lock. The file stays, as removing it could
+	/// remove the one another process just locked.
+	fn unlock(&self) {
+		self.lock_file.lock().unwrap().take();
+	}
+
+	/// handover_requested tells if another running process asked for the library
+	fn handover_requested(&self) -> bool {
+		LockHolder::read(&self.handover_path).map_or(false, |requester| {
+			requester.pid != process::id() && requester.is_alive()
+		})
+	}
+
+	/// take_over asks the process holding the library to hand it over and takes the lock once it's
+	/// released, returning false if that process didn't let go of it in time
+	pub(super) async fn take_over(&self) -> io::Result<bool> {
+		if self.try_lock()? {
+			return Ok(true);
+		}
+
+		fs::write(
+			&self.handover_path,
+			serde_json::to_vec(&LockHolder::current())?,
+		)?;
+
+		let deadline = Instant::now() + HANDOVER_TIMEOUT;
+		let taken = loop {
+			if self.try_lock()? {
+				break true;
+			}
+			if Instant::now() >= deadline {
+				break false;
+			}
+			sleep(Duration::from_millis(250)).await;
+		};
+
+		remove_if_exists(&self.handover_path)?;
+		Ok(taken)
+	}
+
+	/// hand_over stops running the library's jobs, pausing those running so the process taking the
+	/// library resumes them, then releases the lock. Jobs only queued are dropped, as they aren't
+	/// saved until they start.
+	async fn hand_over(library: &LibraryContext) -> io::Result<()> {
+		let lock = &library.process_lock;
+		let jobs = &library.node_context.jobs;
+
+		*lock.access.lock().unwrap() = LibraryAccess::ReadOnly;
+		jobs.clear_queue(library.id).await;
+		// there's no one to tell when no job is running
+		lock.release_tx.send(()).ok();
+
+		let deadline = Instant::now() + HANDOVER_JOBS_DEADLINE;
+		while !jobs.get_running(library).await.is_empty() {
+			// both processes would run the jobs that didn't pause, so this one keeps the library
+			if Instant::now() >= deadline {
+				*lock.access.lock().unwrap() = LibraryAccess::Exclusive;
+				return Err(io::Error::new(
+					io::ErrorKind::TimedOut,
+					"the library's jobs didn't pause in time",
+				));
+			}
+			sleep(Duration::from_millis(50)).await;
+		}
+
+		lock.unlock();
+
+		invalidate_query!(library, "library.getAccess");
+		Ok(())
+	}
+
+	/// spawn_watcher starts a task that hands the library over when another process asks for it.
+	/// The task stops once the library is unloaded.
+	pub(super) fn spawn_watcher(library: LibraryContext) {
+		tokio::spawn(async move {
+			let mut interval = interval(HANDOVER_CHECK_INTERVAL);
+
+			loop {
+				tokio::select! {
+					_ = interval.tick() => {}
+					_ = library.stopped() => break,
+				}
+
+				if library.process_lock.is_read_only() || !library.process_lock.handover_requested()
+				{
+					continue;
+				}
+
+				info!("Handing library '{}' over to another process", library.id);
+				if let Err(e) = Self::hand_over(&library).await {
+					error!("Failed to hand library '{}' over: {e:#?}", library.id);
+				}
+			}
+		});
+	}
+}
+
+/// open_locked opens the lock file and locks it, failing if another process has it locked
+#[cfg(unix)]
+fn open_locked(path: &Path) -> io::Result<File> {
+	use std::os::unix::io::AsRawFd;
+
+	use nix::fcntl::{flock, FlockArg};
+
+	let file = OpenOptions::new().write(true).create(true).open(path)?;
+	flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock)?;
+	Ok(file)
+}
+
+/// open_locked opens the lock file without sharing it, failing if another process has it open
+#[cfg(windows)]
+fn open_locked(path: &Path) -> io::Result<File> {
+	use std::os::windows::fs::OpenOptionsExt;
+
+	OpenOptions::new()
+		.write(true)
+		.create(true)
+		.share_mode(0)
+		.open(path)
+}
+
+/// is_held_elsewhere tells if opening the lock file failed because another process holds it
+fn is_held_elsewhere(e: &io::Error) -> bool {
+	// ERROR_SHARING_VIOLATION on Windows
+	e.kind() == io::ErrorKind::WouldBlock || (cfg!(windows) && e.raw_os_error() == Some(32))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+	match fs::remove_file(path) {
+		Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+		_ => Ok(()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+
+	use super::*;
+
+	#[test]
+	fn locks_libraries() {
+		let dir = tempfile::tempdir().unwrap();
+		let db_path = dir.path().join("library.db");
+
+		let first = ProcessLock::acquire(&db_path).unwrap();
+		assert_eq!(first.access(), LibraryAccess::Exclusive);
+
+		let second = ProcessLock::acquire(&db_path).unwrap();
+		assert_eq!(second.access(), LibraryAccess::ReadOnly);
+		assert!(!second.try_lock().unwrap());
+
+		first.release().unwrap();
+		assert_eq!(first.access(), LibraryAccess::ReadOnly);
+		assert!(second.try_lock().unwrap());
+		assert_eq!(second.access(), LibraryAccess::Exclusive);
+		assert!(!first.try_lock().unwrap());
+
+		// the lock goes away with whoever held it, as with a process that crashed
+		drop(second);
+		let third = ProcessLock::acquire(&db_path).unwrap();
+		assert_eq!(third.access(), LibraryAccess::Exclusive);
+	}
+
+	#[test]
+	fn tells_processes_apart() {
+		// an earlier process that had the same pid
+		let earlier = LockHolder {
+			acquired_at: Utc.timestamp(0, 0),
+			..LockHolder::current()
+		};
+		assert!(!earlier.is_alive());
+		assert!(LockHolder::current().is_alive());
+	}
+}
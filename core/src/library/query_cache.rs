@@ -0,0 +1,253 @@
+use std::{
+	any::Any,
+	collections::HashMap,
+	future::Future,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::api::{CoreEvent, LibraryEvent};
+
+use super::LibraryContext;
+
+/// The cached queries, by the key of their route
+pub const LISTING_QUERY: &str = "locations.getExplorerData";
+pub const DIRECTORY_QUERY: &str = "files.list";
+pub const STATISTICS_QUERY: &str = "library.getStatistics";
+pub const DUPLICATES_QUERY: &str = "files.getDuplicates";
+
+/// How many results are kept, the oldest going first
+const MAX_ENTRIES: usize = 256;
+/// How long a result is kept at most, for changes no event tells about, like files changed on disk
+/// while their location isn't watched
+const MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+	query: &'static str,
+	args: String,
+}
+
+struct CacheEntry {
+	value: Arc<dyn Any + Send + Sync>,
+	/// the location the query read from, none when it read from the whole library
+	location_id: Option<i32>,
+	cached_at: Instant,
+}
+
+/// QueryCache keeps the results of expensive queries, like directory listings, by their arguments
+/// so navigating back and forth doesn't run them again. The results are dropped as the library's
+/// events tell that the rows they were read from changed.
+#[derive(Default)]
+pub struct QueryCache {
+	entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+}
+
+impl QueryCache {
+	/// get_or_insert returns the cached result of `query` with `args`, or runs it and caches what
+	/// it returns. Queries of a single location give its id, so changes to other locations keep them.
+	pub async fn get_or_insert<T, E>(
+		&self,
+		query: &'static str,
+		args: &impl Serialize,
+		location_id: Option<i32>,
+		run: impl Future<Output = Result<T, E>>,
+	) -> Result<T, E>
+	where
+		T: Clone + Send + Sync + 'static,
+	{
+		let key = CacheKey {
+			query,
+			args: serde_json::to_string(args).unwrap_or_default(),
+		};
+
+		let cached = self
+			.entries
+			.lock()
+			.unwrap()
+			.get(&key)
+			.filter(|entry| entry.cached_at.elapsed() < MAX_AGE)
+			.and_then(|entry| entry.value.downcast_ref::<T>().cloned());
+		if let Some(value) = cached {
+			return Ok(value);
+		}
+
+		let value = run.await?;
+
+		let mut entries = self.entries.lock().unwrap();
+		entries.retain(|_, entry| entry.cached_at.elapsed() < MAX_AGE);
+		if entries.len() >= MAX_ENTRIES {
+			let oldest = entries
+				.iter()
+				.min_by_key(|(_, entry)| entry.cached_at)
+				.map(|(key, _)| key.clone());
+			if let Some(oldest) = oldest {
+				entries.remove(&oldest);
+			}
+		}
+		entries.insert(
+			key,
+			CacheEntry {
+				value: Arc::new(value.clone()),
+				location_id,
+				cached_at: Instant::now(),
+			},
+		);
+
+		Ok(value)
+	}
+
+	/// invalidate_location drops the results read from a location, and those read from the whole
+	/// library
+	pub fn invalidate_location(&self, location_id: i32) {
+		self.entries.lock().unwrap().retain(|_, entry| {
+			entry.location_id.map_or(false, |cached_location_id| {
+				cached_location_id != location_id
+			})
+		});
+	}
+
+	/// invalidate_queries drops the results of the given queries, whatever their arguments
+	pub fn invalidate_queries(&self, queries: &[&str]) {
+		self.entries
+			.lock()
+			.unwrap()
+			.retain(|key, _| !queries.contains(&key.query));
+	}
+
+	pub fn clear(&self) {
+		self.entries.lock().unwrap().clear();
+	}
+
+	/// spawn_invalidation starts a task dropping the cached results of a library as its events tell
+	/// their rows changed. Invalidated queries don't tell which library they're about, so they're
+	/// dropped for every library. The task stops once the library is unloaded.
+	pub(super) fn spawn_invalidation(library: LibraryContext) {
+		let mut events = library.node_context.event_bus_tx.subscribe();
+
+		tokio::spawn(async move {
+			loop {
+				let event = tokio::select! {
+					event = events.recv() => event,
+					_ = library.stopped() => break,
+				};
+				let event = match event {
+					Ok(event) => event,
+					// what was missed can't be told, so nothing cached can be trusted
+					Err(RecvError::Lagged(_)) => {
+						library.query_cache.clear();
+						continue;
+					}
+					Err(RecvError::Closed) => break,
+				};
+
+				let cache = &library.query_cache;
+				match event {
+					CoreEvent::InvalidateOperation(operation)
+					| CoreEvent::InvalidateOperationDebounced(operation) => match operation.key() {
+						// the files of a location changed, which any of the queries may have read
+						LISTING_QUERY => {
+							cache.invalidate_queries(&[
+								LISTING_QUERY,
+								DIRECTORY_QUERY,
+								STATISTICS_QUERY,
+								DUPLICATES_QUERY,
+							]);
+						}
+						STATISTICS_QUERY | "library.listWithStatistics" => {
+							cache.invalidate_queries(&[STATISTICS_QUERY]);
+						}
+						_ => {}
+					},
+					CoreEvent::Library { library_id, event } if library_id == library.id => {
+						match event {
+							LibraryEvent::FilesIndexed { location_id, .. }
+							| LibraryEvent::LocationAdded { location_id }
+							| LibraryEvent::LocationUpdated { location_id }
+							| LibraryEvent::LocationRemoved { location_id } => {
+								cache.invalidate_location(location_id);
+							}
+							// jobs change rows of any kind, like the objects files are linked to
+							LibraryEvent::JobCompleted { .. } => cache.clear(),
+							_ => {}
+						}
+					}
+					_ => {}
+				}
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn caches_until_invalidated() {
+		let cache = QueryCache::default();
+		let run = |value: i32| async move { Ok::<_, ()>(value) };
+
+		assert_eq!(
+			cache
+				.get_or_insert(LISTING_QUERY, &1, Some(1), run(1))
+				.await,
+			Ok(1)
+		);
+		assert_eq!(
+			cache
+				.get_or_insert(LISTING_QUERY, &1, Some(1), run(2))
+				.await,
+			Ok(1)
+		);
+		assert_eq!(
+			cache
+				.get_or_insert(LISTING_QUERY, &2, Some(2), run(3))
+				.await,
+			Ok(3)
+		);
+		assert_eq!(
+			cache
+				.get_or_insert(STATISTICS_QUERY, &(), None, run(4))
+				.await,
+			Ok(4)
+		);
+
+		cache.invalidate_location(2);
+		assert_eq!(
+			cache
+				.get_or_insert(LISTING_QUERY, &1, Some(1), run(5))
+				.await,
+			Ok(1)
+		);
+		assert_eq!(
+			cache
+				.get_or_insert(LISTING_QUERY, &2, Some(2), run(6))
+				.await,
+			Ok(6)
+		);
+		assert_eq!(
+			cache
+				.get_or_insert(STATISTICS_QUERY, &(), None, run(7))
+				.await,
+			Ok(7)
+		);
+
+		cache.invalidate_queries(&[LISTING_QUERY]);
+		assert_eq!(
+			cache
+				.get_or_insert(LISTING_QUERY, &1, Some(1), run(8))
+				.await,
+			Ok(8)
+		);
+		assert_eq!(
+			cache
+				.get_or_insert(STATISTICS_QUERY, &(), None, run(9))
+				.await,
+			Ok(7)
+		);
+	}
+}
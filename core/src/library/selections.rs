@@ -0,0 +1,366 @@
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::{prisma_models::PrismaValue, raw::Raw};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+	prisma::file_path,
+	util::db::{raw_date, raw_int},
+};
+
+use super::LibraryContext;
+
+/// Selections that haven't been used for this long are dropped, as the client that made them is likely gone
+const SELECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How many file paths are looked up at once when summing the size of a selection
+const SIZE_QUERY_CHUNK: usize = 500;
+
+#[derive(Error, Debug)]
+pub enum SelectionError {
+	#[error("selection not found: <id='{0}'>")]
+	NotFound(Uuid),
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+}
+
+impl From<SelectionError> for rspc::Error {
+	fn from(err: SelectionError) -> Self {
+		match err {
+			SelectionError::NotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SelectionItem {
+	pub location_id: i32,
+	pub file_path_id: i32,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct SelectionSummary {
+	pub count: usize,
+	/// combined size of the selected files, directories don't count towards it
+	pub size_in_bytes: u64,
+}
+
+/// What a query or command is about: file paths picked by the client, or a selection it made before
+#[derive(Deserialize, Type, Debug)]
+pub enum SelectionTarget {
+	Items(Vec<SelectionItem>),
+	Selection(Uuid),
+}
+
+impl SelectionTarget {
+	/// items returns the file paths the target is made of
+	pub fn items(self, library: &LibraryContext) -> Result<Vec<SelectionItem>, SelectionError> {
+		match self {
+			Self::Items(items) => Ok(items),
+			Self::Selection(id) => library.selections.items(id),
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct KindCount {
+	pub kind: i32,
+	pub count: i64,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct DateRange {
+	pub earliest: DateTime<Utc>,
+	pub latest: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Type, Debug)]
+pub struct TagCount {
+	pub id: i32,
+	pub name: Option<String>,
+	pub color: Option<String>,
+	/// how many of the selected file paths have the tag, it's shared by all of them when this is
+	/// the selection's count
+	#[serde(deserialize_with = "raw_int")]
+	pub count: i64,
+}
+
+/// What the inspector shows about many file paths at once
+#[derive(Serialize, Type, Debug)]
+pub struct SelectionInfo {
+	/// how many of the file paths are in the library, any that were deleted since they were
+	/// selected aren't counted
+	pub count: i64,
+	pub directories: i64,
+	/// combined size of the selected files, directories don't count towards it
+	pub size_in_bytes: u64,
+	/// how many files there are of each kind, most common first
+	pub kinds: Vec<KindCount>,
+	pub date_created: Option<DateRange>,
+	pub date_modified: Option<DateRange>,
+	/// the tags on any of the selected files, the most shared first
+	pub tags: Vec<TagCount>,
+}
+
+#[derive(Deserialize)]
+struct SelectionTotals {
+	#[serde(deserialize_with = "raw_int")]
+	count: i64,
+	#[serde(deserialize_with = "raw_int")]
+	directories: i64,
+	#[serde(deserialize_with = "raw_int")]
+	size_in_bytes: i64,
+	#[serde(deserialize_with = "raw_date")]
+	first_created: Option<DateTime<Utc>>,
+	#[serde(deserialize_with = "raw_date")]
+	last_created: Option<DateTime<Utc>>,
+	#[serde(deserialize_with = "raw_date")]
+	first_modified: Option<DateTime<Utc>>,
+	#[serde(deserialize_with = "raw_date")]
+	last_modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct RawKindCount {
+	#[serde(deserialize_with = "raw_int")]
+	kind: i64,
+	#[serde(deserialize_with = "raw_int")]
+	count: i64,
+}
+
+struct Selection {
+	items: HashSet<SelectionItem>,
+	last_used: Instant,
+}
+
+/// Selections holds sets of file paths picked by clients, so bulk operations can refer to a selection
+/// by id instead of sending every id with each request. They only live in memory and expire when idle.
+#[derive(Default)]
+pub struct Selections(Mutex<HashMap<Uuid, Selection>>);
+
+impl Selections {
+	/// create starts a new, empty selection
+	pub fn create(&self) -> Uuid {
+		self.create_with(vec![])
+	}
+
+	/// create_with starts a new selection of the items
+	pub fn create_with(&self, items: Vec<SelectionItem>) -> Uuid {
+		let mut selections = self.0.lock().unwrap();
+		selections.retain(|_, selection| selection.last_used.elapsed() < SELECTION_IDLE_TIMEOUT);
+
+		let id = Uuid::new_v4();
+		selections.insert(
+			id,
+			Selection {
+				items: items.into_iter().collect(),
+				last_used: Instant::now(),
+			},
+		);
+
+		id
+	}
+
+	fn with<T>(
+		&self,
+		id: Uuid,
+		f: impl FnOnce(&mut HashSet<SelectionItem>) -> T,
+	) -> Result<T, SelectionError> {
+		let mut selections = self.0.lock().unwrap();
+		let selection = selections
+			.get_mut(&id)
+			.filter(|selection| selection.last_used.elapsed() < SELECTION_IDLE_TIMEOUT)
+			.ok_or(SelectionError::NotFound(id))?;
+
+		selection.last_used = Instant::now();
+		Ok(f(&mut selection.items))
+	}
+
+	/// add puts the items in the selection, returning how many items it now has
+	pub fn add(&self, id: Uuid, items: Vec<SelectionItem>) -> Result<usize, SelectionError> {
+		self.with(id, |selection| {
+			selection.extend(items);
+			selection.len()
+		})
+	}
+
+	/// remove takes the items out of the selection, returning how many items it now has
+	pub fn remove(&self, id: Uuid, items: Vec<SelectionItem>) -> Result<usize, SelectionError> {
+		self.with(id, |selection| {
+			for item in &items {
+				selection.remove(item);
+			}
+			selection.len()
+		})
+	}
+
+	pub fn clear(&self, id: Uuid) -> Result<(), SelectionError> {
+		self.with(id, |selection| selection.clear())
+	}
+
+	/// delete drops the selection, returning false if it didn't exist
+	pub fn delete(&self, id: Uuid) -> bool {
+		self.0.lock().unwrap().remove(&id).is_some()
+	}
+
+	pub fn items(&self, id: Uuid) -> Result<Vec<SelectionItem>, SelectionError> {
+		self.with(id, |selection| selection.iter().copied().collect())
+	}
+
+	pub fn count(&self, id: Uuid) -> Result<usize, SelectionError> {
+		self.with(id, |selection| selection.len())
+	}
+}
+
+/// summarise counts the items in a selection and adds up the size of their files
+pub async fn summarise(
+	library: &LibraryContext,
+	id: Uuid,
+) -> Result<SelectionSummary, SelectionError> {
+	let items = library.selections.items(id)?;
+
+	let mut by_location = HashMap::<i32, Vec<i32>>::new();
+	for item in &items {
+		by_location
+			.entry(item.location_id)
+			.or_default()
+			.push(item.file_path_id);
+	}
+
+	let mut size_in_bytes = 0;
+	for (location_id, file_path_ids) in by_location {
+		for chunk in file_path_ids.chunks(SIZE_QUERY_CHUNK) {
+			size_in_bytes += library
+				.db
+				.file_path()
+				.find_many(vec![
+					file_path::location_id::equals(location_id),
+					file_path::id::in_vec(chunk.to_vec()),
+				])
+				.with(file_path::object::fetch())
+				.exec()
+				.await?
+				.iter()
+				.filter_map(|file_path| file_path.object().ok().flatten())
+				.filter_map(|object| object.size_in_bytes.parse::<u64>().ok())
+				.sum::<u64>();
+		}
+	}
+
+	Ok(SelectionSummary {
+		count: items.len(),
+		size_in_bytes,
+	})
+}
+
+/// selected_query selects `columns` from the selected file paths, `fp`. They're bound to the query as
+/// a JSON array of `[location_id, file_path_id]` pairs, so selections of any size take a single
+/// placeholder.
+fn selected_query(selected: &PrismaValue, columns: &str, rest: &str) -> Raw {
+	Raw::new(
+		&format!(
+			"WITH selected AS (SELECT DISTINCT json_extract(value, '$[0]') AS location_id, \
+			json_extract(value, '$[1]') AS id FROM json_each({{}})) \
+			SELECT {columns} FROM selected \
+			JOIN file_path fp ON fp.location_id = selected.location_id AND fp.id = selected.id {rest}"
+		),
+		vec![selected.clone()],
+	)
+}
+
+fn date_range(earliest: Option<DateTime<Utc>>, latest: Option<DateTime<Utc>>) -> Option<DateRange> {
+	Some(DateRange {
+		earliest: earliest?,
+		latest: latest?,
+	})
+}
+
+/// selection_info sums up the selected file paths in the database, so it takes about as long for a
+/// thousand file paths as it does for one
+pub async fn selection_info(
+	library: &LibraryContext,
+	target: SelectionTarget,
+) -> Result<SelectionInfo, SelectionError> {
+	let items = target.items(library)?;
+	let selected = PrismaValue::String(
+		serde_json::to_string(
+			&items
+				.iter()
+				.map(|item| [item.location_id, item.file_path_id])
+				.collect::<Vec<_>>(),
+		)
+		.expect("ids always serialize"),
+	);
+
+	let totals: Vec<SelectionTotals> = library
+		.db
+		._query_raw(selected_query(
+			&selected,
+			"COUNT(*) AS count, COALESCE(SUM(fp.is_dir), 0) AS directories, \
+			COALESCE(SUM(CASE WHEN fp.is_dir THEN 0 ELSE CAST(o.size_in_bytes AS INTEGER) END), 0) \
+			AS size_in_bytes, MIN(fp.date_created) AS first_created, \
+			MAX(fp.date_created) AS last_created, MIN(fp.date_modified) AS first_modified, \
+			MAX(fp.date_modified) AS last_modified",
+			"LEFT JOIN object o ON o.id = fp.object_id",
+		))
+		.exec()
+		.await?;
+
+	let kinds: Vec<RawKindCount> = library
+		.db
+		._query_raw(selected_query(
+			&selected,
+			"COALESCE(o.kind, 0) AS kind, COUNT(*) AS count",
+			"LEFT JOIN object o ON o.id = fp.object_id WHERE NOT fp.is_dir \
+			GROUP BY COALESCE(o.kind, 0) ORDER BY count DESC",
+		))
+		.exec()
+		.await?;
+
+	let tags: Vec<TagCount> = library
+		.db
+		._query_raw(selected_query(
+			&selected,
+			"t.id AS id, t.name AS name, t.color AS color, COUNT(*) AS count",
+			"JOIN tag_on_object tob ON tob.object_id = fp.object_id \
+			JOIN tag t ON t.id = tob.tag_id GROUP BY t.id ORDER BY count DESC, t.name",
+		))
+		.exec()
+		.await?;
+
+	let totals = totals.into_iter().next();
+	Ok(SelectionInfo {
+		count: totals.as_ref().map_or(0, |totals| totals.count),
+		directories: totals.as_ref().map_or(0, |totals| totals.directories),
+		size_in_bytes: totals
+			.as_ref()
+			.map_or(0, |totals| totals.size_in_bytes.max(0) as u64),
+		kinds: kinds
+			.into_iter()
+			.map(|kind| KindCount {
+				kind: kind.kind as i32,
+				count: kind.count,
+			})
+			.collect(),
+		date_created: totals
+			.as_ref()
+			.and_then(|totals| date_range(totals.first_created, totals.last_created)),
+		date_modified: totals
+			.as_ref()
+			.and_then(|totals| date_range(totals.first_modified, totals.last_modified)),
+		tags,
+	})
+}
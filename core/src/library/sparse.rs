@@ -0,0 +1,185 @@
+use std::collections::{HashMap, HashSet};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::prisma::{file_path, location, tag_on_object};
+
+use super::LibraryContext;
+
+/// SparseCheckout lets a node with little storage keep the metadata of the whole library while only
+/// holding the content of what has been pinned. Content missing from the node is fetched from peers.
+#[derive(Debug, Serialize, Deserialize, Clone, Type, Default)]
+pub struct SparseCheckout {
+	pub enabled: bool,
+	pub pins: Vec<Pin>,
+}
+
+/// A Pin marks content the node should always have a copy of while in sparse checkout
+#[derive(Debug, Serialize, Deserialize, Clone, Type, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum Pin {
+	Tag {
+		tag_id: i32,
+	},
+	/// everything under `path` in the location, the whole location if `path` is empty
+	Folder {
+		location_id: i32,
+		path: String,
+	},
+}
+
+impl Pin {
+	fn matches(&self, file_path: &file_path::Data) -> bool {
+		match self {
+			Pin::Tag { .. } => false,
+			Pin::Folder { location_id, path } => {
+				file_path.location_id == *location_id
+					&& (path.is_empty()
+						|| file_path.materialized_path == *path
+						|| file_path
+							.materialized_path
+							.starts_with(&format!("{}/", path.trim_end_matches('/'))))
+			}
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Type)]
+pub struct ContentStatus {
+	pub object_id: i32,
+	/// a copy of the content is on one of this node's online locations
+	pub local: bool,
+	/// the node is meant to hold the content, because sparse checkout is off or it's pinned
+	pub wanted: bool,
+}
+
+/// content_status tells which of the objects have their content on this node and which only exist
+/// as metadata, to be fetched from a peer when opened
+pub async fn content_status(
+	library: &LibraryContext,
+	object_ids: Vec<i32>,
+) -> Result<Vec<ContentStatus>, prisma_client_rust::QueryError> {
+	let sparse = &library.config.sparse_checkout;
+
+	let local_locations = library
+		.db
+		.location()
+		.find_many(vec![
+			location::node_id::equals(library.node_local_id),
+			location::is_online::equals(true),
+		])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|location| location.id)
+		.collect::<HashSet<_>>();
+
+	let mut file_paths_by_object = HashMap::<i32, Vec<file_path::Data>>::new();
+	for file_path in library
+		.db
+		.file_path()
+		.find_many(vec![file_path::object_id::in_vec(object_ids.clone())])
+		.exec()
+		.await?
+	{
+		if let Some(object_id) = file_path.object_id {
+			file_paths_by_object
+				.entry(object_id)
+				.or_default()
+				.push(file_path);
+		}
+	}
+
+	let pinned_tags = sparse
+		.pins
+		.iter()
+		.filter_map(|pin| match pin {
+			Pin::Tag { tag_id } => Some(*tag_id),
+			Pin::Folder { .. } => None,
+		})
+		.collect::<Vec<_>>();
+
+	let tagged = if sparse.enabled && !pinned_tags.is_empty() {
+		library
+			.db
+			.tag_on_object()
+			.find_many(vec![
+				tag_on_object::tag_id::in_vec(pinned_tags),
+				tag_on_object::object_id::in_vec(object_ids.clone()),
+			])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|tag_on_object| tag_on_object.object_id)
+			.collect::<HashSet<_>>()
+	} else {
+		HashSet::new()
+	};
+
+	Ok(object_ids
+		.into_iter()
+		.map(|object_id| {
+			let file_paths = file_paths_by_object
+				.get(&object_id)
+				.map(Vec::as_slice)
+				.unwrap_or_default();
+
+			ContentStatus {
+				object_id,
+				local: file_paths
+					.iter()
+					.any(|file_path| local_locations.contains(&file_path.location_id)),
+				wanted: !sparse.enabled
+					|| tagged.contains(&object_id)
+					|| file_paths
+						.iter()
+						.any(|file_path| sparse.pins.iter().any(|pin| pin.matches(file_path))),
+			}
+		})
+		.collect())
+}
+
+/// missing_pinned_content lists the pinned objects this node doesn't have a copy of yet
+pub async fn missing_pinned_content(
+	library: &LibraryContext,
+) -> Result<Vec<i32>, prisma_client_rust::QueryError> {
+	let sparse = &library.config.sparse_checkout;
+	if !sparse.enabled {
+		return Ok(vec![]);
+	}
+
+	let mut object_ids = HashSet::new();
+	for pin in &sparse.pins {
+		match pin {
+			Pin::Tag { tag_id } => object_ids.extend(
+				library
+					.db
+					.tag_on_object()
+					.find_many(vec![tag_on_object::tag_id::equals(*tag_id)])
+					.exec()
+					.await?
+					.into_iter()
+					.map(|tag_on_object| tag_on_object.object_id),
+			),
+			Pin::Folder { location_id, .. } => object_ids.extend(
+				library
+					.db
+					.file_path()
+					.find_many(vec![file_path::location_id::equals(*location_id)])
+					.exec()
+					.await?
+					.into_iter()
+					.filter(|file_path| pin.matches(file_path))
+					.filter_map(|file_path| file_path.object_id),
+			),
+		}
+	}
+
+	Ok(content_status(library, object_ids.into_iter().collect())
+		.await?
+		.into_iter()
+		.filter(|status| !status.local)
+		.map(|status| status.object_id)
+		.collect())
+}
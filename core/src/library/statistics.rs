@@ -0,0 +1,298 @@
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Mutex,
+};
+
+use chrono::Utc;
+use fs_extra::dir::get_size; // TODO: Remove this dependency as it is sync instead of async
+use prisma_client_rust::Direction;
+use rspc::Type;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::fs;
+
+use crate::{
+	prisma::{file_path, statistics},
+	volume::{get_volumes, save_volume, VolumeError},
+};
+
+use super::LibraryContext;
+
+/// How many file paths are read at once when counting a location
+const STATISTICS_QUERY_CHUNK: i64 = 1000;
+
+#[derive(Error, Debug)]
+pub enum StatisticsError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("Volume error: {0}")]
+	VolumeError(#[from] VolumeError),
+}
+
+impl From<StatisticsError> for rspc::Error {
+	fn from(err: StatisticsError) -> Self {
+		rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+	}
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct LibraryStatistics {
+	/// what was captured in the database, which is what's shown in the list of libraries
+	pub captured: statistics::Data,
+	pub total_files: u64,
+	pub total_bytes: u64,
+	/// bytes of files whose contents are in no other file of the library, plus one copy of the rest
+	pub unique_bytes: u64,
+	/// bytes taken up by every extra copy of the same contents
+	pub duplicated_bytes: u64,
+	/// how many files there are of each kind, the most common first
+	pub kinds: Vec<KindCount>,
+	pub locations: Vec<LocationStatistics>,
+}
+
+#[derive(Serialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct KindCount {
+	pub kind: i32,
+	pub count: u64,
+}
+
+#[derive(Serialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct LocationStatistics {
+	pub location_id: i32,
+	pub files: u64,
+	pub bytes: u64,
+}
+
+/// What a location adds to the statistics of its library. Objects are kept by id, so contents in more
+/// than one location are counted once towards the unique bytes.
+#[derive(Debug, Default, Clone)]
+struct LocationAggregate {
+	files: u64,
+	bytes: u64,
+	objects: HashMap<i32, u64>,
+	kinds: HashMap<i32, u64>,
+}
+
+impl LocationAggregate {
+	/// add counts a file in the aggregate, files that weren't identified yet don't have a size
+	fn add(&mut self, file_path: &file_path::Data) {
+		self.files += 1;
+
+		match file_path.object().ok().flatten() {
+			Some(object) => {
+				let size = object.size_in_bytes.parse().unwrap_or(0);
+				self.bytes += size;
+				self.objects.insert(object.id, size);
+				*self.kinds.entry(object.kind).or_default() += 1;
+			}
+			None => *self.kinds.entry(0).or_default() += 1,
+		}
+	}
+}
+
+/// Statistics keeps what each location adds to the statistics of a library, so they don't have to be
+/// counted from scratch every time they're asked for. Jobs mark the locations they change, and only
+/// those are counted again.
+#[derive(Default)]
+pub struct Statistics {
+	locations: Mutex<HashMap<i32, LocationAggregate>>,
+	changed: Mutex<HashSet<i32>>,
+}
+
+impl Statistics {
+	/// location_changed marks a location whose file paths or objects changed, to be counted again
+	pub fn location_changed(&self, location_id: i32) {
+		self.changed.lock().unwrap().insert(location_id);
+	}
+
+	/// location_removed drops what a location added to the statistics
+	pub fn location_removed(&self, location_id: i32) {
+		self.changed.lock().unwrap().remove(&location_id);
+		self.locations.lock().unwrap().remove(&location_id);
+	}
+
+	/// refresh counts the locations that changed or were never counted, and forgets the ones that are gone
+	async fn refresh(&self, library: &LibraryContext) -> Result<(), StatisticsError> {
+		let location_ids = library
+			.db
+			.location()
+			.find_many(vec![])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|location| location.id)
+			.collect::<HashSet<_>>();
+
+		let stale = {
+			let mut locations = self.locations.lock().unwrap();
+			locations.retain(|id, _| location_ids.contains(id));
+
+			let mut changed = self.changed.lock().unwrap();
+			location_ids
+				.iter()
+				.filter(|id| changed.remove(id) || !locations.contains_key(id))
+				.copied()
+				.collect::<Vec<_>>()
+		};
+
+		for (i, &location_id) in stale.iter().enumerate() {
+			match count_location(library, location_id).await {
+				Ok(aggregate) => {
+					self.locations
+						.lock()
+						.unwrap()
+						.insert(location_id, aggregate);
+				}
+				Err(e) => {
+					// counted again next time
+					self.changed.lock().unwrap().extend(&stale[i..]);
+					return Err(e.into());
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// totals returns how many objects the library's files have, the bytes of its files and the bytes
+	/// of their objects, which is what's left when duplicates are counted once
+	fn totals(&self) -> (usize, u64, u64) {
+		let locations = self.locations.lock().unwrap();
+		let objects = locations
+			.values()
+			.flat_map(|aggregate| &aggregate.objects)
+			.collect::<HashMap<_, _>>();
+
+		(
+			objects.len(),
+			locations.values().map(|aggregate| aggregate.bytes).sum(),
+			objects.into_values().sum(),
+		)
+	}
+
+	/// summarise combines what every location adds to the statistics
+	fn summarise(&self, captured: statistics::Data) -> LibraryStatistics {
+		let (_, total_bytes, unique_bytes) = self.totals();
+		let locations = self.locations.lock().unwrap();
+
+		let mut kinds = HashMap::<i32, u64>::new();
+		let mut location_statistics = Vec::with_capacity(locations.len());
+		for (&location_id, aggregate) in locations.iter() {
+			for (&kind, &count) in &aggregate.kinds {
+				*kinds.entry(kind).or_default() += count;
+			}
+			location_statistics.push(LocationStatistics {
+				location_id,
+				files: aggregate.files,
+				bytes: aggregate.bytes,
+			});
+		}
+		location_statistics.sort_by_key(|location| location.location_id);
+
+		let mut kinds = kinds
+			.into_iter()
+			.map(|(kind, count)| KindCount { kind, count })
+			.collect::<Vec<_>>();
+		kinds.sort_by(|a, b| b.count.cmp(&a.count).then(a.kind.cmp(&b.kind)));
+
+		LibraryStatistics {
+			captured,
+			total_files: locations.values().map(|aggregate| aggregate.files).sum(),
+			total_bytes,
+			unique_bytes,
+			duplicated_bytes: total_bytes.saturating_sub(unique_bytes),
+			kinds,
+			locations: location_statistics,
+		}
+	}
+}
+
+/// count_location adds up the files of a location
+async fn count_location(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<LocationAggregate, prisma_client_rust::QueryError> {
+	let mut aggregate = LocationAggregate::default();
+	let mut cursor = 0;
+
+	loop {
+		let file_paths = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(location_id),
+				file_path::is_dir::equals(false),
+				file_path::id::gt(cursor),
+			])
+			.with(file_path::object::fetch())
+			.order_by(file_path::id::order(Direction::Asc))
+			.take(STATISTICS_QUERY_CHUNK)
+			.exec()
+			.await?;
+
+		cursor = match file_paths.last() {
+			Some(file_path) => file_path.id,
+			None => break,
+		};
+		for file_path in &file_paths {
+			aggregate.add(file_path);
+		}
+	}
+
+	Ok(aggregate)
+}
+
+/// capture_statistics brings the statistics of a library up to date, saving the totals in the database
+pub async fn capture_statistics(
+	library: &LibraryContext,
+) -> Result<LibraryStatistics, StatisticsError> {
+	library.statistics.refresh(library).await?;
+
+	// TODO: get from database, not sys
+	let volumes = get_volumes();
+	save_volume(library).await?;
+
+	let mut available_capacity: u64 = 0;
+	let mut total_capacity: u64 = 0;
+	if volumes.is_ok() {
+		for volume in volumes? {
+			total_capacity += volume.total_capacity;
+			available_capacity += volume.available_capacity;
+		}
+	}
+
+	let library_db_size = match fs::metadata(library.config().data_directory()).await {
+		Ok(metadata) => metadata.len(),
+		Err(_) => 0,
+	};
+
+	let thumbnail_folder_size = get_size(library.config().data_directory().join("thumbnails"));
+
+	let (total_objects, total_bytes, unique_bytes) = library.statistics.totals();
+
+	use statistics::*;
+	let params = vec![
+		id::set(1), // Each library is a database so only one of these ever exists
+		date_captured::set(Utc::now().into()),
+		total_object_count::set(total_objects as i32),
+		library_db_size::set(library_db_size.to_string()),
+		total_bytes_used::set(total_bytes.to_string()),
+		total_bytes_capacity::set(total_capacity.to_string()),
+		total_unique_bytes::set(unique_bytes.to_string()),
+		total_bytes_free::set(available_capacity.to_string()),
+		preview_media_bytes::set(thumbnail_folder_size.unwrap_or(0).to_string()),
+	];
+
+	let captured = library
+		.db
+		.statistics()
+		.upsert(
+			statistics::id::equals(1), // Each library is a database so only one of these ever exists
+			params.clone(),
+			params,
+		)
+		.exec()
+		.await?;
+
+	Ok(library.statistics.summarise(captured))
+}
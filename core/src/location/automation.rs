@@ -0,0 +1,555 @@
+//! Rules users set on a location to do something to files as they show up in it, like "tag PDFs
+//! that land in Downloads as 'documents' and move them to Archive". Rules are run on the files the
+//! identifier finds new in the location, so once they're indexed and have an object to tag, and
+//! every action they take, or fail to, is kept in an audit log.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use globset::{Glob, GlobMatcher};
+use prisma_client_rust::Direction;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::{
+	invalidate_query,
+	library::LibraryContext,
+	object::fs::organize::{move_file_path, OrganizeError},
+	prisma::{automation_rule, automation_run, file_path, location, object, tag, tag_on_object},
+	sync::{
+		models::{uuid_from_pub_id, TAG_ON_OBJECT},
+		SyncError,
+	},
+};
+
+use super::{fetch_location, LocationError};
+
+/// How long what rules did is kept in the audit log
+const RUN_RETENTION_DAYS: i64 = 30;
+/// How many runs are listed when no limit is asked for
+const DEFAULT_RUNS_LIMIT: i64 = 100;
+
+#[derive(Error, Debug)]
+pub enum AutomationError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("{0}")]
+	LocationError(#[from] LocationError),
+	#[error("Automation rule not found (id: {0})")]
+	NotFound(i32),
+	#[error("Invalid glob: {0}")]
+	InvalidGlob(#[from] globset::Error),
+	#[error("Invalid automation rule: {0}")]
+	InvalidRule(&'static str),
+	#[error("Invalid stored automation actions: {0}")]
+	Json(#[from] serde_json::Error),
+}
+
+impl From<AutomationError> for rspc::Error {
+	fn from(err: AutomationError) -> Self {
+		match err {
+			AutomationError::NotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			AutomationError::InvalidGlob(_) | AutomationError::InvalidRule(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			AutomationError::LocationError(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// What a rule does to a file
+#[derive(Debug, Clone, Serialize, Deserialize, Type, PartialEq, Eq)]
+#[serde(tag = "kind")]
+pub enum AutomationAction {
+	Tag {
+		tag_id: i32,
+	},
+	/// moves the file into a directory of its location, given by its path in the location. A file
+	/// by the same name that's already there is kept, and the moved one gets a conflict copy name.
+	Move {
+		to: String,
+	},
+}
+
+impl AutomationAction {
+	pub(super) fn validate(&self) -> Result<(), AutomationError> {
+		match self {
+			Self::Tag { .. } => Ok(()),
+			Self::Move { to } => {
+				let path = Path::new(to);
+				if path.is_absolute()
+					|| path
+						.components()
+						.any(|component| component.as_os_str() == "..")
+				{
+					return Err(AutomationError::InvalidRule(
+						"files can only be moved to a directory of their location",
+					));
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+fn validate(glob: &str, actions: &[AutomationAction]) -> Result<(), AutomationError> {
+	Glob::new(glob)?;
+	if actions.is_empty() {
+		return Err(AutomationError::InvalidRule(
+			"rules need at least one action",
+		));
+	}
+	actions.iter().try_for_each(AutomationAction::validate)
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct AutomationRule {
+	pub id: i32,
+	pub name: String,
+	pub location_id: i32,
+	/// which files the rule is for, matched against their path in the location
+	pub glob: String,
+	pub actions: Vec<AutomationAction>,
+	pub enabled: bool,
+	pub date_created: DateTime<Utc>,
+}
+
+impl TryFrom<automation_rule::Data> for AutomationRule {
+	type Error = AutomationError;
+
+	fn try_from(data: automation_rule::Data) -> Result<Self, Self::Error> {
+		Ok(Self {
+			id: data.id,
+			location_id: data.location_id,
+			actions: serde_json::from_str(&data.actions)?,
+			enabled: data.enabled,
+			date_created: data.date_created.into(),
+			name: data.name,
+			glob: data.glob,
+		})
+	}
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct AutomationRuleCreateArgs {
+	pub name: String,
+	pub location_id: i32,
+	pub glob: String,
+	pub actions: Vec<AutomationAction>,
+}
+
+impl AutomationRuleCreateArgs {
+	pub async fn create(self, library: &LibraryContext) -> Result<AutomationRule, AutomationError> {
+		validate(&self.glob, &self.actions)?;
+		if fetch_location(library, self.location_id)
+			.exec()
+			.await?
+			.is_none()
+		{
+			return Err(LocationError::IdNotFound(self.location_id).into());
+		}
+
+		library
+			.db
+			.automation_rule()
+			.create_unchecked(
+				self.name,
+				self.location_id,
+				self.glob,
+				serde_json::to_string(&self.actions)?,
+				vec![],
+			)
+			.exec()
+			.await?
+			.try_into()
+	}
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct AutomationRuleUpdateArgs {
+	pub id: i32,
+	pub name: Option<String>,
+	pub glob: Option<String>,
+	pub actions: Option<Vec<AutomationAction>>,
+	pub enabled: Option<bool>,
+}
+
+impl AutomationRuleUpdateArgs {
+	pub async fn update(self, library: &LibraryContext) -> Result<AutomationRule, AutomationError> {
+		let current = AutomationRule::try_from(
+			library
+				.db
+				.automation_rule()
+				.find_unique(automation_rule::id::equals(self.id))
+				.exec()
+				.await?
+				.ok_or(AutomationError::NotFound(self.id))?,
+		)?;
+		validate(
+			self.glob.as_deref().unwrap_or(&current.glob),
+			self.actions.as_deref().unwrap_or(&current.actions),
+		)?;
+
+		let mut params = vec![];
+		if let Some(name) = self.name {
+			params.push(automation_rule::name::set(name));
+		}
+		if let Some(glob) = self.glob {
+			params.push(automation_rule::glob::set(glob));
+		}
+		if let Some(actions) = self.actions {
+			params.push(automation_rule::actions::set(serde_json::to_string(
+				&actions,
+			)?));
+		}
+		if let Some(enabled) = self.enabled {
+			params.push(automation_rule::enabled::set(enabled));
+		}
+
+		library
+			.db
+			.automation_rule()
+			.update(automation_rule::id::equals(self.id), params)
+			.exec()
+			.await?
+			.try_into()
+	}
+}
+
+/// list_automations returns the automation rules of a location, or of the whole library, oldest first
+pub async fn list_automations(
+	library: &LibraryContext,
+	location_id: Option<i32>,
+) -> Result<Vec<AutomationRule>, AutomationError> {
+	library
+		.db
+		.automation_rule()
+		.find_many(
+			location_id
+				.map(|location_id| vec![automation_rule::location_id::equals(location_id)])
+				.unwrap_or_default(),
+		)
+		.order_by(automation_rule::id::order(Direction::Asc))
+		.exec()
+		.await?
+		.into_iter()
+		.map(AutomationRule::try_from)
+		.collect()
+}
+
+/// An entry of the audit log, what a rule did to a file
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct AutomationRun {
+	pub id: i32,
+	pub rule_id: i32,
+	pub file_path_id: i32,
+	/// where the file was in the location when the rule ran
+	pub path: String,
+	pub action: Option<AutomationAction>,
+	/// why the action failed, if it did
+	pub error: Option<String>,
+	pub date_created: DateTime<Utc>,
+}
+
+impl From<automation_run::Data> for AutomationRun {
+	fn from(data: automation_run::Data) -> Self {
+		Self {
+			id: data.id,
+			rule_id: data.rule_id,
+			file_path_id: data.file_path_id,
+			path: data.path,
+			action: serde_json::from_str(&data.action).ok(),
+			error: data.error,
+			date_created: data.date_created.into(),
+		}
+	}
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct AutomationRunsArgs {
+	pub rule_id: Option<i32>,
+	pub limit: Option<i64>,
+}
+
+/// list_automation_runs returns the audit log of a rule, or of all of them, latest first
+pub async fn list_automation_runs(
+	library: &LibraryContext,
+	args: AutomationRunsArgs,
+) -> Result<Vec<AutomationRun>, AutomationError> {
+	Ok(library
+		.db
+		.automation_run()
+		.find_many(
+			args.rule_id
+				.map(|rule_id| vec![automation_run::rule_id::equals(rule_id)])
+				.unwrap_or_default(),
+		)
+		.order_by(automation_run::id::order(Direction::Desc))
+		.take(args.limit.unwrap_or(DEFAULT_RUNS_LIMIT))
+		.exec()
+		.await?
+		.into_iter()
+		.map(Into::into)
+		.collect())
+}
+
+#[derive(Error, Debug)]
+enum ActionError {
+	#[error("the file hasn't been identified, so it can't be tagged")]
+	NotIdentified,
+	#[error("tag not found (id: {0})")]
+	TagNotFound(i32),
+	#[error("the location isn't on this node")]
+	NotLocal,
+	#[error("{0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+	#[error("{0}")]
+	Sync(#[from] SyncError),
+	#[error("{0}")]
+	Move(#[from] OrganizeError),
+}
+
+/// tag_file puts the tag on the file's object, if it isn't on it already
+async fn tag_file(
+	library: &LibraryContext,
+	file_path: &file_path::Data,
+	tag_id: i32,
+) -> Result<(), ActionError> {
+	let object = file_path
+		.object()
+		.ok()
+		.flatten()
+		.ok_or(ActionError::NotIdentified)?;
+	let tag = library
+		.db
+		.tag()
+		.find_unique(tag::id::equals(tag_id))
+		.exec()
+		.await?
+		.ok_or(ActionError::TagNotFound(tag_id))?;
+
+	if library
+		.db
+		.tag_on_object()
+		.find_unique(tag_on_object::tag_id_object_id(tag_id, object.id))
+		.exec()
+		.await?
+		.is_some()
+	{
+		return Ok(());
+	}
+
+	library
+		.db
+		.tag_on_object()
+		.create(
+			tag::id::equals(tag_id),
+			object::id::equals(object.id),
+			vec![],
+		)
+		.exec()
+		.await?;
+
+	let object_pub_id = library.sync.ensure_object_pub_id(object).await?;
+	library
+		.sync
+		.write_ops(vec![library.sync.relation_create(
+			TAG_ON_OBJECT,
+			object_pub_id,
+			uuid_from_pub_id(&tag.pub_id),
+		)])
+		.await?;
+
+	Ok(())
+}
+
+/// move_file moves the file into the directory `to` of its location, returning its file path after
+/// the move
+async fn move_file(
+	library: &LibraryContext,
+	location: &location::Data,
+	file_path: &file_path::Data,
+	to: &str,
+) -> Result<file_path::Data, ActionError> {
+	let location_path = location
+		.local_path
+		.as_ref()
+		.map(PathBuf::from)
+		.ok_or(ActionError::NotLocal)?;
+	let file_name = Path::new(&file_path.materialized_path)
+		.file_name()
+		.unwrap_or_default();
+
+	let node_config = library.config().get().await;
+	let target = node_config
+		.conflict_naming
+		.free_path(&location_path.join(to).join(file_name), &node_config.name);
+	let target = target
+		.strip_prefix(&location_path)
+		.unwrap_or(&target)
+		.to_string_lossy()
+		.to_string();
+
+	Ok(move_file_path(
+		library,
+		location.id,
+		&location_path,
+		&location.pub_id,
+		file_path.id,
+		&file_path.materialized_path,
+		&target,
+	)
+	.await?)
+}
+
+/// run_automations runs the enabled rules of a location on its file paths that were just identified,
+/// doing each rule's actions in order and logging what they did. A file a rule moves keeps going
+/// through the following rules at its new path.
+pub async fn run_automations(
+	library: &LibraryContext,
+	location: &location::Data,
+	file_path_ids: Vec<i32>,
+) -> Result<(), AutomationError> {
+	if file_path_ids.is_empty() {
+		return Ok(());
+	}
+
+	let rules = library
+		.db
+		.automation_rule()
+		.find_many(vec![
+			automation_rule::location_id::equals(location.id),
+			automation_rule::enabled::equals(true),
+		])
+		.order_by(automation_rule::id::order(Direction::Asc))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|data| {
+			let id = data.id;
+			let rule = AutomationRule::try_from(data)
+				.and_then(|rule| Ok((Glob::new(&rule.glob)?.compile_matcher(), rule)));
+			if let Err(e) = &rule {
+				error!("Skipping automation rule {id}: {e}");
+			}
+			rule.ok()
+		})
+		.collect::<Vec<(GlobMatcher, AutomationRule)>>();
+	if rules.is_empty() {
+		return Ok(());
+	}
+
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(location.id),
+			file_path::id::in_vec(file_path_ids),
+			file_path::is_dir::equals(false),
+		])
+		.with(file_path::object::fetch())
+		.exec()
+		.await?;
+
+	let mut runs = vec![];
+	let (mut tagged, mut moved) = (false, false);
+	for mut file_path in file_paths {
+		for (matcher, rule) in &rules {
+			if !matcher.is_match(&file_path.materialized_path) {
+				continue;
+			}
+
+			for action in &rule.actions {
+				let path = file_path.materialized_path.clone();
+				let result = match action {
+					AutomationAction::Tag { tag_id } => {
+						tag_file(library, &file_path, *tag_id).await.map(|_| {
+							tagged = true;
+						})
+					}
+					AutomationAction::Move { to } => {
+						move_file(library, location, &file_path, to)
+							.await
+							.map(|moved_file_path| {
+								// the object isn't fetched again, it's the same
+								let object = file_path.object.take();
+								file_path = moved_file_path;
+								file_path.object = object;
+								moved = true;
+							})
+					}
+				};
+
+				let error = result.err().map(|e| e.to_string());
+				match &error {
+					Some(e) => info!("Automation rule '{}' failed on {path}: {e}", rule.name),
+					None => info!("Automation rule '{}' ran on {path}", rule.name),
+				}
+				runs.push(automation_run::create_unchecked(
+					rule.id,
+					file_path.id,
+					path,
+					serde_json::to_string(action)?,
+					vec![automation_run::error::set(error)],
+				));
+			}
+		}
+	}
+
+	if runs.is_empty() {
+		return Ok(());
+	}
+
+	library.db.automation_run().create_many(runs).exec().await?;
+	library
+		.db
+		.automation_run()
+		.delete_many(vec![automation_run::date_created::lt(
+			(Utc::now() - Duration::days(RUN_RETENTION_DAYS)).into(),
+		)])
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.automations.listRuns");
+	if tagged {
+		invalidate_query!(library, "tags.getForObject");
+	}
+	if moved {
+		invalidate_query!(library, "locations.getExplorerData");
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn validates_rules() {
+		let tag = AutomationAction::Tag { tag_id: 1 };
+		let move_to = |to: &str| AutomationAction::Move { to: to.to_string() };
+
+		assert!(validate("*.pdf", &[tag.clone(), move_to("Archive")]).is_ok());
+		assert!(validate("*.pdf", &[]).is_err());
+		assert!(validate("*.{pdf", &[tag]).is_err());
+		assert!(validate("*.pdf", &[move_to("../Archive")]).is_err());
+		assert!(validate("*.pdf", &[move_to("/tmp")]).is_err());
+	}
+
+	#[test]
+	fn globs_match_anywhere_in_the_location() {
+		let matcher = Glob::new("*.pdf").unwrap().compile_matcher();
+
+		assert!(matcher.is_match("invoice.pdf"));
+		assert!(matcher.is_match("Downloads/2022/invoice.pdf"));
+		assert!(!matcher.is_match("Downloads/invoice.pdf.part"));
+	}
+}
@@ -0,0 +1,177 @@
+//! Cataloged locations, for drives that are plugged in once to be indexed and then kept offline, like
+//! archive disks. A catalog is scanned in full, with a thumbnail for every file whatever the
+//! processing budget, so it can be browsed and searched while the drive is away. It's never written
+//! to, so read-only drives can be cataloged too, and it isn't rescanned when the drive comes back: a
+//! full rescan takes a new snapshot.
+
+use chrono::Utc;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+use tracing::error;
+
+use crate::{
+	api::LibraryEvent,
+	invalidate_query,
+	library::LibraryContext,
+	prisma::location,
+	sync::{
+		models::{uuid_from_pub_id, LOCATION},
+		SyncError,
+	},
+};
+
+use super::{
+	fetch_location, indexer::indexer_job::indexer_job_location, scan_location, LocationError,
+};
+
+/// Whether the files of a location can be opened right now
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationAvailability {
+	Online,
+	/// away, with whatever was indexed before it went
+	Offline,
+	/// away, with the complete snapshot taken when it was cataloged
+	Cataloged,
+}
+
+impl LocationAvailability {
+	pub fn new(is_online: bool, is_catalog: bool) -> Self {
+		match (is_online, is_catalog) {
+			(true, _) => Self::Online,
+			(false, false) => Self::Offline,
+			(false, true) => Self::Cataloged,
+		}
+	}
+}
+
+impl From<&location::Data> for LocationAvailability {
+	fn from(location: &location::Data) -> Self {
+		Self::new(location.is_online, location.is_catalog)
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct LocationAvailabilityInfo {
+	pub location_id: i32,
+	pub availability: LocationAvailability,
+	pub date_cataloged: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
+
+/// availability returns whether the files of each of the library's locations can be opened, for
+/// results from many locations to be told apart
+pub async fn availability(
+	library: &LibraryContext,
+) -> Result<Vec<LocationAvailabilityInfo>, LocationError> {
+	Ok(library
+		.db
+		.location()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|location| LocationAvailabilityInfo {
+			location_id: location.id,
+			availability: (&location).into(),
+			date_cataloged: location.date_cataloged,
+		})
+		.collect())
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct SetCatalogArgs {
+	pub location_id: i32,
+	pub catalog: bool,
+}
+
+/// set_catalog makes a location a catalog or a regular one again. A location that becomes a catalog
+/// while it's online is scanned in full right away.
+pub async fn set_catalog(
+	library: &LibraryContext,
+	args: SetCatalogArgs,
+) -> Result<(), LocationError> {
+	let location = fetch_location(library, args.location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(args.location_id))?;
+	if location.is_catalog == args.catalog {
+		return Ok(());
+	}
+
+	let location = library
+		.db
+		.location()
+		.update(
+			location::id::equals(location.id),
+			vec![location::is_catalog::set(args.catalog)],
+		)
+		.exec()
+		.await?;
+
+	if let Err(e) = sync_catalog(library, &location.pub_id, args.catalog).await {
+		error!("Error logging catalog change for sync: {:#?}", e);
+	}
+
+	invalidate_query!(library, "locations.list");
+	library.emit_event(LibraryEvent::LocationUpdated {
+		location_id: location.id,
+	});
+
+	if args.catalog && location.is_online {
+		let location = fetch_location(library, location.id)
+			.include(indexer_job_location::include())
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(location.id))?;
+		scan_location(library, location).await?;
+	}
+
+	Ok(())
+}
+
+async fn sync_catalog(
+	library: &LibraryContext,
+	location_pub_id: &[u8],
+	is_catalog: bool,
+) -> Result<(), SyncError> {
+	if !library.sync.is_location_logged(location_pub_id).await? {
+		return Ok(());
+	}
+
+	let mut data = Map::new();
+	data.insert("is_catalog".to_string(), json!(is_catalog));
+
+	library
+		.sync
+		.write_ops(vec![library.sync.owned_update(
+			LOCATION,
+			[(uuid_from_pub_id(location_pub_id), data)],
+		)])
+		.await
+}
+
+/// mark_cataloged records that a full scan of a location finished, if it's a catalog
+pub async fn mark_cataloged(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<(), LocationError> {
+	let updated = library
+		.db
+		.location()
+		.update_many(
+			vec![
+				location::id::equals(location_id),
+				location::is_catalog::equals(true),
+			],
+			vec![location::date_cataloged::set(Some(Utc::now().into()))],
+		)
+		.exec()
+		.await?;
+
+	if updated > 0 {
+		invalidate_query!(library, "locations.list");
+		invalidate_query!(library, "locations.getAvailability");
+	}
+
+	Ok(())
+}
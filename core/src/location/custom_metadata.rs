@@ -0,0 +1,630 @@
+//! Fields users define on a location to fill in on its files, like "Client", "Project code" or
+//! "Invoice #" for a location of documents. Each field has a kind its values are checked against,
+//! values can be set on many files at once, and files can be searched by them.
+
+use std::{
+	cmp::Ordering,
+	collections::{HashMap, HashSet},
+};
+
+use chrono::NaiveDate;
+use int_enum::IntEnum;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+	library::LibraryContext,
+	prisma::{file_path, metadata_field, metadata_value},
+};
+
+/// How dates are written in metadata values, which also makes them sort as text
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Error, Debug)]
+pub enum MetadataError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("Metadata field not found (id: {0})")]
+	FieldNotFound(i32),
+	#[error("Metadata field {0} belongs to another location")]
+	FieldInOtherLocation(i32),
+	#[error("Choice fields need at least one choice")]
+	MissingChoices,
+	#[error("'{value}' isn't a valid value for '{field}': {reason}")]
+	InvalidValue {
+		field: String,
+		value: String,
+		reason: &'static str,
+	},
+	#[error("'{0}' is required and can't be cleared")]
+	Required(String),
+	#[error("Invalid metadata field choices: {0}")]
+	InvalidChoices(#[from] serde_json::Error),
+}
+
+impl From<MetadataError> for rspc::Error {
+	fn from(err: MetadataError) -> Self {
+		match err {
+			MetadataError::FieldNotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			MetadataError::FieldInOtherLocation(_)
+			| MetadataError::MissingChoices
+			| MetadataError::InvalidValue { .. }
+			| MetadataError::Required(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum MetadataFieldKind {
+	Text = 0,
+	Number = 1,
+	/// a day, written as `YYYY-MM-DD`
+	Date = 2,
+	Boolean = 3,
+	/// one of the field's choices
+	Choice = 4,
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct MetadataField {
+	pub id: i32,
+	pub location_id: i32,
+	pub name: String,
+	pub kind: MetadataFieldKind,
+	pub required: bool,
+	pub choices: Vec<String>,
+	pub position: i32,
+}
+
+impl TryFrom<metadata_field::Data> for MetadataField {
+	type Error = MetadataError;
+
+	fn try_from(data: metadata_field::Data) -> Result<Self, Self::Error> {
+		Ok(Self {
+			id: data.id,
+			location_id: data.location_id,
+			kind: MetadataFieldKind::from_int(data.kind).unwrap_or(MetadataFieldKind::Text),
+			required: data.required,
+			choices: match data.choices {
+				Some(choices) => serde_json::from_str(&choices)?,
+				None => vec![],
+			},
+			position: data.position,
+			name: data.name,
+		})
+	}
+}
+
+impl MetadataField {
+	/// validate checks a value against the kind of the field, returning it the way it's stored
+	pub fn validate(&self, value: &str) -> Result<String, MetadataError> {
+		let value = value.trim();
+		let invalid = |reason| MetadataError::InvalidValue {
+			field: self.name.clone(),
+			value: value.to_string(),
+			reason,
+		};
+
+		match self.kind {
+			MetadataFieldKind::Text => Ok(value.to_string()),
+			MetadataFieldKind::Number => value
+				.parse::<f64>()
+				.ok()
+				.filter(|number| number.is_finite())
+				.map(|number| number.to_string())
+				.ok_or_else(|| invalid("not a number")),
+			MetadataFieldKind::Date => NaiveDate::parse_from_str(value, DATE_FORMAT)
+				.map(|date| date.format(DATE_FORMAT).to_string())
+				.map_err(|_| invalid("not a date like 2022-11-29")),
+			MetadataFieldKind::Boolean => match value.to_lowercase().as_str() {
+				"true" | "yes" | "1" => Ok("true".to_string()),
+				"false" | "no" | "0" => Ok("false".to_string()),
+				_ => Err(invalid("not true or false")),
+			},
+			MetadataFieldKind::Choice => self
+				.choices
+				.iter()
+				.find(|choice| choice.eq_ignore_ascii_case(value))
+				.cloned()
+				.ok_or_else(|| invalid("not one of the field's choices")),
+		}
+	}
+
+	/// compare orders two stored values of the field
+	fn compare(&self, a: &str, b: &str) -> Option<Ordering> {
+		match self.kind {
+			MetadataFieldKind::Number => a.parse::<f64>().ok()?.partial_cmp(&b.parse().ok()?),
+			_ => Some(a.to_lowercase().cmp(&b.to_lowercase())),
+		}
+	}
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct MetadataFieldCreateArgs {
+	pub location_id: i32,
+	pub name: String,
+	pub kind: MetadataFieldKind,
+	pub required: bool,
+	pub choices: Vec<String>,
+}
+
+impl MetadataFieldCreateArgs {
+	pub async fn create(self, library: &LibraryContext) -> Result<MetadataField, MetadataError> {
+		if self.kind == MetadataFieldKind::Choice && self.choices.is_empty() {
+			return Err(MetadataError::MissingChoices);
+		}
+
+		let position = library
+			.db
+			.metadata_field()
+			.count(vec![metadata_field::location_id::equals(self.location_id)])
+			.exec()
+			.await? as i32;
+
+		library
+			.db
+			.metadata_field()
+			.create_unchecked(
+				self.location_id,
+				self.name.trim().to_string(),
+				self.kind.int_value(),
+				vec![
+					metadata_field::required::set(self.required),
+					metadata_field::choices::set(match self.kind {
+						MetadataFieldKind::Choice => Some(serde_json::to_string(&self.choices)?),
+						_ => None,
+					}),
+					metadata_field::position::set(position),
+				],
+			)
+			.exec()
+			.await?
+			.try_into()
+	}
+}
+
+/// A field's settings to change, leaving out the ones that stay the same. The kind of a field can't
+/// change, as the values already set might not fit the new one.
+#[derive(Deserialize, Type, Debug)]
+pub struct MetadataFieldUpdateArgs {
+	pub id: i32,
+	pub name: Option<String>,
+	pub required: Option<bool>,
+	pub choices: Option<Vec<String>>,
+	pub position: Option<i32>,
+}
+
+impl MetadataFieldUpdateArgs {
+	pub async fn update(self, library: &LibraryContext) -> Result<MetadataField, MetadataError> {
+		let field = find_field(library, self.id).await?;
+
+		let mut params = vec![];
+		if let Some(name) = self.name {
+			params.push(metadata_field::name::set(name.trim().to_string()));
+		}
+		if let Some(required) = self.required {
+			params.push(metadata_field::required::set(required));
+		}
+		if let Some(choices) = self
+			.choices
+			.filter(|_| field.kind == MetadataFieldKind::Choice)
+		{
+			if choices.is_empty() {
+				return Err(MetadataError::MissingChoices);
+			}
+			params.push(metadata_field::choices::set(Some(serde_json::to_string(
+				&choices,
+			)?)));
+		}
+		if let Some(position) = self.position {
+			params.push(metadata_field::position::set(position));
+		}
+
+		library
+			.db
+			.metadata_field()
+			.update(metadata_field::id::equals(self.id), params)
+			.exec()
+			.await?
+			.try_into()
+	}
+}
+
+/// The values of a field to set on file paths, a value of `None` clears it
+#[derive(Deserialize, Type, Debug)]
+pub struct MetadataFieldValue {
+	pub field_id: i32,
+	pub value: Option<String>,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct SetMetadataArgs {
+	pub location_id: i32,
+	pub file_path_ids: Vec<i32>,
+	pub values: Vec<MetadataFieldValue>,
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct MetadataValue {
+	pub field_id: i32,
+	pub file_path_id: i32,
+	pub value: String,
+}
+
+impl From<metadata_value::Data> for MetadataValue {
+	fn from(data: metadata_value::Data) -> Self {
+		Self {
+			field_id: data.field_id,
+			file_path_id: data.file_path_id,
+			value: data.value,
+		}
+	}
+}
+
+/// How a filter compares the value of a field
+#[derive(Deserialize, Type, Debug)]
+pub enum MetadataFilterOp {
+	Equals(String),
+	Contains(String),
+	AtLeast(String),
+	AtMost(String),
+	IsSet,
+	NotSet,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct MetadataFilter {
+	pub field_id: i32,
+	pub op: MetadataFilterOp,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct MetadataSearchArgs {
+	pub location_id: i32,
+	/// file paths have to match every filter
+	pub filters: Vec<MetadataFilter>,
+}
+
+async fn find_field(library: &LibraryContext, id: i32) -> Result<MetadataField, MetadataError> {
+	library
+		.db
+		.metadata_field()
+		.find_unique(metadata_field::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(MetadataError::FieldNotFound(id))?
+		.try_into()
+}
+
+/// location_fields returns the fields of a location, in the order they're shown
+pub async fn location_fields(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<Vec<MetadataField>, MetadataError> {
+	let mut fields = library
+		.db
+		.metadata_field()
+		.find_many(vec![metadata_field::location_id::equals(location_id)])
+		.exec()
+		.await?
+		.into_iter()
+		.map(MetadataField::try_from)
+		.collect::<Result<Vec<_>, _>>()?;
+	fields.sort_by_key(|field| (field.position, field.id));
+
+	Ok(fields)
+}
+
+/// get_metadata returns the values set on file paths of a location
+pub async fn get_metadata(
+	library: &LibraryContext,
+	location_id: i32,
+	file_path_ids: Vec<i32>,
+) -> Result<Vec<MetadataValue>, MetadataError> {
+	Ok(library
+		.db
+		.metadata_value()
+		.find_many(vec![
+			metadata_value::location_id::equals(location_id),
+			metadata_value::file_path_id::in_vec(file_path_ids),
+		])
+		.exec()
+		.await?
+		.into_iter()
+		.map(Into::into)
+		.collect())
+}
+
+/// set_metadata sets the same values on every file path given, so many files can be edited at once.
+/// Every value is checked before any is written, so a bad value leaves all files as they were.
+pub async fn set_metadata(
+	library: &LibraryContext,
+	args: SetMetadataArgs,
+) -> Result<(), MetadataError> {
+	let fields = location_fields(library, args.location_id)
+		.await?
+		.into_iter()
+		.map(|field| (field.id, field))
+		.collect::<HashMap<_, _>>();
+
+	let mut changes = Vec::with_capacity(args.values.len());
+	for MetadataFieldValue { field_id, value } in args.values {
+		let field = match fields.get(&field_id) {
+			Some(field) => field,
+			None => {
+				find_field(library, field_id).await?;
+				return Err(MetadataError::FieldInOtherLocation(field_id));
+			}
+		};
+
+		let value = match value.as_deref().map(str::trim) {
+			Some("") | None if field.required => {
+				return Err(MetadataError::Required(field.name.clone()))
+			}
+			Some("") | None => None,
+			Some(value) => Some(field.validate(value)?),
+		};
+		changes.push((field_id, value));
+	}
+
+	for (field_id, value) in changes {
+		library
+			.db
+			.metadata_value()
+			.delete_many(vec![
+				metadata_value::field_id::equals(field_id),
+				metadata_value::file_path_id::in_vec(args.file_path_ids.clone()),
+			])
+			.exec()
+			.await?;
+
+		if let Some(value) = value {
+			library
+				.db
+				.metadata_value()
+				.create_many(
+					args.file_path_ids
+						.iter()
+						.map(|&file_path_id| {
+							metadata_value::create_unchecked(
+								field_id,
+								args.location_id,
+								file_path_id,
+								value.clone(),
+								vec![],
+							)
+						})
+						.collect(),
+				)
+				.exec()
+				.await?;
+		}
+	}
+
+	Ok(())
+}
+
+/// ensure_field returns the field of a location with the given name, making it with the given kind
+/// if there's none
+pub async fn ensure_field(
+	library: &LibraryContext,
+	location_id: i32,
+	name: &str,
+	kind: MetadataFieldKind,
+) -> Result<MetadataField, MetadataError> {
+	if let Some(field) = library
+		.db
+		.metadata_field()
+		.find_unique(metadata_field::location_id_name(
+			location_id,
+			name.to_string(),
+		))
+		.exec()
+		.await?
+	{
+		return field.try_into();
+	}
+
+	MetadataFieldCreateArgs {
+		location_id,
+		name: name.to_string(),
+		kind,
+		required: false,
+		choices: vec![],
+	}
+	.create(library)
+	.await
+}
+
+/// set_if_unset sets the value of a field on a file path that doesn't have one yet, returning
+/// whether it did. Values that don't fit the field are skipped.
+pub async fn set_if_unset(
+	library: &LibraryContext,
+	field: &MetadataField,
+	file_path_id: i32,
+	value: &str,
+) -> Result<bool, MetadataError> {
+	let value = match field.validate(value) {
+		Ok(value) if !value.is_empty() => value,
+		_ => return Ok(false),
+	};
+
+	let is_set = library
+		.db
+		.metadata_value()
+		.find_unique(metadata_value::field_id_file_path_id(
+			field.id,
+			file_path_id,
+		))
+		.exec()
+		.await?
+		.is_some();
+	if is_set {
+		return Ok(false);
+	}
+
+	library
+		.db
+		.metadata_value()
+		.create_unchecked(field.id, field.location_id, file_path_id, value, vec![])
+		.exec()
+		.await?;
+
+	Ok(true)
+}
+
+/// matches returns whether a stored value of a field passes a filter
+fn matches(field: &MetadataField, value: &str, op: &MetadataFilterOp) -> bool {
+	match op {
+		MetadataFilterOp::Equals(other) => match field.validate(other) {
+			Ok(other) => field.compare(value, &other) == Some(Ordering::Equal),
+			Err(_) => false,
+		},
+		MetadataFilterOp::Contains(other) => value.to_lowercase().contains(&other.to_lowercase()),
+		MetadataFilterOp::AtLeast(other) => field
+			.validate(other)
+			.ok()
+			.and_then(|other| field.compare(value, &other))
+			.map_or(false, |ordering| ordering != Ordering::Less),
+		MetadataFilterOp::AtMost(other) => field
+			.validate(other)
+			.ok()
+			.and_then(|other| field.compare(value, &other))
+			.map_or(false, |ordering| ordering != Ordering::Greater),
+		MetadataFilterOp::IsSet | MetadataFilterOp::NotSet => true,
+	}
+}
+
+/// search_metadata returns the file paths of a location whose metadata passes every filter
+pub async fn search_metadata(
+	library: &LibraryContext,
+	args: MetadataSearchArgs,
+) -> Result<Vec<file_path::Data>, MetadataError> {
+	let fields = location_fields(library, args.location_id)
+		.await?
+		.into_iter()
+		.map(|field| (field.id, field))
+		.collect::<HashMap<_, _>>();
+
+	let mut included: Option<HashSet<i32>> = None;
+	let mut excluded = HashSet::new();
+	for filter in &args.filters {
+		let field = fields
+			.get(&filter.field_id)
+			.ok_or(MetadataError::FieldInOtherLocation(filter.field_id))?;
+
+		let matching = library
+			.db
+			.metadata_value()
+			.find_many(vec![metadata_value::field_id::equals(field.id)])
+			.exec()
+			.await?
+			.into_iter()
+			.filter(|value| matches(field, &value.value, &filter.op))
+			.map(|value| value.file_path_id)
+			.collect::<HashSet<_>>();
+
+		if let MetadataFilterOp::NotSet = filter.op {
+			excluded.extend(matching);
+		} else {
+			included = Some(match included {
+				Some(included) => included.intersection(&matching).copied().collect(),
+				None => matching,
+			});
+		}
+	}
+
+	let mut params = vec![file_path::location_id::equals(args.location_id)];
+	match included {
+		Some(included) => params.push(file_path::id::in_vec(
+			included.difference(&excluded).copied().collect(),
+		)),
+		None if !excluded.is_empty() => {
+			params.push(file_path::id::not_in_vec(excluded.into_iter().collect()))
+		}
+		None => {}
+	}
+
+	Ok(library
+		.db
+		.file_path()
+		.find_many(params)
+		.with(file_path::object::fetch())
+		.exec()
+		.await?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn field(kind: MetadataFieldKind) -> MetadataField {
+		MetadataField {
+			id: 1,
+			location_id: 1,
+			name: "Invoice #".into(),
+			kind,
+			required: false,
+			choices: vec!["Draft".into(), "Sent".into()],
+			position: 0,
+		}
+	}
+
+	#[test]
+	fn validates_values_by_kind() {
+		assert_eq!(
+			field(MetadataFieldKind::Number)
+				.validate(" 12.50 ")
+				.unwrap(),
+			"12.5"
+		);
+		assert!(field(MetadataFieldKind::Number).validate("twelve").is_err());
+		assert_eq!(
+			field(MetadataFieldKind::Date)
+				.validate("2022-11-29")
+				.unwrap(),
+			"2022-11-29"
+		);
+		assert!(field(MetadataFieldKind::Date)
+			.validate("29/11/2022")
+			.is_err());
+		assert_eq!(
+			field(MetadataFieldKind::Boolean).validate("Yes").unwrap(),
+			"true"
+		);
+		assert_eq!(
+			field(MetadataFieldKind::Choice).validate("sent").unwrap(),
+			"Sent"
+		);
+		assert!(field(MetadataFieldKind::Choice).validate("Paid").is_err());
+	}
+
+	#[test]
+	fn filters_numbers_by_value() {
+		let number = field(MetadataFieldKind::Number);
+		assert!(matches(
+			&number,
+			"9",
+			&MetadataFilterOp::AtMost("10".into())
+		));
+		assert!(!matches(
+			&number,
+			"100",
+			&MetadataFilterOp::AtMost("10".into())
+		));
+		assert!(matches(
+			&number,
+			"10",
+			&MetadataFilterOp::Equals("10.0".into())
+		));
+	}
+}
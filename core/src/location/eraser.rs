@@ -0,0 +1,291 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use tokio::task::block_in_place;
+use tracing::{error, info};
+
+use crate::{
+	api::LibraryEvent,
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	object::preview::remove_previews,
+	prisma::{
+		comment, file_path, indexer_rules_in_location, label_on_object, location, object,
+		object_in_album, object_in_space, tag_on_object, trash_item,
+	},
+	sync::models::{uuid_from_pub_id, LOCATION},
+};
+
+pub const LOCATION_ERASER_JOB_NAME: &str = "location_eraser";
+
+/// how many rows are deleted in a single step
+const BATCH_SIZE: usize = 500;
+
+/// LocationEraserJob removes a location from the library along with everything that only existed because of it:
+/// its file_paths, the objects no other file_path or trashed file points to, and those objects'
+/// previews.
+pub struct LocationEraserJob;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocationEraserJobInit {
+	pub location_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LocationEraserJobState {
+	removed_file_paths: usize,
+	removed_objects: usize,
+	removed_thumbnails: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum LocationEraserJobStep {
+	/// file_paths of the location to delete
+	FilePaths(Vec<i32>),
+	/// objects the location's file_paths pointed to, deleted if nothing else points to them
+	Objects(Vec<i32>),
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for LocationEraserJob {
+	type Init = LocationEraserJobInit;
+	type Data = LocationEraserJobState;
+	type Step = LocationEraserJobStep;
+
+	fn name(&self) -> &'static str {
+		LOCATION_ERASER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		let file_paths = library
+			.db
+			.file_path()
+			.find_many(vec![file_path::location_id::equals(state.init.location_id)])
+			.exec()
+			.await?;
+
+		let object_ids = file_paths
+			.iter()
+			.filter_map(|file_path| file_path.object_id)
+			.collect::<HashSet<_>>()
+			.into_iter()
+			.collect::<Vec<_>>();
+
+		// every file_path is gone by the time the objects are checked
+		state.steps = file_paths
+			.iter()
+			.map(|file_path| file_path.id)
+			.collect::<Vec<_>>()
+			.chunks(BATCH_SIZE)
+			.map(|chunk| LocationEraserJobStep::FilePaths(chunk.to_vec()))
+			.chain(
+				object_ids
+					.chunks(BATCH_SIZE)
+					.map(|chunk| LocationEraserJobStep::Objects(chunk.to_vec())),
+			)
+			.collect();
+
+		info!(
+			"Erasing location {} with {} file paths and {} objects",
+			state.init.location_id,
+			file_paths.len(),
+			object_ids.len()
+		);
+
+		state.data = Some(LocationEraserJobState::default());
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		match &state.steps[0] {
+			LocationEraserJobStep::FilePaths(ids) => {
+				data.removed_file_paths += library
+					.db
+					.file_path()
+					.delete_many(vec![
+						file_path::location_id::equals(state.init.location_id),
+						file_path::id::in_vec(ids.clone()),
+					])
+					.exec()
+					.await? as usize;
+			}
+			LocationEraserJobStep::Objects(ids) => {
+				// objects that are still in another location are kept, as are the ones of trashed
+				// files, to be linked back once they're restored
+				let mut referenced = library
+					.db
+					.file_path()
+					.find_many(vec![file_path::object_id::in_vec(ids.clone())])
+					.exec()
+					.await?
+					.into_iter()
+					.filter_map(|file_path| file_path.object_id)
+					.collect::<HashSet<_>>();
+				referenced.extend(
+					library
+						.db
+						.trash_item()
+						.find_many(vec![trash_item::object_id::in_vec(ids.clone())])
+						.exec()
+						.await?
+						.into_iter()
+						.filter_map(|item| item.object_id),
+				);
+
+				let orphans = ids
+					.iter()
+					.copied()
+					.filter(|id| !referenced.contains(id))
+					.collect::<Vec<_>>();
+
+				if !orphans.is_empty() {
+					let objects = library
+						.db
+						.object()
+						.find_many(vec![object::id::in_vec(orphans.clone())])
+						.exec()
+						.await?;
+
+					// links to the objects aren't cascaded, so they need to go first
+					library
+						.db
+						.tag_on_object()
+						.delete_many(vec![tag_on_object::object_id::in_vec(orphans.clone())])
+						.exec()
+						.await?;
+					library
+						.db
+						.label_on_object()
+						.delete_many(vec![label_on_object::object_id::in_vec(orphans.clone())])
+						.exec()
+						.await?;
+					library
+						.db
+						.object_in_space()
+						.delete_many(vec![object_in_space::object_id::in_vec(orphans.clone())])
+						.exec()
+						.await?;
+					library
+						.db
+						.object_in_album()
+						.delete_many(vec![object_in_album::object_id::in_vec(orphans.clone())])
+						.exec()
+						.await?;
+					library
+						.db
+						.comment()
+						.delete_many(vec![comment::object_id::in_vec(orphans.clone())])
+						.exec()
+						.await?;
+					library
+						.db
+						.object()
+						.delete_many(vec![object::id::in_vec(orphans)])
+						.exec()
+						.await?;
+
+					data.removed_objects += objects.len();
+
+					// previews are shared by the node's libraries, so they're kept while one still
+					// has the content, or while it can't be told
+					let mut unused = Vec::with_capacity(objects.len());
+					if let Some(library_manager) = library.library_manager() {
+						for object in objects {
+							if !library_manager.has_cas_id(&object.cas_id).await? {
+								unused.push(object);
+							}
+						}
+					}
+
+					let data_dir = library.config().data_directory();
+					block_in_place(|| {
+						for object in &unused {
+							if remove_previews(&data_dir, &object.cas_id)?.is_some() {
+								data.removed_thumbnails += 1;
+							}
+						}
+						Ok::<_, std::io::Error>(())
+					})?;
+				}
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		library
+			.db
+			.indexer_rules_in_location()
+			.delete_many(vec![indexer_rules_in_location::location_id::equals(
+				state.init.location_id,
+			)])
+			.exec()
+			.await?;
+
+		let location = library
+			.db
+			.location()
+			.delete(location::id::equals(state.init.location_id))
+			.exec()
+			.await?;
+
+		if let Err(e) = library
+			.sync
+			.write_ops(vec![library
+				.sync
+				.owned_delete(LOCATION, [uuid_from_pub_id(&location.pub_id)])])
+			.await
+		{
+			error!("Error logging erased location for sync: {:#?}", e);
+		}
+
+		invalidate_query!(library, "locations.list");
+		library.emit_event(LibraryEvent::LocationRemoved {
+			location_id: state.init.location_id,
+		});
+		library.statistics.location_removed(state.init.location_id);
+
+		info!(
+			"Location {} erased: {} file paths, {} objects and {} thumbnails removed",
+			state.init.location_id,
+			data.removed_file_paths,
+			data.removed_objects,
+			data.removed_thumbnails
+		);
+
+		Ok(Some(serde_json::to_value(data)?))
+	}
+}
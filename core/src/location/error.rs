@@ -4,6 +4,8 @@ use thiserror::Error;
 use tokio::io;
 use uuid::Uuid;
 
+use super::storage::StorageError;
+
 /// Error type for location related errors
 #[derive(Error, Debug)]
 pub enum LocationError {
@@ -22,6 +24,20 @@ pub enum LocationError {
 	MissingLocalPath(i32),
 	#[error("Location already exists (path: {0:?})")]
 	LocationAlreadyExists(PathBuf),
+	#[error("Location is offline (id: {0})")]
+	Offline(i32),
+	#[error("Location is a read-only catalog (id: {0})")]
+	ReadOnlyCatalog(i32),
+	#[error("Unlock the key manager to store the share's password")]
+	KeyManagerLocked,
+	#[error("Location has no change feed to sync from (id: {0})")]
+	NoChangeFeed(i32),
+	#[error("Sub path must be a directory inside the location (path: {0:?})")]
+	InvalidSubPath(PathBuf),
+	#[error("Couldn't find where the location's files are now (id: {0})")]
+	MovedRootNotFound(i32),
+	#[error("Directory doesn't have the location's files (path: {0:?})")]
+	NotLocationRoot(PathBuf),
 
 	// Internal Errors
 	#[error("Failed to create location (uuid {uuid:?})")]
@@ -38,6 +54,16 @@ pub enum LocationError {
 	FileReadError(io::Error),
 	#[error("Failed to read mounted volumes from local os (error: {0:?})")]
 	VolumeReadError(String),
+	#[error("Failed to mount network share (remote: {0}); (error: {1})")]
+	MountFailure(String, String),
+	#[error("Network share details of location are invalid (id: {0})")]
+	InvalidNetworkShare(i32),
+	#[error("Key manager error (error: {0:?})")]
+	KeyManagerError(#[from] sd_crypto::Error),
+	#[error("Invalid pipeline stages (error: {0})")]
+	PipelineStagesError(#[from] serde_json::Error),
+	#[error("Object storage error (error: {0})")]
+	StorageError(#[from] StorageError),
 	#[error("Failed to connect to database (error: {0:?})")]
 	IOError(io::Error),
 	#[error("Database error (error: {0:?})")]
@@ -53,7 +79,16 @@ impl From<LocationError> for rspc::Error {
 				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
 			}
 
-			LocationError::NotDirectory(_) | LocationError::MissingLocalPath(_) => {
+			LocationError::NotDirectory(_)
+			| LocationError::MissingLocalPath(_)
+			| LocationError::Offline(_)
+			| LocationError::ReadOnlyCatalog(_)
+			| LocationError::KeyManagerLocked
+			| LocationError::NoChangeFeed(_)
+			| LocationError::InvalidSubPath(_)
+			| LocationError::MovedRootNotFound(_)
+			| LocationError::NotLocationRoot(_)
+			| LocationError::MountFailure(_, _) => {
 				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
 			}
 
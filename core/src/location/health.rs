@@ -0,0 +1,347 @@
+//! Health checks of locations: whether their root is still there and whether the files indexed in
+//! them are still where they were, from a sample of them. A location whose files are mostly gone
+//! most likely moved, e.g. its drive was mounted at another path, and it can be repaired by
+//! pointing it at where its files are now instead of reindexing it.
+
+use std::path::{Component, Path, PathBuf};
+
+use prisma_client_rust::raw::Raw;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::task::block_in_place;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+	api::LibraryEvent,
+	invalidate_query,
+	library::LibraryContext,
+	prisma::{file_path, location},
+	util::{db::raw_int, os_path::resolve_materialized_path},
+	volume::get_volumes,
+};
+
+use super::{
+	fetch_location, volume_watcher::volume_params, DotSpacedrive, LocationError, DOTFILE_NAME,
+};
+
+/// How many of the location's files are looked for on disk
+const SAMPLE_SIZE: usize = 100;
+/// The share of the sampled files that can be missing before the location counts as moved
+const DRIFT_RATIO: f64 = 0.5;
+/// The share of the sampled files another directory needs to have to be taken as where the location
+/// moved to, when it has no dotfile saying so
+const MOVED_ROOT_RATIO: f64 = 0.9;
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationHealthStatus {
+	/// the root is there and so are the sampled files
+	Healthy,
+	/// some of the sampled files are gone, which a rescan catches up with
+	Stale,
+	/// the root or most of the sampled files aren't where they were indexed, the location was moved
+	/// or its drive is mounted elsewhere
+	Drifted,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct LocationHealth {
+	pub location_id: i32,
+	pub status: LocationHealthStatus,
+	pub root_exists: bool,
+	/// how many of the location's files were looked for, and how many of them weren't found
+	pub sampled: usize,
+	pub missing: usize,
+	/// where the location's files are now, if they were found elsewhere, see [`repair_location`]
+	pub moved_to: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct SampledFilePath {
+	#[serde(deserialize_with = "raw_int")]
+	id: i64,
+}
+
+/// sample_file_paths picks files of the location at random
+async fn sample_file_paths(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<Vec<file_path::Data>, LocationError> {
+	let sampled: Vec<SampledFilePath> = library
+		.db
+		._query_raw(Raw::new(
+			&format!(
+				"SELECT id FROM file_path WHERE location_id = {location_id} \
+				ORDER BY RANDOM() LIMIT {SAMPLE_SIZE}"
+			),
+			vec![],
+		))
+		.exec()
+		.await?;
+
+	Ok(library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(location_id),
+			file_path::id::in_vec(sampled.into_iter().map(|sample| sample.id as i32).collect()),
+		])
+		.exec()
+		.await?)
+}
+
+/// count_missing counts the sampled files that aren't in `root`
+fn count_missing(root: &Path, samples: &[file_path::Data]) -> usize {
+	samples
+		.iter()
+		.filter(|file_path| {
+			!resolve_materialized_path(
+				root,
+				&file_path.materialized_path,
+				file_path.raw_path.as_deref(),
+			)
+			.exists()
+		})
+		.count()
+}
+
+/// is_location_root tells if `path` has the dotfile of the location with `location_uuid`
+fn is_location_root(path: &Path, location_uuid: Uuid) -> bool {
+	std::fs::read(path.join(DOTFILE_NAME))
+		.ok()
+		.and_then(|dotfile| serde_json::from_slice::<DotSpacedrive>(&dotfile).ok())
+		.map_or(false, |dotfile| dotfile.location_uuid == location_uuid)
+}
+
+/// candidate_roots lists where a location at `root` may be now: under each of the mount points, at
+/// any of the trailing parts of its path, e.g. `/media/drive/photos` under `/mnt/usb` is
+/// `/mnt/usb/drive/photos`, `/mnt/usb/photos` or `/mnt/usb` itself
+fn candidate_roots(root: &Path, mount_points: &[PathBuf]) -> Vec<PathBuf> {
+	let components = root
+		.components()
+		.filter_map(|component| match component {
+			Component::Normal(name) => Some(name),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+	let components = &components;
+
+	mount_points
+		.iter()
+		.flat_map(|mount_point| {
+			(0..=components.len()).map(move |skipped| {
+				components[skipped..]
+					.iter()
+					.fold(mount_point.clone(), |path, name| path.join(name))
+			})
+		})
+		.filter(|candidate| candidate != root)
+		.collect()
+}
+
+/// find_moved_root looks for the directory the location's files are in now, going by its dotfile
+/// or, for locations without one, by where most of the sampled files are
+fn find_moved_root(
+	root: &Path,
+	location_uuid: Uuid,
+	mount_points: &[PathBuf],
+	samples: &[file_path::Data],
+) -> Option<PathBuf> {
+	let candidates = candidate_roots(root, mount_points)
+		.into_iter()
+		.filter(|candidate| candidate.is_dir())
+		.collect::<Vec<_>>();
+
+	if let Some(candidate) = candidates
+		.iter()
+		.find(|candidate| is_location_root(candidate, location_uuid))
+	{
+		return Some(candidate.clone());
+	}
+
+	if samples.is_empty() {
+		return None;
+	}
+
+	candidates
+		.into_iter()
+		.map(|candidate| {
+			let found = samples.len() - count_missing(&candidate, samples);
+			(candidate, found)
+		})
+		.filter(|(_, found)| *found as f64 >= samples.len() as f64 * MOVED_ROOT_RATIO)
+		.max_by_key(|(_, found)| *found)
+		.map(|(candidate, _)| candidate)
+}
+
+/// check_location looks for the location's root and a sample of its files on disk, and for where
+/// they are now when they aren't where they were indexed
+pub async fn check_location(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<LocationHealth, LocationError> {
+	let location = fetch_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	let root = PathBuf::from(
+		location
+			.local_path
+			.as_ref()
+			.ok_or(LocationError::MissingLocalPath(location_id))?,
+	);
+
+	let samples = sample_file_paths(library, location_id).await?;
+
+	block_in_place(|| {
+		let root_exists = root.is_dir();
+		let missing = match root_exists {
+			true => count_missing(&root, &samples),
+			false => samples.len(),
+		};
+
+		let drifted = !root_exists
+			|| (!samples.is_empty() && missing as f64 > samples.len() as f64 * DRIFT_RATIO);
+		let status = match (drifted, missing) {
+			(true, _) => LocationHealthStatus::Drifted,
+			(false, 0) => LocationHealthStatus::Healthy,
+			(false, _) => LocationHealthStatus::Stale,
+		};
+
+		let moved_to = match status {
+			LocationHealthStatus::Drifted => {
+				let mount_points = get_volumes()
+					.map_err(|e| LocationError::VolumeReadError(e.to_string()))?
+					.into_iter()
+					.map(|volume| PathBuf::from(volume.mount_point))
+					.collect::<Vec<_>>();
+				let location_uuid = Uuid::from_slice(&location.pub_id).unwrap_or_default();
+
+				find_moved_root(&root, location_uuid, &mount_points, &samples)
+			}
+			_ => None,
+		};
+
+		Ok(LocationHealth {
+			location_id,
+			status,
+			root_exists,
+			sampled: samples.len(),
+			missing,
+			moved_to,
+		})
+	})
+}
+
+/// repair_location points the location at where its files are now, `new_root` or the one its health
+/// check found, keeping its index as it is. The new root must have the location's dotfile or most
+/// of its sampled files.
+pub async fn repair_location(
+	library: &LibraryContext,
+	location_id: i32,
+	new_root: Option<PathBuf>,
+) -> Result<LocationHealth, LocationError> {
+	let new_root = match new_root {
+		Some(new_root) => new_root,
+		None => check_location(library, location_id)
+			.await?
+			.moved_to
+			.ok_or(LocationError::MovedRootNotFound(location_id))?,
+	};
+
+	let location = fetch_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	if location.local_path.is_none() {
+		return Err(LocationError::MissingLocalPath(location_id));
+	}
+
+	if !new_root.exists() {
+		return Err(LocationError::PathNotFound(new_root));
+	}
+	if !new_root.is_dir() {
+		return Err(LocationError::NotDirectory(new_root));
+	}
+
+	let new_local_path = new_root.to_string_lossy().to_string();
+	if library
+		.db
+		.location()
+		.find_first(vec![
+			location::local_path::equals(Some(new_local_path.clone())),
+			location::id::not(location_id),
+		])
+		.exec()
+		.await?
+		.is_some()
+	{
+		return Err(LocationError::LocationAlreadyExists(new_root));
+	}
+
+	let samples = sample_file_paths(library, location_id).await?;
+	let location_uuid = Uuid::from_slice(&location.pub_id).unwrap_or_default();
+	let is_root = block_in_place(|| {
+		is_location_root(&new_root, location_uuid)
+			|| (!samples.is_empty()
+				&& (samples.len() - count_missing(&new_root, &samples)) as f64
+					>= samples.len() as f64 * MOVED_ROOT_RATIO)
+	});
+	if !is_root {
+		return Err(LocationError::NotLocationRoot(new_root));
+	}
+
+	let volumes =
+		block_in_place(get_volumes).map_err(|e| LocationError::VolumeReadError(e.to_string()))?;
+	let mut params = volume_params(&volumes, &new_root);
+	params.extend([
+		location::local_path::set(Some(new_local_path)),
+		location::is_online::set(true),
+	]);
+
+	library
+		.db
+		.location()
+		.update(location::id::equals(location_id), params)
+		.exec()
+		.await?;
+
+	info!(
+		"Location {} moved from {:?} to {:?}",
+		location_id,
+		location.local_path.unwrap_or_default(),
+		new_root
+	);
+
+	invalidate_query!(library, "locations.list");
+	library.emit_event(LibraryEvent::LocationUpdated { location_id });
+
+	check_location(library, location_id).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lists_candidate_roots_under_mount_points() {
+		let candidates = candidate_roots(
+			Path::new("/media/drive/photos"),
+			&[PathBuf::from("/mnt/usb"), PathBuf::from("/")],
+		);
+
+		assert_eq!(
+			candidates,
+			[
+				"/mnt/usb/media/drive/photos",
+				"/mnt/usb/drive/photos",
+				"/mnt/usb/photos",
+				"/mnt/usb",
+				"/drive/photos",
+				"/photos",
+				"/",
+			]
+			.map(PathBuf::from)
+		);
+	}
+}
@@ -0,0 +1,297 @@
+//! Git repositories found in a location while it's indexed. Their branch, remote and HEAD are read
+//! from the files in `.git` without needing git, which is only run to tell if a repository has
+//! uncommitted changes. What's found is kept in the `git_repository` table, by the materialized
+//! path of the repository's directory.
+
+use std::{
+	ffi::OsStr,
+	fs, io,
+	path::{Component, Path, PathBuf},
+	process::Command,
+};
+
+use chrono::Utc;
+use prisma_client_rust::QueryError;
+use tokio::task::block_in_place;
+use tracing::warn;
+
+use crate::{
+	library::LibraryContext,
+	prisma::{git_repository, location},
+};
+
+/// The directory git keeps a repository's data in, or the file pointing to it for worktrees and
+/// submodules
+pub const GIT_DIR_NAME: &str = ".git";
+
+/// What a git repository was like when its location was indexed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RepositoryInfo {
+	/// the checked out branch, none when HEAD is detached
+	pub branch: Option<String>,
+	pub head_commit: Option<String>,
+	/// the url of the `origin` remote, or of the first remote when there's no origin
+	pub remote_url: Option<String>,
+	/// none when git couldn't be run to tell
+	pub is_dirty: Option<bool>,
+	/// the size of the working tree, without what's in `.git`
+	pub size_in_bytes: u64,
+}
+
+/// is_git_internal tells if a path is inside the `.git` directory of a repository
+pub fn is_git_internal(path: &Path) -> bool {
+	path.components()
+		.any(|component| component == Component::Normal(OsStr::new(GIT_DIR_NAME)))
+}
+
+/// find_repositories returns the directories among `dirs` that are the working tree of a repository
+pub(super) fn find_repositories<'a>(dirs: impl Iterator<Item = &'a Path>) -> Vec<PathBuf> {
+	dirs.filter(|dir| !is_git_internal(dir) && dir.join(GIT_DIR_NAME).exists())
+		.map(Path::to_path_buf)
+		.collect()
+}
+
+/// git_dir returns where a repository keeps its data: its `.git` directory, or where its `.git`
+/// file points to
+fn git_dir(repository: &Path) -> io::Result<PathBuf> {
+	let dot_git = repository.join(GIT_DIR_NAME);
+	if dot_git.is_dir() {
+		return Ok(dot_git);
+	}
+
+	let contents = fs::read_to_string(&dot_git)?;
+	contents
+		.trim()
+		.strip_prefix("gitdir:")
+		.map(|target| repository.join(target.trim()))
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid .git file"))
+}
+
+/// common_dir returns where the refs and config shared by a repository's worktrees are
+fn common_dir(git_dir: &Path) -> PathBuf {
+	match fs::read_to_string(git_dir.join("commondir")) {
+		Ok(common_dir) => git_dir.join(common_dir.trim()),
+		Err(_) => git_dir.to_path_buf(),
+	}
+}
+
+/// resolve_ref returns the commit a ref like `refs/heads/main` points to, from its own file or
+/// from the refs git packed together
+fn resolve_ref(git_dir: &Path, common_dir: &Path, reference: &str) -> Option<String> {
+	for dir in [git_dir, common_dir] {
+		if let Ok(commit) = fs::read_to_string(dir.join(reference)) {
+			return Some(commit.trim().to_string());
+		}
+	}
+
+	fs::read_to_string(common_dir.join("packed-refs"))
+		.ok()?
+		.lines()
+		.filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+		.find_map(|line| match line.split_once(' ') {
+			Some((commit, name)) if name.trim() == reference => Some(commit.to_string()),
+			_ => None,
+		})
+}
+
+/// read_head returns the branch checked out and the commit HEAD is at
+fn read_head(git_dir: &Path, common_dir: &Path) -> io::Result<(Option<String>, Option<String>)> {
+	let head = fs::read_to_string(git_dir.join("HEAD"))?;
+	let head = head.trim();
+
+	Ok(match head.strip_prefix("ref:") {
+		Some(reference) => {
+			let reference = reference.trim();
+			(
+				Some(
+					reference
+						.strip_prefix("refs/heads/")
+						.unwrap_or(reference)
+						.to_string(),
+				),
+				// none for a branch without any commit yet
+				resolve_ref(git_dir, common_dir, reference),
+			)
+		}
+		None => (None, Some(head.to_string())),
+	})
+}
+
+/// remote_url reads the url of the `origin` remote from a repository's config, or of its first
+/// remote when it doesn't have one named `origin`
+fn remote_url(config: &str) -> Option<String> {
+	let mut remotes = vec![];
+	let mut remote = None;
+
+	for line in config.lines().map(str::trim) {
+		if line.starts_with('[') {
+			remote = line
+				.strip_prefix("[remote \"")
+				.and_then(|rest| rest.strip_suffix("\"]"))
+				.map(ToString::to_string);
+		} else if let (Some(name), Some((key, value))) = (&remote, line.split_once('=')) {
+			if key.trim() == "url" {
+				remotes.push((name.clone(), value.trim().to_string()));
+			}
+		}
+	}
+
+	remotes
+		.iter()
+		.find(|(name, _)| name == "origin")
+		.or_else(|| remotes.first())
+		.map(|(_, url)| url.clone())
+}
+
+/// is_dirty asks git if the repository has changes that aren't committed, untracked files included
+fn is_dirty(repository: &Path) -> Option<bool> {
+	let output = Command::new("git")
+		.arg("-C")
+		.arg(repository)
+		.args(["status", "--porcelain", "--ignore-submodules=dirty"])
+		.output()
+		.ok()?;
+
+	output.status.success().then(|| !output.stdout.is_empty())
+}
+
+/// working_tree_size adds up the size of the files in `dir`, leaving out `.git` and without
+/// following symlinks
+fn working_tree_size(dir: &Path) -> io::Result<u64> {
+	let mut size = 0;
+
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		if entry.file_name() == GIT_DIR_NAME {
+			continue;
+		}
+
+		let metadata = entry.metadata()?;
+		if metadata.is_dir() {
+			size += working_tree_size(&entry.path())?;
+		} else if metadata.is_file() {
+			size += metadata.len();
+		}
+	}
+
+	Ok(size)
+}
+
+/// read_repository reads what a repository is like from its working tree at `repository`
+pub fn read_repository(repository: &Path) -> io::Result<RepositoryInfo> {
+	let git_dir = git_dir(repository)?;
+	let common_dir = common_dir(&git_dir);
+	let (branch, head_commit) = read_head(&git_dir, &common_dir)?;
+
+	Ok(RepositoryInfo {
+		branch,
+		head_commit,
+		remote_url: fs::read_to_string(common_dir.join("config"))
+			.ok()
+			.and_then(|config| remote_url(&config)),
+		is_dirty: is_dirty(repository),
+		size_in_bytes: working_tree_size(repository)?,
+	})
+}
+
+/// record_repositories keeps what the repositories of a location, given by the materialized path
+/// of their directory, are like now, and forgets the ones that aren't there anymore
+pub(super) async fn record_repositories(
+	library: &LibraryContext,
+	location_id: i32,
+	location_path: &Path,
+	repositories: &[String],
+) -> Result<(), QueryError> {
+	library
+		.db
+		.git_repository()
+		.delete_many(vec![
+			git_repository::location_id::equals(location_id),
+			git_repository::materialized_path::not_in_vec(repositories.to_vec()),
+		])
+		.exec()
+		.await?;
+
+	for materialized_path in repositories {
+		let path = location_path.join(materialized_path);
+		let info = match block_in_place(|| read_repository(&path)) {
+			Ok(info) => info,
+			Err(e) => {
+				warn!("Failed to read git repository {}: {e}", path.display());
+				continue;
+			}
+		};
+
+		let size_in_bytes = info.size_in_bytes.min(i64::MAX as u64) as i64;
+		let params = || {
+			vec![
+				git_repository::branch::set(info.branch.clone()),
+				git_repository::head_commit::set(info.head_commit.clone()),
+				git_repository::remote_url::set(info.remote_url.clone()),
+				git_repository::is_dirty::set(info.is_dirty),
+				git_repository::date_indexed::set(Utc::now().into()),
+			]
+		};
+
+		library
+			.db
+			.git_repository()
+			.upsert(
+				git_repository::location_id_materialized_path(
+					location_id,
+					materialized_path.clone(),
+				),
+				(
+					location::id::equals(location_id),
+					materialized_path.clone(),
+					size_in_bytes,
+					params(),
+				),
+				params()
+					.into_iter()
+					.chain([git_repository::size_in_bytes::set(size_in_bytes)])
+					.collect(),
+			)
+			.exec()
+			.await?;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reads_repositories() {
+		let dir = tempfile::tempdir().unwrap();
+		let repository = dir.path().join("project");
+		let git_dir = repository.join(GIT_DIR_NAME);
+		fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+		fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+		fs::write(
+			git_dir.join("packed-refs"),
+			"# pack-refs with: peeled\n0123abcd refs/heads/main\n",
+		)
+		.unwrap();
+		fs::write(
+			git_dir.join("config"),
+			"[core]\n\tbare = false\n[remote \"upstream\"]\n\turl = https://example.com/a.git\n\
+			[remote \"origin\"]\n\turl = git@example.com:b.git\n",
+		)
+		.unwrap();
+		fs::write(repository.join("main.rs"), "fn main() {}").unwrap();
+
+		let info = read_repository(&repository).unwrap();
+		assert_eq!(info.branch.as_deref(), Some("main"));
+		assert_eq!(info.head_commit.as_deref(), Some("0123abcd"));
+		assert_eq!(info.remote_url.as_deref(), Some("git@example.com:b.git"));
+		assert_eq!(info.size_in_bytes, 12);
+
+		assert_eq!(
+			find_repositories([dir.path(), repository.as_path(), git_dir.as_path()].into_iter()),
+			vec![repository]
+		);
+	}
+}
@@ -1,30 +1,54 @@
 use crate::{
+	api::LibraryEvent,
 	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::{
+		network::NETWORK_WALK_THROTTLE, settings::LocationSettings, storage::StorageConfig,
+	},
+	node::HookEvent,
+	object::attributes::{read_attributes, FileAttributes},
 	prisma::{file_path, location},
+	sync::{
+		models::{uuid_from_pub_id, FilePathData, FilePathId, FILE_PATH},
+		SyncError,
+	},
+	util::{
+		db::{OnConflict, Upsert},
+		message::Message,
+		os_path::{lossy_name, raw_path},
+		platform_path::{self, normalize_name},
+	},
 };
 
 use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use prisma_client_rust::Direction;
+use prisma_client_rust::{prisma_models::PrismaValue, Direction};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, ffi::OsStr, path::PathBuf, time::Duration};
-use tokio::time::Instant;
-use tracing::info;
+use std::{
+	collections::HashMap,
+	iter,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+use tokio::{task::block_in_place, time::Instant};
+use tracing::{error, info};
 
 use super::{
-	rules::IndexerRule,
-	walk::{walk, WalkEntry},
+	git::{find_repositories, record_repositories},
+	rules::{IndexerRule, RuleKind},
+	walk::{walk, walk_storage, WalkEntry},
+	IndexerError,
 };
 
 /// BATCH_SIZE is the number of files to index at each step, writing the chunk of files metadata in the database.
-const BATCH_SIZE: usize = 1000;
+pub(super) const BATCH_SIZE: usize = 1000;
 pub const INDEXER_JOB_NAME: &str = "indexer";
 
 #[derive(Clone)]
 pub enum ScanProgress {
 	ChunkCount(usize),
 	SavedChunks(usize),
-	Message(String),
+	Message(Message),
 }
 
 /// A `IndexerJob` is a stateful job that walks a directory and indexes all files.
@@ -51,6 +75,10 @@ pub struct IndexerJobData {
 	db_write_start: DateTime<Utc>,
 	scan_read_time: Duration,
 	total_paths: usize,
+	/// materialized paths of the git repositories found, whose details are read once the entries
+	/// are written
+	#[serde(default)]
+	git_repositories: Vec<String>,
 }
 
 /// `IndexerJobStep` is a type alias, specifying that each step of the [`IndexerJob`] is a vector of
@@ -61,11 +89,15 @@ pub type IndexerJobStep = Vec<IndexerJobStepEntry>;
 /// on the `file_path` table in the database
 #[derive(Serialize, Deserialize)]
 pub struct IndexerJobStepEntry {
-	path: PathBuf,
-	created_at: DateTime<Utc>,
-	file_id: i32,
-	parent_id: Option<i32>,
-	is_dir: bool,
+	#[serde(with = "crate::util::os_path::serde_path")]
+	pub(super) path: PathBuf,
+	pub(super) created_at: DateTime<Utc>,
+	pub(super) file_id: i32,
+	pub(super) parent_id: Option<i32>,
+	pub(super) is_dir: bool,
+	/// read just before the entry is written, none for entries in object storage
+	#[serde(default)]
+	pub(super) attributes: Option<FileAttributes>,
 }
 
 impl IndexerJobData {
@@ -99,13 +131,14 @@ impl StatefulJob for IndexerJob {
 		ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> Result<(), JobError> {
+		// locations in object storage don't have a path, their entries are relative to their root
 		let location_path = state
 			.init
 			.location
 			.local_path
 			.as_ref()
 			.map(PathBuf::from)
-			.unwrap();
+			.unwrap_or_default();
 
 		// query db to highers id, so we can increment it for the new files indexed
 		#[derive(Deserialize, Serialize, Debug)]
@@ -126,32 +159,75 @@ impl StatefulJob for IndexerJob {
 			.map(|r| r.id)
 			.unwrap_or(0);
 
-		let mut indexer_rules_by_kind = HashMap::new();
-		for location_rule in &state.init.location.indexer_rules {
-			let indexer_rule = IndexerRule::try_from(&location_rule.indexer_rule)?;
-
-			indexer_rules_by_kind
-				.entry(indexer_rule.kind)
-				.or_insert(vec![])
-				.push(indexer_rule);
-		}
+		let indexer_rules_by_kind = rules_by_kind(&state.init.location)?;
 
 		let scan_start = Instant::now();
 		let inner_ctx = ctx.clone();
-		let paths = walk(
-			location_path.clone(),
-			&indexer_rules_by_kind,
-			move |path, total_entries| {
-				IndexerJobData::on_scan_progress(
-					inner_ctx.clone(),
-					vec![
-						ScanProgress::Message(format!("Scanning {}", path.display())),
-						ScanProgress::ChunkCount(total_entries / BATCH_SIZE),
-					],
-				);
-			},
-		)
-		.await?;
+		let paths = match StorageConfig::parse(state.init.location.storage_config.as_deref())
+			.map_err(IndexerError::from)?
+		{
+			Some(storage) => {
+				ctx.progress(vec![JobReportUpdate::Message(Message::ListingStorage)]);
+				walk_storage(
+					storage
+						.open_in_job(&ctx)
+						.map_err(IndexerError::from)?
+						.as_ref(),
+					&indexer_rules_by_kind,
+				)
+				.await?
+			}
+			None => {
+				walk(
+					location_path.clone(),
+					&indexer_rules_by_kind,
+					// go easy on network shares, where every directory listing is a round trip
+					state
+						.init
+						.location
+						.network_remote
+						.as_ref()
+						.map(|_| NETWORK_WALK_THROTTLE),
+					move |path, total_entries| {
+						IndexerJobData::on_scan_progress(
+							inner_ctx.clone(),
+							vec![
+								ScanProgress::Message(Message::Scanning {
+									path: path.display().to_string(),
+								}),
+								ScanProgress::ChunkCount(total_entries / BATCH_SIZE),
+							],
+						);
+					},
+				)
+				.await?
+			}
+		};
+
+		// network shares are left out, as looking for `.git` in every directory is a round trip each
+		let location = &state.init.location;
+		let git_repositories = if location.local_path.is_some() && location.network_remote.is_none()
+		{
+			block_in_place(|| {
+				find_repositories(
+					iter::once(location_path.as_path()).chain(
+						paths
+							.iter()
+							.filter(|entry| entry.is_dir)
+							.map(|entry| entry.path.as_path()),
+					),
+				)
+			})
+			.into_iter()
+			.filter_map(|path| {
+				path.strip_prefix(&location_path)
+					.ok()
+					.map(|path| normalize_name(&path.to_string_lossy()).into_owned())
+			})
+			.collect()
+		} else {
+			vec![]
+		};
 
 		let total_paths = paths.len();
 		let mut dirs_ids = HashMap::new();
@@ -181,6 +257,7 @@ impl StatefulJob for IndexerJob {
 						file_id,
 						parent_id,
 						is_dir,
+						attributes: None,
 					}
 				},
 			)
@@ -193,6 +270,7 @@ impl StatefulJob for IndexerJob {
 			db_write_start: Utc::now(),
 			scan_read_time: scan_start.elapsed(),
 			total_paths: total_entries,
+			git_repositories,
 		});
 
 		state.steps = paths_entries
@@ -206,11 +284,10 @@ impl StatefulJob for IndexerJob {
 					ctx.clone(),
 					vec![
 						ScanProgress::SavedChunks(i as usize),
-						ScanProgress::Message(format!(
-							"Writing {} of {} to db",
-							i * chunk_steps.len(),
-							total_entries,
-						)),
+						ScanProgress::Message(Message::SavingEntries {
+							saved: i * chunk_steps.len(),
+							total: total_entries,
+						}),
 					],
 				);
 				chunk_steps
@@ -231,56 +308,30 @@ impl StatefulJob for IndexerJob {
 			.as_ref()
 			.expect("critical error: missing data on job state");
 
-		let location_path = &data.location_path;
-		let location_id = state.init.location.id;
+		if state.init.location.local_path.is_some() {
+			block_in_place(|| {
+				for entry in &mut state.steps[0] {
+					entry.attributes = Some(read_attributes(&entry.path));
+				}
+			});
+		}
 
-		let count = ctx
-			.library_ctx()
-			.db
-			.file_path()
-			.create_many(
-				state.steps[0]
-					.iter()
-					.map(|entry| {
-						let name;
-						let extension;
-
-						// if 'entry.path' is a directory, set extension to an empty string to
-						// avoid periods in folder names being interpreted as file extensions
-						if entry.is_dir {
-							extension = "".to_string();
-							name = extract_name(entry.path.file_name());
-						} else {
-							// if the 'entry.path' is not a directory, then get the extension and name.
-							extension = extract_name(entry.path.extension());
-							name = extract_name(entry.path.file_stem());
-						}
-						let materialized_path = entry
-							.path
-							.strip_prefix(location_path)
-							.unwrap()
-							.to_string_lossy()
-							.to_string();
-
-						file_path::create_unchecked(
-							entry.file_id,
-							location_id,
-							materialized_path,
-							name,
-							vec![
-								file_path::is_dir::set(entry.is_dir),
-								file_path::extension::set(Some(extension)),
-								file_path::parent_id::set(entry.parent_id),
-								file_path::date_created::set(entry.created_at.into()),
-							],
-						)
-					})
-					.collect(),
-			)
-			.exec()
-			.await?;
+		let count = write_entries(
+			&ctx.library_ctx(),
+			state.init.location.id,
+			&data.location_path,
+			&state.steps[0],
+		)
+		.await?;
 
 		info!("Inserted {count} records");
+		ctx.library_ctx().emit_event(LibraryEvent::FilesIndexed {
+			location_id: state.init.location.id,
+			count: count as usize,
+		});
+		ctx.library_ctx()
+			.statistics
+			.location_changed(state.init.location.id);
 
 		Ok(())
 	}
@@ -288,16 +339,49 @@ impl StatefulJob for IndexerJob {
 	/// Logs some metadata about the indexer job
 	async fn finalize(
 		&self,
-		_ctx: WorkerContext,
+		ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> JobResult {
 		let data = state
 			.data
 			.as_ref()
 			.expect("critical error: missing data on job state");
+		let library = ctx.library_ctx();
+
+		if let Err(e) = record_repositories(
+			&library,
+			state.init.location.id,
+			&data.location_path,
+			&data.git_repositories,
+		)
+		.await
+		{
+			error!(
+				"Error recording the git repositories of the location: {:#?}",
+				e
+			);
+		}
+
+		library
+			.hooks()
+			.fire(
+				&library,
+				vec![HookEvent::IndexingFinished {
+					location_id: state.init.location.id,
+					location_name: state.init.location.name.clone(),
+					files: data.total_paths,
+				}],
+			)
+			.await;
+
 		info!(
 			"scan of {} completed in {:?}. {:?} files found. db write completed in {:?}",
-			state.init.location.local_path.as_ref().unwrap(),
+			state
+				.init
+				.location
+				.local_path
+				.as_deref()
+				.unwrap_or("object storage"),
 			data.scan_read_time,
 			data.total_paths,
 			(Utc::now() - data.db_write_start)
@@ -309,11 +393,158 @@ impl StatefulJob for IndexerJob {
 	}
 }
 
-/// Extract name from OsStr returned by PathBuff
-fn extract_name(os_string: Option<&OsStr>) -> String {
-	os_string
-		.unwrap_or_default()
-		.to_str()
-		.unwrap_or_default()
-		.to_owned()
+/// rules_by_kind groups the location's indexer rules by their kind, the way [`walk`] applies them,
+/// along with the rule leaving hidden files out when its settings say so
+pub(super) fn rules_by_kind(
+	location: &indexer_job_location::Data,
+) -> Result<HashMap<RuleKind, Vec<IndexerRule>>, IndexerError> {
+	let mut indexer_rules_by_kind = HashMap::new();
+	let hidden_files_rule = LocationSettings::from(location).hidden_files_rule();
+	for indexer_rule in location
+		.indexer_rules
+		.iter()
+		.map(|location_rule| IndexerRule::try_from(&location_rule.indexer_rule))
+		.chain(hidden_files_rule.map(Ok))
+	{
+		let indexer_rule = indexer_rule?;
+
+		indexer_rules_by_kind
+			.entry(indexer_rule.kind)
+			.or_insert(vec![])
+			.push(indexer_rule);
+	}
+
+	Ok(indexer_rules_by_kind)
+}
+
+/// write_entries writes a batch of entries to the `file_path` table, returning how many were created.
+/// Entries already written are skipped, so a step that's run again after failing halfway is fine.
+pub(super) async fn write_entries(
+	library: &LibraryContext,
+	location_id: i32,
+	location_path: &Path,
+	entries: &[IndexerJobStepEntry],
+) -> Result<i64, prisma_client_rust::QueryError> {
+	let count = Upsert::new(
+		"file_path",
+		[
+			"id",
+			"location_id",
+			"materialized_path",
+			"name",
+			"is_dir",
+			"extension",
+			"parent_id",
+			"date_created",
+			"raw_path",
+			"owner_uid",
+			"owner_gid",
+			"permissions",
+			"xattrs",
+		],
+	)
+	.rows(entries.iter().map(|entry| {
+		let name;
+		let extension;
+
+		// if 'entry.path' is a directory, set extension to an empty string to
+		// avoid periods in folder names being interpreted as file extensions
+		if entry.is_dir {
+			extension = "".to_string();
+			name = normalize_name(&lossy_name(entry.path.file_name())).into_owned();
+		} else {
+			// if the 'entry.path' is not a directory, then get the extension and name.
+			extension = normalize_name(&lossy_name(entry.path.extension())).into_owned();
+			name = normalize_name(&lossy_name(entry.path.file_stem())).into_owned();
+		}
+		let relative_path = platform_path::relative_path(&entry.path, location_path).unwrap();
+		let materialized_path = normalize_name(&relative_path.to_string_lossy()).into_owned();
+		let attributes = entry.attributes.clone().unwrap_or_default();
+		let int_or_null = |value: Option<u32>| {
+			value
+				.map(|value| PrismaValue::Int(value as i32 as i64))
+				.unwrap_or(PrismaValue::Null)
+		};
+
+		[
+			PrismaValue::Int(entry.file_id as i64),
+			PrismaValue::Int(location_id as i64),
+			PrismaValue::String(materialized_path),
+			PrismaValue::String(name),
+			PrismaValue::Boolean(entry.is_dir),
+			PrismaValue::String(extension),
+			entry
+				.parent_id
+				.map(|parent_id| PrismaValue::Int(parent_id as i64))
+				.unwrap_or(PrismaValue::Null),
+			PrismaValue::DateTime(entry.created_at.into()),
+			raw_path(&relative_path)
+				.map(PrismaValue::Bytes)
+				.unwrap_or(PrismaValue::Null),
+			int_or_null(attributes.owner_uid),
+			int_or_null(attributes.owner_gid),
+			int_or_null(attributes.permissions),
+			attributes
+				.encoded_xattrs()
+				.map(PrismaValue::Bytes)
+				.unwrap_or(PrismaValue::Null),
+		]
+	}))
+	.on_conflict(OnConflict::DoNothing(&["location_id", "id"]))
+	.exec(&library.db)
+	.await?;
+
+	if let Err(e) = sync_entries(library, location_id, entries).await {
+		error!("Error logging indexed file paths for sync: {:#?}", e);
+	}
+
+	Ok(count)
+}
+
+/// sync_entries logs the file paths just written, so the library's other nodes get them too
+async fn sync_entries(
+	library: &LibraryContext,
+	location_id: i32,
+	entries: &[IndexerJobStepEntry],
+) -> Result<(), SyncError> {
+	let location = match library
+		.db
+		.location()
+		.find_unique(location::id::equals(location_id))
+		.exec()
+		.await?
+	{
+		Some(location) => location,
+		None => return Ok(()),
+	};
+	if !library.sync.is_location_logged(&location.pub_id).await? {
+		return Ok(());
+	}
+	let location_pub_id = uuid_from_pub_id(&location.pub_id);
+
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(location_id),
+			file_path::id::in_vec(entries.iter().map(|entry| entry.file_id).collect()),
+		])
+		.exec()
+		.await?;
+
+	library
+		.sync
+		.write_ops(vec![library.sync.owned_create(
+			FILE_PATH,
+			file_paths.iter().map(|file_path| {
+				(
+					FilePathId {
+						location: location_pub_id,
+						id: file_path.id,
+					},
+					FilePathData::new(file_path, None),
+				)
+			}),
+		)])
+		.await
 }
@@ -1,5 +1,9 @@
+pub mod git;
 pub mod indexer_job;
+pub mod rescan_plan;
 pub mod rules;
+pub mod storage_sync_job;
+pub mod sweep_job;
 mod walk;
 
 use globset::Error;
@@ -11,6 +15,8 @@ use serde_json::Error as SerdeJsonError;
 use std::io;
 use thiserror::Error;
 
+use super::storage::StorageError;
+
 /// Error type for the indexer module
 #[derive(Error, Debug)]
 pub enum IndexerError {
@@ -29,6 +35,8 @@ pub enum IndexerError {
 	DatabaseError(#[from] prisma_client_rust::QueryError),
 	#[error("I/O error: {0}")]
 	IOError(#[from] io::Error),
+	#[error("Object storage error: {0}")]
+	StorageError(#[from] StorageError),
 	#[error("Indexer rule parameters json serialization error: {0}")]
 	RuleParametersSerdeJson(#[from] SerdeJsonError),
 	#[error("Indexer rule parameters encode error: {0}")]
@@ -0,0 +1,224 @@
+use crate::{
+	library::LibraryContext, location::network::NETWORK_WALK_THROTTLE, prisma::file_path,
+	util::os_path::path_from_raw,
+};
+
+use chrono::{DateTime, Utc};
+use rspc::Type;
+use serde::Serialize;
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+use tokio::fs;
+
+use super::{indexer_job::indexer_job_location, walk::walk, IndexerError};
+
+/// How many of the paths in each group of changes are listed in a [`RescanPlan`]
+const PLAN_SAMPLE_SIZE: usize = 50;
+
+/// What a full rescan of a location would change in the index, worked out without writing anything
+#[derive(Serialize, Type, Debug, Default)]
+pub struct RescanPlan {
+	/// on disk but not in the index
+	pub added: PlannedChanges,
+	/// in the index but gone from disk
+	pub removed: PlannedChanges,
+	/// files that changed since they were indexed, or were never identified, and have to be hashed
+	pub rehashed: PlannedChanges,
+	pub unchanged: usize,
+}
+
+#[derive(Serialize, Type, Debug, Default)]
+pub struct PlannedChanges {
+	pub count: usize,
+	/// size of the files, which for added and rehashed files is how much would be read
+	pub bytes: u64,
+	/// the first few paths, relative to the location
+	pub sample: Vec<String>,
+}
+
+impl PlannedChanges {
+	fn push(&mut self, path: &Path, bytes: u64) {
+		self.count += 1;
+		self.bytes += bytes;
+		if self.sample.len() < PLAN_SAMPLE_SIZE {
+			self.sample.push(path.to_string_lossy().to_string());
+		}
+	}
+}
+
+/// A file or directory found on disk, with paths relative to the location
+struct DiskEntry {
+	is_dir: bool,
+	size: u64,
+	modified_at: DateTime<Utc>,
+}
+
+/// A file path in the index, with paths relative to the location
+struct IndexedEntry {
+	path: PathBuf,
+	is_dir: bool,
+	identified: bool,
+	size: u64,
+	indexed_at: DateTime<Utc>,
+}
+
+/// plan_rescan walks a location like the indexer would and compares what it finds with the index.
+/// Only metadata is read, so it's cheap compared to the hashing a rescan may lead to.
+pub async fn plan_rescan(
+	library: &LibraryContext,
+	location: &indexer_job_location::Data,
+	location_path: &Path,
+) -> Result<RescanPlan, IndexerError> {
+	let walked = walk(
+		location_path.to_path_buf(),
+		&super::indexer_job::rules_by_kind(location)?,
+		location
+			.network_remote
+			.as_ref()
+			.map(|_| NETWORK_WALK_THROTTLE),
+		|_, _| {},
+	)
+	.await?;
+
+	let mut on_disk = HashMap::with_capacity(walked.len());
+	for entry in walked {
+		let relative_path = match entry.path.strip_prefix(location_path) {
+			Ok(relative_path) if !relative_path.as_os_str().is_empty() => {
+				relative_path.to_path_buf()
+			}
+			// the root isn't a file path of its own
+			_ => continue,
+		};
+
+		let (size, modified_at) = if entry.is_dir {
+			(0, entry.created_at)
+		} else {
+			let metadata = fs::metadata(&entry.path).await?;
+			(metadata.len(), metadata.modified()?.into())
+		};
+
+		on_disk.insert(
+			relative_path,
+			DiskEntry {
+				is_dir: entry.is_dir,
+				size,
+				modified_at,
+			},
+		);
+	}
+
+	let indexed = library
+		.db
+		.file_path()
+		.find_many(vec![file_path::location_id::equals(location.id)])
+		.with(file_path::object::fetch())
+		.exec()
+		.await?
+		.into_iter()
+		.map(|file_path| {
+			let object = file_path.object().ok().flatten();
+			IndexedEntry {
+				path: file_path.raw_path.as_deref().map_or_else(
+					|| PathBuf::from(&file_path.materialized_path),
+					path_from_raw,
+				),
+				is_dir: file_path.is_dir,
+				identified: object.is_some(),
+				size: object
+					.and_then(|object| object.size_in_bytes.parse().ok())
+					.unwrap_or(0),
+				indexed_at: file_path.date_indexed.into(),
+			}
+		})
+		.collect();
+
+	Ok(compare(on_disk, indexed))
+}
+
+/// compare sorts every entry on disk and in the index into what a rescan would do with it
+fn compare(mut on_disk: HashMap<PathBuf, DiskEntry>, indexed: Vec<IndexedEntry>) -> RescanPlan {
+	let mut plan = RescanPlan::default();
+
+	for entry in indexed {
+		match on_disk.remove(&entry.path) {
+			// replaced by a file of the other kind, which is removed and added back
+			Some(disk) if disk.is_dir != entry.is_dir => {
+				plan.removed.push(&entry.path, entry.size);
+				plan.added.push(&entry.path, disk.size);
+			}
+			Some(disk)
+				if !disk.is_dir && (!entry.identified || disk.modified_at > entry.indexed_at) =>
+			{
+				plan.rehashed.push(&entry.path, disk.size)
+			}
+			Some(_) => plan.unchanged += 1,
+			None => plan.removed.push(&entry.path, entry.size),
+		}
+	}
+
+	let mut added = on_disk.into_iter().collect::<Vec<_>>();
+	added.sort_by(|(a, _), (b, _)| a.cmp(b));
+	for (path, disk) in added {
+		plan.added.push(&path, disk.size);
+	}
+
+	plan
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Duration;
+
+	#[test]
+	fn compares_disk_with_index() {
+		let indexed_at = Utc::now();
+		let file = |size, modified_at| DiskEntry {
+			is_dir: false,
+			size,
+			modified_at,
+		};
+		let indexed = |path: &str, identified| IndexedEntry {
+			path: PathBuf::from(path),
+			is_dir: false,
+			identified,
+			size: 10,
+			indexed_at,
+		};
+
+		let on_disk = HashMap::from([
+			(
+				PathBuf::from("same.txt"),
+				file(10, indexed_at - Duration::days(1)),
+			),
+			(
+				PathBuf::from("edited.txt"),
+				file(20, indexed_at + Duration::days(1)),
+			),
+			(
+				PathBuf::from("pending.txt"),
+				file(30, indexed_at - Duration::days(1)),
+			),
+			(PathBuf::from("new.txt"), file(40, indexed_at)),
+		]);
+
+		let plan = compare(
+			on_disk,
+			vec![
+				indexed("same.txt", true),
+				indexed("edited.txt", true),
+				indexed("pending.txt", false),
+				indexed("gone.txt", true),
+			],
+		);
+
+		assert_eq!(plan.unchanged, 1);
+		assert_eq!(plan.added.sample, vec!["new.txt"]);
+		assert_eq!(plan.added.bytes, 40);
+		assert_eq!(plan.removed.sample, vec!["gone.txt"]);
+		assert_eq!(plan.rehashed.count, 2);
+		assert_eq!(plan.rehashed.bytes, 50);
+	}
+}
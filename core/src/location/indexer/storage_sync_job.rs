@@ -0,0 +1,443 @@
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::{
+		storage::{is_relative_key, StorageChanges, StorageConfig, StorageEntry},
+		tombstone::remove_vanished,
+		LocationError,
+	},
+	object::versions::{changed_since, unlink_changed},
+	prisma::{file_path, location},
+	util::message::Message,
+};
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::Direction;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+	collections::{HashMap, HashSet},
+	path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+use super::{
+	indexer_job::{
+		indexer_job_location, rules_by_kind, write_entries, IndexerJobStepEntry, BATCH_SIZE,
+	},
+	walk::accepts_storage_path,
+	IndexerError,
+};
+
+pub const STORAGE_SYNC_JOB_NAME: &str = "storage_sync";
+
+/// A `StorageSyncJob` applies the change feed of a cloud drive location to its index, so files
+/// added, modified or removed in the drive show up without listing the whole drive again.
+/// When the feed can't be applied path by path, or a `full` sync is asked for, the whole drive is
+/// listed and compared with the index instead.
+pub struct StorageSyncJob;
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageSyncJobInit {
+	pub location: indexer_job_location::Data,
+	pub full: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct StorageSyncJobData {
+	/// where the feed is read up to once the steps are applied
+	cursor: String,
+	written: usize,
+	removed: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StorageSyncJobStep {
+	/// files or directories to remove from the index, along with everything in them
+	Remove(Vec<String>),
+	/// entries to add to the index, or to identify again when they're already in it
+	Write(Vec<SyncedEntry>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SyncedEntry {
+	path: String,
+	is_dir: bool,
+	modified: DateTime<Utc>,
+}
+
+impl From<StorageEntry> for SyncedEntry {
+	fn from(entry: StorageEntry) -> Self {
+		Self {
+			path: entry.path,
+			is_dir: false,
+			modified: entry.modified,
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for StorageSyncJob {
+	type Init = StorageSyncJobInit;
+	type Data = StorageSyncJobData;
+	type Step = StorageSyncJobStep;
+
+	fn name(&self) -> &'static str {
+		STORAGE_SYNC_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let location = &state.init.location;
+
+		let cursor = location
+			.storage_cursor
+			.as_ref()
+			.ok_or(LocationError::NoChangeFeed(location.id))?;
+		let storage = StorageConfig::parse(location.storage_config.as_deref())
+			.map_err(IndexerError::from)?
+			.ok_or(LocationError::NoChangeFeed(location.id))?
+			.open_in_job(&ctx)
+			.map_err(IndexerError::from)?;
+
+		ctx.progress(vec![JobReportUpdate::Message(
+			Message::ReadingStorageChanges,
+		)]);
+
+		let mut changes = storage.changes(cursor).await.map_err(IndexerError::from)?;
+
+		if state.init.full || changes.rescan {
+			ctx.progress(vec![JobReportUpdate::Message(Message::ListingStorage)]);
+
+			// the cursor is the one from before the listing, so changes made while listing are read again
+			// next time rather than missed
+			changes = reconcile(
+				&library,
+				location.id,
+				storage.list().await.map_err(IndexerError::from)?,
+				changes.cursor,
+			)
+			.await?;
+		}
+
+		// paths leading out of the location would be followed once their files are downloaded
+		for path in changes
+			.upserted
+			.iter()
+			.map(|entry| &entry.path)
+			.chain(&changes.directories)
+			.filter(|path| !is_relative_key(path))
+		{
+			warn!("Skipping '{path}', it leads out of the location");
+		}
+		changes.directories.retain(|path| is_relative_key(path));
+
+		let rules = rules_by_kind(location)?;
+		let mut upserted = Vec::with_capacity(changes.upserted.len());
+		for entry in changes.upserted {
+			if is_relative_key(&entry.path)
+				&& accepts_storage_path(Path::new(&entry.path), &rules).await
+			{
+				upserted.push(SyncedEntry::from(entry));
+			}
+		}
+
+		info!(
+			"Syncing location {}: {} entries to write, {} to remove",
+			location.id,
+			upserted.len() + changes.directories.len(),
+			changes.removed.len()
+		);
+
+		let mut steps = changes
+			.removed
+			.chunks(BATCH_SIZE)
+			.map(|chunk| StorageSyncJobStep::Remove(chunk.to_vec()))
+			.collect::<Vec<_>>();
+
+		// directories go first, so the files in them find them in the index
+		let entries = changes
+			.directories
+			.into_iter()
+			.map(|path| SyncedEntry {
+				path,
+				is_dir: true,
+				modified: Utc::now(),
+			})
+			.chain(upserted)
+			.collect::<Vec<_>>();
+		let mut entries = entries.into_iter().peekable();
+		while entries.peek().is_some() {
+			steps.push(StorageSyncJobStep::Write(
+				entries.by_ref().take(BATCH_SIZE).collect(),
+			));
+		}
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(steps.len())]);
+
+		state.data = Some(StorageSyncJobData {
+			cursor: changes.cursor,
+			..Default::default()
+		});
+		state.steps = steps.into();
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let location_id = state.init.location.id;
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		match &state.steps[0] {
+			StorageSyncJobStep::Remove(paths) => {
+				for path in paths {
+					remove_vanished(&library, location_id, path).await?;
+				}
+				data.removed += paths.len();
+			}
+			StorageSyncJobStep::Write(entries) => {
+				let mut writer = EntryWriter::new(&library, location_id).await?;
+				for entry in entries {
+					writer.write(entry).await?;
+				}
+				data.written += entries.len();
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		// only moved along once everything is applied, so a failed sync reads the same changes again
+		library
+			.db
+			.location()
+			.update(
+				location::id::equals(state.init.location.id),
+				vec![location::storage_cursor::set(Some(data.cursor.clone()))],
+			)
+			.exec()
+			.await?;
+
+		info!(
+			"Sync of location {} completed, {} entries written and {} removed",
+			state.init.location.id, data.written, data.removed
+		);
+
+		if data.written > 0 || data.removed > 0 {
+			invalidate_query!(library, "locations.getExplorerData");
+			library.statistics.location_changed(state.init.location.id);
+		}
+
+		Ok(Some(json!({
+			"location_id": state.init.location.id,
+			"full": state.init.full,
+			"written": data.written,
+			"removed": data.removed,
+		})))
+	}
+}
+
+/// reconcile compares a full listing of the storage with the index, turning it into the changes
+/// that bring the index up to date with it
+async fn reconcile(
+	library: &LibraryContext,
+	location_id: i32,
+	listing: Vec<StorageEntry>,
+	cursor: String,
+) -> Result<StorageChanges, JobError> {
+	let indexed = library
+		.db
+		.file_path()
+		.find_many(vec![file_path::location_id::equals(location_id)])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|file_path| (file_path.materialized_path.clone(), file_path))
+		.collect::<HashMap<_, _>>();
+
+	let mut listed_dirs = HashSet::from([String::new()]);
+	let mut listed = HashSet::with_capacity(listing.len());
+	let mut changes = StorageChanges {
+		cursor,
+		..Default::default()
+	};
+
+	for entry in listing {
+		for ancestor in Path::new(&entry.path).ancestors().skip(1) {
+			listed_dirs.insert(ancestor.to_string_lossy().to_string());
+		}
+		listed.insert(entry.path.clone());
+
+		let changed = match indexed.get(&entry.path) {
+			Some(file_path) => changed_since(file_path, entry.modified),
+			None => true,
+		};
+		if changed {
+			changes.upserted.push(entry);
+		}
+	}
+
+	changes.removed = indexed
+		.into_values()
+		.filter(|file_path| {
+			if file_path.is_dir {
+				!listed_dirs.contains(&file_path.materialized_path)
+			} else {
+				!listed.contains(&file_path.materialized_path)
+			}
+		})
+		.map(|file_path| file_path.materialized_path)
+		.collect();
+
+	Ok(changes)
+}
+
+/// EntryWriter adds entries to the index along with the directories they're in, giving them ids
+/// after the highest one in the location
+struct EntryWriter<'a> {
+	library: &'a LibraryContext,
+	location_id: i32,
+	next_id: i32,
+	dirs_ids: HashMap<PathBuf, i32>,
+}
+
+impl<'a> EntryWriter<'a> {
+	async fn new(
+		library: &'a LibraryContext,
+		location_id: i32,
+	) -> Result<EntryWriter<'a>, prisma_client_rust::QueryError> {
+		let next_id = library
+			.db
+			.file_path()
+			.find_first(vec![file_path::location_id::equals(location_id)])
+			.order_by(file_path::id::order(Direction::Desc))
+			.exec()
+			.await?
+			.map(|file_path| file_path.id + 1)
+			.unwrap_or(0);
+
+		Ok(Self {
+			library,
+			location_id,
+			next_id,
+			dirs_ids: HashMap::new(),
+		})
+	}
+
+	async fn find(
+		&self,
+		path: &Path,
+	) -> Result<Option<file_path::Data>, prisma_client_rust::QueryError> {
+		self.library
+			.db
+			.file_path()
+			.find_first(vec![
+				file_path::location_id::equals(self.location_id),
+				file_path::materialized_path::equals(path.to_string_lossy().to_string()),
+			])
+			.exec()
+			.await
+	}
+
+	async fn create(
+		&mut self,
+		path: &Path,
+		is_dir: bool,
+		created_at: DateTime<Utc>,
+		parent_id: Option<i32>,
+	) -> Result<i32, prisma_client_rust::QueryError> {
+		let file_id = self.next_id;
+		self.next_id += 1;
+
+		write_entries(
+			self.library,
+			self.location_id,
+			Path::new(""),
+			&[IndexerJobStepEntry {
+				path: path.to_path_buf(),
+				created_at,
+				file_id,
+				parent_id,
+				is_dir,
+				attributes: None,
+			}],
+		)
+		.await?;
+
+		Ok(file_id)
+	}
+
+	/// dir_id finds the directory in the index, adding it and any of its parents that are missing
+	async fn dir_id(&mut self, dir: &Path) -> Result<i32, prisma_client_rust::QueryError> {
+		let mut parent_id = None;
+
+		// from the root down, `ancestors` starts with the directory itself
+		for ancestor in dir.ancestors().collect::<Vec<_>>().into_iter().rev() {
+			let id = match self.dirs_ids.get(ancestor) {
+				Some(id) => *id,
+				None => {
+					let id = match self.find(ancestor).await? {
+						Some(file_path) => file_path.id,
+						None => self.create(ancestor, true, Utc::now(), parent_id).await?,
+					};
+					self.dirs_ids.insert(ancestor.to_path_buf(), id);
+					id
+				}
+			};
+			parent_id = Some(id);
+		}
+
+		Ok(parent_id.expect("a path has at least one ancestor, itself"))
+	}
+
+	async fn write(&mut self, entry: &SyncedEntry) -> Result<(), prisma_client_rust::QueryError> {
+		let path = Path::new(&entry.path);
+
+		if entry.is_dir {
+			self.dir_id(path).await?;
+			return Ok(());
+		}
+
+		match self.find(path).await? {
+			// the content changed, so the file is unlinked from its object to be identified again
+			Some(file_path) => {
+				unlink_changed(self.library, &file_path, entry.modified.into()).await?;
+			}
+			None => {
+				let parent_id = self.dir_id(path.parent().unwrap_or(Path::new(""))).await?;
+				self.create(path, false, entry.modified, Some(parent_id))
+					.await?;
+			}
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,426 @@
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	location::{network::NETWORK_WALK_THROTTLE, tombstone::remove_vanished, LocationError},
+	object::{
+		attributes::read_attributes,
+		versions::{changed_since, prune_copies, unlink_changed},
+	},
+	prisma::file_path,
+	util::{
+		message::Message,
+		os_path::{long_path, path_from_raw, raw_path},
+		platform_path::{self, normalize_name, PathKey},
+	},
+};
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::Direction;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+	collections::{HashMap, HashSet},
+	fs, io,
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+use tokio::task::block_in_place;
+use tracing::{debug, error, info};
+
+use super::{
+	indexer_job::{
+		indexer_job_location, rules_by_kind, write_entries, IndexerJobStepEntry, BATCH_SIZE,
+	},
+	walk::{walk_only, WalkEntry},
+};
+
+pub const SWEEP_JOB_NAME: &str = "location_sweeper";
+
+/// A `SweepJob` catches the index of a location up with what changed while the node wasn't running,
+/// without walking every file again like the [`IndexerJob`](super::indexer_job::IndexerJob) does.
+/// Adding, removing or renaming an entry updates the modification time of its directory, so only
+/// the directories modified since then are compared with the index. Files of those directories whose
+/// content changed since they were indexed are unlinked from their objects to be identified again,
+/// keeping what they had as a version. Files changed in place leave their directory alone, those are
+/// picked up by the next full scan. A sweep can be limited to a directory of the location, which is
+/// how a single directory is rescanned, comparing all of it with the index.
+pub struct SweepJob;
+
+#[derive(Serialize, Deserialize)]
+pub struct SweepJobInit {
+	pub location: indexer_job_location::Data,
+	/// when the node was last known to be running
+	pub since: DateTime<Utc>,
+	/// the directory of the location to sweep, relative to it, the whole location when not given
+	#[serde(default)]
+	pub sub_path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SweepJobData {
+	location_path: PathBuf,
+	added: usize,
+	removed: usize,
+	#[serde(default)]
+	changed: usize,
+}
+
+/// Each step compares a directory modified since the node last ran with its entries in the index
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SweepJobStep(#[serde(with = "crate::util::os_path::serde_path")] PathBuf);
+
+#[async_trait::async_trait]
+impl StatefulJob for SweepJob {
+	type Init = SweepJobInit;
+	type Data = SweepJobData;
+	type Step = SweepJobStep;
+
+	fn name(&self) -> &'static str {
+		SWEEP_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let location_path = state
+			.init
+			.location
+			.local_path
+			.as_ref()
+			.map(PathBuf::from)
+			.ok_or(LocationError::MissingLocalPath(state.init.location.id))?;
+
+		let root = match &state.init.sub_path {
+			Some(sub_path) => location_path.join(sub_path),
+			None => location_path.clone(),
+		};
+
+		let since = SystemTime::from(state.init.since);
+		let mut modified = block_in_place(|| modified_dirs(&root, since));
+
+		// a sub directory that isn't indexed yet, or is gone, is compared by the step of its parent
+		if let Some(sub_path) = &state.init.sub_path {
+			let is_indexed = ctx
+				.library_ctx()
+				.db
+				.file_path()
+				.find_first(vec![
+					file_path::location_id::equals(state.init.location.id),
+					file_path::materialized_path::equals(
+						normalize_name(&sub_path.to_string_lossy()).into_owned(),
+					),
+					file_path::is_dir::equals(true),
+				])
+				.exec()
+				.await?
+				.is_some();
+			if !is_indexed || !root.is_dir() {
+				if let Some(parent) = root
+					.parent()
+					.filter(|parent| parent.starts_with(&location_path))
+				{
+					modified.insert(0, parent.to_path_buf());
+				}
+			}
+		}
+
+		info!(
+			"Found {} directories modified in {} since {}",
+			modified.len(),
+			root.display(),
+			state.init.since
+		);
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(modified.len())]);
+
+		state.data = Some(SweepJobData {
+			location_path,
+			..Default::default()
+		});
+		state.steps = modified.into_iter().map(SweepJobStep).collect();
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let location_id = state.init.location.id;
+		let dir = &state.steps[0].0;
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		let relative_path = platform_path::relative_path(dir, &data.location_path).unwrap();
+		let path_param = match raw_path(&relative_path) {
+			Some(raw_path) => file_path::raw_path::equals(Some(raw_path)),
+			None => file_path::materialized_path::equals(
+				normalize_name(&relative_path.to_string_lossy()).into_owned(),
+			),
+		};
+
+		ctx.progress(vec![JobReportUpdate::Message(Message::CheckingDirectory {
+			path: dir.display().to_string(),
+		})]);
+
+		let dir_file_path = match library
+			.db
+			.file_path()
+			.find_first(vec![
+				file_path::location_id::equals(location_id),
+				path_param,
+				file_path::is_dir::equals(true),
+			])
+			.exec()
+			.await?
+		{
+			Some(dir_file_path) => dir_file_path,
+			// new directories are indexed along with everything in them by the step of their parent
+			None => {
+				ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+					state.step_number + 1,
+				)]);
+				return Ok(());
+			}
+		};
+
+		let indexed = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(location_id),
+				file_path::parent_id::equals(Some(dir_file_path.id)),
+			])
+			.exec()
+			.await?;
+
+		// matched by key, so an entry read back in another casing or normalization than it was indexed
+		// with isn't taken for a removed entry and a new one
+		let on_disk = block_in_place(|| {
+			fs::read_dir(long_path(dir.clone()))?
+				.map(|entry| {
+					entry.map(|entry| {
+						let path = dir.join(entry.file_name());
+						(PathKey::new(&path), path)
+					})
+				})
+				.collect::<Result<HashMap<_, _>, io::Error>>()
+		})?;
+
+		let mut indexed_keys = HashSet::with_capacity(indexed.len());
+		for file_path in &indexed {
+			let path = data
+				.location_path
+				.join(file_path.raw_path.as_deref().map_or_else(
+					|| PathBuf::from(&file_path.materialized_path),
+					path_from_raw,
+				));
+			let key = PathKey::new(&path);
+			if !on_disk.contains_key(&key) {
+				debug!("{} is gone, leaving a tombstone for it", path.display());
+				remove_vanished(&library, location_id, &file_path.materialized_path).await?;
+				data.removed += 1;
+			} else if !file_path.is_dir {
+				let modified: DateTime<Utc> =
+					block_in_place(|| fs::metadata(long_path(path.clone()))?.modified())?.into();
+				if changed_since(file_path, modified) {
+					debug!("{} changed, identifying it again", path.display());
+					unlink_changed(&library, file_path, modified.into()).await?;
+					data.changed += 1;
+				}
+			}
+			indexed_keys.insert(key);
+		}
+
+		let added = on_disk
+			.into_iter()
+			.filter(|(key, _)| !indexed_keys.contains(key))
+			.map(|(_, path)| path)
+			.collect::<HashSet<_>>();
+
+		if !added.is_empty() {
+			// only the new entries are walked, the rest of the directory is already in the index
+			let walked = walk_only(
+				dir.clone(),
+				Some(&added),
+				&rules_by_kind(&state.init.location)?,
+				state
+					.init
+					.location
+					.network_remote
+					.as_ref()
+					.map(|_| NETWORK_WALK_THROTTLE),
+				|_, _| {},
+			)
+			.await?;
+
+			let first_file_id = library
+				.db
+				.file_path()
+				.find_first(vec![file_path::location_id::equals(location_id)])
+				.order_by(file_path::id::order(Direction::Desc))
+				.exec()
+				.await?
+				.map(|file_path| file_path.id + 1)
+				.unwrap_or(0);
+
+			let mut dirs_ids = HashMap::from([(dir.clone(), dir_file_path.id)]);
+			let entries = walked
+				.into_iter()
+				.filter(|entry| entry.path != *dir)
+				.zip(first_file_id..)
+				.map(
+					|(
+						WalkEntry {
+							path,
+							is_dir,
+							created_at,
+						},
+						file_id,
+					)| {
+						let parent_id = path
+							.parent()
+							.and_then(|parent_dir| dirs_ids.get(parent_dir).copied());
+
+						if is_dir {
+							dirs_ids.insert(path.clone(), file_id);
+						}
+
+						IndexerJobStepEntry {
+							attributes: Some(block_in_place(|| read_attributes(&path))),
+							path,
+							created_at,
+							file_id,
+							parent_id,
+							is_dir,
+						}
+					},
+				)
+				.collect::<Vec<_>>();
+
+			for chunk in entries.chunks(BATCH_SIZE) {
+				data.added += write_entries(&library, location_id, &data.location_path, chunk)
+					.await? as usize;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		info!(
+			"Sweep of {} completed, {} entries added, {} changed and {} removed",
+			data.location_path.display(),
+			data.added,
+			data.changed,
+			data.removed
+		);
+
+		if data.added > 0 || data.changed > 0 || data.removed > 0 {
+			invalidate_query!(ctx.library_ctx(), "locations.getExplorerData");
+			ctx.library_ctx()
+				.statistics
+				.location_changed(state.init.location.id);
+		}
+
+		// the copies of versions of removed files aren't needed anymore
+		if data.removed > 0 {
+			if let Err(e) = prune_copies(&ctx.library_ctx()).await {
+				error!("Error pruning copies of file versions: {:#?}", e);
+			}
+		}
+
+		Ok(Some(json!({
+			"location_id": state.init.location.id,
+			"since": state.init.since,
+			"added": data.added,
+			"changed": data.changed,
+			"removed": data.removed,
+		})))
+	}
+}
+
+/// modified_dirs returns the directories under `root`, itself included, modified after `since`,
+/// parents first. Only directories are looked at, which is much cheaper than reading the metadata
+/// of every file.
+fn modified_dirs(root: &Path, since: SystemTime) -> Vec<PathBuf> {
+	let mut modified = vec![];
+	let mut to_walk = vec![root.to_path_buf()];
+
+	while let Some(dir) = to_walk.pop() {
+		match fs::metadata(&dir).and_then(|metadata| metadata.modified()) {
+			Ok(modified_at) if modified_at > since => modified.push(dir.clone()),
+			Ok(_) => {}
+			Err(e) => {
+				error!("Error reading metadata of {}: {:#?}", dir.display(), e);
+				continue;
+			}
+		}
+
+		let read_dir = match fs::read_dir(&dir) {
+			Ok(read_dir) => read_dir,
+			Err(e) => {
+				error!("Error reading directory {}: {:#?}", dir.display(), e);
+				continue;
+			}
+		};
+
+		// the file type doesn't follow symlinks, which the indexer ignores as well
+		to_walk.extend(
+			read_dir
+				.flatten()
+				.filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+				.map(|entry| entry.path()),
+		);
+	}
+
+	modified.sort();
+	modified
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+	use tempfile::tempdir;
+
+	#[test]
+	fn finds_modified_dirs() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+
+		fs::create_dir_all(root_path.join("old/inner")).unwrap();
+		fs::create_dir(root_path.join("new")).unwrap();
+
+		let since = SystemTime::now();
+		std::thread::sleep(Duration::from_millis(20));
+
+		fs::File::create(root_path.join("old/inner/file.txt")).unwrap();
+		fs::File::create(root_path.join("new/file.txt")).unwrap();
+
+		assert_eq!(
+			modified_dirs(root_path, since),
+			vec![root_path.join("new"), root_path.join("old/inner")]
+		);
+	}
+}
@@ -1,12 +1,20 @@
 use chrono::{DateTime, Utc};
 use std::{
 	cmp::Ordering,
-	collections::{HashMap, VecDeque},
+	collections::{HashMap, HashSet, VecDeque},
+	fs::Metadata,
 	hash::{Hash, Hasher},
+	io,
 	path::{Path, PathBuf},
+	time::Duration,
+};
+use tokio::{fs, task::spawn_blocking, time::sleep};
+use tracing::{debug, error, warn};
+
+use crate::{
+	location::storage::{is_relative_key, Storage},
+	util::os_path::long_path,
 };
-use tokio::fs;
-use tracing::{debug, error};
 
 use super::{
 	rules::{IndexerRule, RuleKind},
@@ -48,12 +56,39 @@ impl Ord for WalkEntry {
 	}
 }
 
+/// read_dir_entries lists a directory with the metadata of its entries, in one blocking call instead
+/// of a task per entry, which adds up in directories of many tiny files. The directory is read as a
+/// long path on Windows, but entries keep the form of `path`.
+async fn read_dir_entries(
+	path: PathBuf,
+) -> io::Result<Vec<io::Result<(PathBuf, io::Result<Metadata>)>>> {
+	spawn_blocking(move || {
+		Ok(std::fs::read_dir(long_path(path.clone()))?
+			.map(|entry| entry.map(|entry| (path.join(entry.file_name()), entry.metadata())))
+			.collect())
+	})
+	.await?
+}
+
 /// This function walks through the filesystem, applying the rules to each entry and then returning
 /// a list of accepted entries. There are some useful comments in the implementation of this function
 /// in case of doubts.
 pub(super) async fn walk(
 	root: PathBuf,
 	rules_per_kind: &HashMap<RuleKind, Vec<IndexerRule>>,
+	throttle: Option<Duration>,
+	update_notifier: impl Fn(&Path, usize),
+) -> Result<Vec<WalkEntry>, IndexerError> {
+	walk_only(root, None, rules_per_kind, throttle, update_notifier).await
+}
+
+/// Same as [`walk`], but when `only` is given, the root's entries not in it are skipped along with
+/// everything under them. The root itself is still part of the result.
+pub(super) async fn walk_only(
+	root: PathBuf,
+	only: Option<&HashSet<PathBuf>>,
+	rules_per_kind: &HashMap<RuleKind, Vec<IndexerRule>>,
+	throttle: Option<Duration>,
 	update_notifier: impl Fn(&Path, usize),
 ) -> Result<Vec<WalkEntry>, IndexerError> {
 	let mut to_walk = VecDeque::with_capacity(1);
@@ -61,8 +96,12 @@ pub(super) async fn walk(
 	let mut indexed_paths = HashMap::new();
 
 	while let Some((current_path, parent_dir_accepted_by_its_children)) = to_walk.pop_front() {
-		let mut read_dir = match fs::read_dir(&current_path).await {
-			Ok(read_dir) => read_dir,
+		if let Some(throttle) = throttle {
+			sleep(throttle).await;
+		}
+
+		let entries = match read_dir_entries(current_path.clone()).await {
+			Ok(entries) => entries,
 			Err(e) => {
 				error!(
 					"Error reading directory {}: {:#?}",
@@ -74,10 +113,9 @@ pub(super) async fn walk(
 		};
 
 		// Marking with a loop label here in case of rejection or erros, to continue with next entry
-		'entries: loop {
-			let entry = match read_dir.next_entry().await {
-				Ok(Some(entry)) => entry,
-				Ok(None) => break,
+		'entries: for entry in entries {
+			let (entry_path, metadata) = match entry {
+				Ok(entry) => entry,
 				Err(e) => {
 					error!(
 						"Error reading entry in {}: {:#?}",
@@ -88,6 +126,12 @@ pub(super) async fn walk(
 				}
 			};
 
+			if let Some(only) = only {
+				if current_path == root && !only.contains(&entry_path) {
+					continue;
+				}
+			}
+
 			// Accept by children has three states,
 			// None if we don't now yet or if this check doesn't apply
 			// Some(true) if this check applies and it passes
@@ -95,7 +139,7 @@ pub(super) async fn walk(
 			// and we pass the current parent state to its children
 			let mut accept_by_children_dir = parent_dir_accepted_by_its_children;
 
-			let current_path = entry.path();
+			let current_path = entry_path;
 
 			update_notifier(&current_path, indexed_paths.len());
 
@@ -118,7 +162,7 @@ pub(super) async fn walk(
 				}
 			}
 
-			let metadata = entry.metadata().await?;
+			let metadata = metadata?;
 
 			// TODO: Hard ignoring symlinks for now, but this should be configurable
 			if metadata.is_symlink() {
@@ -190,7 +234,7 @@ pub(super) async fn walk(
 				}
 
 				// Then we mark this directory the be walked in too
-				to_walk.push_back((entry.path(), accept_by_children_dir));
+				to_walk.push_back((current_path.clone(), accept_by_children_dir));
 			}
 
 			let mut accept_by_glob = false;
@@ -271,6 +315,94 @@ pub(super) async fn walk(
 	Ok(indexed_paths)
 }
 
+/// accepts_storage_path applies the glob rules to a file in object storage, the only rules that
+/// can be applied without the file being on disk
+pub(super) async fn accepts_storage_path(
+	path: &Path,
+	rules_per_kind: &HashMap<RuleKind, Vec<IndexerRule>>,
+) -> bool {
+	if let Some(reject_rules) = rules_per_kind.get(&RuleKind::RejectFilesByGlob) {
+		for reject_rule in reject_rules {
+			// It's ok to unwrap here, reject rules are infallible
+			if !reject_rule.apply(path).await.unwrap() {
+				return false;
+			}
+		}
+	}
+
+	if let Some(accept_rules) = rules_per_kind.get(&RuleKind::AcceptFilesByGlob) {
+		for accept_rule in accept_rules {
+			// It's ok to unwrap here, accept rules are infallible
+			if accept_rule.apply(path).await.unwrap() {
+				return true;
+			}
+		}
+		return false;
+	}
+
+	true
+}
+
+/// Lists a location in object storage as walk entries, with paths relative to the location's root.
+/// Object storage has no directories, so they're made up from the paths of the files in them, which
+/// also means only the glob rules can be applied.
+pub(super) async fn walk_storage(
+	storage: &dyn Storage,
+	rules_per_kind: &HashMap<RuleKind, Vec<IndexerRule>>,
+) -> Result<Vec<WalkEntry>, IndexerError> {
+	let mut indexed_paths = HashMap::new();
+
+	for entry in storage.list().await? {
+		if !is_relative_key(&entry.path) {
+			warn!("Skipping '{}', it leads out of the location", entry.path);
+			continue;
+		}
+		let path = PathBuf::from(&entry.path);
+
+		if !accepts_storage_path(&path, rules_per_kind).await {
+			continue;
+		}
+
+		for ancestor in path.ancestors().skip(1) {
+			if indexed_paths.contains_key(ancestor) {
+				break;
+			}
+
+			indexed_paths.insert(
+				ancestor.to_path_buf(),
+				WalkEntry {
+					path: ancestor.to_path_buf(),
+					is_dir: true,
+					created_at: entry.modified,
+				},
+			);
+		}
+
+		indexed_paths.insert(
+			path.clone(),
+			WalkEntry {
+				path,
+				is_dir: false,
+				created_at: entry.modified,
+			},
+		);
+	}
+
+	// the location's root is indexed even when it's empty, like on the filesystem
+	indexed_paths
+		.entry(PathBuf::new())
+		.or_insert_with(|| WalkEntry {
+			path: PathBuf::new(),
+			is_dir: true,
+			created_at: Utc::now(),
+		});
+
+	let mut indexed_paths = indexed_paths.into_values().collect::<Vec<_>>();
+	indexed_paths.sort();
+
+	Ok(indexed_paths)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::super::rules::ParametersPerKind;
@@ -375,7 +507,7 @@ mod tests {
 		.into_iter()
 		.collect::<BTreeSet<_>>();
 
-		let actual = walk(root_path.to_path_buf(), &HashMap::new(), |_, _| {})
+		let actual = walk(root_path.to_path_buf(), &HashMap::new(), None, |_, _| {})
 			.await
 			.unwrap()
 			.into_iter()
@@ -414,7 +546,7 @@ mod tests {
 		.into_iter()
 		.collect::<HashMap<_, _>>();
 
-		let actual = walk(root_path.to_path_buf(), &only_photos_rule, |_, _| {})
+		let actual = walk(root_path.to_path_buf(), &only_photos_rule, None, |_, _| {})
 			.await
 			.unwrap()
 			.into_iter()
@@ -423,6 +555,44 @@ mod tests {
 		assert_eq!(actual, expected);
 	}
 
+	#[tokio::test]
+	async fn test_walk_only() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let any_datetime = Utc::now();
+
+		#[rustfmt::skip]
+		let expected = [
+			WalkEntry { path: root_path.to_path_buf(), is_dir: true, created_at: any_datetime.clone() },
+			WalkEntry { path: root_path.join("photos"), is_dir: true, created_at: any_datetime.clone() },
+			WalkEntry { path: root_path.join("photos/photo1.png"), is_dir: false, created_at: any_datetime.clone() },
+			WalkEntry { path: root_path.join("photos/photo2.jpg"), is_dir: false, created_at: any_datetime.clone() },
+			WalkEntry { path: root_path.join("photos/photo3.jpeg"), is_dir: false, created_at: any_datetime.clone() },
+			WalkEntry { path: root_path.join("photos/text.txt"), is_dir: false, created_at: any_datetime },
+		]
+		.into_iter()
+		.collect::<BTreeSet<_>>();
+
+		let only = [root_path.join("photos")]
+			.into_iter()
+			.collect::<HashSet<_>>();
+
+		let actual = walk_only(
+			root_path.to_path_buf(),
+			Some(&only),
+			&HashMap::new(),
+			None,
+			|_, _| {},
+		)
+		.await
+		.unwrap()
+		.into_iter()
+		.collect::<BTreeSet<_>>();
+
+		assert_eq!(actual, expected);
+	}
+
 	#[tokio::test]
 	#[traced_test]
 	async fn test_git_repos() {
@@ -468,7 +638,7 @@ mod tests {
 		.into_iter()
 		.collect::<HashMap<_, _>>();
 
-		let actual = walk(root_path.to_path_buf(), &git_repos, |_, _| {})
+		let actual = walk(root_path.to_path_buf(), &git_repos, None, |_, _| {})
 			.await
 			.unwrap()
 			.into_iter()
@@ -540,6 +710,7 @@ mod tests {
 		let actual = walk(
 			root_path.to_path_buf(),
 			&git_repos_no_deps_no_build_dirs,
+			None,
 			|_, _| {},
 		)
 		.await
@@ -0,0 +1,382 @@
+//! Listing the children of a directory of a location a page at a time, so a directory with hundreds
+//! of thousands of files is never read, or sent to a client, all at once. Pages are sorted by name,
+//! size, modification date or kind, and end with a cursor made of the sort key and id of their
+//! last file path, which the next page is read after. Cursors stay valid as files come and go.
+//! Sorting by name in natural order goes by a [`Collation`] the database doesn't know of, so the
+//! names of the whole directory are read to be sorted, though only the page's files are.
+
+use std::collections::HashMap;
+
+use prisma_client_rust::{raw::Raw, PrismaValue};
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+	library::LibraryContext,
+	object::preview::THUMBNAIL_CACHE_DIR_NAME,
+	prisma::file_path,
+	util::{collation::Collation, db::raw_int},
+};
+
+const DEFAULT_PAGE_SIZE: u16 = 100;
+const MAX_PAGE_SIZE: u16 = 1000;
+
+file_path::include!(file_path_with_object { object });
+
+#[derive(Error, Debug)]
+pub enum ListingError {
+	#[error("directory not found: <location_id={0}, path='{1}'>")]
+	DirectoryNotFound(i32, String),
+	#[error("invalid cursor: {0}")]
+	InvalidCursor(String),
+	#[error("database error: {0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+}
+
+impl From<ListingError> for rspc::Error {
+	fn from(err: ListingError) -> Self {
+		match err {
+			ListingError::DirectoryNotFound(..) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			ListingError::InvalidCursor(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			ListingError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectorySort {
+	Name,
+	Size,
+	DateModified,
+	Kind,
+	/// by name, with numbers in order of their value and accents folded, see [`Collation`]
+	Natural,
+}
+
+impl DirectorySort {
+	/// key returns the SQL the file paths, `fp`, are sorted by, with their object as `o`
+	fn key(&self) -> &'static str {
+		match self {
+			// case doesn't matter to people looking for a file by its name
+			Self::Name => "LOWER(fp.name || COALESCE('.' || NULLIF(fp.extension, ''), ''))",
+			// directories and files that aren't identified yet don't have a size
+			Self::Size => "COALESCE(CAST(o.size_in_bytes AS INTEGER), 0)",
+			// dates are stored as milliseconds since the epoch or as text
+			Self::DateModified => {
+				"CASE WHEN typeof(fp.date_modified) = 'integer' THEN fp.date_modified \
+				ELSE CAST((julianday(fp.date_modified) - 2440587.5) * 86400000 AS INTEGER) END"
+			}
+			Self::Kind => "COALESCE(o.kind, 0)",
+			// sorted outside of the database, see `natural_rows`
+			Self::Natural => Self::Name.key(),
+		}
+	}
+
+	/// is_textual tells if the sort keys are compared as text rather than as numbers
+	fn is_textual(&self) -> bool {
+		matches!(self, Self::Name | Self::Natural)
+	}
+
+	fn as_str(&self) -> &'static str {
+		match self {
+			Self::Name => "name",
+			Self::Size => "size",
+			Self::DateModified => "date_modified",
+			Self::Kind => "kind",
+			Self::Natural => "natural",
+		}
+	}
+}
+
+/// Where a page ends, to read the next one from: the sort key and id of its last file path, as
+/// `<sort>|<file_path_id>|<key>`
+#[derive(Debug, PartialEq, Eq)]
+struct DirectoryCursor {
+	sort: DirectorySort,
+	file_path_id: i32,
+	key: String,
+}
+
+impl DirectoryCursor {
+	fn parse(cursor: &str, sort: DirectorySort) -> Result<Self, ListingError> {
+		let invalid = || ListingError::InvalidCursor(cursor.to_string());
+		let mut parts = cursor.splitn(3, '|');
+		let (cursor_sort, file_path_id, key) = match (parts.next(), parts.next(), parts.next()) {
+			(Some(cursor_sort), Some(file_path_id), Some(key)) => (cursor_sort, file_path_id, key),
+			_ => return Err(invalid()),
+		};
+
+		// a cursor of another sort would skip or repeat file paths
+		if cursor_sort != sort.as_str() {
+			return Err(invalid());
+		}
+		// only names are compared as text
+		if !sort.is_textual() && key.parse::<i64>().is_err() {
+			return Err(invalid());
+		}
+
+		Ok(Self {
+			sort,
+			file_path_id: file_path_id.parse().map_err(|_| invalid())?,
+			key: key.to_string(),
+		})
+	}
+
+	/// key_value returns the sort key as the type it's compared as
+	fn key_value(&self) -> PrismaValue {
+		match self.sort.is_textual() {
+			true => PrismaValue::String(self.key.clone()),
+			false => PrismaValue::Int(self.key.parse().unwrap_or_default()),
+		}
+	}
+}
+
+impl std::fmt::Display for DirectoryCursor {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{}|{}|{}",
+			self.sort.as_str(),
+			self.file_path_id,
+			self.key
+		)
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct DirectoryListArgs {
+	pub location_id: i32,
+	/// materialized path of the directory
+	pub path: String,
+	/// by name when not given
+	pub sort: Option<DirectorySort>,
+	pub descending: bool,
+	/// how names are sorted in natural order, the order shared by most languages when not given
+	#[serde(default)]
+	pub collation: Option<Collation>,
+	/// where the previous page ended, which has to be of the same sort
+	pub cursor: Option<String>,
+	pub take: Option<u16>,
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct DirectoryPage {
+	/// how many children the directory has, on every page
+	pub total: i64,
+	pub items: Vec<file_path_with_object::Data>,
+	/// where to read the next page from, if there's more of the directory
+	pub next_cursor: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListedRow {
+	#[serde(deserialize_with = "raw_int")]
+	id: i64,
+	sort_key: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NamedRow {
+	#[serde(deserialize_with = "raw_int")]
+	id: i64,
+	name: String,
+}
+
+/// natural_rows returns the rows of a page of the directory sorted in natural order, with the
+/// collation keys of their names as their sort keys
+async fn natural_rows(
+	library: &LibraryContext,
+	args: &DirectoryListArgs,
+	directory_id: i32,
+	cursor: Option<&DirectoryCursor>,
+	take: usize,
+) -> Result<Vec<ListedRow>, ListingError> {
+	let named: Vec<NamedRow> = library
+		.db
+		._query_raw(Raw::new(
+			&format!(
+				"SELECT fp.id AS id, \
+				fp.name || COALESCE('.' || NULLIF(fp.extension, ''), '') AS name \
+				FROM file_path fp WHERE fp.location_id = {} AND fp.parent_id = {directory_id}",
+				args.location_id
+			),
+			vec![],
+		))
+		.exec()
+		.await?;
+
+	let collation = args.collation.clone().unwrap_or_default();
+	let mut rows = named
+		.into_iter()
+		.map(|row| ListedRow {
+			id: row.id,
+			sort_key: collation.key(&row.name),
+		})
+		.collect::<Vec<_>>();
+	rows.sort_by(|a, b| (&a.sort_key, a.id).cmp(&(&b.sort_key, b.id)));
+	if args.descending {
+		rows.reverse();
+	}
+
+	let start = cursor.map_or(0, |cursor| {
+		let after = (&cursor.key, cursor.file_path_id as i64);
+		rows.partition_point(|row| match args.descending {
+			true => (&row.sort_key, row.id) >= after,
+			false => (&row.sort_key, row.id) <= after,
+		})
+	});
+
+	Ok(rows.into_iter().skip(start).take(take + 1).collect())
+}
+
+/// list_directory returns a page of the children of a directory of a location, with their objects
+pub async fn list_directory(
+	library: &LibraryContext,
+	args: DirectoryListArgs,
+) -> Result<DirectoryPage, ListingError> {
+	let sort = args.sort.unwrap_or(DirectorySort::Name);
+	let take = args
+		.take
+		.unwrap_or(DEFAULT_PAGE_SIZE)
+		.clamp(1, MAX_PAGE_SIZE) as usize;
+	let cursor = args
+		.cursor
+		.as_deref()
+		.map(|cursor| DirectoryCursor::parse(cursor, sort))
+		.transpose()?;
+
+	let directory = library
+		.db
+		.file_path()
+		.find_first(vec![
+			file_path::location_id::equals(args.location_id),
+			file_path::materialized_path::equals(args.path.clone()),
+			file_path::is_dir::equals(true),
+		])
+		.exec()
+		.await?
+		.ok_or_else(|| ListingError::DirectoryNotFound(args.location_id, args.path.clone()))?;
+
+	let in_directory = vec![
+		file_path::location_id::equals(args.location_id),
+		file_path::parent_id::equals(Some(directory.id)),
+	];
+	let total = library.db.file_path().count(in_directory).exec().await?;
+
+	let mut rows: Vec<ListedRow> = match sort {
+		DirectorySort::Natural => {
+			natural_rows(library, &args, directory.id, cursor.as_ref(), take).await?
+		}
+		_ => {
+			let key = sort.key();
+			let (order, comparison) = match args.descending {
+				true => ("DESC", "<"),
+				false => ("ASC", ">"),
+			};
+			let mut params = vec![];
+			let after = match &cursor {
+				Some(cursor) => {
+					params.push(cursor.key_value());
+					format!(
+						" AND ({key}, fp.id) {comparison} ({{}}, {})",
+						cursor.file_path_id
+					)
+				}
+				None => String::new(),
+			};
+
+			library
+				.db
+				._query_raw(Raw::new(
+					&format!(
+						"SELECT fp.id AS id, CAST({key} AS TEXT) AS sort_key FROM file_path fp \
+						LEFT JOIN object o ON o.id = fp.object_id \
+						WHERE fp.location_id = {} AND fp.parent_id = {}{after} \
+						ORDER BY {key} {order}, fp.id {order} LIMIT {}",
+						args.location_id,
+						directory.id,
+						take + 1
+					),
+					params,
+				))
+				.exec()
+				.await?
+		}
+	};
+
+	let next_cursor = if rows.len() > take {
+		rows.truncate(take);
+		rows.last().map(|last| {
+			DirectoryCursor {
+				sort,
+				file_path_id: last.id as i32,
+				key: last.sort_key.clone(),
+			}
+			.to_string()
+		})
+	} else {
+		None
+	};
+
+	let thumbnails_dir = library
+		.config()
+		.data_directory()
+		.join(THUMBNAIL_CACHE_DIR_NAME);
+	let mut file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(args.location_id),
+			file_path::id::in_vec(rows.iter().map(|row| row.id as i32).collect()),
+		])
+		.include(file_path_with_object::include())
+		.exec()
+		.await?
+		.into_iter()
+		.map(|mut file_path| {
+			if let Some(object) = file_path.object.as_mut() {
+				object.has_thumbnail = thumbnails_dir
+					.join(&object.cas_id)
+					.with_extension("webp")
+					.exists();
+			}
+			(file_path.id, file_path)
+		})
+		.collect::<HashMap<_, _>>();
+
+	Ok(DirectoryPage {
+		total,
+		items: rows
+			.iter()
+			.filter_map(|row| file_paths.remove(&(row.id as i32)))
+			.collect(),
+		next_cursor,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cursors_round_trip() {
+		let cursor =
+			DirectoryCursor::parse("name|42|holiday|2022.jpg", DirectorySort::Name).unwrap();
+		assert_eq!(cursor.file_path_id, 42);
+		assert_eq!(cursor.key, "holiday|2022.jpg");
+		assert_eq!(cursor.to_string(), "name|42|holiday|2022.jpg");
+
+		assert!(DirectoryCursor::parse("size|42|1024", DirectorySort::Size).is_ok());
+		// cursors of another sort, or whose key isn't a number when it should be, are refused
+		assert!(DirectoryCursor::parse("size|42|1024", DirectorySort::Kind).is_err());
+		assert!(DirectoryCursor::parse("size|42|big", DirectorySort::Size).is_err());
+		assert!(DirectoryCursor::parse("name|42", DirectorySort::Name).is_err());
+	}
+}
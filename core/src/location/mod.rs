@@ -1,32 +1,69 @@
 use crate::{
+	api::LibraryEvent,
 	invalidate_query,
 	job::Job,
 	library::LibraryContext,
 	object::{
 		identifier_job::{FileIdentifierJob, FileIdentifierJobInit},
 		preview::{ThumbnailJob, ThumbnailJobInit},
-		validation::validator_job::{ObjectValidatorJob, ObjectValidatorJobInit},
 	},
 	prisma::{indexer_rules_in_location, location, node},
+	volume::get_volumes,
 };
 
+use chrono::{DateTime, Utc};
 use rspc::Type;
+use sd_crypto::{
+	crypto::stream::Algorithm,
+	keys::hashing::{HashingAlgorithm, Params},
+	Protected,
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+	collections::HashSet,
+	path::{Component, PathBuf},
+	time::SystemTime,
+};
 use tokio::{
 	fs::{metadata, File},
 	io::AsyncWriteExt,
+	task::block_in_place,
 };
 use tracing::{debug, info};
 use uuid::Uuid;
 
+pub mod automation;
+pub mod catalog;
+pub mod custom_metadata;
+pub mod eraser;
 mod error;
+pub mod health;
 pub mod indexer;
+pub mod listing;
+pub mod network;
+pub mod pipeline;
+pub mod rule_bundle;
+pub mod settings;
+pub mod space_monitor;
+pub mod storage;
+pub mod tombstone;
+pub mod treemap;
+pub mod volume_watcher;
 
 pub use error::LocationError;
-use indexer::indexer_job::{IndexerJob, IndexerJobInit};
+use indexer::{
+	indexer_job::{IndexerJob, IndexerJobInit},
+	storage_sync_job::{StorageSyncJob, StorageSyncJobInit},
+	sweep_job::{SweepJob, SweepJobInit},
+};
 
-use self::indexer::indexer_job::indexer_job_location;
+use self::{
+	indexer::indexer_job::indexer_job_location,
+	pipeline::{set_location_stages, PipelineStage},
+	settings::LocationSettings,
+	storage::StorageConfig,
+	volume_watcher::volume_params,
+};
 
 static DOTFILE_NAME: &str = ".spacedrive";
 
@@ -37,6 +74,12 @@ static DOTFILE_NAME: &str = ".spacedrive";
 pub struct LocationCreateArgs {
 	pub path: PathBuf,
 	pub indexer_rules_ids: Vec<i32>,
+	/// indexes the location once to be kept offline, see [`catalog`]
+	#[serde(default)]
+	pub catalog: bool,
+	/// what indexing does in the location, the defaults when not given
+	#[serde(default)]
+	pub settings: Option<LocationSettings>,
 }
 
 impl LocationCreateArgs {
@@ -53,7 +96,8 @@ impl LocationCreateArgs {
 			.await
 			.map_err(|e| LocationError::DotfileReadFailure(e, self.path.clone()))?;
 
-		if path_metadata.permissions().readonly() {
+		// catalogs are never written to, so they don't need a dotfile
+		if path_metadata.permissions().readonly() && !self.catalog {
 			return Err(LocationError::ReadonlyDotFileLocationFailure(self.path));
 		}
 
@@ -82,19 +126,28 @@ impl LocationCreateArgs {
 		);
 		let uuid = Uuid::new_v4();
 
+		let mut params = vec![
+			location::name::set(Some(
+				self.path.file_name().unwrap().to_str().unwrap().to_string(),
+			)),
+			location::is_online::set(true),
+			location::local_path::set(Some(self.path.to_string_lossy().to_string())),
+			location::is_catalog::set(self.catalog),
+		];
+		params.extend(self.settings.unwrap_or_default().params());
+		// remember which drive the location is on, so we can tell when it's unplugged
+		params.extend(volume_params(
+			&block_in_place(get_volumes).unwrap_or_default(),
+			&self.path,
+		));
+
 		let mut location = ctx
 			.db
 			.location()
 			.create(
 				uuid.as_bytes().to_vec(),
 				node::id::equals(ctx.node_local_id),
-				vec![
-					location::name::set(Some(
-						self.path.file_name().unwrap().to_str().unwrap().to_string(),
-					)),
-					location::is_online::set(true),
-					location::local_path::set(Some(self.path.to_string_lossy().to_string())),
-				],
+				params,
 			)
 			.include(indexer_job_location::include())
 			.exec()
@@ -113,6 +166,15 @@ impl LocationCreateArgs {
 			.await?
 			.ok_or(LocationError::IdNotFound(location.id))?;
 
+		if self.catalog {
+			invalidate_query!(ctx, "locations.list");
+			ctx.emit_event(LibraryEvent::LocationAdded {
+				location_id: location.id,
+			});
+
+			return Ok(location);
+		}
+
 		// write a file called .spacedrive to path containing the location id in JSON format
 		let mut dotfile = File::create(self.path.join(DOTFILE_NAME))
 			.await
@@ -130,6 +192,9 @@ impl LocationCreateArgs {
 			.map_err(|e| LocationError::DotfileWriteFailure(e, self.path))?;
 
 		invalidate_query!(ctx, "locations.list");
+		ctx.emit_event(LibraryEvent::LocationAdded {
+			location_id: location.id,
+		});
 
 		Ok(location)
 	}
@@ -146,6 +211,15 @@ pub struct LocationUpdateArgs {
 	pub id: i32,
 	pub name: Option<String>,
 	pub indexer_rules_ids: Vec<i32>,
+	/// whether to keep encrypted copies of small files, left as it is when not given
+	#[serde(default)]
+	pub keep_version_copies: Option<bool>,
+	/// the stages the location's pipeline runs from its next scan on, left as they are when not given
+	#[serde(default)]
+	pub pipeline_stages: Option<Vec<PipelineStage>>,
+	/// what indexing does in the location from its next scan on, left as it is when not given
+	#[serde(default)]
+	pub settings: Option<LocationSettings>,
 }
 
 impl LocationUpdateArgs {
@@ -167,6 +241,37 @@ impl LocationUpdateArgs {
 				.await?;
 		}
 
+		if let Some(keep_version_copies) = self.keep_version_copies {
+			if location.keep_version_copies != keep_version_copies {
+				ctx.db
+					.location()
+					.update(
+						location::id::equals(self.id),
+						vec![location::keep_version_copies::set(keep_version_copies)],
+					)
+					.exec()
+					.await?;
+			}
+		}
+
+		if let Some(settings) = self.settings {
+			ctx.db
+				.location()
+				.update(location::id::equals(self.id), settings.params())
+				.exec()
+				.await?;
+		}
+
+		if let Some(pipeline_stages) = self.pipeline_stages {
+			set_location_stages(
+				ctx,
+				self.id,
+				location.pipeline_stages.as_deref(),
+				&pipeline_stages,
+			)
+			.await?;
+		}
+
 		let current_rules_ids = location
 			.indexer_rules
 			.iter()
@@ -201,6 +306,10 @@ impl LocationUpdateArgs {
 			}
 		}
 
+		ctx.emit_event(LibraryEvent::LocationUpdated {
+			location_id: self.id,
+		});
+
 		Ok(())
 	}
 }
@@ -249,26 +358,92 @@ async fn link_location_and_indexer_rules(
 	Ok(())
 }
 
+/// store_secret adds a secret, like a location's credentials, to the key manager and persists it in
+/// the library
+pub(crate) async fn store_secret(
+	ctx: &LibraryContext,
+	secret: String,
+) -> Result<Uuid, LocationError> {
+	let algorithm = Algorithm::XChaCha20Poly1305;
+	let hashing_algorithm = HashingAlgorithm::Argon2id(Params::Standard);
+
+	let uuid = ctx.key_manager.add_to_keystore(
+		Protected::new(secret.into_bytes()),
+		algorithm,
+		hashing_algorithm,
+	)?;
+	let stored_key = ctx.key_manager.access_keystore(uuid)?;
+
+	ctx.db
+		.key()
+		.create(
+			uuid.to_string(),
+			algorithm.serialize().to_vec(),
+			hashing_algorithm.serialize().to_vec(),
+			stored_key.content_salt.to_vec(),
+			stored_key.master_key.to_vec(),
+			stored_key.master_key_nonce.to_vec(),
+			stored_key.key_nonce.to_vec(),
+			stored_key.key.to_vec(),
+			vec![],
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(ctx, "keys.list");
+
+	Ok(uuid)
+}
+
+/// scan_location runs the location's pipeline, indexing it and processing what was indexed
 pub async fn scan_location(
 	ctx: &LibraryContext,
 	location: indexer_job_location::Data,
 ) -> Result<(), LocationError> {
-	if location.local_path.is_none() {
+	if location.local_path.is_none() && location.storage_config.is_none() {
 		return Err(LocationError::MissingLocalPath(location.id));
 	};
 
+	// offline locations keep their last indexed state until their volume comes back
+	if !location.is_online {
+		return Err(LocationError::Offline(location.id));
+	}
+
+	pipeline::start_pipeline(ctx, &location).await
+}
+
+/// sweep_location catches the location's index up with the changes made to it since `since`,
+/// identifying and generating thumbnails for whatever was added afterwards
+pub async fn sweep_location(
+	ctx: &LibraryContext,
+	location: indexer_job_location::Data,
+	since: DateTime<Utc>,
+) -> Result<(), LocationError> {
+	if location.local_path.is_none() {
+		return Err(LocationError::MissingLocalPath(location.id));
+	}
+
+	if !location.is_online {
+		return Err(LocationError::Offline(location.id));
+	}
+
 	let location_id = location.id;
+	let budget = ctx.config.processing_budget.clone();
 	ctx.queue_job(Job::new(
 		FileIdentifierJobInit {
-			location_id: location.id,
+			location_id,
 			sub_path: None,
 		},
 		Box::new(FileIdentifierJob {}),
 	))
 	.await;
 	ctx.spawn_job(Job::new(
-		IndexerJobInit { location },
-		Box::new(IndexerJob {}),
+		SweepJobInit {
+			location,
+			since,
+			sub_path: None,
+		},
+		Box::new(SweepJob {}),
 	))
 	.await;
 	ctx.queue_job(Job::new(
@@ -276,19 +451,190 @@ pub async fn scan_location(
 			location_id,
 			path: PathBuf::new(),
 			background: true,
+			budget: Some(budget),
 		},
 		Box::new(ThumbnailJob {}),
 	))
 	.await;
+
+	Ok(())
+}
+
+/// rescan_path indexes and identifies a directory of the location again rather than all of it,
+/// with thumbnails for what's in it. `sub_path` is relative to the location.
+pub async fn rescan_path(
+	ctx: &LibraryContext,
+	location: indexer_job_location::Data,
+	sub_path: &str,
+) -> Result<(), LocationError> {
+	if location.local_path.is_none() {
+		return Err(LocationError::MissingLocalPath(location.id));
+	}
+
+	if !location.is_online {
+		return Err(LocationError::Offline(location.id));
+	}
+
+	let sub_path = PathBuf::from(sub_path.trim_matches('/'));
+	if sub_path.as_os_str().is_empty()
+		|| !sub_path
+			.components()
+			.all(|component| matches!(component, Component::Normal(_)))
+	{
+		return Err(LocationError::InvalidSubPath(sub_path));
+	}
+
+	let location_id = location.id;
+	let is_catalog = location.is_catalog;
+	let budget = ctx.config.processing_budget.clone();
+	// every directory of the sub path is compared with the index, not only the ones modified lately
+	ctx.spawn_job(Job::new(
+		SweepJobInit {
+			location,
+			since: SystemTime::UNIX_EPOCH.into(),
+			sub_path: Some(sub_path.clone()),
+		},
+		Box::new(SweepJob {}),
+	))
+	.await;
+	ctx.queue_job(Job::new(
+		FileIdentifierJobInit {
+			location_id,
+			sub_path: Some(sub_path.clone()),
+		},
+		Box::new(FileIdentifierJob {}),
+	))
+	.await;
 	ctx.queue_job(Job::new(
-		ObjectValidatorJobInit {
+		ThumbnailJobInit {
+			location_id,
+			path: sub_path,
+			background: true,
+			budget: (!is_catalog).then_some(budget),
+		},
+		Box::new(ThumbnailJob {}),
+	))
+	.await;
+
+	Ok(())
+}
+
+/// sync_storage_location applies the changes made to a cloud drive location since it was last
+/// synced, identifying and generating thumbnails for whatever was added or modified
+pub async fn sync_storage_location(
+	ctx: &LibraryContext,
+	location: indexer_job_location::Data,
+	full: bool,
+) -> Result<(), LocationError> {
+	if location.storage_cursor.is_none() {
+		return Err(LocationError::NoChangeFeed(location.id));
+	}
+
+	if !location.is_online {
+		return Err(LocationError::Offline(location.id));
+	}
+
+	let location_id = location.id;
+	let budget = ctx.config.processing_budget.clone();
+	ctx.queue_job(Job::new(
+		FileIdentifierJobInit {
+			location_id,
+			sub_path: None,
+		},
+		Box::new(FileIdentifierJob {}),
+	))
+	.await;
+	ctx.spawn_job(Job::new(
+		StorageSyncJobInit { location, full },
+		Box::new(StorageSyncJob {}),
+	))
+	.await;
+	ctx.queue_job(Job::new(
+		ThumbnailJobInit {
 			location_id,
 			path: PathBuf::new(),
 			background: true,
+			budget: Some(budget),
 		},
-		Box::new(ObjectValidatorJob {}),
+		Box::new(ThumbnailJob {}),
 	))
 	.await;
 
 	Ok(())
 }
+
+/// sync_storage_locations syncs every online location of this node that has a change feed, but the
+/// ones outside of their transfer window, which are synced once it opens
+pub async fn sync_storage_locations(ctx: &LibraryContext, full: bool) -> Result<(), LocationError> {
+	let locations = ctx
+		.db
+		.location()
+		.find_many(vec![
+			location::node_id::equals(ctx.node_local_id),
+			location::is_online::equals(true),
+			// catalogs keep the snapshot they were taken with
+			location::is_catalog::equals(false),
+			location::watch_changes::equals(true),
+		])
+		.include(indexer_job_location::include())
+		.exec()
+		.await?;
+
+	let transfers = ctx.transfers();
+	for location in locations {
+		if location.storage_cursor.is_none() {
+			continue;
+		}
+
+		let provider = StorageConfig::parse(location.storage_config.as_deref())?
+			.map(|storage| storage.provider());
+		if provider.map_or(false, |provider| !transfers.is_open(provider)) {
+			debug!(
+				"Not syncing location {} outside of its transfer window",
+				location.id
+			);
+			continue;
+		}
+
+		sync_storage_location(ctx, location, full).await?;
+	}
+
+	Ok(())
+}
+
+/// sweep_locations sweeps every location of this node that is there to be swept, which is run when
+/// the library is loaded with the time the node was last seen running
+pub async fn sweep_locations(
+	ctx: &LibraryContext,
+	since: DateTime<Utc>,
+) -> Result<(), LocationError> {
+	let locations = ctx
+		.db
+		.location()
+		.find_many(vec![
+			location::node_id::equals(ctx.node_local_id),
+			location::is_online::equals(true),
+			// catalogs keep the snapshot they were taken with
+			location::is_catalog::equals(false),
+			location::watch_changes::equals(true),
+		])
+		.include(indexer_job_location::include())
+		.exec()
+		.await?;
+
+	for location in locations {
+		// storage locations have no directories to check, and the rest must still be where we left them
+		let is_present = location.storage_config.is_none()
+			&& location
+				.local_path
+				.as_ref()
+				.map(|path| PathBuf::from(path).join(DOTFILE_NAME).exists())
+				.unwrap_or(false);
+
+		if is_present {
+			sweep_location(ctx, location, since).await?;
+		}
+	}
+
+	Ok(())
+}
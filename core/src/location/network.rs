@@ -0,0 +1,352 @@
+use std::{
+	fs,
+	io::Write,
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+	str::FromStr,
+	time::Duration,
+};
+
+use int_enum::IntEnum;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{invalidate_query, library::LibraryContext, prisma::location};
+
+use super::{
+	fetch_location, indexer::indexer_job::indexer_job_location, store_secret, LocationCreateArgs,
+	LocationError, DOTFILE_NAME,
+};
+
+/// Directory in the node's data directory that network shares are mounted under
+const NETWORK_MOUNTS_DIR_NAME: &str = "mounts";
+
+/// How long the indexer waits between directories of a network location, so a scan doesn't
+/// saturate the share for everyone else using it
+pub const NETWORK_WALK_THROTTLE: Duration = Duration::from_millis(20);
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum NetworkProtocol {
+	Smb = 0,
+	Nfs = 1,
+}
+
+impl NetworkProtocol {
+	/// remote formats the share's address the way the platform's mount tools expect it
+	fn remote(&self, host: &str, share: &str) -> String {
+		let share = share.trim_start_matches(|c| c == '/' || c == '\\');
+		match self {
+			// windows reaches both kinds of share through their UNC path
+			_ if cfg!(target_os = "windows") => format!("\\\\{host}\\{}", share.replace('/', "\\")),
+			NetworkProtocol::Smb => format!("//{host}/{share}"),
+			NetworkProtocol::Nfs => format!("{host}:/{share}"),
+		}
+	}
+}
+
+/// `NetworkLocationCreateArgs` is the argument received from the client using `rspc` to add an SMB
+/// or NFS share as a location. The password, if any, is kept in the library's key manager so the
+/// share can be mounted again when it comes back after going offline.
+#[derive(Type, Deserialize)]
+pub struct NetworkLocationCreateArgs {
+	pub protocol: NetworkProtocol,
+	pub host: String,
+	/// the share name for SMB, the exported path for NFS
+	pub share: String,
+	pub username: Option<String>,
+	pub password: Option<String>,
+	pub indexer_rules_ids: Vec<i32>,
+}
+
+impl NetworkLocationCreateArgs {
+	pub async fn create(
+		self,
+		ctx: &LibraryContext,
+	) -> Result<indexer_job_location::Data, LocationError> {
+		let remote = self.protocol.remote(&self.host, &self.share);
+
+		if ctx
+			.db
+			.location()
+			.find_first(vec![location::network_remote::equals(Some(remote.clone()))])
+			.exec()
+			.await?
+			.is_some()
+		{
+			return Err(LocationError::LocationAlreadyExists(PathBuf::from(remote)));
+		}
+
+		// storing the password needs an unlocked key manager, so check before mounting anything
+		if self.password.is_some() && !ctx.key_manager.has_master_password()? {
+			return Err(LocationError::KeyManagerLocked);
+		}
+
+		let mount_point = ctx
+			.config()
+			.data_directory()
+			.join(NETWORK_MOUNTS_DIR_NAME)
+			.join(Uuid::new_v4().to_string());
+
+		let path = block_in_place(|| {
+			mount(
+				self.protocol,
+				&remote,
+				&mount_point,
+				self.username.as_deref(),
+				self.password.as_deref(),
+			)
+		})?;
+
+		let created = async {
+			let key_uuid = match self.password {
+				Some(password) => Some(store_secret(ctx, password).await?),
+				None => None,
+			};
+
+			let location = LocationCreateArgs {
+				path,
+				indexer_rules_ids: self.indexer_rules_ids,
+				catalog: false,
+			}
+			.create(ctx)
+			.await?;
+
+			ctx.db
+				.location()
+				.update(
+					location::id::equals(location.id),
+					vec![
+						location::network_protocol::set(Some(self.protocol.int_value())),
+						location::network_remote::set(Some(remote.clone())),
+						location::network_username::set(self.username),
+						location::network_key::set(key_uuid.map(|uuid| uuid.to_string())),
+					],
+				)
+				.exec()
+				.await?;
+
+			Ok::<_, LocationError>(location)
+		}
+		.await;
+
+		let location = match created {
+			Ok(location) => location,
+			Err(e) => {
+				// nothing would ever use the share, or unmount it
+				block_in_place(|| unmount(&remote, &mount_point));
+				return Err(e);
+			}
+		};
+
+		info!("Added network share {remote} as location {}", location.id);
+
+		invalidate_query!(ctx, "locations.list");
+
+		fetch_location(ctx, location.id)
+			.include(indexer_job_location::include())
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(location.id))
+	}
+}
+
+/// is_mounted checks for the dotfile written when the location was created, as the directory a
+/// share is mounted on is still there, empty, while the share is away
+pub fn is_mounted(local_path: &Path) -> bool {
+	local_path.join(DOTFILE_NAME).exists()
+}
+
+/// remount mounts a network location's share again, using the credentials stored when it was created
+pub async fn remount(ctx: &LibraryContext, location: &location::Data) -> Result<(), LocationError> {
+	let (protocol, remote, local_path) = match (
+		location.network_protocol,
+		&location.network_remote,
+		&location.local_path,
+	) {
+		(Some(protocol), Some(remote), Some(local_path)) => (
+			NetworkProtocol::from_int(protocol)
+				.map_err(|_| LocationError::InvalidNetworkShare(location.id))?,
+			remote,
+			PathBuf::from(local_path),
+		),
+		_ => return Err(LocationError::InvalidNetworkShare(location.id)),
+	};
+
+	let password = match &location.network_key {
+		Some(key) => {
+			let uuid =
+				Uuid::from_str(key).map_err(|_| LocationError::InvalidNetworkShare(location.id))?;
+			let password = ctx.key_manager.get_key(uuid)?;
+			Some(String::from_utf8_lossy(password.expose()).to_string())
+		}
+		None => None,
+	};
+
+	block_in_place(|| {
+		mount(
+			protocol,
+			remote,
+			&local_path,
+			location.network_username.as_deref(),
+			password.as_deref(),
+		)
+	})?;
+
+	Ok(())
+}
+
+/// mount mounts the share using the platform's tools, returning where its files can be found.
+/// On Windows shares are used through their UNC path rather than mounted on a directory.
+fn mount(
+	protocol: NetworkProtocol,
+	remote: &str,
+	mount_point: &Path,
+	username: Option<&str>,
+	password: Option<&str>,
+) -> Result<PathBuf, LocationError> {
+	let mut command;
+	// what's written to the command's input, to keep it out of its arguments
+	let mut input = None;
+
+	if cfg!(target_os = "windows") {
+		// the NFS client reaches exports by their UNC path without any setup
+		if protocol == NetworkProtocol::Nfs {
+			if Path::new(remote).exists() {
+				return Ok(PathBuf::from(remote));
+			}
+
+			return Err(LocationError::MountFailure(
+				remote.to_string(),
+				"share not reachable".to_string(),
+			));
+		}
+
+		command = Command::new("net");
+		command.args(["use", remote]);
+		// `*` has the password read from the input, as arguments can be seen by every user
+		if let Some(password) = password {
+			command.arg("*");
+			input = Some(format!("{password}\r\n"));
+		}
+		if let Some(username) = username {
+			command.arg(format!("/user:{username}"));
+		}
+		command.arg("/persistent:no");
+	} else {
+		fs::create_dir_all(mount_point).map_err(LocationError::IOError)?;
+
+		if cfg!(target_os = "macos") {
+			match protocol {
+				NetworkProtocol::Smb => {
+					let credentials = match (username, password) {
+						(Some(username), Some(password)) => {
+							format!("{}:{}@", url_encode(username), url_encode(password))
+						}
+						(Some(username), None) => format!("{}@", url_encode(username)),
+						_ => String::new(),
+					};
+					command = Command::new("mount_smbfs");
+					command.arg(format!("//{credentials}{}", remote.trim_start_matches('/')));
+				}
+				NetworkProtocol::Nfs => {
+					command = Command::new("mount_nfs");
+					command.arg(remote);
+				}
+			}
+		} else {
+			command = Command::new("mount");
+			match protocol {
+				NetworkProtocol::Smb => {
+					// mount.cifs reads the credentials from the environment, which keeps them out of `ps`
+					command.args(["-t", "cifs", remote]);
+					if let Some(username) = username {
+						command.env("USER", username);
+					}
+					if let Some(password) = password {
+						command.env("PASSWD", password);
+					}
+				}
+				NetworkProtocol::Nfs => {
+					command.args(["-t", "nfs", remote]);
+				}
+			}
+		}
+
+		command.arg(mount_point);
+	}
+
+	let path = if cfg!(target_os = "windows") {
+		PathBuf::from(remote)
+	} else {
+		mount_point.to_path_buf()
+	};
+
+	let output = match input {
+		Some(input) => {
+			let mut child = command
+				.stdin(Stdio::piped())
+				.stdout(Stdio::piped())
+				.stderr(Stdio::piped())
+				.spawn()
+				.map_err(LocationError::IOError)?;
+			if let Some(mut stdin) = child.stdin.take() {
+				stdin
+					.write_all(input.as_bytes())
+					.map_err(LocationError::IOError)?;
+			}
+			child.wait_with_output()
+		}
+		None => command.output(),
+	}
+	.map_err(LocationError::IOError)?;
+	if !output.status.success() {
+		// failing because the share is already connected is fine
+		let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+		if !stderr.contains("already mounted") && !(cfg!(target_os = "windows") && path.exists()) {
+			return Err(LocationError::MountFailure(remote.to_string(), stderr));
+		}
+	}
+
+	Ok(path)
+}
+
+/// unmount undoes [`mount`], for a share mounted for a location that couldn't be added
+fn unmount(remote: &str, mount_point: &Path) {
+	let output = if cfg!(target_os = "windows") {
+		Command::new("net")
+			.args(["use", remote, "/delete", "/y"])
+			.output()
+	} else {
+		Command::new("umount").arg(mount_point).output()
+	};
+
+	match output {
+		Ok(output) if output.status.success() => {}
+		Ok(output) => warn!(
+			"Failed to unmount {remote}: {}",
+			String::from_utf8_lossy(&output.stderr).trim()
+		),
+		Err(e) => warn!("Failed to unmount {remote}: {e:#?}"),
+	}
+
+	if !cfg!(target_os = "windows") {
+		fs::remove_dir(mount_point).ok();
+	}
+}
+
+/// url_encode escapes credentials for use in an `smb://` url
+fn url_encode(value: &str) -> String {
+	value
+		.bytes()
+		.map(|b| match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+				(b as char).to_string()
+			}
+			_ => format!("%{b:02X}"),
+		})
+		.collect()
+}
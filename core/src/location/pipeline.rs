@@ -0,0 +1,550 @@
+//! The jobs a location goes through when it's scanned, run as one pipeline: indexing, then
+//! identifying what was indexed, then thumbnails along with the media metadata read for them, and
+//! whatever else the location is set to go through. Each stage is queued once the one before it
+//! completed, and a pipeline stops when one of them fails. Pipelines are kept in the library's
+//! database with the job of the stage they're at, so one that was cut short by the node shutting
+//! down goes on from that stage once the node is back.
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use int_enum::IntEnum;
+use prisma_client_rust::Direction;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	job::{DynJob, Job, JobManager, JobReport, JobStatus},
+	library::LibraryContext,
+	object::{
+		faces::{FaceGrouperJob, FaceGrouperJobInit},
+		identifier_job::{FileIdentifierJob, FileIdentifierJobInit},
+		labeler::{ImageLabelerJob, ImageLabelerJobInit},
+		preview::{ThumbnailJob, ThumbnailJobInit},
+		validation::validator_job::{ObjectValidatorJob, ObjectValidatorJobInit},
+	},
+	prisma::{job, location, location_pipeline},
+};
+
+use super::{
+	fetch_location,
+	indexer::indexer_job::{indexer_job_location, IndexerJob, IndexerJobInit},
+	LocationError,
+};
+
+/// A stage of a location's pipeline, in the order they run
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PipelineStage {
+	/// walks the location for its files, which every pipeline starts with
+	Index,
+	/// tells files apart by their content, which the stages after it work on
+	Identify,
+	/// generates thumbnails and previews, reading the EXIF and media metadata of files on the way
+	Thumbnails,
+	/// tags images with what's in them
+	Labels,
+	/// finds and groups the faces in photos
+	Faces,
+	/// checks files against their checksums
+	Validate,
+}
+
+impl PipelineStage {
+	const ALL: [Self; 6] = [
+		Self::Index,
+		Self::Identify,
+		Self::Thumbnails,
+		Self::Labels,
+		Self::Faces,
+		Self::Validate,
+	];
+
+	/// The stages locations run when they weren't set to run others
+	const DEFAULT: [Self; 4] = [
+		Self::Index,
+		Self::Identify,
+		Self::Thumbnails,
+		Self::Validate,
+	];
+
+	/// resolve orders the stages a location is set to run, adding the stages they depend on.
+	/// Validating reads every file in full, which is too much to pull over the network on every
+	/// scan, so remote locations skip it.
+	fn resolve(chosen: &[Self], is_remote: bool) -> Vec<Self> {
+		let mut stages = chosen
+			.iter()
+			.copied()
+			.filter(|stage| !(is_remote && *stage == Self::Validate))
+			.collect::<Vec<_>>();
+
+		stages.push(Self::Index);
+		if stages.iter().any(|stage| *stage > Self::Identify) {
+			stages.push(Self::Identify);
+		}
+
+		stages.sort();
+		stages.dedup();
+		stages
+	}
+
+	/// job returns the job that runs this stage on a location
+	async fn job(
+		&self,
+		library: &LibraryContext,
+		location_id: i32,
+	) -> Result<Box<dyn DynJob>, LocationError> {
+		Ok(match self {
+			Self::Index => {
+				let location = fetch_location(library, location_id)
+					.include(indexer_job_location::include())
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(location_id))?;
+				Job::new(IndexerJobInit { location }, Box::new(IndexerJob {}))
+			}
+			Self::Identify => Job::new(
+				FileIdentifierJobInit {
+					location_id,
+					sub_path: None,
+				},
+				Box::new(FileIdentifierJob {}),
+			),
+			Self::Thumbnails => {
+				let location = fetch_location(library, location_id)
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(location_id))?;
+				Job::new(
+					ThumbnailJobInit {
+						location_id,
+						path: PathBuf::new(),
+						background: true,
+						// a catalog may not be seen again, so everything in it gets a thumbnail
+						budget: (!location.is_catalog)
+							.then_some(library.config.processing_budget.clone()),
+					},
+					Box::new(ThumbnailJob {}),
+				)
+			}
+			Self::Labels => Job::new(
+				ImageLabelerJobInit {
+					location_id,
+					min_confidence: None,
+				},
+				Box::new(ImageLabelerJob {}),
+			),
+			Self::Faces => Job::new(
+				FaceGrouperJobInit { location_id },
+				Box::new(FaceGrouperJob {}),
+			),
+			Self::Validate => Job::new(
+				ObjectValidatorJobInit {
+					location_id,
+					path: PathBuf::new(),
+					background: true,
+				},
+				Box::new(ObjectValidatorJob {}),
+			),
+		})
+	}
+}
+
+/// location_stages returns the stages a location is set to run, before they're resolved
+pub fn location_stages(pipeline_stages: Option<&str>) -> Result<Vec<PipelineStage>, LocationError> {
+	Ok(match pipeline_stages {
+		Some(stages) => serde_json::from_str(stages)?,
+		None => PipelineStage::DEFAULT.to_vec(),
+	})
+}
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum PipelineStatus {
+	Running = 0,
+	Completed = 1,
+	/// one of its stages failed, and the ones after it didn't run
+	Failed = 2,
+}
+
+/// start_pipeline runs the stages a location is set to run, one after the other. A location whose
+/// pipeline is already running isn't scanned again until it's over.
+pub(super) async fn start_pipeline(
+	library: &LibraryContext,
+	location: &indexer_job_location::Data,
+) -> Result<(), LocationError> {
+	if library
+		.db
+		.location_pipeline()
+		.find_first(vec![
+			location_pipeline::location_id::equals(location.id),
+			location_pipeline::status::equals(PipelineStatus::Running.int_value()),
+		])
+		.exec()
+		.await?
+		.is_some()
+	{
+		info!("Pipeline of location {} is already running", location.id);
+		return Ok(());
+	}
+
+	let is_remote = location.network_remote.is_some() || location.storage_config.is_some();
+	let stages = PipelineStage::resolve(
+		&location_stages(location.pipeline_stages.as_deref())?,
+		is_remote,
+	);
+
+	let pipeline = library
+		.db
+		.location_pipeline()
+		.create_unchecked(location.id, serde_json::to_string(&stages)?, vec![])
+		.exec()
+		.await?;
+
+	let job = prepare_stage(library, &pipeline, 0).await?;
+	library.spawn_job(job).await;
+
+	Ok(())
+}
+
+/// prepare_stage returns the job of a stage of a pipeline, and records the pipeline is at it
+async fn prepare_stage(
+	library: &LibraryContext,
+	pipeline: &location_pipeline::Data,
+	stage_index: usize,
+) -> Result<Box<dyn DynJob>, LocationError> {
+	let stages: Vec<PipelineStage> = serde_json::from_str(&pipeline.stages)?;
+	let mut job = stages[stage_index]
+		.job(library, pipeline.location_id)
+		.await?;
+	let job_id = job
+		.report()
+		.as_ref()
+		.map(|report| report.id.as_bytes().to_vec());
+
+	library
+		.db
+		.location_pipeline()
+		.update(
+			location_pipeline::id::equals(pipeline.id),
+			vec![
+				location_pipeline::current_stage::set(stage_index as i32),
+				location_pipeline::job_id::set(job_id),
+				location_pipeline::date_modified::set(Utc::now().into()),
+			],
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.getPipeline");
+
+	Ok(job)
+}
+
+/// advance returns the job of the stage after the one a pipeline is at, or finishes the pipeline
+/// when that was its last stage
+async fn advance(
+	library: &LibraryContext,
+	pipeline: &location_pipeline::Data,
+) -> Result<Option<Box<dyn DynJob>>, LocationError> {
+	let stages: Vec<PipelineStage> = serde_json::from_str(&pipeline.stages)?;
+	let next_stage = pipeline.current_stage as usize + 1;
+	if next_stage < stages.len() {
+		return Ok(Some(prepare_stage(library, pipeline, next_stage).await?));
+	}
+
+	info!("Pipeline of location {} completed", pipeline.location_id);
+	set_status(
+		library,
+		pipeline,
+		PipelineStatus::Completed,
+		vec![location_pipeline::current_stage::set(stages.len() as i32)],
+	)
+	.await?;
+
+	Ok(None)
+}
+
+async fn set_status(
+	library: &LibraryContext,
+	pipeline: &location_pipeline::Data,
+	status: PipelineStatus,
+	mut params: Vec<location_pipeline::SetParam>,
+) -> Result<(), LocationError> {
+	params.push(location_pipeline::status::set(status.int_value()));
+	params.push(location_pipeline::job_id::set(None));
+	params.push(location_pipeline::date_modified::set(Utc::now().into()));
+
+	library
+		.db
+		.location_pipeline()
+		.update(location_pipeline::id::equals(pipeline.id), params)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.getPipeline");
+
+	Ok(())
+}
+
+/// on_job_stopped moves the pipeline a job was running a stage of along once the job stopped, and
+/// returns the job of its next stage, if there's one to run. A stage that failed stops the pipeline.
+pub(crate) async fn on_job_stopped(
+	library: &LibraryContext,
+	job_id: Uuid,
+) -> Result<Option<Box<dyn DynJob>>, LocationError> {
+	let pipeline = match library
+		.db
+		.location_pipeline()
+		.find_first(vec![
+			location_pipeline::job_id::equals(Some(job_id.as_bytes().to_vec())),
+			location_pipeline::status::equals(PipelineStatus::Running.int_value()),
+		])
+		.exec()
+		.await?
+	{
+		Some(pipeline) => pipeline,
+		None => return Ok(None),
+	};
+
+	let status = library
+		.db
+		.job()
+		.find_unique(job::id::equals(job_id.as_bytes().to_vec()))
+		.exec()
+		.await?
+		.and_then(|job| JobStatus::from_int(job.status).ok());
+
+	match status {
+		Some(JobStatus::Completed) => advance(library, &pipeline).await,
+		Some(JobStatus::Failed | JobStatus::DeadLetter | JobStatus::Canceled) => {
+			info!(
+				"Pipeline of location {} failed at stage {}",
+				pipeline.location_id, pipeline.current_stage
+			);
+			set_status(library, &pipeline, PipelineStatus::Failed, vec![]).await?;
+			Ok(None)
+		}
+		// paused jobs and jobs waiting on a decision go on later, with the same id
+		_ => Ok(None),
+	}
+}
+
+/// resume_pipelines picks up the pipelines that were running when the node shut down, running the
+/// stage that was queued again or the one after the stage that completed. Stages whose job was
+/// paused go on with it, once it's resumed.
+pub async fn resume_pipelines(library: &LibraryContext) -> Result<(), LocationError> {
+	for pipeline in library
+		.db
+		.location_pipeline()
+		.find_many(vec![location_pipeline::status::equals(
+			PipelineStatus::Running.int_value(),
+		)])
+		.exec()
+		.await?
+	{
+		let status = match &pipeline.job_id {
+			Some(job_id) => library
+				.db
+				.job()
+				.find_unique(job::id::equals(job_id.clone()))
+				.exec()
+				.await?
+				.and_then(|job| JobStatus::from_int(job.status).ok()),
+			None => None,
+		};
+
+		let job = match status {
+			// queued jobs only get a row once they start, so the stage never ran
+			None => Some(prepare_stage(library, &pipeline, pipeline.current_stage as usize).await?),
+			Some(JobStatus::Completed) => advance(library, &pipeline).await?,
+			Some(JobStatus::Failed | JobStatus::DeadLetter | JobStatus::Canceled) => {
+				set_status(library, &pipeline, PipelineStatus::Failed, vec![]).await?;
+				None
+			}
+			Some(_) => None,
+		};
+
+		if let Some(job) = job {
+			info!(
+				"Resuming pipeline of location {} at stage {}",
+				pipeline.location_id, pipeline.current_stage
+			);
+			library.spawn_job(job).await;
+		}
+	}
+
+	Ok(())
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageStatus {
+	Pending,
+	Running,
+	Completed,
+	Failed,
+	/// the location isn't set to run it
+	Skipped,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct PipelineStageReport {
+	pub stage: PipelineStage,
+	pub status: StageStatus,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct PipelineReport {
+	pub id: i32,
+	pub location_id: i32,
+	pub status: PipelineStatus,
+	/// every stage, with those the location skips
+	pub stages: Vec<PipelineStageReport>,
+	/// how much of the pipeline is done, from 0 to 1, the stage running counted by its tasks
+	pub progress: f64,
+	/// the job of the stage running, once it started
+	pub job: Option<JobReport>,
+	pub date_created: DateTime<FixedOffset>,
+	pub date_modified: DateTime<FixedOffset>,
+}
+
+/// pipeline_report returns the state of the last pipeline of a location, if it ever ran one
+pub async fn pipeline_report(
+	library: &LibraryContext,
+	jobs: &JobManager,
+	location_id: i32,
+) -> Result<Option<PipelineReport>, LocationError> {
+	let pipeline = match library
+		.db
+		.location_pipeline()
+		.find_first(vec![location_pipeline::location_id::equals(location_id)])
+		.order_by(location_pipeline::id::order(Direction::Desc))
+		.exec()
+		.await?
+	{
+		Some(pipeline) => pipeline,
+		None => return Ok(None),
+	};
+
+	let status = PipelineStatus::from_int(pipeline.status).unwrap_or(PipelineStatus::Failed);
+	let stages: Vec<PipelineStage> = serde_json::from_str(&pipeline.stages)?;
+	let current_stage = pipeline.current_stage as usize;
+
+	let job = match &pipeline.job_id {
+		Some(job_id) => match jobs
+			.get_running(library)
+			.await
+			.into_iter()
+			.find(|report| report.id.as_bytes()[..] == job_id[..])
+		{
+			Some(report) => Some(report),
+			None => library
+				.db
+				.job()
+				.find_unique(job::id::equals(job_id.clone()))
+				.exec()
+				.await?
+				.map(JobReport::from),
+		},
+		None => None,
+	};
+
+	let stage_progress = job
+		.as_ref()
+		.filter(|report| report.task_count > 0)
+		.map_or(0.0, |report| {
+			report.completed_task_count as f64 / report.task_count as f64
+		});
+	let progress = match status {
+		PipelineStatus::Completed => 1.0,
+		_ => (current_stage as f64 + stage_progress.min(1.0)) / stages.len().max(1) as f64,
+	};
+
+	Ok(Some(PipelineReport {
+		id: pipeline.id,
+		location_id,
+		status,
+		stages: PipelineStage::ALL
+			.iter()
+			.map(|stage| {
+				let status = match stages.iter().position(|s| s == stage) {
+					None => StageStatus::Skipped,
+					Some(index) if index < current_stage => StageStatus::Completed,
+					Some(index) if index > current_stage => StageStatus::Pending,
+					Some(_) => match status {
+						PipelineStatus::Running => StageStatus::Running,
+						PipelineStatus::Completed => StageStatus::Completed,
+						PipelineStatus::Failed => StageStatus::Failed,
+					},
+				};
+				PipelineStageReport {
+					stage: *stage,
+					status,
+				}
+			})
+			.collect(),
+		progress,
+		job,
+		date_created: pipeline.date_created,
+		date_modified: pipeline.date_modified,
+	}))
+}
+
+/// set_location_stages sets which stages a location's pipeline runs from its next scan on
+pub(super) async fn set_location_stages(
+	library: &LibraryContext,
+	location_id: i32,
+	current: Option<&str>,
+	stages: &[PipelineStage],
+) -> Result<(), LocationError> {
+	let stages = PipelineStage::resolve(stages, false);
+	if location_stages(current)? == stages {
+		return Ok(());
+	}
+
+	library
+		.db
+		.location()
+		.update(
+			location::id::equals(location_id),
+			vec![location::pipeline_stages::set(Some(serde_json::to_string(
+				&stages,
+			)?))],
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn stages_run_after_their_dependencies() {
+		assert_eq!(
+			PipelineStage::resolve(&[PipelineStage::Faces, PipelineStage::Thumbnails], false),
+			vec![
+				PipelineStage::Index,
+				PipelineStage::Identify,
+				PipelineStage::Thumbnails,
+				PipelineStage::Faces
+			]
+		);
+		assert_eq!(
+			PipelineStage::resolve(&[], false),
+			vec![PipelineStage::Index]
+		);
+		assert_eq!(
+			PipelineStage::resolve(&PipelineStage::DEFAULT, true),
+			vec![
+				PipelineStage::Index,
+				PipelineStage::Identify,
+				PipelineStage::Thumbnails
+			]
+		);
+	}
+}
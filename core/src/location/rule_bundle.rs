@@ -0,0 +1,539 @@
+//! A portable JSON format for the rules of a location, so they can be shared between libraries and
+//! with other people: its automation rules, which tag and move files as they show up, and the
+//! indexer rules that tell what's ignored. Nothing in a bundle refers to what only exists in one
+//! library, tags are named rather than referred to by id, and a bundle is validated as a whole before
+//! anything of it is imported.
+
+use std::collections::{HashMap, HashSet};
+
+use globset::Glob;
+use prisma_client_rust::{Direction, QueryError};
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	library::LibraryContext,
+	prisma::{automation_rule, indexer_rule, indexer_rules_in_location, tag},
+	sync::{
+		models::{uuid_from_pub_id, TagData, TAG},
+		SyncError,
+	},
+};
+
+use super::{
+	automation::{list_automations, AutomationAction, AutomationError, AutomationRule},
+	fetch_location,
+	indexer::{
+		rules::{IndexerRule, IndexerRuleCreateArgs, ParametersPerKind, RuleKind},
+		IndexerError,
+	},
+	LocationError,
+};
+
+/// The version of the format bundles are written in, bundles of other versions aren't imported
+pub const RULE_BUNDLE_FORMAT: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum RuleBundleError {
+	#[error("Invalid rules file: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("Rules file format {0} isn't supported, format {RULE_BUNDLE_FORMAT} is")]
+	UnsupportedFormat(u32),
+	#[error("Invalid rule '{0}': {1}")]
+	InvalidRule(String, String),
+	#[error("{0}")]
+	Automation(#[from] AutomationError),
+	#[error("{0}")]
+	Indexer(#[from] IndexerError),
+	#[error("{0}")]
+	Location(#[from] LocationError),
+	#[error("Database error: {0}")]
+	Database(#[from] QueryError),
+	#[error("Sync error: {0}")]
+	Sync(#[from] SyncError),
+}
+
+impl From<RuleBundleError> for rspc::Error {
+	fn from(err: RuleBundleError) -> Self {
+		match err {
+			RuleBundleError::Json(_)
+			| RuleBundleError::UnsupportedFormat(_)
+			| RuleBundleError::InvalidRule(..) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			RuleBundleError::Automation(err) => err.into(),
+			RuleBundleError::Indexer(err) => err.into(),
+			RuleBundleError::Location(err) => err.into(),
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// What an automation rule does to a file, with its tag named
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind")]
+pub enum PortableAction {
+	/// tags the file with the tag of this name, which is created with `color` if there's none
+	Tag {
+		tag: String,
+		color: Option<String>,
+	},
+	Move {
+		to: String,
+	},
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct PortableAutomation {
+	pub name: String,
+	pub glob: String,
+	pub actions: Vec<PortableAction>,
+	pub enabled: bool,
+}
+
+impl PortableAutomation {
+	fn validate(&self) -> Result<(), RuleBundleError> {
+		let invalid = |reason: String| RuleBundleError::InvalidRule(self.name.clone(), reason);
+
+		Glob::new(&self.glob).map_err(|e| invalid(e.to_string()))?;
+		if self.actions.is_empty() {
+			return Err(invalid("rules need at least one action".to_string()));
+		}
+		for action in &self.actions {
+			match action {
+				PortableAction::Tag { tag, .. } if tag.trim().is_empty() => {
+					return Err(invalid("tags need a name".to_string()));
+				}
+				PortableAction::Tag { .. } => {}
+				PortableAction::Move { to } => AutomationAction::Move { to: to.clone() }
+					.validate()
+					.map_err(|e| invalid(e.to_string()))?,
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// The parameters of an indexer rule: a glob for the rules by glob, directory names for the rules by
+/// children directories
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PortableParameters {
+	Glob(String),
+	Children(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct PortableIndexerRule {
+	pub name: String,
+	pub kind: RuleKind,
+	pub parameters: PortableParameters,
+}
+
+impl PortableIndexerRule {
+	fn validate(&self) -> Result<(), RuleBundleError> {
+		let invalid = |reason: &str| RuleBundleError::InvalidRule(self.name.clone(), reason.into());
+
+		match (self.kind, &self.parameters) {
+			(
+				RuleKind::AcceptFilesByGlob | RuleKind::RejectFilesByGlob,
+				PortableParameters::Glob(glob),
+			) => Glob::new(glob)
+				.map(|_| ())
+				.map_err(|e| invalid(&e.to_string())),
+			(
+				RuleKind::AcceptIfChildrenDirectoriesArePresent
+				| RuleKind::RejectIfChildrenDirectoriesArePresent,
+				PortableParameters::Children(children),
+			) => {
+				if children.is_empty() || children.iter().any(|child| child.trim().is_empty()) {
+					Err(invalid("rules by children directories need their names"))
+				} else {
+					Ok(())
+				}
+			}
+			(RuleKind::AcceptFilesByGlob | RuleKind::RejectFilesByGlob, _) => {
+				Err(invalid("rules by glob need a glob as their parameters"))
+			}
+			_ => Err(invalid(
+				"rules by children directories need a list of names as their parameters",
+			)),
+		}
+	}
+}
+
+impl From<&IndexerRule> for PortableIndexerRule {
+	fn from(rule: &IndexerRule) -> Self {
+		Self {
+			name: rule.name.clone(),
+			kind: rule.kind,
+			parameters: match &rule.parameters {
+				ParametersPerKind::AcceptFilesByGlob(glob)
+				| ParametersPerKind::RejectFilesByGlob(glob) => {
+					PortableParameters::Glob(glob.glob().to_string())
+				}
+				ParametersPerKind::AcceptIfChildrenDirectoriesArePresent(children)
+				| ParametersPerKind::RejectIfChildrenDirectoriesArePresent(children) => {
+					let mut children = children.iter().cloned().collect::<Vec<_>>();
+					children.sort();
+					PortableParameters::Children(children)
+				}
+			},
+		}
+	}
+}
+
+/// The rules of a location, as they're shared
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct RuleBundle {
+	pub format: u32,
+	#[serde(default)]
+	pub automations: Vec<PortableAutomation>,
+	/// the indexer rules, which tell what's ignored
+	#[serde(default)]
+	pub indexer_rules: Vec<PortableIndexerRule>,
+}
+
+impl RuleBundle {
+	/// parse reads a bundle from its JSON, and checks all of its rules are valid
+	pub fn parse(json: &str) -> Result<Self, RuleBundleError> {
+		let bundle = serde_json::from_str::<Self>(json)?;
+		if bundle.format != RULE_BUNDLE_FORMAT {
+			return Err(RuleBundleError::UnsupportedFormat(bundle.format));
+		}
+
+		bundle
+			.automations
+			.iter()
+			.try_for_each(PortableAutomation::validate)?;
+		bundle
+			.indexer_rules
+			.iter()
+			.try_for_each(PortableIndexerRule::validate)?;
+
+		Ok(bundle)
+	}
+}
+
+/// export_rules writes the rules of a location as a bundle, or every rule of the library when no
+/// location is given
+pub async fn export_rules(
+	library: &LibraryContext,
+	location_id: Option<i32>,
+) -> Result<String, RuleBundleError> {
+	let tags = library
+		.db
+		.tag()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|tag| (tag.id, tag))
+		.collect::<HashMap<_, _>>();
+
+	let mut automations = vec![];
+	for rule in list_automations(library, location_id).await? {
+		let actions = rule
+			.actions
+			.iter()
+			.filter_map(|action| match action {
+				AutomationAction::Tag { tag_id } => {
+					match tags.get(tag_id).and_then(|tag| tag.name.clone()) {
+						Some(name) => Some(PortableAction::Tag {
+							tag: name,
+							color: tags[tag_id].color.clone(),
+						}),
+						None => {
+							warn!(
+								"Leaving out the action of rule '{}' tagging with tag {}, it has no name",
+								rule.name, tag_id
+							);
+							None
+						}
+					}
+				}
+				AutomationAction::Move { to } => Some(PortableAction::Move { to: to.clone() }),
+			})
+			.collect::<Vec<_>>();
+		if actions.is_empty() {
+			continue;
+		}
+
+		automations.push(PortableAutomation {
+			name: rule.name,
+			glob: rule.glob,
+			actions,
+			enabled: rule.enabled,
+		});
+	}
+
+	let indexer_rules = library
+		.db
+		.indexer_rule()
+		.find_many(
+			location_id
+				.map(|location_id| {
+					vec![indexer_rule::locations::some(vec![
+						indexer_rules_in_location::location_id::equals(location_id),
+					])]
+				})
+				.unwrap_or_default(),
+		)
+		.order_by(indexer_rule::id::order(Direction::Asc))
+		.exec()
+		.await?
+		.iter()
+		.map(|data| IndexerRule::try_from(data).map(|rule| PortableIndexerRule::from(&rule)))
+		.collect::<Result<Vec<_>, _>>()?;
+
+	Ok(serde_json::to_string_pretty(&RuleBundle {
+		format: RULE_BUNDLE_FORMAT,
+		automations,
+		indexer_rules,
+	})?)
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct ImportRulesArgs {
+	/// the location the rules are added to
+	pub location_id: i32,
+	/// the bundle, as it was exported
+	pub bundle: String,
+}
+
+/// What importing a bundle added
+#[derive(Serialize, Type, Debug)]
+pub struct ImportedRules {
+	pub automations: Vec<AutomationRule>,
+	/// the indexer rules now on the location, including the ones the library already had
+	pub indexer_rule_ids: Vec<i32>,
+	/// the tags that were created for the rules, by name
+	pub created_tags: Vec<String>,
+}
+
+/// resolve_tag finds the tag named `name`, creating it if there's none, and returns its id and if it
+/// was created
+async fn resolve_tag(
+	library: &LibraryContext,
+	name: &str,
+	color: Option<String>,
+) -> Result<(i32, bool), RuleBundleError> {
+	if let Some(tag) = library
+		.db
+		.tag()
+		.find_first(vec![tag::name::equals(Some(name.to_string()))])
+		.exec()
+		.await?
+	{
+		return Ok((tag.id, false));
+	}
+
+	let tag = library
+		.db
+		.tag()
+		.create(
+			Uuid::new_v4().as_bytes().to_vec(),
+			vec![
+				tag::name::set(Some(name.to_string())),
+				tag::color::set(color),
+			],
+		)
+		.exec()
+		.await?;
+	library
+		.sync
+		.write_ops(vec![library.sync.shared_create(
+			TAG,
+			uuid_from_pub_id(&tag.pub_id),
+			&TagData::from(&tag),
+		)])
+		.await?;
+
+	Ok((tag.id, true))
+}
+
+/// import_rules adds the rules of a bundle to a location. Tags are found by name, and created when
+/// the library has none by that name. Indexer rules the library already has by the same kind and
+/// name are used as they are rather than added again.
+pub async fn import_rules(
+	library: &LibraryContext,
+	args: ImportRulesArgs,
+) -> Result<ImportedRules, RuleBundleError> {
+	// everything is checked before anything is added, so a bad bundle doesn't leave half of it behind
+	let bundle = RuleBundle::parse(&args.bundle)?;
+	if fetch_location(library, args.location_id)
+		.exec()
+		.await?
+		.is_none()
+	{
+		return Err(LocationError::IdNotFound(args.location_id).into());
+	}
+
+	let mut tag_ids = HashMap::new();
+	let mut created_tags = vec![];
+	let mut automations = vec![];
+	for automation in bundle.automations {
+		let mut actions = vec![];
+		for action in automation.actions {
+			actions.push(match action {
+				PortableAction::Tag { tag, color } => {
+					let tag_id = match tag_ids.get(&tag) {
+						Some(tag_id) => *tag_id,
+						None => {
+							let (tag_id, created) = resolve_tag(library, &tag, color).await?;
+							if created {
+								created_tags.push(tag.clone());
+							}
+							tag_ids.insert(tag, tag_id);
+							tag_id
+						}
+					};
+					AutomationAction::Tag { tag_id }
+				}
+				PortableAction::Move { to } => AutomationAction::Move { to },
+			});
+		}
+
+		automations.push(AutomationRule::try_from(
+			library
+				.db
+				.automation_rule()
+				.create_unchecked(
+					automation.name,
+					args.location_id,
+					automation.glob,
+					serde_json::to_string(&actions)?,
+					vec![automation_rule::enabled::set(automation.enabled)],
+				)
+				.exec()
+				.await?,
+		)?);
+	}
+
+	let existing = library
+		.db
+		.indexer_rule()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|rule| ((rule.kind, rule.name), rule.id))
+		.collect::<HashMap<_, _>>();
+	let mut indexer_rule_ids = vec![];
+	for rule in bundle.indexer_rules {
+		let id = match existing.get(&(rule.kind as i32, rule.name.clone())) {
+			Some(id) => *id,
+			None => {
+				IndexerRuleCreateArgs {
+					kind: rule.kind,
+					name: rule.name,
+					parameters: match rule.parameters {
+						PortableParameters::Glob(glob) => serde_json::to_vec(&glob)?,
+						PortableParameters::Children(children) => serde_json::to_vec(&children)?,
+					},
+				}
+				.create(library)
+				.await?
+				.id
+			}
+		};
+		if !indexer_rule_ids.contains(&id) {
+			indexer_rule_ids.push(id);
+		}
+	}
+
+	let linked = library
+		.db
+		.indexer_rules_in_location()
+		.find_many(vec![indexer_rules_in_location::location_id::equals(
+			args.location_id,
+		)])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|link| link.indexer_rule_id)
+		.collect::<HashSet<_>>();
+	library
+		.db
+		.indexer_rules_in_location()
+		.create_many(
+			indexer_rule_ids
+				.iter()
+				.filter(|id| !linked.contains(id))
+				.map(|id| {
+					indexer_rules_in_location::create_unchecked(args.location_id, *id, vec![])
+				})
+				.collect(),
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.automations.list");
+	invalidate_query!(library, "locations.getById");
+	if !created_tags.is_empty() {
+		invalidate_query!(library, "tags.list");
+	}
+
+	Ok(ImportedRules {
+		automations,
+		indexer_rule_ids,
+		created_tags,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn validates_bundles() {
+		let bundle = RuleBundle {
+			format: RULE_BUNDLE_FORMAT,
+			automations: vec![PortableAutomation {
+				name: "PDFs".to_string(),
+				glob: "Downloads/*.pdf".to_string(),
+				actions: vec![
+					PortableAction::Tag {
+						tag: "documents".to_string(),
+						color: None,
+					},
+					PortableAction::Move {
+						to: "Archive".to_string(),
+					},
+				],
+				enabled: true,
+			}],
+			indexer_rules: vec![PortableIndexerRule {
+				name: "No node_modules".to_string(),
+				kind: RuleKind::RejectIfChildrenDirectoriesArePresent,
+				parameters: PortableParameters::Children(vec!["node_modules".to_string()]),
+			}],
+		};
+		let json = serde_json::to_string(&bundle).unwrap();
+		assert_eq!(RuleBundle::parse(&json).unwrap(), bundle);
+
+		let with = |edit: fn(&mut RuleBundle)| {
+			let mut bundle = bundle.clone();
+			edit(&mut bundle);
+			RuleBundle::parse(&serde_json::to_string(&bundle).unwrap())
+		};
+		assert!(matches!(
+			with(|bundle| bundle.format = RULE_BUNDLE_FORMAT + 1),
+			Err(RuleBundleError::UnsupportedFormat(_))
+		));
+		assert!(with(|bundle| bundle.automations[0].glob = "*.{pdf".to_string()).is_err());
+		assert!(with(
+			|bundle| bundle.automations[0].actions[1] = PortableAction::Move {
+				to: "../Archive".to_string()
+			}
+		)
+		.is_err());
+		assert!(with(|bundle| bundle.indexer_rules[0].kind = RuleKind::RejectFilesByGlob).is_err());
+		assert!(RuleBundle::parse("{\"automations\": []}").is_err());
+	}
+}
@@ -0,0 +1,175 @@
+//! What indexing does in each location. The settings are columns of the location, read by the
+//! indexer, the identifier and the thumbnailer whenever they run in it, so a location of photos can
+//! get previews while one of code skips its dotfiles and one of backups has every file hashed.
+
+use globset::Glob;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::prisma::location;
+
+use super::indexer::{
+	indexer_job::indexer_job_location,
+	rules::{IndexerRule, ParametersPerKind, RuleKind},
+};
+
+/// Files and directories that are hidden on Unix, by having a name starting with a dot
+const HIDDEN_FILES_GLOB: &str = "**/.*";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type, PartialEq, Eq)]
+pub struct LocationSettings {
+	/// whether the files of the location get thumbnails and previews
+	pub generate_thumbnails: bool,
+	/// whether identified files get a checksum of their whole contents, on top of the cas_id made
+	/// from samples of them, so their integrity can be checked right away
+	pub hash_full_contents: bool,
+	/// files larger than this many bytes don't get their whole contents hashed
+	pub max_hash_size: Option<u64>,
+	/// whether files and directories whose name starts with a dot are indexed
+	pub index_hidden_files: bool,
+	/// whether the location keeps itself up to date: it's swept for changes when the node starts,
+	/// rescanned when its drive comes back and synced with its storage's change feed. Without it,
+	/// the location only changes when it's rescanned.
+	pub watch_changes: bool,
+	/// whether the files in the `.git` directories of repositories are identified, which code drives
+	/// are better off without as they're mostly git's own objects
+	pub hash_git_internals: bool,
+	/// files larger than this many bytes aren't read to be identified, they're identified by their
+	/// size and path only so they don't share an object with copies of them
+	pub max_identify_size: Option<u64>,
+	/// the extensions of the files that are identified by their size and path only, like the disk
+	/// images of virtual machines, which change all the time and are too big to be worth reading
+	pub unhashed_extensions: Vec<String>,
+}
+
+impl Default for LocationSettings {
+	fn default() -> Self {
+		Self {
+			generate_thumbnails: true,
+			hash_full_contents: false,
+			max_hash_size: None,
+			index_hidden_files: true,
+			watch_changes: true,
+			hash_git_internals: true,
+			max_identify_size: None,
+			unhashed_extensions: vec![],
+		}
+	}
+}
+
+macro_rules! impl_from_location {
+	($($data:ty),+) => {
+		$(impl From<&$data> for LocationSettings {
+			fn from(location: &$data) -> Self {
+				Self {
+					generate_thumbnails: location.generate_thumbnails,
+					hash_full_contents: location.hash_full_contents,
+					max_hash_size: location.max_hash_size.map(|size| size.max(0) as u64),
+					index_hidden_files: location.index_hidden_files,
+					watch_changes: location.watch_changes,
+					hash_git_internals: location.hash_git_internals,
+					max_identify_size: location.max_identify_size.map(|size| size.max(0) as u64),
+					unhashed_extensions: location
+						.unhashed_extensions
+						.as_deref()
+						.and_then(|extensions| serde_json::from_str(extensions).ok())
+						.unwrap_or_default(),
+				}
+			}
+		})+
+	};
+}
+
+impl_from_location!(location::Data, indexer_job_location::Data);
+
+impl LocationSettings {
+	/// params returns what to set on the location to save the settings
+	pub fn params(&self) -> Vec<location::SetParam> {
+		vec![
+			location::generate_thumbnails::set(self.generate_thumbnails),
+			location::hash_full_contents::set(self.hash_full_contents),
+			location::max_hash_size::set(
+				self.max_hash_size
+					.map(|size| size.min(i64::MAX as u64) as i64),
+			),
+			location::index_hidden_files::set(self.index_hidden_files),
+			location::watch_changes::set(self.watch_changes),
+			location::hash_git_internals::set(self.hash_git_internals),
+			location::max_identify_size::set(
+				self.max_identify_size
+					.map(|size| size.min(i64::MAX as u64) as i64),
+			),
+			location::unhashed_extensions::set(
+				(!self.unhashed_extensions.is_empty())
+					.then(|| serde_json::to_string(&self.unhashed_extensions).ok())
+					.flatten(),
+			),
+		]
+	}
+
+	/// hashes_fully tells if a file of `size` bytes in the location gets its whole contents hashed
+	pub fn hashes_fully(&self, size: u64) -> bool {
+		self.hash_full_contents && self.max_hash_size.map_or(true, |max_size| size <= max_size)
+	}
+
+	/// identifies_by_path tells if a file of `size` bytes with `extension` is identified by its
+	/// size and path instead of samples of its contents
+	pub fn identifies_by_path(&self, size: u64, extension: Option<&str>) -> bool {
+		self.max_identify_size
+			.map_or(false, |max_size| size > max_size)
+			|| extension.map_or(false, |extension| {
+				self.unhashed_extensions.iter().any(|unhashed| {
+					unhashed
+						.trim_start_matches('.')
+						.eq_ignore_ascii_case(extension)
+				})
+			})
+	}
+
+	/// hidden_files_rule returns the rule the indexer applies on top of the location's own rules to
+	/// leave hidden files out, when they aren't indexed
+	pub fn hidden_files_rule(&self) -> Option<IndexerRule> {
+		(!self.index_hidden_files).then(|| {
+			IndexerRule::new(
+				RuleKind::RejectFilesByGlob,
+				"Hidden files".to_string(),
+				ParametersPerKind::RejectFilesByGlob(
+					Glob::new(HIDDEN_FILES_GLOB).expect("the hidden files glob is valid"),
+				),
+			)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn hashes_fully_up_to_max_size() {
+		let settings = LocationSettings {
+			hash_full_contents: true,
+			max_hash_size: Some(1024),
+			..Default::default()
+		};
+
+		assert!(settings.hashes_fully(1024));
+		assert!(!settings.hashes_fully(1025));
+		assert!(!LocationSettings::default().hashes_fully(0));
+	}
+
+	#[test]
+	fn identifies_by_path_over_max_size_or_by_extension() {
+		let settings = LocationSettings {
+			max_identify_size: Some(1024),
+			unhashed_extensions: vec!["vmdk".to_string(), ".QCOW2".to_string()],
+			..Default::default()
+		};
+
+		assert!(!settings.identifies_by_path(1024, Some("png")));
+		assert!(settings.identifies_by_path(1025, None));
+		assert!(settings.identifies_by_path(0, Some("VMDK")));
+		assert!(settings.identifies_by_path(0, Some("qcow2")));
+		assert!(!LocationSettings::default().identifies_by_path(u64::MAX, Some("vmdk")));
+	}
+}
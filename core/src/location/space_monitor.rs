@@ -0,0 +1,377 @@
+//! Free space of the volumes backing a library's locations. Each of them is sampled every hour, and
+//! the samples are kept for a while to forecast when a volume fills up at the rate it's been filling.
+//! A [`LibraryEvent::SpaceAlert`] goes out when a volume drops below the node's [`SpaceThresholds`]
+//! or is forecast to run out soon, and when a copy to it wouldn't fit.
+
+use std::{
+	collections::{HashMap, HashSet},
+	path::Path,
+	time::Duration,
+};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use prisma_client_rust::Direction;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::{task::block_in_place, time::interval};
+use tracing::{error, warn};
+
+use crate::{
+	api::LibraryEvent,
+	invalidate_query,
+	library::LibraryContext,
+	prisma::{location, volume_space_sample},
+	volume::{
+		ensure_space, find_volume, get_volumes, volume_of, InsufficientSpace, Volume, VolumeError,
+	},
+};
+
+/// How often the volumes are sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long samples are kept
+const SAMPLE_RETENTION_DAYS: i64 = 90;
+/// How far back samples are looked at to forecast
+const FORECAST_WINDOW_DAYS: i64 = 14;
+/// How long the samples have to span before a forecast is made, so one large copy isn't taken for a
+/// trend
+const MIN_FORECAST_SPAN_HOURS: i64 = 24;
+const GB: u64 = 1024 * 1024 * 1024;
+
+/// SpaceThresholds is when volumes are alerted about for running out of space, set in the node config
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Type, PartialEq)]
+pub struct SpaceThresholds {
+	/// share of a volume that has to stay free, e.g. `0.05` for 5%
+	pub min_free_ratio: f64,
+	/// how many GB have to stay free whatever the size of the volume, none for no minimum
+	pub min_free_gb: Option<u32>,
+	/// how many days ahead a volume filling up is alerted about, none to not forecast
+	pub forecast_days: Option<u32>,
+}
+
+impl Default for SpaceThresholds {
+	fn default() -> Self {
+		Self {
+			min_free_ratio: 0.05,
+			min_free_gb: Some(5),
+			forecast_days: Some(14),
+		}
+	}
+}
+
+impl SpaceThresholds {
+	/// is_low tells if less of a volume is free than the thresholds allow
+	fn is_low(&self, total_bytes: u64, available_bytes: u64) -> bool {
+		let below_ratio =
+			total_bytes > 0 && (available_bytes as f64) < total_bytes as f64 * self.min_free_ratio;
+		let below_minimum = self.min_free_gb.map_or(false, |min_free_gb| {
+			available_bytes < min_free_gb as u64 * GB
+		});
+
+		below_ratio || below_minimum
+	}
+}
+
+/// Why a volume is alerted about
+#[derive(Debug, Clone, Serialize, Type, PartialEq)]
+#[serde(tag = "type")]
+pub enum SpaceAlertReason {
+	/// less of it is free than the thresholds allow
+	LowSpace,
+	/// it'll be full in `days_left` days at the rate it's been filling up
+	RunningOut { days_left: f64 },
+	/// something planned to be written to it needs more space than there is
+	WontFit { required_bytes: u64 },
+}
+
+/// volume_key is what a volume's samples are kept by
+fn volume_key(volume: &Volume) -> String {
+	volume
+		.serial
+		.clone()
+		.unwrap_or_else(|| volume.mount_point.clone())
+}
+
+/// forecast_days_left fits a line through a volume's free space over time, the oldest sample first,
+/// and returns in how many days it reaches zero. `None` when the volume isn't filling up, or the
+/// samples don't span long enough to tell.
+fn forecast_days_left(samples: &[(DateTime<Utc>, u64)]) -> Option<f64> {
+	let (first, last) = (samples.first()?, samples.last()?);
+	if last.0 - first.0 < ChronoDuration::hours(MIN_FORECAST_SPAN_HOURS) {
+		return None;
+	}
+
+	// seconds since the first sample against the bytes free then
+	let points = samples
+		.iter()
+		.map(|(date, available)| ((*date - first.0).num_seconds() as f64, *available as f64))
+		.collect::<Vec<_>>();
+	let count = points.len() as f64;
+	let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+	let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+	let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+		(
+			cov + (x - mean_x) * (y - mean_y),
+			var + (x - mean_x) * (x - mean_x),
+		)
+	});
+
+	// bytes freed per second, negative when the volume is filling up
+	let slope = covariance / variance;
+	if variance == 0.0 || slope >= 0.0 {
+		return None;
+	}
+
+	Some(last.1 as f64 / -slope / (24.0 * 60.0 * 60.0))
+}
+
+/// A sample of the free space of a volume
+#[derive(Serialize, Type, Debug)]
+pub struct SpaceSample {
+	pub total_bytes: u64,
+	pub available_bytes: u64,
+	pub date: DateTime<Utc>,
+}
+
+impl From<volume_space_sample::Data> for SpaceSample {
+	fn from(sample: volume_space_sample::Data) -> Self {
+		Self {
+			total_bytes: sample.total_bytes as u64,
+			available_bytes: sample.available_bytes as u64,
+			date: sample.date_created.into(),
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct SpaceHistory {
+	/// oldest first
+	pub samples: Vec<SpaceSample>,
+	/// in how many days the volume is forecast to be full, if it's filling up
+	pub days_left: Option<f64>,
+}
+
+/// space_history returns the samples of a volume, by its serial or mount point, since `days` ago
+pub async fn space_history(
+	library: &LibraryContext,
+	volume: String,
+	days: u32,
+) -> Result<SpaceHistory, VolumeError> {
+	let samples = library
+		.db
+		.volume_space_sample()
+		.find_many(vec![
+			volume_space_sample::volume::equals(volume),
+			volume_space_sample::date_created::gt(
+				(Utc::now() - ChronoDuration::days(days as i64)).into(),
+			),
+		])
+		.order_by(volume_space_sample::date_created::order(Direction::Asc))
+		.exec()
+		.await?
+		.into_iter()
+		.map(SpaceSample::from)
+		.collect::<Vec<_>>();
+
+	let window_start = Utc::now() - ChronoDuration::days(FORECAST_WINDOW_DAYS);
+	let days_left = forecast_days_left(
+		&samples
+			.iter()
+			.filter(|sample| sample.date > window_start)
+			.map(|sample| (sample.date, sample.available_bytes))
+			.collect::<Vec<_>>(),
+	);
+
+	Ok(SpaceHistory { samples, days_left })
+}
+
+/// sample_space records the free space of the volumes backing the library's locations on this node,
+/// and returns the ones to alert about
+async fn sample_space(
+	library: &LibraryContext,
+) -> Result<Vec<(Volume, SpaceAlertReason)>, VolumeError> {
+	let volumes = block_in_place(get_volumes)?;
+
+	let mut backing = HashMap::new();
+	for location in library
+		.db
+		.location()
+		.find_many(vec![
+			location::node_id::equals(library.node_local_id),
+			location::is_online::equals(true),
+		])
+		.exec()
+		.await?
+	{
+		if let Some((volume, _)) = location
+			.local_path
+			.as_ref()
+			.and_then(|path| find_volume(&volumes, Path::new(path)))
+		{
+			backing.insert(volume_key(volume), volume.clone());
+		}
+	}
+
+	library
+		.db
+		.volume_space_sample()
+		.create_many(
+			backing
+				.iter()
+				.map(|(key, volume)| {
+					volume_space_sample::create_unchecked(
+						key.clone(),
+						volume.mount_point.clone(),
+						volume.total_capacity as i64,
+						volume.available_capacity as i64,
+						vec![],
+					)
+				})
+				.collect(),
+		)
+		.exec()
+		.await?;
+
+	library
+		.db
+		.volume_space_sample()
+		.delete_many(vec![volume_space_sample::date_created::lt(
+			(Utc::now() - ChronoDuration::days(SAMPLE_RETENTION_DAYS)).into(),
+		)])
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "volumes.getSpaceHistory");
+
+	let thresholds = library.config().get().await.space_alerts;
+	let mut alerts = vec![];
+	for (key, volume) in backing {
+		if thresholds.is_low(volume.total_capacity, volume.available_capacity) {
+			alerts.push((volume, SpaceAlertReason::LowSpace));
+			continue;
+		}
+
+		if let Some(forecast_days) = thresholds.forecast_days {
+			match space_history(library, key, FORECAST_WINDOW_DAYS as u32)
+				.await?
+				.days_left
+			{
+				Some(days_left) if days_left <= forecast_days as f64 => {
+					alerts.push((volume, SpaceAlertReason::RunningOut { days_left }))
+				}
+				_ => {}
+			}
+		}
+	}
+
+	Ok(alerts)
+}
+
+fn emit_alert(library: &LibraryContext, volume: &Volume, reason: SpaceAlertReason) {
+	warn!(
+		"Volume {} at {} is running out of space, {} of {} bytes free: {:?}",
+		volume.name, volume.mount_point, volume.available_capacity, volume.total_capacity, reason
+	);
+	library.emit_event(LibraryEvent::SpaceAlert {
+		volume: volume_key(volume),
+		mount_point: volume.mount_point.clone(),
+		total_bytes: volume.total_capacity,
+		available_bytes: volume.available_capacity,
+		reason,
+	});
+}
+
+/// ensure_fits is [`ensure_space`] that also alerts about the volume when what's planned to be
+/// written to it doesn't fit
+pub fn ensure_fits(
+	library: &LibraryContext,
+	path: &Path,
+	required: u64,
+) -> Result<(), InsufficientSpace> {
+	ensure_space(path, required).map_err(|e| {
+		if let Some(volume) = volume_of(path) {
+			emit_alert(
+				library,
+				&volume,
+				SpaceAlertReason::WontFit {
+					required_bytes: required,
+				},
+			);
+		}
+		e
+	})
+}
+
+/// spawn_space_monitor samples the volumes backing the library's locations every hour, for as long
+/// as the library is loaded. A volume is alerted about when it starts running out of space, and
+/// again only once it has recovered in between.
+pub fn spawn_space_monitor(library: LibraryContext) {
+	tokio::spawn(async move {
+		let mut interval = interval(SAMPLE_INTERVAL);
+		let mut alerted = HashSet::new();
+
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {}
+				_ = library.stopped() => break,
+			}
+
+			if library.process_lock.is_read_only() {
+				continue;
+			}
+
+			match sample_space(&library).await {
+				Ok(alerts) => {
+					let running_out = alerts
+						.iter()
+						.map(|(volume, _)| volume_key(volume))
+						.collect::<HashSet<_>>();
+					for (volume, reason) in alerts {
+						if alerted.insert(volume_key(&volume)) {
+							emit_alert(&library, &volume, reason);
+						}
+					}
+					alerted.retain(|key| running_out.contains(key));
+				}
+				Err(e) => error!(
+					"Failed to sample the free space of library '{}': {e:#?}",
+					library.id
+				),
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn samples(free_gb_by_day: &[u64]) -> Vec<(DateTime<Utc>, u64)> {
+		let start = Utc::now();
+		free_gb_by_day
+			.iter()
+			.enumerate()
+			.map(|(day, free_gb)| (start + ChronoDuration::days(day as i64), free_gb * GB))
+			.collect()
+	}
+
+	#[test]
+	fn forecasts_volumes_filling_up() {
+		// 10 GB a day with 40 GB left
+		let days_left = forecast_days_left(&samples(&[100, 90, 80, 70, 60, 50, 40])).unwrap();
+		assert!((days_left - 4.0).abs() < 0.01);
+
+		assert_eq!(forecast_days_left(&samples(&[40, 50, 60])), None);
+		assert_eq!(forecast_days_left(&samples(&[40, 40, 40])), None);
+		// a single day isn't enough to tell
+		assert_eq!(forecast_days_left(&samples(&[40])), None);
+	}
+
+	#[test]
+	fn thresholds_apply_to_ratio_and_minimum() {
+		let thresholds = SpaceThresholds::default();
+		assert!(thresholds.is_low(1000 * GB, 40 * GB));
+		assert!(!thresholds.is_low(1000 * GB, 60 * GB));
+		// 5% of a small volume is less than the minimum
+		assert!(thresholds.is_low(64 * GB, 4 * GB));
+	}
+}
@@ -0,0 +1,382 @@
+use std::{ops::Range, path::Path};
+
+use chrono::{DateTime, Utc};
+use reqwest::{header, Client, RequestBuilder, Response};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use super::{
+	oauth::{OAuthConfig, TokenSource},
+	Storage, StorageChanges, StorageEntry, StorageError,
+};
+
+pub const DROPBOX_TOKEN_URL: &str = "https://api.dropboxapi.com/oauth2/token";
+const API_URL: &str = "https://api.dropboxapi.com/2";
+const CONTENT_URL: &str = "https://content.dropboxapi.com/2";
+
+/// DropboxConfig locates a folder in a Dropbox account
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct DropboxConfig {
+	/// e.g. `/Photos`, empty for the whole account
+	pub root: String,
+	pub oauth: OAuthConfig,
+}
+
+pub struct DropboxStorage {
+	config: DropboxConfig,
+	tokens: TokenSource,
+	client: Client,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+enum Metadata {
+	File {
+		path_display: String,
+		size: u64,
+		server_modified: DateTime<Utc>,
+		content_hash: Option<String>,
+	},
+	Folder {
+		path_display: String,
+	},
+	Deleted {
+		path_display: String,
+	},
+}
+
+#[derive(Deserialize, Debug)]
+struct ListFolderResult {
+	entries: Vec<Metadata>,
+	cursor: String,
+	has_more: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct CursorResult {
+	cursor: String,
+}
+
+impl DropboxStorage {
+	pub fn new(config: DropboxConfig, refresh_token: String) -> Self {
+		let client = Client::new();
+		Self {
+			tokens: TokenSource::new(
+				DROPBOX_TOKEN_URL,
+				config.oauth.clone(),
+				refresh_token,
+				client.clone(),
+			),
+			config,
+			client,
+		}
+	}
+
+	/// check makes sure the root folder can be listed
+	pub async fn check(&self) -> Result<(), StorageError> {
+		self.rpc::<ListFolderResult>(
+			"files/list_folder",
+			json!({ "path": self.config.root, "limit": 1 }),
+		)
+		.await
+		.map(|_| ())
+	}
+
+	fn remote_path(&self, path: &str) -> String {
+		format!("{}/{path}", self.config.root)
+	}
+
+	/// relative_path turns a path in the account into one relative to the root, `None` if it's outside
+	/// of it. Dropbox paths are case insensitive, so the root is matched without regard to case.
+	fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+		let root = &self.config.root;
+		match path.get(..root.len()) {
+			Some(prefix) if prefix.eq_ignore_ascii_case(root) => {
+				path[root.len()..].strip_prefix('/')
+			}
+			_ => None,
+		}
+	}
+
+	async fn send(&self, request: RequestBuilder) -> Result<Response, StorageError> {
+		let response = request
+			.bearer_auth(self.tokens.access_token().await?)
+			.send()
+			.await?;
+
+		let status = response.status();
+		if !status.is_success() {
+			let body = response.text().await.unwrap_or_default();
+			return Err(StorageError::Status(status.as_u16(), body));
+		}
+
+		Ok(response)
+	}
+
+	async fn rpc<T: for<'de> Deserialize<'de>>(
+		&self,
+		endpoint: &str,
+		args: serde_json::Value,
+	) -> Result<T, StorageError> {
+		let body = self
+			.send(
+				self.client
+					.post(format!("{API_URL}/{endpoint}"))
+					.header(header::CONTENT_TYPE, "application/json")
+					.body(args.to_string()),
+			)
+			.await?
+			.text()
+			.await?;
+
+		serde_json::from_str(&body).map_err(|e| StorageError::InvalidResponse(e.to_string()))
+	}
+
+	/// download_request builds a request for a file's content, its arguments going in a header
+	fn download_request(&self, path: &str) -> RequestBuilder {
+		self.client
+			.post(format!("{CONTENT_URL}/files/download"))
+			.header(
+				"Dropbox-API-Arg",
+				header_json(&json!({ "path": self.remote_path(path) })),
+			)
+	}
+
+	/// list_all reads a listing and all of its continuations
+	async fn list_all(&self, mut page: ListFolderResult) -> Result<ListFolderResult, StorageError> {
+		let mut entries = vec![];
+		loop {
+			entries.append(&mut page.entries);
+			if !page.has_more {
+				break;
+			}
+			page = self
+				.rpc(
+					"files/list_folder/continue",
+					json!({ "cursor": page.cursor }),
+				)
+				.await?;
+		}
+
+		Ok(ListFolderResult { entries, ..page })
+	}
+}
+
+#[async_trait::async_trait]
+impl Storage for DropboxStorage {
+	async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+		let first = self
+			.rpc(
+				"files/list_folder",
+				json!({ "path": self.config.root, "recursive": true }),
+			)
+			.await?;
+
+		Ok(self
+			.list_all(first)
+			.await?
+			.entries
+			.into_iter()
+			.filter_map(|metadata| match metadata {
+				Metadata::File {
+					path_display,
+					size,
+					server_modified,
+					content_hash,
+				} => Some(StorageEntry {
+					path: self.relative_path(&path_display)?.to_string(),
+					size,
+					modified: server_modified,
+					content_hash,
+				}),
+				_ => None,
+			})
+			.collect())
+	}
+
+	async fn stat(&self, path: &str) -> Result<StorageEntry, StorageError> {
+		match self
+			.rpc(
+				"files/get_metadata",
+				json!({ "path": self.remote_path(path) }),
+			)
+			.await?
+		{
+			Metadata::File {
+				size,
+				server_modified,
+				content_hash,
+				..
+			} => Ok(StorageEntry {
+				path: path.to_string(),
+				size,
+				modified: server_modified,
+				content_hash,
+			}),
+			_ => Err(StorageError::InvalidResponse(format!(
+				"'{path}' is not a file"
+			))),
+		}
+	}
+
+	async fn read_range(&self, path: &str, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+		if range.is_empty() {
+			return Ok(vec![]);
+		}
+
+		let request = self.download_request(path).header(
+			header::RANGE,
+			format!("bytes={}-{}", range.start, range.end - 1),
+		);
+
+		Ok(self.send(request).await?.bytes().await?.to_vec())
+	}
+
+	async fn download(&self, path: &str, target: &Path) -> Result<(), StorageError> {
+		let mut response = self.send(self.download_request(path)).await?;
+
+		let mut file = File::create(target).await?;
+		while let Some(chunk) = response.chunk().await? {
+			file.write_all(&chunk).await?;
+		}
+		file.flush().await?;
+
+		Ok(())
+	}
+
+	async fn cursor(&self) -> Result<Option<String>, StorageError> {
+		self.rpc::<CursorResult>(
+			"files/list_folder/get_latest_cursor",
+			json!({ "path": self.config.root, "recursive": true }),
+		)
+		.await
+		.map(|result| Some(result.cursor))
+	}
+
+	async fn changes(&self, cursor: &str) -> Result<StorageChanges, StorageError> {
+		let first = self
+			.rpc("files/list_folder/continue", json!({ "cursor": cursor }))
+			.await?;
+		let listing = self.list_all(first).await?;
+
+		// moves and renames show up as the old path being deleted and the new one added, for every
+		// entry under a moved folder, so the feed can always be applied path by path
+		let mut changes = StorageChanges {
+			cursor: listing.cursor,
+			..Default::default()
+		};
+		for metadata in listing.entries {
+			match metadata {
+				Metadata::File {
+					path_display,
+					size,
+					server_modified,
+					content_hash,
+				} => {
+					if let Some(path) = self.relative_path(&path_display) {
+						changes.upserted.push(StorageEntry {
+							path: path.to_string(),
+							size,
+							modified: server_modified,
+							content_hash,
+						});
+					}
+				}
+				Metadata::Folder { path_display } => {
+					if let Some(path) = self.relative_path(&path_display) {
+						changes.directories.push(path.to_string());
+					}
+				}
+				Metadata::Deleted { path_display } => {
+					if let Some(path) = self.relative_path(&path_display) {
+						changes.removed.push(path.to_string());
+					}
+				}
+			}
+		}
+
+		Ok(changes)
+	}
+}
+
+/// header_json serializes arguments for a header, where Dropbox wants anything outside of ASCII escaped
+fn header_json(value: &serde_json::Value) -> String {
+	value
+		.to_string()
+		.encode_utf16()
+		.map(|unit| match unit {
+			0x20..=0x7e => char::from(unit as u8).to_string(),
+			_ => format!("\\u{unit:04x}"),
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use uuid::Uuid;
+
+	#[test]
+	fn escapes_header_json() {
+		assert_eq!(
+			header_json(&json!({ "path": "/Fotos/Café ☕.jpg" })),
+			r#"{"path":"/Fotos/Caf\u00e9 \u2615.jpg"}"#
+		);
+	}
+
+	#[test]
+	fn makes_paths_relative() {
+		let storage = DropboxStorage::new(
+			DropboxConfig {
+				root: "/Photos".to_string(),
+				oauth: OAuthConfig {
+					client_id: "client".to_string(),
+					client_secret: None,
+					refresh_token: Uuid::nil(),
+				},
+			},
+			"token".to_string(),
+		);
+
+		assert_eq!(
+			storage.relative_path("/photos/2022/beach.jpg"),
+			Some("2022/beach.jpg")
+		);
+		assert_eq!(storage.relative_path("/Photos"), None);
+		assert_eq!(storage.relative_path("/Photoshop/file.psd"), None);
+	}
+
+	#[test]
+	fn parses_listing() {
+		let listing: ListFolderResult = serde_json::from_str(
+			r#"{
+				"entries": [
+					{ ".tag": "folder", "name": "2022", "path_lower": "/photos/2022", "path_display": "/Photos/2022", "id": "id:a" },
+					{
+						".tag": "file", "name": "beach.jpg", "path_lower": "/photos/2022/beach.jpg",
+						"path_display": "/Photos/2022/beach.jpg", "id": "id:b", "size": 1024,
+						"client_modified": "2022-11-18T10:00:00Z", "server_modified": "2022-11-18T10:00:01Z",
+						"rev": "a1c10ce0dd78", "content_hash": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+					},
+					{ ".tag": "deleted", "name": "old.jpg", "path_lower": "/photos/old.jpg", "path_display": "/Photos/old.jpg" }
+				],
+				"cursor": "cursor",
+				"has_more": false
+			}"#,
+		)
+		.unwrap();
+
+		assert_eq!(listing.entries.len(), 3);
+		assert!(matches!(
+			&listing.entries[1],
+			Metadata::File {
+				size: 1024,
+				content_hash: Some(_),
+				..
+			}
+		));
+		assert!(matches!(&listing.entries[2], Metadata::Deleted { .. }));
+	}
+}
@@ -0,0 +1,456 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	ops::Range,
+	path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::{header, Client, RequestBuilder, Response};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use super::{
+	oauth::{OAuthConfig, TokenSource},
+	Storage, StorageChanges, StorageEntry, StorageError,
+};
+
+pub const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const API_URL: &str = "https://www.googleapis.com/drive/v3";
+
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+/// Docs, Sheets and the like use mime types under this, they have no content of their own to download
+const NATIVE_MIME_TYPE_PREFIX: &str = "application/vnd.google-apps.";
+
+const FILE_FIELDS: &str = "id,name,mimeType,parents,trashed,size,md5Checksum,modifiedTime";
+
+/// GoogleDriveConfig locates a folder in a Google Drive
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct GoogleDriveConfig {
+	/// e.g. `Photos/2022`, empty for the whole of My Drive
+	pub root: String,
+	pub oauth: OAuthConfig,
+}
+
+/// GoogleDriveStorage maps Drive's folders to paths. Drive identifies files by id rather than by
+/// path, so paths are found by following the parents of files and looking up names in folders.
+pub struct GoogleDriveStorage {
+	config: GoogleDriveConfig,
+	tokens: TokenSource,
+	client: Client,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DriveFile {
+	id: String,
+	name: String,
+	#[serde(default)]
+	mime_type: String,
+	#[serde(default)]
+	parents: Vec<String>,
+	#[serde(default)]
+	trashed: bool,
+	/// int64 values are sent as strings
+	size: Option<String>,
+	md5_checksum: Option<String>,
+	modified_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FileList {
+	files: Vec<DriveFile>,
+	next_page_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Change {
+	#[serde(default)]
+	removed: bool,
+	file: Option<DriveFile>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChangeList {
+	changes: Vec<Change>,
+	next_page_token: Option<String>,
+	new_start_page_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StartPageToken {
+	start_page_token: String,
+}
+
+impl DriveFile {
+	fn is_folder(&self) -> bool {
+		self.mime_type == FOLDER_MIME_TYPE
+	}
+
+	fn has_content(&self) -> bool {
+		!self.mime_type.starts_with(NATIVE_MIME_TYPE_PREFIX)
+	}
+
+	fn entry(&self, path: String) -> Result<StorageEntry, StorageError> {
+		Ok(StorageEntry {
+			path,
+			size: self.size.as_deref().unwrap_or("0").parse().map_err(|_| {
+				StorageError::InvalidResponse(format!("invalid size of {}", self.id))
+			})?,
+			modified: self.modified_time.unwrap_or_else(Utc::now),
+			content_hash: self.md5_checksum.clone(),
+		})
+	}
+}
+
+impl GoogleDriveStorage {
+	pub fn new(config: GoogleDriveConfig, refresh_token: String) -> Self {
+		let client = Client::new();
+		Self {
+			tokens: TokenSource::new(
+				GOOGLE_TOKEN_URL,
+				config.oauth.clone(),
+				refresh_token,
+				client.clone(),
+			),
+			config,
+			client,
+		}
+	}
+
+	/// check makes sure the root folder can be found
+	pub async fn check(&self) -> Result<(), StorageError> {
+		self.root_id().await.map(|_| ())
+	}
+
+	async fn send(&self, request: RequestBuilder) -> Result<Response, StorageError> {
+		let response = request
+			.bearer_auth(self.tokens.access_token().await?)
+			.send()
+			.await?;
+
+		let status = response.status();
+		if !status.is_success() {
+			let body = response.text().await.unwrap_or_default();
+			return Err(StorageError::Status(status.as_u16(), body));
+		}
+
+		Ok(response)
+	}
+
+	async fn get<T: for<'de> Deserialize<'de>>(
+		&self,
+		url: &str,
+		query: &[(&str, &str)],
+	) -> Result<T, StorageError> {
+		let body = self
+			.send(self.client.get(url).query(query))
+			.await?
+			.text()
+			.await?;
+
+		serde_json::from_str(&body).map_err(|e| StorageError::InvalidResponse(e.to_string()))
+	}
+
+	async fn find(&self, query: &str) -> Result<Vec<DriveFile>, StorageError> {
+		let fields = format!("nextPageToken,files({FILE_FIELDS})");
+		let mut files = vec![];
+		let mut page_token = None;
+
+		loop {
+			let mut params = vec![
+				("q", query),
+				("fields", fields.as_str()),
+				("pageSize", "1000"),
+			];
+			if let Some(page_token) = &page_token {
+				params.push(("pageToken", page_token));
+			}
+
+			let mut page = self
+				.get::<FileList>(&format!("{API_URL}/files"), &params)
+				.await?;
+			files.append(&mut page.files);
+
+			match page.next_page_token {
+				Some(next) => page_token = Some(next),
+				None => break,
+			}
+		}
+
+		Ok(files)
+	}
+
+	async fn child(&self, folder_id: &str, name: &str) -> Result<Option<DriveFile>, StorageError> {
+		Ok(self
+			.find(&format!(
+				"'{folder_id}' in parents and name = '{}' and trashed = false",
+				query_escape(name)
+			))
+			.await?
+			.into_iter()
+			.next())
+	}
+
+	/// root_id finds the id of the location's root folder
+	async fn root_id(&self) -> Result<String, StorageError> {
+		let mut id = self
+			.get::<DriveFile>(&format!("{API_URL}/files/root"), &[("fields", "id,name")])
+			.await?
+			.id;
+
+		for name in self.config.root.split('/').filter(|name| !name.is_empty()) {
+			id = match self.child(&id, name).await? {
+				Some(folder) if folder.is_folder() => folder.id,
+				_ => {
+					return Err(StorageError::InvalidConfig(format!(
+						"folder '{}' not found",
+						self.config.root
+					)))
+				}
+			};
+		}
+
+		Ok(id)
+	}
+
+	/// resolve finds the file at `path`
+	async fn resolve(&self, path: &str) -> Result<DriveFile, StorageError> {
+		let not_found = || StorageError::InvalidResponse(format!("'{path}' not found"));
+
+		let mut file = None;
+		let mut folder_id = self.root_id().await?;
+		for name in path.split('/') {
+			let child = self.child(&folder_id, name).await?.ok_or_else(not_found)?;
+			folder_id = child.id.clone();
+			file = Some(child);
+		}
+
+		file.ok_or_else(not_found)
+	}
+
+	/// path_of follows the parents of a file up to the root, `None` if the file is outside of it.
+	/// Folders fetched along the way are kept in `folders` for the next files.
+	async fn path_of(
+		&self,
+		file: &DriveFile,
+		root_id: &str,
+		folders: &mut HashMap<String, DriveFile>,
+	) -> Result<Option<String>, StorageError> {
+		let mut names = vec![file.name.clone()];
+		let mut parent = file.parents.first().cloned();
+
+		while let Some(parent_id) = parent {
+			if parent_id == root_id {
+				names.reverse();
+				return Ok(Some(names.join("/")));
+			}
+
+			if !folders.contains_key(&parent_id) {
+				let folder = self
+					.get(
+						&format!("{API_URL}/files/{parent_id}"),
+						&[("fields", "id,name,parents")],
+					)
+					.await?;
+				folders.insert(parent_id.clone(), folder);
+			}
+
+			let folder = &folders[&parent_id];
+			names.push(folder.name.clone());
+			parent = folder.parents.first().cloned();
+		}
+
+		Ok(None)
+	}
+
+	fn media_request(&self, id: &str) -> RequestBuilder {
+		self.client
+			.get(format!("{API_URL}/files/{id}"))
+			.query(&[("alt", "media")])
+	}
+}
+
+#[async_trait::async_trait]
+impl Storage for GoogleDriveStorage {
+	async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+		let mut entries = vec![];
+		let mut to_list = VecDeque::from([(self.root_id().await?, String::new())]);
+
+		while let Some((folder_id, folder_path)) = to_list.pop_front() {
+			for file in self
+				.find(&format!("'{folder_id}' in parents and trashed = false"))
+				.await?
+			{
+				let path = match folder_path.as_str() {
+					"" => file.name.clone(),
+					folder_path => format!("{folder_path}/{}", file.name),
+				};
+
+				if file.is_folder() {
+					to_list.push_back((file.id, path));
+				} else if file.has_content() {
+					entries.push(file.entry(path)?);
+				}
+			}
+		}
+
+		Ok(entries)
+	}
+
+	async fn stat(&self, path: &str) -> Result<StorageEntry, StorageError> {
+		let file = self.resolve(path).await?;
+		if file.is_folder() || !file.has_content() {
+			return Err(StorageError::InvalidResponse(format!(
+				"'{path}' is not a file"
+			)));
+		}
+
+		file.entry(path.to_string())
+	}
+
+	async fn read_range(&self, path: &str, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+		if range.is_empty() {
+			return Ok(vec![]);
+		}
+
+		let file = self.resolve(path).await?;
+		let request = self.media_request(&file.id).header(
+			header::RANGE,
+			format!("bytes={}-{}", range.start, range.end - 1),
+		);
+
+		Ok(self.send(request).await?.bytes().await?.to_vec())
+	}
+
+	async fn download(&self, path: &str, target: &Path) -> Result<(), StorageError> {
+		let file = self.resolve(path).await?;
+		let mut response = self.send(self.media_request(&file.id)).await?;
+
+		let mut file = File::create(target).await?;
+		while let Some(chunk) = response.chunk().await? {
+			file.write_all(&chunk).await?;
+		}
+		file.flush().await?;
+
+		Ok(())
+	}
+
+	async fn cursor(&self) -> Result<Option<String>, StorageError> {
+		self.get::<StartPageToken>(&format!("{API_URL}/changes/startPageToken"), &[])
+			.await
+			.map(|token| Some(token.start_page_token))
+	}
+
+	/// Drive's feed says which files changed but not where they were before, so changes it can't
+	/// apply path by path ask for a rescan: files deleted for good, and folders, whose moves change
+	/// the path of everything in them. Files renamed or moved out of the location leave their old
+	/// path behind until the next rescan.
+	async fn changes(&self, cursor: &str) -> Result<StorageChanges, StorageError> {
+		let root_id = self.root_id().await?;
+		let fields =
+			format!("nextPageToken,newStartPageToken,changes(removed,file({FILE_FIELDS}))");
+
+		let mut changes = StorageChanges::default();
+		let mut folders = HashMap::new();
+		let mut page_token = cursor.to_string();
+
+		loop {
+			let page = self
+				.get::<ChangeList>(
+					&format!("{API_URL}/changes"),
+					&[
+						("pageToken", page_token.as_str()),
+						("fields", fields.as_str()),
+						("spaces", "drive"),
+						("pageSize", "1000"),
+					],
+				)
+				.await?;
+
+			for change in page.changes {
+				let file = match change.file {
+					Some(file) if !change.removed && !file.is_folder() => file,
+					_ => {
+						changes.rescan = true;
+						continue;
+					}
+				};
+
+				match self.path_of(&file, &root_id, &mut folders).await? {
+					Some(path) if file.trashed => changes.removed.push(path),
+					Some(path) if file.has_content() => changes.upserted.push(file.entry(path)?),
+					_ => {}
+				}
+			}
+
+			match (page.new_start_page_token, page.next_page_token) {
+				(Some(new_start_page_token), _) => {
+					changes.cursor = new_start_page_token;
+					break;
+				}
+				(None, Some(next_page_token)) => page_token = next_page_token,
+				(None, None) => {
+					return Err(StorageError::InvalidResponse(
+						"change list without a page token".to_string(),
+					))
+				}
+			}
+		}
+
+		Ok(changes)
+	}
+}
+
+/// query_escape escapes a value for a string literal in a Drive search query
+fn query_escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn escapes_queries() {
+		assert_eq!(query_escape(r"it's a\b"), r"it\'s a\\b");
+	}
+
+	#[test]
+	fn parses_changes() {
+		let changes: ChangeList = serde_json::from_str(
+			r#"{
+				"newStartPageToken": "42",
+				"changes": [
+					{
+						"removed": false,
+						"file": {
+							"id": "a", "name": "beach.jpg", "mimeType": "image/jpeg", "parents": ["root-id"],
+							"trashed": false, "size": "1024", "md5Checksum": "9b2cf535f27731c974343645a3985328",
+							"modifiedTime": "2022-11-18T10:00:00.000Z"
+						}
+					},
+					{ "removed": true }
+				]
+			}"#,
+		)
+		.unwrap();
+
+		assert_eq!(changes.new_start_page_token.as_deref(), Some("42"));
+		assert!(changes.changes[1].removed);
+
+		let file = changes.changes[0].file.as_ref().unwrap();
+		assert!(!file.is_folder() && file.has_content());
+
+		let entry = file.entry("beach.jpg".to_string()).unwrap();
+		assert_eq!(entry.size, 1024);
+		assert_eq!(
+			entry.content_hash.as_deref(),
+			Some("9b2cf535f27731c974343645a3985328")
+		);
+	}
+}
@@ -0,0 +1,656 @@
+use std::{
+	io,
+	ops::Range,
+	path::{Component, Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::interval;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+	api::LibraryEvent,
+	invalidate_query,
+	job::{TransferGovernor, TransferProvider, WorkerContext},
+	library::LibraryContext,
+	object::cas::sample_ranges,
+	prisma::{location, node},
+};
+
+use self::{
+	dropbox::{DropboxConfig, DropboxStorage, DROPBOX_TOKEN_URL},
+	google_drive::{GoogleDriveConfig, GoogleDriveStorage, GOOGLE_TOKEN_URL},
+	oauth::{exchange_code, OAuthConfig},
+	s3::{S3Config, S3Storage},
+	sftp::{SftpConfig, SftpStorage},
+	webdav::{WebDavConfig, WebDavStorage},
+};
+
+use super::{
+	fetch_location, indexer::indexer_job::indexer_job_location, link_location_and_indexer_rules,
+	store_secret, sync_storage_locations, LocationError,
+};
+
+pub mod dropbox;
+pub mod google_drive;
+pub mod oauth;
+pub mod s3;
+pub mod sftp;
+pub mod webdav;
+
+/// Directory in the node's data directory that files of storage locations are downloaded to while in use
+const STORAGE_CACHE_DIR_NAME: &str = "storage_cache";
+
+/// How often locations with a change feed are synced with it
+const STORAGE_SYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Every how many syncs the whole storage is listed instead, catching what the feeds can't tell
+const FULL_SYNC_EVERY: u32 = 288;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+	#[error("request failed: {0}")]
+	Request(#[from] reqwest::Error),
+	#[error("storage responded with {0}: {1}")]
+	Status(u16, String),
+	#[error("invalid response from storage: {0}")]
+	InvalidResponse(String),
+	#[error("invalid storage config: {0}")]
+	InvalidConfig(String),
+	#[error("I/O error: {0}")]
+	IO(#[from] io::Error),
+	#[error("remote command failed: {0}")]
+	Command(String),
+	#[error("key manager error: {0}")]
+	KeyManager(#[from] sd_crypto::Error),
+	#[error("storage doesn't support this")]
+	Unsupported,
+	#[error("path leads out of the location: {0}")]
+	InvalidPath(String),
+}
+
+/// StorageConfig is stored on locations whose files live in object storage rather than on a filesystem
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum StorageConfig {
+	S3(S3Config),
+	Sftp(SftpConfig),
+	GoogleDrive(GoogleDriveConfig),
+	Dropbox(DropboxConfig),
+	WebDav(WebDavConfig),
+}
+
+impl StorageConfig {
+	/// parse reads a location's `storage_config`, `None` meaning the location is on a filesystem
+	pub fn parse(storage_config: Option<&str>) -> Result<Option<Self>, StorageError> {
+		storage_config
+			.map(|config| {
+				serde_json::from_str(config).map_err(|e| StorageError::InvalidConfig(e.to_string()))
+			})
+			.transpose()
+	}
+
+	/// open connects to the storage, reading its secrets from the library's key manager
+	pub fn open(&self, library: &LibraryContext) -> Result<Box<dyn Storage>, StorageError> {
+		match self {
+			StorageConfig::S3(config) => {
+				let secret = library.key_manager.get_key(config.secret_key)?;
+				Ok(Box::new(S3Storage::new(
+					config.clone(),
+					String::from_utf8_lossy(secret.expose()).to_string(),
+				)))
+			}
+			StorageConfig::Sftp(config) => {
+				let private_key = library.key_manager.get_key(config.private_key)?;
+				Ok(Box::new(SftpStorage::new(
+					config.clone(),
+					&String::from_utf8_lossy(private_key.expose()),
+				)?))
+			}
+			StorageConfig::GoogleDrive(config) => {
+				let refresh_token = library.key_manager.get_key(config.oauth.refresh_token)?;
+				Ok(Box::new(GoogleDriveStorage::new(
+					config.clone(),
+					String::from_utf8_lossy(refresh_token.expose()).to_string(),
+				)))
+			}
+			StorageConfig::Dropbox(config) => {
+				let refresh_token = library.key_manager.get_key(config.oauth.refresh_token)?;
+				Ok(Box::new(DropboxStorage::new(
+					config.clone(),
+					String::from_utf8_lossy(refresh_token.expose()).to_string(),
+				)))
+			}
+			StorageConfig::WebDav(config) => {
+				let password = library.key_manager.get_key(config.password)?;
+				Ok(Box::new(WebDavStorage::new(
+					config.clone(),
+					String::from_utf8_lossy(password.expose()).to_string(),
+				)?))
+			}
+		}
+	}
+
+	/// open_in_job connects to the storage for a job, whose requests are held to the node's
+	/// [`TransferLimits`](crate::job::TransferLimits)
+	pub fn open_in_job(&self, ctx: &WorkerContext) -> Result<Box<dyn Storage>, StorageError> {
+		Ok(Box::new(GovernedStorage {
+			inner: self.open(&ctx.library_ctx())?,
+			provider: self.provider(),
+			transfers: ctx.transfers(),
+		}))
+	}
+
+	pub fn provider(&self) -> TransferProvider {
+		match self {
+			StorageConfig::S3(_) => TransferProvider::S3,
+			StorageConfig::Sftp(_) => TransferProvider::Sftp,
+			StorageConfig::GoogleDrive(_) => TransferProvider::GoogleDrive,
+			StorageConfig::Dropbox(_) => TransferProvider::Dropbox,
+			StorageConfig::WebDav(_) => TransferProvider::WebDav,
+		}
+	}
+
+	/// with_secret points the config at its secret once it's stored in the key manager
+	pub(crate) fn with_secret(self, uuid: Uuid) -> Self {
+		match self {
+			StorageConfig::S3(config) => StorageConfig::S3(S3Config {
+				secret_key: uuid,
+				..config
+			}),
+			StorageConfig::Sftp(config) => StorageConfig::Sftp(SftpConfig {
+				private_key: uuid,
+				..config
+			}),
+			StorageConfig::GoogleDrive(config) => StorageConfig::GoogleDrive(GoogleDriveConfig {
+				oauth: OAuthConfig {
+					refresh_token: uuid,
+					..config.oauth
+				},
+				..config
+			}),
+			StorageConfig::Dropbox(config) => StorageConfig::Dropbox(DropboxConfig {
+				oauth: OAuthConfig {
+					refresh_token: uuid,
+					..config.oauth
+				},
+				..config
+			}),
+			StorageConfig::WebDav(config) => StorageConfig::WebDav(WebDavConfig {
+				password: uuid,
+				..config
+			}),
+		}
+	}
+}
+
+/// A file in object storage, `path` being relative to the location's root and always using `/`
+#[derive(Debug, Clone)]
+pub struct StorageEntry {
+	pub path: String,
+	pub size: u64,
+	pub modified: DateTime<Utc>,
+	/// the storage's own content hash, if it has one that only depends on the content
+	pub content_hash: Option<String>,
+}
+
+/// `Storage` is implemented by the object storage services a location can live in.
+/// Files are listed for indexing and read on demand, they're never kept on the node. Backups
+/// upload to the storages that can be written to.
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+	/// list returns every file in the location
+	async fn list(&self) -> Result<Vec<StorageEntry>, StorageError>;
+
+	/// stat returns a single file's details
+	async fn stat(&self, path: &str) -> Result<StorageEntry, StorageError>;
+
+	/// read_range reads part of a file, without downloading the rest of it
+	async fn read_range(&self, path: &str, range: Range<u64>) -> Result<Vec<u8>, StorageError>;
+
+	/// download writes the whole file to `target`
+	async fn download(&self, path: &str, target: &Path) -> Result<(), StorageError>;
+
+	/// cursor marks the current state of the storage for [`Storage::changes`], `None` when it
+	/// doesn't keep a feed of changes and has to be listed again to find them
+	async fn cursor(&self) -> Result<Option<String>, StorageError> {
+		Ok(None)
+	}
+
+	/// changes returns what changed since `cursor`, along with the cursor to ask from next time
+	async fn changes(&self, _cursor: &str) -> Result<StorageChanges, StorageError> {
+		Err(StorageError::Unsupported)
+	}
+
+	/// get reads a whole file into memory, for small ones
+	async fn get(&self, path: &str) -> Result<Vec<u8>, StorageError> {
+		let size = self.stat(path).await?.size;
+		self.read_range(path, 0..size).await
+	}
+
+	/// put writes a whole file, replacing the one at `path` if there's one
+	async fn put(&self, _path: &str, _data: Vec<u8>) -> Result<(), StorageError> {
+		Err(StorageError::Unsupported)
+	}
+
+	/// delete removes a file, succeeding if it was already gone
+	async fn delete(&self, _path: &str) -> Result<(), StorageError> {
+		Err(StorageError::Unsupported)
+	}
+}
+
+/// GovernedStorage makes every request to a storage wait for the transfer window and a transfer slot
+/// of its provider, and throttles what's downloaded to the bandwidth caps
+struct GovernedStorage {
+	inner: Box<dyn Storage>,
+	provider: TransferProvider,
+	transfers: Arc<TransferGovernor>,
+}
+
+#[async_trait::async_trait]
+impl Storage for GovernedStorage {
+	async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+		let _permit = self.transfers.transfer(self.provider).await;
+		self.inner.list().await
+	}
+
+	async fn stat(&self, path: &str) -> Result<StorageEntry, StorageError> {
+		let _permit = self.transfers.transfer(self.provider).await;
+		self.inner.stat(path).await
+	}
+
+	async fn read_range(&self, path: &str, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+		let _permit = self.transfers.transfer(self.provider).await;
+		let bytes = self.inner.read_range(path, range).await?;
+		self.transfers
+			.throttle(self.provider, bytes.len() as u64)
+			.await;
+
+		Ok(bytes)
+	}
+
+	async fn download(&self, path: &str, target: &Path) -> Result<(), StorageError> {
+		let _permit = self.transfers.transfer(self.provider).await;
+		self.inner.download(path, target).await?;
+		// the slot is held while the download is paid for, so the next one waits its turn
+		let size = tokio::fs::metadata(target).await?.len();
+		self.transfers.throttle(self.provider, size).await;
+
+		Ok(())
+	}
+
+	async fn cursor(&self) -> Result<Option<String>, StorageError> {
+		let _permit = self.transfers.transfer(self.provider).await;
+		self.inner.cursor().await
+	}
+
+	async fn changes(&self, cursor: &str) -> Result<StorageChanges, StorageError> {
+		let _permit = self.transfers.transfer(self.provider).await;
+		self.inner.changes(cursor).await
+	}
+
+	async fn put(&self, path: &str, data: Vec<u8>) -> Result<(), StorageError> {
+		let _permit = self.transfers.transfer(self.provider).await;
+		let size = data.len() as u64;
+		self.inner.put(path, data).await?;
+		self.transfers.throttle(self.provider, size).await;
+
+		Ok(())
+	}
+
+	async fn delete(&self, path: &str) -> Result<(), StorageError> {
+		let _permit = self.transfers.transfer(self.provider).await;
+		self.inner.delete(path).await
+	}
+}
+
+/// What changed in a storage since a cursor, as returned by [`Storage::changes`]
+#[derive(Debug, Default)]
+pub struct StorageChanges {
+	/// files added or modified
+	pub upserted: Vec<StorageEntry>,
+	/// directories added, which may be empty
+	pub directories: Vec<String>,
+	/// files or directories removed, along with everything in them
+	pub removed: Vec<String>,
+	/// set when some changes couldn't be told path by path, so the location needs a full rescan
+	pub rescan: bool,
+	pub cursor: String,
+}
+
+/// storage_cas_id identifies a file in object storage without downloading it, by the storage's own
+/// content hash when it has one, or by reading just the ranges [`generate_cas_id`] samples otherwise.
+///
+/// [`generate_cas_id`]: crate::object::cas::generate_cas_id
+pub async fn storage_cas_id(
+	storage: &dyn Storage,
+	entry: &StorageEntry,
+) -> Result<String, StorageError> {
+	let mut hasher = blake3::Hasher::new();
+	hasher.update(&entry.size.to_le_bytes());
+
+	match &entry.content_hash {
+		// the storage already hashed the content, which is as good as sampling it and costs nothing
+		Some(content_hash) => {
+			hasher.update(content_hash.as_bytes());
+		}
+		None => {
+			for range in sample_ranges(entry.size) {
+				hasher.update(&storage.read_range(&entry.path, range).await?);
+			}
+		}
+	}
+
+	Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// is_relative_key tells if a path from a storage stays in the location once joined onto a local
+/// directory. Keys are only names to the storage, so they can hold `..` or start with a `/`.
+pub fn is_relative_key(path: &str) -> bool {
+	let mut components = Path::new(path).components().peekable();
+	components.peek().is_some()
+		&& components.all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// fetch_to_cache downloads a file of a storage location so it can be read like a local file,
+/// e.g. to generate its preview. The caller should remove it once done with it.
+pub async fn fetch_to_cache(
+	library: &LibraryContext,
+	storage: &dyn Storage,
+	location_id: i32,
+	path: &str,
+) -> Result<PathBuf, StorageError> {
+	if !is_relative_key(path) {
+		return Err(StorageError::InvalidPath(path.to_string()));
+	}
+
+	let target = library
+		.config()
+		.data_directory()
+		.join(STORAGE_CACHE_DIR_NAME)
+		.join(location_id.to_string())
+		.join(path);
+
+	if let Some(parent) = target.parent() {
+		tokio::fs::create_dir_all(parent).await?;
+	}
+
+	storage.download(path, &target).await?;
+
+	Ok(target)
+}
+
+/// spawn_storage_sync starts a task that keeps the library's cloud drive locations in sync with
+/// their change feeds, starting with whatever changed while the node wasn't running. A full sync
+/// runs about once a day. The task stops once the library is unloaded.
+pub fn spawn_storage_sync(library: LibraryContext) {
+	tokio::spawn(async move {
+		let mut interval = interval(STORAGE_SYNC_INTERVAL);
+
+		for tick in 1.. {
+			tokio::select! {
+				_ = interval.tick() => {}
+				_ = library.stopped() => break,
+			}
+
+			if library.process_lock.is_read_only() {
+				continue;
+			}
+
+			if let Err(e) = sync_storage_locations(&library, tick % FULL_SYNC_EVERY == 0).await {
+				error!(
+					"Failed to sync storage locations of library '{}': {e:#?}",
+					library.id
+				);
+			}
+		}
+	});
+}
+
+/// `StorageLocationCreateArgs` is the argument received from the client using `rspc` to add an
+/// object storage bucket, a directory on an SSH or WebDAV server or a cloud drive folder as a
+/// location
+#[derive(Type, Deserialize)]
+pub struct StorageLocationCreateArgs {
+	pub name: String,
+	pub storage: StorageCreateArgs,
+	pub indexer_rules_ids: Vec<i32>,
+}
+
+#[derive(Type, Deserialize)]
+#[serde(tag = "type")]
+pub enum StorageCreateArgs {
+	S3 {
+		endpoint: String,
+		region: String,
+		bucket: String,
+		prefix: String,
+		access_key_id: String,
+		secret_access_key: String,
+	},
+	Sftp {
+		host: String,
+		port: u16,
+		username: String,
+		root: String,
+		/// an OpenSSH private key, without a passphrase
+		private_key: String,
+	},
+	/// The cloud drives are authorized by the client, which passes on the code it got back along
+	/// with the PKCE verifier it made up for it
+	GoogleDrive {
+		root: String,
+		oauth: OAuthCodeArgs,
+	},
+	Dropbox {
+		root: String,
+		oauth: OAuthCodeArgs,
+	},
+	WebDav {
+		url: String,
+		username: String,
+		password: String,
+	},
+}
+
+#[derive(Type, Deserialize)]
+pub struct OAuthCodeArgs {
+	pub client_id: String,
+	pub client_secret: Option<String>,
+	pub code: String,
+	pub code_verifier: String,
+	pub redirect_uri: String,
+}
+
+impl OAuthCodeArgs {
+	/// exchange trades the code for a refresh token, returning it with the config to refresh it with
+	async fn exchange(self, token_url: &str) -> Result<(OAuthConfig, String), StorageError> {
+		let refresh_token = exchange_code(
+			&reqwest::Client::new(),
+			token_url,
+			&self.client_id,
+			self.client_secret.as_deref(),
+			&self.code,
+			&self.code_verifier,
+			&self.redirect_uri,
+		)
+		.await?;
+
+		Ok((
+			OAuthConfig {
+				client_id: self.client_id,
+				client_secret: self.client_secret,
+				refresh_token: Uuid::nil(),
+			},
+			refresh_token,
+		))
+	}
+}
+
+impl StorageCreateArgs {
+	/// connect makes sure the storage can be reached with the credentials given, returning its
+	/// config along with the secret to keep in the key manager
+	pub(crate) async fn connect(self) -> Result<(StorageConfig, String), StorageError> {
+		Ok(match self {
+			StorageCreateArgs::S3 {
+				endpoint,
+				region,
+				bucket,
+				prefix,
+				access_key_id,
+				secret_access_key,
+			} => {
+				let config = S3Config {
+					endpoint: endpoint.trim_end_matches('/').to_string(),
+					region,
+					bucket,
+					prefix: prefix.trim_matches('/').to_string(),
+					access_key_id,
+					secret_key: Uuid::nil(),
+				};
+				S3Storage::new(config.clone(), secret_access_key.clone())
+					.check()
+					.await?;
+
+				(StorageConfig::S3(config), secret_access_key)
+			}
+			StorageCreateArgs::Sftp {
+				host,
+				port,
+				username,
+				root,
+				private_key,
+			} => {
+				let config = SftpConfig {
+					host,
+					port,
+					username,
+					root: format!("/{}", root.trim_matches('/')),
+					private_key: Uuid::nil(),
+				};
+				SftpStorage::new(config.clone(), &private_key)?
+					.check()
+					.await?;
+
+				(StorageConfig::Sftp(config), private_key)
+			}
+			StorageCreateArgs::GoogleDrive { root, oauth } => {
+				let (oauth, refresh_token) = oauth.exchange(GOOGLE_TOKEN_URL).await?;
+				let config = GoogleDriveConfig {
+					root: root.trim_matches('/').to_string(),
+					oauth,
+				};
+				GoogleDriveStorage::new(config.clone(), refresh_token.clone())
+					.check()
+					.await?;
+
+				(StorageConfig::GoogleDrive(config), refresh_token)
+			}
+			StorageCreateArgs::Dropbox { root, oauth } => {
+				let (oauth, refresh_token) = oauth.exchange(DROPBOX_TOKEN_URL).await?;
+				let config = DropboxConfig {
+					// Dropbox wants the root of the account as an empty path
+					root: match root.trim_matches('/') {
+						"" => String::new(),
+						root => format!("/{root}"),
+					},
+					oauth,
+				};
+				DropboxStorage::new(config.clone(), refresh_token.clone())
+					.check()
+					.await?;
+
+				(StorageConfig::Dropbox(config), refresh_token)
+			}
+			StorageCreateArgs::WebDav {
+				url,
+				username,
+				password,
+			} => {
+				let config = WebDavConfig {
+					url: url.trim_end_matches('/').to_string(),
+					username,
+					password: Uuid::nil(),
+				};
+				WebDavStorage::new(config.clone(), password.clone())?
+					.check()
+					.await?;
+
+				(StorageConfig::WebDav(config), password)
+			}
+		})
+	}
+}
+
+impl StorageLocationCreateArgs {
+	pub async fn create(
+		self,
+		ctx: &LibraryContext,
+	) -> Result<indexer_job_location::Data, LocationError> {
+		// the secret goes in the key manager, so it needs to be unlocked
+		if !ctx.key_manager.has_master_password()? {
+			return Err(LocationError::KeyManagerLocked);
+		}
+
+		let (config, secret) = self.storage.connect().await?;
+		let config = config.with_secret(store_secret(ctx, secret).await?);
+
+		// the cursor is taken before the first scan lists the storage, so nothing changed in between
+		// is missed by the syncs that follow it
+		let cursor = config.open(ctx)?.cursor().await?;
+
+		let location = ctx
+			.db
+			.location()
+			.create(
+				Uuid::new_v4().as_bytes().to_vec(),
+				node::id::equals(ctx.node_local_id),
+				vec![
+					location::name::set(Some(self.name)),
+					location::is_online::set(true),
+					location::storage_config::set(Some(
+						serde_json::to_string(&config)
+							.map_err(|e| StorageError::InvalidConfig(e.to_string()))?,
+					)),
+					location::storage_cursor::set(cursor),
+				],
+			)
+			.exec()
+			.await?;
+
+		if !self.indexer_rules_ids.is_empty() {
+			link_location_and_indexer_rules(ctx, location.id, &self.indexer_rules_ids).await?;
+		}
+
+		invalidate_query!(ctx, "locations.list");
+		ctx.emit_event(LibraryEvent::LocationAdded {
+			location_id: location.id,
+		});
+
+		fetch_location(ctx, location.id)
+			.include(indexer_job_location::include())
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(location.id))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn keeps_keys_in_the_location() {
+		assert!(is_relative_key("photos/2022/beach.jpg"));
+		assert!(is_relative_key("notes.txt"));
+		assert!(!is_relative_key(""));
+		assert!(!is_relative_key("/etc/passwd"));
+		assert!(!is_relative_key("photos/../../../.ssh/authorized_keys"));
+		assert!(!is_relative_key("./notes.txt"));
+	}
+}
@@ -0,0 +1,145 @@
+use std::{
+	collections::HashMap,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use reqwest::{header, Client};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::StorageError;
+
+/// Access tokens are refreshed this long before they expire, so they don't run out mid request
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Access tokens by the uuid of the refresh token they came from. They only live in memory, as
+/// they're short lived and can always be refreshed.
+static ACCESS_TOKENS: Lazy<Mutex<HashMap<Uuid, (String, Instant)>>> = Lazy::new(Default::default);
+
+/// OAuthConfig is what's needed to get access tokens for a cloud provider's API
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct OAuthConfig {
+	pub client_id: String,
+	/// only some providers want one from installed apps, where it's not really a secret
+	pub client_secret: Option<String>,
+	/// uuid of the refresh token in the library's key manager
+	pub refresh_token: Uuid,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+	access_token: String,
+	expires_in: u64,
+	refresh_token: Option<String>,
+}
+
+/// TokenSource hands out access tokens for requests, refreshing them when they expire
+pub struct TokenSource {
+	token_url: &'static str,
+	config: OAuthConfig,
+	refresh_token: String,
+	client: Client,
+}
+
+impl TokenSource {
+	pub fn new(
+		token_url: &'static str,
+		config: OAuthConfig,
+		refresh_token: String,
+		client: Client,
+	) -> Self {
+		Self {
+			token_url,
+			config,
+			refresh_token,
+			client,
+		}
+	}
+
+	pub async fn access_token(&self) -> Result<String, StorageError> {
+		if let Some((token, expires_at)) = ACCESS_TOKENS
+			.lock()
+			.unwrap()
+			.get(&self.config.refresh_token)
+		{
+			if Instant::now() + EXPIRY_MARGIN < *expires_at {
+				return Ok(token.clone());
+			}
+		}
+
+		let mut params = vec![
+			("grant_type", "refresh_token"),
+			("refresh_token", self.refresh_token.as_str()),
+			("client_id", self.config.client_id.as_str()),
+		];
+		if let Some(client_secret) = &self.config.client_secret {
+			params.push(("client_secret", client_secret.as_str()));
+		}
+
+		let response = request_token(&self.client, self.token_url, &params).await?;
+
+		ACCESS_TOKENS.lock().unwrap().insert(
+			self.config.refresh_token,
+			(
+				response.access_token.clone(),
+				Instant::now() + Duration::from_secs(response.expires_in),
+			),
+		);
+
+		Ok(response.access_token)
+	}
+}
+
+/// exchange_code finishes an authorization code flow with PKCE started by the client, returning
+/// the refresh token to keep. The provider must have been asked for offline access.
+pub async fn exchange_code(
+	client: &Client,
+	token_url: &str,
+	client_id: &str,
+	client_secret: Option<&str>,
+	code: &str,
+	code_verifier: &str,
+	redirect_uri: &str,
+) -> Result<String, StorageError> {
+	let mut params = vec![
+		("grant_type", "authorization_code"),
+		("code", code),
+		("code_verifier", code_verifier),
+		("redirect_uri", redirect_uri),
+		("client_id", client_id),
+	];
+	if let Some(client_secret) = client_secret {
+		params.push(("client_secret", client_secret));
+	}
+
+	request_token(client, token_url, &params)
+		.await?
+		.refresh_token
+		.ok_or_else(|| {
+			StorageError::InvalidResponse("no refresh token, was offline access asked for?".into())
+		})
+}
+
+async fn request_token(
+	client: &Client,
+	token_url: &str,
+	params: &[(&str, &str)],
+) -> Result<TokenResponse, StorageError> {
+	let response = client
+		.post(token_url)
+		.header(header::ACCEPT, "application/json")
+		.form(params)
+		.send()
+		.await?;
+
+	let status = response.status();
+	let body = response.text().await?;
+	if !status.is_success() {
+		return Err(StorageError::Status(status.as_u16(), body));
+	}
+
+	serde_json::from_str(&body).map_err(|e| StorageError::InvalidResponse(e.to_string()))
+}
@@ -0,0 +1,465 @@
+use std::{ops::Range, path::Path};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header, Client, Method, RequestBuilder, Response, Url};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::AsyncWriteExt};
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{is_relative_key, Storage, StorageEntry, StorageError};
+
+/// S3 lets us skip hashing request bodies, which are only sent by uploads over TLS anyway
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// characters that are left as they are in urls, everything else is percent encoded
+const URI_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'-')
+	.remove(b'.')
+	.remove(b'_')
+	.remove(b'~');
+
+/// S3Config locates a bucket in Amazon S3 or any service speaking its API, like MinIO or R2
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct S3Config {
+	/// e.g. `https://s3.eu-west-1.amazonaws.com`, buckets are addressed by path
+	pub endpoint: String,
+	pub region: String,
+	pub bucket: String,
+	/// only objects under this prefix are part of the location, empty for the whole bucket
+	pub prefix: String,
+	pub access_key_id: String,
+	/// uuid of the secret access key in the library's key manager
+	pub secret_key: Uuid,
+}
+
+pub struct S3Storage {
+	config: S3Config,
+	secret_access_key: String,
+	client: Client,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+	#[serde(default)]
+	contents: Vec<ListedObject>,
+	is_truncated: bool,
+	next_continuation_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+	key: String,
+	last_modified: String,
+	#[serde(rename = "ETag")]
+	e_tag: String,
+	size: u64,
+}
+
+impl S3Storage {
+	pub fn new(config: S3Config, secret_access_key: String) -> Self {
+		Self {
+			config,
+			secret_access_key,
+			client: Client::new(),
+		}
+	}
+
+	/// check makes sure the bucket can be listed with the credentials we have
+	pub async fn check(&self) -> Result<(), StorageError> {
+		self.list_page(None, Some(1)).await.map(|_| ())
+	}
+
+	fn key(&self, path: &str) -> String {
+		match self.config.prefix.as_str() {
+			"" => path.to_string(),
+			prefix => format!("{prefix}/{path}"),
+		}
+	}
+
+	/// request builds a request signed with AWS Signature Version 4
+	fn request(
+		&self,
+		method: Method,
+		key: Option<&str>,
+		mut query: Vec<(String, String)>,
+	) -> Result<RequestBuilder, StorageError> {
+		let endpoint = Url::parse(&self.config.endpoint)
+			.map_err(|e| StorageError::InvalidConfig(e.to_string()))?;
+		let host = match (endpoint.host_str(), endpoint.port()) {
+			(Some(host), Some(port)) => format!("{host}:{port}"),
+			(Some(host), None) => host.to_string(),
+			_ => {
+				return Err(StorageError::InvalidConfig(format!(
+					"endpoint '{}' has no host",
+					self.config.endpoint
+				)))
+			}
+		};
+
+		let mut canonical_uri = format!("/{}", uri_encode(&self.config.bucket, false));
+		if let Some(key) = key {
+			canonical_uri.push('/');
+			canonical_uri.push_str(&uri_encode(key, true));
+		}
+
+		query.sort();
+		let canonical_query = query
+			.iter()
+			.map(|(name, value)| {
+				format!("{}={}", uri_encode(name, false), uri_encode(value, false))
+			})
+			.collect::<Vec<_>>()
+			.join("&");
+
+		let now = Utc::now();
+		let authorization = authorization(
+			&self.config,
+			&self.secret_access_key,
+			method.as_str(),
+			&canonical_uri,
+			&canonical_query,
+			&host,
+			now,
+		);
+
+		let mut url = format!(
+			"{}{canonical_uri}",
+			self.config.endpoint.trim_end_matches('/')
+		);
+		if !canonical_query.is_empty() {
+			url.push('?');
+			url.push_str(&canonical_query);
+		}
+
+		Ok(self
+			.client
+			.request(method, url)
+			.header("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string())
+			.header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+			.header(header::AUTHORIZATION, authorization))
+	}
+
+	async fn send(&self, request: RequestBuilder) -> Result<Response, StorageError> {
+		let response = request.send().await?;
+
+		let status = response.status();
+		if !status.is_success() {
+			let body = response.text().await.unwrap_or_default();
+			return Err(StorageError::Status(status.as_u16(), body));
+		}
+
+		Ok(response)
+	}
+
+	async fn list_page(
+		&self,
+		continuation_token: Option<String>,
+		max_keys: Option<u32>,
+	) -> Result<ListBucketResult, StorageError> {
+		let mut query = vec![("list-type".to_string(), "2".to_string())];
+		if !self.config.prefix.is_empty() {
+			query.push(("prefix".to_string(), format!("{}/", self.config.prefix)));
+		}
+		if let Some(token) = continuation_token {
+			query.push(("continuation-token".to_string(), token));
+		}
+		if let Some(max_keys) = max_keys {
+			query.push(("max-keys".to_string(), max_keys.to_string()));
+		}
+
+		let body = self
+			.send(self.request(Method::GET, None, query)?)
+			.await?
+			.text()
+			.await?;
+
+		quick_xml::de::from_str(&body).map_err(|e| StorageError::InvalidResponse(e.to_string()))
+	}
+
+	/// entry turns an object's key and details into an entry relative to the location
+	fn entry(
+		&self,
+		key: &str,
+		size: u64,
+		etag: &str,
+		modified: DateTime<Utc>,
+	) -> Option<StorageEntry> {
+		let path = match self.config.prefix.as_str() {
+			"" => key,
+			prefix => key.strip_prefix(prefix)?.strip_prefix('/')?,
+		};
+
+		// keys ending in a slash are the markers consoles create for empty folders
+		if path.is_empty() || path.ends_with('/') {
+			return None;
+		}
+		if !is_relative_key(path) {
+			warn!("Skipping object '{key}', its key leads out of the location");
+			return None;
+		}
+
+		let etag = etag.trim_matches('"');
+
+		Some(StorageEntry {
+			path: path.to_string(),
+			size,
+			modified,
+			// multipart uploads get an etag made from their parts' hashes, which depends on how the
+			// file was split up, so only single part etags (the content's md5) identify the content
+			content_hash: (!etag.is_empty() && !etag.contains('-')).then(|| etag.to_string()),
+		})
+	}
+}
+
+#[async_trait::async_trait]
+impl Storage for S3Storage {
+	async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+		let mut entries = vec![];
+		let mut continuation_token = None;
+
+		loop {
+			let page = self.list_page(continuation_token, None).await?;
+
+			for object in page.contents {
+				let modified = DateTime::parse_from_rfc3339(&object.last_modified)
+					.map(|modified| modified.with_timezone(&Utc))
+					.map_err(|e| StorageError::InvalidResponse(e.to_string()))?;
+
+				entries.extend(self.entry(&object.key, object.size, &object.e_tag, modified));
+			}
+
+			match page.next_continuation_token {
+				Some(token) if page.is_truncated => continuation_token = Some(token),
+				_ => break,
+			}
+		}
+
+		Ok(entries)
+	}
+
+	async fn stat(&self, path: &str) -> Result<StorageEntry, StorageError> {
+		let key = self.key(path);
+		let response = self
+			.send(self.request(Method::HEAD, Some(&key), vec![])?)
+			.await?;
+
+		let headers = response.headers();
+		let header = |name: header::HeaderName| {
+			headers
+				.get(&name)
+				.and_then(|value| value.to_str().ok())
+				.ok_or_else(|| StorageError::InvalidResponse(format!("missing {name} header")))
+		};
+
+		let size = header(header::CONTENT_LENGTH)?
+			.parse::<u64>()
+			.map_err(|e| StorageError::InvalidResponse(e.to_string()))?;
+		let modified = DateTime::parse_from_rfc2822(header(header::LAST_MODIFIED)?)
+			.map(|modified| modified.with_timezone(&Utc))
+			.map_err(|e| StorageError::InvalidResponse(e.to_string()))?;
+		let etag = header(header::ETAG).unwrap_or_default();
+
+		self.entry(&key, size, etag, modified)
+			.ok_or_else(|| StorageError::InvalidResponse(format!("'{path}' is not a file")))
+	}
+
+	async fn read_range(&self, path: &str, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+		if range.is_empty() {
+			return Ok(vec![]);
+		}
+
+		let request = self
+			.request(Method::GET, Some(&self.key(path)), vec![])?
+			.header(
+				header::RANGE,
+				format!("bytes={}-{}", range.start, range.end - 1),
+			);
+
+		Ok(self.send(request).await?.bytes().await?.to_vec())
+	}
+
+	async fn download(&self, path: &str, target: &Path) -> Result<(), StorageError> {
+		let mut response = self
+			.send(self.request(Method::GET, Some(&self.key(path)), vec![])?)
+			.await?;
+
+		let mut file = File::create(target).await?;
+		while let Some(chunk) = response.chunk().await? {
+			file.write_all(&chunk).await?;
+		}
+		file.flush().await?;
+
+		Ok(())
+	}
+
+	async fn put(&self, path: &str, data: Vec<u8>) -> Result<(), StorageError> {
+		let request = self
+			.request(Method::PUT, Some(&self.key(path)), vec![])?
+			.body(data);
+
+		self.send(request).await.map(|_| ())
+	}
+
+	async fn delete(&self, path: &str) -> Result<(), StorageError> {
+		// S3 answers deletes of keys that aren't there like any other
+		self.send(self.request(Method::DELETE, Some(&self.key(path)), vec![])?)
+			.await
+			.map(|_| ())
+	}
+}
+
+/// uri_encode percent encodes a value the way AWS expects in canonical requests
+fn uri_encode(value: &str, keep_slashes: bool) -> String {
+	let encoded = utf8_percent_encode(value, URI_ENCODE_SET).to_string();
+	if keep_slashes {
+		encoded.replace("%2F", "/")
+	} else {
+		encoded
+	}
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+	mac.update(data.as_bytes());
+	mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+	let date_key = hmac_sha256(format!("AWS4{secret_access_key}").as_bytes(), date);
+	let region_key = hmac_sha256(&date_key, region);
+	let service_key = hmac_sha256(&region_key, service);
+	hmac_sha256(&service_key, "aws4_request")
+}
+
+/// authorization computes the `Authorization` header value of a request at `now`
+fn authorization(
+	config: &S3Config,
+	secret_access_key: &str,
+	method: &str,
+	canonical_uri: &str,
+	canonical_query: &str,
+	host: &str,
+	now: DateTime<Utc>,
+) -> String {
+	let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+	let date = now.format("%Y%m%d").to_string();
+	let scope = format!("{date}/{}/s3/aws4_request", config.region);
+	let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+	let canonical_request = format!(
+		"{method}\n{canonical_uri}\n{canonical_query}\nhost:{host}\nx-amz-content-sha256:{UNSIGNED_PAYLOAD}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{UNSIGNED_PAYLOAD}"
+	);
+
+	let string_to_sign = format!(
+		"AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+		hex::encode(Sha256::digest(canonical_request.as_bytes()))
+	);
+
+	let signature = hex::encode(hmac_sha256(
+		&signing_key(secret_access_key, &date, &config.region, "s3"),
+		&string_to_sign,
+	));
+
+	format!(
+		"AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+		config.access_key_id
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn derives_signing_key() {
+		// example from the AWS documentation on deriving signing keys
+		assert_eq!(
+			hex::encode(signing_key(
+				"wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+				"20120215",
+				"us-east-1",
+				"iam"
+			)),
+			"f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+		);
+	}
+
+	#[test]
+	fn encodes_uris() {
+		assert_eq!(
+			uri_encode("photos/2022/a b+c.jpg", true),
+			"photos/2022/a%20b%2Bc.jpg"
+		);
+		assert_eq!(uri_encode("photos/", false), "photos%2F");
+	}
+
+	#[test]
+	fn parses_listing() {
+		let storage = S3Storage::new(
+			S3Config {
+				endpoint: "https://s3.example.com".to_string(),
+				region: "us-east-1".to_string(),
+				bucket: "bucket".to_string(),
+				prefix: "photos".to_string(),
+				access_key_id: "key".to_string(),
+				secret_key: Uuid::nil(),
+			},
+			"secret".to_string(),
+		);
+
+		let listing: ListBucketResult = quick_xml::de::from_str(
+			r#"<?xml version="1.0" encoding="UTF-8"?>
+			<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+				<Name>bucket</Name>
+				<Prefix>photos/</Prefix>
+				<KeyCount>3</KeyCount>
+				<IsTruncated>true</IsTruncated>
+				<NextContinuationToken>token</NextContinuationToken>
+				<Contents>
+					<Key>photos/</Key>
+					<LastModified>2022-11-18T10:00:00.000Z</LastModified>
+					<ETag>"d41d8cd98f00b204e9800998ecf8427e"</ETag>
+					<Size>0</Size>
+				</Contents>
+				<Contents>
+					<Key>photos/beach.jpg</Key>
+					<LastModified>2022-11-18T10:00:00.000Z</LastModified>
+					<ETag>"9b2cf535f27731c974343645a3985328"</ETag>
+					<Size>1024</Size>
+				</Contents>
+				<Contents>
+					<Key>photos/video.mp4</Key>
+					<LastModified>2022-11-18T10:00:00.000Z</LastModified>
+					<ETag>"3858f62230ac3c915f300c664312c11f-9"</ETag>
+					<Size>104857600</Size>
+				</Contents>
+			</ListBucketResult>"#,
+		)
+		.unwrap();
+
+		assert!(listing.is_truncated);
+		assert_eq!(listing.next_continuation_token.as_deref(), Some("token"));
+
+		let entries = listing
+			.contents
+			.iter()
+			.filter_map(|object| storage.entry(&object.key, object.size, &object.e_tag, Utc::now()))
+			.collect::<Vec<_>>();
+
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].path, "beach.jpg");
+		assert_eq!(
+			entries[0].content_hash.as_deref(),
+			Some("9b2cf535f27731c974343645a3985328")
+		);
+		assert_eq!(entries[1].path, "video.mp4");
+		assert_eq!(entries[1].content_hash, None);
+	}
+}
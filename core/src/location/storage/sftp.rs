@@ -0,0 +1,310 @@
+use std::{
+	fs::{self, OpenOptions},
+	io::Write,
+	ops::Range,
+	path::{Path, PathBuf},
+	process::{Command, Output, Stdio},
+	time::Duration,
+};
+
+use chrono::{TimeZone, Utc};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::task::block_in_place;
+use uuid::Uuid;
+
+use super::{Storage, StorageEntry, StorageError};
+
+/// Directory in the system's temporary directory holding connection sockets and private keys in use
+const SFTP_RUNTIME_DIR_NAME: &str = "spacedrive-sftp";
+
+/// How long an idle connection to a server is kept open for the next request to reuse
+const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the server is pinged while connected, and how many missed pings drop the connection
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const KEEPALIVE_COUNT_MAX: u32 = 3;
+
+/// SftpConfig locates a directory on a server reachable over SSH
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct SftpConfig {
+	pub host: String,
+	pub port: u16,
+	pub username: String,
+	/// absolute path of the directory on the server
+	pub root: String,
+	/// uuid of the private key in the library's key manager
+	pub private_key: Uuid,
+}
+
+/// SftpStorage talks to the server through the system's OpenSSH client. Requests to the same server
+/// share a single multiplexed connection, which is kept alive between them.
+pub struct SftpStorage {
+	config: SftpConfig,
+	runtime_dir: PathBuf,
+	/// the private key, written out for the ssh client for as long as the storage is open
+	identity: PathBuf,
+}
+
+impl SftpStorage {
+	pub fn new(config: SftpConfig, private_key: &str) -> Result<Self, StorageError> {
+		let runtime_dir = std::env::temp_dir().join(SFTP_RUNTIME_DIR_NAME);
+		fs::create_dir_all(&runtime_dir)?;
+
+		let identity = runtime_dir.join(format!("{}-{}", config.private_key, Uuid::new_v4()));
+		write_identity(&identity, private_key)?;
+
+		Ok(Self {
+			config,
+			runtime_dir,
+			identity,
+		})
+	}
+
+	/// check makes sure we can log in and the root directory exists
+	pub async fn check(&self) -> Result<(), StorageError> {
+		self.exec(&format!("test -d {}", shell_quote(&self.config.root)))
+			.await
+			.map(|_| ())
+	}
+
+	fn remote_path(&self, path: &str) -> String {
+		format!("{}/{path}", self.config.root.trim_end_matches('/'))
+	}
+
+	fn destination(&self) -> String {
+		format!("{}@{}", self.config.username, self.config.host)
+	}
+
+	/// options are the ssh client options shared by `ssh` and `sftp`
+	fn options(&self) -> Vec<String> {
+		let mut options = vec![
+			"BatchMode=yes".to_string(),
+			"IdentitiesOnly=yes".to_string(),
+			"StrictHostKeyChecking=accept-new".to_string(),
+			format!("ServerAliveInterval={}", KEEPALIVE_INTERVAL.as_secs()),
+			format!("ServerAliveCountMax={KEEPALIVE_COUNT_MAX}"),
+		];
+
+		// the windows client doesn't support connection sharing, so it connects for every request
+		if !cfg!(target_os = "windows") {
+			options.extend([
+				"ControlMaster=auto".to_string(),
+				format!("ControlPath={}", self.runtime_dir.join("%C").display()),
+				format!("ControlPersist={}", CONNECTION_IDLE_TIMEOUT.as_secs()),
+			]);
+		}
+
+		options
+			.into_iter()
+			.flat_map(|option| ["-o".to_string(), option])
+			.collect()
+	}
+
+	/// exec runs a command on the server, returning what it wrote to stdout
+	async fn exec(&self, command: &str) -> Result<Vec<u8>, StorageError> {
+		let output = block_in_place(|| {
+			Command::new("ssh")
+				.args(self.options())
+				.arg("-i")
+				.arg(&self.identity)
+				.arg("-p")
+				.arg(self.config.port.to_string())
+				.arg(self.destination())
+				.arg("--")
+				.arg(command)
+				.stdin(Stdio::null())
+				.output()
+		})?;
+
+		check_output(&output)?;
+
+		Ok(output.stdout)
+	}
+}
+
+impl Drop for SftpStorage {
+	fn drop(&mut self) {
+		fs::remove_file(&self.identity).ok();
+	}
+}
+
+#[async_trait::async_trait]
+impl Storage for SftpStorage {
+	async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+		let listing = self
+			.exec(&format!(
+				"find {} -type f -printf '%s\\t%T@\\t%P\\0'",
+				shell_quote(&self.config.root)
+			))
+			.await?;
+
+		parse_listing(&listing)
+	}
+
+	async fn stat(&self, path: &str) -> Result<StorageEntry, StorageError> {
+		let listing = self
+			.exec(&format!(
+				"find {} -maxdepth 0 -type f -printf '%s\\t%T@\\t\\0'",
+				shell_quote(&self.remote_path(path))
+			))
+			.await?;
+
+		parse_listing(&listing)?
+			.into_iter()
+			.next()
+			.map(|entry| StorageEntry {
+				path: path.to_string(),
+				..entry
+			})
+			.ok_or_else(|| StorageError::InvalidResponse(format!("'{path}' is not a file")))
+	}
+
+	async fn read_range(&self, path: &str, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+		if range.is_empty() {
+			return Ok(vec![]);
+		}
+
+		self.exec(&format!(
+			"tail -c +{} {} | head -c {}",
+			range.start + 1,
+			shell_quote(&self.remote_path(path)),
+			range.end - range.start
+		))
+		.await
+	}
+
+	async fn download(&self, path: &str, target: &Path) -> Result<(), StorageError> {
+		let output = block_in_place(|| {
+			let mut child = Command::new("sftp")
+				.args(self.options())
+				.arg("-i")
+				.arg(&self.identity)
+				.arg("-P")
+				.arg(self.config.port.to_string())
+				.args(["-b", "-"])
+				.arg(self.destination())
+				.stdin(Stdio::piped())
+				.stdout(Stdio::null())
+				.stderr(Stdio::piped())
+				.spawn()?;
+
+			// stdin is closed once the command is written, ending the batch
+			child
+				.stdin
+				.take()
+				.expect("sftp was spawned with a piped stdin")
+				.write_all(
+					format!(
+						"get {} {}\n",
+						sftp_quote(&self.remote_path(path)),
+						sftp_quote(&target.to_string_lossy())
+					)
+					.as_bytes(),
+				)?;
+
+			child.wait_with_output()
+		})?;
+
+		check_output(&output)
+	}
+}
+
+fn check_output(output: &Output) -> Result<(), StorageError> {
+	if output.status.success() {
+		Ok(())
+	} else {
+		Err(StorageError::Command(
+			String::from_utf8_lossy(&output.stderr).trim().to_string(),
+		))
+	}
+}
+
+/// write_identity writes the private key where only we can read it, as the ssh client insists on
+fn write_identity(path: &Path, private_key: &str) -> Result<(), StorageError> {
+	let mut options = OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+	let mut file = options.open(path)?;
+	file.write_all(private_key.trim_end().as_bytes())?;
+	// the client refuses keys without a trailing newline
+	file.write_all(b"\n")?;
+
+	Ok(())
+}
+
+/// parse_listing reads the `size\tmtime\tpath\0` records our `find` commands print
+fn parse_listing(listing: &[u8]) -> Result<Vec<StorageEntry>, StorageError> {
+	String::from_utf8_lossy(listing)
+		.split('\0')
+		.filter(|record| !record.is_empty())
+		.map(|record| {
+			let invalid = || StorageError::InvalidResponse(format!("invalid listing '{record}'"));
+
+			let mut fields = record.splitn(3, '\t');
+			let (size, modified, path) = match (fields.next(), fields.next(), fields.next()) {
+				(Some(size), Some(modified), Some(path)) => (size, modified, path),
+				_ => return Err(invalid()),
+			};
+
+			let modified = modified.parse::<f64>().map_err(|_| invalid())?;
+
+			Ok(StorageEntry {
+				path: path.to_string(),
+				size: size.parse().map_err(|_| invalid())?,
+				modified: Utc
+					.timestamp_opt(
+						modified.trunc() as i64,
+						(modified.fract() * 1_000_000_000.0) as u32,
+					)
+					.single()
+					.ok_or_else(invalid)?,
+				content_hash: None,
+			})
+		})
+		.collect()
+}
+
+/// shell_quote quotes a value for the remote shell
+fn shell_quote(value: &str) -> String {
+	format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// sftp_quote quotes a value for an sftp batch command
+fn sftp_quote(value: &str) -> String {
+	format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn quotes_values() {
+		assert_eq!(
+			shell_quote("/home/me/it's here"),
+			"'/home/me/it'\\''s here'"
+		);
+		assert_eq!(sftp_quote("C:\\a \"b\""), "\"C:\\\\a \\\"b\\\"\"");
+	}
+
+	#[test]
+	fn parses_listing() {
+		let entries = parse_listing(
+			b"1024\t1668765600.5000000000\tphotos/beach.jpg\x000\t1668765600.0000000000\tempty file\0",
+		)
+		.unwrap();
+
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].path, "photos/beach.jpg");
+		assert_eq!(entries[0].size, 1024);
+		assert_eq!(entries[0].modified.timestamp(), 1668765600);
+		assert_eq!(entries[0].modified.timestamp_subsec_millis(), 500);
+		assert_eq!(entries[1].path, "empty file");
+		assert_eq!(entries[1].size, 0);
+
+		assert!(parse_listing(b"not a listing\0").is_err());
+	}
+}
@@ -0,0 +1,388 @@
+use std::{collections::HashSet, ops::Range, path::Path, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::{events::Event, Reader};
+use reqwest::{header, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncWriteExt};
+use uuid::Uuid;
+
+use super::{Storage, StorageEntry, StorageError};
+
+/// characters that are left as they are in path segments, everything else is percent encoded
+const SEGMENT_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'-')
+	.remove(b'.')
+	.remove(b'_')
+	.remove(b'~');
+
+/// The properties asked for when listing, the only ones we read
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+	<d:prop>
+		<d:resourcetype/>
+		<d:getcontentlength/>
+		<d:getlastmodified/>
+	</d:prop>
+</d:propfind>"#;
+
+/// WebDavConfig locates a directory on a WebDAV server, like Nextcloud, ownCloud or most NASes
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct WebDavConfig {
+	/// the url of the directory, e.g. `https://cloud.example.com/remote.php/dav/files/me/Photos`
+	pub url: String,
+	pub username: String,
+	/// uuid of the password in the library's key manager
+	pub password: Uuid,
+}
+
+pub struct WebDavStorage {
+	config: WebDavConfig,
+	password: String,
+	/// the url of the directory, always ending in a slash so paths are joined under it
+	root: Url,
+	client: Client,
+	/// directories known to be on the server, which aren't created again before uploads
+	directories: Mutex<HashSet<String>>,
+}
+
+/// A file or directory, as a PROPFIND response describes it
+#[derive(Debug, Default)]
+struct DavEntry {
+	href: String,
+	is_dir: bool,
+	size: u64,
+	modified: Option<DateTime<Utc>>,
+}
+
+impl WebDavStorage {
+	pub fn new(config: WebDavConfig, password: String) -> Result<Self, StorageError> {
+		let root = Url::parse(&format!("{}/", config.url.trim_end_matches('/')))
+			.map_err(|e| StorageError::InvalidConfig(e.to_string()))?;
+
+		Ok(Self {
+			config,
+			password,
+			root,
+			client: Client::new(),
+			directories: Mutex::new(HashSet::new()),
+		})
+	}
+
+	/// check makes sure we can log in and the directory exists
+	pub async fn check(&self) -> Result<(), StorageError> {
+		match self.propfind("", "0").await?.first() {
+			Some(entry) if entry.is_dir => Ok(()),
+			_ => Err(StorageError::InvalidConfig(format!(
+				"'{}' is not a directory",
+				self.config.url
+			))),
+		}
+	}
+
+	fn url(&self, path: &str) -> Result<Url, StorageError> {
+		let encoded = path
+			.split('/')
+			.map(|segment| utf8_percent_encode(segment, SEGMENT_ENCODE_SET).to_string())
+			.collect::<Vec<_>>()
+			.join("/");
+
+		self.root
+			.join(&encoded)
+			.map_err(|e| StorageError::InvalidConfig(e.to_string()))
+	}
+
+	fn request(&self, method: Method, path: &str) -> Result<RequestBuilder, StorageError> {
+		Ok(self
+			.client
+			.request(method, self.url(path)?)
+			.basic_auth(&self.config.username, Some(&self.password)))
+	}
+
+	async fn send(&self, request: RequestBuilder) -> Result<Response, StorageError> {
+		let response = request.send().await?;
+
+		let status = response.status();
+		if !status.is_success() {
+			let body = response.text().await.unwrap_or_default();
+			return Err(StorageError::Status(status.as_u16(), body));
+		}
+
+		Ok(response)
+	}
+
+	/// propfind describes the file or directory at `path`, and what's in it with a depth of 1
+	async fn propfind(&self, path: &str, depth: &str) -> Result<Vec<DavEntry>, StorageError> {
+		let request = self
+			.request(
+				Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method"),
+				path,
+			)?
+			.header("Depth", depth)
+			.header(header::CONTENT_TYPE, "application/xml")
+			.body(PROPFIND_BODY);
+
+		parse_multistatus(&self.send(request).await?.text().await?)
+	}
+
+	/// relative_path turns an href the server returned into a path relative to the root, none if
+	/// it's the root itself or outside of it
+	fn relative_path(&self, href: &str) -> Option<String> {
+		let url = self.root.join(href).ok()?;
+		let path = url.path().strip_prefix(self.root.path())?;
+		let path = percent_decode_str(path).decode_utf8().ok()?;
+		let path = path.trim_end_matches('/');
+
+		(!path.is_empty()).then(|| path.to_string())
+	}
+
+	/// create_parents makes the directories the file at `path` goes in, the ones not known to be
+	/// there already
+	async fn create_parents(&self, path: &str) -> Result<(), StorageError> {
+		let segments = path.split('/').collect::<Vec<_>>();
+		for end in 1..segments.len() {
+			let directory = segments[..end].join("/");
+			if self.directories.lock().unwrap().contains(&directory) {
+				continue;
+			}
+
+			let response = self
+				.request(
+					Method::from_bytes(b"MKCOL").expect("MKCOL is a valid method"),
+					&format!("{directory}/"),
+				)?
+				.send()
+				.await?;
+			// servers answer that the method isn't allowed on directories that already exist
+			let status = response.status();
+			if !status.is_success() && status != StatusCode::METHOD_NOT_ALLOWED {
+				let body = response.text().await.unwrap_or_default();
+				return Err(StorageError::Status(status.as_u16(), body));
+			}
+
+			self.directories.lock().unwrap().insert(directory);
+		}
+
+		Ok(())
+	}
+}
+
+#[async_trait::async_trait]
+impl Storage for WebDavStorage {
+	async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+		let mut entries = vec![];
+		// not every server allows an infinite depth, so directories are listed one at a time
+		let mut directories = vec![String::new()];
+
+		while let Some(directory) = directories.pop() {
+			let path = match directory.as_str() {
+				"" => String::new(),
+				directory => format!("{directory}/"),
+			};
+
+			for entry in self.propfind(&path, "1").await? {
+				let path = match self.relative_path(&entry.href) {
+					Some(path) if path != directory => path,
+					_ => continue,
+				};
+
+				if entry.is_dir {
+					directories.push(path);
+				} else {
+					entries.push(StorageEntry {
+						path,
+						size: entry.size,
+						modified: entry.modified.unwrap_or_else(Utc::now),
+						// etags of WebDAV servers don't only depend on the content
+						content_hash: None,
+					});
+				}
+			}
+		}
+
+		Ok(entries)
+	}
+
+	async fn stat(&self, path: &str) -> Result<StorageEntry, StorageError> {
+		match self.propfind(path, "0").await?.into_iter().next() {
+			Some(entry) if !entry.is_dir => Ok(StorageEntry {
+				path: path.to_string(),
+				size: entry.size,
+				modified: entry.modified.unwrap_or_else(Utc::now),
+				content_hash: None,
+			}),
+			_ => Err(StorageError::InvalidResponse(format!(
+				"'{path}' is not a file"
+			))),
+		}
+	}
+
+	async fn read_range(&self, path: &str, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+		if range.is_empty() {
+			return Ok(vec![]);
+		}
+
+		let request = self.request(Method::GET, path)?.header(
+			header::RANGE,
+			format!("bytes={}-{}", range.start, range.end - 1),
+		);
+		let response = self.send(request).await?;
+		let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+		let bytes = response.bytes().await?;
+
+		// servers that don't support ranges send the whole file
+		Ok(match partial {
+			true => bytes.to_vec(),
+			false => bytes
+				.get(range.start as usize..(range.end as usize).min(bytes.len()))
+				.unwrap_or_default()
+				.to_vec(),
+		})
+	}
+
+	async fn download(&self, path: &str, target: &Path) -> Result<(), StorageError> {
+		let mut response = self.send(self.request(Method::GET, path)?).await?;
+
+		let mut file = File::create(target).await?;
+		while let Some(chunk) = response.chunk().await? {
+			file.write_all(&chunk).await?;
+		}
+		file.flush().await?;
+
+		Ok(())
+	}
+
+	async fn put(&self, path: &str, data: Vec<u8>) -> Result<(), StorageError> {
+		self.create_parents(path).await?;
+		self.send(self.request(Method::PUT, path)?.body(data))
+			.await
+			.map(|_| ())
+	}
+
+	async fn delete(&self, path: &str) -> Result<(), StorageError> {
+		match self.send(self.request(Method::DELETE, path)?).await {
+			Err(StorageError::Status(404, _)) => Ok(()),
+			result => result.map(|_| ()),
+		}
+	}
+}
+
+/// parse_multistatus reads the entries of a PROPFIND response, whatever prefix the server gave the
+/// `DAV:` namespace
+fn parse_multistatus(body: &str) -> Result<Vec<DavEntry>, StorageError> {
+	let invalid = |e: quick_xml::Error| StorageError::InvalidResponse(e.to_string());
+
+	let mut reader = Reader::from_str(body);
+	reader.trim_text(true);
+
+	let mut buf = vec![];
+	let mut entries = vec![];
+	let mut entry: Option<DavEntry> = None;
+	// the element whose text comes next
+	let mut element = vec![];
+
+	loop {
+		match reader.read_event(&mut buf).map_err(invalid)? {
+			Event::Start(start) => match start.local_name() {
+				b"response" => entry = Some(DavEntry::default()),
+				b"collection" => entry.iter_mut().for_each(|entry| entry.is_dir = true),
+				name => element = name.to_vec(),
+			},
+			Event::Empty(empty) if empty.local_name() == b"collection" => {
+				entry.iter_mut().for_each(|entry| entry.is_dir = true)
+			}
+			Event::Text(text) => {
+				if let Some(entry) = &mut entry {
+					let text = text.unescape_and_decode(&reader).map_err(invalid)?;
+					match element.as_slice() {
+						b"href" => entry.href = text,
+						b"getcontentlength" => entry.size = text.parse().unwrap_or(0),
+						b"getlastmodified" => {
+							entry.modified = DateTime::parse_from_rfc2822(&text)
+								.ok()
+								.map(|modified| modified.with_timezone(&Utc))
+						}
+						_ => {}
+					}
+				}
+			}
+			Event::End(end) => {
+				if end.local_name() == b"response" {
+					entries.extend(entry.take());
+				}
+				element.clear();
+			}
+			Event::Eof => break,
+			_ => {}
+		}
+		buf.clear();
+	}
+
+	Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_multistatus() {
+		let storage = WebDavStorage::new(
+			WebDavConfig {
+				url: "https://cloud.example.com/dav/files/me/Photos".to_string(),
+				username: "me".to_string(),
+				password: Uuid::nil(),
+			},
+			"secret".to_string(),
+		)
+		.unwrap();
+
+		let entries = parse_multistatus(
+			r#"<?xml version="1.0"?>
+			<d:multistatus xmlns:d="DAV:">
+				<d:response>
+					<d:href>/dav/files/me/Photos/</d:href>
+					<d:propstat>
+						<d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+						<d:status>HTTP/1.1 200 OK</d:status>
+					</d:propstat>
+				</d:response>
+				<d:response>
+					<d:href>/dav/files/me/Photos/Summer%202022/</d:href>
+					<d:propstat>
+						<d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+						<d:status>HTTP/1.1 200 OK</d:status>
+					</d:propstat>
+				</d:response>
+				<d:response>
+					<d:href>/dav/files/me/Photos/beach.jpg</d:href>
+					<d:propstat>
+						<d:prop>
+							<d:resourcetype/>
+							<d:getcontentlength>1024</d:getcontentlength>
+							<d:getlastmodified>Fri, 18 Nov 2022 10:00:00 GMT</d:getlastmodified>
+						</d:prop>
+						<d:status>HTTP/1.1 200 OK</d:status>
+					</d:propstat>
+				</d:response>
+			</d:multistatus>"#,
+		)
+		.unwrap();
+
+		assert_eq!(entries.len(), 3);
+		assert!(entries[0].is_dir && entries[1].is_dir && !entries[2].is_dir);
+		assert_eq!(storage.relative_path(&entries[0].href), None);
+		assert_eq!(
+			storage.relative_path(&entries[1].href).as_deref(),
+			Some("Summer 2022")
+		);
+		assert_eq!(
+			storage.relative_path(&entries[2].href).as_deref(),
+			Some("beach.jpg")
+		);
+		assert_eq!(entries[2].size, 1024);
+		assert!(entries[2].modified.is_some());
+	}
+}
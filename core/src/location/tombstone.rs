@@ -0,0 +1,148 @@
+//! What disappeared from a location. Files the sweep or a storage sync find gone from disk are removed
+//! from the index as always, but leave a tombstone behind with what was known about them and when
+//! they were found gone, so accidental deletions on drives that are rarely looked at can be caught.
+
+use chrono::{DateTime, Duration, Utc};
+use prisma_client_rust::Direction;
+use rspc::Type;
+use serde::Deserialize;
+
+use crate::{
+	invalidate_query,
+	library::LibraryContext,
+	object::fs::delete_file_path_tree,
+	prisma::{file_path, file_path_tombstone},
+};
+
+/// How long tombstones are kept
+const TOMBSTONE_RETENTION_DAYS: i64 = 90;
+/// How many tombstones are written per statement
+const TOMBSTONE_BATCH_SIZE: usize = 100;
+/// How many tombstones are listed when no limit is asked for
+const DEFAULT_TOMBSTONES_LIMIT: i64 = 500;
+
+/// remove_vanished removes a file_path that's gone from disk from the index, along with everything in it
+/// if it's a directory, leaving a tombstone for each of them. Returns how many were removed.
+pub async fn remove_vanished(
+	library: &LibraryContext,
+	location_id: i32,
+	materialized_path: &str,
+) -> Result<usize, prisma_client_rust::QueryError> {
+	let mut file_paths = Vec::new();
+	for path_param in [
+		file_path::materialized_path::equals(materialized_path.to_string()),
+		file_path::materialized_path::starts_with(format!("{materialized_path}/")),
+	] {
+		file_paths.extend(
+			library
+				.db
+				.file_path()
+				.find_many(vec![
+					file_path::location_id::equals(location_id),
+					path_param,
+				])
+				.with(file_path::object::fetch())
+				.exec()
+				.await?,
+		);
+	}
+
+	let mut tombstones = file_paths
+		.iter()
+		.map(|file_path| {
+			let object = file_path.object().ok().flatten();
+			file_path_tombstone::create_unchecked(
+				location_id,
+				file_path.id,
+				file_path.materialized_path.clone(),
+				file_path.name.clone(),
+				file_path.is_dir,
+				file_path.date_modified,
+				vec![
+					file_path_tombstone::extension::set(file_path.extension.clone()),
+					file_path_tombstone::cas_id::set(object.map(|object| object.cas_id.clone())),
+					file_path_tombstone::size_in_bytes::set(
+						object.map(|object| object.size_in_bytes.clone()),
+					),
+				],
+			)
+		})
+		.peekable();
+	while tombstones.peek().is_some() {
+		library
+			.db
+			.file_path_tombstone()
+			.create_many(tombstones.by_ref().take(TOMBSTONE_BATCH_SIZE).collect())
+			.exec()
+			.await?;
+	}
+
+	delete_file_path_tree(library, location_id, materialized_path).await?;
+
+	library
+		.db
+		.file_path_tombstone()
+		.delete_many(vec![file_path_tombstone::date_deleted::lt(
+			(Utc::now() - Duration::days(TOMBSTONE_RETENTION_DAYS)).into(),
+		)])
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.recentlyDeleted");
+
+	Ok(file_paths.len())
+}
+
+#[derive(Type, Deserialize)]
+pub struct RecentlyDeletedArgs {
+	/// every location's when not given
+	pub location_id: Option<i32>,
+	/// only what was found gone after this, like the previous scan of the location
+	pub since: Option<DateTime<Utc>>,
+	pub limit: Option<i64>,
+}
+
+/// recently_deleted lists what was found gone from disk, the latest first
+pub async fn recently_deleted(
+	library: &LibraryContext,
+	args: RecentlyDeletedArgs,
+) -> Result<Vec<file_path_tombstone::Data>, prisma_client_rust::QueryError> {
+	let mut params = vec![];
+	if let Some(location_id) = args.location_id {
+		params.push(file_path_tombstone::location_id::equals(location_id));
+	}
+	if let Some(since) = args.since {
+		params.push(file_path_tombstone::date_deleted::gte(since.into()));
+	}
+
+	library
+		.db
+		.file_path_tombstone()
+		.find_many(params)
+		.order_by(file_path_tombstone::date_deleted::order(Direction::Desc))
+		.take(args.limit.unwrap_or(DEFAULT_TOMBSTONES_LIMIT))
+		.exec()
+		.await
+}
+
+/// clear_deleted forgets the tombstones of a location, or of every location when not given
+pub async fn clear_deleted(
+	library: &LibraryContext,
+	location_id: Option<i32>,
+) -> Result<(), prisma_client_rust::QueryError> {
+	library
+		.db
+		.file_path_tombstone()
+		.delete_many(
+			location_id
+				.map(file_path_tombstone::location_id::equals)
+				.into_iter()
+				.collect(),
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.recentlyDeleted");
+
+	Ok(())
+}
@@ -0,0 +1,265 @@
+//! Sizes of the directories of a location, for drawing treemaps. They're added up from the file paths
+//! once a location is indexed and its files identified, and kept in the `directory_size` table, so
+//! a treemap only reads the directories it shows.
+
+use std::{collections::HashMap, path::Path};
+
+use prisma_client_rust::{Direction, QueryError};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+	library::LibraryContext,
+	prisma::{directory_size, file_path, location},
+};
+
+use super::LocationError;
+
+/// How many file paths are read, and directory sizes written, at once
+const TREEMAP_CHUNK_SIZE: usize = 1000;
+/// How many levels of directories a treemap goes down when it isn't asked for a depth
+const DEFAULT_TREEMAP_DEPTH: u8 = 2;
+
+#[derive(Deserialize, Type, Debug)]
+pub struct TreemapArgs {
+	pub location_id: i32,
+	/// materialized path of the directory to start from, the root of the location if not given
+	pub path: Option<String>,
+	/// how many levels of directories below it to return
+	pub depth: Option<u8>,
+}
+
+/// A directory in a treemap. The bytes of the files directly in it are what's left of its bytes once
+/// the bytes of its directories are taken out.
+#[derive(Serialize, Type, Debug, PartialEq, Eq)]
+pub struct TreemapEntry {
+	pub path: String,
+	pub parent_path: Option<String>,
+	pub name: String,
+	pub bytes: u64,
+	pub files: u64,
+	pub directories: u64,
+}
+
+impl From<directory_size::Data> for TreemapEntry {
+	fn from(data: directory_size::Data) -> Self {
+		Self {
+			bytes: data.bytes.parse().unwrap_or(0),
+			files: data.files as u64,
+			directories: data.directories as u64,
+			path: data.materialized_path,
+			parent_path: data.parent_path,
+			name: data.name,
+		}
+	}
+}
+
+/// What's inside of a directory, counted up from the file paths in it
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Rollup {
+	bytes: u64,
+	files: i32,
+	directories: i32,
+}
+
+/// parent_paths returns the materialized paths of the directories a path is in, closest first and
+/// ending with the root of the location
+fn parent_paths(materialized_path: &str) -> impl Iterator<Item = &str> {
+	Path::new(materialized_path)
+		.ancestors()
+		.skip(1)
+		.map(|path| path.to_str().unwrap_or_default())
+}
+
+/// add_up adds a file path to the directories it's in. Files that weren't identified yet don't have
+/// a size, so they add nothing to the bytes.
+fn add_up(rollups: &mut HashMap<String, Rollup>, file_path: &file_path::Data) {
+	let bytes = match file_path.object().ok().flatten() {
+		Some(object) if !file_path.is_dir => object.size_in_bytes.parse().unwrap_or(0),
+		_ => 0,
+	};
+
+	if file_path.is_dir {
+		rollups
+			.entry(file_path.materialized_path.clone())
+			.or_default();
+	}
+
+	for parent_path in parent_paths(&file_path.materialized_path) {
+		let rollup = rollups.entry(parent_path.to_string()).or_default();
+		if file_path.is_dir {
+			rollup.directories += 1;
+		} else {
+			rollup.files += 1;
+			rollup.bytes += bytes;
+		}
+	}
+}
+
+/// compute_directory_sizes adds up the sizes of every directory in a location, replacing the ones
+/// computed before
+pub async fn compute_directory_sizes(
+	library: &LibraryContext,
+	location: &location::Data,
+) -> Result<usize, QueryError> {
+	let mut rollups = HashMap::from([(String::new(), Rollup::default())]);
+	let mut cursor = 0;
+
+	loop {
+		let file_paths = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(location.id),
+				file_path::id::gt(cursor),
+			])
+			.with(file_path::object::fetch())
+			.order_by(file_path::id::order(Direction::Asc))
+			.take(TREEMAP_CHUNK_SIZE as i64)
+			.exec()
+			.await?;
+
+		cursor = match file_paths.last() {
+			Some(file_path) => file_path.id,
+			None => break,
+		};
+		for file_path in &file_paths {
+			add_up(&mut rollups, file_path);
+		}
+	}
+
+	let location_name = location.name.clone().unwrap_or_default();
+	let rows = rollups
+		.into_iter()
+		.map(|(path, rollup)| {
+			let (parent_path, name) = match path.is_empty() {
+				true => (None, location_name.clone()),
+				false => (
+					parent_paths(&path).next().map(str::to_string),
+					Path::new(&path)
+						.file_name()
+						.map(|name| name.to_string_lossy().to_string())
+						.unwrap_or_default(),
+				),
+			};
+
+			directory_size::create_unchecked(
+				location.id,
+				path,
+				name,
+				rollup.bytes.to_string(),
+				rollup.files,
+				rollup.directories,
+				vec![directory_size::parent_path::set(parent_path)],
+			)
+		})
+		.collect::<Vec<_>>();
+	let count = rows.len();
+
+	library
+		.db
+		.directory_size()
+		.delete_many(vec![directory_size::location_id::equals(location.id)])
+		.exec()
+		.await?;
+
+	let mut rows = rows.into_iter().peekable();
+	while rows.peek().is_some() {
+		library
+			.db
+			.directory_size()
+			.create_many(rows.by_ref().take(TREEMAP_CHUNK_SIZE).collect())
+			.exec()
+			.await?;
+	}
+
+	info!(
+		"Computed the sizes of {count} directories in location {}",
+		location.id
+	);
+
+	Ok(count)
+}
+
+/// treemap returns a directory of a location and the directories below it, down to the depth asked
+/// for, each level ordered from the biggest directory
+pub async fn treemap(
+	library: &LibraryContext,
+	args: TreemapArgs,
+) -> Result<Vec<TreemapEntry>, LocationError> {
+	let location = library
+		.db
+		.location()
+		.find_unique(location::id::equals(args.location_id))
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(args.location_id))?;
+
+	// locations indexed before directory sizes were kept don't have any yet
+	let computed = library
+		.db
+		.directory_size()
+		.count(vec![directory_size::location_id::equals(location.id)])
+		.exec()
+		.await?;
+	if computed == 0 {
+		compute_directory_sizes(library, &location).await?;
+	}
+
+	let path = args.path.unwrap_or_default();
+	let root = library
+		.db
+		.directory_size()
+		.find_unique(directory_size::location_id_materialized_path(
+			location.id,
+			path.clone(),
+		))
+		.exec()
+		.await?
+		.ok_or_else(|| LocationError::PathNotFound(path.clone().into()))?;
+
+	let mut entries = vec![TreemapEntry::from(root)];
+	let mut parents = vec![path];
+	for _ in 0..args.depth.unwrap_or(DEFAULT_TREEMAP_DEPTH) {
+		if parents.is_empty() {
+			break;
+		}
+
+		let children = library
+			.db
+			.directory_size()
+			.find_many(vec![
+				directory_size::location_id::equals(location.id),
+				directory_size::parent_path::in_vec(parents),
+			])
+			.exec()
+			.await?;
+
+		let mut children = children
+			.into_iter()
+			.map(TreemapEntry::from)
+			.collect::<Vec<_>>();
+		children.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+
+		parents = children.iter().map(|child| child.path.clone()).collect();
+		entries.extend(children);
+	}
+
+	Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lists_parent_paths_up_to_the_root() {
+		assert_eq!(
+			parent_paths("photos/2022/beach.jpg").collect::<Vec<_>>(),
+			vec!["photos/2022", "photos", ""]
+		);
+		assert_eq!(parent_paths("photos").collect::<Vec<_>>(), vec![""]);
+		assert!(parent_paths("").next().is_none());
+	}
+}
@@ -0,0 +1,182 @@
+use std::{path::Path, time::Duration};
+
+use chrono::Utc;
+use tokio::{task::block_in_place, time::interval};
+use tracing::{debug, error, info};
+
+use crate::{
+	api::LibraryEvent,
+	invalidate_query,
+	library::LibraryContext,
+	prisma::{location, node},
+	volume::{find_volume, get_volumes, Volume},
+};
+
+use super::{
+	fetch_location, indexer::indexer_job::indexer_job_location, network, scan_location,
+	LocationError,
+};
+
+/// How often the node's volumes are checked for locations coming online or going offline
+const VOLUME_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// volume_params returns the fingerprint of the volume `path` is on, to be stored on its location
+pub fn volume_params(volumes: &[Volume], path: &Path) -> Vec<location::SetParam> {
+	match find_volume(volumes, path) {
+		Some((volume, _)) => vec![
+			location::volume_serial::set(volume.serial.clone()),
+			location::volume_capacity::set(Some(volume.total_capacity.to_string())),
+		],
+		None => vec![],
+	}
+}
+
+/// is_online checks that the location's path is there and, if we know which volume it belongs on,
+/// that it's that volume mounted there and not another drive that happens to use the same mount point
+fn is_online(location: &location::Data, volumes: &[Volume]) -> bool {
+	// object storage isn't on any of our volumes, requests to it fail on their own when it's unreachable
+	if location.storage_config.is_some() {
+		return location.is_online;
+	}
+
+	let local_path = match &location.local_path {
+		Some(local_path) => Path::new(local_path),
+		None => return false,
+	};
+
+	if !local_path.exists() {
+		return false;
+	}
+
+	// network shares are checked by what's mounted on their directory, their volume can be anything
+	if location.network_remote.is_some() {
+		return network::is_mounted(local_path);
+	}
+
+	match (find_volume(volumes, local_path), &location.volume_capacity) {
+		(Some((volume, _)), Some(capacity)) => {
+			volume.total_capacity.to_string() == *capacity
+				&& match (&location.volume_serial, &volume.serial) {
+					(Some(serial), Some(volume_serial)) => serial == volume_serial,
+					_ => true,
+				}
+		}
+		_ => true,
+	}
+}
+
+/// check_locations updates whether each of the library's locations is online, rescanning the ones that came back
+pub async fn check_locations(library: &LibraryContext) -> Result<(), LocationError> {
+	let volumes =
+		block_in_place(get_volumes).map_err(|e| LocationError::VolumeReadError(e.to_string()))?;
+
+	let locations = library
+		.db
+		.location()
+		.find_many(vec![location::node_id::equals(library.node_local_id)])
+		.exec()
+		.await?;
+
+	let mut changed = false;
+	for location in locations {
+		let mut online = is_online(&location, &volumes);
+
+		// network shares don't come back on their own like drives do, so try mounting them again
+		if !online && location.network_remote.is_some() {
+			match network::remount(library, &location).await {
+				Ok(()) => online = is_online(&location, &volumes),
+				Err(e) => debug!("Couldn't remount location {}: {e}", location.id),
+			}
+		}
+
+		// locations created before fingerprinting get one the first time they're seen online
+		let mut params = vec![];
+		if online && location.volume_capacity.is_none() {
+			if let Some(local_path) = &location.local_path {
+				params = volume_params(&volumes, Path::new(local_path));
+			}
+		}
+
+		if online != location.is_online {
+			changed = true;
+			params.push(location::is_online::set(online));
+			info!(
+				"Location {} is now {}",
+				location.id,
+				if online { "online" } else { "offline" }
+			);
+		}
+
+		if params.is_empty() {
+			continue;
+		}
+
+		library
+			.db
+			.location()
+			.update(location::id::equals(location.id), params)
+			.exec()
+			.await?;
+		library.emit_event(LibraryEvent::LocationUpdated {
+			location_id: location.id,
+		});
+
+		// catch up on whatever changed while the drive was away, unless it's a catalog, which keeps the
+		// snapshot it was taken with, or the location isn't set to watch for changes
+		if online && !location.is_online && !location.is_catalog && location.watch_changes {
+			if let Some(location) = fetch_location(library, location.id)
+				.include(indexer_job_location::include())
+				.exec()
+				.await?
+			{
+				scan_location(library, location).await?;
+			}
+		}
+	}
+
+	if changed {
+		invalidate_query!(library, "locations.list");
+	}
+
+	Ok(())
+}
+
+/// spawn_volume_watcher starts a task that keeps the library's locations' online status up to date.
+/// The task stops once the library is unloaded.
+pub fn spawn_volume_watcher(library: LibraryContext) {
+	tokio::spawn(async move {
+		let mut interval = interval(VOLUME_CHECK_INTERVAL);
+
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {}
+				_ = library.stopped() => break,
+			}
+
+			if library.process_lock.is_read_only() {
+				continue;
+			}
+
+			if let Err(e) = check_locations(&library).await {
+				error!(
+					"Failed to check locations for library '{}': {e:#?}",
+					library.id
+				);
+			}
+
+			// the next start sweeps the locations for changes made after this
+			if let Err(e) = library
+				.db
+				.node()
+				.update(
+					node::id::equals(library.node_local_id),
+					vec![node::last_seen::set(Utc::now().into())],
+				)
+				.exec()
+				.await
+			{
+				error!("Failed to update when the node was last seen: {e:#?}");
+			}
+		}
+	});
+}
@@ -0,0 +1,76 @@
+//! Tokens clients authenticate with when they reach a node over the network, like the desktop app
+//! connecting to a headless node running on a NAS. The config only keeps a hash of each token, the
+//! token itself is handed out once when it's made.
+
+use chrono::{DateTime, Utc};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::NodeConfig;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ApiToken {
+	pub id: Uuid,
+	/// what the token is for, e.g. the client it was made for
+	pub name: String,
+	/// hex encoded SHA-256 of the token
+	pub hash: String,
+	pub date_created: DateTime<Utc>,
+}
+
+/// A token that was just made, the only time the secret is known
+#[derive(Debug, Serialize, Type)]
+pub struct CreatedApiToken {
+	pub token: ApiToken,
+	pub secret: String,
+}
+
+fn hash_secret(secret: &str) -> String {
+	hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+impl ApiToken {
+	/// generate makes a token out of 244 random bits, two v4 UUIDs, returning it with its secret
+	pub fn generate(name: String) -> CreatedApiToken {
+		let secret = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+		CreatedApiToken {
+			token: ApiToken {
+				id: Uuid::new_v4(),
+				name,
+				hash: hash_secret(&secret),
+				date_created: Utc::now(),
+			},
+			secret,
+		}
+	}
+
+	fn matches(&self, secret: &str) -> bool {
+		self.hash == hash_secret(secret.trim())
+	}
+}
+
+impl NodeConfig {
+	/// authorizes tells if a client presenting `secret` is allowed in
+	pub fn authorizes(&self, secret: &str) -> bool {
+		self.api_tokens.iter().any(|token| token.matches(secret))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn matches_its_secret_only() {
+		let created = ApiToken::generate("NAS".to_string());
+		let other = ApiToken::generate("Laptop".to_string());
+
+		assert!(created.token.matches(&created.secret));
+		assert!(!created.token.matches(&other.secret));
+		assert!(!created.token.matches(""));
+		assert_eq!(created.secret.len(), 64);
+	}
+}
@@ -1,6 +1,17 @@
+use crate::{
+	extension::ExtensionSettings,
+	job::{ThrottleLimits, TransferLimits},
+	location::space_monitor::SpaceThresholds,
+	object::share::ShareRelay,
+	util::conflict::ConflictNaming,
+};
+
+use super::{ApiToken, Hook};
+
 use rspc::Type;
 use serde::{Deserialize, Serialize};
 use std::{
+	collections::HashMap,
 	fs::File,
 	io::{self, BufReader, Seek, SeekFrom, Write},
 	path::{Path, PathBuf},
@@ -8,6 +19,7 @@ use std::{
 };
 use thiserror::Error;
 use tokio::sync::{RwLock, RwLockWriteGuard};
+use tracing::warn;
 use uuid::Uuid;
 
 /// NODE_STATE_CONFIG_NAME is the name of the file which stores the NodeState
@@ -40,6 +52,30 @@ pub struct NodeConfig {
 	pub name: String,
 	// the port this node uses for peer to peer communication. By default a random free port will be chosen each time the application is started.
 	pub p2p_port: Option<u32>,
+	/// how copies of files are named when a file by the same name is already where they go
+	#[serde(default)]
+	pub conflict_naming: ConflictNaming,
+	/// how much background jobs can read and hash at once
+	#[serde(default)]
+	pub job_throttle: ThrottleLimits,
+	/// how much is sent and received over the network at once, and when
+	#[serde(default)]
+	pub transfer_limits: TransferLimits,
+	/// when the volumes backing locations are alerted about for running out of space
+	#[serde(default)]
+	pub space_alerts: SpaceThresholds,
+	/// the tokens clients connecting over the network authenticate with
+	#[serde(default)]
+	pub api_tokens: Vec<ApiToken>,
+	/// what the user allowed each extension to do, by the extension's id
+	#[serde(default)]
+	pub extensions: HashMap<String, ExtensionSettings>,
+	/// the commands and webhooks run on what happens in the node's libraries
+	#[serde(default)]
+	pub hooks: Vec<Hook>,
+	/// where files shared with links are uploaded, none until one is set up
+	#[serde(default)]
+	pub share_relay: Option<ShareRelay>,
 	// /// The P2P identity public key
 	// pub p2p_cert: Vec<u8>,
 	// /// The P2P identity private key
@@ -58,6 +94,12 @@ pub enum NodeConfigError {
 	Migration(String),
 }
 
+impl From<NodeConfigError> for rspc::Error {
+	fn from(err: NodeConfigError) -> Self {
+		rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+	}
+}
+
 impl NodeConfig {
 	fn default() -> Self {
 		NodeConfig {
@@ -66,11 +108,19 @@ impl NodeConfig {
 				// SAFETY: This is just for display purposes so it doesn't matter if it's lossy
 				Ok(hostname) => hostname.to_string_lossy().into_owned(),
 				Err(err) => {
-					eprintln!("Falling back to default node name as an error occurred getting your systems hostname: '{}'", err);
+					warn!("Falling back to default node name as an error occurred getting your systems hostname: '{}'", err);
 					"my-spacedrive".into()
 				}
 			},
 			p2p_port: None,
+			conflict_naming: ConflictNaming::default(),
+			job_throttle: ThrottleLimits::default(),
+			transfer_limits: TransferLimits::default(),
+			space_alerts: SpaceThresholds::default(),
+			api_tokens: vec![],
+			extensions: HashMap::new(),
+			hooks: vec![],
+			share_relay: None,
 			metadata: ConfigMetadata {
 				version: Some(env!("CARGO_PKG_VERSION").into()),
 			},
@@ -100,7 +150,6 @@ impl NodeConfigManager {
 	}
 
 	/// write allows the user to update the configuration. This is done in a closure while a Mutex lock is held so that the user can't cause a race condition if the config were to be updated in multiple parts of the app at the same time.
-	#[allow(unused)]
 	pub(crate) async fn write<F: FnOnce(RwLockWriteGuard<NodeConfig>)>(
 		&self,
 		mutation_fn: F,
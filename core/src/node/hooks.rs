@@ -0,0 +1,448 @@
+//! Hooks run a shell command or post to a webhook when something happens in a library, like a
+//! location finishing indexing, so the node can be tied into other tools. They're kept in the
+//! node's config rather than the library, as what they run only makes sense on the node they were
+//! set up on.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	process::Stdio,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use globset::Glob;
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tokio::{io::AsyncWriteExt, process::Command, time::timeout};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{library::LibraryContext, object::search::duplicate_objects};
+
+/// How long a command or webhook gets before it's given up on
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum HookError {
+	#[error("Hook not found (id: {0})")]
+	NotFound(Uuid),
+	#[error("Invalid glob: {0}")]
+	InvalidGlob(#[from] globset::Error),
+	#[error("Invalid hook: {0}")]
+	InvalidHook(&'static str),
+	#[error("I/O error: {0}")]
+	IOError(#[from] std::io::Error),
+	#[error("Webhook request failed: {0}")]
+	Request(#[from] reqwest::Error),
+	#[error("Hook didn't finish in time")]
+	Timeout,
+	#[error("Hook failed: {0}")]
+	Failed(String),
+}
+
+impl From<HookError> for rspc::Error {
+	fn from(err: HookError) -> Self {
+		match err {
+			HookError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			HookError::InvalidGlob(_) | HookError::InvalidHook(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// When a hook runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(tag = "kind")]
+pub enum HookTrigger {
+	/// a location was indexed, any of the library's without a location
+	IndexingFinished { location_id: Option<i32> },
+	/// the number of objects with more than one copy went over the threshold, checked once new
+	/// files were identified
+	DuplicatesExceeded { threshold: u32 },
+	/// a file matching the glob, against its path in the location, was found new in a location
+	FileAppeared {
+		location_id: Option<i32>,
+		glob: String,
+	},
+}
+
+/// What a hook does
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(tag = "kind")]
+pub enum HookAction {
+	/// runs a command with the shell, given the payload on its standard input and the event's
+	/// values as environment variables, `SD_` and their name in capitals, e.g. `SD_LOCATION_ID`
+	Command { command: String },
+	/// posts the payload to the url
+	Webhook { url: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct Hook {
+	pub id: Uuid,
+	pub name: String,
+	pub enabled: bool,
+	/// the library the hook is for, all of them without one
+	pub library_id: Option<Uuid>,
+	pub trigger: HookTrigger,
+	pub action: HookAction,
+	/// the payload, with `{{name}}` replaced by the event's value by that name, escaped to go in a
+	/// JSON string. The event as JSON without one.
+	pub payload: Option<String>,
+	/// the hook runs at most once in this many seconds, the events in between are dropped and
+	/// counted in the `skipped` value of the next run
+	pub min_interval_secs: u32,
+}
+
+impl Hook {
+	pub fn validate(&self) -> Result<(), HookError> {
+		match &self.action {
+			HookAction::Command { command } if command.trim().is_empty() => {
+				return Err(HookError::InvalidHook("the command is empty"))
+			}
+			HookAction::Webhook { url } if reqwest::Url::parse(url).is_err() => {
+				return Err(HookError::InvalidHook("the webhook url isn't valid"))
+			}
+			_ => {}
+		}
+
+		if let HookTrigger::FileAppeared { glob, .. } = &self.trigger {
+			Glob::new(glob)?;
+		}
+		Ok(())
+	}
+
+	/// triggered_by tells if the event makes the hook run, given how many duplicates there were
+	/// before for duplicates to have gone over the threshold
+	fn triggered_by(&self, event: &HookEvent, previous_duplicates: usize) -> bool {
+		match (&self.trigger, event) {
+			(
+				HookTrigger::IndexingFinished { location_id },
+				HookEvent::IndexingFinished {
+					location_id: indexed,
+					..
+				},
+			) => location_id.map_or(true, |location_id| location_id == *indexed),
+			(
+				HookTrigger::DuplicatesExceeded { threshold },
+				HookEvent::DuplicatesExceeded { duplicates },
+			) => {
+				let threshold = *threshold as usize;
+				previous_duplicates < threshold && *duplicates >= threshold
+			}
+			(
+				HookTrigger::FileAppeared { location_id, glob },
+				HookEvent::FileAppeared {
+					location_id: appeared_in,
+					path,
+				},
+			) => {
+				location_id.map_or(true, |location_id| location_id == *appeared_in)
+					&& Glob::new(glob)
+						.map(|glob| glob.compile_matcher().is_match(path))
+						.unwrap_or(false)
+			}
+			_ => false,
+		}
+	}
+}
+
+/// Something that happened in a library that hooks can run on
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HookEvent {
+	IndexingFinished {
+		location_id: i32,
+		location_name: Option<String>,
+		files: usize,
+	},
+	DuplicatesExceeded {
+		duplicates: usize,
+	},
+	FileAppeared {
+		location_id: i32,
+		/// the path of the file in its location
+		path: String,
+	},
+}
+
+/// The values an event's payload is made with, by their name
+fn event_values(
+	event: &HookEvent,
+	hook: &Hook,
+	library_id: Uuid,
+	skipped: usize,
+) -> BTreeMap<String, String> {
+	let mut values = BTreeMap::new();
+	if let Ok(Value::Object(fields)) = serde_json::to_value(event) {
+		for (name, value) in fields {
+			let value = match value {
+				Value::String(value) => value,
+				Value::Null => String::new(),
+				value => value.to_string(),
+			};
+			values.insert(name, value);
+		}
+	}
+
+	values.insert("hook".to_string(), hook.name.clone());
+	values.insert("library_id".to_string(), library_id.to_string());
+	values.insert("skipped".to_string(), skipped.to_string());
+	values.insert("timestamp".to_string(), Utc::now().to_rfc3339());
+	values
+}
+
+/// render replaces the `{{name}}`s of a payload template with the values by that name, escaped to
+/// go in a JSON string
+fn render(template: &str, values: &BTreeMap<String, String>) -> String {
+	values
+		.iter()
+		.fold(template.to_string(), |rendered, (name, value)| {
+			let escaped = serde_json::to_string(value).unwrap_or_default();
+			rendered.replace(&format!("{{{{{name}}}}}"), &escaped[1..escaped.len() - 1])
+		})
+}
+
+/// Runs the hooks of the node as events happen in its libraries
+#[derive(Default)]
+pub struct HookRunner {
+	/// when each hook last ran, and how many events it dropped since
+	turns: Mutex<HashMap<Uuid, (Instant, usize)>>,
+	/// the duplicates each library had when they were last counted
+	duplicates: Mutex<HashMap<Uuid, usize>>,
+	client: reqwest::Client,
+}
+
+impl HookRunner {
+	/// fire runs the hooks the events trigger, in the background
+	pub(crate) async fn fire(&self, library: &LibraryContext, events: Vec<HookEvent>) {
+		let hooks = library.config().get().await.hooks;
+		if hooks.is_empty() || events.is_empty() {
+			return;
+		}
+
+		let previous_duplicates = self.previous_duplicates(library.id, &events);
+		for hook in hooks
+			.iter()
+			.filter(|hook| hook.enabled && hook.library_id.map_or(true, |id| id == library.id))
+		{
+			for event in events
+				.iter()
+				.filter(|event| hook.triggered_by(event, previous_duplicates))
+			{
+				let skipped = match self.take_turn(hook) {
+					Some(skipped) => skipped,
+					None => continue,
+				};
+
+				let values = event_values(event, hook, library.id, skipped);
+				let payload = match &hook.payload {
+					Some(template) => render(template, &values),
+					None => serde_json::to_string(event).unwrap_or_default(),
+				};
+
+				let hook = hook.clone();
+				let client = self.client.clone();
+				tokio::spawn(async move {
+					match run(&client, &hook.action, payload, values).await {
+						Ok(()) => info!("Ran hook '{}'", hook.name),
+						Err(e) => error!("Hook '{}' failed: {:#?}", hook.name, e),
+					}
+				});
+			}
+		}
+	}
+
+	/// check_duplicates counts the objects with more than one copy, when there are hooks to run
+	/// once there are too many
+	pub(crate) async fn check_duplicates(&self, library: &LibraryContext) {
+		let watched = library.config().get().await.hooks.iter().any(|hook| {
+			hook.enabled
+				&& hook.library_id.map_or(true, |id| id == library.id)
+				&& matches!(hook.trigger, HookTrigger::DuplicatesExceeded { .. })
+		});
+		if !watched {
+			return;
+		}
+
+		match duplicate_objects(library, None).await {
+			Ok(objects) => {
+				let event = HookEvent::DuplicatesExceeded {
+					duplicates: objects.len(),
+				};
+				self.fire(library, vec![event]).await;
+			}
+			Err(e) => error!("Failed to count duplicates for hooks: {:#?}", e),
+		}
+	}
+
+	/// previous_duplicates returns how many duplicates the library had, keeping the new count
+	fn previous_duplicates(&self, library_id: Uuid, events: &[HookEvent]) -> usize {
+		let mut counts = self.duplicates.lock().expect("hook state poisoned");
+		let previous = counts.get(&library_id).copied().unwrap_or_default();
+		for event in events {
+			if let HookEvent::DuplicatesExceeded { duplicates } = event {
+				counts.insert(library_id, *duplicates);
+			}
+		}
+		previous
+	}
+
+	/// take_turn tells if the hook can run now, with how many events it dropped since it last did
+	fn take_turn(&self, hook: &Hook) -> Option<usize> {
+		let mut turns = self.turns.lock().expect("hook state poisoned");
+		let now = Instant::now();
+		let min_interval = Duration::from_secs(hook.min_interval_secs.into());
+
+		match turns.get_mut(&hook.id) {
+			Some((last_run, skipped)) if now.duration_since(*last_run) < min_interval => {
+				*skipped += 1;
+				None
+			}
+			Some((last_run, skipped)) => {
+				*last_run = now;
+				Some(std::mem::take(skipped))
+			}
+			None => {
+				turns.insert(hook.id, (now, 0));
+				Some(0)
+			}
+		}
+	}
+}
+
+async fn run(
+	client: &reqwest::Client,
+	action: &HookAction,
+	payload: String,
+	values: BTreeMap<String, String>,
+) -> Result<(), HookError> {
+	match action {
+		HookAction::Command { command } => {
+			let mut shell = if cfg!(windows) {
+				let mut shell = Command::new("cmd");
+				shell.arg("/C");
+				shell
+			} else {
+				let mut shell = Command::new("sh");
+				shell.arg("-c");
+				shell
+			};
+
+			let mut child = shell
+				.arg(command)
+				.envs(
+					values
+						.iter()
+						.map(|(name, value)| (format!("SD_{}", name.to_uppercase()), value)),
+				)
+				.stdin(Stdio::piped())
+				.stdout(Stdio::null())
+				.stderr(Stdio::piped())
+				.kill_on_drop(true)
+				.spawn()?;
+
+			if let Some(mut stdin) = child.stdin.take() {
+				// commands that don't read their input close it early
+				let _ = stdin.write_all(payload.as_bytes()).await;
+			}
+
+			let output = timeout(HOOK_TIMEOUT, child.wait_with_output())
+				.await
+				.map_err(|_| HookError::Timeout)??;
+			if !output.status.success() {
+				return Err(HookError::Failed(
+					String::from_utf8_lossy(&output.stderr).trim().to_string(),
+				));
+			}
+		}
+		HookAction::Webhook { url } => {
+			let response = client
+				.post(url)
+				.header(reqwest::header::CONTENT_TYPE, "application/json")
+				.body(payload)
+				.timeout(HOOK_TIMEOUT)
+				.send()
+				.await?;
+			if !response.status().is_success() {
+				return Err(HookError::Failed(format!(
+					"the webhook responded with {}",
+					response.status()
+				)));
+			}
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hook(trigger: HookTrigger, min_interval_secs: u32) -> Hook {
+		Hook {
+			id: Uuid::new_v4(),
+			name: "test".to_string(),
+			enabled: true,
+			library_id: None,
+			trigger,
+			action: HookAction::Command {
+				command: "true".to_string(),
+			},
+			payload: None,
+			min_interval_secs,
+		}
+	}
+
+	#[test]
+	fn renders_escaped_values() {
+		let event = HookEvent::FileAppeared {
+			location_id: 3,
+			path: "a \"quoted\" name.pdf".to_string(),
+		};
+		let hook = hook(
+			HookTrigger::FileAppeared {
+				location_id: None,
+				glob: "*.pdf".to_string(),
+			},
+			0,
+		);
+		let values = event_values(&event, &hook, Uuid::nil(), 0);
+
+		assert_eq!(
+			render(r#"{"text": "{{path}} in {{location_id}}"}"#, &values),
+			r#"{"text": "a \"quoted\" name.pdf in 3"}"#
+		);
+		assert!(hook.triggered_by(&event, 0));
+	}
+
+	#[test]
+	fn fires_on_crossing_the_duplicates_threshold() {
+		let hook = hook(HookTrigger::DuplicatesExceeded { threshold: 10 }, 0);
+		let event = HookEvent::DuplicatesExceeded { duplicates: 12 };
+
+		assert!(hook.triggered_by(&event, 4));
+		assert!(!hook.triggered_by(&event, 11));
+	}
+
+	#[test]
+	fn limits_the_rate_of_runs() {
+		let runner = HookRunner::default();
+		let hook = hook(HookTrigger::IndexingFinished { location_id: None }, 60);
+
+		assert_eq!(runner.take_turn(&hook), Some(0));
+		assert_eq!(runner.take_turn(&hook), None);
+		assert_eq!(runner.take_turn(&hook), None);
+
+		runner.turns.lock().unwrap().get_mut(&hook.id).unwrap().0 -= Duration::from_secs(61);
+		assert_eq!(runner.take_turn(&hook), Some(2));
+	}
+}
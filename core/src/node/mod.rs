@@ -6,9 +6,13 @@ use rspc::Type;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+mod api_token;
 mod config;
+mod hooks;
 
+pub use api_token::*;
 pub use config::*;
+pub use hooks::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Type)]
 pub struct LibraryNode {
@@ -0,0 +1,364 @@
+//! Ownership, permissions and a few extended attributes of files, captured when they're indexed.
+//! Copies are given the ones their source had, and the tags file managers keep in extended
+//! attributes, Finder tags on macOS and `user.xdg.tags` on Linux, can be imported as tags.
+
+use std::{collections::BTreeMap, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	library::LibraryContext,
+	prisma::{file_path, object, tag, tag_on_object},
+	sync::{
+		models::{uuid_from_pub_id, TagData, TAG, TAG_ON_OBJECT},
+		SyncError,
+	},
+};
+
+const FINDER_TAGS_ATTR: &str = "com.apple.metadata:_kMDItemUserTags";
+const XDG_TAGS_ATTR: &str = "user.xdg.tags";
+
+/// The extended attributes kept of files, the rest aren't worth their space in the index
+pub const KEPT_XATTRS: &[&str] = &[
+	FINDER_TAGS_ATTR,
+	// set on downloads by macOS until they're first opened
+	"com.apple.quarantine",
+	XDG_TAGS_ATTR,
+	"user.xdg.comment",
+];
+
+/// The colors of Finder tags, by the index Finder keeps after their name
+#[cfg(any(target_os = "macos", test))]
+const FINDER_TAG_COLORS: [Option<&str>; 8] = [
+	None,
+	Some("#8E8E93"),
+	Some("#34C759"),
+	Some("#AF52DE"),
+	Some("#007AFF"),
+	Some("#FFCC00"),
+	Some("#FF3B30"),
+	Some("#FF9500"),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileAttributes {
+	pub owner_uid: Option<u32>,
+	pub owner_gid: Option<u32>,
+	/// the POSIX permission bits
+	pub permissions: Option<u32>,
+	/// the values of the [`KEPT_XATTRS`] the file has
+	pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl FileAttributes {
+	/// from_file_path returns the attributes captured when the file path was indexed
+	pub fn from_file_path(file_path: &file_path::Data) -> Self {
+		Self {
+			// ids above `i32::MAX` are stored wrapped around
+			owner_uid: file_path.owner_uid.map(|uid| uid as u32),
+			owner_gid: file_path.owner_gid.map(|gid| gid as u32),
+			permissions: file_path.permissions.map(|permissions| permissions as u32),
+			xattrs: file_path
+				.xattrs
+				.as_deref()
+				.and_then(|xattrs| rmp_serde::from_slice(xattrs).ok())
+				.unwrap_or_default(),
+		}
+	}
+
+	/// encoded_xattrs returns the extended attributes the way they're stored, none without any
+	pub fn encoded_xattrs(&self) -> Option<Vec<u8>> {
+		if self.xattrs.is_empty() {
+			return None;
+		}
+		rmp_serde::to_vec(&self.xattrs).ok()
+	}
+
+	/// tags returns the names and colors of the tags file managers keep on the file
+	pub fn tags(&self) -> Vec<(String, Option<&'static str>)> {
+		let mut tags = vec![];
+
+		#[cfg(target_os = "macos")]
+		if let Some(value) = self.xattrs.get(FINDER_TAGS_ATTR) {
+			tags.extend(
+				plist::from_bytes::<Vec<String>>(value)
+					.unwrap_or_default()
+					.iter()
+					.map(|tag| parse_finder_tag(tag)),
+			);
+		}
+
+		if let Some(value) = self.xattrs.get(XDG_TAGS_ATTR) {
+			tags.extend(
+				String::from_utf8_lossy(value)
+					.split(',')
+					.map(str::trim)
+					.filter(|name| !name.is_empty())
+					.map(|name| (name.to_string(), None)),
+			);
+		}
+
+		tags.dedup_by(|(a, _), (b, _)| a == b);
+		tags
+	}
+}
+
+/// parse_finder_tag splits a Finder tag in its name and color, which it stores as `name\ncolor`
+#[cfg(any(target_os = "macos", test))]
+fn parse_finder_tag(tag: &str) -> (String, Option<&'static str>) {
+	match tag.split_once('\n') {
+		Some((name, color)) => (
+			name.to_string(),
+			color
+				.parse::<usize>()
+				.ok()
+				.and_then(|color| FINDER_TAG_COLORS.get(color).copied().flatten()),
+		),
+		None => (tag.to_string(), None),
+	}
+}
+
+/// read_attributes reads the attributes of a file, leaving out what the platform doesn't have
+pub fn read_attributes(path: &Path) -> FileAttributes {
+	platform::read_attributes(path)
+}
+
+/// apply_attributes gives a file the attributes another one had. Only root can give files away, so
+/// the owner is left alone when the node isn't allowed to change it.
+pub fn apply_attributes(path: &Path, attributes: &FileAttributes) -> io::Result<()> {
+	platform::apply_attributes(path, attributes)
+}
+
+#[cfg(unix)]
+mod platform {
+	use super::{FileAttributes, KEPT_XATTRS};
+	use nix::{
+		errno::Errno,
+		unistd::{chown, Gid, Uid},
+	};
+	use std::{
+		fs, io,
+		os::unix::fs::{MetadataExt, PermissionsExt},
+		path::Path,
+	};
+
+	pub fn read_attributes(path: &Path) -> FileAttributes {
+		let mut attributes = FileAttributes::default();
+
+		if let Ok(metadata) = fs::metadata(path) {
+			attributes.owner_uid = Some(metadata.uid());
+			attributes.owner_gid = Some(metadata.gid());
+			attributes.permissions = Some(metadata.mode() & 0o7777);
+		}
+
+		// attributes the filesystem doesn't support, like Apple's on Linux, are errors
+		for name in KEPT_XATTRS {
+			if let Ok(Some(value)) = xattr::get(path, name) {
+				attributes.xattrs.insert(name.to_string(), value);
+			}
+		}
+
+		attributes
+	}
+
+	pub fn apply_attributes(path: &Path, attributes: &FileAttributes) -> io::Result<()> {
+		for (name, value) in &attributes.xattrs {
+			xattr::set(path, name, value)?;
+		}
+
+		if attributes.owner_uid.is_some() || attributes.owner_gid.is_some() {
+			match chown(
+				path,
+				attributes.owner_uid.map(Uid::from_raw),
+				attributes.owner_gid.map(Gid::from_raw),
+			) {
+				Ok(()) | Err(Errno::EPERM) => {}
+				Err(e) => return Err(e.into()),
+			}
+		}
+
+		// after the owner, as changing it clears the setuid and setgid bits
+		if let Some(permissions) = attributes.permissions {
+			fs::set_permissions(path, fs::Permissions::from_mode(permissions))?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(not(unix))]
+mod platform {
+	use super::FileAttributes;
+	use std::{io, path::Path};
+
+	pub fn read_attributes(_path: &Path) -> FileAttributes {
+		FileAttributes::default()
+	}
+
+	pub fn apply_attributes(_path: &Path, _attributes: &FileAttributes) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// file_tag returns the user tag with the given name, creating it the first time
+async fn file_tag(
+	library: &LibraryContext,
+	name: &str,
+	color: Option<&str>,
+) -> Result<tag::Data, SyncError> {
+	if let Some(tag) = library
+		.db
+		.tag()
+		.find_first(vec![
+			tag::name::equals(Some(name.to_string())),
+			tag::is_system::equals(false),
+		])
+		.exec()
+		.await?
+	{
+		return Ok(tag);
+	}
+
+	let tag = library
+		.db
+		.tag()
+		.create(
+			Uuid::new_v4().as_bytes().to_vec(),
+			vec![
+				tag::name::set(Some(name.to_string())),
+				tag::color::set(color.map(ToString::to_string)),
+			],
+		)
+		.exec()
+		.await?;
+	library
+		.sync
+		.write_ops(vec![library.sync.shared_create(
+			TAG,
+			uuid_from_pub_id(&tag.pub_id),
+			&TagData::from(&tag),
+		)])
+		.await?;
+
+	Ok(tag)
+}
+
+/// tag_object tags an object with the user tag with the given name, making the tag if there's none.
+/// Returns whether the object wasn't tagged with it yet.
+pub(crate) async fn tag_object(
+	library: &LibraryContext,
+	object: &object::Data,
+	name: &str,
+	color: Option<&str>,
+) -> Result<bool, SyncError> {
+	let tag = file_tag(library, name, color).await?;
+
+	let is_tagged = library
+		.db
+		.tag_on_object()
+		.find_unique(tag_on_object::tag_id_object_id(tag.id, object.id))
+		.exec()
+		.await?
+		.is_some();
+	if is_tagged {
+		return Ok(false);
+	}
+
+	library
+		.db
+		.tag_on_object()
+		.create(
+			tag::id::equals(tag.id),
+			object::id::equals(object.id),
+			vec![],
+		)
+		.exec()
+		.await?;
+	library
+		.sync
+		.write_ops(vec![library.sync.relation_create(
+			TAG_ON_OBJECT,
+			library.sync.ensure_object_pub_id(object).await?,
+			uuid_from_pub_id(&tag.pub_id),
+		)])
+		.await?;
+
+	Ok(true)
+}
+
+/// import_file_tags tags the objects of a location with the tags file managers keep on their files,
+/// making the tags that don't exist yet. Returns how many tags were given to objects.
+pub async fn import_file_tags(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<usize, SyncError> {
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(location_id),
+			file_path::xattrs::not(None),
+			file_path::object_id::not(None),
+		])
+		.with(file_path::object::fetch())
+		.exec()
+		.await?;
+
+	let mut tagged = 0;
+	for file_path in &file_paths {
+		let object = match file_path.object() {
+			Ok(Some(object)) => object,
+			_ => continue,
+		};
+
+		for (name, color) in FileAttributes::from_file_path(file_path).tags() {
+			if tag_object(library, object, &name, color).await? {
+				tagged += 1;
+			}
+		}
+	}
+
+	if tagged > 0 {
+		invalidate_query!(library, "tags.list");
+		invalidate_query!(library, "tags.getForObject");
+	}
+
+	Ok(tagged)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_finder_tags() {
+		assert_eq!(
+			parse_finder_tag("Work\n6"),
+			("Work".to_string(), Some("#FF3B30"))
+		);
+		assert_eq!(parse_finder_tag("Work\n0"), ("Work".to_string(), None));
+		assert_eq!(parse_finder_tag("Archive"), ("Archive".to_string(), None));
+	}
+
+	#[test]
+	fn reads_xdg_tags() {
+		let attributes = FileAttributes {
+			xattrs: BTreeMap::from([(XDG_TAGS_ATTR.to_string(), b"work, taxes,,".to_vec())]),
+			..Default::default()
+		};
+
+		assert_eq!(
+			attributes.tags(),
+			vec![("work".to_string(), None), ("taxes".to_string(), None)]
+		);
+		assert_eq!(
+			rmp_serde::from_slice::<BTreeMap<String, Vec<u8>>>(
+				&attributes.encoded_xattrs().unwrap()
+			)
+			.unwrap(),
+			attributes.xattrs
+		);
+	}
+}
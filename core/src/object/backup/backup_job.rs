@@ -0,0 +1,320 @@
+use std::{
+	collections::{HashMap, HashSet},
+	path::PathBuf,
+};
+
+use chrono::{DateTime, Utc};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	location::LocationError,
+	object::{
+		chunks::{file_manifests, manifest_content, read_chunk, store_file, ChunkError},
+		fs::batch::local_location,
+	},
+	prisma::{backup_location, backup_target, file_path, location},
+	util::{message::Message, os_path::resolve_materialized_path},
+};
+
+use super::{
+	fetch_target, mark_uploaded, prune, record_snapshot, uploaded_chunks, Repository, Snapshot,
+	SnapshotFile,
+};
+
+pub const BACKUP_JOB_NAME: &str = "backup";
+
+/// BackupJob takes a snapshot of the locations of a backup target. Their files are put in the chunk
+/// store first, so only the chunks the target doesn't have yet are uploaded.
+pub struct BackupJob;
+
+#[derive(Serialize, Deserialize, Type, Debug)]
+pub struct BackupJobInit {
+	pub backup_target_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupJobState {
+	snapshot_id: Uuid,
+	started: DateTime<Utc>,
+	locations: HashMap<i32, BackedUpLocation>,
+	files: Vec<SnapshotFile>,
+	uploaded_chunks: usize,
+	uploaded_bytes: u64,
+	failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BackedUpLocation {
+	name: Option<String>,
+	path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupJobStep {
+	location_id: i32,
+	file_path_id: i32,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for BackupJob {
+	type Init = BackupJobInit;
+	type Data = BackupJobState;
+	type Step = BackupJobStep;
+
+	fn name(&self) -> &'static str {
+		BACKUP_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		if !library.config.chunk_store {
+			return Err(ChunkError::Disabled.into());
+		}
+
+		let target_id = state.init.backup_target_id;
+		let location_ids = library
+			.db
+			.backup_location()
+			.find_many(vec![backup_location::backup_target_id::equals(target_id)])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|backup_location| backup_location.location_id)
+			.collect::<Vec<_>>();
+		let names = library
+			.db
+			.location()
+			.find_many(vec![location::id::in_vec(location_ids.clone())])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|location| (location.id, location.name))
+			.collect::<HashMap<_, _>>();
+
+		let mut locations = HashMap::new();
+		for location_id in location_ids {
+			// the others are still backed up, a location that's offline is in the next snapshot
+			let path = match local_location(&library, location_id).await {
+				Ok((path, _)) => path,
+				Err(e @ LocationError::Offline(_)) => {
+					warn!("Not backing up location {location_id} to target {target_id}: {e}");
+					continue;
+				}
+				Err(e) => return Err(e.into()),
+			};
+
+			let file_paths = library
+				.db
+				.file_path()
+				.find_many(vec![
+					file_path::location_id::equals(location_id),
+					file_path::is_dir::equals(false),
+				])
+				.exec()
+				.await?;
+			state
+				.steps
+				.extend(file_paths.into_iter().map(|file_path| BackupJobStep {
+					location_id,
+					file_path_id: file_path.id,
+				}));
+
+			locations.insert(
+				location_id,
+				BackedUpLocation {
+					name: names.get(&location_id).cloned().flatten(),
+					path,
+				},
+			);
+		}
+
+		state.data = Some(BackupJobState {
+			snapshot_id: Uuid::new_v4(),
+			started: Utc::now(),
+			locations,
+			files: vec![],
+			uploaded_chunks: 0,
+			uploaded_bytes: 0,
+			failed: 0,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+		let step = &state.steps[0];
+
+		let file_path = library
+			.db
+			.file_path()
+			.find_unique(file_path::location_id_id(
+				step.location_id,
+				step.file_path_id,
+			))
+			.with(file_path::object::fetch())
+			.exec()
+			.await?;
+		// deleted since the job started
+		if let Some(file_path) = file_path {
+			ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+				path: file_path.materialized_path.clone(),
+			})]);
+
+			let location = &data.locations[&step.location_id];
+			let location_name = location.name.clone();
+			let cas_id = file_path
+				.object()
+				.ok()
+				.flatten()
+				.map(|object| object.cas_id.clone());
+			let path = resolve_materialized_path(
+				&location.path,
+				&file_path.materialized_path,
+				file_path.raw_path.as_deref(),
+			);
+
+			match store_file(&library, &file_path, cas_id.as_deref(), &path).await {
+				Ok(_) => {
+					// the latest manifest has the file as it is now, just stored or not
+					let manifest = file_manifests(&library, step.location_id, step.file_path_id)
+						.await?
+						.into_iter()
+						.next();
+
+					if let Some(manifest) = manifest {
+						let content = manifest_content(&library, manifest.id).await?;
+						let hashes = content
+							.chunks
+							.iter()
+							.map(|chunk| chunk.hash.clone())
+							.collect::<Vec<_>>();
+
+						let target = fetch_target(&library, state.init.backup_target_id).await?;
+						let uploaded = uploaded_chunks(&library, target.id, hashes.clone()).await?;
+						let new_hashes = hashes
+							.iter()
+							.filter(|hash| !uploaded.contains(*hash))
+							.cloned()
+							.collect::<HashSet<_>>()
+							.into_iter()
+							.collect::<Vec<_>>();
+
+						if !new_hashes.is_empty() {
+							let repository = Repository::open_in_job(&ctx, &target)?;
+							for hash in &new_hashes {
+								let chunk = block_in_place(|| read_chunk(&library, hash))?;
+								repository.put_chunk(hash, &chunk).await?;
+								data.uploaded_bytes += chunk.len() as u64;
+							}
+							mark_uploaded(&library, target.id, &new_hashes).await?;
+							data.uploaded_chunks += new_hashes.len();
+						}
+
+						data.files.push(SnapshotFile {
+							location_id: step.location_id,
+							location_name,
+							path: manifest.path,
+							size: manifest.size_in_bytes as u64,
+							checksum: manifest.checksum,
+							chunks: hashes,
+						});
+					}
+				}
+				Err(ChunkError::IOError(e)) => {
+					ctx.record_file_error(FileError::new(&file_path, &e)).await;
+					data.failed += 1;
+				}
+				Err(e) => return Err(e.into()),
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		let target = fetch_target(&library, state.init.backup_target_id).await?;
+		let repository = Repository::open_in_job(&ctx, &target)?;
+
+		let snapshot = Snapshot {
+			id: data.snapshot_id,
+			date: data.started,
+			files: std::mem::take(&mut data.files),
+		};
+		repository.put_snapshot(&snapshot).await?;
+		record_snapshot(&library, target.id, &snapshot).await?;
+
+		library
+			.db
+			.backup_target()
+			.update(
+				backup_target::id::equals(target.id),
+				vec![backup_target::last_backup::set(Some(data.started.into()))],
+			)
+			.exec()
+			.await?;
+
+		let pruned = prune(&library, &target, &repository).await?;
+
+		invalidate_query!(library, "backups.listTargets");
+		invalidate_query!(library, "backups.getSnapshots");
+		invalidate_query!(library, "chunks.stats");
+
+		info!(
+			"Backed up to target {}: snapshot {} of {} files, {} failed, {} chunks uploaded \
+			({} bytes), {} snapshots pruned",
+			target.id,
+			snapshot.id,
+			snapshot.files.len(),
+			data.failed,
+			data.uploaded_chunks,
+			data.uploaded_bytes,
+			pruned.snapshots
+		);
+
+		Ok(Some(json!({
+			"snapshot": snapshot.id,
+			"files": snapshot.files.len(),
+			"failed": data.failed,
+			"uploaded_chunks": data.uploaded_chunks,
+			"uploaded_bytes": data.uploaded_bytes,
+			"pruned_snapshots": pruned.snapshots,
+			"pruned_chunks": pruned.chunks,
+		})))
+	}
+}
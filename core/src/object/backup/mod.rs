@@ -0,0 +1,853 @@
+//! Encrypted backups of locations to storage that can be written to, like an S3 bucket or a WebDAV
+//! server, built on the chunk store. A backup puts the files of the target's locations in the chunk
+//! store, uploads the chunks the target doesn't have yet, and then a snapshot listing every file
+//! with its chunks. Every snapshot is complete, while only what changed since the last one is
+//! uploaded, and snapshots past the target's [`PrunePolicy`] are removed along with the chunks only
+//! they had.
+//!
+//! Everything uploaded is encrypted with the repository key, which is kept on the target encrypted
+//! with the password the repository was created with. A repository can so be connected to again
+//! from another node, or after losing the library, and restored with nothing but its password.
+//!
+//! What's on the target:
+//! - `config`: the repository key, encrypted with the password
+//! - `data/<id[..2]>/<id>`: the chunks, named by a hash of their content hash keyed with the
+//!   repository key, so the target can't tell which content it has
+//! - `snapshots/<uuid>`: the snapshots
+
+mod backup_job;
+mod restore_job;
+
+pub use backup_job::*;
+pub use restore_job::*;
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::{Direction, PrismaValue};
+use rspc::Type;
+use sd_crypto::{
+	crypto::stream::{Algorithm, StreamDecryption, StreamEncryption},
+	keys::hashing::{HashingAlgorithm, Params},
+	primitives::{generate_master_key, generate_nonce, generate_salt, to_array, SALT_LEN},
+	Error as CryptoError, Protected,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	job::WorkerContext,
+	library::LibraryContext,
+	location::{
+		storage::{Storage, StorageConfig, StorageCreateArgs, StorageError},
+		store_secret, LocationError,
+	},
+	prisma::{backup_chunk, backup_location, backup_snapshot, backup_target, location},
+	util::db::{OnConflict, Upsert},
+};
+
+use super::chunks::ChunkError;
+
+const CONFIG_PATH: &str = "config";
+const SNAPSHOTS_DIR: &str = "snapshots";
+/// What the config of a repository starts with, so something else at its path isn't taken for it
+const CONFIG_MAGIC: &[u8; 8] = b"sdbackup";
+const ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+/// How many chunks are looked up or removed at once, under SQLite's placeholder limit
+const BATCH_SIZE: usize = 500;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("{0}")]
+	LocationError(#[from] LocationError),
+	#[error("Storage error: {0}")]
+	StorageError(#[from] StorageError),
+	#[error("Crypto error: {0}")]
+	CryptoError(#[from] CryptoError),
+	#[error("{0}")]
+	ChunkError(#[from] ChunkError),
+	#[error("I/O error: {0}")]
+	IOError(#[from] std::io::Error),
+	#[error("Backup target not found (id: {0})")]
+	NotFound(i32),
+	#[error("Backup snapshot not found (id: {0})")]
+	SnapshotNotFound(i32),
+	#[error("The password doesn't unlock this backup repository")]
+	WrongPassword,
+	#[error("Invalid backup repository: {0}")]
+	InvalidRepository(String),
+	#[error("A prune policy has to keep some snapshots")]
+	EmptyPrunePolicy,
+}
+
+impl From<BackupError> for rspc::Error {
+	fn from(err: BackupError) -> Self {
+		match err {
+			BackupError::NotFound(_) | BackupError::SnapshotNotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			BackupError::WrongPassword
+			| BackupError::InvalidRepository(_)
+			| BackupError::EmptyPrunePolicy => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			BackupError::LocationError(err) => err.into(),
+			BackupError::ChunkError(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// A file as it was when a snapshot was taken
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct SnapshotFile {
+	pub location_id: i32,
+	pub location_name: Option<String>,
+	/// the materialized path of the file in its location
+	pub path: String,
+	pub size: u64,
+	/// blake3 of the whole content
+	pub checksum: String,
+	/// the hashes of the chunks of the file, in order
+	pub chunks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+	pub id: Uuid,
+	pub date: DateTime<Utc>,
+	pub files: Vec<SnapshotFile>,
+}
+
+impl Snapshot {
+	fn size(&self) -> u64 {
+		self.files.iter().map(|file| file.size).sum()
+	}
+}
+
+/// Repository is a backup target opened with its repository key
+pub struct Repository {
+	storage: Box<dyn Storage>,
+	key: Protected<[u8; 32]>,
+}
+
+impl Repository {
+	/// open connects to the storage of a target
+	pub fn open(
+		library: &LibraryContext,
+		target: &backup_target::Data,
+	) -> Result<Self, BackupError> {
+		Ok(Self {
+			storage: storage_config(target)?.open(library)?,
+			key: repository_key(library, target)?,
+		})
+	}
+
+	/// open_in_job connects to the storage of a target for a job, whose uploads and downloads are
+	/// held to the node's transfer limits
+	pub fn open_in_job(
+		ctx: &WorkerContext,
+		target: &backup_target::Data,
+	) -> Result<Self, BackupError> {
+		Ok(Self {
+			storage: storage_config(target)?.open_in_job(ctx)?,
+			key: repository_key(&ctx.library_ctx(), target)?,
+		})
+	}
+
+	fn chunk_path(&self, hash: &str) -> String {
+		let id = blake3::keyed_hash(self.key.expose(), hash.as_bytes()).to_hex();
+		format!("data/{}/{id}", &id[..2])
+	}
+
+	fn snapshot_path(id: &str) -> String {
+		format!("{SNAPSHOTS_DIR}/{id}")
+	}
+
+	/// encrypt seals `data` with the repository key, with the nonce it used in front of it
+	fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, BackupError> {
+		let nonce = generate_nonce(ALGORITHM);
+		let encrypted =
+			StreamEncryption::encrypt_bytes(self.key.clone(), &nonce, ALGORITHM, data, &[])?;
+
+		Ok([nonce, encrypted].concat())
+	}
+
+	fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, BackupError> {
+		if data.len() < ALGORITHM.nonce_len() {
+			return Err(BackupError::InvalidRepository(
+				"an object is too short to be encrypted".to_string(),
+			));
+		}
+
+		let (nonce, encrypted) = data.split_at(ALGORITHM.nonce_len());
+		Ok(
+			StreamDecryption::decrypt_bytes(self.key.clone(), nonce, ALGORITHM, encrypted, &[])?
+				.expose()
+				.clone(),
+		)
+	}
+
+	pub async fn put_chunk(&self, hash: &str, data: &[u8]) -> Result<(), BackupError> {
+		let encrypted = block_in_place(|| self.encrypt(data))?;
+		Ok(self.storage.put(&self.chunk_path(hash), encrypted).await?)
+	}
+
+	/// get_chunk downloads a chunk, checked against its hash
+	pub async fn get_chunk(&self, hash: &str) -> Result<Vec<u8>, BackupError> {
+		let encrypted = self.storage.get(&self.chunk_path(hash)).await?;
+		let data = block_in_place(|| self.decrypt(&encrypted))?;
+		if blake3::hash(&data).to_hex().as_str() != hash {
+			return Err(BackupError::InvalidRepository(format!(
+				"chunk {hash} doesn't have the content it's named by"
+			)));
+		}
+
+		Ok(data)
+	}
+
+	pub async fn delete_chunk(&self, hash: &str) -> Result<(), BackupError> {
+		Ok(self.storage.delete(&self.chunk_path(hash)).await?)
+	}
+
+	pub async fn put_snapshot(&self, snapshot: &Snapshot) -> Result<(), BackupError> {
+		let json = serde_json::to_vec(snapshot)
+			.map_err(|e| BackupError::InvalidRepository(e.to_string()))?;
+		let encrypted = block_in_place(|| self.encrypt(&json))?;
+
+		Ok(self
+			.storage
+			.put(&Self::snapshot_path(&snapshot.id.to_string()), encrypted)
+			.await?)
+	}
+
+	pub async fn get_snapshot(&self, id: &str) -> Result<Snapshot, BackupError> {
+		let encrypted = self.storage.get(&Self::snapshot_path(id)).await?;
+		let json = block_in_place(|| self.decrypt(&encrypted))?;
+
+		serde_json::from_slice(&json).map_err(|e| BackupError::InvalidRepository(e.to_string()))
+	}
+
+	pub async fn delete_snapshot(&self, id: &str) -> Result<(), BackupError> {
+		Ok(self.storage.delete(&Self::snapshot_path(id)).await?)
+	}
+
+	/// snapshot_ids lists the ids of the snapshots on the target
+	async fn snapshot_ids(&self) -> Result<Vec<String>, BackupError> {
+		Ok(self
+			.storage
+			.list()
+			.await?
+			.into_iter()
+			.filter_map(|entry| {
+				entry
+					.path
+					.strip_prefix(&format!("{SNAPSHOTS_DIR}/"))
+					.map(str::to_string)
+			})
+			.collect())
+	}
+}
+
+async fn fetch_target(
+	library: &LibraryContext,
+	target_id: i32,
+) -> Result<backup_target::Data, BackupError> {
+	library
+		.db
+		.backup_target()
+		.find_unique(backup_target::id::equals(target_id))
+		.exec()
+		.await?
+		.ok_or(BackupError::NotFound(target_id))
+}
+
+fn storage_config(target: &backup_target::Data) -> Result<StorageConfig, BackupError> {
+	StorageConfig::parse(Some(&target.storage_config))?
+		.ok_or_else(|| BackupError::InvalidRepository("missing storage config".to_string()))
+}
+
+/// repository_key reads the key of a target from the library's key manager
+fn repository_key(
+	library: &LibraryContext,
+	target: &backup_target::Data,
+) -> Result<Protected<[u8; 32]>, BackupError> {
+	let uuid = Uuid::parse_str(&target.repository_key)
+		.map_err(|e| BackupError::InvalidRepository(e.to_string()))?;
+	let key = library.key_manager.get_key(uuid)?;
+	let key =
+		hex::decode(key.expose()).map_err(|e| BackupError::InvalidRepository(e.to_string()))?;
+
+	Ok(Protected::new(to_array(key)?))
+}
+
+/// password_key derives the key the repository key is encrypted with from the password
+fn password_key(password: &str, salt: [u8; SALT_LEN]) -> Result<Protected<[u8; 32]>, BackupError> {
+	Ok(HashingAlgorithm::Argon2id(Params::Standard)
+		.hash(Protected::new(password.as_bytes().to_vec()), salt)?)
+}
+
+/// seal_config makes the config of a new repository, holding its key encrypted with the password
+fn seal_config(key: &Protected<[u8; 32]>, password: &str) -> Result<Vec<u8>, BackupError> {
+	let salt = generate_salt();
+	let nonce = generate_nonce(ALGORITHM);
+	let encrypted = StreamEncryption::encrypt_bytes(
+		password_key(password, salt)?,
+		&nonce,
+		ALGORITHM,
+		key.expose(),
+		CONFIG_MAGIC,
+	)?;
+
+	Ok([CONFIG_MAGIC.to_vec(), salt.to_vec(), nonce, encrypted].concat())
+}
+
+/// open_config returns the key of a repository from its config
+fn open_config(config: &[u8], password: &str) -> Result<Protected<[u8; 32]>, BackupError> {
+	let header_len = CONFIG_MAGIC.len() + SALT_LEN + ALGORITHM.nonce_len();
+	if config.len() < header_len || !config.starts_with(CONFIG_MAGIC) {
+		return Err(BackupError::InvalidRepository(
+			"the target has something else at the path of a repository's config".to_string(),
+		));
+	}
+
+	let (salt, rest) = config[CONFIG_MAGIC.len()..].split_at(SALT_LEN);
+	let (nonce, encrypted) = rest.split_at(ALGORITHM.nonce_len());
+	let key = StreamDecryption::decrypt_bytes(
+		password_key(password, to_array(salt.to_vec())?)?,
+		nonce,
+		ALGORITHM,
+		encrypted,
+		CONFIG_MAGIC,
+	)
+	// the key only fails to decrypt when it was encrypted with another password
+	.map_err(|_| BackupError::WrongPassword)?;
+
+	Ok(Protected::new(to_array(key.expose().clone())?))
+}
+
+/// unlock_or_init returns the key of the repository on the storage, creating the repository if
+/// there isn't one yet
+async fn unlock_or_init(
+	storage: &dyn Storage,
+	password: &str,
+) -> Result<(Protected<[u8; 32]>, bool), BackupError> {
+	match storage.get(CONFIG_PATH).await {
+		Ok(config) => Ok((block_in_place(|| open_config(&config, password))?, false)),
+		Err(StorageError::Status(404, _)) => {
+			let key = generate_master_key();
+			let config = block_in_place(|| seal_config(&key, password))?;
+			storage.put(CONFIG_PATH, config).await?;
+			Ok((key, true))
+		}
+		Err(e) => Err(e.into()),
+	}
+}
+
+/// PrunePolicy tells which snapshots are kept after each backup, like `restic forget` does: the
+/// latest ones, and the latest one of each of the last days, weeks and months with a snapshot. A
+/// snapshot is kept if any of the rules keeps it, and the latest snapshot is always kept.
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrunePolicy {
+	pub keep_last: Option<u32>,
+	pub keep_daily: Option<u32>,
+	pub keep_weekly: Option<u32>,
+	pub keep_monthly: Option<u32>,
+}
+
+impl PrunePolicy {
+	/// validate refuses a policy that keeps no snapshot, which would remove every snapshot and chunk
+	/// of the target
+	fn validate(&self) -> Result<(), BackupError> {
+		let keeps = [
+			self.keep_last,
+			self.keep_daily,
+			self.keep_weekly,
+			self.keep_monthly,
+		]
+		.into_iter()
+		.any(|keep| keep.unwrap_or(0) > 0);
+
+		if keeps {
+			Ok(())
+		} else {
+			Err(BackupError::EmptyPrunePolicy)
+		}
+	}
+
+	/// kept tells of each snapshot, by the date it was taken, whether it's kept
+	fn kept(&self, dates: &[DateTime<Utc>]) -> Vec<bool> {
+		let mut kept = vec![false; dates.len()];
+		let mut latest_first = (0..dates.len()).collect::<Vec<_>>();
+		latest_first.sort_by(|a, b| dates[*b].cmp(&dates[*a]));
+
+		// the snapshot the backup just took
+		if let Some(&latest) = latest_first.first() {
+			kept[latest] = true;
+		}
+
+		for &i in latest_first
+			.iter()
+			.take(self.keep_last.unwrap_or(0) as usize)
+		{
+			kept[i] = true;
+		}
+
+		for (keep, period) in [
+			(self.keep_daily, "%Y-%m-%d"),
+			(self.keep_weekly, "%G-%V"),
+			(self.keep_monthly, "%Y-%m"),
+		] {
+			let keep = keep.unwrap_or(0) as usize;
+			let mut periods = HashSet::new();
+			for &i in &latest_first {
+				if periods.len() == keep {
+					break;
+				}
+				if periods.insert(dates[i].format(period).to_string()) {
+					kept[i] = true;
+				}
+			}
+		}
+
+		kept
+	}
+}
+
+#[derive(Serialize, Type, Debug, Default)]
+pub struct PruneReport {
+	pub snapshots: usize,
+	pub chunks: usize,
+}
+
+/// prune removes the snapshots of a target past its prune policy, and the chunks no snapshot left
+/// on the target has, including the snapshots other nodes took to it. Targets without a policy
+/// keep every snapshot.
+pub async fn prune(
+	library: &LibraryContext,
+	target: &backup_target::Data,
+	repository: &Repository,
+) -> Result<PruneReport, BackupError> {
+	let policy: PrunePolicy = match target.prune_policy.as_deref() {
+		Some(policy) => serde_json::from_str(policy)
+			.map_err(|e| BackupError::InvalidRepository(e.to_string()))?,
+		None => return Ok(PruneReport::default()),
+	};
+	// set before empty policies were refused, and taken as no policy
+	if policy.validate().is_err() {
+		return Ok(PruneReport::default());
+	}
+
+	let snapshots = library
+		.db
+		.backup_snapshot()
+		.find_many(vec![backup_snapshot::backup_target_id::equals(target.id)])
+		.exec()
+		.await?;
+	let kept = policy.kept(
+		&snapshots
+			.iter()
+			.map(|snapshot| snapshot.date_created.into())
+			.collect::<Vec<_>>(),
+	);
+
+	let mut report = PruneReport::default();
+	for (snapshot, kept) in snapshots.iter().zip(kept) {
+		if kept {
+			continue;
+		}
+
+		repository.delete_snapshot(&snapshot.pub_id).await?;
+		library
+			.db
+			.backup_snapshot()
+			.delete(backup_snapshot::id::equals(snapshot.id))
+			.exec()
+			.await?;
+		report.snapshots += 1;
+	}
+
+	if report.snapshots == 0 {
+		return Ok(report);
+	}
+
+	// the repository can be shared with other nodes, whose snapshots the library doesn't know of
+	let mut referenced = HashSet::new();
+	for id in repository.snapshot_ids().await? {
+		match repository.get_snapshot(&id).await {
+			Ok(snapshot) => {
+				referenced.extend(snapshot.files.into_iter().flat_map(|file| file.chunks));
+			}
+			Err(e) => {
+				warn!(
+					"Keeping the chunks of backup target {}, snapshot '{id}' can't be read: {e}",
+					target.id
+				);
+				return Ok(report);
+			}
+		}
+	}
+
+	let unreferenced = library
+		.db
+		.backup_chunk()
+		.find_many(vec![backup_chunk::backup_target_id::equals(target.id)])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|chunk| chunk.chunk_hash)
+		.filter(|hash| !referenced.contains(hash))
+		.collect::<Vec<_>>();
+	for batch in unreferenced.chunks(BATCH_SIZE) {
+		for hash in batch {
+			repository.delete_chunk(hash).await?;
+		}
+		library
+			.db
+			.backup_chunk()
+			.delete_many(vec![
+				backup_chunk::backup_target_id::equals(target.id),
+				backup_chunk::chunk_hash::in_vec(batch.to_vec()),
+			])
+			.exec()
+			.await?;
+		report.chunks += batch.len();
+	}
+
+	info!(
+		"Pruned backup target {}: {} snapshots and {} chunks removed",
+		target.id, report.snapshots, report.chunks
+	);
+
+	Ok(report)
+}
+
+/// import_snapshots records the snapshots on the target the library doesn't know of, like the ones
+/// of a repository made from another node, returning how many there were
+async fn import_snapshots(
+	library: &LibraryContext,
+	target: &backup_target::Data,
+	repository: &Repository,
+) -> Result<usize, BackupError> {
+	let known = library
+		.db
+		.backup_snapshot()
+		.find_many(vec![backup_snapshot::backup_target_id::equals(target.id)])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|snapshot| snapshot.pub_id)
+		.collect::<HashSet<_>>();
+
+	let mut imported = 0;
+	for id in repository.snapshot_ids().await? {
+		if known.contains(&id) {
+			continue;
+		}
+
+		let snapshot = match repository.get_snapshot(&id).await {
+			Ok(snapshot) => snapshot,
+			Err(e) => {
+				warn!(
+					"Skipping snapshot '{id}' of backup target {}: {e}",
+					target.id
+				);
+				continue;
+			}
+		};
+		record_snapshot(library, target.id, &snapshot).await?;
+		// the chunks it has are on the target already
+		let chunks = snapshot
+			.files
+			.iter()
+			.flat_map(|file| file.chunks.iter().cloned())
+			.collect::<HashSet<_>>();
+		for batch in chunks.into_iter().collect::<Vec<_>>().chunks(BATCH_SIZE) {
+			mark_uploaded(library, target.id, batch).await?;
+		}
+		imported += 1;
+	}
+
+	Ok(imported)
+}
+
+async fn record_snapshot(
+	library: &LibraryContext,
+	target_id: i32,
+	snapshot: &Snapshot,
+) -> Result<backup_snapshot::Data, BackupError> {
+	Ok(library
+		.db
+		.backup_snapshot()
+		.create(
+			backup_target::id::equals(target_id),
+			snapshot.id.to_string(),
+			snapshot.files.len() as i32,
+			snapshot.size() as i64,
+			vec![backup_snapshot::date_created::set(snapshot.date.into())],
+		)
+		.exec()
+		.await?)
+}
+
+/// mark_uploaded records that the target has the chunks
+async fn mark_uploaded(
+	library: &LibraryContext,
+	target_id: i32,
+	hashes: &[String],
+) -> Result<(), BackupError> {
+	Upsert::new("backup_chunk", ["backup_target_id", "chunk_hash"])
+		.rows(hashes.iter().map(|hash| {
+			[
+				PrismaValue::Int(target_id as i64),
+				PrismaValue::String(hash.clone()),
+			]
+		}))
+		.on_conflict(OnConflict::DoNothing(&["backup_target_id", "chunk_hash"]))
+		.exec(&library.db)
+		.await?;
+
+	Ok(())
+}
+
+/// uploaded_chunks returns which of the chunks the target has
+async fn uploaded_chunks(
+	library: &LibraryContext,
+	target_id: i32,
+	hashes: Vec<String>,
+) -> Result<HashSet<String>, BackupError> {
+	let mut uploaded = HashSet::new();
+	for batch in hashes.chunks(BATCH_SIZE) {
+		uploaded.extend(
+			library
+				.db
+				.backup_chunk()
+				.find_many(vec![
+					backup_chunk::backup_target_id::equals(target_id),
+					backup_chunk::chunk_hash::in_vec(batch.to_vec()),
+				])
+				.exec()
+				.await?
+				.into_iter()
+				.map(|chunk| chunk.chunk_hash),
+		);
+	}
+
+	Ok(uploaded)
+}
+
+#[derive(Deserialize, Type)]
+pub struct BackupTargetCreateArgs {
+	pub name: String,
+	/// an S3 bucket or a WebDAV directory, the other storages can't be written to
+	pub storage: StorageCreateArgs,
+	/// the password of the repository, or of the one already on the target to connect to it
+	pub password: String,
+	pub location_ids: Vec<i32>,
+	pub prune_policy: Option<PrunePolicy>,
+}
+
+impl BackupTargetCreateArgs {
+	/// create adds a backup target, making a repository on the storage unless there's already one
+	/// there, whose snapshots are then listed for restoring
+	pub async fn create(
+		self,
+		library: &LibraryContext,
+	) -> Result<backup_target::Data, BackupError> {
+		// the secrets go in the key manager, so it needs to be unlocked
+		if !library.key_manager.has_master_password()? {
+			return Err(LocationError::KeyManagerLocked.into());
+		}
+		if let Some(policy) = &self.prune_policy {
+			policy.validate()?;
+		}
+
+		let (config, secret) = self.storage.connect().await?;
+		let config = config.with_secret(store_secret(library, secret).await?);
+		let storage = config.open(library)?;
+
+		let (key, created) = unlock_or_init(&*storage, &self.password).await?;
+		let repository_key = store_secret(library, hex::encode(key.expose())).await?;
+
+		let target = library
+			.db
+			.backup_target()
+			.create(
+				self.name,
+				serde_json::to_string(&config)
+					.map_err(|e| StorageError::InvalidConfig(e.to_string()))?,
+				repository_key.to_string(),
+				vec![backup_target::prune_policy::set(
+					self.prune_policy
+						.map(|policy| serde_json::to_string(&policy))
+						.transpose()
+						.map_err(|e| BackupError::InvalidRepository(e.to_string()))?,
+				)],
+			)
+			.exec()
+			.await?;
+
+		set_locations(library, target.id, self.location_ids).await?;
+
+		if !created {
+			let imported = import_snapshots(library, &target, &Repository { storage, key }).await?;
+			info!(
+				"Connected to the backup repository of target {}, with {imported} snapshots",
+				target.id
+			);
+		}
+
+		invalidate_query!(library, "backups.listTargets");
+
+		Ok(target)
+	}
+}
+
+/// set_locations changes which locations are backed up to a target
+pub async fn set_locations(
+	library: &LibraryContext,
+	target_id: i32,
+	location_ids: Vec<i32>,
+) -> Result<(), BackupError> {
+	library
+		.db
+		.backup_location()
+		.delete_many(vec![backup_location::backup_target_id::equals(target_id)])
+		.exec()
+		.await?;
+
+	for location_id in location_ids {
+		library
+			.db
+			.backup_location()
+			.create(
+				backup_target::id::equals(target_id),
+				location::id::equals(location_id),
+				vec![],
+			)
+			.exec()
+			.await?;
+	}
+
+	invalidate_query!(library, "backups.listTargets");
+
+	Ok(())
+}
+
+/// set_prune_policy changes which snapshots of a target are kept, from the next backup on
+pub async fn set_prune_policy(
+	library: &LibraryContext,
+	target_id: i32,
+	policy: Option<PrunePolicy>,
+) -> Result<(), BackupError> {
+	if let Some(policy) = &policy {
+		policy.validate()?;
+	}
+
+	let policy = policy
+		.map(|policy| serde_json::to_string(&policy))
+		.transpose()
+		.map_err(|e| BackupError::InvalidRepository(e.to_string()))?;
+
+	let updated = library
+		.db
+		.backup_target()
+		.update_many(
+			vec![backup_target::id::equals(target_id)],
+			vec![backup_target::prune_policy::set(policy)],
+		)
+		.exec()
+		.await?;
+	if updated == 0 {
+		return Err(BackupError::NotFound(target_id));
+	}
+
+	invalidate_query!(library, "backups.listTargets");
+
+	Ok(())
+}
+
+/// snapshots lists the snapshots of a target, the latest first
+pub async fn snapshots(
+	library: &LibraryContext,
+	target_id: i32,
+) -> Result<Vec<backup_snapshot::Data>, BackupError> {
+	Ok(library
+		.db
+		.backup_snapshot()
+		.find_many(vec![backup_snapshot::backup_target_id::equals(target_id)])
+		.order_by(backup_snapshot::date_created::order(Direction::Desc))
+		.exec()
+		.await?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+
+	#[test]
+	fn keeps_latest_of_each_period() {
+		let dates = [
+			Utc.ymd(2022, 12, 20).and_hms(22, 0, 0),
+			Utc.ymd(2022, 12, 20).and_hms(10, 0, 0),
+			Utc.ymd(2022, 12, 19).and_hms(10, 0, 0),
+			Utc.ymd(2022, 12, 1).and_hms(10, 0, 0),
+			Utc.ymd(2022, 11, 15).and_hms(10, 0, 0),
+		];
+
+		let policy = PrunePolicy {
+			keep_daily: Some(2),
+			keep_monthly: Some(2),
+			..Default::default()
+		};
+		assert_eq!(policy.kept(&dates), [true, false, true, false, true]);
+
+		let policy = PrunePolicy {
+			keep_last: Some(2),
+			..Default::default()
+		};
+		assert_eq!(policy.kept(&dates), [true, true, false, false, false]);
+	}
+
+	#[test]
+	fn always_keeps_latest_snapshot() {
+		let dates = [
+			Utc.ymd(2022, 12, 19).and_hms(10, 0, 0),
+			Utc.ymd(2022, 12, 20).and_hms(10, 0, 0),
+		];
+
+		let policy = PrunePolicy {
+			keep_last: Some(0),
+			..Default::default()
+		};
+		assert!(matches!(
+			policy.validate(),
+			Err(BackupError::EmptyPrunePolicy)
+		));
+		assert_eq!(policy.kept(&dates), [false, true]);
+	}
+
+	#[test]
+	fn seals_repository_key_with_password() {
+		let key = generate_master_key();
+		let config = seal_config(&key, "correct horse").unwrap();
+
+		assert_eq!(
+			open_config(&config, "correct horse").unwrap().expose(),
+			key.expose()
+		);
+		assert!(matches!(
+			open_config(&config, "battery staple"),
+			Err(BackupError::WrongPassword)
+		));
+	}
+}
@@ -0,0 +1,269 @@
+use std::{
+	fs::File,
+	io::Write,
+	path::{Component, Path, PathBuf},
+};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::task::block_in_place;
+use tracing::info;
+
+use crate::{
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	object::chunks::read_chunk,
+	prisma::backup_snapshot,
+	util::message::Message,
+};
+
+use super::{fetch_target, BackupError, Repository, SnapshotFile};
+
+pub const RESTORE_BACKUP_JOB_NAME: &str = "restore_backup";
+
+/// RestoreBackupJob rebuilds the files of a snapshot in a directory, one directory per location.
+/// Chunks still in the library's chunk store are read from it, the others are downloaded from the
+/// target, so a repository connected to from another node can be restored too.
+pub struct RestoreBackupJob;
+
+#[derive(Serialize, Deserialize, Type, Debug)]
+pub struct RestoreBackupJobInit {
+	pub snapshot_id: i32,
+	/// the directory to restore into, files already there are kept and the restored ones renamed
+	pub target_path: PathBuf,
+	/// the materialized path of a directory to restore the files of, none for the whole snapshot
+	pub sub_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RestoreBackupJobState {
+	backup_target_id: i32,
+	restored: usize,
+	restored_bytes: u64,
+	downloaded_chunks: usize,
+	failed: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for RestoreBackupJob {
+	type Init = RestoreBackupJobInit;
+	type Data = RestoreBackupJobState;
+	type Step = SnapshotFile;
+
+	fn name(&self) -> &'static str {
+		RESTORE_BACKUP_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let snapshot_id = state.init.snapshot_id;
+
+		let snapshot = library
+			.db
+			.backup_snapshot()
+			.find_unique(backup_snapshot::id::equals(snapshot_id))
+			.exec()
+			.await?
+			.ok_or(BackupError::SnapshotNotFound(snapshot_id))?;
+		let target = fetch_target(&library, snapshot.backup_target_id).await?;
+
+		let files = Repository::open_in_job(&ctx, &target)?
+			.get_snapshot(&snapshot.pub_id)
+			.await?
+			.files;
+		let sub_path = state
+			.init
+			.sub_path
+			.as_ref()
+			.map(|sub_path| format!("{}/", sub_path.trim_end_matches('/')));
+		state.steps.extend(files.into_iter().filter(|file| {
+			sub_path
+				.as_ref()
+				.map_or(true, |sub_path| file.path.starts_with(sub_path))
+		}));
+
+		state.data = Some(RestoreBackupJobState {
+			backup_target_id: target.id,
+			restored: 0,
+			restored_bytes: 0,
+			downloaded_chunks: 0,
+			failed: 0,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+		let file = &state.steps[0];
+
+		ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+			path: file.path.clone(),
+		})]);
+
+		let path = match restore_path(&state.init.target_path, file) {
+			Some(path) => {
+				let node_config = library.config().get().await;
+				node_config
+					.conflict_naming
+					.free_path(&path, &node_config.name)
+			}
+			None => {
+				ctx.record_file_error(restore_error(
+					file,
+					Message::Error {
+						text: "invalid path in snapshot".to_string(),
+					},
+				))
+				.await;
+				data.failed += 1;
+				return Ok(());
+			}
+		};
+		let partial = path.with_file_name(format!(
+			".{}.sdrestore",
+			path.file_name().unwrap_or_default().to_string_lossy()
+		));
+
+		let target = fetch_target(&library, data.backup_target_id).await?;
+		let mut repository = None;
+
+		let restored = async {
+			if let Some(parent) = path.parent() {
+				tokio::fs::create_dir_all(parent).await?;
+			}
+			let mut writer = File::create(&partial)?;
+			let mut hasher = blake3::Hasher::new();
+
+			for hash in &file.chunks {
+				let chunk = match block_in_place(|| read_chunk(&library, hash)) {
+					Ok(chunk) => chunk,
+					// not in the chunk store anymore, or never was on this node
+					Err(_) => {
+						if repository.is_none() {
+							repository = Some(Repository::open_in_job(&ctx, &target)?);
+						}
+						let repository = repository.as_ref().expect("opened above");
+						data.downloaded_chunks += 1;
+						repository.get_chunk(hash).await?
+					}
+				};
+				hasher.update(&chunk);
+				block_in_place(|| writer.write_all(&chunk))?;
+			}
+
+			if hasher.finalize().to_hex().as_str() != file.checksum {
+				return Err(BackupError::InvalidRepository(format!(
+					"'{}' doesn't have the content it had when backed up",
+					file.path
+				)));
+			}
+
+			Ok::<_, BackupError>(())
+		}
+		.await;
+
+		match restored {
+			Ok(()) => {
+				tokio::fs::rename(&partial, &path).await?;
+				data.restored += 1;
+				data.restored_bytes += file.size;
+			}
+			Err(e) => {
+				tokio::fs::remove_file(&partial).await.ok();
+				let message = match e {
+					BackupError::IOError(e) => (&e).into(),
+					BackupError::StorageError(_) | BackupError::InvalidRepository(_) => {
+						Message::Error {
+							text: e.to_string(),
+						}
+					}
+					e => return Err(e.into()),
+				};
+				ctx.record_file_error(restore_error(file, message)).await;
+				data.failed += 1;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		_ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		info!(
+			"Restored snapshot {} to {}: {} files ({} bytes), {} failed, {} chunks downloaded",
+			state.init.snapshot_id,
+			state.init.target_path.display(),
+			data.restored,
+			data.restored_bytes,
+			data.failed,
+			data.downloaded_chunks
+		);
+
+		Ok(Some(json!({
+			"restored": data.restored,
+			"restored_bytes": data.restored_bytes,
+			"failed": data.failed,
+			"downloaded_chunks": data.downloaded_chunks,
+		})))
+	}
+}
+
+fn restore_error(file: &SnapshotFile, message: Message) -> FileError {
+	FileError {
+		location_id: Some(file.location_id),
+		file_path_id: None,
+		path: file.path.clone(),
+		message,
+	}
+}
+
+/// restore_path returns where a file of a snapshot goes, none if its path would lead out of the
+/// directory it's restored into
+fn restore_path(target_path: &Path, file: &SnapshotFile) -> Option<PathBuf> {
+	let location = file
+		.location_name
+		.clone()
+		.unwrap_or_else(|| format!("location-{}", file.location_id));
+
+	let mut path = target_path.to_path_buf();
+	for relative in [
+		Path::new(&location),
+		Path::new(file.path.trim_start_matches('/')),
+	] {
+		for component in relative.components() {
+			match component {
+				Component::Normal(component) => path.push(component),
+				_ => return None,
+			}
+		}
+	}
+
+	Some(path)
+}
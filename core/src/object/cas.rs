@@ -1,5 +1,8 @@
+use crate::prisma::PrismaClient;
+
 use blake3::Hasher;
-use std::path::PathBuf;
+use prisma_client_rust::{prisma_models::PrismaValue, raw::Raw, QueryError};
+use std::{ops::Range, path::PathBuf};
 use tokio::{
 	fs::File,
 	io::{self, AsyncReadExt, AsyncSeekExt, SeekFrom},
@@ -7,6 +10,8 @@ use tokio::{
 
 static SAMPLE_COUNT: u64 = 4;
 static SAMPLE_SIZE: u64 = 10000;
+/// How many rows a single bulk update statement sets, each takes 3 of the 999 variables SQLite allows
+const BULK_UPDATE_SIZE: usize = 300;
 
 async fn read_at(file: &mut File, offset: u64, size: u64) -> Result<Vec<u8>, io::Error> {
 	let mut buf = vec![0u8; size as usize];
@@ -17,6 +22,35 @@ async fn read_at(file: &mut File, offset: u64, size: u64) -> Result<Vec<u8>, io:
 	Ok(buf)
 }
 
+/// is_small_file tells if a file of `size` bytes is hashed whole, so it can be read in one go and
+/// hashed with [`small_file_cas_id`]
+pub fn is_small_file(size: u64) -> bool {
+	SAMPLE_COUNT * SAMPLE_SIZE > size
+}
+
+/// sample_ranges returns the parts of a file of `size` bytes that its cas_id is made from, in order
+pub fn sample_ranges(size: u64) -> Vec<Range<u64>> {
+	// if size is small enough, just read the whole thing
+	if is_small_file(size) {
+		return vec![0..size];
+	}
+
+	(0..SAMPLE_COUNT)
+		.map(|i| (size / SAMPLE_COUNT) * i)
+		// sample end of file
+		.chain([size - SAMPLE_SIZE])
+		.map(|start| start..start + SAMPLE_SIZE)
+		.collect()
+}
+
+/// sampled_bytes returns how much of a file of `size` bytes is read to make its cas_id
+pub fn sampled_bytes(size: u64) -> u64 {
+	sample_ranges(size)
+		.iter()
+		.map(|range| range.end - range.start)
+		.sum()
+}
+
 pub async fn generate_cas_id(path: PathBuf, size: u64) -> Result<String, io::Error> {
 	// open file reference
 	let mut file = File::open(path).await?;
@@ -26,19 +60,8 @@ pub async fn generate_cas_id(path: PathBuf, size: u64) -> Result<String, io::Err
 	// include the file size in the checksum
 	hasher.update(&size.to_le_bytes());
 
-	// if size is small enough, just read the whole thing
-
-	if SAMPLE_COUNT * SAMPLE_SIZE > size {
-		let buf = read_at(&mut file, 0, size).await?;
-		hasher.update(&buf);
-	} else {
-		// loop over samples
-		for i in 0..SAMPLE_COUNT {
-			let buf = read_at(&mut file, (size / SAMPLE_COUNT) * i, SAMPLE_SIZE).await?;
-			hasher.update(&buf);
-		}
-		// sample end of file
-		let buf = read_at(&mut file, size - SAMPLE_SIZE, SAMPLE_SIZE).await?;
+	for range in sample_ranges(size) {
+		let buf = read_at(&mut file, range.start, range.end - range.start).await?;
 		hasher.update(&buf);
 	}
 
@@ -46,3 +69,190 @@ pub async fn generate_cas_id(path: PathBuf, size: u64) -> Result<String, io::Err
 
 	Ok(hex.to_string())
 }
+
+/// small_file_cas_id is [`generate_cas_id`] for the whole contents of a small file, already read
+pub fn small_file_cas_id(contents: &[u8]) -> String {
+	let mut hasher = Hasher::new();
+	hasher.update(&(contents.len() as u64).to_le_bytes());
+	hasher.update(contents);
+
+	hasher.finalize().to_hex().to_string()
+}
+
+/// path_cas_id is the cas_id of a file identified by its size and path instead of its contents, see
+/// [`LocationSettings::identifies_by_path`](crate::location::settings::LocationSettings)
+pub fn path_cas_id(location_pub_id: &[u8], materialized_path: &str, size: u64) -> String {
+	let mut hasher = Hasher::new();
+	hasher.update(&size.to_le_bytes());
+	hasher.update(location_pub_id);
+	hasher.update(materialized_path.as_bytes());
+
+	hasher.finalize().to_hex().to_string()
+}
+
+/// `CasHasher` computes a file's cas_id and full checksum from data streamed through it in order,
+/// so a job that's already reading the whole file (e.g. to copy it) doesn't need to read it again.
+pub struct CasHasher {
+	size: u64,
+	offset: u64,
+	full: Hasher,
+	/// the sampled ranges of the file, in the order [`generate_cas_id`] hashes them
+	samples: Vec<(u64, Vec<u8>)>,
+}
+
+impl CasHasher {
+	pub fn new(size: u64) -> Self {
+		let samples = sample_ranges(size)
+			.into_iter()
+			.map(|range| {
+				(
+					range.start,
+					Vec::with_capacity((range.end - range.start) as usize),
+				)
+			})
+			.collect();
+
+		Self {
+			size,
+			offset: 0,
+			full: Hasher::new(),
+			samples,
+		}
+	}
+
+	pub fn update(&mut self, buf: &[u8]) {
+		self.full.update(buf);
+
+		let sample_len = if is_small_file(self.size) {
+			self.size
+		} else {
+			SAMPLE_SIZE
+		};
+
+		let buf_start = self.offset;
+		let buf_end = self.offset + buf.len() as u64;
+		for (sample_start, sample) in self.samples.iter_mut() {
+			let start = (*sample_start + sample.len() as u64).max(buf_start);
+			let end = (*sample_start + sample_len).min(buf_end);
+			if start < end {
+				sample.extend_from_slice(
+					&buf[(start - buf_start) as usize..(end - buf_start) as usize],
+				);
+			}
+		}
+
+		self.offset = buf_end;
+	}
+
+	/// finalize returns the cas_id and the full checksum of everything passed to `update`
+	pub fn finalize(self) -> (String, String) {
+		let mut cas_hasher = Hasher::new();
+		cas_hasher.update(&self.size.to_le_bytes());
+		for (_, sample) in &self.samples {
+			cas_hasher.update(sample);
+		}
+
+		(
+			cas_hasher.finalize().to_hex().to_string(),
+			self.full.finalize().to_hex().to_string(),
+		)
+	}
+}
+
+/// bulk_update_sql returns the statement setting `column` of `rows` rows of `table`, picked by id and
+/// the `scope` columns, with a placeholder for each value
+fn bulk_update_sql(table: &str, column: &str, scope: &[&str], rows: usize) -> String {
+	let mut conditions = scope
+		.iter()
+		.map(|scope| format!("{scope} = {{}}"))
+		.collect::<Vec<_>>();
+	conditions.push(format!("id IN ({})", vec!["{}"; rows].join(", ")));
+
+	format!(
+		"UPDATE {table} SET {column} = CASE id {} END WHERE {}",
+		"WHEN {} THEN {} ".repeat(rows).trim_end(),
+		conditions.join(" AND ")
+	)
+}
+
+/// bulk_update sets `column` of many rows of `table` at once, with a statement per few hundred rows
+/// instead of one per row. `rows` has the id of each row and its new value, and `scope` narrows the rows
+/// down for tables whose ids are only unique along other columns, like file paths in a location.
+/// Returns how many rows were updated.
+pub async fn bulk_update(
+	db: &PrismaClient,
+	table: &str,
+	column: &str,
+	scope: &[(&str, PrismaValue)],
+	rows: Vec<(i32, PrismaValue)>,
+) -> Result<i64, QueryError> {
+	let scope_columns = scope.iter().map(|(column, _)| *column).collect::<Vec<_>>();
+	let mut updated = 0;
+
+	for batch in rows.chunks(BULK_UPDATE_SIZE) {
+		let mut values = Vec::with_capacity(batch.len() * 3 + scope.len());
+		for (id, value) in batch {
+			values.extend([PrismaValue::Int(*id as i64), value.clone()]);
+		}
+		values.extend(scope.iter().map(|(_, value)| value.clone()));
+		values.extend(batch.iter().map(|(id, _)| PrismaValue::Int(*id as i64)));
+
+		updated += db
+			._execute_raw(Raw::new(
+				&bulk_update_sql(table, column, &scope_columns, batch.len()),
+				values,
+			))
+			.exec()
+			.await?;
+	}
+
+	Ok(updated)
+}
+
+/// link_file_paths links file paths of a location to their objects, `links` going from each file path id
+/// to its object id
+pub async fn link_file_paths(
+	db: &PrismaClient,
+	location_id: i32,
+	links: impl IntoIterator<Item = (i32, i32)>,
+) -> Result<i64, QueryError> {
+	bulk_update(
+		db,
+		"file_path",
+		"object_id",
+		&[("location_id", PrismaValue::Int(location_id as i64))],
+		links
+			.into_iter()
+			.map(|(file_path_id, object_id)| (file_path_id, PrismaValue::Int(object_id as i64)))
+			.collect(),
+	)
+	.await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn hashes_small_files_like_any_other() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("note.eml");
+		let contents = b"Subject: hello\r\n\r\nsee you soon\r\n";
+		std::fs::write(&path, contents).unwrap();
+
+		assert!(is_small_file(contents.len() as u64));
+		assert_eq!(
+			small_file_cas_id(contents),
+			generate_cas_id(path, contents.len() as u64).await.unwrap()
+		);
+	}
+
+	#[test]
+	fn builds_bulk_update_statements() {
+		assert_eq!(
+			bulk_update_sql("file_path", "object_id", &["location_id"], 2),
+			"UPDATE file_path SET object_id = CASE id WHEN {} THEN {} WHEN {} THEN {} END \
+			WHERE location_id = {} AND id IN ({}, {})"
+		);
+	}
+}
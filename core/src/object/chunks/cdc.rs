@@ -0,0 +1,197 @@
+//! Content-defined chunking with FastCDC (Xia et al., 2016). Chunk boundaries are found with a gear
+//! rolling hash over the last bytes read, so they follow the content and not its offset: inserting
+//! a few bytes at the start of a file only changes the chunks around the insertion, and the rest of
+//! the file is cut into the same chunks as before.
+
+use std::io::{self, Read};
+
+/// Chunks are at least this big, except for the last one of a file
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+/// The size chunks are on average
+pub const AVG_CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks are cut at this size if no boundary was found before
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// The random value of each byte in the gear hash, the same on every node so they cut files alike
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+	// splitmix64, from a fixed seed
+	let mut table = [0; 256];
+	let mut state = 0x5344_4348_554e_4b53u64;
+	let mut i = 0;
+	while i < 256 {
+		state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		table[i] = z ^ (z >> 31);
+		i += 1;
+	}
+	table
+}
+
+/// mask returns a mask of the `bits` highest bits, the ones of the hash the most bytes went into
+const fn mask(bits: u32) -> u64 {
+	!0 << (64 - bits)
+}
+
+/// ChunkSizes sets how big chunks are, the average has to be a power of two
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkSizes {
+	pub min: usize,
+	pub avg: usize,
+	pub max: usize,
+}
+
+impl Default for ChunkSizes {
+	fn default() -> Self {
+		Self {
+			min: MIN_CHUNK_SIZE,
+			avg: AVG_CHUNK_SIZE,
+			max: MAX_CHUNK_SIZE,
+		}
+	}
+}
+
+impl ChunkSizes {
+	/// cut returns where the first chunk of `data` ends. With normalized chunking, a boundary is
+	/// harder to find before the average size and easier after it, so sizes stay close to it.
+	pub fn cut(&self, data: &[u8]) -> usize {
+		if data.len() <= self.min {
+			return data.len();
+		}
+
+		let bits = self.avg.trailing_zeros();
+		let (mask_small, mask_large) = (mask(bits + 1), mask(bits - 1));
+		let end = data.len().min(self.max);
+		let normal = self.avg.min(end);
+
+		let mut hash = 0u64;
+		for (i, byte) in data.iter().enumerate().take(end).skip(self.min) {
+			hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+			let mask = if i < normal { mask_small } else { mask_large };
+			if hash & mask == 0 {
+				return i + 1;
+			}
+		}
+
+		end
+	}
+}
+
+/// Chunker splits what it reads into content-defined chunks
+pub struct Chunker<R> {
+	reader: R,
+	sizes: ChunkSizes,
+	buffer: Vec<u8>,
+	eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+	pub fn new(reader: R, sizes: ChunkSizes) -> Self {
+		Self {
+			reader,
+			sizes,
+			buffer: Vec::with_capacity(sizes.max),
+			eof: false,
+		}
+	}
+
+	/// fill reads until the buffer holds a chunk of the largest size, or the reader is done
+	fn fill(&mut self) -> io::Result<()> {
+		while !self.eof && self.buffer.len() < self.sizes.max {
+			let start = self.buffer.len();
+			self.buffer.resize(self.sizes.max, 0);
+			match self.reader.read(&mut self.buffer[start..]) {
+				Ok(0) => {
+					self.buffer.truncate(start);
+					self.eof = true;
+				}
+				Ok(read) => self.buffer.truncate(start + read),
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buffer.truncate(start),
+				Err(e) => {
+					self.buffer.truncate(start);
+					return Err(e);
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+	type Item = io::Result<Vec<u8>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Err(e) = self.fill() {
+			return Some(Err(e));
+		}
+		if self.buffer.is_empty() {
+			return None;
+		}
+
+		let rest = self.buffer.split_off(self.sizes.cut(&self.buffer));
+		Some(Ok(std::mem::replace(&mut self.buffer, rest)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// random returns `len` bytes of xorshift noise, which compresses and chunks like real content
+	fn random(len: usize, mut state: u64) -> Vec<u8> {
+		(0..len)
+			.map(|_| {
+				state ^= state << 13;
+				state ^= state >> 7;
+				state ^= state << 17;
+				state as u8
+			})
+			.collect()
+	}
+
+	fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+		Chunker::new(data, ChunkSizes::default())
+			.collect::<io::Result<_>>()
+			.unwrap()
+	}
+
+	#[test]
+	fn chunks_make_up_the_content() {
+		let data = random(4 * 1024 * 1024, 1);
+		let chunks = chunks(&data);
+
+		assert_eq!(chunks.concat(), data);
+		let (last, rest) = chunks.split_last().unwrap();
+		assert!(last.len() <= MAX_CHUNK_SIZE);
+		assert!(rest
+			.iter()
+			.all(|chunk| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk.len())));
+		// sizes stay around the average
+		assert!(
+			(32..=128).contains(&chunks.len()),
+			"{} chunks",
+			chunks.len()
+		);
+	}
+
+	#[test]
+	fn insertions_only_change_nearby_chunks() {
+		let data = random(2 * 1024 * 1024, 2);
+		let mut edited = random(100, 3);
+		edited.extend_from_slice(&data);
+
+		let before = chunks(&data);
+		let after = chunks(&edited);
+		let shared = after.iter().filter(|chunk| before.contains(chunk)).count();
+
+		assert!(
+			shared + 2 >= before.len(),
+			"{shared} of {} shared",
+			before.len()
+		);
+	}
+}
@@ -0,0 +1,584 @@
+//! The chunk store, an optional backup of a library's files kept on this node. Files are split
+//! into content-defined chunks (see [`cdc`]), and each chunk is stored once under its blake3 hash,
+//! however many files, or versions of a file, have it. What a file had when it was stored is recorded as a
+//! manifest listing its chunks in order, so storing a file again after a small edit only adds the
+//! chunks around the edit, and any of its stored versions can be restored.
+//!
+//! Manifests are also what a delta transfer is built from: a peer sends the chunk hashes of a file,
+//! the other side asks for the [`missing_chunks`] only and puts them in its own store.
+
+pub mod cdc;
+mod store_job;
+
+pub use store_job::*;
+
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::{self, Read, Write},
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::{raw::Raw, Direction, PrismaValue};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::warn;
+
+use crate::{
+	invalidate_query,
+	library::LibraryContext,
+	location::{fetch_location, LocationError},
+	prisma::{chunk, file_manifest, file_path, manifest_chunk},
+	util::{
+		db::{raw_int, OnConflict, Upsert},
+		os_path::resolve_materialized_path,
+	},
+};
+
+use cdc::{ChunkSizes, Chunker};
+
+const CHUNKS_DIR_NAME: &str = "chunks";
+/// How many chunks are looked up or removed at once, under SQLite's placeholder limit
+const BATCH_SIZE: usize = 500;
+
+#[derive(Error, Debug)]
+pub enum ChunkError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("{0}")]
+	LocationError(#[from] LocationError),
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("The chunk store isn't enabled for this library")]
+	Disabled,
+	#[error("File manifest not found (id: {0})")]
+	ManifestNotFound(i32),
+	#[error("Chunk {0} is missing from the store")]
+	MissingChunk(String),
+	#[error("Restored content doesn't match the manifest (id: {0})")]
+	ChecksumMismatch(i32),
+	#[error("A file already exists at '{0}'")]
+	AlreadyExists(PathBuf),
+}
+
+impl From<ChunkError> for rspc::Error {
+	fn from(err: ChunkError) -> Self {
+		match err {
+			ChunkError::ManifestNotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			ChunkError::Disabled | ChunkError::AlreadyExists(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			ChunkError::LocationError(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// chunks_dir is where the library keeps its chunks, each stored under its hash
+pub fn chunks_dir(library: &LibraryContext) -> PathBuf {
+	library
+		.config()
+		.data_directory()
+		.join(CHUNKS_DIR_NAME)
+		.join(library.id.to_string())
+}
+
+/// is_chunk_hash tells if `hash` is a blake3 hash in hex, the only names chunks have
+fn is_chunk_hash(hash: &str) -> bool {
+	hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// chunk_path spreads chunks in directories by the start of their hash, so none gets too big
+fn chunk_path(library: &LibraryContext, hash: &str) -> PathBuf {
+	chunks_dir(library).join(&hash[..2]).join(hash)
+}
+
+/// write_chunk stores a chunk unless it's already there, returning if it was written
+fn write_chunk(library: &LibraryContext, hash: &str, data: &[u8]) -> Result<bool, io::Error> {
+	let target = chunk_path(library, hash);
+	if target.exists() {
+		return Ok(false);
+	}
+
+	std::fs::create_dir_all(target.parent().expect("chunks are in a directory"))?;
+	// written aside and renamed, so a chunk that's there is always whole
+	let partial = target.with_extension("partial");
+	File::create(&partial)?.write_all(data)?;
+	std::fs::rename(partial, target)?;
+
+	Ok(true)
+}
+
+/// ChunkedFile is a file split into chunks that are all in the store
+struct ChunkedFile {
+	/// the hash and size of each chunk, in order
+	chunks: Vec<(String, usize)>,
+	checksum: String,
+	size: u64,
+	new_chunks: usize,
+	new_bytes: u64,
+}
+
+fn chunk_file(library: &LibraryContext, path: &Path) -> Result<ChunkedFile, io::Error> {
+	let mut file = ChunkedFile {
+		chunks: vec![],
+		checksum: String::new(),
+		size: 0,
+		new_chunks: 0,
+		new_bytes: 0,
+	};
+	let mut hasher = blake3::Hasher::new();
+
+	for chunk in Chunker::new(File::open(path)?, ChunkSizes::default()) {
+		let chunk = chunk?;
+		hasher.update(&chunk);
+
+		let hash = blake3::hash(&chunk).to_hex().to_string();
+		if write_chunk(library, &hash, &chunk)? {
+			file.new_chunks += 1;
+			file.new_bytes += chunk.len() as u64;
+		}
+		file.size += chunk.len() as u64;
+		file.chunks.push((hash, chunk.len()));
+	}
+	file.checksum = hasher.finalize().to_hex().to_string();
+
+	Ok(file)
+}
+
+/// What storing a file did
+#[derive(Debug, Default)]
+pub struct StoredFile {
+	/// the new manifest, none if the latest one of the file already has its content
+	pub manifest: Option<file_manifest::Data>,
+	pub new_chunks: usize,
+	pub new_bytes: u64,
+}
+
+/// store_file puts the content of `file_path`, found at `path`, in the chunk store with a manifest,
+/// unless the latest manifest of the file already has it
+pub async fn store_file(
+	library: &LibraryContext,
+	file_path: &file_path::Data,
+	cas_id: Option<&str>,
+	path: &Path,
+) -> Result<StoredFile, ChunkError> {
+	let latest = library
+		.db
+		.file_manifest()
+		.find_first(vec![
+			file_manifest::location_id::equals(file_path.location_id),
+			file_manifest::file_path_id::equals(file_path.id),
+		])
+		.order_by(file_manifest::id::order(Direction::Desc))
+		.exec()
+		.await?;
+	// not even read when it wasn't modified since it was stored
+	if let Some(latest) = &latest {
+		if cas_id.is_some()
+			&& latest.cas_id.as_deref() == cas_id
+			&& file_path.date_modified <= latest.date_created
+		{
+			return Ok(StoredFile::default());
+		}
+	}
+
+	let file = block_in_place(|| chunk_file(library, path))?;
+	if latest.map_or(false, |latest| latest.checksum == file.checksum) {
+		return Ok(StoredFile::default());
+	}
+
+	let mut sizes = HashMap::new();
+	for (hash, size) in &file.chunks {
+		sizes.insert(hash.as_str(), *size);
+	}
+	Upsert::new("chunk", ["hash", "size"])
+		.rows(sizes.into_iter().map(|(hash, size)| {
+			[
+				PrismaValue::String(hash.to_string()),
+				PrismaValue::Int(size as i64),
+			]
+		}))
+		.on_conflict(OnConflict::DoNothing(&["hash"]))
+		.exec(&library.db)
+		.await?;
+
+	let manifest = library
+		.db
+		.file_manifest()
+		.create(
+			file_path.location_id,
+			file_path.id,
+			file_path.materialized_path.clone(),
+			file.checksum,
+			file.size as i64,
+			vec![file_manifest::cas_id::set(cas_id.map(str::to_string))],
+		)
+		.exec()
+		.await?;
+
+	let linked = Upsert::new("manifest_chunk", ["manifest_id", "position", "chunk_hash"])
+		.rows(
+			file.chunks
+				.into_iter()
+				.enumerate()
+				.map(|(position, (hash, _))| {
+					[
+						PrismaValue::Int(manifest.id as i64),
+						PrismaValue::Int(position as i64),
+						PrismaValue::String(hash),
+					]
+				}),
+		)
+		.exec(&library.db)
+		.await;
+	// a manifest missing some of its chunks would restore a broken file
+	if let Err(e) = linked {
+		library
+			.db
+			.file_manifest()
+			.delete_many(vec![file_manifest::id::equals(manifest.id)])
+			.exec()
+			.await?;
+		return Err(e.into());
+	}
+
+	Ok(StoredFile {
+		manifest: Some(manifest),
+		new_chunks: file.new_chunks,
+		new_bytes: file.new_bytes,
+	})
+}
+
+/// file_manifests lists the stored versions of a file path, the latest first
+pub async fn file_manifests(
+	library: &LibraryContext,
+	location_id: i32,
+	file_path_id: i32,
+) -> Result<Vec<file_manifest::Data>, ChunkError> {
+	Ok(library
+		.db
+		.file_manifest()
+		.find_many(vec![
+			file_manifest::location_id::equals(location_id),
+			file_manifest::file_path_id::equals(file_path_id),
+		])
+		.order_by(file_manifest::id::order(Direction::Desc))
+		.exec()
+		.await?)
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct ManifestChunkRef {
+	pub hash: String,
+	pub size: i32,
+}
+
+/// ManifestContent is what a peer needs to rebuild a file from chunks
+#[derive(Serialize, Type, Debug)]
+pub struct ManifestContent {
+	pub manifest: file_manifest::Data,
+	/// the chunks of the file, in order
+	pub chunks: Vec<ManifestChunkRef>,
+}
+
+/// manifest_content returns a manifest along with its chunks
+pub async fn manifest_content(
+	library: &LibraryContext,
+	manifest_id: i32,
+) -> Result<ManifestContent, ChunkError> {
+	let manifest = library
+		.db
+		.file_manifest()
+		.find_unique(file_manifest::id::equals(manifest_id))
+		.exec()
+		.await?
+		.ok_or(ChunkError::ManifestNotFound(manifest_id))?;
+
+	let chunks = library
+		.db
+		.manifest_chunk()
+		.find_many(vec![manifest_chunk::manifest_id::equals(manifest_id)])
+		.order_by(manifest_chunk::position::order(Direction::Asc))
+		.with(manifest_chunk::chunk::fetch())
+		.exec()
+		.await?
+		.into_iter()
+		.map(|manifest_chunk| ManifestChunkRef {
+			size: manifest_chunk.chunk().map_or(0, |chunk| chunk.size),
+			hash: manifest_chunk.chunk_hash,
+		})
+		.collect();
+
+	Ok(ManifestContent { manifest, chunks })
+}
+
+/// missing_chunks returns which of the given chunks aren't in the store, the ones a peer sending a
+/// file has to send
+pub async fn missing_chunks(
+	library: &LibraryContext,
+	hashes: Vec<String>,
+) -> Result<Vec<String>, ChunkError> {
+	let mut missing = vec![];
+	for batch in hashes.chunks(BATCH_SIZE) {
+		let stored = library
+			.db
+			.chunk()
+			.find_many(vec![chunk::hash::in_vec(batch.to_vec())])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|chunk| chunk.hash)
+			.collect::<Vec<_>>();
+		missing.extend(
+			batch
+				.iter()
+				.filter(|hash| !stored.contains(hash) || !chunk_path(library, hash).exists())
+				.cloned(),
+		);
+	}
+
+	Ok(missing)
+}
+
+/// read_chunk returns the content of a chunk, checked against its hash
+pub fn read_chunk(library: &LibraryContext, hash: &str) -> Result<Vec<u8>, ChunkError> {
+	// hashes come from peers, and mustn't lead out of the store
+	if !is_chunk_hash(hash) {
+		return Err(ChunkError::MissingChunk(hash.to_string()));
+	}
+
+	let mut data = vec![];
+	match File::open(chunk_path(library, hash)) {
+		Ok(mut file) => file.read_to_end(&mut data)?,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {
+			return Err(ChunkError::MissingChunk(hash.to_string()))
+		}
+		Err(e) => return Err(e.into()),
+	};
+
+	if blake3::hash(&data).to_hex().as_str() != hash {
+		return Err(ChunkError::MissingChunk(hash.to_string()));
+	}
+
+	Ok(data)
+}
+
+/// put_chunk adds a chunk received from a peer to the store, returning its hash. It's collected as
+/// garbage unless a manifest lists it by then.
+pub async fn put_chunk(library: &LibraryContext, data: &[u8]) -> Result<String, ChunkError> {
+	let hash = blake3::hash(data).to_hex().to_string();
+	block_in_place(|| write_chunk(library, &hash, data))?;
+
+	Upsert::new("chunk", ["hash", "size"])
+		.row([
+			PrismaValue::String(hash.clone()),
+			PrismaValue::Int(data.len() as i64),
+		])
+		.on_conflict(OnConflict::DoNothing(&["hash"]))
+		.exec(&library.db)
+		.await?;
+
+	Ok(hash)
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct RestoreManifestArgs {
+	pub manifest_id: i32,
+	/// where to write the file, none for next to the file it was stored from, with a conflict name
+	pub target: Option<PathBuf>,
+}
+
+/// restore_manifest rebuilds the content of a manifest from its chunks, and returns where it was
+/// restored to
+pub async fn restore_manifest(
+	library: &LibraryContext,
+	args: RestoreManifestArgs,
+) -> Result<PathBuf, ChunkError> {
+	let content = manifest_content(library, args.manifest_id).await?;
+	let manifest = &content.manifest;
+
+	let target = match args.target {
+		Some(target) if target.exists() => return Err(ChunkError::AlreadyExists(target)),
+		Some(target) => target,
+		None => {
+			let location = fetch_location(library, manifest.location_id)
+				.exec()
+				.await?
+				.ok_or(LocationError::IdNotFound(manifest.location_id))?;
+			let location_path = location
+				.local_path
+				.ok_or(LocationError::MissingLocalPath(manifest.location_id))?;
+
+			let node_config = library.config().get().await;
+			node_config.conflict_naming.free_path(
+				&resolve_materialized_path(location_path, &manifest.path, None),
+				&node_config.name,
+			)
+		}
+	};
+
+	block_in_place(|| {
+		if let Some(parent) = target.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		let partial = target.with_file_name(format!(
+			".{}.sdrestore",
+			target.file_name().unwrap_or_default().to_string_lossy()
+		));
+
+		let mut writer = File::create(&partial)?;
+		let mut hasher = blake3::Hasher::new();
+		let written = content.chunks.iter().try_for_each(|chunk| {
+			let data = read_chunk(library, &chunk.hash)?;
+			hasher.update(&data);
+			writer.write_all(&data)?;
+			Ok::<_, ChunkError>(())
+		});
+
+		let written = written.and_then(|_| {
+			if hasher.finalize().to_hex().as_str() != manifest.checksum {
+				return Err(ChunkError::ChecksumMismatch(manifest.id));
+			}
+			Ok(())
+		});
+		if let Err(e) = written {
+			drop(writer);
+			std::fs::remove_file(&partial).ok();
+			return Err(e);
+		}
+
+		std::fs::rename(&partial, &target)?;
+
+		Ok::<_, ChunkError>(())
+	})?;
+
+	invalidate_query!(library, "locations.getExplorerData");
+
+	Ok(target)
+}
+
+/// delete_manifest forgets a stored version of a file, and removes the chunks no other manifest has
+pub async fn delete_manifest(library: &LibraryContext, manifest_id: i32) -> Result<(), ChunkError> {
+	let deleted = library
+		.db
+		.file_manifest()
+		.delete_many(vec![file_manifest::id::equals(manifest_id)])
+		.exec()
+		.await?;
+	if deleted == 0 {
+		return Err(ChunkError::ManifestNotFound(manifest_id));
+	}
+
+	collect_garbage(library).await?;
+
+	Ok(())
+}
+
+#[derive(Deserialize)]
+struct UnusedChunk {
+	hash: String,
+}
+
+/// collect_garbage removes the chunks no manifest has, returning how many were removed
+pub async fn collect_garbage(library: &LibraryContext) -> Result<usize, ChunkError> {
+	let unused: Vec<UnusedChunk> = library
+		.db
+		._query_raw(Raw::new(
+			"SELECT hash FROM chunk WHERE NOT EXISTS \
+			(SELECT 1 FROM manifest_chunk mc WHERE mc.chunk_hash = chunk.hash)",
+			vec![],
+		))
+		.exec()
+		.await?;
+
+	let hashes = unused
+		.into_iter()
+		.map(|chunk| chunk.hash)
+		.collect::<Vec<_>>();
+	for batch in hashes.chunks(BATCH_SIZE) {
+		library
+			.db
+			.chunk()
+			.delete_many(vec![chunk::hash::in_vec(batch.to_vec())])
+			.exec()
+			.await?;
+	}
+
+	for hash in &hashes {
+		if let Err(e) = std::fs::remove_file(chunk_path(library, hash)) {
+			if e.kind() != io::ErrorKind::NotFound {
+				warn!("Failed to remove chunk {}: {:#?}", hash, e);
+			}
+		}
+	}
+
+	if !hashes.is_empty() {
+		invalidate_query!(library, "chunks.stats");
+	}
+
+	Ok(hashes.len())
+}
+
+#[derive(Deserialize)]
+struct RawChunkStoreStats {
+	#[serde(deserialize_with = "raw_int")]
+	chunks: i64,
+	#[serde(deserialize_with = "raw_int")]
+	stored_bytes: i64,
+	#[serde(deserialize_with = "raw_int")]
+	manifests: i64,
+	#[serde(deserialize_with = "raw_int")]
+	logical_bytes: i64,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ChunkStoreStats {
+	pub enabled: bool,
+	pub chunks: u64,
+	/// what the chunks take up on disk
+	pub stored_bytes: u64,
+	pub manifests: u64,
+	/// what the stored files would take up if each was kept whole
+	pub logical_bytes: u64,
+	pub last_stored: Option<DateTime<Utc>>,
+}
+
+pub async fn chunk_store_stats(library: &LibraryContext) -> Result<ChunkStoreStats, ChunkError> {
+	let stats: Vec<RawChunkStoreStats> = library
+		.db
+		._query_raw(Raw::new(
+			"SELECT (SELECT COUNT(*) FROM chunk) AS chunks, \
+			(SELECT COALESCE(SUM(size), 0) FROM chunk) AS stored_bytes, \
+			(SELECT COUNT(*) FROM file_manifest) AS manifests, \
+			(SELECT COALESCE(SUM(size_in_bytes), 0) FROM file_manifest) AS logical_bytes",
+			vec![],
+		))
+		.exec()
+		.await?;
+	let last_stored = library
+		.db
+		.file_manifest()
+		.find_first(vec![])
+		.order_by(file_manifest::id::order(Direction::Desc))
+		.exec()
+		.await?
+		.map(|manifest| manifest.date_created.into());
+
+	let stats = stats.into_iter().next();
+	Ok(ChunkStoreStats {
+		enabled: library.config.chunk_store,
+		chunks: stats.as_ref().map_or(0, |stats| stats.chunks as u64),
+		stored_bytes: stats.as_ref().map_or(0, |stats| stats.stored_bytes as u64),
+		manifests: stats.as_ref().map_or(0, |stats| stats.manifests as u64),
+		logical_bytes: stats.as_ref().map_or(0, |stats| stats.logical_bytes as u64),
+		last_stored,
+	})
+}
@@ -0,0 +1,192 @@
+use std::path::PathBuf;
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+	invalidate_query,
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	object::fs::batch::local_location,
+	prisma::file_path,
+	util::{message::Message, os_path::resolve_materialized_path},
+};
+
+use super::{store_file, ChunkError};
+
+pub const CHUNK_STORE_JOB_NAME: &str = "chunk_store";
+
+/// ChunkStoreJob puts the files of a location, or of a directory in it, in the library's chunk
+/// store. Files whose latest manifest already has their content are skipped, so running it again
+/// only stores what changed since.
+pub struct ChunkStoreJob;
+
+#[derive(Serialize, Deserialize, Type, Debug)]
+pub struct ChunkStoreJobInit {
+	pub location_id: i32,
+	/// the materialized path of a directory to store the files of, none for the whole location
+	pub sub_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChunkStoreJobState {
+	location_path: PathBuf,
+	stored: usize,
+	unchanged: usize,
+	new_chunks: usize,
+	new_bytes: u64,
+	failed: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ChunkStoreJob {
+	type Init = ChunkStoreJobInit;
+	type Data = ChunkStoreJobState;
+	/// the ids of the file paths to store
+	type Step = i32;
+
+	fn name(&self) -> &'static str {
+		CHUNK_STORE_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		if !library.config.chunk_store {
+			return Err(ChunkError::Disabled.into());
+		}
+
+		let (location_path, _) = local_location(&library, state.init.location_id).await?;
+
+		let mut params = vec![
+			file_path::location_id::equals(state.init.location_id),
+			file_path::is_dir::equals(false),
+		];
+		if let Some(sub_path) = &state.init.sub_path {
+			params.push(file_path::materialized_path::starts_with(format!(
+				"{}/",
+				sub_path.trim_end_matches('/')
+			)));
+		}
+
+		let file_paths = library.db.file_path().find_many(params).exec().await?;
+		state
+			.steps
+			.extend(file_paths.into_iter().map(|file_path| file_path.id));
+
+		state.data = Some(ChunkStoreJobState {
+			location_path,
+			stored: 0,
+			unchanged: 0,
+			new_chunks: 0,
+			new_bytes: 0,
+			failed: 0,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		let file_path = library
+			.db
+			.file_path()
+			.find_unique(file_path::location_id_id(
+				state.init.location_id,
+				state.steps[0],
+			))
+			.with(file_path::object::fetch())
+			.exec()
+			.await?;
+		// deleted since the job started
+		if let Some(file_path) = file_path {
+			ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+				path: file_path.materialized_path.clone(),
+			})]);
+
+			let cas_id = file_path
+				.object()
+				.ok()
+				.flatten()
+				.map(|object| object.cas_id.clone());
+			let path = resolve_materialized_path(
+				&data.location_path,
+				&file_path.materialized_path,
+				file_path.raw_path.as_deref(),
+			);
+
+			match store_file(&library, &file_path, cas_id.as_deref(), &path).await {
+				Ok(stored) => {
+					match stored.manifest {
+						Some(_) => data.stored += 1,
+						None => data.unchanged += 1,
+					}
+					data.new_chunks += stored.new_chunks;
+					data.new_bytes += stored.new_bytes;
+				}
+				Err(ChunkError::IOError(e)) => {
+					ctx.record_file_error(FileError::new(&file_path, &e)).await;
+					data.failed += 1;
+				}
+				Err(e) => return Err(e.into()),
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		invalidate_query!(library, "chunks.stats");
+		if data.stored > 0 {
+			invalidate_query!(library, "chunks.getManifests");
+		}
+
+		info!(
+			"Stored location {} in the chunk store: {} files stored, {} unchanged, {} failed, \
+			{} new chunks ({} bytes)",
+			state.init.location_id,
+			data.stored,
+			data.unchanged,
+			data.failed,
+			data.new_chunks,
+			data.new_bytes
+		);
+
+		Ok(Some(json!({
+			"stored": data.stored,
+			"unchanged": data.unchanged,
+			"failed": data.failed,
+			"new_chunks": data.new_chunks,
+			"new_bytes": data.new_bytes,
+		})))
+	}
+}
@@ -0,0 +1,852 @@
+//! Faces in photos, found and told apart on this device like labels are. A detector
+//! (`models/face_detector.onnx`, an UltraFace style model taking a 320x240 image and returning the
+//! scores and corners of the boxes it considered) finds the faces in an image, and an embedder
+//! (`models/face_embedder.onnx`, an ArcFace style model taking a 112x112 face) turns each into a
+//! vector pointing the same way for faces of the same person. New faces join the person whose faces
+//! they're closest to, or start an unnamed person of their own, which can then be named, merged with
+//! another when one person was split in two, or split when faces were grouped wrongly.
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use image::DynamicImage;
+use once_cell::sync::OnceCell;
+use rspc::{ErrorCode, Type};
+use sd_file_ext::extensions::Extension;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::LocationError,
+	prisma::{face, file_path, location, object, person},
+	util::os_path::resolve_materialized_path,
+};
+
+use super::{labeler::MODELS_DIR_NAME, preview::THUMBNAIL_IMAGE_EXTENSIONS};
+
+pub const FACE_GROUPER_JOB_NAME: &str = "face_grouper";
+#[cfg(feature = "ml")]
+const DETECTOR_FILE_NAME: &str = "face_detector.onnx";
+#[cfg(feature = "ml")]
+const EMBEDDER_FILE_NAME: &str = "face_embedder.onnx";
+#[cfg(feature = "ml")]
+const DETECTOR_WIDTH: u32 = 320;
+#[cfg(feature = "ml")]
+const DETECTOR_HEIGHT: u32 = 240;
+/// The detector takes `(pixel - 127) / 128`
+#[cfg(feature = "ml")]
+const DETECTOR_MEAN: [f32; 3] = [127.0 / 255.0; 3];
+#[cfg(feature = "ml")]
+const DETECTOR_STD: [f32; 3] = [128.0 / 255.0; 3];
+#[cfg(feature = "ml")]
+const EMBEDDER_SIZE: u32 = 112;
+/// The embedder takes `(pixel - 127.5) / 127.5`
+#[cfg(feature = "ml")]
+const EMBEDDER_MEAN: [f32; 3] = [0.5; 3];
+#[cfg(feature = "ml")]
+const EMBEDDER_STD: [f32; 3] = [0.5; 3];
+/// How sure the detector has to be a box is a face
+#[cfg(any(feature = "ml", test))]
+const MIN_FACE_CONFIDENCE: f32 = 0.7;
+/// How much two boxes can overlap before they're taken to be the same face
+#[cfg(any(feature = "ml", test))]
+const MAX_OVERLAP: f32 = 0.3;
+/// Faces smaller than this part of the image's width are too blurry to tell apart
+#[cfg(feature = "ml")]
+const MIN_FACE_WIDTH: f32 = 0.03;
+/// How much of the face's size is added around it before it's embedded, like the embedder was
+/// trained with
+#[cfg(feature = "ml")]
+const FACE_MARGIN: f32 = 0.1;
+/// How alike, from -1 to 1, a face has to be to a person's faces to be theirs
+const SAME_PERSON_SIMILARITY: f32 = 0.5;
+
+/// The face models are loaded once, the first time faces are searched for
+static FACE_MODELS: OnceCell<FaceModels> = OnceCell::new();
+
+#[derive(Error, Debug)]
+pub enum FaceError {
+	#[error("this node was built without face grouping")]
+	NotAvailable,
+	#[error("no face model at {0}")]
+	ModelNotFound(PathBuf),
+	#[error("face model error: {0}")]
+	Model(String),
+	#[error("person not found (id: {0})")]
+	PersonNotFound(i32),
+	#[error("a person can't be merged into itself")]
+	MergeIntoSelf,
+	#[error("no faces to split off")]
+	NoFaces,
+	#[error("database error: {0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+}
+
+impl From<FaceError> for rspc::Error {
+	fn from(err: FaceError) -> Self {
+		match err {
+			FaceError::PersonNotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			FaceError::MergeIntoSelf | FaceError::NoFaces => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// A box around a face, relative to the size of the image from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaceBox {
+	pub x: f32,
+	pub y: f32,
+	pub width: f32,
+	pub height: f32,
+	pub confidence: f32,
+}
+
+impl FaceBox {
+	/// overlap returns how much of the two boxes is shared, their intersection over their union
+	#[cfg(any(feature = "ml", test))]
+	fn overlap(&self, other: &Self) -> f32 {
+		let width = (self.x + self.width).min(other.x + other.width) - self.x.max(other.x);
+		let height = (self.y + self.height).min(other.y + other.height) - self.y.max(other.y);
+		if width <= 0.0 || height <= 0.0 {
+			return 0.0;
+		}
+
+		let intersection = width * height;
+		intersection / (self.width * self.height + other.width * other.height - intersection)
+	}
+}
+
+/// detections reads the boxes of the detector, scores being pairs of background and face
+/// probabilities and corners the top left and bottom right corners of each box, and keeps the
+/// surest box of each face
+#[cfg(any(feature = "ml", test))]
+fn detections(scores: &[f32], corners: &[f32]) -> Vec<FaceBox> {
+	let mut boxes = scores
+		.chunks_exact(2)
+		.zip(corners.chunks_exact(4))
+		.filter(|(score, _)| score[1] >= MIN_FACE_CONFIDENCE)
+		.map(|(score, corners)| {
+			let (left, top) = (corners[0].clamp(0.0, 1.0), corners[1].clamp(0.0, 1.0));
+			let (right, bottom) = (corners[2].clamp(0.0, 1.0), corners[3].clamp(0.0, 1.0));
+			FaceBox {
+				x: left,
+				y: top,
+				width: right - left,
+				height: bottom - top,
+				confidence: score[1],
+			}
+		})
+		.filter(|face| face.width > 0.0 && face.height > 0.0)
+		.collect::<Vec<_>>();
+	boxes.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+	let mut kept: Vec<FaceBox> = vec![];
+	for face in boxes {
+		if kept.iter().all(|other| face.overlap(other) <= MAX_OVERLAP) {
+			kept.push(face);
+		}
+	}
+	kept
+}
+
+/// normalize scales a vector to unit length
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+	let length = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+	if length > 0.0 {
+		vector.iter_mut().for_each(|value| *value /= length);
+	}
+	vector
+}
+
+/// similarity returns the cosine similarity of two vectors of unit length
+fn similarity(a: &[f32], b: &[f32]) -> f32 {
+	a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn embedding_to_bytes(embedding: &[f32]) -> Vec<u8> {
+	embedding
+		.iter()
+		.flat_map(|value| value.to_le_bytes())
+		.collect()
+}
+
+fn embedding_from_bytes(bytes: &[u8]) -> Vec<f32> {
+	bytes
+		.chunks_exact(4)
+		.map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+		.collect()
+}
+
+/// FaceModels run the face detector and embedder
+pub struct FaceModels {
+	#[cfg(feature = "ml")]
+	detector: super::labeler::OnnxModel,
+	#[cfg(feature = "ml")]
+	embedder: super::labeler::OnnxModel,
+}
+
+impl FaceModels {
+	/// get returns the face models of the node, loading them the first time
+	pub fn get(data_dir: &Path) -> Result<&'static Self, FaceError> {
+		FACE_MODELS
+			.get_or_try_init(|| block_in_place(|| Self::load(&data_dir.join(MODELS_DIR_NAME))))
+	}
+
+	#[cfg(feature = "ml")]
+	fn load(models_dir: &Path) -> Result<Self, FaceError> {
+		use super::labeler::load_onnx;
+
+		let load = |file_name: &str, width: u32, height: u32| {
+			let path = models_dir.join(file_name);
+			if !path.exists() {
+				return Err(FaceError::ModelNotFound(path));
+			}
+			load_onnx(&path, width, height).map_err(|e| FaceError::Model(e.to_string()))
+		};
+
+		Ok(Self {
+			detector: load(DETECTOR_FILE_NAME, DETECTOR_WIDTH, DETECTOR_HEIGHT)?,
+			embedder: load(EMBEDDER_FILE_NAME, EMBEDDER_SIZE, EMBEDDER_SIZE)?,
+		})
+	}
+
+	#[cfg(not(feature = "ml"))]
+	fn load(_: &Path) -> Result<Self, FaceError> {
+		Err(FaceError::NotAvailable)
+	}
+
+	/// find_faces returns the faces in an image, each with its embedding
+	#[cfg(feature = "ml")]
+	pub fn find_faces(&self, img: &DynamicImage) -> Result<Vec<(FaceBox, Vec<f32>)>, FaceError> {
+		use super::labeler::run_onnx;
+		use image::imageops::FilterType;
+
+		let model_error = |e: tract_onnx::prelude::TractError| FaceError::Model(e.to_string());
+
+		let input = img
+			.resize_exact(DETECTOR_WIDTH, DETECTOR_HEIGHT, FilterType::Triangle)
+			.to_rgb8();
+		let mut outputs =
+			run_onnx(&self.detector, &input, DETECTOR_MEAN, DETECTOR_STD).map_err(model_error)?;
+		if outputs.len() < 2 {
+			return Err(FaceError::Model(
+				"the detector should return scores and boxes".into(),
+			));
+		}
+		let corners = outputs.swap_remove(1);
+		let scores = outputs.swap_remove(0);
+
+		let (width, height) = (img.width() as f32, img.height() as f32);
+		detections(&scores, &corners)
+			.into_iter()
+			.filter(|face| face.width >= MIN_FACE_WIDTH)
+			.map(|face| {
+				let (margin_x, margin_y) = (face.width * FACE_MARGIN, face.height * FACE_MARGIN);
+				let left = ((face.x - margin_x).max(0.0) * width) as u32;
+				let top = ((face.y - margin_y).max(0.0) * height) as u32;
+				let right = ((face.x + face.width + margin_x).min(1.0) * width) as u32;
+				let bottom = ((face.y + face.height + margin_y).min(1.0) * height) as u32;
+
+				let crop = img
+					.crop_imm(left, top, (right - left).max(1), (bottom - top).max(1))
+					.resize_exact(EMBEDDER_SIZE, EMBEDDER_SIZE, FilterType::Triangle)
+					.to_rgb8();
+				let embedding = run_onnx(&self.embedder, &crop, EMBEDDER_MEAN, EMBEDDER_STD)
+					.map_err(model_error)?
+					.swap_remove(0);
+
+				Ok((face, normalize(embedding)))
+			})
+			.collect()
+	}
+
+	#[cfg(not(feature = "ml"))]
+	pub fn find_faces(&self, _: &DynamicImage) -> Result<Vec<(FaceBox, Vec<f32>)>, FaceError> {
+		Err(FaceError::NotAvailable)
+	}
+}
+
+/// The faces of a person added up, to find the person a face is closest to
+struct PersonFaces {
+	person_id: i32,
+	sum: Vec<f32>,
+}
+
+impl PersonFaces {
+	fn add(&mut self, embedding: &[f32]) {
+		if self.sum.is_empty() {
+			self.sum = vec![0.0; embedding.len()];
+		}
+		self.sum
+			.iter_mut()
+			.zip(embedding)
+			.for_each(|(sum, value)| *sum += value);
+	}
+}
+
+/// closest_person returns which of the people a face belongs to, if any is alike enough
+fn closest_person(people: &[PersonFaces], embedding: &[f32]) -> Option<usize> {
+	people
+		.iter()
+		.enumerate()
+		.map(|(i, person)| (i, similarity(&normalize(person.sum.clone()), embedding)))
+		.filter(|(_, similarity)| *similarity >= SAME_PERSON_SIMILARITY)
+		.max_by(|(_, a), (_, b)| a.total_cmp(b))
+		.map(|(i, _)| i)
+}
+
+/// group_faces puts each face that has no person yet with the person its closest to, or a new one.
+/// Returns how many faces were grouped.
+pub async fn group_faces(library: &LibraryContext) -> Result<usize, FaceError> {
+	let mut people = HashMap::<i32, PersonFaces>::new();
+	for face in library
+		.db
+		.face()
+		.find_many(vec![face::person_id::not(None)])
+		.exec()
+		.await?
+	{
+		if let Some(person_id) = face.person_id {
+			people
+				.entry(person_id)
+				.or_insert(PersonFaces {
+					person_id,
+					sum: vec![],
+				})
+				.add(&embedding_from_bytes(&face.embedding));
+		}
+	}
+	let mut people = people.into_values().collect::<Vec<_>>();
+
+	let faces = library
+		.db
+		.face()
+		.find_many(vec![face::person_id::equals(None)])
+		.exec()
+		.await?;
+
+	let mut grouped = HashMap::<i32, Vec<i32>>::new();
+	for face in &faces {
+		let embedding = embedding_from_bytes(&face.embedding);
+		let i = match closest_person(&people, &embedding) {
+			Some(i) => i,
+			None => {
+				let person = library.db.person().create(vec![]).exec().await?;
+				people.push(PersonFaces {
+					person_id: person.id,
+					sum: vec![],
+				});
+				people.len() - 1
+			}
+		};
+		people[i].add(&embedding);
+		grouped
+			.entry(people[i].person_id)
+			.or_default()
+			.push(face.id);
+	}
+
+	for (person_id, face_ids) in grouped {
+		library
+			.db
+			.face()
+			.update_many(
+				vec![face::id::in_vec(face_ids)],
+				vec![face::person_id::set(Some(person_id))],
+			)
+			.exec()
+			.await?;
+	}
+
+	Ok(faces.len())
+}
+
+/// A face as the client sees it, to crop it out of its object's thumbnail
+#[derive(Serialize, Type, Debug)]
+pub struct FaceInfo {
+	pub id: i32,
+	pub object_id: i32,
+	pub person_id: Option<i32>,
+	pub x: f64,
+	pub y: f64,
+	pub width: f64,
+	pub height: f64,
+}
+
+impl From<face::Data> for FaceInfo {
+	fn from(face: face::Data) -> Self {
+		Self {
+			id: face.id,
+			object_id: face.object_id,
+			person_id: face.person_id,
+			x: face.x,
+			y: face.y,
+			width: face.width,
+			height: face.height,
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct PersonSummary {
+	pub id: i32,
+	pub name: Option<String>,
+	pub face_count: i32,
+	/// the face shown for the person, the surest of them
+	pub cover: Option<FaceInfo>,
+}
+
+/// list_people returns the people of the library, the ones with the most faces first
+pub async fn list_people(library: &LibraryContext) -> Result<Vec<PersonSummary>, FaceError> {
+	let mut people = library
+		.db
+		.person()
+		.find_many(vec![])
+		.with(person::faces::fetch(vec![]))
+		.exec()
+		.await?
+		.into_iter()
+		.map(|mut person| {
+			let faces = person.faces.take().unwrap_or_default();
+			PersonSummary {
+				id: person.id,
+				name: person.name,
+				face_count: faces.len() as i32,
+				cover: faces
+					.into_iter()
+					.max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+					.map(Into::into),
+			}
+		})
+		.collect::<Vec<_>>();
+	people.sort_by(|a, b| b.face_count.cmp(&a.face_count).then(a.id.cmp(&b.id)));
+
+	Ok(people)
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct RenamePersonArgs {
+	pub id: i32,
+	pub name: Option<String>,
+}
+
+/// rename_person names a person, or makes them unnamed again
+pub async fn rename_person(
+	library: &LibraryContext,
+	args: RenamePersonArgs,
+) -> Result<(), FaceError> {
+	let name = args
+		.name
+		.map(|name| name.trim().to_string())
+		.filter(|name| !name.is_empty());
+
+	let updated = library
+		.db
+		.person()
+		.update_many(
+			vec![person::id::equals(args.id)],
+			vec![
+				person::name::set(name),
+				person::date_modified::set(Utc::now().into()),
+			],
+		)
+		.exec()
+		.await?;
+	if updated == 0 {
+		return Err(FaceError::PersonNotFound(args.id));
+	}
+
+	Ok(())
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct MergePeopleArgs {
+	/// the person the others are merged into, who keeps their name
+	pub into: i32,
+	pub people: Vec<i32>,
+}
+
+/// merge_people moves the faces of the people into another, and removes them
+pub async fn merge_people(
+	library: &LibraryContext,
+	args: MergePeopleArgs,
+) -> Result<(), FaceError> {
+	if args.people.contains(&args.into) {
+		return Err(FaceError::MergeIntoSelf);
+	}
+	let into = library
+		.db
+		.person()
+		.find_unique(person::id::equals(args.into))
+		.exec()
+		.await?
+		.ok_or(FaceError::PersonNotFound(args.into))?;
+
+	// a person merged into an unnamed one lends them their name
+	let name = match into.name {
+		Some(_) => None,
+		None => library
+			.db
+			.person()
+			.find_many(vec![
+				person::id::in_vec(args.people.clone()),
+				person::name::not(None),
+			])
+			.exec()
+			.await?
+			.into_iter()
+			.next()
+			.and_then(|person| person.name),
+	};
+
+	library
+		.db
+		.face()
+		.update_many(
+			vec![face::person_id::in_vec(args.people.clone())],
+			vec![face::person_id::set(Some(args.into))],
+		)
+		.exec()
+		.await?;
+	library
+		.db
+		.person()
+		.delete_many(vec![person::id::in_vec(args.people)])
+		.exec()
+		.await?;
+
+	let mut params = vec![person::date_modified::set(Utc::now().into())];
+	if name.is_some() {
+		params.push(person::name::set(name));
+	}
+	library
+		.db
+		.person()
+		.update(person::id::equals(args.into), params)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+/// split_person moves faces that were grouped wrongly to a new unnamed person, and returns their id.
+/// People left without faces are removed.
+pub async fn split_person(library: &LibraryContext, face_ids: Vec<i32>) -> Result<i32, FaceError> {
+	let faces = library
+		.db
+		.face()
+		.find_many(vec![face::id::in_vec(face_ids.clone())])
+		.exec()
+		.await?;
+	if faces.is_empty() {
+		return Err(FaceError::NoFaces);
+	}
+
+	let person = library.db.person().create(vec![]).exec().await?;
+	library
+		.db
+		.face()
+		.update_many(
+			vec![face::id::in_vec(face_ids)],
+			vec![face::person_id::set(Some(person.id))],
+		)
+		.exec()
+		.await?;
+
+	let mut previous = faces
+		.iter()
+		.filter_map(|face| face.person_id)
+		.collect::<Vec<_>>();
+	previous.sort_unstable();
+	previous.dedup();
+	library
+		.db
+		.person()
+		.delete_many(vec![
+			person::id::in_vec(previous),
+			person::faces::none(vec![]),
+		])
+		.exec()
+		.await?;
+
+	Ok(person.id)
+}
+
+/// The face grouper searches the images of a location that weren't searched before for faces, and
+/// groups the faces it finds with the people they belong to
+pub struct FaceGrouperJob {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FaceGrouperJobInit {
+	pub location_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FaceGrouperJobState {
+	root_path: PathBuf,
+	faces: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FaceGrouperJobStep {
+	object_id: i32,
+	materialized_path: String,
+	raw_path: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FaceGrouperJob {
+	type Init = FaceGrouperJobInit;
+	type Data = FaceGrouperJobState;
+	type Step = FaceGrouperJobStep;
+
+	fn name(&self) -> &'static str {
+		FACE_GROUPER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		// the models are loaded first, so nodes without them don't go through every image for nothing
+		FaceModels::get(&library.config().data_directory())?;
+
+		let location = library
+			.db
+			.location()
+			.find_unique(location::id::equals(state.init.location_id))
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+		let root_path = location
+			.local_path
+			.map(PathBuf::from)
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+
+		let mut object_ids = vec![];
+		let steps = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(location.id),
+				file_path::extension::in_vec(
+					THUMBNAIL_IMAGE_EXTENSIONS
+						.into_iter()
+						.map(|extension| Extension::Image(extension).to_string())
+						.collect(),
+				),
+				file_path::object::is(vec![object::faces_scanned::equals(false)]),
+			])
+			.exec()
+			.await?
+			.into_iter()
+			.filter_map(|file_path| {
+				// an object is searched once, whichever of its paths is found first
+				let object_id = file_path.object_id?;
+				if object_ids.contains(&object_id) {
+					return None;
+				}
+				object_ids.push(object_id);
+
+				Some(FaceGrouperJobStep {
+					object_id,
+					materialized_path: file_path.materialized_path,
+					raw_path: file_path.raw_path,
+				})
+			})
+			.collect::<Vec<_>>();
+
+		info!(
+			"Searching {} images in location {} for faces",
+			steps.len(),
+			location.id
+		);
+		ctx.progress(vec![JobReportUpdate::TaskCount(steps.len())]);
+
+		state.steps = steps.into();
+		state.data = Some(FaceGrouperJobState {
+			root_path,
+			faces: 0,
+		});
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state.data.as_mut().expect("fatal: missing job state");
+		let library = ctx.library_ctx();
+		let models = FaceModels::get(&library.config().data_directory())?;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		let path = resolve_materialized_path(
+			&data.root_path,
+			&step.materialized_path,
+			step.raw_path.as_deref(),
+		);
+		let faces = {
+			let _hasher = ctx.governor().hasher().await;
+			block_in_place(|| match image::open(&path) {
+				Ok(img) => models.find_faces(&img).map(Some),
+				Err(e) => {
+					warn!("Couldn't open image {:?} to find faces: {}", path, e);
+					Ok(None)
+				}
+			})?
+		};
+		// images that can't be opened are tried again next time
+		let faces = match faces {
+			Some(faces) => faces,
+			None => return Ok(()),
+		};
+
+		// faces found before are replaced, in case the object was scanned halfway before
+		library
+			.db
+			.face()
+			.delete_many(vec![face::object_id::equals(step.object_id)])
+			.exec()
+			.await?;
+		if !faces.is_empty() {
+			data.faces += faces.len();
+			library
+				.db
+				.face()
+				.create_many(
+					faces
+						.into_iter()
+						.map(|(face, embedding)| {
+							face::create_unchecked(
+								step.object_id,
+								face.x as f64,
+								face.y as f64,
+								face.width as f64,
+								face.height as f64,
+								face.confidence as f64,
+								embedding_to_bytes(&embedding),
+								vec![],
+							)
+						})
+						.collect(),
+				)
+				.exec()
+				.await?;
+		}
+		library
+			.db
+			.object()
+			.update(
+				object::id::equals(step.object_id),
+				vec![object::faces_scanned::set(true)],
+			)
+			.exec()
+			.await?;
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		let library = ctx.library_ctx();
+		let grouped = group_faces(&library).await?;
+		invalidate_query!(library, "people.list");
+
+		info!(
+			"Found {} faces, grouped {} faces with their people",
+			data.faces, grouped
+		);
+
+		Ok(Some(json!({ "faces": data.faces, "grouped": grouped })))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn overlapping_boxes_are_one_face() {
+		let scores = [0.1, 0.9, 0.2, 0.8, 0.05, 0.95, 0.6, 0.4];
+		let corners = [
+			0.1, 0.1, 0.3, 0.4, // a face
+			0.11, 0.12, 0.31, 0.41, // the same face, less sure
+			0.6, 0.2, 0.8, 0.5, // another face
+			0.0, 0.0, 1.0, 1.0, // not a face
+		];
+
+		let faces = detections(&scores, &corners);
+		assert_eq!(faces.len(), 2);
+		assert_eq!(faces[0].confidence, 0.95);
+		assert!((faces[0].x - 0.6).abs() < 1e-6);
+		assert_eq!(faces[1].confidence, 0.9);
+	}
+
+	#[test]
+	fn faces_join_the_closest_person() {
+		let alice = normalize(vec![1.0, 0.1, 0.0]);
+		let bob = normalize(vec![0.0, 1.0, 0.2]);
+		let mut people = vec![
+			PersonFaces {
+				person_id: 1,
+				sum: vec![],
+			},
+			PersonFaces {
+				person_id: 2,
+				sum: vec![],
+			},
+		];
+		people[0].add(&alice);
+		people[1].add(&bob);
+
+		assert_eq!(
+			closest_person(&people, &normalize(vec![0.9, 0.2, 0.1])),
+			Some(0)
+		);
+		assert_eq!(
+			closest_person(&people, &normalize(vec![0.1, 0.9, 0.3])),
+			Some(1)
+		);
+		assert_eq!(
+			closest_person(&people, &normalize(vec![0.0, 0.0, 1.0])),
+			None
+		);
+
+		let embedding = normalize(vec![0.3, -0.5, 0.8]);
+		assert_eq!(
+			embedding_from_bytes(&embedding_to_bytes(&embedding)),
+			embedding
+		);
+	}
+}
@@ -0,0 +1,436 @@
+use std::{
+	collections::VecDeque,
+	ffi::OsString,
+	fs::{self, File, OpenOptions},
+	io::{self, BufReader, BufWriter, Seek, SeekFrom},
+	path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use sd_codec::Artifact;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use tokio::task::block_in_place;
+use tracing::info;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	location::space_monitor::ensure_fits,
+	object::cas::generate_cas_id,
+	prisma::{file_path, location},
+	util::{file_lock::find_lock_holder, message::Message, os_path::resolve_materialized_path},
+};
+
+use super::{delete_file_path_tree, InUseTracker};
+
+pub const ARCHIVE_JOB_NAME: &str = "file_archiver";
+
+pub struct ArchiveJob;
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy)]
+pub enum ArchiveFormat {
+	Tar,
+	TarGz,
+	/// tar compressed with zstd at a high level, for files archived to be kept rather than shared
+	TarZst,
+	/// zip with its entries deflated, which every OS opens without extra tools
+	Zip,
+}
+
+impl ArchiveFormat {
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Self::Tar => "tar",
+			Self::TarGz => "tar.gz",
+			Self::TarZst => "tar.zst",
+			Self::Zip => "zip",
+		}
+	}
+
+	/// required_space estimates the space needed to write an archive of `steps`, which for tar is the
+	/// size of the files plus a header per entry. The compressed archive is written from the finished
+	/// tar, so both exist on disk at the same time and compression is assumed to gain nothing. Zip
+	/// entries are compressed as they're added, with headers smaller than tar's.
+	fn required_space(&self, steps: &VecDeque<ArchiveJobStep>) -> io::Result<u64> {
+		let mut tar_size = 0;
+		for step in steps {
+			// a header block per entry, and the contents padded to the block size
+			tar_size += 512;
+			if !step.is_dir {
+				tar_size += (fs::symlink_metadata(&step.path)?.len() + 511) / 512 * 512;
+			}
+		}
+
+		Ok(match self {
+			Self::Tar | Self::Zip => tar_size,
+			Self::TarGz | Self::TarZst => tar_size * 2,
+		})
+	}
+}
+
+#[derive(Serialize, Deserialize, Type)]
+pub struct ArchiveJobInit {
+	pub location_id: i32,
+	/// file paths to put in the archive, directories are added with all of their contents
+	pub path_ids: Vec<i32>,
+	pub format: ArchiveFormat,
+	/// where to write the archive, defaults to next to the first selected file path
+	pub output_path: Option<PathBuf>,
+	/// remove the archived files once the archive has been written
+	pub delete_originals: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveJobState {
+	root_path: PathBuf,
+	output_path: PathBuf,
+	/// the uncompressed tar, or the zip, being built, entries are appended to it one step at a time
+	tar_path: PathBuf,
+	/// length of the tar written so far, not counting the end of archive marker, or of the zip
+	tar_len: u64,
+	/// materialized paths of the selected file paths, removed once archived if `delete_originals` is set
+	originals: Vec<String>,
+	/// originals that were kept because another application was using them
+	in_use: InUseTracker,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveJobStep {
+	/// absolute path on disk
+	path: PathBuf,
+	/// path of the entry inside the archive
+	entry_name: PathBuf,
+	is_dir: bool,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ArchiveJob {
+	type Init = ArchiveJobInit;
+	type Data = ArchiveJobState;
+	type Step = ArchiveJobStep;
+
+	fn name(&self) -> &'static str {
+		ARCHIVE_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		let location = library
+			.db
+			.location()
+			.find_unique(location::id::equals(state.init.location_id))
+			.exec()
+			.await?
+			.ok_or_else(|| JobError::JobDataNotFound(ARCHIVE_JOB_NAME.to_string()))?;
+
+		let root_path = location
+			.local_path
+			.as_ref()
+			.map(PathBuf::from)
+			.ok_or_else(|| JobError::JobDataNotFound(ARCHIVE_JOB_NAME.to_string()))?;
+
+		let file_paths = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(state.init.location_id),
+				file_path::id::in_vec(state.init.path_ids.clone()),
+			])
+			.exec()
+			.await?;
+
+		if file_paths.is_empty() {
+			return Err(JobError::JobDataNotFound(ARCHIVE_JOB_NAME.to_string()));
+		}
+
+		let output_path = match &state.init.output_path {
+			Some(path) => path.clone(),
+			None => {
+				let first_path = resolve_materialized_path(
+					&root_path,
+					&file_paths[0].materialized_path,
+					file_paths[0].raw_path.as_deref(),
+				);
+				let name = if file_paths.len() == 1 {
+					first_path
+						.file_name()
+						.map(ToOwned::to_owned)
+						.unwrap_or_else(|| OsString::from("Archive"))
+				} else {
+					OsString::from("Archive")
+				};
+
+				let mut file_name = name;
+				file_name.push(".");
+				file_name.push(state.init.format.extension());
+
+				first_path.with_file_name(file_name)
+			}
+		};
+
+		if output_path.exists() {
+			return Err(JobError::IOError(io::Error::new(
+				io::ErrorKind::AlreadyExists,
+				format!("archive '{}' already exists", output_path.display()),
+			)));
+		}
+
+		let mut steps = VecDeque::new();
+		for file_path in &file_paths {
+			let path = resolve_materialized_path(
+				&root_path,
+				&file_path.materialized_path,
+				file_path.raw_path.as_deref(),
+			);
+			let entry_name = path
+				.file_name()
+				.map(PathBuf::from)
+				.unwrap_or_else(|| PathBuf::from(&file_path.name));
+
+			collect_steps(path, entry_name, &mut steps)?;
+		}
+
+		ensure_fits(
+			&library,
+			&output_path,
+			state.init.format.required_space(&steps)?,
+		)?;
+
+		let mut tar_path = output_path.clone().into_os_string();
+		tar_path.push(".part");
+
+		state.data = Some(ArchiveJobState {
+			root_path,
+			output_path,
+			tar_path: tar_path.into(),
+			tar_len: 0,
+			originals: file_paths
+				.into_iter()
+				.map(|file_path| file_path.materialized_path)
+				.collect(),
+			in_use: InUseTracker::default(),
+		});
+		state.steps = steps;
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		ctx.progress_debounced(vec![JobReportUpdate::Message(Message::Compressing {
+			name: step.entry_name.display().to_string(),
+		})]);
+
+		data.tar_len = block_in_place(|| match state.init.format {
+			ArchiveFormat::Zip => append_zip_entry(&data.tar_path, data.tar_len, step),
+			_ => append_entry(&data.tar_path, data.tar_len, step),
+		})?;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		match state.init.format {
+			ArchiveFormat::Tar | ArchiveFormat::Zip => {
+				fs::rename(&data.tar_path, &data.output_path)?
+			}
+			format => {
+				ctx.progress(vec![JobReportUpdate::Message(Message::WritingArchive {
+					path: data.output_path.display().to_string(),
+				})]);
+
+				block_in_place(|| -> Result<(), JobError> {
+					let mut reader = BufReader::new(File::open(&data.tar_path)?);
+					let writer = BufWriter::new(File::create(&data.output_path)?);
+					if let ArchiveFormat::TarZst = format {
+						let mut encoder = Artifact::ColdData.codec().encoder(writer)?;
+						io::copy(&mut reader, &mut encoder)?;
+						encoder.finish()?;
+					} else {
+						let mut encoder = GzEncoder::new(writer, Compression::default());
+						io::copy(&mut reader, &mut encoder)?;
+						encoder.finish()?;
+					}
+					Ok(())
+				})?;
+
+				fs::remove_file(&data.tar_path)?;
+			}
+		}
+
+		let size = fs::metadata(&data.output_path)?.len();
+		let cas_id = generate_cas_id(data.output_path.clone(), size).await?;
+
+		if state.init.delete_originals {
+			let library = ctx.library_ctx();
+
+			for materialized_path in &data.originals {
+				let path = data.root_path.join(materialized_path);
+
+				// the file is already in the archive, so we just leave the original where it is
+				if let Some(holder) = find_lock_holder(&path).await {
+					info!("Keeping {} as it's {holder}", path.display());
+					data.in_use.record(&path, holder);
+					continue;
+				}
+
+				if path.is_dir() {
+					fs::remove_dir_all(&path)?;
+				} else {
+					fs::remove_file(&path)?;
+				}
+
+				delete_file_path_tree(&library, state.init.location_id, materialized_path).await?;
+			}
+
+			invalidate_query!(library, "locations.getExplorerData");
+		}
+
+		info!(
+			"Finished archiving {} entries into {}",
+			state.step_number,
+			data.output_path.display()
+		);
+
+		let mut metadata = json!({
+			"archive_path": data.output_path,
+			"cas_id": cas_id,
+			"size": size,
+		});
+		data.in_use.add_to_metadata(&mut metadata);
+
+		Ok(Some(metadata))
+	}
+}
+
+/// collect_steps adds a step for `path` and, if it is a directory, for everything inside it
+fn collect_steps(
+	path: PathBuf,
+	entry_name: PathBuf,
+	steps: &mut VecDeque<ArchiveJobStep>,
+) -> Result<(), io::Error> {
+	let metadata = fs::symlink_metadata(&path)?;
+
+	if metadata.is_dir() {
+		let mut children = fs::read_dir(&path)?.collect::<Result<Vec<_>, _>>()?;
+		children.sort_by_key(|entry| entry.file_name());
+
+		steps.push_back(ArchiveJobStep {
+			path,
+			entry_name: entry_name.clone(),
+			is_dir: true,
+		});
+
+		for child in children {
+			collect_steps(child.path(), entry_name.join(child.file_name()), steps)?;
+		}
+	} else {
+		steps.push_back(ArchiveJobStep {
+			path,
+			entry_name,
+			is_dir: false,
+		});
+	}
+
+	Ok(())
+}
+
+/// append_entry appends a single entry to the tar at `tar_path`, which holds `tar_len` bytes of entries,
+/// and returns the new length. The end of archive marker is always written after the entry, so the tar is
+/// valid after each step, and overwritten by the next one.
+fn append_entry(tar_path: &Path, tar_len: u64, step: &ArchiveJobStep) -> Result<u64, io::Error> {
+	let mut file = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.open(tar_path)?;
+	// drop anything past the last complete entry, in case a previous run was interrupted mid write
+	file.set_len(tar_len)?;
+	file.seek(SeekFrom::Start(tar_len))?;
+
+	let mut builder = tar::Builder::new(file);
+	builder.follow_symlinks(false);
+
+	if step.is_dir {
+		builder.append_dir(&step.entry_name, &step.path)?;
+	} else {
+		builder.append_path_with_name(&step.path, &step.entry_name)?;
+	}
+
+	let new_len = builder.get_mut().stream_position()?;
+	builder.finish()?;
+
+	Ok(new_len)
+}
+
+/// append_zip_entry is [`append_entry`] for zip archives, whose central directory is rewritten
+/// after each entry so the zip is valid after each step
+fn append_zip_entry(
+	zip_path: &Path,
+	zip_len: u64,
+	step: &ArchiveJobStep,
+) -> Result<u64, io::Error> {
+	let file = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.open(zip_path)?;
+	// drop anything past the last complete step, in case a previous run was interrupted mid write
+	file.set_len(zip_len)?;
+
+	let mut zip = match zip_len {
+		0 => ZipWriter::new(file),
+		_ => ZipWriter::new_append(file)?,
+	};
+
+	// zip entry names always use forward slashes
+	let name = step.entry_name.to_string_lossy().replace('\\', "/");
+	let metadata = fs::symlink_metadata(&step.path)?;
+	let options = FileOptions::default()
+		.compression_method(CompressionMethod::Deflated)
+		.large_file(metadata.len() > u32::MAX as u64);
+
+	if step.is_dir {
+		zip.add_directory(name, options)?;
+	} else if metadata.file_type().is_symlink() {
+		let target = fs::read_link(&step.path)?;
+		zip.add_symlink(name, target.to_string_lossy(), options)?;
+	} else {
+		zip.start_file(name, options)?;
+		io::copy(&mut BufReader::new(File::open(&step.path)?), &mut zip)?;
+	}
+
+	zip.finish()?.stream_position()
+}
@@ -0,0 +1,466 @@
+use std::{
+	fs::File,
+	io::{self, BufReader, Read, Seek, Write},
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use sd_codec::Codec;
+use sevenz_rust::{Password, SevenZReader};
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::info;
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{
+	library::LibraryContext,
+	prisma::{archive_entry, file_path},
+	util::os_path::resolve_materialized_path,
+};
+
+use super::disk_image::{self, is_disk_image_name};
+
+/// Archives with more entries than this are not indexed, to keep huge archives from flooding the database
+const MAX_INDEXED_ENTRIES: usize = 10_000;
+
+#[derive(Error, Debug)]
+pub enum ArchiveReaderError {
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("not a supported archive: {0}")]
+	UnsupportedFormat(PathBuf),
+	#[error("failed to read zip archive: {0}")]
+	Zip(#[from] ZipError),
+	#[error("failed to read 7z archive: {0}")]
+	SevenZip(#[from] sevenz_rust::Error),
+	#[error("entry '{0}' not found in archive")]
+	EntryNotFound(String),
+	#[error("malformed disk image: {0}")]
+	MalformedImage(&'static str),
+	#[error("the disk image's filesystem isn't supported")]
+	UnsupportedFilesystem,
+	#[error("failed to decompress archive: {0}")]
+	Codec(#[from] sd_codec::Error),
+}
+
+impl From<ArchiveReaderError> for rspc::Error {
+	fn from(err: ArchiveReaderError) -> Self {
+		match err {
+			ArchiveReaderError::EntryNotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			ArchiveReaderError::UnsupportedFormat(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+	Zip,
+	SevenZip,
+	Tar,
+	TarGz,
+	TarZst,
+	/// an ISO, IMG or DMG, whose filesystem is read like an archive
+	DiskImage,
+}
+
+impl ArchiveKind {
+	/// from_path detects the kind of archive from its file name, as `.tar.gz` can't be told apart from `.gz` by its extension alone
+	pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+		let name = path.as_ref().file_name()?.to_str()?.to_lowercase();
+
+		if name.ends_with(".zip") {
+			Some(Self::Zip)
+		} else if name.ends_with(".7z") {
+			Some(Self::SevenZip)
+		} else if name.ends_with(".tar") {
+			Some(Self::Tar)
+		} else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+			Some(Self::TarGz)
+		} else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+			Some(Self::TarZst)
+		} else if is_disk_image_name(&name) {
+			Some(Self::DiskImage)
+		} else {
+			None
+		}
+	}
+}
+
+/// An entry found inside of an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveEntryInfo {
+	pub path: String,
+	pub is_dir: bool,
+	pub size: u64,
+	pub date_modified: Option<DateTime<Utc>>,
+}
+
+/// list_entries reads the table of contents of the archive at `path`, without extracting anything
+pub fn list_entries(path: impl AsRef<Path>) -> Result<Vec<ArchiveEntryInfo>, ArchiveReaderError> {
+	let path = path.as_ref();
+	match ArchiveKind::from_path(path) {
+		Some(ArchiveKind::Zip) => list_zip_entries(BufReader::new(File::open(path)?)),
+		Some(ArchiveKind::SevenZip) => list_7z_entries(path),
+		Some(ArchiveKind::Tar) => list_tar_entries(File::open(path)?),
+		Some(ArchiveKind::TarGz) => list_tar_entries(GzDecoder::new(File::open(path)?)),
+		Some(ArchiveKind::TarZst) => list_tar_entries(zstd_decoder(path)?),
+		Some(ArchiveKind::DiskImage) => {
+			disk_image::list_entries(&mut BufReader::new(File::open(path)?))
+		}
+		None => Err(ArchiveReaderError::UnsupportedFormat(path.to_path_buf())),
+	}
+}
+
+/// extract_entry copies a single member of the archive at `path` into `output`
+pub fn extract_entry(
+	path: impl AsRef<Path>,
+	entry_path: &str,
+	output: &mut impl Write,
+) -> Result<u64, ArchiveReaderError> {
+	let path = path.as_ref();
+	match ArchiveKind::from_path(path) {
+		Some(ArchiveKind::Zip) => {
+			extract_zip_entry(BufReader::new(File::open(path)?), entry_path, output)
+		}
+		Some(ArchiveKind::SevenZip) => extract_7z_entry(path, entry_path, output),
+		Some(ArchiveKind::Tar) => extract_tar_entry(File::open(path)?, entry_path, output),
+		Some(ArchiveKind::TarGz) => {
+			extract_tar_entry(GzDecoder::new(File::open(path)?), entry_path, output)
+		}
+		Some(ArchiveKind::TarZst) => extract_tar_entry(zstd_decoder(path)?, entry_path, output),
+		Some(ArchiveKind::DiskImage) => {
+			disk_image::extract_entry(&mut BufReader::new(File::open(path)?), entry_path, output)
+		}
+		None => Err(ArchiveReaderError::UnsupportedFormat(path.to_path_buf())),
+	}
+}
+
+fn zstd_decoder(path: &Path) -> Result<impl Read, ArchiveReaderError> {
+	Ok(Codec::Zstd(0).decoder(File::open(path)?)?)
+}
+
+/// index_archive replaces the indexed entries of an archive file_path with the ones currently in the archive
+pub async fn index_archive(
+	library: &LibraryContext,
+	location_path: impl AsRef<Path>,
+	file_path: &file_path::Data,
+) -> Result<usize, ArchiveReaderError> {
+	let path = resolve_materialized_path(
+		location_path,
+		&file_path.materialized_path,
+		file_path.raw_path.as_deref(),
+	);
+	let entries = match block_in_place(|| list_entries(&path)) {
+		Ok(entries) => entries,
+		// most DMGs are HFS+ or APFS, which is no reason to fail indexing them
+		Err(ArchiveReaderError::UnsupportedFilesystem) => {
+			info!(
+				"Skipping disk image {} as its filesystem isn't supported",
+				path.display()
+			);
+			return Ok(0);
+		}
+		Err(e) => return Err(e),
+	};
+
+	if entries.len() > MAX_INDEXED_ENTRIES {
+		info!(
+			"Skipping archive {} as it has {} entries",
+			path.display(),
+			entries.len()
+		);
+		return Ok(0);
+	}
+
+	library
+		.db
+		.archive_entry()
+		.delete_many(vec![
+			archive_entry::location_id::equals(file_path.location_id),
+			archive_entry::file_path_id::equals(file_path.id),
+		])
+		.exec()
+		.await?;
+
+	let count = library
+		.db
+		.archive_entry()
+		.create_many(
+			entries
+				.into_iter()
+				.map(|entry| {
+					let entry_path = Path::new(&entry.path);
+					let name = entry_path
+						.file_name()
+						.map(|name| name.to_string_lossy().to_string())
+						.unwrap_or_default();
+					let extension = if entry.is_dir {
+						None
+					} else {
+						entry_path
+							.extension()
+							.map(|extension| extension.to_string_lossy().to_string())
+					};
+
+					archive_entry::create_unchecked(
+						file_path.location_id,
+						file_path.id,
+						entry.path,
+						name,
+						entry.size.to_string(),
+						vec![
+							archive_entry::extension::set(extension),
+							archive_entry::is_dir::set(entry.is_dir),
+							archive_entry::date_modified::set(entry.date_modified.map(Into::into)),
+						],
+					)
+				})
+				.collect(),
+		)
+		.exec()
+		.await?;
+
+	Ok(count as usize)
+}
+
+fn list_tar_entries(reader: impl Read) -> Result<Vec<ArchiveEntryInfo>, ArchiveReaderError> {
+	let mut archive = tar::Archive::new(reader);
+	let mut entries = vec![];
+
+	for entry in archive.entries()? {
+		let entry = entry?;
+		let header = entry.header();
+
+		entries.push(ArchiveEntryInfo {
+			path: entry
+				.path()?
+				.to_string_lossy()
+				.trim_end_matches('/')
+				.to_string(),
+			is_dir: header.entry_type().is_dir(),
+			size: header.size()?,
+			date_modified: header
+				.mtime()
+				.ok()
+				.and_then(|mtime| Utc.timestamp_opt(mtime as i64, 0).single()),
+		});
+	}
+
+	Ok(entries)
+}
+
+fn extract_tar_entry(
+	reader: impl Read,
+	entry_path: &str,
+	output: &mut impl Write,
+) -> Result<u64, ArchiveReaderError> {
+	let mut archive = tar::Archive::new(reader);
+
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		if entry.path()?.to_string_lossy().trim_end_matches('/') == entry_path {
+			return Ok(io::copy(&mut entry, output)?);
+		}
+	}
+
+	Err(ArchiveReaderError::EntryNotFound(entry_path.to_string()))
+}
+
+fn list_zip_entries(reader: impl Read + Seek) -> Result<Vec<ArchiveEntryInfo>, ArchiveReaderError> {
+	let mut archive = ZipArchive::new(reader)?;
+	let mut entries = Vec::with_capacity(archive.len());
+
+	for index in 0..archive.len() {
+		// the raw entry is enough for its header, nothing is decompressed
+		let entry = archive.by_index_raw(index)?;
+		let modified = entry.last_modified();
+
+		entries.push(ArchiveEntryInfo {
+			path: entry.name().trim_end_matches('/').to_string(),
+			is_dir: entry.is_dir(),
+			size: entry.size(),
+			date_modified: dos_date_time(modified.datepart(), modified.timepart()),
+		});
+	}
+
+	Ok(entries)
+}
+
+fn extract_zip_entry(
+	reader: impl Read + Seek,
+	entry_path: &str,
+	output: &mut impl Write,
+) -> Result<u64, ArchiveReaderError> {
+	let mut archive = ZipArchive::new(reader)?;
+
+	for index in 0..archive.len() {
+		if archive.by_index_raw(index)?.name().trim_end_matches('/') == entry_path {
+			return Ok(io::copy(&mut archive.by_index(index)?, output)?);
+		}
+	}
+
+	Err(ArchiveReaderError::EntryNotFound(entry_path.to_string()))
+}
+
+fn list_7z_entries(path: &Path) -> Result<Vec<ArchiveEntryInfo>, ArchiveReaderError> {
+	let mut entries = vec![];
+
+	// entries are listed without reading their contents, so the whole archive isn't decompressed
+	SevenZReader::open(path, Password::empty())?.for_each_entries(|entry, _| {
+		entries.push(ArchiveEntryInfo {
+			path: entry.name().trim_end_matches('/').to_string(),
+			is_dir: entry.is_directory(),
+			size: entry.size(),
+			date_modified: entry
+				.has_last_modified_date
+				.then(|| SystemTime::from(entry.last_modified_date()).into()),
+		});
+		Ok(true)
+	})?;
+
+	Ok(entries)
+}
+
+fn extract_7z_entry(
+	path: &Path,
+	entry_path: &str,
+	output: &mut impl Write,
+) -> Result<u64, ArchiveReaderError> {
+	let mut written = None;
+
+	SevenZReader::open(path, Password::empty())?.for_each_entries(|entry, reader| {
+		if entry.name().trim_end_matches('/') != entry_path {
+			return Ok(true);
+		}
+
+		written = Some(io::copy(reader, output));
+		Ok(false)
+	})?;
+
+	match written {
+		Some(written) => Ok(written?),
+		None => Err(ArchiveReaderError::EntryNotFound(entry_path.to_string())),
+	}
+}
+
+/// dos_date_time converts the MS-DOS date and time fields used by zip files and FAT filesystems
+pub(super) fn dos_date_time(date: u16, time: u16) -> Option<DateTime<Utc>> {
+	NaiveDate::from_ymd_opt(
+		1980 + (date >> 9) as i32,
+		((date >> 5) & 0xF) as u32,
+		(date & 0x1F) as u32,
+	)?
+	.and_hms_opt(
+		(time >> 11) as u32,
+		((time >> 5) & 0x3F) as u32,
+		((time & 0x1F) * 2) as u32,
+	)
+	.map(|date_time| Utc.from_utc_datetime(&date_time))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+	use tempfile::tempdir;
+
+	#[test]
+	fn detects_archive_kind_from_name() {
+		assert_eq!(ArchiveKind::from_path("a.zip"), Some(ArchiveKind::Zip));
+		assert_eq!(ArchiveKind::from_path("a.7z"), Some(ArchiveKind::SevenZip));
+		assert_eq!(ArchiveKind::from_path("a.TAR"), Some(ArchiveKind::Tar));
+		assert_eq!(ArchiveKind::from_path("a.tar.gz"), Some(ArchiveKind::TarGz));
+		assert_eq!(
+			ArchiveKind::from_path("a.tar.zst"),
+			Some(ArchiveKind::TarZst)
+		);
+		assert_eq!(
+			ArchiveKind::from_path("disc.ISO"),
+			Some(ArchiveKind::DiskImage)
+		);
+		assert_eq!(ArchiveKind::from_path("a.gz"), None);
+	}
+
+	#[test]
+	fn lists_and_extracts_tar_entries() {
+		let dir = tempdir().unwrap();
+		let archive_path = dir.path().join("test.tar");
+
+		let mut builder = tar::Builder::new(File::create(&archive_path).unwrap());
+		let mut header = tar::Header::new_gnu();
+		header.set_size(5);
+		header.set_mode(0o644);
+		header.set_cksum();
+		builder
+			.append_data(&mut header, "folder/hello.txt", &b"hello"[..])
+			.unwrap();
+		builder.finish().unwrap();
+		drop(builder);
+
+		let entries = list_entries(&archive_path).unwrap();
+		assert_eq!(entries.len(), 1);
+		assert_eq!(entries[0].path, "folder/hello.txt");
+		assert_eq!(entries[0].size, 5);
+
+		let mut output = Cursor::new(vec![]);
+		extract_entry(&archive_path, "folder/hello.txt", &mut output).unwrap();
+		assert_eq!(output.into_inner(), b"hello");
+
+		assert!(matches!(
+			extract_entry(&archive_path, "missing.txt", &mut Cursor::new(vec![])),
+			Err(ArchiveReaderError::EntryNotFound(_))
+		));
+	}
+
+	#[test]
+	fn lists_and_extracts_zip_entries() {
+		let dir = tempdir().unwrap();
+		let archive_path = dir.path().join("test.zip");
+
+		let mut zip = zip::ZipWriter::new(File::create(&archive_path).unwrap());
+		zip.add_directory("folder/", Default::default()).unwrap();
+		zip.start_file("folder/hello.txt", Default::default())
+			.unwrap();
+		zip.write_all(b"hello").unwrap();
+		zip.finish().unwrap();
+		drop(zip);
+
+		let entries = list_entries(&archive_path).unwrap();
+		assert_eq!(entries.len(), 2);
+		assert!(entries[0].is_dir);
+		assert_eq!(entries[1].path, "folder/hello.txt");
+		assert_eq!(entries[1].size, 5);
+
+		let mut output = Cursor::new(vec![]);
+		extract_entry(&archive_path, "folder/hello.txt", &mut output).unwrap();
+		assert_eq!(output.into_inner(), b"hello");
+
+		assert!(matches!(
+			extract_entry(&archive_path, "missing.txt", &mut Cursor::new(vec![])),
+			Err(ArchiveReaderError::EntryNotFound(_))
+		));
+	}
+
+	#[test]
+	fn converts_dos_date_time() {
+		// 2022-11-12 14:30:50
+		let date = ((2022 - 1980) << 9) | (11 << 5) | 12;
+		let time = (14 << 11) | (30 << 5) | 25;
+
+		assert_eq!(
+			dos_date_time(date, time).unwrap().to_rfc3339(),
+			"2022-11-12T14:30:50+00:00"
+		);
+	}
+}
@@ -0,0 +1,511 @@
+//! Commands run on every file path of a selection: tagging their objects, moving them into a
+//! directory, moving them to the trash and encrypting them. A command is given a
+//! [`SelectionTarget`], so a client acting on the results of a search only sends the id of the
+//! selection it made of them, however many there are.
+
+use std::{
+	collections::HashMap,
+	path::{Component, Path, PathBuf},
+};
+
+use rspc::Type;
+use sd_crypto::crypto::stream::Algorithm;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+	api::LibraryEvent,
+	invalidate_query,
+	job::{
+		FileError, Job, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::{record_operations, LibraryContext, Operation, SelectionItem, SelectionTarget},
+	location::{fetch_location, LocationError},
+	prisma::{file_path, object, tag, tag_on_object},
+	sync::{
+		models::{uuid_from_pub_id, TAG_ON_OBJECT},
+		SyncError,
+	},
+	util::message::Message,
+};
+
+use super::{
+	encrypt::{FileEncryptorJob, FileEncryptorJobInit},
+	organize::{move_file_path, OrganizeError},
+	trash::{clean_trash, move_to_trash, TrashError},
+};
+
+pub const BATCH_JOB_NAME: &str = "batch";
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+	#[error("tag not found: <id='{0}'>")]
+	TagNotFound(i32),
+	#[error("invalid target directory: '{0}', it must be a relative path in the location")]
+	InvalidTargetPath(String),
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("Sync error: {0}")]
+	SyncError(#[from] SyncError),
+}
+
+impl From<BatchError> for rspc::Error {
+	fn from(err: BatchError) -> Self {
+		match err {
+			BatchError::TagNotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			BatchError::InvalidTargetPath(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// What is done to each file path of a selection
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub enum BatchCommand {
+	/// gives the tag to the objects of the file paths, or takes it from them
+	Tag {
+		tag_id: i32,
+		unassign: bool,
+	},
+	/// moves the files into a directory of their location, a materialized path that's made if it
+	/// doesn't exist. Files whose name is taken there get a conflict copy name, and directories
+	/// aren't moved.
+	Move {
+		target_path: String,
+	},
+	Trash,
+	/// encrypts the files with the key, each in an encryption job of its own
+	Encrypt {
+		key_uuid: Uuid,
+		algorithm: Algorithm,
+		metadata: bool,
+		preview_media: bool,
+	},
+}
+
+impl BatchCommand {
+	/// validate checks the command can be run before it's given the selection
+	pub async fn validate(&self, library: &LibraryContext) -> Result<(), BatchError> {
+		match self {
+			Self::Tag { tag_id, .. } => {
+				library
+					.db
+					.tag()
+					.find_unique(tag::id::equals(*tag_id))
+					.exec()
+					.await?
+					.ok_or(BatchError::TagNotFound(*tag_id))?;
+			}
+			Self::Move { target_path } => {
+				let is_relative = Path::new(target_path)
+					.components()
+					.all(|component| matches!(component, Component::Normal(_)));
+				if !is_relative {
+					return Err(BatchError::InvalidTargetPath(target_path.clone()));
+				}
+			}
+			Self::Trash | Self::Encrypt { .. } => {}
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Deserialize, Type)]
+pub struct BatchArgs {
+	pub target: SelectionTarget,
+	pub command: BatchCommand,
+}
+
+/// BatchJob runs a command on each file path of a selection. File paths that are gone or that the
+/// command doesn't apply to, like ones that aren't identified yet when tagging, are skipped.
+pub struct BatchJob;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BatchJobInit {
+	pub items: Vec<SelectionItem>,
+	pub command: BatchCommand,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BatchJobState {
+	/// the path and pub id of the locations of the files being moved
+	locations: HashMap<i32, (PathBuf, Vec<u8>)>,
+	done: usize,
+	skipped: usize,
+	/// the tags given or taken and the moves made, to be undone
+	operations: Vec<Operation>,
+}
+
+/// local_location returns where a location that can be written to is on this node, with its pub id
+pub(crate) async fn local_location(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<(PathBuf, Vec<u8>), LocationError> {
+	let location = fetch_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	if !location.is_online {
+		return Err(LocationError::Offline(location.id));
+	}
+	if location.is_catalog {
+		return Err(LocationError::ReadOnlyCatalog(location.id));
+	}
+
+	Ok((
+		location
+			.local_path
+			.map(PathBuf::from)
+			.ok_or(LocationError::MissingLocalPath(location.id))?,
+		location.pub_id,
+	))
+}
+
+/// set_tag gives the tag to the object, or takes it from it, returning false if it already had it
+/// or didn't
+pub(crate) async fn set_tag(
+	library: &LibraryContext,
+	tag: &tag::Data,
+	object: &object::Data,
+	unassign: bool,
+) -> Result<bool, SyncError> {
+	let is_tagged = library
+		.db
+		.tag_on_object()
+		.find_unique(tag_on_object::tag_id_object_id(tag.id, object.id))
+		.exec()
+		.await?
+		.is_some();
+	if is_tagged != unassign {
+		return Ok(false);
+	}
+
+	let object_pub_id = library.sync.ensure_object_pub_id(object).await?;
+	let tag_pub_id = uuid_from_pub_id(&tag.pub_id);
+	if unassign {
+		library
+			.db
+			.tag_on_object()
+			.delete(tag_on_object::tag_id_object_id(tag.id, object.id))
+			.exec()
+			.await?;
+		library
+			.sync
+			.write_ops(vec![library.sync.relation_delete(
+				TAG_ON_OBJECT,
+				object_pub_id,
+				tag_pub_id,
+			)])
+			.await?;
+	} else {
+		library
+			.db
+			.tag_on_object()
+			.create(
+				tag::id::equals(tag.id),
+				object::id::equals(object.id),
+				vec![],
+			)
+			.exec()
+			.await?;
+		library
+			.sync
+			.write_ops(vec![library.sync.relation_create(
+				TAG_ON_OBJECT,
+				object_pub_id,
+				tag_pub_id,
+			)])
+			.await?;
+	}
+
+	Ok(true)
+}
+
+/// run_command runs the command on a file path, returning false if it was skipped. Files the
+/// command failed on are recorded as the job's file errors.
+async fn run_command(
+	ctx: &WorkerContext,
+	command: &BatchCommand,
+	data: &mut BatchJobState,
+	file_path: &file_path::Data,
+) -> Result<bool, JobError> {
+	let library = ctx.library_ctx();
+
+	Ok(match command {
+		BatchCommand::Tag { tag_id, unassign } => {
+			let tag = library
+				.db
+				.tag()
+				.find_unique(tag::id::equals(*tag_id))
+				.exec()
+				.await?
+				.ok_or(BatchError::TagNotFound(*tag_id))?;
+
+			let object = match file_path.object().ok().flatten() {
+				Some(object) => object,
+				None => return Ok(false),
+			};
+			let changed = set_tag(&library, &tag, object, *unassign)
+				.await
+				.map_err(BatchError::from)?;
+			if changed {
+				data.operations.push(Operation::Tag {
+					tag_id: tag.id,
+					object_id: object.id,
+					assigned: !unassign,
+				});
+			}
+			changed
+		}
+		BatchCommand::Move { .. } if file_path.is_dir => false,
+		BatchCommand::Move { target_path } => {
+			if !data.locations.contains_key(&file_path.location_id) {
+				match local_location(&library, file_path.location_id).await {
+					Ok(location) => {
+						data.locations.insert(file_path.location_id, location);
+					}
+					Err(e) => {
+						ctx.record_file_error(FileError::new(
+							file_path,
+							Message::Error {
+								text: e.to_string(),
+							},
+						))
+						.await;
+						return Ok(false);
+					}
+				}
+			}
+			let (location_path, location_pub_id) = &data.locations[&file_path.location_id];
+
+			let file_name = Path::new(&file_path.materialized_path)
+				.file_name()
+				.unwrap_or_default();
+			let node_config = library.config().get().await;
+			let target = node_config.conflict_naming.free_path(
+				&location_path.join(target_path).join(file_name),
+				&node_config.name,
+			);
+			let target = target
+				.strip_prefix(location_path)
+				.unwrap_or(&target)
+				.to_string_lossy()
+				.to_string();
+
+			if target == file_path.materialized_path {
+				false
+			} else {
+				match move_file_path(
+					&library,
+					file_path.location_id,
+					location_path,
+					location_pub_id,
+					file_path.id,
+					&file_path.materialized_path,
+					&target,
+				)
+				.await
+				{
+					Ok(_) => {
+						data.operations.push(Operation::Move {
+							location_id: file_path.location_id,
+							file_path_id: file_path.id,
+							from: file_path.materialized_path.clone(),
+							to: target,
+						});
+						true
+					}
+					Err(OrganizeError::IO(e)) => {
+						ctx.record_file_error(FileError::new(file_path, &e)).await;
+						false
+					}
+					Err(e) => return Err(e.into()),
+				}
+			}
+		}
+		BatchCommand::Trash => {
+			match move_to_trash(&library, file_path.location_id, file_path.id).await {
+				Ok(_) => true,
+				Err(TrashError::FilePathNotFound(..)) => false,
+				Err(TrashError::IOError(e)) => {
+					ctx.record_file_error(FileError::new(file_path, &e)).await;
+					false
+				}
+				Err(
+					e @ (TrashError::InUse(..)
+					| TrashError::ReadOnlyCatalog(_)
+					| TrashError::MissingLocalPath(_)),
+				) => {
+					ctx.record_file_error(FileError::new(
+						file_path,
+						Message::Error {
+							text: e.to_string(),
+						},
+					))
+					.await;
+					false
+				}
+				Err(e) => return Err(e.into()),
+			}
+		}
+		BatchCommand::Encrypt {
+			key_uuid,
+			algorithm,
+			metadata,
+			preview_media,
+		} => match file_path.object_id {
+			Some(object_id) if !file_path.is_dir => {
+				library
+					.queue_job(Job::new(
+						FileEncryptorJobInit {
+							location_id: file_path.location_id,
+							object_id,
+							key_uuid: *key_uuid,
+							algorithm: *algorithm,
+							metadata: *metadata,
+							preview_media: *preview_media,
+							output_path: None,
+						},
+						Box::new(FileEncryptorJob {}),
+					))
+					.await;
+				true
+			}
+			_ => false,
+		},
+	})
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for BatchJob {
+	type Init = BatchJobInit;
+	type Data = BatchJobState;
+	type Step = SelectionItem;
+
+	fn name(&self) -> &'static str {
+		BATCH_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		state.steps = state.init.items.iter().copied().collect();
+		state.data = Some(BatchJobState::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let item = state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+		let library = ctx.library_ctx();
+
+		let file_path = library
+			.db
+			.file_path()
+			.find_unique(file_path::location_id_id(
+				item.location_id,
+				item.file_path_id,
+			))
+			.with(file_path::object::fetch())
+			.exec()
+			.await?;
+
+		// the file path may have been deleted since it was selected
+		let done = match file_path {
+			Some(file_path) => {
+				ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+					path: file_path.materialized_path.clone(),
+				})]);
+				run_command(&ctx, &state.init.command, data, &file_path).await?
+			}
+			None => false,
+		};
+
+		match done {
+			true => data.done += 1,
+			false => data.skipped += 1,
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		if data.done > 0 {
+			match &state.init.command {
+				BatchCommand::Tag { tag_id, unassign } => {
+					record_operations(
+						&library,
+						format!(
+							"{} {} files",
+							if *unassign { "Untag" } else { "Tag" },
+							data.done
+						),
+						data.operations.clone(),
+					);
+					invalidate_query!(library, "tags.getForObject");
+					library.emit_event(LibraryEvent::TagUpdated { tag_id: *tag_id });
+				}
+				// the trash might be over its size cap now
+				BatchCommand::Trash => clean_trash(&library).await,
+				BatchCommand::Move { .. } => {
+					record_operations(
+						&library,
+						format!("Move {} files", data.done),
+						data.operations.clone(),
+					);
+					for location_id in data.locations.keys() {
+						library.statistics.location_changed(*location_id);
+					}
+				}
+				BatchCommand::Encrypt { .. } => {}
+			}
+			invalidate_query!(library, "locations.getExplorerData");
+		}
+
+		info!(
+			"Ran a batch command on {} files, {} skipped",
+			data.done, data.skipped
+		);
+
+		Ok(Some(json!({
+			"done": data.done,
+			"skipped": data.skipped,
+		})))
+	}
+}
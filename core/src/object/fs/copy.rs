@@ -0,0 +1,430 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	fs::{self, File},
+	io::{self, Read, Write},
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::space_monitor::ensure_fits,
+	object::{
+		attributes::{apply_attributes, FileAttributes},
+		cas::CasHasher,
+	},
+	prisma::{file_path, location, object},
+	util::{message::FileAction, os_path::resolve_materialized_path},
+};
+
+use super::{progress_message, ProgressReader};
+
+pub const COPY_JOB_NAME: &str = "file_copier";
+
+const BLOCK_SIZE: usize = 1048576;
+
+pub struct FileCopierJob;
+
+#[derive(Serialize, Deserialize, Type, Debug)]
+pub struct FileCopierJobInit {
+	pub source_location_id: i32,
+	pub source_path_ids: Vec<i32>,
+	pub target_location_id: i32,
+	/// materialized path of the directory to copy into, the root of the target location if empty
+	pub target_path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileCopierJobState {
+	/// bytes copied and verified against the source's hashes
+	verified_bytes: u64,
+	/// copies that didn't match their source, which were removed
+	mismatches: Vec<PathBuf>,
+	/// the directories copied with the attributes of their source, given to them once they're filled
+	/// as they may not be writable anymore after
+	#[serde(default)]
+	dirs: Vec<(PathBuf, FileAttributes)>,
+}
+
+/// The hashes the library has for a file, which the copy is checked against
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExpectedHash {
+	object_id: i32,
+	cas_id: String,
+	integrity_checksum: Option<String>,
+}
+
+impl ExpectedHash {
+	pub(super) fn new(object: &object::Data) -> Self {
+		Self {
+			object_id: object.id,
+			cas_id: object.cas_id.clone(),
+			integrity_checksum: object.integrity_checksum.clone(),
+		}
+	}
+
+	/// matches checks a copy's hashes against the source's, using the full checksum when we have it
+	pub(super) fn matches(&self, cas_id: &str, checksum: &str) -> bool {
+		match &self.integrity_checksum {
+			Some(integrity_checksum) => checksum == integrity_checksum,
+			// the identifier only stores the start of the cas_id
+			None => cas_id.starts_with(&self.cas_id),
+		}
+	}
+
+	/// update_checksum stores the full checksum of a verified copy if the object didn't have one yet
+	pub(super) async fn update_checksum(
+		&self,
+		library: &LibraryContext,
+		checksum: String,
+	) -> Result<(), prisma_client_rust::QueryError> {
+		// we read the whole file anyway, so the full checksum comes for free
+		if self.integrity_checksum.is_none() {
+			library
+				.db
+				.object()
+				.update(
+					object::id::equals(self.object_id),
+					vec![object::integrity_checksum::set(Some(checksum))],
+				)
+				.exec()
+				.await?;
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileCopierJobStep {
+	#[serde(with = "crate::util::os_path::serde_path")]
+	source: PathBuf,
+	#[serde(with = "crate::util::os_path::serde_path")]
+	target: PathBuf,
+	is_dir: bool,
+	expected: Option<ExpectedHash>,
+	/// what the source had when it was indexed
+	#[serde(default)]
+	attributes: Option<FileAttributes>,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FileCopierJob {
+	type Init = FileCopierJobInit;
+	type Data = FileCopierJobState;
+	type Step = FileCopierJobStep;
+
+	fn name(&self) -> &'static str {
+		COPY_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		let location_path = |location: Option<location::Data>| {
+			location
+				.and_then(|location| location.local_path)
+				.map(PathBuf::from)
+				.ok_or_else(|| JobError::JobDataNotFound(COPY_JOB_NAME.to_string()))
+		};
+
+		let source_root = location_path(
+			library
+				.db
+				.location()
+				.find_unique(location::id::equals(state.init.source_location_id))
+				.exec()
+				.await?,
+		)?;
+		let target_root = location_path(
+			library
+				.db
+				.location()
+				.find_unique(location::id::equals(state.init.target_location_id))
+				.exec()
+				.await?,
+		)?
+		.join(&state.init.target_path);
+
+		let file_paths = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(state.init.source_location_id),
+				file_path::id::in_vec(state.init.source_path_ids.clone()),
+			])
+			.exec()
+			.await?;
+
+		if file_paths.is_empty() {
+			return Err(JobError::JobDataNotFound(COPY_JOB_NAME.to_string()));
+		}
+
+		// the hashes and attributes of everything being copied, by path, so each copy can be verified
+		// and given the attributes of its source
+		let mut objects = HashMap::new();
+		let mut attributes = HashMap::new();
+		for selected in &file_paths {
+			attributes.insert(
+				resolve_materialized_path(
+					&source_root,
+					&selected.materialized_path,
+					selected.raw_path.as_deref(),
+				),
+				FileAttributes::from_file_path(selected),
+			);
+
+			let mut params = vec![file_path::materialized_path::equals(
+				selected.materialized_path.clone(),
+			)];
+			if selected.is_dir {
+				params = vec![file_path::materialized_path::starts_with(format!(
+					"{}/",
+					selected.materialized_path
+				))];
+			}
+			params.push(file_path::location_id::equals(
+				state.init.source_location_id,
+			));
+
+			for file_path in library
+				.db
+				.file_path()
+				.find_many(params)
+				.with(file_path::object::fetch())
+				.exec()
+				.await?
+			{
+				let path = resolve_materialized_path(
+					&source_root,
+					&file_path.materialized_path,
+					file_path.raw_path.as_deref(),
+				);
+				if let Ok(Some(object)) = file_path.object() {
+					objects.insert(path.clone(), ExpectedHash::new(object));
+				}
+				attributes.insert(path, FileAttributes::from_file_path(&file_path));
+			}
+		}
+
+		// what's already in the target directory is kept, copies go next to it with their own name
+		let node_config = library.config().get().await;
+
+		let mut steps = VecDeque::new();
+		for file_path in &file_paths {
+			let source = resolve_materialized_path(
+				&source_root,
+				&file_path.materialized_path,
+				file_path.raw_path.as_deref(),
+			);
+			let target = node_config.conflict_naming.free_path(
+				&match source.file_name() {
+					Some(name) => target_root.join(name),
+					None => target_root.join(&file_path.name),
+				},
+				&node_config.name,
+			);
+
+			collect_steps(source, target, &mut objects, &mut attributes, &mut steps)?;
+		}
+
+		let required = steps
+			.iter()
+			.filter(|step| !step.is_dir)
+			.map(|step| fs::metadata(&step.source).map(|metadata| metadata.len()))
+			.sum::<Result<u64, _>>()?;
+		ensure_fits(&library, &target_root, required)?;
+
+		state.steps = steps;
+		state.data = Some(FileCopierJobState::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		if step.is_dir {
+			fs::create_dir_all(&step.target)?;
+			if let Some(attributes) = &step.attributes {
+				data.dirs.push((step.target.clone(), attributes.clone()));
+			}
+		} else {
+			let size = fs::metadata(&step.source)?.len();
+
+			let progress_ctx = ctx.clone();
+			let name = step
+				.source
+				.file_name()
+				.map(|name| name.to_string_lossy().to_string())
+				.unwrap_or_default();
+			let reader =
+				ProgressReader::new(File::open(&step.source)?, move |bytes_read| {
+					progress_ctx.progress_debounced(vec![JobReportUpdate::Message(
+						progress_message(FileAction::Copying, &name, bytes_read, size),
+					)]);
+				});
+
+			let (cas_id, checksum) = block_in_place(|| copy_and_hash(reader, &step.target, size))?;
+
+			if let Some(expected) = &step.expected {
+				if !expected.matches(&cas_id, &checksum) {
+					warn!(
+						"Copy of {} doesn't match its source, removing it",
+						step.source.display()
+					);
+					fs::remove_file(&step.target)?;
+					data.mismatches.push(step.source.clone());
+				} else {
+					data.verified_bytes += size;
+					expected
+						.update_checksum(&ctx.library_ctx(), checksum)
+						.await?;
+				}
+			}
+
+			if let Some(attributes) = &step.attributes {
+				if step.target.exists() {
+					restore_attributes(&step.target, attributes);
+				}
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		// the deepest directories first, so their parents are still writable
+		for (dir, attributes) in data.dirs.iter().rev() {
+			restore_attributes(dir, attributes);
+		}
+
+		invalidate_query!(ctx.library_ctx(), "locations.getExplorerData");
+
+		info!(
+			"Copied {} entries, {} bytes verified, {} mismatched",
+			state.step_number,
+			data.verified_bytes,
+			data.mismatches.len()
+		);
+
+		Ok(Some(json!({
+			"init": state.init,
+			"verified_bytes": data.verified_bytes,
+			"mismatches": data.mismatches,
+		})))
+	}
+}
+
+/// collect_steps adds a step for copying `source` to `target` and, if it is a directory, for everything inside it
+fn collect_steps(
+	source: PathBuf,
+	target: PathBuf,
+	objects: &mut HashMap<PathBuf, ExpectedHash>,
+	attributes: &mut HashMap<PathBuf, FileAttributes>,
+	steps: &mut VecDeque<FileCopierJobStep>,
+) -> Result<(), io::Error> {
+	if fs::metadata(&source)?.is_dir() {
+		let mut children = fs::read_dir(&source)?.collect::<Result<Vec<_>, _>>()?;
+		children.sort_by_key(|entry| entry.file_name());
+
+		steps.push_back(FileCopierJobStep {
+			expected: None,
+			attributes: attributes.remove(&source),
+			source,
+			target: target.clone(),
+			is_dir: true,
+		});
+
+		for child in children {
+			collect_steps(
+				child.path(),
+				target.join(child.file_name()),
+				objects,
+				attributes,
+				steps,
+			)?;
+		}
+	} else {
+		steps.push_back(FileCopierJobStep {
+			expected: objects.remove(&source),
+			attributes: attributes.remove(&source),
+			source,
+			target,
+			is_dir: false,
+		});
+	}
+
+	Ok(())
+}
+
+/// restore_attributes gives a copy the attributes of its source, which isn't worth failing the copy over
+fn restore_attributes(path: &Path, attributes: &FileAttributes) {
+	if let Err(e) = block_in_place(|| apply_attributes(path, attributes)) {
+		warn!(
+			"Failed to give {} the attributes of its source: {:#?}",
+			path.display(),
+			e
+		);
+	}
+}
+
+/// copy_and_hash copies everything from `reader` into a new file at `target`, hashing it on the way through.
+/// Returns the cas_id and full checksum of the copied data.
+pub(super) fn copy_and_hash(
+	mut reader: impl Read,
+	target: &Path,
+	size: u64,
+) -> Result<(String, String), io::Error> {
+	let mut writer = File::create(target)?;
+	let mut hasher = CasHasher::new(size);
+	let mut buffer = vec![0; BLOCK_SIZE].into_boxed_slice();
+
+	loop {
+		let read_count = reader.read(&mut buffer)?;
+		if read_count == 0 {
+			break;
+		}
+
+		hasher.update(&buffer[..read_count]);
+		writer.write_all(&buffer[..read_count])?;
+	}
+
+	writer.sync_all()?;
+
+	Ok(hasher.finalize())
+}
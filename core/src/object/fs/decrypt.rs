@@ -3,15 +3,21 @@ use std::{collections::VecDeque, path::PathBuf};
 use sd_crypto::{crypto::stream::StreamDecryption, header::file::FileHeader};
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use tokio::task::block_in_place;
 
 use crate::{
 	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
 	prisma::{file_path, location},
+	util::message::FileAction,
 };
 
+use super::{progress_message, InUseTracker, ProgressReader};
+
 pub struct FileDecryptorJob;
-#[derive(Serialize, Deserialize, Debug)]
-pub struct FileDecryptorJobState {}
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileDecryptorJobState {
+	in_use: InUseTracker,
+}
 
 // decrypt could have an option to restore metadata (and another specific option for file name? - would turn "output file" into "output path" in the UI)
 #[derive(Serialize, Deserialize, Debug, Type)]
@@ -21,13 +27,13 @@ pub struct FileDecryptorJobInit {
 	pub output_path: Option<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileDecryptorJobStep {
 	obj_name: String,
 	obj_path: PathBuf,
 }
 
-const JOB_NAME: &str = "file_decryptor";
+pub const DECRYPT_JOB_NAME: &str = "file_decryptor";
 
 #[async_trait::async_trait]
 impl StatefulJob for FileDecryptorJob {
@@ -36,7 +42,7 @@ impl StatefulJob for FileDecryptorJob {
 	type Step = FileDecryptorJobStep;
 
 	fn name(&self) -> &'static str {
-		JOB_NAME
+		DECRYPT_JOB_NAME
 	}
 
 	async fn init(
@@ -65,9 +71,10 @@ impl StatefulJob for FileDecryptorJob {
 		let item = library
 			.db
 			.file_path()
-			.find_first(vec![file_path::object_id::equals(Some(
-				state.init.object_id,
-			))])
+			.find_first(vec![
+				file_path::location_id::equals(state.init.location_id),
+				file_path::object_id::equals(Some(state.init.object_id)),
+			])
 			.exec()
 			.await?
 			.expect("critical error: can't find object");
@@ -81,6 +88,7 @@ impl StatefulJob for FileDecryptorJob {
 		state
 			.steps
 			.push_back(FileDecryptorJobStep { obj_name, obj_path });
+		state.data = Some(FileDecryptorJobState::default());
 
 		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
 
@@ -92,35 +100,47 @@ impl StatefulJob for FileDecryptorJob {
 		ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> Result<(), JobError> {
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		// files that are open in another application are retried later, or skipped
+		let obj_path = state.steps[0].obj_path.clone();
+		if data
+			.in_use
+			.check(&ctx, &obj_path, &mut state.steps, state.step_number)
+			.await
+		{
+			return Ok(());
+		}
+
 		let step = &state.steps[0];
 		// handle overwriting checks, and making sure there's enough available space
 
 		let keys = ctx.library_ctx().key_manager.enumerate_hashed_keys();
+		ctx.library_ctx().key_lock.touch();
 
 		let output_path = if let Some(path) = state.init.output_path.clone() {
 			path
 		} else {
 			let mut path = step.obj_path.clone();
 
-			// i really can't decide on the functionality of this
-			// maybe we should open a dialog in JS, and have the default as the file name without the ".sdenc",
+			// files encrypted by spacedrive are named "{name}.{ext}.sdenc", so stripping ".sdenc" restores the original name
 			// we don't do any overwriting checks as of yet, maybe these should be front-end though
-			let extension = if let Some(ext) = path.extension() {
-				// allow this with clippy as this won't be the final behaviour
-				#[allow(clippy::if_same_then_else)]
-				if ext == ".sdenc" {
-					"decrypted"
-				} else {
-					"decrypted"
+			match path.extension() {
+				Some(ext) if ext == "sdenc" => {
+					path.set_extension("");
 				}
-			} else {
-				"decrypted"
-			};
-			path.set_extension(extension);
+				_ => {
+					path.set_extension("decrypted");
+				}
+			}
 			path
 		};
 
-		let mut reader = std::fs::File::open(step.obj_path.clone())?;
+		let file_size = std::fs::metadata(&step.obj_path)?.len();
+		let mut reader = std::fs::File::open(&step.obj_path)?;
 		let mut writer = std::fs::File::create(output_path)?;
 
 		let (header, aad) = FileHeader::deserialize(&mut reader)?;
@@ -129,7 +149,19 @@ impl StatefulJob for FileDecryptorJob {
 
 		let decryptor = StreamDecryption::new(master_key, &header.nonce, header.algorithm)?;
 
-		decryptor.decrypt_streams(&mut reader, &mut writer, &aad)?;
+		// large files can take a while, so we report how far along the current file is
+		let progress_ctx = ctx.clone();
+		let obj_name = step.obj_name.clone();
+		let reader = ProgressReader::new(reader, move |bytes_read| {
+			progress_ctx.progress_debounced(vec![JobReportUpdate::Message(progress_message(
+				FileAction::Decrypting,
+				&obj_name,
+				bytes_read,
+				file_size,
+			))]);
+		});
+
+		block_in_place(|| decryptor.decrypt_streams(reader, &mut writer, &aad))?;
 
 		// need to decrypt preview media/metadata, and maybe add an option in the UI so the user can chosoe to restore these values
 		// for now this can't easily be implemented, as we don't know what the new object id for the file will be (we know the old one, but it may differ)
@@ -146,7 +178,12 @@ impl StatefulJob for FileDecryptorJob {
 		_ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> JobResult {
+		let mut metadata = serde_json::to_value(&state.init)?;
+		if let Some(data) = &state.data {
+			data.in_use.add_to_metadata(&mut metadata);
+		}
+
 		// mark job as successful
-		Ok(Some(serde_json::to_value(&state.init)?))
+		Ok(Some(metadata))
 	}
 }
@@ -0,0 +1,642 @@
+//! Reading the files of disk images without mounting them, so their contents can be indexed like an
+//! archive's. ISO 9660 images (with their Joliet names when they have them) and FAT12/16/32
+//! filesystems are read, either on their own or as the first FAT partition of a whole-disk image.
+//! Images of other filesystems, like the HFS+ or APFS of most DMGs, are reported as unsupported.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+
+use super::archive_reader::{dos_date_time, ArchiveEntryInfo, ArchiveReaderError};
+
+const ISO_SECTOR_SIZE: u64 = 2048;
+/// The volume descriptors of an ISO 9660 image start after its 16 system sectors
+const ISO_FIRST_DESCRIPTOR: u64 = 16;
+/// Directories nested deeper than this are left out, which also stops images with looping
+/// directories from being read forever
+const MAX_DEPTH: usize = 64;
+/// The MBR partition types of FAT filesystems
+const FAT_PARTITION_TYPES: [u8; 6] = [0x01, 0x04, 0x06, 0x0B, 0x0C, 0x0E];
+/// Directories bigger than this are taken for a corrupted image rather than read into memory
+const MAX_DIR_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A file or directory of a disk image, with where its data is in the image
+#[derive(Debug)]
+struct ImageEntry {
+	info: ArchiveEntryInfo,
+	/// the byte ranges of the file's data, in order
+	extents: Vec<(u64, u64)>,
+}
+
+/// is_disk_image_name tells if a file name is the one of a disk image that can be read
+pub(super) fn is_disk_image_name(name: &str) -> bool {
+	[".iso", ".img", ".ima", ".dmg", ".cdr", ".toast"]
+		.iter()
+		.any(|extension| name.ends_with(extension))
+}
+
+/// list_entries lists the files and directories of the filesystem in a disk image
+pub(super) fn list_entries(
+	reader: &mut (impl Read + Seek),
+) -> Result<Vec<ArchiveEntryInfo>, ArchiveReaderError> {
+	Ok(read_entries(reader)?
+		.into_iter()
+		.map(|entry| entry.info)
+		.collect())
+}
+
+/// extract_entry copies a single file of a disk image into `output`
+pub(super) fn extract_entry(
+	reader: &mut (impl Read + Seek),
+	entry_path: &str,
+	output: &mut impl Write,
+) -> Result<u64, ArchiveReaderError> {
+	let entry = read_entries(reader)?
+		.into_iter()
+		.find(|entry| !entry.info.is_dir && entry.info.path == entry_path)
+		.ok_or_else(|| ArchiveReaderError::EntryNotFound(entry_path.to_string()))?;
+
+	let mut written = 0;
+	for (offset, len) in entry.extents {
+		reader.seek(SeekFrom::Start(offset))?;
+		written += io::copy(&mut (&mut *reader).take(len), output)?;
+	}
+
+	Ok(written)
+}
+
+fn read_entries(reader: &mut (impl Read + Seek)) -> Result<Vec<ImageEntry>, ArchiveReaderError> {
+	if let Some(root) = iso_root(reader)? {
+		let mut entries = vec![];
+		read_iso_dir(reader, &root, "", 0, &mut entries)?;
+		return Ok(entries);
+	}
+
+	for offset in fat_offsets(reader)? {
+		if let Some(fat) = FatVolume::open(reader, offset)? {
+			return fat.entries(reader);
+		}
+	}
+
+	Err(ArchiveReaderError::UnsupportedFilesystem)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+	u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+	u32::from_le_bytes([
+		buf[offset],
+		buf[offset + 1],
+		buf[offset + 2],
+		buf[offset + 3],
+	])
+}
+
+/// read_at fills `buf` from `offset` of the image, returning false if the image ends before
+fn read_at(
+	reader: &mut (impl Read + Seek),
+	offset: u64,
+	buf: &mut [u8],
+) -> Result<bool, io::Error> {
+	reader.seek(SeekFrom::Start(offset))?;
+	match reader.read_exact(buf) {
+		Ok(()) => Ok(true),
+		Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+		Err(e) => Err(e),
+	}
+}
+
+/// The root directory of an ISO 9660 image
+struct IsoDir {
+	extent: u64,
+	size: u64,
+	joliet: bool,
+}
+
+/// iso_root finds the root directory of an ISO 9660 image, preferring the Joliet one as it has
+/// long and unicode names, or returns none if the image isn't one
+fn iso_root(reader: &mut (impl Read + Seek)) -> Result<Option<IsoDir>, ArchiveReaderError> {
+	let mut root = None;
+	let mut descriptor = [0; ISO_SECTOR_SIZE as usize];
+
+	for sector in ISO_FIRST_DESCRIPTOR.. {
+		if !read_at(reader, sector * ISO_SECTOR_SIZE, &mut descriptor)?
+			|| &descriptor[1..6] != b"CD001"
+		{
+			break;
+		}
+
+		let record = &descriptor[156..190];
+		let dir = |joliet| IsoDir {
+			extent: read_u32(record, 2) as u64,
+			size: read_u32(record, 10) as u64,
+			joliet,
+		};
+		match descriptor[0] {
+			// the primary volume descriptor
+			1 if root.is_none() => root = Some(dir(false)),
+			// a supplementary one, which is Joliet if it says it's UCS-2
+			2 if matches!(&descriptor[88..91], b"%/@" | b"%/C" | b"%/E") => {
+				root = Some(dir(true));
+			}
+			255 => break,
+			_ => {}
+		}
+	}
+
+	Ok(root)
+}
+
+fn read_iso_dir(
+	reader: &mut (impl Read + Seek),
+	dir: &IsoDir,
+	parent: &str,
+	depth: usize,
+	entries: &mut Vec<ImageEntry>,
+) -> Result<(), ArchiveReaderError> {
+	if depth > MAX_DEPTH {
+		return Ok(());
+	}
+
+	if dir.size > MAX_DIR_SIZE {
+		return Err(ArchiveReaderError::MalformedImage("directory too large"));
+	}
+	let mut data = vec![0; dir.size as usize];
+	if !read_at(reader, dir.extent * ISO_SECTOR_SIZE, &mut data)? {
+		return Err(ArchiveReaderError::MalformedImage("truncated directory"));
+	}
+
+	let mut offset = 0;
+	while offset < data.len() {
+		let len = data[offset] as usize;
+		// records don't cross sectors, the rest of a sector is padded with zeroes
+		if len == 0 {
+			offset = (offset / ISO_SECTOR_SIZE as usize + 1) * ISO_SECTOR_SIZE as usize;
+			continue;
+		}
+		if len < 34 || offset + len > data.len() {
+			return Err(ArchiveReaderError::MalformedImage(
+				"invalid directory record",
+			));
+		}
+
+		let record = &data[offset..offset + len];
+		offset += len;
+
+		let name_len = record[32] as usize;
+		if 33 + name_len > record.len() {
+			return Err(ArchiveReaderError::MalformedImage(
+				"invalid directory record",
+			));
+		}
+		let name = &record[33..33 + name_len];
+		// the directory itself and its parent
+		if name == [0] || name == [1] {
+			continue;
+		}
+
+		let name = iso_name(name, dir.joliet);
+		let path = match parent.is_empty() {
+			true => name,
+			false => format!("{parent}/{name}"),
+		};
+		let extent = read_u32(record, 2) as u64;
+		let size = read_u32(record, 10) as u64;
+		let flags = record[25];
+		let is_dir = flags & 0x02 != 0;
+
+		// a large file is split in several records of the same name, the last without the flag
+		if let Some(previous) = entries.last_mut() {
+			if previous.info.path == path && !previous.info.is_dir && !previous.extents.is_empty() {
+				previous.info.size += size;
+				previous.extents.push((extent * ISO_SECTOR_SIZE, size));
+				continue;
+			}
+		}
+
+		entries.push(ImageEntry {
+			info: ArchiveEntryInfo {
+				path: path.clone(),
+				is_dir,
+				size: if is_dir { 0 } else { size },
+				date_modified: iso_date(&record[18..25]),
+			},
+			extents: match is_dir {
+				true => vec![],
+				false => vec![(extent * ISO_SECTOR_SIZE, size)],
+			},
+		});
+
+		if is_dir {
+			let child = IsoDir {
+				extent,
+				size,
+				joliet: dir.joliet,
+			};
+			read_iso_dir(reader, &child, &path, depth + 1, entries)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// iso_name decodes the name of an ISO 9660 record, without its `;1` version number
+fn iso_name(name: &[u8], joliet: bool) -> String {
+	let name = match joliet {
+		true => String::from_utf16_lossy(
+			&name
+				.chunks_exact(2)
+				.map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+				.collect::<Vec<_>>(),
+		),
+		false => String::from_utf8_lossy(name).to_string(),
+	};
+
+	let name = name.split(';').next().unwrap_or_default();
+	// a file without an extension still gets its separator
+	name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+/// iso_date decodes the recording date of an ISO 9660 record, which is in local time with its
+/// offset from UTC in 15 minute intervals
+fn iso_date(date: &[u8]) -> Option<DateTime<Utc>> {
+	let local = NaiveDate::from_ymd_opt(1900 + date[0] as i32, date[1] as u32, date[2] as u32)?
+		.and_hms_opt(date[3] as u32, date[4] as u32, date[5] as u32)?;
+	let offset = Duration::minutes(date[6] as i8 as i64 * 15);
+
+	Some(Utc.from_utc_datetime(&(local - offset)))
+}
+
+/// fat_offsets returns where a FAT filesystem may start in the image: at its start if it's the
+/// image of a single volume, or at the start of its FAT partitions if it's the image of a disk
+fn fat_offsets(reader: &mut (impl Read + Seek)) -> Result<Vec<u64>, ArchiveReaderError> {
+	let mut sector = [0; 512];
+	if !read_at(reader, 0, &mut sector)? || sector[510..512] != [0x55, 0xAA] {
+		return Ok(vec![]);
+	}
+
+	let mut offsets = vec![0];
+	offsets.extend(sector[446..510].chunks_exact(16).filter_map(|partition| {
+		FAT_PARTITION_TYPES
+			.contains(&partition[4])
+			.then(|| read_u32(partition, 8) as u64 * 512)
+	}));
+
+	Ok(offsets)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+	Fat12,
+	Fat16,
+	Fat32,
+}
+
+/// A FAT filesystem in the image, from its boot sector
+struct FatVolume {
+	fat_type: FatType,
+	cluster_size: u64,
+	/// where the first cluster, number 2, starts
+	data_offset: u64,
+	/// where FAT12 and FAT16 keep their root directory, outside of the clusters
+	root_offset: u64,
+	root_size: u64,
+	root_cluster: u32,
+	fat: Vec<u8>,
+	cluster_count: u32,
+}
+
+impl FatVolume {
+	/// open reads the boot sector at `offset`, returning none if there isn't a FAT filesystem
+	fn open(
+		reader: &mut (impl Read + Seek),
+		offset: u64,
+	) -> Result<Option<Self>, ArchiveReaderError> {
+		let mut boot = [0; 512];
+		if !read_at(reader, offset, &mut boot)? || !matches!(boot[0], 0xEB | 0xE9) {
+			return Ok(None);
+		}
+
+		let bytes_per_sector = read_u16(&boot, 11) as u64;
+		let sectors_per_cluster = boot[13] as u64;
+		let reserved_sectors = read_u16(&boot, 14) as u64;
+		let fat_count = boot[16] as u64;
+		let root_entries = read_u16(&boot, 17) as u64;
+		let total_sectors = match read_u16(&boot, 19) {
+			0 => read_u32(&boot, 32) as u64,
+			sectors => sectors as u64,
+		};
+		let fat_sectors = match read_u16(&boot, 22) {
+			0 => read_u32(&boot, 36) as u64,
+			sectors => sectors as u64,
+		};
+
+		if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+			|| !sectors_per_cluster.is_power_of_two()
+			|| fat_count == 0
+			|| fat_sectors == 0
+		{
+			return Ok(None);
+		}
+
+		let root_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector;
+		let data_sector = reserved_sectors + fat_count * fat_sectors + root_sectors;
+		let cluster_count = match total_sectors.checked_sub(data_sector) {
+			Some(data_sectors) => (data_sectors / sectors_per_cluster) as u32,
+			None => return Ok(None),
+		};
+		// the type is told by how many clusters there are, not by the label in the boot sector
+		let fat_type = match cluster_count {
+			0..=4084 => FatType::Fat12,
+			4085..=65524 => FatType::Fat16,
+			_ => FatType::Fat32,
+		};
+
+		// only the part of the table for the clusters there are is read
+		let fat_size = match fat_type {
+			FatType::Fat12 => (cluster_count as u64 + 2) * 3 / 2 + 1,
+			FatType::Fat16 => (cluster_count as u64 + 2) * 2,
+			FatType::Fat32 => (cluster_count as u64 + 2) * 4,
+		}
+		.min(fat_sectors * bytes_per_sector);
+		let fat_offset = offset + reserved_sectors * bytes_per_sector;
+		if fat_offset + fat_size > reader.seek(SeekFrom::End(0))? {
+			return Ok(None);
+		}
+		let mut fat = vec![0; fat_size as usize];
+		if !read_at(reader, fat_offset, &mut fat)? {
+			return Err(ArchiveReaderError::MalformedImage(
+				"truncated allocation table",
+			));
+		}
+
+		Ok(Some(Self {
+			fat_type,
+			cluster_size: sectors_per_cluster * bytes_per_sector,
+			data_offset: offset + data_sector * bytes_per_sector,
+			root_offset: offset + (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+			root_size: root_entries * 32,
+			root_cluster: match fat_type {
+				FatType::Fat32 => read_u32(&boot, 44),
+				_ => 0,
+			},
+			fat,
+			cluster_count,
+		}))
+	}
+
+	/// next_cluster reads the allocation table for the cluster after `cluster`, none at the end of
+	/// its chain
+	fn next_cluster(&self, cluster: u32) -> Option<u32> {
+		let index = cluster as usize;
+		let next = match self.fat_type {
+			FatType::Fat12 => {
+				let offset = index + index / 2;
+				let value = read_u16(self.fat.get(offset..offset + 2)?, 0);
+				match index % 2 {
+					0 => (value & 0x0FFF) as u32,
+					_ => (value >> 4) as u32,
+				}
+			}
+			FatType::Fat16 => read_u16(self.fat.get(index * 2..index * 2 + 2)?, 0) as u32,
+			FatType::Fat32 => read_u32(self.fat.get(index * 4..index * 4 + 4)?, 0) & 0x0FFF_FFFF,
+		};
+
+		(2..self.cluster_count.saturating_add(2))
+			.contains(&next)
+			.then_some(next)
+	}
+
+	/// extents returns the byte ranges of the chain of clusters starting at `cluster`, with
+	/// consecutive clusters merged. `size` limits them to a file's size, as its last cluster is
+	/// only partly used.
+	fn extents(&self, cluster: u32, size: Option<u64>) -> Vec<(u64, u64)> {
+		let mut extents: Vec<(u64, u64)> = vec![];
+		let mut left = size.unwrap_or(u64::MAX);
+		let mut cluster = (cluster >= 2).then_some(cluster);
+
+		// a chain can't be longer than the volume, which stops a looping one
+		for _ in 0..self.cluster_count {
+			let current = match cluster {
+				Some(current) if left > 0 => current,
+				_ => break,
+			};
+			let start = self.data_offset + (current as u64 - 2) * self.cluster_size;
+			let len = self.cluster_size.min(left);
+			left -= len;
+
+			match extents.last_mut() {
+				Some((last_start, last_len)) if *last_start + *last_len == start => {
+					*last_len += len
+				}
+				_ => extents.push((start, len)),
+			}
+			cluster = self.next_cluster(current);
+		}
+
+		extents
+	}
+
+	fn entries(
+		&self,
+		reader: &mut (impl Read + Seek),
+	) -> Result<Vec<ImageEntry>, ArchiveReaderError> {
+		let root = match self.fat_type {
+			FatType::Fat32 => self.extents(self.root_cluster, None),
+			_ => vec![(self.root_offset, self.root_size)],
+		};
+
+		let mut entries = vec![];
+		self.read_dir(reader, &root, "", 0, &mut entries)?;
+		Ok(entries)
+	}
+
+	fn read_dir(
+		&self,
+		reader: &mut (impl Read + Seek),
+		extents: &[(u64, u64)],
+		parent: &str,
+		depth: usize,
+		entries: &mut Vec<ImageEntry>,
+	) -> Result<(), ArchiveReaderError> {
+		if depth > MAX_DEPTH {
+			return Ok(());
+		}
+
+		if extents.iter().map(|(_, len)| len).sum::<u64>() > MAX_DIR_SIZE {
+			return Err(ArchiveReaderError::MalformedImage("directory too large"));
+		}
+		let mut data = vec![];
+		for (offset, len) in extents {
+			let mut extent = vec![0; *len as usize];
+			if !read_at(reader, *offset, &mut extent)? {
+				return Err(ArchiveReaderError::MalformedImage("truncated directory"));
+			}
+			data.extend(extent);
+		}
+
+		// long names are stored in the entries before the one they're for, last part first
+		let mut long_name: Vec<u16> = vec![];
+		for record in data.chunks_exact(32) {
+			match record[0] {
+				// no more entries
+				0x00 => break,
+				// deleted
+				0xE5 => {
+					long_name.clear();
+					continue;
+				}
+				_ => {}
+			}
+
+			let attributes = record[11];
+			if attributes == 0x0F {
+				let mut part = [&record[1..11], &record[14..26], &record[28..32]]
+					.concat()
+					.chunks_exact(2)
+					.map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+					.take_while(|c| *c != 0 && *c != 0xFFFF)
+					.collect::<Vec<_>>();
+				// the first part read is flagged as the last one
+				if record[0] & 0x40 != 0 {
+					long_name.clear();
+				}
+				part.extend(&long_name);
+				long_name = part;
+				continue;
+			}
+
+			let name = match long_name.is_empty() {
+				true => short_name(&record[0..11]),
+				false => String::from_utf16_lossy(&long_name),
+			};
+			long_name.clear();
+
+			// the volume label, and the directory itself and its parent
+			if attributes & 0x08 != 0 || name == "." || name == ".." {
+				continue;
+			}
+
+			let path = match parent.is_empty() {
+				true => name,
+				false => format!("{parent}/{name}"),
+			};
+			let is_dir = attributes & 0x10 != 0;
+			let cluster = (read_u16(record, 20) as u32) << 16 | read_u16(record, 26) as u32;
+			let size = read_u32(record, 28) as u64;
+			let extents = match is_dir {
+				true => self.extents(cluster, None),
+				false => self.extents(cluster, Some(size)),
+			};
+
+			entries.push(ImageEntry {
+				info: ArchiveEntryInfo {
+					path: path.clone(),
+					is_dir,
+					size: if is_dir { 0 } else { size },
+					date_modified: dos_date_time(read_u16(record, 24), read_u16(record, 22)),
+				},
+				extents: match is_dir {
+					true => vec![],
+					false => extents.clone(),
+				},
+			});
+
+			if is_dir {
+				self.read_dir(reader, &extents, &path, depth + 1, entries)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// short_name decodes an 8.3 name, e.g. `README  TXT` to `README.TXT`
+fn short_name(name: &[u8]) -> String {
+	let mut base = String::from_utf8_lossy(&name[0..8]).trim_end().to_string();
+	// a name starting with 0xE5 has it stored as 0x05, as 0xE5 marks deleted entries
+	if name[0] == 0x05 {
+		base.replace_range(0..1, "\u{E5}");
+	}
+	let extension = String::from_utf8_lossy(&name[8..11]).trim_end().to_string();
+
+	match extension.is_empty() {
+		true => base,
+		false => format!("{base}.{extension}"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	/// iso_record builds a directory record of an ISO 9660 image
+	fn iso_record(name: &[u8], extent: u32, size: u32, is_dir: bool) -> Vec<u8> {
+		let mut record = vec![0; 33 + name.len() + (name.len() + 1) % 2];
+		record[0] = record.len() as u8;
+		record[2..6].copy_from_slice(&extent.to_le_bytes());
+		record[10..14].copy_from_slice(&size.to_le_bytes());
+		// 2022-11-12 14:30:50, an hour ahead of UTC
+		record[18..25].copy_from_slice(&[122, 11, 12, 14, 30, 50, 4]);
+		record[25] = if is_dir { 0x02 } else { 0 };
+		record[32] = name.len() as u8;
+		record[33..33 + name.len()].copy_from_slice(name);
+		record
+	}
+
+	#[test]
+	fn lists_and_extracts_iso_files() {
+		let sector = ISO_SECTOR_SIZE as usize;
+		let mut image = vec![0; 21 * sector];
+
+		// the primary volume descriptor, whose root directory is in sector 18
+		image[16 * sector] = 1;
+		image[16 * sector + 1..16 * sector + 6].copy_from_slice(b"CD001");
+		let root = iso_record(&[0], 18, sector as u32, true);
+		image[16 * sector + 156..16 * sector + 156 + root.len()].copy_from_slice(&root);
+		image[17 * sector] = 255;
+		image[17 * sector + 1..17 * sector + 6].copy_from_slice(b"CD001");
+
+		let mut records = [
+			iso_record(&[0], 18, sector as u32, true),
+			iso_record(&[1], 18, sector as u32, true),
+			iso_record(b"DOCS", 19, sector as u32, true),
+		]
+		.concat();
+		image[18 * sector..18 * sector + records.len()].copy_from_slice(&records);
+		records = [
+			iso_record(&[0], 19, sector as u32, true),
+			iso_record(&[1], 18, sector as u32, true),
+			iso_record(b"HELLO.TXT;1", 20, 5, false),
+		]
+		.concat();
+		image[19 * sector..19 * sector + records.len()].copy_from_slice(&records);
+		image[20 * sector..20 * sector + 5].copy_from_slice(b"hello");
+
+		let entries = list_entries(&mut Cursor::new(&image)).unwrap();
+		assert_eq!(
+			entries
+				.iter()
+				.map(|entry| (entry.path.as_str(), entry.is_dir))
+				.collect::<Vec<_>>(),
+			[("DOCS", true), ("DOCS/HELLO.TXT", false)]
+		);
+		assert_eq!(
+			entries[1].date_modified.unwrap().to_rfc3339(),
+			"2022-11-12T13:30:50+00:00"
+		);
+
+		let mut output = vec![];
+		extract_entry(&mut Cursor::new(&image), "DOCS/HELLO.TXT", &mut output).unwrap();
+		assert_eq!(output, b"hello");
+	}
+
+	#[test]
+	fn decodes_short_names() {
+		assert_eq!(short_name(b"README  TXT"), "README.TXT");
+		assert_eq!(short_name(b"DCIM       "), "DCIM");
+	}
+}
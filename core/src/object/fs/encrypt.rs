@@ -1,4 +1,4 @@
-use std::{collections::VecDeque, path::PathBuf};
+use std::{collections::VecDeque, ffi::OsString, path::PathBuf};
 
 use chrono::FixedOffset;
 use sd_crypto::{
@@ -8,23 +8,29 @@ use sd_crypto::{
 };
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use tokio::task::block_in_place;
 use tracing::warn;
 
 use crate::{
 	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
 	prisma::{file_path, location, object},
+	util::message::FileAction,
 };
 
+use super::{progress_message, InUseTracker, ProgressReader};
+
 pub struct FileEncryptorJob;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 enum ObjectType {
 	File,
 	Directory,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct FileEncryptorJobState {}
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileEncryptorJobState {
+	in_use: InUseTracker,
+}
 
 #[derive(Serialize, Deserialize, Type)]
 pub struct FileEncryptorJobInit {
@@ -37,7 +43,7 @@ pub struct FileEncryptorJobInit {
 	pub output_path: Option<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileEncryptorJobStep {
 	obj_name: String,
 	obj_path: PathBuf,
@@ -56,7 +62,7 @@ pub struct Metadata {
 	pub date_modified: chrono::DateTime<FixedOffset>,
 }
 
-const JOB_NAME: &str = "file_encryptor";
+pub const ENCRYPT_JOB_NAME: &str = "file_encryptor";
 
 #[async_trait::async_trait]
 impl StatefulJob for FileEncryptorJob {
@@ -65,7 +71,7 @@ impl StatefulJob for FileEncryptorJob {
 	type Step = FileEncryptorJobStep;
 
 	fn name(&self) -> &'static str {
-		JOB_NAME
+		ENCRYPT_JOB_NAME
 	}
 
 	async fn init(
@@ -94,9 +100,10 @@ impl StatefulJob for FileEncryptorJob {
 		let item = library
 			.db
 			.file_path()
-			.find_first(vec![file_path::object_id::equals(Some(
-				state.init.object_id,
-			))])
+			.find_first(vec![
+				file_path::location_id::equals(state.init.location_id),
+				file_path::object_id::equals(Some(state.init.object_id)),
+			])
 			.exec()
 			.await?
 			.expect("critical error: can't find object");
@@ -119,6 +126,7 @@ impl StatefulJob for FileEncryptorJob {
 			obj_path,
 			obj_type,
 		});
+		state.data = Some(FileEncryptorJobState::default());
 
 		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
 
@@ -130,6 +138,21 @@ impl StatefulJob for FileEncryptorJob {
 		ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> Result<(), JobError> {
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		// files that are open in another application are retried later, or skipped
+		let obj_path = state.steps[0].obj_path.clone();
+		if data
+			.in_use
+			.check(&ctx, &obj_path, &mut state.steps, state.step_number)
+			.await
+		{
+			return Ok(());
+		}
+
 		let step = &state.steps[0];
 
 		match step.obj_type {
@@ -147,22 +170,28 @@ impl StatefulJob for FileEncryptorJob {
 					.key_manager
 					.access_keystore(state.init.key_uuid)?;
 
+				// keep the keys unlocked while they're being used
+				ctx.library_ctx().key_lock.touch();
+
 				let output_path = if let Some(path) = state.init.output_path.clone() {
 					path
 				} else {
 					let mut path = step.obj_path.clone();
-					let extension = if let Some(ext) = path.extension() {
-						ext.to_str()
-							.expect("critical error: path is not valid utf-8")
-							.to_string() + ".sdenc"
-					} else {
-						"sdenc".to_string()
-					};
+					// we keep the original extension so it can be restored on decryption
+					let extension = path.extension().map_or_else(
+						|| OsString::from("sdenc"),
+						|ext| {
+							let mut ext = ext.to_os_string();
+							ext.push(".sdenc");
+							ext
+						},
+					);
 					path.set_extension(extension);
 					path
 				};
 
-				let mut reader = std::fs::File::open(step.obj_path.clone())?;
+				let file_size = std::fs::metadata(&step.obj_path)?.len();
+				let reader = std::fs::File::open(&step.obj_path)?;
 				let mut writer = std::fs::File::create(output_path)?;
 
 				let master_key = generate_master_key();
@@ -214,7 +243,8 @@ impl StatefulJob for FileEncryptorJob {
 
 					if state.init.preview_media
 						&& (object.has_thumbnail
-							|| object.has_video_preview || object.has_thumbstrip)
+							|| object.has_video_preview
+							|| object.has_thumbstrip)
 					{
 						// need to find the preview media, read it and return it as Some()
 						// not currently able to do this as thumnails don't generate
@@ -225,7 +255,18 @@ impl StatefulJob for FileEncryptorJob {
 
 				let encryptor = StreamEncryption::new(master_key, &header.nonce, header.algorithm)?;
 
-				encryptor.encrypt_streams(&mut reader, &mut writer, &header.generate_aad())?;
+				// large files can take a while, so we report how far along the current file is
+				let progress_ctx = ctx.clone();
+				let obj_name = step.obj_name.clone();
+				let reader = ProgressReader::new(reader, move |bytes_read| {
+					progress_ctx.progress_debounced(vec![JobReportUpdate::Message(
+						progress_message(FileAction::Encrypting, &obj_name, bytes_read, file_size),
+					)]);
+				});
+
+				block_in_place(|| {
+					encryptor.encrypt_streams(reader, &mut writer, &header.generate_aad())
+				})?;
 			}
 			_ => warn!(
 				"encryption is skipping {} as it isn't a file",
@@ -245,7 +286,12 @@ impl StatefulJob for FileEncryptorJob {
 		_ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> JobResult {
+		let mut metadata = serde_json::to_value(&state.init)?;
+		if let Some(data) = &state.data {
+			data.in_use.add_to_metadata(&mut metadata);
+		}
+
 		// mark job as successful
-		Ok(Some(serde_json::to_value(&state.init)?))
+		Ok(Some(metadata))
 	}
 }
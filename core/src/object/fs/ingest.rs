@@ -0,0 +1,454 @@
+//! Ingesting a camera card: the files on it that the library doesn't have yet are copied into a
+//! location, named with an organize pattern like `{year}/{month}/{original_name}`. Files are known
+//! by their cas_id, so a card that's ingested again only has its new photos copied. Every copy is
+//! read back and checked against the checksum of what was read from the card before it's kept.
+
+use std::{
+	collections::HashSet,
+	fs::{self, File},
+	io,
+	path::{Path, PathBuf},
+};
+
+use chrono::Local;
+use prisma_client_rust::QueryError;
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{
+	invalidate_query,
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	location::{
+		fetch_location, indexer::indexer_job::indexer_job_location, rescan_path, scan_location,
+		space_monitor::ensure_fits,
+	},
+	object::{cas::generate_cas_id, validation::hash::file_checksum},
+	prisma::object,
+	util::{
+		message::{FileAction, Message},
+		os_path::lossy_name,
+	},
+	volume::{eject_volume, find_volume, get_volumes},
+};
+
+use super::{
+	batch::local_location,
+	copy::copy_and_hash,
+	organize::{file_date, render_pattern, validate_pattern},
+	progress_message, ProgressReader,
+};
+
+pub const INGEST_JOB_NAME: &str = "ingest";
+
+/// Entries of a card that aren't photos or videos, left there by the OS or the camera
+const IGNORED_NAMES: [&str; 2] = ["System Volume Information", "$RECYCLE.BIN"];
+
+#[derive(Error, Debug)]
+pub enum IngestError {
+	#[error("Ingest source '{}' isn't a directory", .0.display())]
+	InvalidSource(PathBuf),
+	#[error("Database error: {0}")]
+	Database(#[from] QueryError),
+	#[error("I/O error: {0}")]
+	IO(#[from] io::Error),
+}
+
+impl From<IngestError> for rspc::Error {
+	fn from(err: IngestError) -> Self {
+		match err {
+			IngestError::InvalidSource(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+pub struct IngestJob;
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct IngestJobInit {
+	/// the directory to ingest, usually where the card is mounted
+	pub source_path: PathBuf,
+	pub target_location_id: i32,
+	/// materialized path of the directory to copy into, the root of the location if empty
+	pub target_path: String,
+	/// where files go in the directory, e.g. `{year}/{month}/{original_name}`
+	pub pattern: String,
+	/// only files with these extensions are ingested, all of them if none
+	pub extensions: Option<Vec<String>>,
+	/// ejects the source's volume once everything's copied
+	pub eject: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IngestJobState {
+	#[serde(with = "crate::util::os_path::serde_path")]
+	target_root: PathBuf,
+	/// the cas_ids of what this job copied, so copies of a file on the card aren't ingested twice
+	ingested: HashSet<String>,
+	copied: usize,
+	copied_bytes: u64,
+	/// files the library already had
+	skipped: usize,
+	/// copies that didn't match what was read from the card, which were removed
+	mismatched: usize,
+	failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IngestJobStep {
+	#[serde(with = "crate::util::os_path::serde_path")]
+	source: PathBuf,
+	size: u64,
+}
+
+/// collect_files lists the files under `dir` that are ingested, in order, leaving out hidden ones
+fn collect_files(
+	dir: &Path,
+	extensions: Option<&[String]>,
+	files: &mut Vec<IngestJobStep>,
+) -> Result<(), io::Error> {
+	let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+	entries.sort_by_key(|entry| entry.file_name());
+
+	for entry in entries {
+		let name = entry.file_name().to_string_lossy().to_string();
+		if name.starts_with('.') || IGNORED_NAMES.contains(&name.as_str()) {
+			continue;
+		}
+
+		let metadata = entry.metadata()?;
+		if metadata.is_dir() {
+			collect_files(&entry.path(), extensions, files)?;
+		} else if metadata.is_file() {
+			let path = entry.path();
+			let extension = lossy_name(path.extension()).to_lowercase();
+			if extensions.map_or(true, |extensions| {
+				extensions
+					.iter()
+					.any(|wanted| wanted.trim_start_matches('.').to_lowercase() == extension)
+			}) {
+				files.push(IngestJobStep {
+					source: path,
+					size: metadata.len(),
+				});
+			}
+		}
+	}
+
+	Ok(())
+}
+
+fn ingest_error(source: &Path, message: Message) -> FileError {
+	FileError {
+		location_id: None,
+		file_path_id: None,
+		path: source.to_string_lossy().to_string(),
+		message,
+	}
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for IngestJob {
+	type Init = IngestJobInit;
+	type Data = IngestJobState;
+	type Step = IngestJobStep;
+
+	fn name(&self) -> &'static str {
+		INGEST_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let init = &state.init;
+
+		validate_pattern(&init.pattern)?;
+		if !init.source_path.is_dir() {
+			return Err(IngestError::InvalidSource(init.source_path.clone()).into());
+		}
+
+		let (location_path, _) = local_location(&library, init.target_location_id).await?;
+		let target_root = location_path.join(init.target_path.trim_matches('/'));
+
+		let mut files = vec![];
+		block_in_place(|| {
+			collect_files(&init.source_path, init.extensions.as_deref(), &mut files)
+		})?;
+
+		ensure_fits(
+			&library,
+			&target_root,
+			files.iter().map(|file| file.size).sum(),
+		)?;
+
+		state.steps = files.into();
+		state.data = Some(IngestJobState {
+			target_root,
+			ingested: HashSet::new(),
+			copied: 0,
+			copied_bytes: 0,
+			skipped: 0,
+			mismatched: 0,
+			failed: 0,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+			path: step.source.to_string_lossy().to_string(),
+		})]);
+
+		let ingested = async {
+			// objects only keep the start of the cas_id
+			let mut cas_id = generate_cas_id(step.source.clone(), step.size).await?;
+			cas_id.truncate(16);
+
+			let known = data.ingested.contains(&cas_id)
+				|| library
+					.db
+					.object()
+					.find_first(vec![object::cas_id::equals(cas_id.clone())])
+					.exec()
+					.await?
+					.is_some();
+			if known {
+				data.skipped += 1;
+				return Ok(());
+			}
+
+			let extension = lossy_name(step.source.extension());
+			let date = block_in_place(|| file_date(&step.source, &extension))
+				.map(|(date, _)| date)
+				.unwrap_or_else(|| Local::now().naive_local());
+			let relative = render_pattern(
+				&state.init.pattern,
+				date,
+				&lossy_name(step.source.file_stem()),
+				&extension,
+			);
+
+			let node_config = library.config().get().await;
+			let target = node_config
+				.conflict_naming
+				.free_path(&data.target_root.join(relative), &node_config.name);
+			if let Some(parent) = target.parent() {
+				block_in_place(|| fs::create_dir_all(parent))?;
+			}
+			let partial =
+				target.with_file_name(format!(".{}.sdingest", lossy_name(target.file_name())));
+
+			let progress_ctx = ctx.clone();
+			let name = lossy_name(step.source.file_name());
+			let size = step.size;
+			let reader =
+				ProgressReader::new(File::open(&step.source)?, move |bytes_read| {
+					progress_ctx.progress_debounced(vec![JobReportUpdate::Message(
+						progress_message(FileAction::Copying, &name, bytes_read, size),
+					)]);
+				});
+			let (copied_cas_id, checksum) =
+				match block_in_place(|| copy_and_hash(reader, &partial, size)) {
+					Ok(hashes) => hashes,
+					Err(e) => {
+						// the card may have been pulled out halfway
+						tokio::fs::remove_file(&partial).await.ok();
+						return Err(e.into());
+					}
+				};
+
+			// what was written is read back, so a copy the disk didn't store right isn't kept
+			let written = file_checksum(partial.clone()).await?;
+			if !copied_cas_id.starts_with(&cas_id) || written != checksum {
+				warn!(
+					"Copy of {} doesn't match what was read from the card, removing it",
+					step.source.display()
+				);
+				tokio::fs::remove_file(&partial).await?;
+				ctx.record_file_error(ingest_error(
+					&step.source,
+					Message::Error {
+						text: "the copy doesn't match the original".to_string(),
+					},
+				))
+				.await;
+				data.mismatched += 1;
+				return Ok(());
+			}
+
+			tokio::fs::rename(&partial, &target).await?;
+			data.ingested.insert(cas_id);
+			data.copied += 1;
+			data.copied_bytes += size;
+
+			Ok::<_, IngestError>(())
+		}
+		.await;
+
+		match ingested {
+			Ok(()) => {}
+			Err(IngestError::IO(e)) => {
+				ctx.record_file_error(ingest_error(&step.source, (&e).into()))
+					.await;
+				data.failed += 1;
+			}
+			Err(e) => return Err(e.into()),
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+		let location_id = state.init.target_location_id;
+
+		// indexed again, so what was copied is known the next time the card is ingested
+		if data.copied > 0 {
+			let location = fetch_location(&library, location_id)
+				.include(indexer_job_location::include())
+				.exec()
+				.await?;
+			if let Some(location) = location {
+				let sub_path = state.init.target_path.trim_matches('/');
+				let scanned = match sub_path.is_empty() {
+					true => scan_location(&library, location).await,
+					false => rescan_path(&library, location, sub_path).await,
+				};
+				if let Err(e) = scanned {
+					warn!("Failed to index location {location_id} after ingesting: {e}");
+				}
+			}
+			library.statistics.location_changed(location_id);
+			invalidate_query!(library, "locations.getExplorerData");
+		}
+
+		let ejected = state.init.eject && eject_source(&state.init.source_path);
+		if ejected {
+			invalidate_query!(library, "volumes.list");
+		}
+
+		info!(
+			"Ingested {}: {} files copied ({} bytes), {} already in the library, {} mismatched, \
+			{} failed",
+			state.init.source_path.display(),
+			data.copied,
+			data.copied_bytes,
+			data.skipped,
+			data.mismatched,
+			data.failed
+		);
+
+		Ok(Some(json!({
+			"init": state.init,
+			"copied": data.copied,
+			"copied_bytes": data.copied_bytes,
+			"skipped": data.skipped,
+			"mismatched": data.mismatched,
+			"failed": data.failed,
+			"ejected": ejected,
+		})))
+	}
+}
+
+/// eject_source ejects the volume an ingested directory is on, which has to be removable so the
+/// system's own disk is never unmounted. Failing to eject is only logged, as everything was copied.
+fn eject_source(source_path: &Path) -> bool {
+	let volumes = match get_volumes() {
+		Ok(volumes) => volumes,
+		Err(e) => {
+			warn!("Failed to list volumes to eject: {e}");
+			return false;
+		}
+	};
+	let source_path = fs::canonicalize(source_path).unwrap_or_else(|_| source_path.to_path_buf());
+
+	match find_volume(&volumes, &source_path) {
+		Some((volume, _)) if volume.is_removable && !volume.is_root_filesystem => {
+			match block_in_place(|| eject_volume(volume)) {
+				Ok(()) => true,
+				Err(e) => {
+					warn!("Failed to eject {}: {e}", volume.name);
+					false
+				}
+			}
+		}
+		_ => {
+			warn!(
+				"Not ejecting {}, it isn't on a removable volume",
+				source_path.display()
+			);
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn collects_files_to_ingest() {
+		let dir = tempfile::tempdir().unwrap();
+		let dcim = dir.path().join("DCIM/100CANON");
+		fs::create_dir_all(&dcim).unwrap();
+		fs::create_dir_all(dir.path().join(".Trashes")).unwrap();
+		for name in [
+			"IMG_0001.JPG",
+			"IMG_0001.CR2",
+			"IMG_0002.JPG",
+			"._IMG_0002.JPG",
+		] {
+			fs::write(dcim.join(name), name).unwrap();
+		}
+		fs::write(dir.path().join(".Trashes/IMG_0000.JPG"), "deleted").unwrap();
+
+		let mut files = vec![];
+		collect_files(dir.path(), None, &mut files).unwrap();
+		assert_eq!(
+			files
+				.iter()
+				.map(|file| lossy_name(file.source.file_name()))
+				.collect::<Vec<_>>(),
+			["IMG_0001.CR2", "IMG_0001.JPG", "IMG_0002.JPG"]
+		);
+
+		let mut files = vec![];
+		collect_files(dir.path(), Some(&["jpg".to_string()]), &mut files).unwrap();
+		assert_eq!(files.len(), 2);
+	}
+}
@@ -1,2 +1,200 @@
+use std::{
+	collections::VecDeque,
+	io::Read,
+	path::{Path, PathBuf},
+};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	job::{JobReportUpdate, WorkerContext},
+	library::LibraryContext,
+	prisma::file_path,
+	util::{
+		file_lock::{find_lock_holder, FileLockHolder},
+		message::{FileAction, Message},
+	},
+};
+
+pub mod archive;
+pub mod archive_reader;
+pub mod batch;
+pub mod copy;
 pub mod decrypt;
+pub mod disk_image;
 pub mod encrypt;
+pub mod ingest;
+pub mod organize;
+pub mod rename;
+pub mod restore;
+pub mod sync;
+pub mod trash;
+pub mod upload;
+
+/// `ProgressReader` wraps a reader and calls `on_progress` with the total amount of bytes read so far.
+/// The crypto streams are synchronous and can take a long time on large files, so this lets jobs
+/// report progress while a single step is still running.
+pub struct ProgressReader<R, F> {
+	inner: R,
+	bytes_read: u64,
+	on_progress: F,
+}
+
+impl<R, F> ProgressReader<R, F>
+where
+	R: Read,
+	F: FnMut(u64),
+{
+	pub fn new(inner: R, on_progress: F) -> Self {
+		Self {
+			inner,
+			bytes_read: 0,
+			on_progress,
+		}
+	}
+}
+
+impl<R, F> Read for ProgressReader<R, F>
+where
+	R: Read,
+	F: FnMut(u64),
+{
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let read_count = self.inner.read(buf)?;
+		self.bytes_read += read_count as u64;
+		(self.on_progress)(self.bytes_read);
+		Ok(read_count)
+	}
+}
+
+/// Builds the progress message for a step that processes a single file, e.g. "Encrypting photo.png (42%)"
+pub(crate) fn progress_message(
+	action: FileAction,
+	name: &str,
+	bytes_done: u64,
+	total_bytes: u64,
+) -> Message {
+	Message::FileProgress {
+		action,
+		name: name.to_string(),
+		percent: (total_bytes > 0)
+			.then(|| ((bytes_done.min(total_bytes) * 100) / total_bytes) as u8),
+	}
+}
+
+/// Removes a file_path from the database and, if it's a directory, every file_path inside of it
+pub(crate) async fn delete_file_path_tree(
+	library: &LibraryContext,
+	location_id: i32,
+	materialized_path: &str,
+) -> Result<(), prisma_client_rust::QueryError> {
+	library
+		.db
+		.file_path()
+		.delete_many(vec![
+			file_path::location_id::equals(location_id),
+			file_path::materialized_path::equals(materialized_path.to_string()),
+		])
+		.exec()
+		.await?;
+
+	library
+		.db
+		.file_path()
+		.delete_many(vec![
+			file_path::location_id::equals(location_id),
+			file_path::materialized_path::starts_with(format!("{materialized_path}/")),
+		])
+		.exec()
+		.await?;
+
+	library.statistics.location_changed(location_id);
+
+	Ok(())
+}
+
+/// A file a job left alone because another application was using it
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct LockedFile {
+	pub path: PathBuf,
+	pub holder: FileLockHolder,
+}
+
+/// `InUseTracker` lets jobs work around files that are in use by other applications instead of failing.
+/// The first time a file is found in use its step is moved to the back of the queue, giving the other
+/// application time to let go of it; if it's still in use when we get back to it, the file is skipped.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct InUseTracker {
+	deferred: Vec<PathBuf>,
+	pub locked_files: Vec<LockedFile>,
+}
+
+impl InUseTracker {
+	/// check returns true if the file at `path` is in use and the current step should leave it alone,
+	/// in which case the step has already been deferred or skipped and its progress reported.
+	pub async fn check<S: Clone + Send>(
+		&mut self,
+		ctx: &WorkerContext,
+		path: &Path,
+		steps: &mut VecDeque<S>,
+		step_number: usize,
+	) -> bool {
+		let holder = match find_lock_holder(path).await {
+			Some(holder) => holder,
+			None => return false,
+		};
+
+		let name = path
+			.file_name()
+			.map(|name| name.to_string_lossy().to_string())
+			.unwrap_or_else(|| path.display().to_string());
+
+		if self.deferred.iter().any(|deferred| deferred == path) {
+			ctx.progress(vec![
+				JobReportUpdate::CompletedTaskCount(step_number + 1),
+				JobReportUpdate::Message(Message::SkippedInUse {
+					name,
+					holder: holder.clone(),
+				}),
+			]);
+			self.locked_files.push(LockedFile {
+				path: path.to_path_buf(),
+				holder,
+			});
+		} else {
+			self.deferred.push(path.to_path_buf());
+			steps.push_back(steps[0].clone());
+			// the current step is counted as completed once it's popped, so the retry adds a task
+			ctx.progress(vec![
+				JobReportUpdate::TaskCount(step_number + steps.len()),
+				JobReportUpdate::CompletedTaskCount(step_number + 1),
+				JobReportUpdate::Message(Message::WaitingOnInUse { name, holder }),
+			]);
+		}
+
+		true
+	}
+
+	/// record notes a file that was left alone outside of a step, e.g. while cleaning up in `finalize`
+	pub fn record(&mut self, path: &Path, holder: FileLockHolder) {
+		self.locked_files.push(LockedFile {
+			path: path.to_path_buf(),
+			holder,
+		});
+	}
+
+	/// add_to_metadata lists the skipped files in a job's metadata, if there are any
+	pub fn add_to_metadata(&self, metadata: &mut serde_json::Value) {
+		if self.locked_files.is_empty() {
+			return;
+		}
+
+		if let Some(metadata) = metadata.as_object_mut() {
+			metadata.insert(
+				"locked_files".to_string(),
+				serde_json::to_value(&self.locked_files).unwrap_or_default(),
+			);
+		}
+	}
+}
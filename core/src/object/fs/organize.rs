@@ -0,0 +1,678 @@
+//! Organizing a location by date: files are moved into the directories of a pattern like
+//! `{year}/{month}/{original_name}`, dated by when their photo was taken if their EXIF says so or when
+//! they were last modified otherwise. A plan of the moves can be looked at before anything is moved,
+//! with [`plan_organize`] or a dry run of the [`OrganizerJob`]. Directories emptied by the moves are
+//! left in place.
+
+use std::{
+	collections::HashSet,
+	fs::{self, File},
+	io::{self, BufReader},
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use prisma_client_rust::{Direction, QueryError};
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::{error, info, warn};
+
+use crate::{
+	invalidate_query,
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::{record_operations, LibraryContext, Operation},
+	location::{fetch_location, LocationError},
+	prisma::file_path,
+	sync::{
+		models::{uuid_from_pub_id, FilePathData, FilePathId, FILE_PATH},
+		SyncError,
+	},
+	util::{
+		message::Message,
+		os_path::{lossy_name, resolve_materialized_path},
+	},
+};
+
+pub const ORGANIZER_JOB_NAME: &str = "file_organizer";
+
+/// The placeholders a pattern can have
+const PLACEHOLDERS: [&str; 6] = [
+	"{year}",
+	"{month}",
+	"{day}",
+	"{original_name}",
+	"{name}",
+	"{extension}",
+];
+/// Extensions of the files whose EXIF is looked at for the date they were taken
+const EXIF_EXTENSIONS: [&str; 9] = [
+	"jpg", "jpeg", "tif", "tiff", "heic", "heif", "png", "webp", "dng",
+];
+
+#[derive(Error, Debug)]
+pub enum OrganizeError {
+	#[error("Invalid organize pattern '{0}': {1}")]
+	InvalidPattern(String, &'static str),
+	#[error("Location error: {0}")]
+	Location(#[from] LocationError),
+	#[error("Database error: {0}")]
+	Database(#[from] QueryError),
+	#[error("I/O error: {0}")]
+	IO(#[from] io::Error),
+}
+
+impl From<OrganizeError> for rspc::Error {
+	fn from(err: OrganizeError) -> Self {
+		match err {
+			OrganizeError::InvalidPattern(..) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			OrganizeError::Location(err) => err.into(),
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// Where the date a file is organized by came from
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateSource {
+	/// when the photo was taken, from its EXIF
+	Exif,
+	/// when the file was last modified
+	Modified,
+}
+
+/// A move the organizer plans, with materialized paths
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct PlannedMove {
+	pub file_path_id: i32,
+	pub from: String,
+	pub to: String,
+	pub date: NaiveDateTime,
+	pub date_source: DateSource,
+}
+
+pub struct OrganizerJob {}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct OrganizerJobInit {
+	pub location_id: i32,
+	/// materialized path of the directory to organize, the whole location if none
+	pub sub_path: Option<String>,
+	/// where files go in the directory, e.g. `{year}/{month}/{original_name}`
+	pub pattern: String,
+	/// only plans the moves, which are listed in the job's metadata
+	pub dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OrganizerJobState {
+	location_path: PathBuf,
+	location_pub_id: Vec<u8>,
+	moved: usize,
+	skipped: usize,
+	/// the moves of a dry run
+	plan: Vec<PlannedMove>,
+	/// the moves made, to be undone
+	operations: Vec<Operation>,
+}
+
+/// validate_pattern checks a pattern only has known placeholders, stays in the directory it organizes
+/// and names the files it moves
+pub fn validate_pattern(pattern: &str) -> Result<(), OrganizeError> {
+	let invalid = |reason| Err(OrganizeError::InvalidPattern(pattern.to_string(), reason));
+
+	if pattern.starts_with('/') || pattern.contains('\\') {
+		return invalid("it must be a relative path, separated by '/'");
+	}
+
+	let segments = pattern.split('/').collect::<Vec<_>>();
+	if segments
+		.iter()
+		.any(|segment| segment.is_empty() || *segment == "." || *segment == "..")
+	{
+		return invalid("it can't have empty, '.' or '..' directories");
+	}
+
+	let mut rest = pattern.to_string();
+	for placeholder in PLACEHOLDERS {
+		rest = rest.replace(placeholder, "");
+	}
+	if rest.contains(['{', '}']) {
+		return invalid("it has an unknown placeholder");
+	}
+
+	match segments.last() {
+		Some(name) if name.contains("{original_name}") || name.contains("{name}") => Ok(()),
+		_ => invalid("it must end with '{original_name}' or '{name}'"),
+	}
+}
+
+/// render_pattern returns where a file goes in the organized directory, relative to it
+pub(crate) fn render_pattern(
+	pattern: &str,
+	date: NaiveDateTime,
+	stem: &str,
+	extension: &str,
+) -> PathBuf {
+	let original_name = match extension.is_empty() {
+		true => stem.to_string(),
+		false => format!("{stem}.{extension}"),
+	};
+
+	pattern
+		.split('/')
+		.map(|segment| {
+			segment
+				.replace("{year}", &date.format("%Y").to_string())
+				.replace("{month}", &date.format("%m").to_string())
+				.replace("{day}", &date.format("%d").to_string())
+				.replace("{original_name}", &original_name)
+				.replace("{name}", stem)
+				.replace("{extension}", extension)
+		})
+		.collect()
+}
+
+/// exif_date returns when a photo was taken, if its EXIF says
+pub(crate) fn exif_date(path: &Path) -> Option<NaiveDateTime> {
+	let exif = exif::Reader::new()
+		.read_from_container(&mut BufReader::new(File::open(path).ok()?))
+		.ok()?;
+
+	[
+		exif::Tag::DateTimeOriginal,
+		exif::Tag::DateTimeDigitized,
+		exif::Tag::DateTime,
+	]
+	.into_iter()
+	.find_map(|tag| match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+		exif::Value::Ascii(values) => {
+			let date = exif::DateTime::from_ascii(values.first()?).ok()?;
+			NaiveDate::from_ymd_opt(date.year as i32, date.month as u32, date.day as u32)?
+				.and_hms_opt(date.hour as u32, date.minute as u32, date.second as u32)
+		}
+		_ => None,
+	})
+}
+
+/// file_date returns the date a file is organized by
+pub(crate) fn file_date(path: &Path, extension: &str) -> Option<(NaiveDateTime, DateSource)> {
+	if EXIF_EXTENSIONS.contains(&extension.to_lowercase().as_str()) {
+		if let Some(date) = exif_date(path) {
+			return Some((date, DateSource::Exif));
+		}
+	}
+
+	let modified = fs::metadata(path)
+		.and_then(|metadata| metadata.modified())
+		.ok()?;
+	Some((
+		DateTime::<Local>::from(modified).naive_local(),
+		DateSource::Modified,
+	))
+}
+
+/// plan_organize works out where the files under `sub_path` of the location go with the pattern. Files
+/// that are already where they go are left out, and ones that would land on another file get a
+/// conflict copy name.
+pub async fn plan_organize(
+	library: &LibraryContext,
+	init: &OrganizerJobInit,
+) -> Result<Vec<PlannedMove>, OrganizeError> {
+	validate_pattern(&init.pattern)?;
+
+	let location = fetch_location(library, init.location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(init.location_id))?;
+	if !location.is_online {
+		return Err(LocationError::Offline(location.id).into());
+	}
+	if location.is_catalog {
+		return Err(LocationError::ReadOnlyCatalog(location.id).into());
+	}
+	let location_path = location
+		.local_path
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingLocalPath(location.id))?;
+
+	let mut params = vec![
+		file_path::location_id::equals(location.id),
+		file_path::is_dir::equals(false),
+	];
+	if let Some(sub_path) = &init.sub_path {
+		params.push(file_path::materialized_path::starts_with(format!(
+			"{sub_path}/"
+		)));
+	}
+	let file_paths = library.db.file_path().find_many(params).exec().await?;
+
+	let node_config = library.config().get().await;
+	let root = PathBuf::from(init.sub_path.clone().unwrap_or_default());
+
+	Ok(block_in_place(|| {
+		let mut claimed = HashSet::new();
+
+		file_paths
+			.into_iter()
+			// names that aren't valid UTF-8 can't be put in a pattern without changing them
+			.filter(|file_path| file_path.raw_path.is_none())
+			.filter_map(|file_path| {
+				let extension = file_path.extension.clone().unwrap_or_default();
+				let path =
+					resolve_materialized_path(&location_path, &file_path.materialized_path, None);
+				let (date, date_source) = file_date(&path, &extension)?;
+
+				let target = location_path.join(&root).join(render_pattern(
+					&init.pattern,
+					date,
+					&file_path.name,
+					&extension,
+				));
+				if target == path {
+					return None;
+				}
+
+				let target = node_config
+					.conflict_naming
+					.candidates(target, &node_config.name)
+					.find(|candidate| !candidate.exists() && !claimed.contains(candidate))?;
+				claimed.insert(target.clone());
+
+				Some(PlannedMove {
+					file_path_id: file_path.id,
+					from: file_path.materialized_path,
+					to: target
+						.strip_prefix(&location_path)
+						.ok()?
+						.to_string_lossy()
+						.to_string(),
+					date,
+					date_source,
+				})
+			})
+			.collect()
+	}))
+}
+
+/// next_file_path_id returns the id the next file path made in the location gets
+pub(super) async fn next_file_path_id(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<i32, QueryError> {
+	Ok(library
+		.db
+		.file_path()
+		.find_first(vec![file_path::location_id::equals(location_id)])
+		.order_by(file_path::id::order(Direction::Desc))
+		.exec()
+		.await?
+		.map(|file_path| file_path.id + 1)
+		.unwrap_or(0))
+}
+
+/// ensure_dir returns the id of the file path of a directory of the location, indexing it and the
+/// directories above it if they aren't yet. Returns `None` for the root of the location.
+pub(super) async fn ensure_dir(
+	library: &LibraryContext,
+	location_id: i32,
+	location_pub_id: &[u8],
+	dir: &Path,
+) -> Result<Option<i32>, QueryError> {
+	let mut parent_id = None;
+	let mut materialized_path = PathBuf::new();
+
+	for component in dir.components() {
+		materialized_path.push(component);
+		let materialized = materialized_path.to_string_lossy().to_string();
+
+		if let Some(existing) = library
+			.db
+			.file_path()
+			.find_first(vec![
+				file_path::location_id::equals(location_id),
+				file_path::materialized_path::equals(materialized.clone()),
+				file_path::is_dir::equals(true),
+			])
+			.exec()
+			.await?
+		{
+			parent_id = Some(existing.id);
+			continue;
+		}
+
+		let id = next_file_path_id(library, location_id).await?;
+
+		library
+			.db
+			.file_path()
+			.create_many(vec![file_path::create_unchecked(
+				id,
+				location_id,
+				materialized,
+				lossy_name(Some(component.as_os_str())),
+				vec![
+					file_path::is_dir::set(true),
+					file_path::extension::set(Some(String::new())),
+					file_path::parent_id::set(parent_id),
+				],
+			)])
+			.exec()
+			.await?;
+
+		if let Err(e) = sync_created(library, location_id, location_pub_id, id).await {
+			error!("Error logging organized directory for sync: {:#?}", e);
+		}
+
+		parent_id = Some(id);
+	}
+
+	Ok(parent_id)
+}
+
+/// sync_created logs a file path made outside of the indexer, so the library's other nodes see it
+pub(super) async fn sync_created(
+	library: &LibraryContext,
+	location_id: i32,
+	location_pub_id: &[u8],
+	id: i32,
+) -> Result<(), SyncError> {
+	if !library.sync.is_location_logged(location_pub_id).await? {
+		return Ok(());
+	}
+
+	if let Some(created) = library
+		.db
+		.file_path()
+		.find_unique(file_path::location_id_id(location_id, id))
+		.with(file_path::object::fetch())
+		.exec()
+		.await?
+	{
+		let object = match created.object().ok().flatten() {
+			Some(object) => library
+				.sync
+				.ensure_object_pub_ids(std::slice::from_ref(object))
+				.await?
+				.get(&object.id)
+				.copied(),
+			None => None,
+		};
+
+		library
+			.sync
+			.write_ops(vec![library.sync.owned_create(
+				FILE_PATH,
+				[(
+					FilePathId {
+						location: uuid_from_pub_id(location_pub_id),
+						id,
+					},
+					FilePathData::new(&created, object),
+				)],
+			)])
+			.await?;
+	}
+
+	Ok(())
+}
+
+/// sync_moved logs where a file path was moved to, so the library's other nodes see it too
+async fn sync_moved(
+	library: &LibraryContext,
+	location_pub_id: &[u8],
+	moved: &file_path::Data,
+) -> Result<(), SyncError> {
+	if !library.sync.is_location_logged(location_pub_id).await? {
+		return Ok(());
+	}
+
+	let mut data = Map::new();
+	data.insert(
+		"materialized_path".to_string(),
+		json!(moved.materialized_path),
+	);
+	data.insert("name".to_string(), json!(moved.name));
+	data.insert("extension".to_string(), json!(moved.extension));
+	data.insert("parent_id".to_string(), json!(moved.parent_id));
+
+	library
+		.sync
+		.write_ops(vec![library.sync.owned_update(
+			FILE_PATH,
+			[(
+				FilePathId {
+					location: uuid_from_pub_id(location_pub_id),
+					id: moved.id,
+				},
+				data,
+			)],
+		)])
+		.await
+}
+
+/// move_file_path moves a file of a location to `to`, a materialized path in the same location, and
+/// updates its file path. The directories it goes in are made and indexed if they aren't yet.
+pub async fn move_file_path(
+	library: &LibraryContext,
+	location_id: i32,
+	location_path: &Path,
+	location_pub_id: &[u8],
+	file_path_id: i32,
+	from: &str,
+	to: &str,
+) -> Result<file_path::Data, OrganizeError> {
+	let to_path = Path::new(to);
+	let to_dir = to_path.parent().unwrap_or_else(|| Path::new(""));
+
+	block_in_place(|| fs::create_dir_all(location_path.join(to_dir)))?;
+	let parent_id = ensure_dir(library, location_id, location_pub_id, to_dir).await?;
+	block_in_place(|| fs::rename(location_path.join(from), location_path.join(to)))?;
+
+	let moved = library
+		.db
+		.file_path()
+		.update(
+			file_path::location_id_id(location_id, file_path_id),
+			vec![
+				file_path::materialized_path::set(to.to_string()),
+				file_path::name::set(lossy_name(to_path.file_stem())),
+				file_path::extension::set(Some(lossy_name(to_path.extension()))),
+				file_path::parent_id::set(parent_id),
+			],
+		)
+		.exec()
+		.await?;
+
+	if let Err(e) = sync_moved(library, location_pub_id, &moved).await {
+		error!("Error logging moved file for sync: {:#?}", e);
+	}
+
+	Ok(moved)
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for OrganizerJob {
+	type Init = OrganizerJobInit;
+	type Data = OrganizerJobState;
+	type Step = PlannedMove;
+
+	fn name(&self) -> &'static str {
+		ORGANIZER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		let location = fetch_location(&library, state.init.location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+		let location_path = location
+			.local_path
+			.map(PathBuf::from)
+			.ok_or(LocationError::MissingLocalPath(location.id))?;
+
+		let plan = plan_organize(&library, &state.init).await?;
+
+		let mut data = OrganizerJobState {
+			location_path,
+			location_pub_id: location.pub_id,
+			..Default::default()
+		};
+		match state.init.dry_run {
+			true => data.plan = plan,
+			false => state.steps = plan.into(),
+		}
+		state.data = Some(data);
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+		let library = ctx.library_ctx();
+		let location_id = state.init.location_id;
+
+		ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+			path: step.from.clone(),
+		})]);
+
+		let from = data.location_path.join(&step.from);
+		let to = data.location_path.join(&step.to);
+
+		// the location may have changed since the moves were planned
+		if !from.exists() || to.exists() {
+			warn!(
+				"Not moving {} to {}, one of them changed since the move was planned",
+				from.display(),
+				to.display()
+			);
+			data.skipped += 1;
+		} else {
+			match move_file_path(
+				&library,
+				location_id,
+				&data.location_path,
+				&data.location_pub_id,
+				step.file_path_id,
+				&step.from,
+				&step.to,
+			)
+			.await
+			{
+				Ok(_) => {
+					data.moved += 1;
+					data.operations.push(Operation::Move {
+						location_id,
+						file_path_id: step.file_path_id,
+						from: step.from.clone(),
+						to: step.to.clone(),
+					});
+				}
+				Err(OrganizeError::IO(e)) => {
+					ctx.record_file_error(FileError {
+						location_id: Some(location_id),
+						file_path_id: Some(step.file_path_id),
+						path: step.from.clone(),
+						message: Message::from(&e),
+					})
+					.await;
+					data.skipped += 1;
+				}
+				Err(e) => return Err(e.into()),
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		if data.moved > 0 {
+			record_operations(
+				&ctx.library_ctx(),
+				format!("Organize {} files", data.moved),
+				data.operations.clone(),
+			);
+			invalidate_query!(ctx.library_ctx(), "locations.getExplorerData");
+			ctx.library_ctx()
+				.statistics
+				.location_changed(state.init.location_id);
+		}
+
+		info!(
+			"Organized {}: {} files moved, {} skipped, {} planned",
+			data.location_path.display(),
+			data.moved,
+			data.skipped,
+			data.plan.len()
+		);
+
+		Ok(Some(json!({
+			"init": state.init,
+			"moved": data.moved,
+			"skipped": data.skipped,
+			"plan": data.plan,
+		})))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn validates_patterns() {
+		assert!(validate_pattern("{year}/{month}/{original_name}").is_ok());
+		assert!(validate_pattern("Photos {year}/{year}-{month}-{day} {name}.{extension}").is_ok());
+		assert!(validate_pattern("{year}/{month}").is_err());
+		assert!(validate_pattern("../{original_name}").is_err());
+		assert!(validate_pattern("/{year}/{original_name}").is_err());
+		assert!(validate_pattern("{camera}/{original_name}").is_err());
+	}
+
+	#[test]
+	fn renders_patterns() {
+		let date = NaiveDate::from_ymd(2022, 3, 9).and_hms(14, 30, 0);
+
+		assert_eq!(
+			render_pattern("{year}/{month}/{original_name}", date, "beach", "jpg"),
+			Path::new("2022/03/beach.jpg")
+		);
+		assert_eq!(
+			render_pattern("{year}-{month}-{day} {name}", date, "notes", ""),
+			Path::new("2022-03-09 notes")
+		);
+	}
+}
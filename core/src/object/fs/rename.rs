@@ -0,0 +1,611 @@
+//! Renaming a selection of files by a pattern: a template like `{name}_{counter}` or
+//! `{date_taken} {name}` for their new names, after an optional regex find and replace on the current
+//! ones. Files whose new name is taken, by a file that's already there or by another file of the
+//! selection, are left alone and reported as conflicts. A plan of the renames can be looked at with
+//! [`plan_rename`] before anything is renamed. Extensions are kept, and directories aren't renamed.
+
+use std::{
+	collections::{HashMap, HashSet},
+	path::{Path, PathBuf},
+};
+
+use chrono::{
+	format::{Item, StrftimeItems},
+	NaiveDateTime,
+};
+use prisma_client_rust::QueryError;
+use regex::Regex;
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{
+	invalidate_query,
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::{
+		record_operations, LibraryContext, Operation, SelectionError, SelectionItem,
+		SelectionTarget,
+	},
+	location::{fetch_location, LocationError},
+	prisma::{file_path, media_data},
+	util::message::Message,
+};
+
+use super::organize::{move_file_path, OrganizeError};
+
+pub const RENAMER_JOB_NAME: &str = "file_renamer";
+
+/// The placeholders a template can have
+const PLACEHOLDERS: [&str; 3] = ["{name}", "{counter}", "{date_taken}"];
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Error, Debug)]
+pub enum RenameError {
+	#[error("Invalid rename template '{0}': {1}")]
+	InvalidTemplate(String, &'static str),
+	#[error("Invalid date format '{0}'")]
+	InvalidDateFormat(String),
+	#[error("Invalid regex: {0}")]
+	InvalidRegex(#[from] regex::Error),
+	#[error("Selection error: {0}")]
+	Selection(#[from] SelectionError),
+	#[error("Location error: {0}")]
+	Location(#[from] LocationError),
+	#[error("Database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<RenameError> for rspc::Error {
+	fn from(err: RenameError) -> Self {
+		match err {
+			RenameError::InvalidTemplate(..)
+			| RenameError::InvalidDateFormat(_)
+			| RenameError::InvalidRegex(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			RenameError::Selection(err) => err.into(),
+			RenameError::Location(err) => err.into(),
+			RenameError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// How the files of a selection are renamed
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct RenamePattern {
+	/// the new name, without its extension, e.g. `{name}_{counter}`: `{name}` is the current name once
+	/// `find` is replaced, `{counter}` the file's place in the selection and `{date_taken}` when its
+	/// photo was taken, or when it was last modified if that isn't known
+	pub template: String,
+	/// a regex replaced in the current name by `replace`, which can refer to its groups as `$1`
+	pub find: Option<String>,
+	#[serde(default)]
+	pub replace: String,
+	/// what the first file is numbered, 1 if not given
+	pub counter_start: Option<u32>,
+	/// how many digits the counter is padded to, enough for the last file if not given
+	pub counter_digits: Option<u8>,
+	/// how `{date_taken}` is written, as a strftime format, `%Y-%m-%d` if not given
+	pub date_format: Option<String>,
+}
+
+impl RenamePattern {
+	/// validate checks the template only has known placeholders and makes names rather than paths,
+	/// and that the regex and date format are valid
+	pub fn validate(&self) -> Result<(), RenameError> {
+		let invalid = |reason| Err(RenameError::InvalidTemplate(self.template.clone(), reason));
+
+		if self.template.is_empty() {
+			return invalid("it can't be empty");
+		}
+		if self.template.contains(['/', '\\']) {
+			return invalid("it can't contain path separators");
+		}
+
+		let mut rest = self.template.clone();
+		for placeholder in PLACEHOLDERS {
+			rest = rest.replace(placeholder, "");
+		}
+		if rest.contains(['{', '}']) {
+			return invalid("it has an unknown placeholder");
+		}
+
+		let date_format = self.date_format();
+		if StrftimeItems::new(date_format).any(|item| item == Item::Error) {
+			return Err(RenameError::InvalidDateFormat(date_format.to_string()));
+		}
+
+		self.regex().map(|_| ())
+	}
+
+	fn regex(&self) -> Result<Option<Regex>, RenameError> {
+		Ok(self.find.as_deref().map(Regex::new).transpose()?)
+	}
+
+	fn date_format(&self) -> &str {
+		self.date_format.as_deref().unwrap_or(DEFAULT_DATE_FORMAT)
+	}
+
+	/// render returns the new name of a file called `name`, without its extension
+	fn render(
+		&self,
+		regex: Option<&Regex>,
+		name: &str,
+		counter: u32,
+		digits: usize,
+		date: NaiveDateTime,
+	) -> String {
+		let name = match regex {
+			Some(regex) => regex.replace_all(name, self.replace.as_str()).to_string(),
+			None => name.to_string(),
+		};
+
+		// `{name}` goes last, so placeholders in a file's name are kept as they are
+		self.template
+			.replace("{counter}", &format!("{counter:0digits$}"))
+			.replace(
+				"{date_taken}",
+				&sanitize(&date.format(self.date_format()).to_string()),
+			)
+			.replace("{name}", &sanitize(&name))
+	}
+}
+
+/// sanitize keeps a replaced name or a date from adding directories to a file name
+fn sanitize(part: &str) -> String {
+	part.replace(['/', '\\'], "-")
+}
+
+/// Why a file of a rename is left alone
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameConflict {
+	/// a file by its new name is already there
+	Exists,
+	/// another file of the selection would get the same name
+	Duplicate,
+	/// its new name is empty or only dots
+	Invalid,
+}
+
+/// A rename the renamer plans, with materialized paths
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct PlannedRename {
+	pub location_id: i32,
+	pub file_path_id: i32,
+	pub from: String,
+	pub to: String,
+	pub conflict: Option<RenameConflict>,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct RenameArgs {
+	pub pattern: RenamePattern,
+	pub target: SelectionTarget,
+}
+
+/// location_path returns where an online location is on this node
+async fn location_path(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<(PathBuf, Vec<u8>), RenameError> {
+	let location = fetch_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	if !location.is_online {
+		return Err(LocationError::Offline(location.id).into());
+	}
+	if location.is_catalog {
+		return Err(LocationError::ReadOnlyCatalog(location.id).into());
+	}
+
+	Ok((
+		location
+			.local_path
+			.map(PathBuf::from)
+			.ok_or(LocationError::MissingLocalPath(location.id))?,
+		location.pub_id,
+	))
+}
+
+/// plan_rename works out the new names of the selected files. Files are numbered in the order of their
+/// paths, and ones whose name doesn't change are left out.
+pub async fn plan_rename(
+	library: &LibraryContext,
+	pattern: &RenamePattern,
+	items: &[SelectionItem],
+) -> Result<Vec<PlannedRename>, RenameError> {
+	pattern.validate()?;
+	let regex = pattern.regex()?;
+
+	// file paths are only unique within their location
+	let mut ids_by_location = HashMap::<i32, Vec<i32>>::new();
+	for item in items {
+		ids_by_location
+			.entry(item.location_id)
+			.or_default()
+			.push(item.file_path_id);
+	}
+
+	let mut location_paths = HashMap::new();
+	let mut file_paths = vec![];
+	for (location_id, ids) in ids_by_location {
+		location_paths.insert(location_id, location_path(library, location_id).await?.0);
+		file_paths.extend(
+			library
+				.db
+				.file_path()
+				.find_many(vec![
+					file_path::location_id::equals(location_id),
+					file_path::id::in_vec(ids),
+					file_path::is_dir::equals(false),
+				])
+				.exec()
+				.await?
+				.into_iter()
+				// names that aren't valid UTF-8 can't be put in a template without changing them
+				.filter(|file_path| file_path.raw_path.is_none()),
+		);
+	}
+	file_paths.sort_by(|a, b| {
+		a.materialized_path
+			.cmp(&b.materialized_path)
+			.then(a.location_id.cmp(&b.location_id))
+	});
+
+	let dates_taken = library
+		.db
+		.media_data()
+		.find_many(vec![media_data::id::in_vec(
+			file_paths
+				.iter()
+				.filter_map(|file_path| file_path.object_id)
+				.collect(),
+		)])
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|media_data| Some((media_data.id, media_data.date_taken?.naive_utc())))
+		.collect::<HashMap<_, _>>();
+
+	let start = pattern.counter_start.unwrap_or(1);
+	let digits = pattern.counter_digits.map(usize::from).unwrap_or_else(|| {
+		(start as usize + file_paths.len().saturating_sub(1))
+			.to_string()
+			.len()
+	});
+
+	let mut plan = file_paths
+		.iter()
+		.enumerate()
+		.filter_map(|(i, file_path)| {
+			let date = file_path
+				.object_id
+				.and_then(|id| dates_taken.get(&id).copied())
+				.unwrap_or_else(|| file_path.date_modified.naive_local());
+			let name = pattern.render(
+				regex.as_ref(),
+				&file_path.name,
+				start + i as u32,
+				digits,
+				date,
+			);
+			let file_name = match file_path.extension.as_deref() {
+				Some(extension) if !extension.is_empty() => format!("{name}.{extension}"),
+				_ => name,
+			};
+
+			let to = match file_name.chars().all(|c| c == '.') {
+				true => file_name.clone(),
+				false => Path::new(&file_path.materialized_path)
+					.parent()
+					.unwrap_or_else(|| Path::new(""))
+					.join(&file_name)
+					.to_string_lossy()
+					.to_string(),
+			};
+			if to == file_path.materialized_path {
+				return None;
+			}
+
+			Some(PlannedRename {
+				location_id: file_path.location_id,
+				file_path_id: file_path.id,
+				from: file_path.materialized_path.clone(),
+				to,
+				conflict: file_name
+					.chars()
+					.all(|c| c == '.')
+					.then_some(RenameConflict::Invalid),
+			})
+		})
+		.collect::<Vec<_>>();
+
+	let mut claims = HashMap::<(i32, String), usize>::new();
+	for rename in plan.iter().filter(|rename| rename.conflict.is_none()) {
+		*claims
+			.entry((rename.location_id, rename.to.clone()))
+			.or_default() += 1;
+	}
+
+	// names of files that are indexed but may not be on disk, e.g. while the location is rescanned
+	let mut indexed = HashSet::new();
+	for location_id in location_paths.keys() {
+		indexed.extend(
+			library
+				.db
+				.file_path()
+				.find_many(vec![
+					file_path::location_id::equals(*location_id),
+					file_path::materialized_path::in_vec(
+						plan.iter()
+							.filter(|rename| rename.location_id == *location_id)
+							.map(|rename| rename.to.clone())
+							.collect(),
+					),
+				])
+				.exec()
+				.await?
+				.into_iter()
+				.map(|file_path| (file_path.location_id, file_path.materialized_path)),
+		);
+	}
+
+	block_in_place(|| {
+		for rename in plan.iter_mut().filter(|rename| rename.conflict.is_none()) {
+			let key = (rename.location_id, rename.to.clone());
+			if claims.get(&key).copied().unwrap_or_default() > 1 {
+				rename.conflict = Some(RenameConflict::Duplicate);
+			} else if indexed.contains(&key)
+				|| location_paths[&rename.location_id]
+					.join(&rename.to)
+					.exists()
+			{
+				rename.conflict = Some(RenameConflict::Exists);
+			}
+		}
+	});
+
+	Ok(plan)
+}
+
+pub struct RenamerJob {}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct RenamerJobInit {
+	pub items: Vec<SelectionItem>,
+	pub pattern: RenamePattern,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RenamerJobState {
+	/// the path and pub id of the locations of the files being renamed
+	locations: HashMap<i32, (PathBuf, Vec<u8>)>,
+	renamed: usize,
+	skipped: usize,
+	/// the renames that were left out because of a conflict
+	conflicts: Vec<PlannedRename>,
+	/// the renames made, to be undone
+	operations: Vec<Operation>,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for RenamerJob {
+	type Init = RenamerJobInit;
+	type Data = RenamerJobState;
+	type Step = PlannedRename;
+
+	fn name(&self) -> &'static str {
+		RENAMER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		let (renames, conflicts): (Vec<_>, Vec<_>) =
+			plan_rename(&library, &state.init.pattern, &state.init.items)
+				.await?
+				.into_iter()
+				.partition(|rename| rename.conflict.is_none());
+
+		let mut locations = HashMap::new();
+		for rename in &renames {
+			if !locations.contains_key(&rename.location_id) {
+				locations.insert(
+					rename.location_id,
+					location_path(&library, rename.location_id).await?,
+				);
+			}
+		}
+
+		state.steps = renames.into();
+		state.data = Some(RenamerJobState {
+			locations,
+			conflicts,
+			..Default::default()
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+		let library = ctx.library_ctx();
+		let (location_path, location_pub_id) = &data.locations[&step.location_id];
+
+		ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+			path: step.from.clone(),
+		})]);
+
+		let from = location_path.join(&step.from);
+		let to = location_path.join(&step.to);
+
+		// the location may have changed since the renames were planned
+		if !from.exists() || to.exists() {
+			warn!(
+				"Not renaming {} to {}, one of them changed since the rename was planned",
+				from.display(),
+				to.display()
+			);
+			data.skipped += 1;
+		} else {
+			match move_file_path(
+				&library,
+				step.location_id,
+				location_path,
+				location_pub_id,
+				step.file_path_id,
+				&step.from,
+				&step.to,
+			)
+			.await
+			{
+				Ok(_) => {
+					data.renamed += 1;
+					data.operations.push(Operation::Move {
+						location_id: step.location_id,
+						file_path_id: step.file_path_id,
+						from: step.from.clone(),
+						to: step.to.clone(),
+					});
+				}
+				Err(OrganizeError::IO(e)) => {
+					ctx.record_file_error(FileError {
+						location_id: Some(step.location_id),
+						file_path_id: Some(step.file_path_id),
+						path: step.from.clone(),
+						message: Message::from(&e),
+					})
+					.await;
+					data.skipped += 1;
+				}
+				Err(e) => return Err(e.into()),
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		if data.renamed > 0 {
+			record_operations(
+				&ctx.library_ctx(),
+				format!("Rename {} files", data.renamed),
+				data.operations.clone(),
+			);
+			invalidate_query!(ctx.library_ctx(), "locations.getExplorerData");
+			for location_id in data.locations.keys() {
+				ctx.library_ctx().statistics.location_changed(*location_id);
+			}
+		}
+
+		info!(
+			"Renamed {} files, {} skipped, {} left out because of a conflict",
+			data.renamed,
+			data.skipped,
+			data.conflicts.len()
+		);
+
+		Ok(Some(json!({
+			"pattern": state.init.pattern,
+			"renamed": data.renamed,
+			"skipped": data.skipped,
+			"conflicts": data.conflicts,
+		})))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pattern(template: &str) -> RenamePattern {
+		RenamePattern {
+			template: template.to_string(),
+			find: None,
+			replace: String::new(),
+			counter_start: None,
+			counter_digits: None,
+			date_format: None,
+		}
+	}
+
+	#[test]
+	fn validates_patterns() {
+		assert!(pattern("{name}_{counter}").validate().is_ok());
+		assert!(pattern("{date_taken} {name}").validate().is_ok());
+		assert!(pattern("").validate().is_err());
+		assert!(pattern("photos/{name}").validate().is_err());
+		assert!(pattern("{camera}_{name}").validate().is_err());
+
+		let mut bad_regex = pattern("{name}");
+		bad_regex.find = Some("(unclosed".to_string());
+		assert!(matches!(
+			bad_regex.validate(),
+			Err(RenameError::InvalidRegex(_))
+		));
+	}
+
+	#[test]
+	fn renders_names() {
+		let date =
+			NaiveDateTime::parse_from_str("2022-12-24 18:30:00", "%Y-%m-%d %H:%M:%S").unwrap();
+
+		assert_eq!(
+			pattern("{name}_{counter}").render(None, "IMG", 7, 3, date),
+			"IMG_007"
+		);
+		assert_eq!(
+			pattern("{date_taken} {name}").render(None, "beach", 1, 1, date),
+			"2022-12-24 beach"
+		);
+
+		let mut replaced = pattern("{name}");
+		replaced.find = Some(r"^IMG_(\d+)$".to_string());
+		replaced.replace = "Holiday $1".to_string();
+		let regex = replaced.regex().unwrap();
+		assert_eq!(
+			replaced.render(regex.as_ref(), "IMG_0042", 1, 1, date),
+			"Holiday 0042"
+		);
+
+		// replacements can't move a file to another directory, nor fill in placeholders
+		replaced.replace = "a/{counter}".to_string();
+		assert_eq!(
+			replaced.render(regex.as_ref(), "IMG_0042", 1, 1, date),
+			"a-{counter}"
+		);
+	}
+}
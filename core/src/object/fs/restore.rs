@@ -0,0 +1,394 @@
+use std::{
+	collections::HashMap,
+	fs::{self, File},
+	path::PathBuf,
+};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::{fetch_location, LocationError},
+	prisma::{file_path, location, object},
+	util::{message::FileAction, os_path::resolve_materialized_path},
+};
+
+use super::{
+	copy::{copy_and_hash, ExpectedHash},
+	progress_message, ProgressReader,
+};
+
+pub const RESTORE_JOB_NAME: &str = "file_restorer";
+
+location::include!(location_with_node { node });
+
+/// Where the content of a missing file can still be found
+#[derive(Serialize, Type, Debug)]
+pub struct RecoverySource {
+	pub location_id: i32,
+	pub file_path_id: i32,
+	pub node_name: String,
+	/// the full path of the copy, only set for copies on this node
+	pub local_path: Option<PathBuf>,
+}
+
+/// A file the library knows about that is gone from disk, but whose content exists elsewhere
+#[derive(Serialize, Type, Debug)]
+pub struct RecoverableFile {
+	pub file_path_id: i32,
+	pub materialized_path: String,
+	/// the OS bytes of the path, only set when it isn't valid UTF-8
+	pub raw_path: Option<Vec<u8>>,
+	pub object_id: i32,
+	pub sources: Vec<RecoverySource>,
+}
+
+/// find_recoverable looks through the location for files that are missing on disk and finds other
+/// copies of their content, on this node or another. Pass `file_path_ids` to only check those files.
+pub async fn find_recoverable(
+	library: &LibraryContext,
+	location_id: i32,
+	file_path_ids: Option<Vec<i32>>,
+) -> Result<Vec<RecoverableFile>, LocationError> {
+	let location = fetch_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+
+	// an offline location has every file missing, which says nothing about them being lost
+	if !location.is_online {
+		return Err(LocationError::Offline(location_id));
+	}
+	if location.is_catalog {
+		return Err(LocationError::ReadOnlyCatalog(location_id));
+	}
+
+	let location_path = location
+		.local_path
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingLocalPath(location_id))?;
+
+	let mut params = vec![
+		file_path::location_id::equals(location_id),
+		file_path::is_dir::equals(false),
+	];
+	if let Some(file_path_ids) = file_path_ids {
+		params.push(file_path::id::in_vec(file_path_ids));
+	}
+
+	let file_paths = library.db.file_path().find_many(params).exec().await?;
+	let missing = block_in_place(|| {
+		file_paths
+			.into_iter()
+			.filter(|file_path| {
+				file_path.object_id.is_some()
+					&& !resolve_materialized_path(
+						&location_path,
+						&file_path.materialized_path,
+						file_path.raw_path.as_deref(),
+					)
+					.exists()
+			})
+			.collect::<Vec<_>>()
+	});
+
+	if missing.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let locations = library
+		.db
+		.location()
+		.find_many(vec![])
+		.include(location_with_node::include())
+		.exec()
+		.await?
+		.into_iter()
+		.map(|location| (location.id, location))
+		.collect::<HashMap<_, _>>();
+
+	let mut copies_by_object = HashMap::<i32, Vec<file_path::Data>>::new();
+	for copy in library
+		.db
+		.file_path()
+		.find_many(vec![file_path::object_id::in_vec(
+			missing
+				.iter()
+				.filter_map(|file_path| file_path.object_id)
+				.collect(),
+		)])
+		.exec()
+		.await?
+	{
+		if let Some(object_id) = copy.object_id {
+			copies_by_object.entry(object_id).or_default().push(copy);
+		}
+	}
+
+	let mut recoverable = vec![];
+	for file_path in missing {
+		let object_id = match file_path.object_id {
+			Some(object_id) => object_id,
+			None => continue,
+		};
+
+		let mut sources = vec![];
+		for copy in copies_by_object.get(&object_id).into_iter().flatten() {
+			if copy.location_id == location_id && copy.id == file_path.id {
+				continue;
+			}
+
+			let copy_location = match locations.get(&copy.location_id) {
+				Some(copy_location) => copy_location,
+				None => continue,
+			};
+
+			let local_path = if copy_location.node_id == library.node_local_id {
+				// copies on this node are only worth suggesting if they're really there
+				match &copy_location.local_path {
+					Some(path) if copy_location.is_online => {
+						let path = resolve_materialized_path(
+							path,
+							&copy.materialized_path,
+							copy.raw_path.as_deref(),
+						);
+						if !path.exists() {
+							continue;
+						}
+						Some(path)
+					}
+					_ => continue,
+				}
+			} else {
+				None
+			};
+
+			sources.push(RecoverySource {
+				location_id: copy.location_id,
+				file_path_id: copy.id,
+				node_name: copy_location.node.name.clone(),
+				local_path,
+			});
+		}
+
+		if !sources.is_empty() {
+			recoverable.push(RecoverableFile {
+				file_path_id: file_path.id,
+				materialized_path: file_path.materialized_path,
+				raw_path: file_path.raw_path,
+				object_id,
+				sources,
+			});
+		}
+	}
+
+	Ok(recoverable)
+}
+
+/// FileRestorerJob copies the content of missing files back to where they were, from other copies
+/// of the same content on this node. Each copy is verified against the object's hashes first.
+pub struct FileRestorerJob;
+
+#[derive(Serialize, Deserialize, Type, Debug)]
+pub struct FileRestorerJobInit {
+	pub location_id: i32,
+	pub file_path_ids: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileRestorerJobState {
+	restored: Vec<PathBuf>,
+	/// files none of the copies could be restored from
+	failed: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileRestorerJobStep {
+	#[serde(with = "crate::util::os_path::serde_path")]
+	target: PathBuf,
+	sources: Vec<PathBuf>,
+	expected: ExpectedHash,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FileRestorerJob {
+	type Init = FileRestorerJobInit;
+	type Data = FileRestorerJobState;
+	type Step = FileRestorerJobStep;
+
+	fn name(&self) -> &'static str {
+		RESTORE_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		let location_path = fetch_location(&library, state.init.location_id)
+			.exec()
+			.await?
+			.and_then(|location| location.local_path)
+			.map(PathBuf::from)
+			.ok_or_else(|| JobError::JobDataNotFound(RESTORE_JOB_NAME.to_string()))?;
+
+		let recoverable = find_recoverable(
+			&library,
+			state.init.location_id,
+			Some(state.init.file_path_ids.clone()),
+		)
+		.await?;
+
+		let objects = library
+			.db
+			.object()
+			.find_many(vec![object::id::in_vec(
+				recoverable.iter().map(|file| file.object_id).collect(),
+			)])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|object| (object.id, object))
+			.collect::<HashMap<_, _>>();
+
+		// content only on other nodes has to come back through sync, it can't be copied from here
+		state.steps = recoverable
+			.into_iter()
+			.filter_map(|file| {
+				let sources = file
+					.sources
+					.into_iter()
+					.filter_map(|source| source.local_path)
+					.collect::<Vec<_>>();
+
+				match (sources.is_empty(), objects.get(&file.object_id)) {
+					(false, Some(object)) => Some(FileRestorerJobStep {
+						target: resolve_materialized_path(
+							&location_path,
+							&file.materialized_path,
+							file.raw_path.as_deref(),
+						),
+						sources,
+						expected: ExpectedHash::new(object),
+					}),
+					_ => None,
+				}
+			})
+			.collect();
+
+		state.data = Some(FileRestorerJobState::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		// something else may have put a file there since we looked
+		if step.target.exists() {
+			warn!("{} exists again, not restoring it", step.target.display());
+		} else {
+			if let Some(parent) = step.target.parent() {
+				fs::create_dir_all(parent)?;
+			}
+
+			let name = step
+				.target
+				.file_name()
+				.map(|name| name.to_string_lossy().to_string())
+				.unwrap_or_default();
+
+			let mut restored = false;
+			for source in &step.sources {
+				let size = match fs::metadata(source) {
+					Ok(metadata) => metadata.len(),
+					Err(e) => {
+						warn!("Copy at {} is gone: {e}", source.display());
+						continue;
+					}
+				};
+
+				let progress_ctx = ctx.clone();
+				let progress_name = name.clone();
+				let reader = ProgressReader::new(File::open(source)?, move |bytes_read| {
+					progress_ctx.progress_debounced(vec![JobReportUpdate::Message(
+						progress_message(FileAction::Restoring, &progress_name, bytes_read, size),
+					)]);
+				});
+
+				let (cas_id, checksum) =
+					block_in_place(|| copy_and_hash(reader, &step.target, size))?;
+
+				if step.expected.matches(&cas_id, &checksum) {
+					step.expected
+						.update_checksum(&ctx.library_ctx(), checksum)
+						.await?;
+					restored = true;
+					break;
+				}
+
+				warn!(
+					"Copy at {} doesn't match the missing file's content",
+					source.display()
+				);
+				fs::remove_file(&step.target)?;
+			}
+
+			if restored {
+				data.restored.push(step.target.clone());
+			} else {
+				data.failed.push(step.target.clone());
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		invalidate_query!(ctx.library_ctx(), "files.getRecoverable");
+		invalidate_query!(ctx.library_ctx(), "locations.getExplorerData");
+
+		info!(
+			"Restored {} missing files, {} couldn't be restored",
+			data.restored.len(),
+			data.failed.len()
+		);
+
+		Ok(Some(json!({
+			"init": state.init,
+			"restored": data.restored,
+			"failed": data.failed,
+		})))
+	}
+}
@@ -0,0 +1,950 @@
+//! Two locations kept in sync with each other in both directions, like a folder on a laptop and its
+//! copy on an external drive. The sides are compared through the index: a file is matched by its
+//! materialized path, and its cas_id on each side is compared with the one it had on both when they
+//! were last in sync. A file changed on one side only is copied to the other side, or deleted from
+//! it. A file changed on both sides is a conflict, and neither side is touched until the user picks
+//! which version to keep.
+//!
+//! Files are checked on disk before they're replaced or deleted. A file changed since it was last
+//! indexed is left alone, and the next sync after a rescan picks it up. Files that aren't
+//! identified yet, and ones whose name isn't valid UTF-8, aren't synced.
+
+use std::{
+	collections::{BTreeSet, HashMap},
+	io,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use int_enum::IntEnum;
+use prisma_client_rust::{raw::Raw, PrismaValue, QueryError};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::{fs, task::block_in_place};
+use tracing::{info, warn};
+
+use crate::{
+	invalidate_query,
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::{
+		fetch_location, indexer::indexer_job::indexer_job_location, space_monitor::ensure_fits,
+		sweep_location, LocationError,
+	},
+	object::cas::generate_cas_id,
+	prisma::{folder_sync, folder_sync_conflict, folder_sync_entry, location},
+	util::{db::raw_int, message::Message},
+};
+
+use super::{
+	batch::local_location,
+	copy::copy_and_hash,
+	organize::{move_file_path, OrganizeError},
+	trash::{move_to_trash, TrashError},
+};
+
+pub const FOLDER_SYNC_JOB_NAME: &str = "folder_sync";
+
+#[derive(Error, Debug)]
+pub enum FolderSyncError {
+	#[error("folder sync not found: <id='{0}'>")]
+	NotFound(i32),
+	#[error("folder sync conflict not found: <id='{0}'>")]
+	ConflictNotFound(i32),
+	#[error("a location can't be synced with itself: <id='{0}'>")]
+	SameLocation(i32),
+	#[error("locations are already synced: <left_id='{0}', right_id='{1}'>")]
+	AlreadySynced(i32, i32),
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] QueryError),
+	#[error("{0}")]
+	LocationError(#[from] LocationError),
+}
+
+impl From<FolderSyncError> for rspc::Error {
+	fn from(err: FolderSyncError) -> Self {
+		match err {
+			FolderSyncError::NotFound(_) | FolderSyncError::ConflictNotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			FolderSyncError::SameLocation(_) | FolderSyncError::AlreadySynced(..) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			FolderSyncError::LocationError(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// Which version of a conflicting file is kept, picked by the user
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum ConflictResolution {
+	/// the left version replaces the right one, or the file is deleted from the right side if it
+	/// was deleted from the left one
+	KeepLeft = 0,
+	KeepRight = 1,
+	/// the right version is renamed with a conflict copy name, and both end up on both sides
+	KeepBoth = 2,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct FolderSyncCreateArgs {
+	pub left_location_id: i32,
+	pub right_location_id: i32,
+}
+
+impl FolderSyncCreateArgs {
+	pub async fn create(
+		self,
+		library: &LibraryContext,
+	) -> Result<folder_sync::Data, FolderSyncError> {
+		if self.left_location_id == self.right_location_id {
+			return Err(FolderSyncError::SameLocation(self.left_location_id));
+		}
+
+		for location_id in [self.left_location_id, self.right_location_id] {
+			let location = fetch_location(library, location_id)
+				.exec()
+				.await?
+				.ok_or(LocationError::IdNotFound(location_id))?;
+			// both sides are written to
+			if location.is_catalog {
+				return Err(LocationError::ReadOnlyCatalog(location_id).into());
+			}
+		}
+
+		// the locations may be synced the other way around already
+		let location_ids = vec![self.left_location_id, self.right_location_id];
+		let existing = library
+			.db
+			.folder_sync()
+			.find_first(vec![
+				folder_sync::left_location_id::in_vec(location_ids.clone()),
+				folder_sync::right_location_id::in_vec(location_ids),
+			])
+			.exec()
+			.await?;
+		if existing.is_some() {
+			return Err(FolderSyncError::AlreadySynced(
+				self.left_location_id,
+				self.right_location_id,
+			));
+		}
+
+		Ok(library
+			.db
+			.folder_sync()
+			.create(
+				location::id::equals(self.left_location_id),
+				location::id::equals(self.right_location_id),
+				vec![],
+			)
+			.exec()
+			.await?)
+	}
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct ResolveConflictArgs {
+	pub id: i32,
+	/// none to take back a resolution before the locations are synced again
+	pub resolution: Option<ConflictResolution>,
+}
+
+/// resolve_conflict records which version of a conflicting file is kept the next time its locations
+/// are synced
+pub async fn resolve_conflict(
+	library: &LibraryContext,
+	args: ResolveConflictArgs,
+) -> Result<(), FolderSyncError> {
+	let updated = library
+		.db
+		.folder_sync_conflict()
+		.update_many(
+			vec![folder_sync_conflict::id::equals(args.id)],
+			vec![folder_sync_conflict::resolution::set(
+				args.resolution.map(|resolution| resolution.int_value()),
+			)],
+		)
+		.exec()
+		.await?;
+	if updated == 0 {
+		return Err(FolderSyncError::ConflictNotFound(args.id));
+	}
+
+	Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+	Left,
+	Right,
+}
+
+impl Side {
+	fn other(self) -> Self {
+		match self {
+			Self::Left => Self::Right,
+			Self::Right => Self::Left,
+		}
+	}
+
+	fn index(self) -> usize {
+		match self {
+			Self::Left => 0,
+			Self::Right => 1,
+		}
+	}
+}
+
+/// What a file needs for both sides to be in sync, from the content each side has of it and the
+/// one both had when they were last in sync
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+	/// both sides have the same content, which they didn't have when last in sync
+	Settle,
+	/// only the right side changed
+	ToLeft,
+	/// only the left side changed
+	ToRight,
+	Conflict,
+}
+
+/// diff tells what a file needs from its cas_id on each side and when last in sync, if anything,
+/// with none for a side that doesn't have the file
+fn diff(left: Option<&str>, right: Option<&str>, synced: Option<&str>) -> Option<Change> {
+	if left == right {
+		(left != synced).then_some(Change::Settle)
+	} else if left == synced {
+		Some(Change::ToLeft)
+	} else if right == synced {
+		Some(Change::ToRight)
+	} else {
+		Some(Change::Conflict)
+	}
+}
+
+/// FolderSyncJob syncs the two locations of a folder sync once, both ways, applying the
+/// resolutions the user picked for their conflicts. Both locations are swept for the changes made
+/// to them afterwards.
+pub struct FolderSyncJob;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FolderSyncJobInit {
+	pub folder_sync_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SyncSide {
+	location_id: i32,
+	path: PathBuf,
+	pub_id: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FolderSyncJobState {
+	/// the left side, then the right one
+	sides: [SyncSide; 2],
+	started: DateTime<Utc>,
+	copied: usize,
+	deleted: usize,
+	renamed: usize,
+	conflicts: usize,
+	skipped: usize,
+}
+
+impl FolderSyncJobState {
+	fn side(&self, side: Side) -> &SyncSide {
+		&self.sides[side.index()]
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum FolderSyncStep {
+	/// copies the file from the other side to this one, where the file must still have the content
+	/// `replaces`, or not be there if it's none
+	Copy {
+		to: Side,
+		path: String,
+		cas_id: String,
+		replaces: Option<String>,
+	},
+	/// moves the file of a side to the trash, if it still has the content `cas_id`
+	Delete {
+		from: Side,
+		file_path_id: i32,
+		path: String,
+		cas_id: String,
+	},
+	/// renames the file of a side with a conflict copy name, for both versions to be kept
+	KeepAside {
+		side: Side,
+		file_path_id: i32,
+		path: String,
+	},
+	/// records the content both sides have now, none if neither has the file anymore
+	Settle {
+		path: String,
+		cas_id: Option<String>,
+	},
+}
+
+#[derive(Deserialize, Debug)]
+struct IndexedFile {
+	#[serde(deserialize_with = "raw_int")]
+	id: i64,
+	path: String,
+	cas_id: Option<String>,
+	size_in_bytes: Option<String>,
+}
+
+impl IndexedFile {
+	fn size(&self) -> u64 {
+		self.size_in_bytes
+			.as_deref()
+			.and_then(|size| size.parse().ok())
+			.unwrap_or(0)
+	}
+}
+
+/// indexed_files returns the files of a location by their materialized path
+async fn indexed_files(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<HashMap<String, IndexedFile>, QueryError> {
+	let files: Vec<IndexedFile> = library
+		.db
+		._query_raw(Raw::new(
+			"SELECT fp.id AS id, fp.materialized_path AS path, o.cas_id AS cas_id, \
+			o.size_in_bytes AS size_in_bytes FROM file_path fp \
+			LEFT JOIN object o ON o.id = fp.object_id \
+			WHERE fp.location_id = {} AND fp.is_dir = 0 AND fp.raw_path IS NULL",
+			vec![PrismaValue::Int(location_id as i64)],
+		))
+		.exec()
+		.await?;
+
+	Ok(files
+		.into_iter()
+		.map(|file| (file.path.clone(), file))
+		.collect())
+}
+
+/// transfer returns the step giving the `to` side the version of the file the other side has, as
+/// `source`
+fn transfer(
+	to: Side,
+	path: &str,
+	source: Option<&IndexedFile>,
+	target: Option<&IndexedFile>,
+) -> Option<FolderSyncStep> {
+	match (source, target) {
+		(Some(source), _) => Some(FolderSyncStep::Copy {
+			to,
+			path: path.to_string(),
+			cas_id: source.cas_id.clone()?,
+			replaces: target.and_then(|target| target.cas_id.clone()),
+		}),
+		(None, Some(target)) => Some(FolderSyncStep::Delete {
+			from: to,
+			file_path_id: target.id as i32,
+			path: path.to_string(),
+			cas_id: target.cas_id.clone()?,
+		}),
+		(None, None) => None,
+	}
+}
+
+/// has_content tells if the file at `path` has the content `cas_id`, or isn't there if it's none
+async fn has_content(path: &Path, cas_id: Option<&str>) -> Result<bool, io::Error> {
+	let metadata = match fs::metadata(path).await {
+		Ok(metadata) => Some(metadata),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+		Err(e) => return Err(e),
+	};
+
+	Ok(match (metadata, cas_id) {
+		(None, None) => true,
+		(Some(metadata), Some(cas_id)) if metadata.is_file() => {
+			// objects only keep the start of the cas_id
+			let mut generated = generate_cas_id(path.to_path_buf(), metadata.len()).await?;
+			generated.truncate(16);
+			generated == cas_id
+		}
+		_ => false,
+	})
+}
+
+/// copy_file copies `source` to `target` if they both still have the content the index says they
+/// have, returning false if either of them changed since they were indexed. The copy is written
+/// next to the target and only renamed over it once its content is checked.
+async fn copy_file(
+	source: &Path,
+	target: &Path,
+	cas_id: &str,
+	replaces: Option<&str>,
+) -> Result<bool, io::Error> {
+	if !has_content(target, replaces).await? {
+		return Ok(false);
+	}
+
+	let size = fs::metadata(source).await?.len();
+	if let Some(parent) = target.parent() {
+		fs::create_dir_all(parent).await?;
+	}
+	let partial = target.with_file_name(format!(
+		".{}.sdsync",
+		target.file_name().unwrap_or_default().to_string_lossy()
+	));
+
+	let (mut copied_cas_id, _) = block_in_place(|| {
+		let reader = std::fs::File::open(source)?;
+		copy_and_hash(reader, &partial, size)
+	})?;
+	copied_cas_id.truncate(16);
+	if copied_cas_id != cas_id {
+		fs::remove_file(&partial).await?;
+		return Ok(false);
+	}
+
+	fs::rename(&partial, target).await?;
+
+	Ok(true)
+}
+
+/// set_synced records the content both sides have of a file, or that neither has it when it's none
+async fn set_synced(
+	library: &LibraryContext,
+	folder_sync_id: i32,
+	path: &str,
+	cas_id: Option<&str>,
+) -> Result<(), QueryError> {
+	match cas_id {
+		Some(cas_id) => {
+			library
+				.db
+				.folder_sync_entry()
+				.upsert(
+					folder_sync_entry::folder_sync_id_path(folder_sync_id, path.to_string()),
+					(
+						folder_sync::id::equals(folder_sync_id),
+						path.to_string(),
+						cas_id.to_string(),
+						vec![],
+					),
+					vec![folder_sync_entry::cas_id::set(cas_id.to_string())],
+				)
+				.exec()
+				.await?;
+		}
+		None => {
+			library
+				.db
+				.folder_sync_entry()
+				.delete_many(vec![
+					folder_sync_entry::folder_sync_id::equals(folder_sync_id),
+					folder_sync_entry::path::equals(path.to_string()),
+				])
+				.exec()
+				.await?;
+		}
+	}
+
+	Ok(())
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FolderSyncJob {
+	type Init = FolderSyncJobInit;
+	type Data = FolderSyncJobState;
+	type Step = FolderSyncStep;
+
+	fn name(&self) -> &'static str {
+		FOLDER_SYNC_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let folder_sync_id = state.init.folder_sync_id;
+
+		let folder_sync = library
+			.db
+			.folder_sync()
+			.find_unique(folder_sync::id::equals(folder_sync_id))
+			.exec()
+			.await?
+			.ok_or(FolderSyncError::NotFound(folder_sync_id))?;
+
+		let mut sides = vec![];
+		for location_id in [folder_sync.left_location_id, folder_sync.right_location_id] {
+			let (path, pub_id) = local_location(&library, location_id).await?;
+			sides.push(SyncSide {
+				location_id,
+				path,
+				pub_id,
+			});
+		}
+		let right = sides.pop().expect("both sides were just added");
+		let left = sides.pop().expect("both sides were just added");
+
+		let left_files = indexed_files(&library, left.location_id).await?;
+		let right_files = indexed_files(&library, right.location_id).await?;
+		let entries = library
+			.db
+			.folder_sync_entry()
+			.find_many(vec![folder_sync_entry::folder_sync_id::equals(
+				folder_sync_id,
+			)])
+			.exec()
+			.await?;
+		let synced = entries
+			.iter()
+			.map(|entry| (entry.path.as_str(), entry.cas_id.as_str()))
+			.collect::<HashMap<_, _>>();
+		let conflicts = library
+			.db
+			.folder_sync_conflict()
+			.find_many(vec![folder_sync_conflict::folder_sync_id::equals(
+				folder_sync_id,
+			)])
+			.exec()
+			.await?;
+		let conflicts = conflicts
+			.iter()
+			.map(|conflict| (conflict.path.as_str(), conflict))
+			.collect::<HashMap<_, _>>();
+
+		let paths = left_files
+			.keys()
+			.chain(right_files.keys())
+			.map(String::as_str)
+			.chain(synced.keys().copied())
+			.collect::<BTreeSet<_>>();
+
+		let mut data = FolderSyncJobState {
+			sides: [left, right],
+			started: Utc::now(),
+			copied: 0,
+			deleted: 0,
+			renamed: 0,
+			conflicts: 0,
+			skipped: 0,
+		};
+		// conflicts still standing, which aren't deleted
+		let mut conflicted = vec![];
+		// bytes copied to each side, to check they fit
+		let mut required = [0u64; 2];
+
+		for path in paths {
+			let (left, right) = (left_files.get(path), right_files.get(path));
+			let conflict = conflicts.get(path);
+
+			// files that aren't identified yet are synced once they are
+			if [left, right]
+				.iter()
+				.any(|file| file.map_or(false, |file| file.cas_id.is_none()))
+			{
+				data.skipped += 1;
+				if conflict.is_some() {
+					conflicted.push(path.to_string());
+				}
+				continue;
+			}
+
+			let left_cas_id = left.and_then(|file| file.cas_id.as_deref());
+			let right_cas_id = right.and_then(|file| file.cas_id.as_deref());
+			// a resolution only holds for the versions the user saw
+			let conflict_stands = conflict.map_or(false, |conflict| {
+				conflict.left_cas_id.as_deref() == left_cas_id
+					&& conflict.right_cas_id.as_deref() == right_cas_id
+			});
+			let resolution = conflict
+				.filter(|_| conflict_stands)
+				.and_then(|conflict| conflict.resolution)
+				.and_then(|resolution| ConflictResolution::from_int(resolution).ok());
+
+			let change = match resolution {
+				Some(ConflictResolution::KeepLeft) => Some(Change::ToRight),
+				Some(ConflictResolution::KeepRight) => Some(Change::ToLeft),
+				Some(ConflictResolution::KeepBoth) => match (left, right) {
+					(Some(left), Some(right)) => {
+						state.steps.push_back(FolderSyncStep::KeepAside {
+							side: Side::Right,
+							file_path_id: right.id as i32,
+							path: path.to_string(),
+						});
+						required[Side::Right.index()] += left.size();
+						state.steps.push_back(FolderSyncStep::Copy {
+							to: Side::Right,
+							path: path.to_string(),
+							cas_id: left_cas_id.unwrap_or_default().to_string(),
+							replaces: None,
+						});
+						None
+					}
+					(Some(_), None) => Some(Change::ToRight),
+					_ => Some(Change::ToLeft),
+				},
+				None => diff(left_cas_id, right_cas_id, synced.get(path).copied()),
+			};
+
+			let step = match change {
+				None => None,
+				Some(Change::Settle) => Some(FolderSyncStep::Settle {
+					path: path.to_string(),
+					cas_id: left_cas_id.map(str::to_string),
+				}),
+				Some(Change::ToLeft) => {
+					required[Side::Left.index()] += right.map_or(0, IndexedFile::size);
+					transfer(Side::Left, path, right, left)
+				}
+				Some(Change::ToRight) => {
+					required[Side::Right.index()] += left.map_or(0, IndexedFile::size);
+					transfer(Side::Right, path, left, right)
+				}
+				Some(Change::Conflict) => {
+					data.conflicts += 1;
+					conflicted.push(path.to_string());
+					// conflicts the user was already told about are kept as they are
+					if !conflict_stands {
+						let params = vec![
+							folder_sync_conflict::left_cas_id::set(left_cas_id.map(str::to_string)),
+							folder_sync_conflict::right_cas_id::set(
+								right_cas_id.map(str::to_string),
+							),
+						];
+						library
+							.db
+							.folder_sync_conflict()
+							.upsert(
+								folder_sync_conflict::folder_sync_id_path(
+									folder_sync_id,
+									path.to_string(),
+								),
+								(
+									folder_sync::id::equals(folder_sync_id),
+									path.to_string(),
+									params.clone(),
+								),
+								[
+									params,
+									vec![
+										folder_sync_conflict::resolution::set(None),
+										folder_sync_conflict::date_detected::set(Utc::now().into()),
+									],
+								]
+								.concat(),
+							)
+							.exec()
+							.await?;
+					}
+					None
+				}
+			};
+			state.steps.extend(step);
+		}
+
+		// conflicts that were resolved, by the user or on disk, are gone
+		library
+			.db
+			.folder_sync_conflict()
+			.delete_many(vec![
+				folder_sync_conflict::folder_sync_id::equals(folder_sync_id),
+				folder_sync_conflict::path::not_in_vec(conflicted),
+			])
+			.exec()
+			.await?;
+
+		for side in [Side::Left, Side::Right] {
+			if required[side.index()] > 0 {
+				ensure_fits(&library, &data.side(side).path, required[side.index()])?;
+			}
+		}
+
+		state.data = Some(data);
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+		let library = ctx.library_ctx();
+		let folder_sync_id = state.init.folder_sync_id;
+
+		match step {
+			FolderSyncStep::Copy {
+				to,
+				path,
+				cas_id,
+				replaces,
+			} => {
+				ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+					path: path.clone(),
+				})]);
+
+				let source = data.side(to.other()).path.join(path);
+				let target = data.side(*to).path.join(path);
+				match copy_file(&source, &target, cas_id, replaces.as_deref()).await {
+					Ok(true) => {
+						set_synced(&library, folder_sync_id, path, Some(cas_id)).await?;
+						data.copied += 1;
+					}
+					Ok(false) => {
+						warn!("Not syncing '{path}', it changed since it was indexed");
+						data.skipped += 1;
+					}
+					Err(e) => {
+						ctx.record_file_error(FileError {
+							location_id: Some(data.side(*to).location_id),
+							file_path_id: None,
+							path: path.clone(),
+							message: (&e).into(),
+						})
+						.await;
+						data.skipped += 1;
+					}
+				}
+			}
+			FolderSyncStep::Delete {
+				from,
+				file_path_id,
+				path,
+				cas_id,
+			} => {
+				let (location_id, path_on_disk) = {
+					let side = data.side(*from);
+					(side.location_id, side.path.join(path))
+				};
+				// true once deleted, false if skipped
+				let outcome = match has_content(&path_on_disk, Some(cas_id)).await {
+					Ok(true) => match move_to_trash(&library, location_id, *file_path_id).await {
+						Ok(_) => {
+							set_synced(&library, folder_sync_id, path, None).await?;
+							Ok(true)
+						}
+						Err(TrashError::FilePathNotFound(..)) => Ok(false),
+						Err(TrashError::IOError(e)) => Err(Message::from(&e)),
+						Err(
+							e @ (TrashError::InUse(..)
+							| TrashError::ReadOnlyCatalog(_)
+							| TrashError::MissingLocalPath(_)),
+						) => Err(Message::Error {
+							text: e.to_string(),
+						}),
+						Err(e) => return Err(e.into()),
+					},
+					Ok(false) => {
+						warn!("Not deleting '{path}', it changed since it was indexed");
+						Ok(false)
+					}
+					Err(e) => Err(Message::from(&e)),
+				};
+
+				match outcome {
+					Ok(true) => data.deleted += 1,
+					Ok(false) => data.skipped += 1,
+					Err(message) => {
+						ctx.record_file_error(FileError {
+							location_id: Some(location_id),
+							file_path_id: Some(*file_path_id),
+							path: path.clone(),
+							message,
+						})
+						.await;
+						data.skipped += 1;
+					}
+				}
+			}
+			FolderSyncStep::KeepAside {
+				side,
+				file_path_id,
+				path,
+			} => {
+				let SyncSide {
+					location_id,
+					path: location_path,
+					pub_id,
+				} = data.side(*side);
+				let (location_id, location_path, pub_id) =
+					(*location_id, location_path.clone(), pub_id.clone());
+				let node_config = library.config().get().await;
+				let aside = node_config
+					.conflict_naming
+					.free_path(&location_path.join(path), &node_config.name);
+				let aside = aside
+					.strip_prefix(&location_path)
+					.unwrap_or(&aside)
+					.to_string_lossy()
+					.to_string();
+
+				match move_file_path(
+					&library,
+					location_id,
+					&location_path,
+					&pub_id,
+					*file_path_id,
+					path,
+					&aside,
+				)
+				.await
+				{
+					Ok(_) => data.renamed += 1,
+					// the copy over it that follows is skipped, as the file is still there
+					Err(OrganizeError::IO(e)) => {
+						ctx.record_file_error(FileError {
+							location_id: Some(location_id),
+							file_path_id: Some(*file_path_id),
+							path: path.clone(),
+							message: (&e).into(),
+						})
+						.await;
+						data.skipped += 1;
+					}
+					Err(e) => return Err(e.into()),
+				}
+			}
+			FolderSyncStep::Settle { path, cas_id } => {
+				set_synced(&library, folder_sync_id, path, cas_id.as_deref()).await?;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		library
+			.db
+			.folder_sync()
+			.update(
+				folder_sync::id::equals(state.init.folder_sync_id),
+				vec![folder_sync::last_synced::set(Some(Utc::now().into()))],
+			)
+			.exec()
+			.await?;
+
+		// the index catches up with what was copied, deleted and renamed
+		if data.copied + data.deleted + data.renamed > 0 {
+			for side in &data.sides {
+				let location = library
+					.db
+					.location()
+					.find_unique(location::id::equals(side.location_id))
+					.include(indexer_job_location::include())
+					.exec()
+					.await?;
+				if let Some(location) = location {
+					if let Err(e) = sweep_location(&library, location, data.started).await {
+						warn!("Failed to sweep location {}: {e}", side.location_id);
+					}
+				}
+				library.statistics.location_changed(side.location_id);
+			}
+			invalidate_query!(library, "locations.getExplorerData");
+		}
+
+		invalidate_query!(library, "folderSync.list");
+		invalidate_query!(library, "folderSync.getConflicts");
+
+		info!(
+			"Synced locations {} and {}: {} copied, {} deleted, {} renamed, {} conflicts, {} skipped",
+			data.sides[0].location_id,
+			data.sides[1].location_id,
+			data.copied,
+			data.deleted,
+			data.renamed,
+			data.conflicts,
+			data.skipped
+		);
+
+		Ok(Some(json!({
+			"copied": data.copied,
+			"deleted": data.deleted,
+			"renamed": data.renamed,
+			"conflicts": data.conflicts,
+			"skipped": data.skipped,
+		})))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn diff_tells_which_side_changed() {
+		assert_eq!(diff(Some("a"), Some("a"), Some("a")), None);
+		assert_eq!(diff(Some("b"), Some("b"), Some("a")), Some(Change::Settle));
+		assert_eq!(diff(None, None, Some("a")), Some(Change::Settle));
+		// changed, created or deleted on one side
+		assert_eq!(diff(Some("a"), Some("b"), Some("a")), Some(Change::ToLeft));
+		assert_eq!(diff(Some("b"), Some("a"), Some("a")), Some(Change::ToRight));
+		assert_eq!(diff(None, Some("b"), None), Some(Change::ToLeft));
+		assert_eq!(diff(Some("a"), None, Some("a")), Some(Change::ToLeft));
+		// changed on both sides, or created on both with different content
+		assert_eq!(
+			diff(Some("b"), Some("c"), Some("a")),
+			Some(Change::Conflict)
+		);
+		assert_eq!(diff(None, Some("c"), Some("a")), Some(Change::Conflict));
+		assert_eq!(diff(Some("b"), Some("c"), None), Some(Change::Conflict));
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn copies_and_deletes_identified_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let source = dir.path().join("left").join("notes.txt");
+		let target = dir.path().join("right").join("notes.txt");
+		std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+		std::fs::write(&source, "hello").unwrap();
+
+		// as the identifier stores it
+		let mut cas_id = generate_cas_id(source.clone(), 5).await.unwrap();
+		cas_id.truncate(16);
+
+		assert!(copy_file(&source, &target, &cas_id, None).await.unwrap());
+		assert_eq!(std::fs::read(&target).unwrap(), b"hello");
+		// what a delete checks before trashing the file
+		assert!(has_content(&target, Some(&cas_id)).await.unwrap());
+
+		// changed since it was indexed
+		std::fs::write(&source, "changed").unwrap();
+		assert!(!has_content(&source, Some(&cas_id)).await.unwrap());
+		assert!(!copy_file(&source, &target, &cas_id, Some(&cas_id))
+			.await
+			.unwrap());
+		assert_eq!(std::fs::read(&target).unwrap(), b"hello");
+	}
+}
@@ -0,0 +1,522 @@
+use std::{
+	collections::VecDeque,
+	io,
+	path::{Path, PathBuf},
+};
+
+use chrono::{Duration, Utc};
+use fs_extra::dir::{get_size, move_dir, CopyOptions};
+use prisma_client_rust::Direction;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{fs, task::block_in_place};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	job::{Job, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::{
+		fetch_location, indexer::indexer_job::indexer_job_location, rescan_path, LocationError,
+	},
+	prisma::{file_path, location, trash_item},
+	util::{
+		file_lock::{find_lock_holder, FileLockHolder},
+		os_path::{lossy_name, resolve_materialized_path},
+		platform_path::normalize_name,
+	},
+};
+
+use super::{
+	delete_file_path_tree,
+	organize::{ensure_dir, next_file_path_id, sync_created},
+	InUseTracker,
+};
+
+pub const TRASH_DIR_NAME: &str = "trash";
+pub const TRASH_CLEANER_JOB_NAME: &str = "trash_cleaner";
+
+/// clean_trash has the trash brought back under its size cap and retention, unless a cleaner is
+/// already queued or running
+pub async fn clean_trash(library: &LibraryContext) {
+	if library.has_job(TRASH_CLEANER_JOB_NAME).await {
+		return;
+	}
+
+	library
+		.spawn_job(Job::new(
+			TrashCleanerJobInit { empty_all: false },
+			Box::new(TrashCleanerJob {}),
+		))
+		.await;
+}
+
+/// TrashRetention controls when items in a library's trash are permanently deleted
+#[derive(Debug, Serialize, Deserialize, Clone, Type, Default)]
+pub struct TrashRetention {
+	/// delete items that have been in the trash for longer than this many days
+	pub max_age_days: Option<u32>,
+	/// delete the oldest items once the trash is larger than this many bytes
+	pub max_size: Option<u64>,
+}
+
+#[derive(Error, Debug)]
+pub enum TrashError {
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("file path not found: <location_id={0}, file_path_id={1}>")]
+	FilePathNotFound(i32, i32),
+	#[error("location has no local path: <id='{0}'>")]
+	MissingLocalPath(i32),
+	#[error("location is a read-only catalog: <id='{0}'>")]
+	ReadOnlyCatalog(i32),
+	#[error("trash item not found: <id='{0}'>")]
+	ItemNotFound(i32),
+	#[error("can't restore as '{}' already exists", .0.display())]
+	RestoreConflict(PathBuf),
+	#[error("'{}' is {1}", .0.display())]
+	InUse(PathBuf, FileLockHolder),
+	#[error("Location error: {0}")]
+	Location(#[from] LocationError),
+}
+
+impl From<TrashError> for rspc::Error {
+	fn from(err: TrashError) -> Self {
+		match err {
+			TrashError::FilePathNotFound(..) | TrashError::ItemNotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			TrashError::RestoreConflict(_) | TrashError::InUse(..) => {
+				rspc::Error::with_cause(rspc::ErrorCode::Conflict, err.to_string(), err)
+			}
+			TrashError::ReadOnlyCatalog(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			TrashError::Location(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// trash_dir is where the library keeps trashed items, each stored under its `pub_id`
+pub fn trash_dir(library: &LibraryContext) -> PathBuf {
+	library
+		.config()
+		.data_directory()
+		.join(TRASH_DIR_NAME)
+		.join(library.id.to_string())
+}
+
+fn item_path(library: &LibraryContext, pub_id: &[u8]) -> PathBuf {
+	trash_dir(library).join(
+		Uuid::from_slice(pub_id)
+			.map(|id| id.to_string())
+			.unwrap_or_else(|_| hex_name(pub_id)),
+	)
+}
+
+fn hex_name(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// move_to_trash moves a file path into the library's trash, recording where it came from so it can be restored
+pub async fn move_to_trash(
+	library: &LibraryContext,
+	location_id: i32,
+	file_path_id: i32,
+) -> Result<trash_item::Data, TrashError> {
+	let location = library
+		.db
+		.location()
+		.find_unique(location::id::equals(location_id))
+		.exec()
+		.await?
+		.ok_or(TrashError::FilePathNotFound(location_id, file_path_id))?;
+	if location.is_catalog {
+		return Err(TrashError::ReadOnlyCatalog(location_id));
+	}
+
+	let location_path = location
+		.local_path
+		.map(PathBuf::from)
+		.ok_or(TrashError::MissingLocalPath(location_id))?;
+
+	let file_path = library
+		.db
+		.file_path()
+		.find_unique(file_path::location_id_id(location_id, file_path_id))
+		.exec()
+		.await?
+		.ok_or(TrashError::FilePathNotFound(location_id, file_path_id))?;
+
+	let original_path = resolve_materialized_path(
+		location_path,
+		&file_path.materialized_path,
+		file_path.raw_path.as_deref(),
+	);
+	if let Some(holder) = find_lock_holder(&original_path).await {
+		return Err(TrashError::InUse(original_path, holder));
+	}
+
+	let is_dir = fs::metadata(&original_path).await?.is_dir();
+	let size = if is_dir {
+		block_in_place(|| get_size(&original_path)).unwrap_or(0)
+	} else {
+		fs::metadata(&original_path).await?.len()
+	};
+
+	let pub_id = Uuid::new_v4().as_bytes().to_vec();
+	fs::create_dir_all(trash_dir(library)).await?;
+	move_path(&original_path, &item_path(library, &pub_id)).await?;
+
+	let name = original_path
+		.file_name()
+		.map(|name| name.to_string_lossy().to_string())
+		.unwrap_or_else(|| file_path.name.clone());
+
+	let item = library
+		.db
+		.trash_item()
+		.create_unchecked(
+			pub_id,
+			file_path.materialized_path.clone(),
+			original_path.to_string_lossy().to_string(),
+			name,
+			size.to_string(),
+			library.node_local_id,
+			vec![
+				trash_item::location_id::set(Some(location_id)),
+				trash_item::is_dir::set(is_dir),
+				trash_item::object_id::set(file_path.object_id),
+			],
+		)
+		.exec()
+		.await?;
+
+	delete_file_path_tree(library, location_id, &file_path.materialized_path).await?;
+
+	invalidate_query!(library, "locations.getExplorerData");
+	invalidate_query!(library, "files.getTrash");
+
+	Ok(item)
+}
+
+/// restore_from_trash moves a trashed item back to where it was deleted from
+pub async fn restore_from_trash(
+	library: &LibraryContext,
+	trash_item_id: i32,
+) -> Result<PathBuf, TrashError> {
+	let item = library
+		.db
+		.trash_item()
+		.find_unique(trash_item::id::equals(trash_item_id))
+		.exec()
+		.await?
+		.ok_or(TrashError::ItemNotFound(trash_item_id))?;
+
+	let original_path = PathBuf::from(&item.original_path);
+	if original_path.exists() {
+		return Err(TrashError::RestoreConflict(original_path));
+	}
+
+	if let Some(parent) = original_path.parent() {
+		fs::create_dir_all(parent).await?;
+	}
+	let trashed_path = item_path(library, &item.pub_id);
+	move_path(&trashed_path, &original_path).await?;
+
+	let restored = async {
+		// the item keeps its object referenced until it's indexed again, or it could be collected
+		reindex_restored(library, &item, &original_path).await?;
+
+		library
+			.db
+			.trash_item()
+			.delete(trash_item::id::equals(item.id))
+			.exec()
+			.await?;
+
+		Ok::<_, TrashError>(())
+	}
+	.await;
+
+	// the item stays in the trash, to be restored or purged again
+	if let Err(e) = restored {
+		if let Err(e) = move_path(&original_path, &trashed_path).await {
+			error!(
+				"Failed to move '{}' back to the trash: {e:#?}",
+				original_path.display()
+			);
+		}
+		return Err(e);
+	}
+
+	invalidate_query!(library, "locations.getExplorerData");
+	invalidate_query!(library, "files.getTrash");
+
+	Ok(original_path)
+}
+
+/// reindex_restored puts a restored item back in its location's index, linked to the object it had
+/// so it keeps its tags and notes, and has the contents of a restored directory indexed again
+async fn reindex_restored(
+	library: &LibraryContext,
+	item: &trash_item::Data,
+	original_path: &Path,
+) -> Result<(), TrashError> {
+	let location = match item.location_id {
+		Some(location_id) => {
+			fetch_location(library, location_id)
+				.include(indexer_job_location::include())
+				.exec()
+				.await?
+		}
+		None => None,
+	};
+	// the location was removed or moved elsewhere since, its next scan finds the item if it's in it
+	let location = match location {
+		Some(location)
+			if location
+				.local_path
+				.as_ref()
+				.map_or(false, |local_path| original_path.starts_with(local_path)) =>
+		{
+			location
+		}
+		_ => return Ok(()),
+	};
+
+	let materialized_path = Path::new(&item.materialized_path);
+	let parent_id = ensure_dir(
+		library,
+		location.id,
+		&location.pub_id,
+		materialized_path.parent().unwrap_or_else(|| Path::new("")),
+	)
+	.await?;
+
+	let (name, extension) = if item.is_dir {
+		(lossy_name(original_path.file_name()), String::new())
+	} else {
+		(
+			lossy_name(original_path.file_stem()),
+			lossy_name(original_path.extension()),
+		)
+	};
+
+	let id = next_file_path_id(library, location.id).await?;
+	library
+		.db
+		.file_path()
+		.create_many(vec![file_path::create_unchecked(
+			id,
+			location.id,
+			item.materialized_path.clone(),
+			normalize_name(&name).into_owned(),
+			vec![
+				file_path::is_dir::set(item.is_dir),
+				file_path::extension::set(Some(normalize_name(&extension).into_owned())),
+				file_path::parent_id::set(parent_id),
+				file_path::object_id::set(item.object_id),
+			],
+		)])
+		.exec()
+		.await?;
+
+	if let Err(e) = sync_created(library, location.id, &location.pub_id, id).await {
+		warn!("Error logging restored file path for sync: {:#?}", e);
+	}
+
+	if item.is_dir {
+		rescan_path(library, location, &item.materialized_path).await?;
+	}
+
+	Ok(())
+}
+
+/// move_path renames `from` to `to`, falling back to copying when they're on different filesystems
+async fn move_path(from: &Path, to: &Path) -> Result<(), io::Error> {
+	if fs::rename(from, to).await.is_ok() {
+		return Ok(());
+	}
+
+	if fs::metadata(from).await?.is_dir() {
+		block_in_place(|| {
+			let mut options = CopyOptions::new();
+			options.copy_inside = true;
+			move_dir(from, to, &options)
+		})
+		.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+	} else {
+		fs::copy(from, to).await?;
+		fs::remove_file(from).await?;
+	}
+
+	Ok(())
+}
+
+/// TrashCleanerJob permanently deletes trashed items, either all of them or the ones that are past the
+/// library's [`TrashRetention`] settings.
+pub struct TrashCleanerJob;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrashCleanerJobInit {
+	/// delete every item, instead of only the ones past the retention settings
+	pub empty_all: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TrashCleanerJobState {
+	in_use: InUseTracker,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashCleanerJobStep {
+	trash_item_id: i32,
+	path: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for TrashCleanerJob {
+	type Init = TrashCleanerJobInit;
+	type Data = TrashCleanerJobState;
+	type Step = TrashCleanerJobStep;
+
+	fn name(&self) -> &'static str {
+		TRASH_CLEANER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let retention = &library.config.trash_retention;
+
+		// newest first, so the size cap keeps the most recently trashed items
+		let items = library
+			.db
+			.trash_item()
+			.find_many(vec![])
+			.order_by(trash_item::date_deleted::order(Direction::Desc))
+			.exec()
+			.await?;
+
+		let now = Utc::now();
+		let mut kept_size = 0;
+
+		state.steps = items
+			.into_iter()
+			.filter(|item| {
+				if state.init.empty_all {
+					return true;
+				}
+
+				if let Some(max_age_days) = retention.max_age_days {
+					let age = now.signed_duration_since(item.date_deleted.with_timezone(&Utc));
+					if age > Duration::days(max_age_days as i64) {
+						return true;
+					}
+				}
+
+				let size = item.size_in_bytes.parse::<u64>().unwrap_or(0);
+				if let Some(max_size) = retention.max_size {
+					if kept_size + size > max_size {
+						return true;
+					}
+				}
+
+				kept_size += size;
+				false
+			})
+			.map(|item| TrashCleanerJobStep {
+				path: item_path(&library, &item.pub_id),
+				trash_item_id: item.id,
+			})
+			.collect::<VecDeque<_>>();
+
+		info!("Found {} items to remove from trash", state.steps.len());
+
+		state.data = Some(TrashCleanerJobState::default());
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		// items that are open in another application are retried later, or kept until the next clean up
+		let path = state.steps[0].path.clone();
+		if data
+			.in_use
+			.check(&ctx, &path, &mut state.steps, state.step_number)
+			.await
+		{
+			return Ok(());
+		}
+
+		let step = &state.steps[0];
+
+		let result = if fs::metadata(&step.path)
+			.await
+			.map(|metadata| metadata.is_dir())
+			.unwrap_or(false)
+		{
+			fs::remove_dir_all(&step.path).await
+		} else {
+			fs::remove_file(&step.path).await
+		};
+
+		// if the item is already gone from disk, we still want to drop it from the database
+		if let Err(e) = result {
+			if e.kind() != io::ErrorKind::NotFound {
+				return Err(e.into());
+			}
+			warn!("Trashed item {} was already removed", step.path.display());
+		}
+
+		ctx.library_ctx()
+			.db
+			.trash_item()
+			.delete(trash_item::id::equals(step.trash_item_id))
+			.exec()
+			.await?;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		invalidate_query!(library, "files.getTrash");
+
+		let mut metadata = serde_json::to_value(&state.init)?;
+		if let Some(data) = &state.data {
+			data.in_use.add_to_metadata(&mut metadata);
+		}
+
+		Ok(Some(metadata))
+	}
+}
@@ -0,0 +1,270 @@
+//! Lets clients without access to a node's filesystem, like browsers, add files to its locations. Files
+//! are sent in chunks that are appended to a `.sdupload` file next to where the file is going, so an
+//! upload that's cut off carries on from the last chunk that made it. Once every chunk is in, the file
+//! is checked against the blake3 checksum the client sent and indexed right away.
+
+use std::{
+	io,
+	path::{Component, Path, PathBuf},
+};
+
+use chrono::{Duration, Utc};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+	fs::{self, OpenOptions},
+	io::AsyncWriteExt,
+};
+
+use crate::{
+	library::LibraryContext,
+	location::{
+		fetch_location, indexer::indexer_job::indexer_job_location, sweep_location, LocationError,
+	},
+	object::validation::hash::file_checksum,
+	util::os_path::resolve_materialized_path,
+	volume::{ensure_space, InsufficientSpace},
+};
+
+/// The largest chunk accepted at once, clients should send chunks of this size
+pub const MAX_UPLOAD_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const UPLOAD_EXTENSION: &str = "sdupload";
+
+#[derive(Error, Debug)]
+pub enum UploadError {
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("{0}")]
+	LocationError(#[from] LocationError),
+	#[error("{0}")]
+	InsufficientSpace(#[from] InsufficientSpace),
+	#[error("Invalid chunk data: {0}")]
+	InvalidChunk(#[from] base64::DecodeError),
+	#[error("Invalid upload path '{0}'")]
+	InvalidPath(String),
+	#[error("Chunks can't be larger than {MAX_UPLOAD_CHUNK_SIZE} bytes")]
+	ChunkTooLarge,
+	#[error("'{}' already exists", .0.display())]
+	AlreadyExists(PathBuf),
+	#[error("The upload is {received} bytes in, a chunk at {offset} doesn't follow on")]
+	UnexpectedOffset { received: u64, offset: u64 },
+	#[error("The chunk goes past the end of the {size} bytes file")]
+	PastEnd { size: u64 },
+	#[error("The uploaded file is {received} bytes, {size} bytes were expected")]
+	Incomplete { received: u64, size: u64 },
+	#[error("The uploaded file doesn't match its checksum")]
+	ChecksumMismatch,
+}
+
+impl From<UploadError> for rspc::Error {
+	fn from(err: UploadError) -> Self {
+		match err {
+			UploadError::InvalidChunk(_)
+			| UploadError::InvalidPath(_)
+			| UploadError::ChunkTooLarge
+			| UploadError::PastEnd { .. }
+			| UploadError::Incomplete { .. }
+			| UploadError::ChecksumMismatch => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			UploadError::AlreadyExists(_)
+			| UploadError::UnexpectedOffset { .. }
+			| UploadError::InsufficientSpace(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::Conflict, err.to_string(), err)
+			}
+			UploadError::LocationError(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// Where an uploaded file goes
+#[derive(Debug, Clone, Deserialize, Type)]
+pub struct UploadTarget {
+	pub location_id: i32,
+	/// materialized path of the directory in the location, its root if empty
+	pub path: String,
+	pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Type)]
+pub struct UploadChunkArgs {
+	#[serde(flatten)]
+	pub target: UploadTarget,
+	/// the size of the whole file
+	pub size: u64,
+	/// where the chunk starts in the file, which has to be how much was received so far
+	pub offset: u64,
+	/// base64 encoded contents of the chunk
+	pub data: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Type)]
+pub struct FinishUploadArgs {
+	#[serde(flatten)]
+	pub target: UploadTarget,
+	pub size: u64,
+	/// blake3 checksum of the whole file, in hex
+	pub checksum: String,
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct UploadStatus {
+	/// how much of the file the node has, the offset of the next chunk
+	pub received: u64,
+	pub max_chunk_size: usize,
+}
+
+/// target_path works out where an upload goes on disk, making sure it stays in the location
+async fn target_path(
+	library: &LibraryContext,
+	target: &UploadTarget,
+) -> Result<(indexer_job_location::Data, PathBuf), UploadError> {
+	let location = fetch_location(library, target.location_id)
+		.include(indexer_job_location::include())
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(target.location_id))?;
+	if location.is_catalog {
+		return Err(LocationError::ReadOnlyCatalog(location.id).into());
+	}
+
+	let location_path = location
+		.local_path
+		.clone()
+		.ok_or(LocationError::MissingLocalPath(location.id))?;
+
+	// both come from the client, so they can't point outside of the location
+	let dir_is_valid = Path::new(&target.path)
+		.components()
+		.all(|component| matches!(component, Component::Normal(_)));
+	let name_is_valid = Path::new(&target.name)
+		.file_name()
+		.and_then(|name| name.to_str())
+		== Some(target.name.as_str());
+	if !dir_is_valid || !name_is_valid {
+		return Err(UploadError::InvalidPath(format!(
+			"{}/{}",
+			target.path, target.name
+		)));
+	}
+
+	let path = resolve_materialized_path(location_path, &target.path, None).join(&target.name);
+	Ok((location, path))
+}
+
+fn upload_path(target: &Path) -> PathBuf {
+	let mut upload = target.as_os_str().to_owned();
+	upload.push(".");
+	upload.push(UPLOAD_EXTENSION);
+	PathBuf::from(upload)
+}
+
+/// received returns how much of a file was uploaded so far
+async fn received(upload: &Path) -> Result<u64, io::Error> {
+	match fs::metadata(upload).await {
+		Ok(metadata) => Ok(metadata.len()),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+		Err(e) => Err(e),
+	}
+}
+
+/// upload_status returns how much of a file was uploaded so far, so a client can resume sending it
+pub async fn upload_status(
+	library: &LibraryContext,
+	target: &UploadTarget,
+) -> Result<UploadStatus, UploadError> {
+	let (_, path) = target_path(library, target).await?;
+
+	Ok(UploadStatus {
+		received: received(&upload_path(&path)).await?,
+		max_chunk_size: MAX_UPLOAD_CHUNK_SIZE,
+	})
+}
+
+/// write_chunk appends a chunk to an upload. Chunks have to arrive in order, one that doesn't follow
+/// on from what was received is turned down and the client can pick up from the status.
+pub async fn write_chunk(
+	library: &LibraryContext,
+	args: UploadChunkArgs,
+) -> Result<UploadStatus, UploadError> {
+	let data = base64::decode(&args.data)?;
+	if data.len() > MAX_UPLOAD_CHUNK_SIZE {
+		return Err(UploadError::ChunkTooLarge);
+	}
+
+	let (_, path) = target_path(library, &args.target).await?;
+	if fs::metadata(&path).await.is_ok() {
+		return Err(UploadError::AlreadyExists(path));
+	}
+
+	let upload = upload_path(&path);
+	let received = received(&upload).await?;
+	if args.offset != received {
+		return Err(UploadError::UnexpectedOffset {
+			received,
+			offset: args.offset,
+		});
+	}
+	if received + data.len() as u64 > args.size {
+		return Err(UploadError::PastEnd { size: args.size });
+	}
+
+	// checked on the first chunk, so an upload that can't fit fails before anything is written
+	if received == 0 {
+		fs::create_dir_all(path.parent().unwrap_or(&path)).await?;
+		ensure_space(&path, args.size)?;
+	}
+
+	let mut writer = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(&upload)
+		.await?;
+	writer.write_all(&data).await?;
+	writer.sync_all().await?;
+
+	Ok(UploadStatus {
+		received: received + data.len() as u64,
+		max_chunk_size: MAX_UPLOAD_CHUNK_SIZE,
+	})
+}
+
+/// finish_upload checks the uploaded file against its checksum, moves it in place and indexes it.
+/// A file that doesn't match is thrown away, as there's no telling which chunk was wrong.
+pub async fn finish_upload(
+	library: &LibraryContext,
+	args: FinishUploadArgs,
+) -> Result<(), UploadError> {
+	let (location, path) = target_path(library, &args.target).await?;
+	let upload = upload_path(&path);
+
+	let received = received(&upload).await?;
+	if received != args.size {
+		return Err(UploadError::Incomplete {
+			received,
+			size: args.size,
+		});
+	}
+
+	if file_checksum(upload.clone()).await? != args.checksum.to_lowercase() {
+		fs::remove_file(&upload).await?;
+		return Err(UploadError::ChecksumMismatch);
+	}
+
+	if fs::metadata(&path).await.is_ok() {
+		return Err(UploadError::AlreadyExists(path));
+	}
+	let started = Utc::now();
+	fs::rename(&upload, &path).await?;
+
+	// a sweep only walks what changed since the upload landed, so the file shows up right away
+	sweep_location(library, location, started - Duration::seconds(1)).await?;
+
+	Ok(())
+}
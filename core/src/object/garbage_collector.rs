@@ -0,0 +1,239 @@
+use std::collections::HashSet;
+
+use prisma_client_rust::raw::Raw;
+use serde::{Deserialize, Serialize};
+use tokio::task::block_in_place;
+use tracing::info;
+
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	object::preview::remove_previews,
+	prisma::{
+		comment, file_path, label_on_object, object, object_in_album, object_in_space,
+		tag_on_object, trash_item,
+	},
+	util::db::raw_int,
+};
+
+pub const GARBAGE_COLLECTOR_JOB_NAME: &str = "garbage_collector";
+
+/// how many objects are removed in a single step
+const BATCH_SIZE: usize = 100;
+
+/// Objects without a file_path, left behind as their files were removed. Trashed files keep their
+/// object for it to be linked back once they're restored.
+const ORPHANS_QUERY: &str = "SELECT id, cas_id FROM object \
+	WHERE NOT EXISTS (SELECT 1 FROM file_path WHERE file_path.object_id = object.id) \
+	AND NOT EXISTS (SELECT 1 FROM trash_item WHERE trash_item.object_id = object.id)";
+
+/// What the user added to an object, which is kept unless the collection is forced
+const ANNOTATED_FILTER: &str = " \
+	AND (object.note IS NULL OR object.note = '') \
+	AND NOT EXISTS (SELECT 1 FROM tag_on_object WHERE tag_on_object.object_id = object.id) \
+	AND NOT EXISTS (SELECT 1 FROM comment WHERE comment.object_id = object.id) \
+	AND NOT EXISTS (SELECT 1 FROM object_in_album WHERE object_in_album.object_id = object.id) \
+	AND NOT EXISTS (SELECT 1 FROM object_in_space WHERE object_in_space.object_id = object.id)";
+
+/// GarbageCollectorJob removes the objects no file_path points to anymore, along with their
+/// previews. Objects that were tagged, noted, commented on or added to an album or space are kept
+/// unless the collection is forced.
+pub struct GarbageCollectorJob {}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GarbageCollectorJobInit {
+	/// also remove the objects the user added tags, notes or comments to
+	pub force: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GarbageCollectorJobState {
+	removed_objects: usize,
+	removed_previews: usize,
+	reclaimed_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OrphanObject {
+	#[serde(deserialize_with = "raw_int")]
+	id: i64,
+	cas_id: String,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for GarbageCollectorJob {
+	type Init = GarbageCollectorJobInit;
+	type Data = GarbageCollectorJobState;
+	type Step = Vec<OrphanObject>;
+
+	fn name(&self) -> &'static str {
+		GARBAGE_COLLECTOR_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		let query = match state.init.force {
+			true => ORPHANS_QUERY.to_string(),
+			false => format!("{ORPHANS_QUERY}{ANNOTATED_FILTER}"),
+		};
+		let orphans: Vec<OrphanObject> = library
+			.db
+			._query_raw(Raw::new(&query, vec![]))
+			.exec()
+			.await?;
+
+		info!("Found {} objects without files", orphans.len());
+
+		state.steps = orphans
+			.chunks(BATCH_SIZE)
+			.map(|orphans| orphans.to_vec())
+			.collect();
+		state.data = Some(GarbageCollectorJobState::default());
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		let ids = state.steps[0]
+			.iter()
+			.map(|orphan| orphan.id as i32)
+			.collect::<Vec<_>>();
+
+		// files may have been indexed or trashed with these objects since the job started
+		let referenced = library
+			.db
+			.file_path()
+			.find_many(vec![file_path::object_id::in_vec(ids.clone())])
+			.exec()
+			.await?
+			.into_iter()
+			.filter_map(|file_path| file_path.object_id)
+			.chain(
+				library
+					.db
+					.trash_item()
+					.find_many(vec![trash_item::object_id::in_vec(ids.clone())])
+					.exec()
+					.await?
+					.into_iter()
+					.filter_map(|item| item.object_id),
+			)
+			.collect::<HashSet<_>>();
+
+		let orphans = state.steps[0]
+			.iter()
+			.filter(|orphan| !referenced.contains(&(orphan.id as i32)))
+			.collect::<Vec<_>>();
+		let ids = orphans
+			.iter()
+			.map(|orphan| orphan.id as i32)
+			.collect::<Vec<_>>();
+
+		if !ids.is_empty() {
+			// links to the objects aren't cascaded, so they need to go first
+			library
+				.db
+				.tag_on_object()
+				.delete_many(vec![tag_on_object::object_id::in_vec(ids.clone())])
+				.exec()
+				.await?;
+			library
+				.db
+				.label_on_object()
+				.delete_many(vec![label_on_object::object_id::in_vec(ids.clone())])
+				.exec()
+				.await?;
+			library
+				.db
+				.object_in_space()
+				.delete_many(vec![object_in_space::object_id::in_vec(ids.clone())])
+				.exec()
+				.await?;
+			library
+				.db
+				.object_in_album()
+				.delete_many(vec![object_in_album::object_id::in_vec(ids.clone())])
+				.exec()
+				.await?;
+			library
+				.db
+				.comment()
+				.delete_many(vec![comment::object_id::in_vec(ids.clone())])
+				.exec()
+				.await?;
+			data.removed_objects += library
+				.db
+				.object()
+				.delete_many(vec![object::id::in_vec(ids)])
+				.exec()
+				.await? as usize;
+
+			// previews are shared by the node's libraries, so they're kept while one still has the
+			// content, or while it can't be told
+			let mut unused = Vec::with_capacity(orphans.len());
+			if let Some(library_manager) = library.library_manager() {
+				for orphan in orphans {
+					if !library_manager.has_cas_id(&orphan.cas_id).await? {
+						unused.push(orphan);
+					}
+				}
+			}
+
+			let data_dir = library.config().data_directory();
+			block_in_place(|| {
+				for orphan in &unused {
+					if let Some(bytes) = remove_previews(&data_dir, &orphan.cas_id)? {
+						data.removed_previews += 1;
+						data.reclaimed_bytes += bytes;
+					}
+				}
+				Ok::<_, std::io::Error>(())
+			})?;
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		info!(
+			"Removed {} objects without files and {} previews, reclaiming {} bytes",
+			data.removed_objects, data.removed_previews, data.reclaimed_bytes
+		);
+
+		invalidate_query!(library, "cache.stats");
+		invalidate_query!(library, "library.getStatistics");
+		invalidate_query!(library, "locations.getExplorerData");
+
+		Ok(Some(serde_json::to_value(data)?))
+	}
+}
@@ -0,0 +1,509 @@
+//! Where photos were taken, for map views. The GPS coordinates in a photo's EXIF are kept with its
+//! media data when its thumbnail is generated, along with their geohash: a base32 string naming
+//! nested cells of the globe, so photos in the same cell share a prefix. Geohashes are indexed, which
+//! makes them the spatial index of the library. A box on the map is covered by a few cells whose
+//! ranges of geohashes are looked up, and zoomed out maps cluster photos by a shorter prefix.
+
+use std::{collections::HashSet, fs::File, io::BufReader, path::Path};
+
+use itertools::Itertools;
+use prisma_client_rust::raw::Raw;
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{library::LibraryContext, util::db::raw_int};
+
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+/// Length of the geohashes kept, cells of a few meters
+pub const GEOHASH_PRECISION: usize = 9;
+/// Most cells a box on the map is covered by, the fewer the larger they are
+const MAX_COVERING_CELLS: usize = 32;
+/// Maps zoomed in at least this far show each photo rather than clusters, zooms are as web map tiles'
+const CLUSTER_BELOW_ZOOM: u8 = 15;
+/// Most photos shown one by one, past this many they're clustered however far the map is zoomed in
+const MAX_MARKERS: usize = 500;
+const DEFAULT_NEAR_RADIUS_METERS: u32 = 1000;
+const MAX_NEAR_RADIUS_METERS: u32 = 100_000;
+const DEFAULT_NEAR_LIMIT: u32 = 100;
+const MAX_NEAR_LIMIT: u32 = 1000;
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+#[derive(Error, Debug)]
+pub enum GeoError {
+	#[error("coordinates out of range: {0}, {1}")]
+	InvalidCoordinates(f64, f64),
+	#[error("database error: {0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+}
+
+impl From<GeoError> for rspc::Error {
+	fn from(err: GeoError) -> Self {
+		match err {
+			GeoError::InvalidCoordinates(..) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			GeoError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// geohash encodes a coordinate as a geohash of `precision` characters
+pub fn geohash(latitude: f64, longitude: f64, precision: usize) -> String {
+	let (mut latitudes, mut longitudes) = ((-90.0, 90.0), (-180.0, 180.0));
+	let mut hash = String::with_capacity(precision);
+	let (mut index, mut bits, mut is_longitude) = (0, 0, true);
+
+	while hash.len() < precision {
+		let (range, value) = if is_longitude {
+			(&mut longitudes, longitude)
+		} else {
+			(&mut latitudes, latitude)
+		};
+		let middle = (range.0 + range.1) / 2.0;
+		index <<= 1;
+		if value >= middle {
+			index |= 1;
+			range.0 = middle;
+		} else {
+			range.1 = middle;
+		}
+		is_longitude = !is_longitude;
+
+		bits += 1;
+		if bits == 5 {
+			hash.push(GEOHASH_ALPHABET[index] as char);
+			(index, bits) = (0, 0);
+		}
+	}
+
+	hash
+}
+
+/// cell_size returns the height and width, in degrees, of the cells of geohashes of `precision`
+/// characters. Bits alternate between longitude and latitude, starting with longitude.
+fn cell_size(precision: usize) -> (f64, f64) {
+	let bits = 5 * precision as i32;
+	(
+		180.0 / 2f64.powi(bits / 2),
+		360.0 / 2f64.powi((bits + 1) / 2),
+	)
+}
+
+/// A box on the map, in degrees. Boxes crossing the antimeridian have a west edge east of their east
+/// edge.
+#[derive(Deserialize, Type, Debug, Clone, Copy)]
+pub struct Bounds {
+	pub north: f64,
+	pub south: f64,
+	pub east: f64,
+	pub west: f64,
+}
+
+impl Bounds {
+	fn validate(&self) -> Result<(), GeoError> {
+		for (latitude, longitude) in [(self.north, self.east), (self.south, self.west)] {
+			validate_coordinate(latitude, longitude)?;
+		}
+		Ok(())
+	}
+
+	/// split returns the box as boxes that don't cross the antimeridian
+	fn split(self) -> Vec<Bounds> {
+		let (north, south) = (self.north.max(self.south), self.north.min(self.south));
+		if self.west <= self.east {
+			vec![Bounds {
+				north,
+				south,
+				..self
+			}]
+		} else {
+			vec![
+				Bounds {
+					north,
+					south,
+					east: 180.0,
+					west: self.west,
+				},
+				Bounds {
+					north,
+					south,
+					east: self.east,
+					west: -180.0,
+				},
+			]
+		}
+	}
+
+	fn contains_sql(&self) -> String {
+		format!(
+			"(md.latitude BETWEEN {} AND {} AND md.longitude BETWEEN {} AND {})",
+			self.south, self.north, self.west, self.east
+		)
+	}
+}
+
+fn validate_coordinate(latitude: f64, longitude: f64) -> Result<(), GeoError> {
+	if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+		return Err(GeoError::InvalidCoordinates(latitude, longitude));
+	}
+	Ok(())
+}
+
+/// covering_cells returns the geohash prefixes of the cells covering the boxes, as small as they can
+/// be without there being more than `MAX_COVERING_CELLS` of them
+fn covering_cells(boxes: &[Bounds]) -> Vec<String> {
+	let cells_at = |precision| {
+		let (height, width) = cell_size(precision);
+		let mut cells = HashSet::new();
+		for bounds in boxes {
+			// stepping a cell at a time, every row and column of cells is landed on
+			let rows = ((bounds.north - bounds.south) / height).ceil() as usize;
+			let columns = ((bounds.east - bounds.west) / width).ceil() as usize;
+			if (rows + 1) * (columns + 1) > MAX_COVERING_CELLS * 4 {
+				return None;
+			}
+			for row in 0..=rows {
+				for column in 0..=columns {
+					cells.insert(geohash(
+						(bounds.south + row as f64 * height).min(bounds.north),
+						(bounds.west + column as f64 * width).min(bounds.east),
+						precision,
+					));
+				}
+			}
+		}
+		(cells.len() <= MAX_COVERING_CELLS).then_some(cells)
+	};
+
+	(1..=GEOHASH_PRECISION)
+		.rev()
+		.find_map(cells_at)
+		.map(|cells| cells.into_iter().sorted().collect())
+		// no cells at all covers the whole globe
+		.unwrap_or_default()
+}
+
+/// within_sql returns the condition of media data being within the boxes, found by their geohash
+fn within_sql(boxes: &[Bounds]) -> String {
+	let cells = covering_cells(boxes);
+	// '~' sorts after every character of geohashes, so the range holds every geohash of the cell
+	let cells = if cells.is_empty() {
+		"md.geohash IS NOT NULL".to_string()
+	} else {
+		cells
+			.iter()
+			.map(|cell| format!("(md.geohash >= '{cell}' AND md.geohash < '{cell}~')"))
+			.join(" OR ")
+	};
+
+	format!(
+		"({cells}) AND ({})",
+		boxes.iter().map(Bounds::contains_sql).join(" OR ")
+	)
+}
+
+/// cluster_precision returns the length of the geohashes photos are clustered by at a zoom level,
+/// cells about a quarter of a map tile wide
+fn cluster_precision(zoom: u8) -> usize {
+	((2 * (zoom as usize + 2)) / 5).clamp(1, GEOHASH_PRECISION - 1)
+}
+
+/// distance_meters returns the distance between two coordinates along the surface of the Earth
+fn distance_meters(from: (f64, f64), to: (f64, f64)) -> f64 {
+	let (latitude_from, latitude_to) = (from.0.to_radians(), to.0.to_radians());
+	let half_latitude = ((to.0 - from.0).to_radians() / 2.0).sin();
+	let half_longitude = ((to.1 - from.1).to_radians() / 2.0).sin();
+
+	let a =
+		half_latitude.powi(2) + latitude_from.cos() * latitude_to.cos() * half_longitude.powi(2);
+	2.0 * EARTH_RADIUS_METERS * a.sqrt().min(1.0).asin()
+}
+
+/// gps_coordinate reads a coordinate written as degrees, minutes and seconds, south and west being
+/// negative
+fn gps_coordinate(dms: [f64; 3], negative: bool) -> f64 {
+	let degrees = dms[0] + dms[1] / 60.0 + dms[2] / 3600.0;
+	if negative {
+		-degrees
+	} else {
+		degrees
+	}
+}
+
+/// exif_location returns where a photo was taken, if its EXIF says
+pub fn exif_location(path: &Path) -> Option<(f64, f64)> {
+	let exif = exif::Reader::new()
+		.read_from_container(&mut BufReader::new(File::open(path).ok()?))
+		.ok()?;
+
+	let coordinate = |tag, reference_tag, negative_reference| {
+		let dms = match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+			exif::Value::Rational(values) if values.len() >= 3 => {
+				[values[0].to_f64(), values[1].to_f64(), values[2].to_f64()]
+			}
+			_ => return None,
+		};
+		let negative = match exif
+			.get_field(reference_tag, exif::In::PRIMARY)
+			.map(|field| &field.value)
+		{
+			Some(exif::Value::Ascii(values)) => values
+				.first()
+				.and_then(|value| value.first())
+				.map_or(false, |reference| *reference == negative_reference),
+			_ => false,
+		};
+		Some(gps_coordinate(dms, negative))
+	};
+
+	let latitude = coordinate(exif::Tag::GPSLatitude, exif::Tag::GPSLatitudeRef, b'S')?;
+	let longitude = coordinate(exif::Tag::GPSLongitude, exif::Tag::GPSLongitudeRef, b'W')?;
+
+	// cameras without a fix write zeroes
+	if (latitude == 0.0 && longitude == 0.0) || validate_coordinate(latitude, longitude).is_err() {
+		return None;
+	}
+
+	Some((latitude, longitude))
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct MapMarkersArgs {
+	pub bounds: Bounds,
+	pub zoom: u8,
+}
+
+/// What's shown at a place on the map
+#[derive(Serialize, Type, Debug)]
+#[serde(tag = "type")]
+pub enum MapMarker {
+	Photo {
+		object_id: i32,
+		cas_id: String,
+		latitude: f64,
+		longitude: f64,
+	},
+	/// photos taken close to each other, shown at their average location with one of them as cover
+	Cluster {
+		count: i32,
+		latitude: f64,
+		longitude: f64,
+		object_id: i32,
+		cas_id: String,
+	},
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotoRow {
+	#[serde(deserialize_with = "raw_int")]
+	object_id: i64,
+	cas_id: String,
+	latitude: f64,
+	longitude: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClusterRow {
+	#[serde(deserialize_with = "raw_int")]
+	count: i64,
+	latitude: f64,
+	longitude: f64,
+	#[serde(deserialize_with = "raw_int")]
+	object_id: i64,
+	cas_id: String,
+}
+
+/// photos_within returns at most `limit` of the photos within the boxes
+async fn photos_within(
+	library: &LibraryContext,
+	boxes: &[Bounds],
+	limit: usize,
+) -> Result<Vec<PhotoRow>, GeoError> {
+	Ok(library
+		.db
+		._query_raw(Raw::new(
+			&format!(
+				"SELECT md.id AS object_id, o.cas_id AS cas_id, md.latitude AS latitude, \
+				md.longitude AS longitude FROM media_data md JOIN object o ON o.id = md.id \
+				WHERE {} LIMIT {limit}",
+				within_sql(boxes)
+			),
+			vec![],
+		))
+		.exec()
+		.await?)
+}
+
+/// map_markers returns what's on a box of the map: each photo when zoomed in, or clusters of them
+pub async fn map_markers(
+	library: &LibraryContext,
+	args: MapMarkersArgs,
+) -> Result<Vec<MapMarker>, GeoError> {
+	args.bounds.validate()?;
+	let boxes = args.bounds.split();
+
+	if args.zoom >= CLUSTER_BELOW_ZOOM {
+		let photos = photos_within(library, &boxes, MAX_MARKERS + 1).await?;
+		if photos.len() <= MAX_MARKERS {
+			return Ok(photos
+				.into_iter()
+				.map(|photo| MapMarker::Photo {
+					object_id: photo.object_id as i32,
+					cas_id: photo.cas_id,
+					latitude: photo.latitude,
+					longitude: photo.longitude,
+				})
+				.collect());
+		}
+	}
+
+	let clusters: Vec<ClusterRow> = library
+		.db
+		._query_raw(Raw::new(
+			&format!(
+				"SELECT c.count AS count, c.latitude AS latitude, c.longitude AS longitude, \
+				c.object_id AS object_id, o.cas_id AS cas_id FROM (\
+				SELECT substr(md.geohash, 1, {}) AS cell, COUNT(*) AS count, \
+				AVG(md.latitude) AS latitude, AVG(md.longitude) AS longitude, MIN(md.id) AS object_id \
+				FROM media_data md WHERE {} GROUP BY cell) c JOIN object o ON o.id = c.object_id",
+				cluster_precision(args.zoom),
+				within_sql(&boxes)
+			),
+			vec![],
+		))
+		.exec()
+		.await?;
+
+	Ok(clusters
+		.into_iter()
+		.map(|cluster| MapMarker::Cluster {
+			count: cluster.count as i32,
+			latitude: cluster.latitude,
+			longitude: cluster.longitude,
+			object_id: cluster.object_id as i32,
+			cas_id: cluster.cas_id,
+		})
+		.collect())
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct NearbyPhotosArgs {
+	pub latitude: f64,
+	pub longitude: f64,
+	pub radius_meters: Option<u32>,
+	pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct NearbyPhoto {
+	pub object_id: i32,
+	pub cas_id: String,
+	pub latitude: f64,
+	pub longitude: f64,
+	pub distance_meters: f64,
+}
+
+/// nearby_photos returns the photos taken within a radius of a coordinate, the closest first
+pub async fn nearby_photos(
+	library: &LibraryContext,
+	args: NearbyPhotosArgs,
+) -> Result<Vec<NearbyPhoto>, GeoError> {
+	validate_coordinate(args.latitude, args.longitude)?;
+	let radius = args
+		.radius_meters
+		.unwrap_or(DEFAULT_NEAR_RADIUS_METERS)
+		.min(MAX_NEAR_RADIUS_METERS) as f64;
+	let limit = args.limit.unwrap_or(DEFAULT_NEAR_LIMIT).min(MAX_NEAR_LIMIT) as usize;
+
+	// the box around the circle, narrower near the poles where longitudes are closer together
+	let latitude_span = radius / METERS_PER_DEGREE;
+	let longitude_span =
+		(radius / (METERS_PER_DEGREE * args.latitude.to_radians().cos().abs())).min(180.0);
+	let wrap = |longitude: f64| (longitude + 540.0) % 360.0 - 180.0;
+	let bounds = Bounds {
+		north: (args.latitude + latitude_span).min(90.0),
+		south: (args.latitude - latitude_span).max(-90.0),
+		east: if longitude_span >= 180.0 {
+			180.0
+		} else {
+			wrap(args.longitude + longitude_span)
+		},
+		west: if longitude_span >= 180.0 {
+			-180.0
+		} else {
+			wrap(args.longitude - longitude_span)
+		},
+	};
+
+	// the box's corners are further than the radius, so a few more photos than asked for are read
+	let mut photos = photos_within(library, &bounds.split(), limit * 2 + MAX_MARKERS)
+		.await?
+		.into_iter()
+		.map(|photo| NearbyPhoto {
+			distance_meters: distance_meters(
+				(args.latitude, args.longitude),
+				(photo.latitude, photo.longitude),
+			),
+			object_id: photo.object_id as i32,
+			cas_id: photo.cas_id,
+			latitude: photo.latitude,
+			longitude: photo.longitude,
+		})
+		.filter(|photo| photo.distance_meters <= radius)
+		.collect::<Vec<_>>();
+	photos.sort_by(|a, b| a.distance_meters.total_cmp(&b.distance_meters));
+	photos.truncate(limit);
+
+	Ok(photos)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encodes_geohashes() {
+		assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+		assert_eq!(geohash(-33.8568, 151.2153, 6), "r3gx2u");
+
+		let (height, width) = cell_size(1);
+		assert_eq!((height, width), (45.0, 45.0));
+	}
+
+	#[test]
+	fn boxes_are_covered_by_cells() {
+		let paris = Bounds {
+			north: 48.90,
+			south: 48.82,
+			east: 2.42,
+			west: 2.25,
+		};
+		let cells = covering_cells(&[paris]);
+		assert!(!cells.is_empty() && cells.len() <= MAX_COVERING_CELLS);
+		let eiffel_tower = geohash(48.8584, 2.2945, GEOHASH_PRECISION);
+		assert!(cells.iter().any(|cell| eiffel_tower.starts_with(cell)));
+
+		let pacific = Bounds {
+			north: 10.0,
+			south: -10.0,
+			east: -170.0,
+			west: 170.0,
+		};
+		assert_eq!(pacific.split().len(), 2);
+	}
+
+	#[test]
+	fn reads_gps_coordinates() {
+		let latitude = gps_coordinate([40.0, 26.0, 46.0], false);
+		assert!((latitude - 40.446111).abs() < 1e-6);
+		assert!((gps_coordinate([79.0, 58.0, 56.0], true) + 79.982222).abs() < 1e-6);
+
+		// Paris to London
+		let distance = distance_meters((48.8566, 2.3522), (51.5074, -0.1278));
+		assert!((distance - 343_500.0).abs() < 1000.0);
+	}
+}
@@ -1,25 +1,71 @@
 use crate::{
-	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	invalidate_query,
+	job::{
+		FileError, JobError, JobManager, JobReport, JobReportUpdate, JobResult, JobState,
+		JobStatus, StatefulJob, WorkerContext,
+	},
 	library::LibraryContext,
-	prisma::{file_path, location, object},
+	location::{
+		automation::run_automations,
+		indexer::git::GIT_DIR_NAME,
+		settings::LocationSettings,
+		storage::{storage_cas_id, Storage, StorageConfig, StorageError},
+		treemap::compute_directory_sizes,
+		LocationError,
+	},
+	node::HookEvent,
+	prisma::{file_path, job, location, object},
+	sync::{
+		models::{uuid_from_pub_id, FilePathId, FILE_PATH},
+		SyncError,
+	},
+	util::{
+		db::{OnConflict, Upsert},
+		message::Message,
+		os_path::resolve_materialized_path,
+	},
 };
 use chrono::{DateTime, FixedOffset};
 use int_enum::IntEnum;
-use prisma_client_rust::{prisma_models::PrismaValue, raw::Raw, Direction};
-use sd_file_ext::{extensions::Extension, kind::ObjectKind};
+use prisma_client_rust::{prisma_models::PrismaValue, Direction};
+use rspc::Type;
+use sd_file_ext::{extensions::Extension, kind::ObjectKind, magic::ExtensionPossibility};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
 use std::{
 	collections::{HashMap, HashSet},
+	io::{Read, Seek, SeekFrom},
 	path::{Path, PathBuf},
+	sync::Arc,
 };
-use tokio::{fs, io};
+use tokio::{fs, io, task::block_in_place};
 use tracing::{error, info};
+use uuid::Uuid;
 
-use super::cas::generate_cas_id;
+use super::{
+	cas::{
+		generate_cas_id, is_small_file, link_file_paths, path_cas_id, sampled_bytes,
+		small_file_cas_id,
+	},
+	fs::archive_reader::{index_archive, ArchiveKind},
+	sources::{read_sources, record_sources, SourceKind},
+	validation::hash::file_checksum,
+	versions::{keep_copy, VERSION_COPY_MAX_SIZE},
+};
 
-// we break this job into chunks of 100 to improve performance
-static CHUNK_SIZE: usize = 100;
+// we break this job into chunks of 500 to improve performance, objects are written in bulk so bigger
+// chunks take fewer statements per file, which is most of the time spent on tiny files
+static CHUNK_SIZE: usize = 500;
 pub const IDENTIFIER_JOB_NAME: &str = "file_identifier";
+/// How much of the small files of a chunk is read in its one blocking call, past which the rest
+/// are identified one at a time. With the chunk size, it bounds what a step holds whatever the
+/// location's size, as nothing but the cursor is kept from one chunk to the next.
+const SMALL_FILES_BATCH_BYTES: u64 = 8 * 1024 * 1024;
+/// The share of the first chunk of files already in the library from which the job stops to ask what
+/// to do with them, so adding e.g. a backup of a folder doesn't double the library by accident
+const DUPLICATE_GUARD_RATIO: f64 = 0.9;
+/// Fewer files than this are identified without asking, however many are already in the library
+const DUPLICATE_GUARD_MIN_FILES: usize = 20;
 
 pub struct FileIdentifierJob {}
 
@@ -51,6 +97,26 @@ pub struct FileIdentifierJobState {
 	location: location::Data,
 	location_path: PathBuf,
 	cursor: FilePathIdAndLocationIdCursor,
+	/// what to do with files already in the library, once the user was asked
+	#[serde(default)]
+	duplicates: Option<DuplicateDecision>,
+}
+
+/// What a job identifying mostly files the library already has does with them, see
+/// [`JobStatus::AwaitingDecision`]
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateDecision {
+	/// stop the job, leaving the files it didn't get to unidentified
+	Cancel,
+	/// identify every file, the duplicates included
+	ImportAnyway,
+	/// only keep the files that are new to the library, removing the others from the location's index
+	OnlyNew,
+}
+
+/// is_mostly_duplicates tells if enough of the files are already in the library to ask before going on
+fn is_mostly_duplicates(duplicates: usize, total: usize) -> bool {
+	total >= DUPLICATE_GUARD_MIN_FILES && duplicates as f64 >= total as f64 * DUPLICATE_GUARD_RATIO
 }
 
 #[async_trait::async_trait]
@@ -88,7 +154,15 @@ impl StatefulJob for FileIdentifierJob {
 			.map(PathBuf::from)
 			.unwrap_or_default();
 
-		let total_count = count_orphan_file_paths(&library, state.init.location_id).await?;
+		let skip_git_internals = !LocationSettings::from(&location).hash_git_internals;
+
+		let total_count = count_orphan_file_paths(
+			&library,
+			state.init.location_id,
+			state.init.sub_path.as_deref(),
+			skip_git_internals,
+		)
+		.await?;
 		info!("Found {} orphan file paths", total_count);
 
 		let task_count = (total_count as f64 / CHUNK_SIZE as f64).ceil() as usize;
@@ -103,7 +177,12 @@ impl StatefulJob for FileIdentifierJob {
 		let first_path_id = library
 			.db
 			.file_path()
-			.find_first(orphan_path_filters(location_id, None))
+			.find_first(orphan_path_filters(
+				location_id,
+				state.init.sub_path.as_deref(),
+				None,
+				skip_git_internals,
+			))
 			.exec()
 			.await?
 			.map(|d| d.id)
@@ -118,6 +197,7 @@ impl StatefulJob for FileIdentifierJob {
 				file_path_id: first_path_id,
 				location_id: state.init.location_id,
 			},
+			duplicates: None,
 		});
 
 		state.steps = (0..task_count).map(|_| ()).collect();
@@ -130,20 +210,35 @@ impl StatefulJob for FileIdentifierJob {
 		ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> Result<(), JobError> {
-		let db = ctx.library_ctx().db;
+		let library = ctx.library_ctx();
+		let db = library.db.clone();
 
 		// link file_path ids to a CreateObject struct containing unique file data
 		let mut chunk: HashMap<i32, CreateObject> = HashMap::new();
-		let mut cas_lookup: HashMap<String, i32> = HashMap::new();
+		// where downloaded files came from, by file_path id
+		let mut sources: HashMap<i32, Vec<(SourceKind, String)>> = HashMap::new();
 
 		let data = state
 			.data
 			.as_mut()
 			.expect("Critical error: missing data on job state");
 
+		// files of storage locations are identified through the storage, without downloading them
+		let storage = StorageConfig::parse(data.location.storage_config.as_deref())
+			.and_then(|storage| storage.map(|storage| storage.open_in_job(&ctx)).transpose())
+			.map_err(LocationError::from)?;
+
+		let settings = LocationSettings::from(&data.location);
+
 		// get chunk of orphans to process
-		let file_paths =
-			get_orphan_file_paths(&ctx.library_ctx(), &data.cursor, data.location.id).await?;
+		let file_paths = get_orphan_file_paths(
+			&ctx.library_ctx(),
+			&data.cursor,
+			data.location.id,
+			state.init.sub_path.as_deref(),
+			!settings.hash_git_internals,
+		)
+		.await?;
 
 		// if no file paths found, abort entire job early
 		if file_paths.is_empty() {
@@ -159,25 +254,111 @@ impl StatefulJob for FileIdentifierJob {
 			data.task_count
 		);
 
+		// small files are read and hashed together in one blocking call, as one at a time they spend more
+		// time on syscalls and handing tasks around than on hashing
+		let mut small_objects = HashMap::new();
+		if storage.is_none() {
+			let _hasher = ctx.governor().hasher().await;
+			small_objects = block_in_place(|| {
+				identify_small_files(
+					file_paths
+						.iter()
+						.filter(|file_path| {
+							!file_path.is_dir
+								&& !settings.identifies_by_path(0, file_path.extension.as_deref())
+						})
+						.map(|file_path| {
+							(
+								file_path.id,
+								resolve_materialized_path(
+									&data.location_path,
+									&file_path.materialized_path,
+									file_path.raw_path.as_deref(),
+								),
+								file_path.date_created,
+							)
+						}),
+					settings.max_identify_size,
+					SMALL_FILES_BATCH_BYTES,
+				)
+			});
+		}
+		ctx.governor()
+			.throttle_read(
+				small_objects
+					.values()
+					.filter_map(|object| object.as_ref().ok())
+					.map(|object| object.size_in_bytes as u64)
+					.sum(),
+			)
+			.await;
+
 		// analyze each file_path
 		for file_path in &file_paths {
 			// get the cas_id and extract metadata
-			match assemble_object_metadata(&data.location_path, file_path).await {
+			let object = match &storage {
+				Some(storage) => assemble_storage_object_metadata(
+					storage.as_ref(),
+					file_path,
+					&settings,
+					&data.location.pub_id,
+				)
+				.await
+				.map_err(|e| Message::Error {
+					text: e.to_string(),
+				}),
+				None if small_objects.contains_key(&file_path.id) => small_objects
+					.remove(&file_path.id)
+					.expect("checked above")
+					.map_err(|e| Message::from(&e)),
+				None => {
+					let object = {
+						let _hasher = ctx.governor().hasher().await;
+						assemble_object_metadata(
+							&data.location_path,
+							file_path,
+							&settings,
+							&data.location.pub_id,
+						)
+						.await
+					};
+					if let Ok(object) = &object {
+						ctx.governor()
+							.throttle_read(sampled_bytes(object.size_in_bytes as u64))
+							.await;
+					}
+					object.map_err(|e| Message::from(&e))
+				}
+			};
+
+			match object {
 				Ok(object) => {
-					let cas_id = object.cas_id.clone();
+					if storage.is_none() {
+						let path = resolve_materialized_path(
+							&data.location_path,
+							&file_path.materialized_path,
+							file_path.raw_path.as_deref(),
+						);
+						let file_sources = block_in_place(|| read_sources(&path));
+						if !file_sources.is_empty() {
+							sources.insert(file_path.id, file_sources);
+						}
+					}
+
 					// create entry into chunks for created file data
 					chunk.insert(file_path.id, object);
-					cas_lookup.insert(cas_id, file_path.id);
 				}
 				Err(e) => {
-					error!("Error assembling Object metadata: {:#?}", e);
+					ctx.record_file_error(FileError::new(file_path, e)).await;
 					continue;
 				}
 			};
 		}
 
+		let cas_lookup = group_by_cas_id(&chunk);
+
 		// find all existing files by cas id
-		let generated_cas_ids = chunk.values().map(|c| c.cas_id.clone()).collect();
+		let generated_cas_ids = cas_lookup.keys().cloned().collect();
 		let existing_objects = db
 			.object()
 			.find_many(vec![object::cas_id::in_vec(generated_cas_ids)])
@@ -186,88 +367,231 @@ impl StatefulJob for FileIdentifierJob {
 
 		info!("Found {} existing files", existing_objects.len());
 
-		for existing_object in &existing_objects {
-			if let Err(e) = db
-				.file_path()
-				.update(
-					file_path::location_id_id(
-						state.init.location_id,
-						*cas_lookup.get(&existing_object.cas_id).unwrap(),
-					),
-					vec![file_path::object_id::set(Some(existing_object.id))],
-				)
-				.exec()
-				.await
-			{
-				error!("Error updating file_id: {:#?}", e);
-			}
-		}
-
 		let existing_object_cas_ids = existing_objects
 			.iter()
 			.map(|object| object.cas_id.clone())
 			.collect::<HashSet<_>>();
+		let duplicate_ids = chunk
+			.iter()
+			.filter(|(_, object)| existing_object_cas_ids.contains(&object.cas_id))
+			.map(|(file_path_id, _)| *file_path_id)
+			.collect::<HashSet<_>>();
+
+		// the first chunk tells if the files are mostly ones the library already has
+		if data.duplicates.is_none()
+			&& state.step_number == 0
+			&& is_mostly_duplicates(duplicate_ids.len(), chunk.len())
+		{
+			return Err(JobError::DuplicateImport {
+				duplicates: duplicate_ids.len(),
+				total: chunk.len(),
+			});
+		}
 
-		// extract objects that don't already exist in the database
-		let new_objects = chunk
+		let removed = match data.duplicates {
+			Some(DuplicateDecision::OnlyNew) if !duplicate_ids.is_empty() => {
+				remove_duplicates(&library, &data.location, &duplicate_ids).await?;
+				duplicate_ids
+			}
+			_ => HashSet::new(),
+		};
+
+		// the objects each file_path got linked to
+		let mut linked = HashMap::new();
+		let file_paths_by_id = file_paths
 			.iter()
-			.map(|(_id, create_file)| create_file)
-			.filter(|create_file| !existing_object_cas_ids.contains(&create_file.cas_id))
+			.map(|file_path| (file_path.id, file_path))
+			.collect::<HashMap<_, _>>();
+
+		link_by_cas_id(
+			&cas_lookup,
+			existing_objects
+				.iter()
+				.map(|object| (object.cas_id.as_str(), object.id)),
+			&removed,
+			&mut linked,
+		);
+
+		// extract objects that don't already exist in the database, once for each cas_id
+		let new_objects = cas_lookup
+			.iter()
+			.filter(|(cas_id, _)| !existing_object_cas_ids.contains(*cas_id))
+			.map(|(_, file_path_ids)| &chunk[&file_path_ids[0]])
 			.collect::<Vec<_>>();
 
 		if !new_objects.is_empty() {
-			// assemble prisma values for new unique files
-			let mut values = Vec::with_capacity(new_objects.len() * 3);
-			for object in &new_objects {
-				values.extend([
+			// create new file records for new unique files
+			// TODO: Use create_many with skip_duplicates. Waiting on https://github.com/Brendonovich/prisma-client-rust/issues/143
+			let created_files: Vec<FileCreated> = Upsert::new(
+				"object",
+				[
+					"cas_id",
+					"size_in_bytes",
+					"date_created",
+					"kind",
+					"identified_by_path",
+				],
+			)
+			.rows(new_objects.iter().map(|object| {
+				[
 					PrismaValue::String(object.cas_id.clone()),
 					PrismaValue::Int(object.size_in_bytes),
 					PrismaValue::DateTime(object.date_created),
 					PrismaValue::Int(object.kind.int_value() as i64),
-				]);
-			}
+					PrismaValue::Boolean(object.identified_by_path),
+				]
+			}))
+			.on_conflict(OnConflict::DoNothing(&["cas_id"]))
+			.returning(&db, &["id", "cas_id"])
+			.await
+			.unwrap_or_else(|e| {
+				error!("Error inserting files: {:#?}", e);
+				Vec::new()
+			});
 
-			// create new file records with assembled values
-			// TODO: Use create_many with skip_duplicates. Waiting on https://github.com/Brendonovich/prisma-client-rust/issues/143
-			let created_files: Vec<FileCreated> = db
-				._query_raw(Raw::new(
-					&format!(
-						"INSERT INTO object (cas_id, size_in_bytes, date_created, kind) VALUES {}
-						ON CONFLICT (cas_id) DO NOTHING RETURNING id, cas_id",
-						vec!["({}, {}, {}, {})"; new_objects.len()].join(",")
-					),
-					values,
+			link_by_cas_id(
+				&cas_lookup,
+				created_files
+					.iter()
+					.map(|object| (object.cas_id.as_str(), object.id)),
+				&removed,
+				&mut linked,
+			);
+		}
+
+		// associate the file_paths with their objects, all at once
+		if let Err(e) = link_file_paths(
+			&db,
+			state.init.location_id,
+			linked
+				.iter()
+				.map(|(file_path_id, object_id)| (*file_path_id, *object_id)),
+		)
+		.await
+		{
+			for file_path_id in linked.keys() {
+				ctx.record_file_error(FileError::new(
+					file_paths_by_id[file_path_id],
+					Message::Error {
+						text: e.to_string(),
+					},
 				))
-				.exec()
-				.await
-				.unwrap_or_else(|e| {
-					error!("Error inserting files: {:#?}", e);
-					Vec::new()
-				});
-
-			for created_file in created_files {
-				// associate newly created files with their respective file_paths
-				// TODO: this is potentially bottle necking the chunk system, individually linking file_path to file, 100 queries per chunk
-				// - insert many could work, but I couldn't find a good way to do this in a single SQL query
-				if let Err(e) = ctx
-					.library_ctx()
-					.db
-					.file_path()
-					.update(
-						file_path::location_id_id(
-							state.init.location_id,
-							*cas_lookup.get(&created_file.cas_id).unwrap(),
-						),
-						vec![file_path::object_id::set(Some(created_file.id))],
-					)
-					.exec()
+				.await;
+			}
+			linked.clear();
+		}
+
+		if let Err(e) = sync_linked(&library, &data.location, &linked).await {
+			error!("Error logging identified objects for sync: {:#?}", e);
+		}
+
+		for (file_path_id, file_sources) in sources {
+			if let Some(object_id) = linked.get(&file_path_id) {
+				if let Err(e) = record_sources(&library, *object_id, file_sources).await {
+					error!("Error recording sources of object {}: {:#?}", object_id, e);
+				}
+			}
+		}
+
+		// the location's settings can have the whole contents of its files hashed as they're identified
+		if storage.is_none() && settings.hash_full_contents {
+			for (file_path_id, object_id) in &linked {
+				let object = &chunk[file_path_id];
+				let size = object.size_in_bytes as u64;
+				if object.identified_by_path || !settings.hashes_fully(size) {
+					continue;
+				}
+
+				let file_path = file_paths_by_id[file_path_id];
+				let checksum = {
+					let _hasher = ctx.governor().hasher().await;
+					file_checksum(resolve_materialized_path(
+						&data.location_path,
+						&file_path.materialized_path,
+						file_path.raw_path.as_deref(),
+					))
 					.await
-				{
-					info!("Error updating file_id: {:#?}", e);
+				};
+				match checksum {
+					Ok(checksum) => {
+						ctx.governor().throttle_read(size).await;
+						db.object()
+							.update_many(
+								vec![
+									object::id::equals(*object_id),
+									object::integrity_checksum::equals(None),
+								],
+								vec![object::integrity_checksum::set(Some(checksum))],
+							)
+							.exec()
+							.await?;
+					}
+					Err(e) => {
+						ctx.record_file_error(FileError::new(file_path, Message::from(&e)))
+							.await
+					}
 				}
 			}
 		}
 
+		// copies of small files are kept as they were identified, to restore them from once they change
+		if storage.is_none() && data.location.keep_version_copies {
+			for (file_path_id, object) in chunk.iter().filter(|(file_path_id, object)| {
+				linked.contains_key(*file_path_id)
+					&& object.size_in_bytes as u64 <= VERSION_COPY_MAX_SIZE
+			}) {
+				let file_path = file_paths_by_id[file_path_id];
+				let path = resolve_materialized_path(
+					&data.location_path,
+					&file_path.materialized_path,
+					file_path.raw_path.as_deref(),
+				);
+				if let Err(e) = keep_copy(&library, &path, &object.cas_id) {
+					error!("Error keeping a copy of {}: {:#?}", path.display(), e);
+				}
+			}
+		}
+
+		// index the contents of archives, so they can be browsed without being extracted
+		for file_path in file_paths.iter().filter(|file_path| {
+			storage.is_none()
+				&& !removed.contains(&file_path.id)
+				&& ArchiveKind::from_path(&file_path.materialized_path).is_some()
+		}) {
+			if let Err(e) = index_archive(&ctx.library_ctx(), &data.location_path, file_path).await
+			{
+				ctx.record_file_error(FileError::new(
+					file_path,
+					Message::Error {
+						text: e.to_string(),
+					},
+				))
+				.await;
+			}
+		}
+
+		// hooks are given where the files appeared, before automation rules move them
+		library
+			.hooks()
+			.fire(
+				&library,
+				linked
+					.keys()
+					.map(|file_path_id| HookEvent::FileAppeared {
+						location_id: data.location.id,
+						path: file_paths_by_id[file_path_id].materialized_path.clone(),
+					})
+					.collect(),
+			)
+			.await;
+
+		// the location's automation rules run last, as they can move the files
+		if let Err(e) =
+			run_automations(&library, &data.location, linked.keys().copied().collect()).await
+		{
+			error!("Error running automation rules: {:#?}", e);
+		}
+
 		// set the step data cursor to the last row of this chunk
 		if let Some(last_row) = file_paths.last() {
 			data.cursor.file_path_id = last_row.id;
@@ -275,11 +599,10 @@ impl StatefulJob for FileIdentifierJob {
 
 		ctx.progress(vec![
 			JobReportUpdate::CompletedTaskCount(state.step_number),
-			JobReportUpdate::Message(format!(
-				"Processed {} of {} orphan Paths",
-				state.step_number * CHUNK_SIZE,
-				data.total_count
-			)),
+			JobReportUpdate::Message(Message::IdentifyingOrphans {
+				processed: state.step_number * CHUNK_SIZE,
+				total: data.total_count,
+			}),
 		]);
 
 		// let _remaining = count_orphan_file_paths(&ctx.core_ctx, location_id.into()).await?;
@@ -288,7 +611,7 @@ impl StatefulJob for FileIdentifierJob {
 
 	async fn finalize(
 		&self,
-		_ctx: WorkerContext,
+		ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> JobResult {
 		let data = state
@@ -301,16 +624,185 @@ impl StatefulJob for FileIdentifierJob {
 			data.task_count
 		);
 
+		// the files now have objects, so their sizes and kinds are known
+		ctx.library_ctx()
+			.statistics
+			.location_changed(data.location.id);
+		let library = ctx.library_ctx();
+		match compute_directory_sizes(&library, &data.location).await {
+			Ok(_) => invalidate_query!(library, "locations.getTreemap"),
+			Err(e) => error!("Failed to compute the directory sizes of location: {e:#?}"),
+		}
+		library.hooks().check_duplicates(&library).await;
+
 		Ok(Some(serde_json::to_value(&state.init)?))
 	}
 }
 
-fn orphan_path_filters(location_id: i32, file_path_id: Option<i32>) -> Vec<file_path::WhereParam> {
+/// remove_duplicates removes file paths whose content the library already has from the location's
+/// index, so they don't show up in it
+async fn remove_duplicates(
+	library: &LibraryContext,
+	location: &location::Data,
+	file_path_ids: &HashSet<i32>,
+) -> Result<(), JobError> {
+	library
+		.db
+		.file_path()
+		.delete_many(vec![
+			file_path::location_id::equals(location.id),
+			file_path::id::in_vec(file_path_ids.iter().copied().collect()),
+		])
+		.exec()
+		.await?;
+	info!(
+		"Left {} files already in the library out of location {}",
+		file_path_ids.len(),
+		location.id
+	);
+
+	let logged = library
+		.sync
+		.is_location_logged(&location.pub_id)
+		.await
+		.unwrap_or(false);
+	if logged {
+		let location_pub_id = uuid_from_pub_id(&location.pub_id);
+		if let Err(e) = library
+			.sync
+			.write_ops(vec![library.sync.owned_delete(
+				FILE_PATH,
+				file_path_ids.iter().map(|id| FilePathId {
+					location: location_pub_id,
+					id: *id,
+				}),
+			)])
+			.await
+		{
+			error!("Error logging removed duplicates for sync: {:#?}", e);
+		}
+	}
+
+	Ok(())
+}
+
+/// decide_duplicates picks up a job that stopped because it was identifying mostly files the library
+/// already has, doing with them what the user decided
+pub async fn decide_duplicates(
+	library: &LibraryContext,
+	jobs: Arc<JobManager>,
+	job_id: Uuid,
+	decision: DuplicateDecision,
+) -> Result<(), JobError> {
+	let mut report = library
+		.db
+		.job()
+		.find_first(vec![
+			job::id::equals(job_id.as_bytes().to_vec()),
+			job::name::equals(IDENTIFIER_JOB_NAME.to_string()),
+			job::status::equals(JobStatus::AwaitingDecision.int_value()),
+		])
+		.exec()
+		.await?
+		.map(JobReport::from)
+		.ok_or(JobError::NotAwaitingDecision(job_id))?;
+
+	if decision == DuplicateDecision::Cancel {
+		report.status = JobStatus::Canceled;
+		report.data = None;
+		report.update(library).await?;
+		invalidate_query!(library, "jobs.getHistory");
+		return Ok(());
+	}
+
+	let mut state: JobState<FileIdentifierJobInit, FileIdentifierJobState, ()> =
+		match report.data.as_deref() {
+			Some(data) => rmp_serde::from_slice(data)?,
+			None => return Err(JobError::MissingJobDataState(report.id, report.name)),
+		};
+	if let Some(data) = state.data.as_mut() {
+		data.duplicates = Some(decision);
+	}
+
+	report.data = Some(rmp_serde::to_vec_named(&state)?);
+	report.status = JobStatus::Paused;
+	report.update(library).await?;
+
+	jobs.resume(library, report).await
+}
+
+/// sync_linked logs the objects file paths were linked to and the links themselves, so the library's
+/// other nodes see them too
+async fn sync_linked(
+	library: &LibraryContext,
+	location: &location::Data,
+	linked: &HashMap<i32, i32>,
+) -> Result<(), SyncError> {
+	let pub_ids = library
+		.sync
+		.ensure_object_pub_ids(
+			&library
+				.db
+				.object()
+				.find_many(vec![object::id::in_vec(linked.values().copied().collect())])
+				.exec()
+				.await?,
+		)
+		.await?;
+
+	if !library.sync.is_location_logged(&location.pub_id).await? {
+		return Ok(());
+	}
+
+	let location_pub_id = uuid_from_pub_id(&location.pub_id);
+	library
+		.sync
+		.write_ops(vec![library.sync.owned_update(
+			FILE_PATH,
+			linked.iter().filter_map(|(file_path_id, object_id)| {
+				let mut data = Map::new();
+				data.insert("object".to_string(), json!(pub_ids.get(object_id)?));
+				Some((
+					FilePathId {
+						location: location_pub_id,
+						id: *file_path_id,
+					},
+					data,
+				))
+			}),
+		)])
+		.await
+}
+
+fn orphan_path_filters(
+	location_id: i32,
+	sub_path: Option<&Path>,
+	file_path_id: Option<i32>,
+	skip_git_internals: bool,
+) -> Vec<file_path::WhereParam> {
 	let mut params = vec![
 		file_path::object_id::equals(None),
 		file_path::is_dir::equals(false),
 		file_path::location_id::equals(location_id),
 	];
+	// only the files under the sub path are identified when there's one
+	if let Some(sub_path) = sub_path.filter(|sub_path| !sub_path.as_os_str().is_empty()) {
+		params.push(file_path::materialized_path::starts_with(format!(
+			"{}/",
+			sub_path.to_string_lossy()
+		)));
+	}
+	// the files of `.git` directories stay unidentified, they're git's objects rather than the user's
+	if skip_git_internals {
+		params.extend([
+			file_path::WhereParam::Not(vec![file_path::materialized_path::starts_with(format!(
+				"{GIT_DIR_NAME}/"
+			))]),
+			file_path::WhereParam::Not(vec![file_path::materialized_path::contains(format!(
+				"/{GIT_DIR_NAME}/"
+			))]),
+		]);
+	}
 	// this is a workaround for the cursor not working properly
 	if let Some(file_path_id) = file_path_id {
 		params.push(file_path::id::gte(file_path_id))
@@ -326,15 +818,18 @@ struct CountRes {
 async fn count_orphan_file_paths(
 	ctx: &LibraryContext,
 	location_id: i32,
+	sub_path: Option<&Path>,
+	skip_git_internals: bool,
 ) -> Result<usize, prisma_client_rust::QueryError> {
 	let files_count = ctx
 		.db
 		.file_path()
-		.count(vec![
-			file_path::object_id::equals(None),
-			file_path::is_dir::equals(false),
-			file_path::location_id::equals(location_id),
-		])
+		.count(orphan_path_filters(
+			location_id,
+			sub_path,
+			None,
+			skip_git_internals,
+		))
 		.exec()
 		.await?;
 	// Is this
@@ -345,6 +840,8 @@ async fn get_orphan_file_paths(
 	ctx: &LibraryContext,
 	cursor: &FilePathIdAndLocationIdCursor,
 	location_id: i32,
+	sub_path: Option<&Path>,
+	skip_git_internals: bool,
 ) -> Result<Vec<file_path::Data>, prisma_client_rust::QueryError> {
 	info!(
 		"Querying {} orphan Paths at cursor: {:?}",
@@ -352,7 +849,12 @@ async fn get_orphan_file_paths(
 	);
 	ctx.db
 		.file_path()
-		.find_many(orphan_path_filters(location_id, Some(cursor.file_path_id)))
+		.find_many(orphan_path_filters(
+			location_id,
+			sub_path,
+			Some(cursor.file_path_id),
+			skip_git_internals,
+		))
 		.order_by(file_path::id::order(Direction::Asc))
 		// .cursor(cursor.into())
 		.take(CHUNK_SIZE as i64)
@@ -367,6 +869,8 @@ struct CreateObject {
 	pub size_in_bytes: i64,
 	pub date_created: DateTime<FixedOffset>,
 	pub kind: ObjectKind,
+	/// the cas_id was made from the file's size and path, see [`path_cas_id`]
+	pub identified_by_path: bool,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -375,18 +879,89 @@ struct FileCreated {
 	pub cas_id: String,
 }
 
+/// group_by_cas_id gathers the file_paths of a chunk by cas_id, as files with the same contents
+/// share one object
+fn group_by_cas_id(chunk: &HashMap<i32, CreateObject>) -> HashMap<String, Vec<i32>> {
+	let mut cas_lookup: HashMap<String, Vec<i32>> = HashMap::new();
+	for (file_path_id, object) in chunk {
+		cas_lookup
+			.entry(object.cas_id.clone())
+			.or_default()
+			.push(*file_path_id);
+	}
+	for file_path_ids in cas_lookup.values_mut() {
+		file_path_ids.sort_unstable();
+	}
+	cas_lookup
+}
+
+/// link_by_cas_id links every file_path of a cas_id to its object, given by cas_id and id, but the
+/// ones in `skipped`
+fn link_by_cas_id<'a>(
+	cas_lookup: &HashMap<String, Vec<i32>>,
+	objects: impl IntoIterator<Item = (&'a str, i32)>,
+	skipped: &HashSet<i32>,
+	linked: &mut HashMap<i32, i32>,
+) {
+	for (cas_id, object_id) in objects {
+		for file_path_id in cas_lookup.get(cas_id).into_iter().flatten() {
+			if !skipped.contains(file_path_id) {
+				linked.insert(*file_path_id, object_id);
+			}
+		}
+	}
+}
+
+/// kind_from_extension is the kind of a file going by its extension alone, for files that aren't
+/// read
+fn kind_from_extension(extension: Option<&str>) -> ObjectKind {
+	match extension.and_then(Extension::from_str) {
+		Some(ExtensionPossibility::Known(ext)) => ext.into(),
+		_ => ObjectKind::Unknown,
+	}
+}
+
+/// identify_by_path makes the object of a file that the location's settings have identified by its
+/// size and path, without reading it
+fn identify_by_path(
+	location_pub_id: &[u8],
+	file_path: &file_path::Data,
+	size: u64,
+) -> CreateObject {
+	let mut cas_id = path_cas_id(location_pub_id, &file_path.materialized_path, size);
+	cas_id.truncate(16);
+
+	CreateObject {
+		cas_id,
+		size_in_bytes: size as i64,
+		date_created: file_path.date_created,
+		kind: kind_from_extension(file_path.extension.as_deref()),
+		identified_by_path: true,
+	}
+}
+
 async fn assemble_object_metadata(
 	location_path: impl AsRef<Path>,
 	file_path: &file_path::Data,
+	settings: &LocationSettings,
+	location_pub_id: &[u8],
 ) -> Result<CreateObject, io::Error> {
-	let path = location_path
-		.as_ref()
-		.join(file_path.materialized_path.as_str());
+	let path = resolve_materialized_path(
+		location_path,
+		&file_path.materialized_path,
+		file_path.raw_path.as_deref(),
+	);
 
 	info!("Reading path: {:?}", path);
 
 	let metadata = fs::metadata(&path).await?;
 
+	if !file_path.is_dir
+		&& settings.identifies_by_path(metadata.len(), file_path.extension.as_deref())
+	{
+		return Ok(identify_by_path(location_pub_id, file_path, metadata.len()));
+	}
+
 	// derive Object kind
 	let object_kind: ObjectKind = match path.extension() {
 		Some(ext) => match ext.to_str() {
@@ -418,5 +993,197 @@ async fn assemble_object_metadata(
 		size_in_bytes: size as i64,
 		date_created: file_path.date_created,
 		kind: object_kind,
+		identified_by_path: false,
 	})
 }
+
+/// identify_small_files identifies the files among `files`, given by file path id, path and date of
+/// creation, that are small enough to be hashed whole. Each is opened once and read in one go, instead
+/// of being stat'ed and opened again for its kind and its samples. Files that turn out to be bigger are
+/// left out, for [`assemble_object_metadata`] to identify, as are files over `max_size` bytes and the
+/// ones after `batch_bytes` were read. The files are read one after the other into the same buffer.
+fn identify_small_files(
+	files: impl IntoIterator<Item = (i32, PathBuf, DateTime<FixedOffset>)>,
+	max_size: Option<u64>,
+	batch_bytes: u64,
+) -> HashMap<i32, Result<CreateObject, io::Error>> {
+	let mut contents = Vec::new();
+	let mut read = 0;
+
+	files
+		.into_iter()
+		.filter_map(|(file_path_id, path, date_created)| {
+			if read >= batch_bytes {
+				return None;
+			}
+
+			let mut identify = || -> Result<Option<CreateObject>, io::Error> {
+				let mut file = std::fs::File::open(&path)?;
+				let size = file.metadata()?.len();
+				if !is_small_file(size) || max_size.map_or(false, |max_size| size > max_size) {
+					return Ok(None);
+				}
+
+				let kind = match path.extension().and_then(|ext| ext.to_str()) {
+					Some(ext) => Extension::resolve_conflicting(ext, &mut file, true)
+						.map(Into::into)
+						.unwrap_or(ObjectKind::Unknown),
+					None => ObjectKind::Unknown,
+				};
+
+				contents.clear();
+				file.seek(SeekFrom::Start(0))?;
+				file.take(size).read_to_end(&mut contents)?;
+				read += contents.len() as u64;
+
+				let mut cas_id = small_file_cas_id(&contents);
+				cas_id.truncate(16);
+
+				Ok(Some(CreateObject {
+					cas_id,
+					size_in_bytes: contents.len() as i64,
+					date_created,
+					kind,
+					identified_by_path: false,
+				}))
+			};
+
+			identify().transpose().map(|object| (file_path_id, object))
+		})
+		.collect()
+}
+
+/// assemble_storage_object_metadata is [`assemble_object_metadata`] for files in object storage,
+/// where the kind can only come from the extension as magic bytes would need a download
+async fn assemble_storage_object_metadata(
+	storage: &dyn Storage,
+	file_path: &file_path::Data,
+	settings: &LocationSettings,
+	location_pub_id: &[u8],
+) -> Result<CreateObject, StorageError> {
+	let entry = storage.stat(&file_path.materialized_path).await?;
+
+	if settings.identifies_by_path(entry.size, file_path.extension.as_deref()) {
+		return Ok(identify_by_path(location_pub_id, file_path, entry.size));
+	}
+
+	let mut cas_id = storage_cas_id(storage, &entry).await?;
+	cas_id.truncate(16);
+
+	Ok(CreateObject {
+		cas_id,
+		size_in_bytes: entry.size as i64,
+		date_created: file_path.date_created,
+		kind: kind_from_extension(file_path.extension.as_deref()),
+		identified_by_path: false,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+	use std::time::Instant;
+
+	#[test]
+	fn links_files_with_the_same_contents_to_one_object() {
+		let dir = tempfile::tempdir().unwrap();
+		let files = ["a.txt", "b.txt", "c.txt"]
+			.iter()
+			.zip(["same", "same", "other"])
+			.enumerate()
+			.map(|(i, (name, contents))| {
+				let path = dir.path().join(name);
+				std::fs::write(&path, contents).unwrap();
+				(i as i32, path, Utc::now().into())
+			})
+			.collect::<Vec<(i32, PathBuf, DateTime<FixedOffset>)>>();
+
+		let chunk = identify_small_files(files, None, SMALL_FILES_BATCH_BYTES)
+			.into_iter()
+			.map(|(file_path_id, object)| (file_path_id, object.unwrap()))
+			.collect::<HashMap<_, _>>();
+		let cas_lookup = group_by_cas_id(&chunk);
+		assert_eq!(cas_lookup.len(), 2);
+		assert_eq!(cas_lookup[&chunk[&0].cas_id], vec![0, 1]);
+
+		// the one object made for the contents, as the chunk only creates one per cas_id
+		let mut linked = HashMap::new();
+		link_by_cas_id(
+			&cas_lookup,
+			[
+				(chunk[&0].cas_id.as_str(), 10),
+				(chunk[&2].cas_id.as_str(), 11),
+			],
+			&HashSet::new(),
+			&mut linked,
+		);
+		assert_eq!(linked, HashMap::from([(0, 10), (1, 10), (2, 11)]));
+	}
+
+	#[test]
+	fn reads_small_files_up_to_the_batch_size() {
+		let dir = tempfile::tempdir().unwrap();
+		let files = (0..4)
+			.map(|i| {
+				let path = dir.path().join(format!("{i}.txt"));
+				std::fs::write(&path, [b'a' + i as u8; 100]).unwrap();
+				(i, path, Utc::now().into())
+			})
+			.collect::<Vec<(i32, PathBuf, DateTime<FixedOffset>)>>();
+
+		// the batch stops once it read 150 bytes, the last files are identified one at a time
+		let objects = identify_small_files(files, None, 150);
+		assert_eq!(objects.len(), 2);
+		assert!(objects.contains_key(&0) && objects.contains_key(&1));
+		assert_ne!(
+			objects[&0].as_ref().unwrap().cas_id,
+			objects[&1].as_ref().unwrap().cas_id
+		);
+	}
+
+	/// Benchmark of identifying a maildir of tiny files, run with
+	/// `cargo test --release -p sd-core tiny_files_throughput -- --ignored --nocapture`
+	#[tokio::test(flavor = "multi_thread")]
+	#[ignore]
+	async fn tiny_files_throughput() {
+		const FILES: usize = 20_000;
+
+		let dir = tempfile::tempdir().unwrap();
+		let files = (0..FILES)
+			.map(|i| {
+				let path = dir.path().join(format!("{i}.eml"));
+				std::fs::write(&path, format!("Subject: message {i}\r\n\r\nhello\r\n")).unwrap();
+				(i as i32, path, Utc::now().into())
+			})
+			.collect::<Vec<(i32, PathBuf, DateTime<FixedOffset>)>>();
+
+		// one file at a time, as files bigger than the samples are identified
+		let start = Instant::now();
+		for (_, path, _) in &files {
+			let size = fs::metadata(path).await.unwrap().len();
+			let mut file = std::fs::File::open(path).unwrap();
+			Extension::resolve_conflicting("eml", &mut file, true);
+			generate_cas_id(path.clone(), size).await.unwrap();
+		}
+		let one_by_one = start.elapsed();
+
+		let start = Instant::now();
+		for chunk in files.chunks(CHUNK_SIZE) {
+			let objects = block_in_place(|| {
+				identify_small_files(chunk.iter().cloned(), None, SMALL_FILES_BATCH_BYTES)
+			});
+			assert_eq!(objects.len(), chunk.len());
+		}
+		let batched = start.elapsed();
+
+		let speedup = one_by_one.as_secs_f64() / batched.as_secs_f64();
+		println!(
+			"{FILES} tiny files: {one_by_one:?} one by one, {batched:?} batched, {speedup:.1}x"
+		);
+		assert!(
+			speedup >= 5.0,
+			"batching tiny files is only {speedup:.1}x faster"
+		);
+	}
+}
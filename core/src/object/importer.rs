@@ -0,0 +1,589 @@
+//! Tags and metadata other tools left on the files of a location, imported once so they carry over
+//! into the library: the tags file managers keep in extended attributes (Finder tags on macOS), the
+//! title, comment, keywords and rating Windows Explorer writes into the EXIF of photos, and XMP
+//! sidecars like the ones Lightroom and darktable keep next to photos. Keywords become tags of the
+//! files' objects and the rest goes into metadata fields of the location, leaving alone the values
+//! already set in the library. The metadata extractors of extensions are run on the files too.
+
+use std::{
+	ffi::OsString,
+	fs::{self, File},
+	io::BufReader,
+	path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use quick_xml::{
+	events::{BytesStart, Event},
+	Reader,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::info;
+
+use crate::{
+	extension::{ExtensionContext, ExtractedField},
+	invalidate_query,
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	location::{
+		custom_metadata::{ensure_field, set_if_unset, MetadataError, MetadataFieldKind},
+		LocationError,
+	},
+	prisma::{file_path, location},
+	sync::SyncError,
+	util::{message::Message, os_path::resolve_materialized_path},
+};
+
+use super::attributes::{tag_object, FileAttributes};
+
+pub const IMPORT_METADATA_JOB_NAME: &str = "metadata_importer";
+
+/// Files Windows Explorer writes its properties into, as EXIF
+const EXIF_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "tif", "tiff", "heic", "heif", "png", "webp"];
+
+/// The EXIF tags Windows Explorer keeps its properties in, the text ones as UTF-16
+const XP_TITLE: u16 = 0x9c9b;
+const XP_COMMENT: u16 = 0x9c9c;
+const XP_KEYWORDS: u16 = 0x9c9e;
+const XP_SUBJECT: u16 = 0x9c9f;
+const RATING: u16 = 0x4746;
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("Metadata error: {0}")]
+	MetadataError(#[from] MetadataError),
+	#[error("Sync error: {0}")]
+	SyncError(#[from] SyncError),
+}
+
+/// What was found on a file, to be imported
+#[derive(Debug, Default, PartialEq)]
+struct ImportedMetadata {
+	tags: Vec<(String, Option<&'static str>)>,
+	title: Option<String>,
+	description: Option<String>,
+	comment: Option<String>,
+	/// out of 5, unrated files don't have one
+	rating: Option<u8>,
+}
+
+impl ImportedMetadata {
+	fn is_empty(&self) -> bool {
+		*self == Self::default()
+	}
+
+	/// merge adds what `other` found that isn't known yet, the first source to have a value wins
+	fn merge(&mut self, other: Self) {
+		for (name, color) in other.tags {
+			if !self
+				.tags
+				.iter()
+				.any(|(known, _)| known.eq_ignore_ascii_case(&name))
+			{
+				self.tags.push((name, color));
+			}
+		}
+		self.title = self.title.take().or(other.title);
+		self.description = self.description.take().or(other.description);
+		self.comment = self.comment.take().or(other.comment);
+		self.rating = self.rating.or(other.rating);
+	}
+
+	/// fields returns the metadata fields the values go in, with the kind they're made with
+	fn fields(&self) -> Vec<(&'static str, MetadataFieldKind, String)> {
+		[
+			("Title", &self.title),
+			("Description", &self.description),
+			("Comment", &self.comment),
+		]
+		.into_iter()
+		.filter_map(|(name, value)| Some((name, MetadataFieldKind::Text, value.clone()?)))
+		.chain(
+			self.rating
+				.map(|rating| ("Rating", MetadataFieldKind::Number, rating.to_string())),
+		)
+		.collect()
+	}
+}
+
+/// non_empty trims text and drops it when nothing is left
+fn non_empty(text: &str) -> Option<String> {
+	let text = text.trim();
+	(!text.is_empty()).then(|| text.to_string())
+}
+
+/// decode_xp reads the text of a Windows property, UTF-16 ending with a null
+fn decode_xp(bytes: &[u8]) -> Option<String> {
+	let units = bytes
+		.chunks_exact(2)
+		.map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+		.collect::<Vec<_>>();
+	non_empty(String::from_utf16_lossy(&units).trim_end_matches('\0'))
+}
+
+/// windows_properties reads the properties Windows Explorer writes into the EXIF of photos
+fn windows_properties(path: &Path) -> ImportedMetadata {
+	let exif = match File::open(path).ok().and_then(|file| {
+		exif::Reader::new()
+			.read_from_container(&mut BufReader::new(file))
+			.ok()
+	}) {
+		Some(exif) => exif,
+		None => return ImportedMetadata::default(),
+	};
+
+	let field = |tag| {
+		exif.get_field(exif::Tag(exif::Context::Tiff, tag), exif::In::PRIMARY)
+			.map(|field| &field.value)
+	};
+	let text = |tag| match field(tag) {
+		Some(exif::Value::Byte(bytes)) => decode_xp(bytes),
+		_ => None,
+	};
+
+	ImportedMetadata {
+		tags: text(XP_KEYWORDS)
+			.map(|keywords| {
+				keywords
+					.split(';')
+					.filter_map(non_empty)
+					.map(|keyword| (keyword, None))
+					.collect()
+			})
+			.unwrap_or_default(),
+		title: text(XP_TITLE),
+		description: text(XP_SUBJECT),
+		comment: text(XP_COMMENT),
+		rating: match field(RATING) {
+			Some(exif::Value::Short(rating)) => rating
+				.first()
+				.map(|rating| *rating as u8)
+				.filter(|rating| (1..=5).contains(rating)),
+			_ => None,
+		},
+	}
+}
+
+/// sidecar_path finds the XMP sidecar of a file, either `photo.xmp` like Lightroom names them or
+/// `photo.jpg.xmp` like darktable does
+fn sidecar_path(path: &Path) -> Option<PathBuf> {
+	let mut appended = OsString::from(path.as_os_str());
+	appended.push(".xmp");
+
+	[
+		path.with_extension("xmp"),
+		path.with_extension("XMP"),
+		PathBuf::from(appended),
+	]
+	.into_iter()
+	.find(|sidecar| sidecar.is_file())
+}
+
+/// parse_xmp reads the keywords, title, description and rating out of an XMP packet. Properties
+/// are written either as elements or, for the simple ones, as attributes of their description.
+fn parse_xmp(xmp: &str) -> ImportedMetadata {
+	let mut imported = ImportedMetadata::default();
+	let mut reader = Reader::from_str(xmp);
+	reader.trim_text(true);
+
+	let rating_attribute = |element: &BytesStart, reader: &Reader<&[u8]>| {
+		element
+			.attributes()
+			.flatten()
+			.find(|attribute| attribute.key == b"xmp:Rating")
+			.and_then(|attribute| attribute.unescape_and_decode_value(reader).ok())
+	};
+
+	// the elements the current one is in, values belonging to the innermost that isn't an `rdf:`
+	// container of them
+	let mut properties: Vec<Vec<u8>> = vec![];
+	let mut buf = vec![];
+	loop {
+		let text = match reader.read_event(&mut buf) {
+			Ok(Event::Start(element)) => {
+				if let Some(rating) = rating_attribute(&element, &reader) {
+					imported.rating = imported.rating.or_else(|| parse_rating(&rating));
+				}
+				properties.push(element.name().to_vec());
+				None
+			}
+			Ok(Event::Empty(element)) => {
+				if let Some(rating) = rating_attribute(&element, &reader) {
+					imported.rating = imported.rating.or_else(|| parse_rating(&rating));
+				}
+				None
+			}
+			Ok(Event::End(_)) => {
+				properties.pop();
+				None
+			}
+			Ok(Event::Text(text)) => text.unescape_and_decode(&reader).ok(),
+			Ok(Event::Eof) | Err(_) => break,
+			_ => None,
+		};
+		buf.clear();
+
+		let text = match text.as_deref().and_then(non_empty) {
+			Some(text) => text,
+			None => continue,
+		};
+		match properties
+			.iter()
+			.rev()
+			.find(|name| !name.starts_with(b"rdf:"))
+			.map(Vec::as_slice)
+		{
+			Some(b"dc:subject") => imported.merge(ImportedMetadata {
+				tags: vec![(text, None)],
+				..Default::default()
+			}),
+			Some(b"dc:title") => {
+				imported.title.get_or_insert(text);
+			}
+			Some(b"dc:description") => {
+				imported.description.get_or_insert(text);
+			}
+			Some(b"xmp:Rating") => {
+				imported.rating = imported.rating.or_else(|| parse_rating(&text))
+			}
+			_ => {}
+		}
+	}
+
+	imported
+}
+
+/// parse_rating reads an XMP rating, where 0 is unrated and -1 rejected
+fn parse_rating(rating: &str) -> Option<u8> {
+	rating
+		.trim()
+		.parse::<f64>()
+		.ok()
+		.filter(|rating| *rating >= 1.0)
+		.map(|rating| rating.min(5.0).round() as u8)
+}
+
+/// read_imported reads what the other tools left on a file, the sidecar first as it's what photo
+/// tools keep up to date, then what's in the file and the tags captured when it was indexed
+fn read_imported(path: &Path, file_path: &file_path::Data) -> ImportedMetadata {
+	let mut imported = sidecar_path(path)
+		.and_then(|sidecar| fs::read_to_string(sidecar).ok())
+		.map(|xmp| parse_xmp(&xmp))
+		.unwrap_or_default();
+
+	if file_path.extension.as_deref().map_or(false, |extension| {
+		EXIF_EXTENSIONS.contains(&extension.to_lowercase().as_str())
+	}) {
+		imported.merge(windows_properties(path));
+	}
+
+	imported.merge(ImportedMetadata {
+		tags: FileAttributes::from_file_path(file_path).tags(),
+		..Default::default()
+	});
+
+	imported
+}
+
+/// The `ImportMetadataJob` imports the tags and metadata other tools left on a location's files. It
+/// runs once per location, later runs only import again when forced to.
+pub struct ImportMetadataJob;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportMetadataJobInit {
+	pub location_id: i32,
+	/// import again into a location that was imported before
+	pub force: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ImportMetadataJobState {
+	root_path: PathBuf,
+	/// files something was imported from
+	files: usize,
+	tags: usize,
+	values: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportMetadataJobStep {
+	file_path_id: i32,
+}
+
+/// extract_with_extensions reads a file with the metadata extractors of the enabled extensions,
+/// recording the errors they run into as the file's
+async fn extract_with_extensions(
+	ctx: &WorkerContext,
+	file_path: &file_path::Data,
+	path: &Path,
+) -> Vec<ExtractedField> {
+	let library = ctx.library_ctx();
+	let extractors = match &file_path.extension {
+		Some(extension) => library
+			.extensions()
+			.extractors(&library.config().get().await.extensions, extension),
+		None => return vec![],
+	};
+
+	let mut fields = vec![];
+	for (extension_id, extractor) in extractors {
+		let extracted = match ExtensionContext::new(&library, &extension_id).await {
+			Ok(extension_ctx) => extractor.extract(&extension_ctx, path).await,
+			Err(e) => Err(e),
+		};
+
+		match extracted {
+			Ok(extracted) => fields.extend(extracted),
+			Err(e) => {
+				ctx.record_file_error(FileError::new(
+					file_path,
+					Message::Error {
+						text: format!("{} of {extension_id}: {e}", extractor.name()),
+					},
+				))
+				.await
+			}
+		}
+	}
+	fields
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ImportMetadataJob {
+	type Init = ImportMetadataJobInit;
+	type Data = ImportMetadataJobState;
+	type Step = ImportMetadataJobStep;
+
+	fn name(&self) -> &'static str {
+		IMPORT_METADATA_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let location_id = state.init.location_id;
+
+		let location = library
+			.db
+			.location()
+			.find_unique(location::id::equals(location_id))
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(location_id))?;
+		let root_path = location
+			.local_path
+			.map(PathBuf::from)
+			.ok_or(LocationError::MissingLocalPath(location_id))?;
+
+		state.data = Some(ImportMetadataJobState {
+			root_path,
+			..Default::default()
+		});
+
+		if location.date_metadata_imported.is_some() && !state.init.force {
+			info!("Metadata of location {location_id} was already imported, skipping");
+			return Ok(());
+		}
+
+		// sidecars are read along with the files they belong to
+		state.steps = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(location_id),
+				file_path::is_dir::equals(false),
+				file_path::extension::not(Some("xmp".to_string())),
+			])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|file_path| ImportMetadataJobStep {
+				file_path_id: file_path.id,
+			})
+			.collect();
+
+		info!(
+			"Importing metadata of {} files in location {location_id}",
+			state.steps.len()
+		);
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let location_id = state.init.location_id;
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		let file_path = match library
+			.db
+			.file_path()
+			.find_unique(file_path::location_id_id(location_id, step.file_path_id))
+			.with(file_path::object::fetch())
+			.exec()
+			.await?
+		{
+			Some(file_path) => file_path,
+			None => return Ok(()),
+		};
+
+		let path = resolve_materialized_path(
+			&data.root_path,
+			&file_path.materialized_path,
+			file_path.raw_path.as_deref(),
+		);
+		let imported = block_in_place(|| read_imported(&path, &file_path));
+		let extracted = extract_with_extensions(&ctx, &file_path, &path).await;
+		if imported.is_empty() && extracted.is_empty() {
+			return Ok(());
+		}
+
+		// files not identified yet don't have an object to tag
+		if let Ok(Some(object)) = file_path.object() {
+			for (name, color) in &imported.tags {
+				if tag_object(&library, object, name, *color)
+					.await
+					.map_err(ImportError::from)?
+				{
+					data.tags += 1;
+				}
+			}
+		}
+
+		let fields = imported
+			.fields()
+			.into_iter()
+			.map(|(name, kind, value)| (name.to_string(), kind, value))
+			.chain(
+				extracted
+					.into_iter()
+					.map(|field| (field.name, field.kind, field.value)),
+			);
+		for (name, kind, value) in fields {
+			let field = ensure_field(&library, location_id, &name, kind)
+				.await
+				.map_err(ImportError::from)?;
+			if set_if_unset(&library, &field, file_path.id, &value)
+				.await
+				.map_err(ImportError::from)?
+			{
+				data.values += 1;
+			}
+		}
+
+		data.files += 1;
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		library
+			.db
+			.location()
+			.update(
+				location::id::equals(state.init.location_id),
+				vec![location::date_metadata_imported::set(Some(
+					Utc::now().into(),
+				))],
+			)
+			.exec()
+			.await?;
+
+		info!(
+			"Imported metadata of {} files in location {}: {} tags and {} values",
+			data.files, state.init.location_id, data.tags, data.values
+		);
+
+		invalidate_query!(library, "tags.list");
+		invalidate_query!(library, "tags.getForObject");
+		invalidate_query!(library, "locations.metadata.listFields");
+		invalidate_query!(library, "locations.metadata.get");
+
+		Ok(Some(json!({
+			"location_id": state.init.location_id,
+			"files": data.files,
+			"tags": data.tags,
+			"values": data.values,
+		})))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_xmp_sidecars() {
+		let imported = parse_xmp(
+			r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+				<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+					<rdf:Description xmp:Rating="4">
+						<dc:subject>
+							<rdf:Bag>
+								<rdf:li>beach</rdf:li>
+								<rdf:li>Family</rdf:li>
+							</rdf:Bag>
+						</dc:subject>
+						<dc:title>
+							<rdf:Alt>
+								<rdf:li xml:lang="x-default">Summer &amp; sun</rdf:li>
+							</rdf:Alt>
+						</dc:title>
+					</rdf:Description>
+				</rdf:RDF>
+			</x:xmpmeta>"#,
+		);
+
+		assert_eq!(
+			imported,
+			ImportedMetadata {
+				tags: vec![("beach".to_string(), None), ("Family".to_string(), None)],
+				title: Some("Summer & sun".to_string()),
+				rating: Some(4),
+				..Default::default()
+			}
+		);
+		assert_eq!(parse_rating("-1"), None);
+		assert_eq!(parse_rating("0"), None);
+	}
+
+	#[test]
+	fn decodes_windows_properties() {
+		let keywords = "beach;family\0"
+			.encode_utf16()
+			.flat_map(u16::to_le_bytes)
+			.collect::<Vec<_>>();
+		assert_eq!(decode_xp(&keywords).as_deref(), Some("beach;family"));
+		assert_eq!(decode_xp(&[0, 0]), None);
+	}
+}
@@ -0,0 +1,573 @@
+//! Labels for images, found on this device by an image classification model so no image ever leaves
+//! it. The model is `models/labeler.onnx` in the data directory, an ONNX model taking a 224x224 RGB
+//! image normalized like ImageNet, with `models/labeler.txt` naming the label of each of its classes,
+//! one per line. Classes can share a label, so the hundred dog breeds a model knows all tag "dog", and
+//! classes on a blank line aren't labeled. Labels are kept as system tags, which are listed and
+//! searched like any other tag. Nodes built without the `ml` feature can't label images.
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use image::DynamicImage;
+use once_cell::sync::OnceCell;
+use sd_file_ext::extensions::Extension;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::LocationError,
+	prisma::{file_path, location, object, tag, tag_on_object},
+	sync::{
+		models::{uuid_from_pub_id, TagData, TAG, TAG_ON_OBJECT},
+		SyncError,
+	},
+	util::os_path::resolve_materialized_path,
+};
+
+use super::preview::THUMBNAIL_IMAGE_EXTENSIONS;
+
+pub const IMAGE_LABELER_JOB_NAME: &str = "image_labeler";
+pub static MODELS_DIR_NAME: &str = "models";
+#[cfg(feature = "ml")]
+const MODEL_FILE_NAME: &str = "labeler.onnx";
+#[cfg(feature = "ml")]
+const LABELS_FILE_NAME: &str = "labeler.txt";
+/// Size of the side of the square images are shrunk to for the model
+#[cfg(feature = "ml")]
+const INPUT_SIZE: u32 = 224;
+/// The mean and standard deviation of each channel the model was trained with
+#[cfg(feature = "ml")]
+const INPUT_MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+#[cfg(feature = "ml")]
+const INPUT_STD: [f32; 3] = [0.229, 0.224, 0.225];
+/// How sure the model has to be of a label to tag an image with it when not asked for
+const DEFAULT_MIN_CONFIDENCE: f32 = 0.3;
+/// Most labels an image is tagged with
+#[cfg(any(feature = "ml", test))]
+const MAX_LABELS: usize = 5;
+
+/// The labeler is loaded once, the first time images are labeled
+static LABELER: OnceCell<Labeler> = OnceCell::new();
+
+#[derive(Error, Debug)]
+pub enum LabelerError {
+	#[error("this node was built without image labeling")]
+	NotAvailable,
+	#[error("no labeling model at {0}")]
+	ModelNotFound(PathBuf),
+	#[error("the labeling model has {classes} classes but {labels} labels")]
+	LabelCount { classes: usize, labels: usize },
+	#[error("labeling model error: {0}")]
+	Model(String),
+	#[error("I/O error: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("database error: {0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+	#[error("sync error: {0}")]
+	Sync(#[from] SyncError),
+}
+
+/// An ONNX model ready to run
+#[cfg(feature = "ml")]
+pub(super) type OnnxModel =
+	tract_onnx::prelude::TypedRunnableModel<tract_onnx::prelude::TypedModel>;
+
+/// load_onnx loads an ONNX model taking a single RGB image of the given size
+#[cfg(feature = "ml")]
+pub(super) fn load_onnx(
+	path: &Path,
+	width: u32,
+	height: u32,
+) -> tract_onnx::prelude::TractResult<OnnxModel> {
+	use tract_onnx::prelude::*;
+
+	tract_onnx::onnx()
+		.model_for_path(path)?
+		.with_input_fact(0, f32::fact([1, 3, height as usize, width as usize]).into())?
+		.into_optimized()?
+		.into_runnable()
+}
+
+/// run_onnx runs a model on an image, scaling each channel from 0 to 1 before normalizing it with its
+/// mean and standard deviation, and returns each of the model's outputs flattened
+#[cfg(feature = "ml")]
+pub(super) fn run_onnx(
+	model: &OnnxModel,
+	img: &image::RgbImage,
+	mean: [f32; 3],
+	std: [f32; 3],
+) -> tract_onnx::prelude::TractResult<Vec<Vec<f32>>> {
+	use tract_onnx::prelude::*;
+
+	let input: Tensor = tract_ndarray::Array4::from_shape_fn(
+		(1, 3, img.height() as usize, img.width() as usize),
+		|(_, channel, y, x)| {
+			let value = img.get_pixel(x as u32, y as u32)[channel] as f32 / 255.0;
+			(value - mean[channel]) / std[channel]
+		},
+	)
+	.into();
+
+	model
+		.run(tvec!(input))?
+		.iter()
+		.map(|output| Ok(output.to_array_view::<f32>()?.iter().copied().collect()))
+		.collect()
+}
+
+/// Labeler runs the labeling model
+pub struct Labeler {
+	#[cfg(feature = "ml")]
+	model: OnnxModel,
+	#[cfg(feature = "ml")]
+	labels: Vec<Option<String>>,
+}
+
+impl Labeler {
+	/// get returns the labeler of the node, loading it the first time
+	pub fn get(data_dir: &Path) -> Result<&'static Self, LabelerError> {
+		LABELER.get_or_try_init(|| block_in_place(|| Self::load(&data_dir.join(MODELS_DIR_NAME))))
+	}
+
+	#[cfg(feature = "ml")]
+	fn load(models_dir: &Path) -> Result<Self, LabelerError> {
+		let model_path = models_dir.join(MODEL_FILE_NAME);
+		let labels_path = models_dir.join(LABELS_FILE_NAME);
+		for path in [&model_path, &labels_path] {
+			if !path.exists() {
+				return Err(LabelerError::ModelNotFound(path.clone()));
+			}
+		}
+
+		let labels = parse_labels(&std::fs::read_to_string(labels_path)?);
+		let model = load_onnx(&model_path, INPUT_SIZE, INPUT_SIZE)
+			.map_err(|e| LabelerError::Model(e.to_string()))?;
+
+		info!("Loaded labeling model with {} labels", labels.len());
+
+		Ok(Self { model, labels })
+	}
+
+	#[cfg(not(feature = "ml"))]
+	fn load(_: &Path) -> Result<Self, LabelerError> {
+		Err(LabelerError::NotAvailable)
+	}
+
+	/// label returns the labels of an image the model is at least `min_confidence` sure of, the
+	/// surest first
+	#[cfg(feature = "ml")]
+	pub fn label(
+		&self,
+		img: &DynamicImage,
+		min_confidence: f32,
+	) -> Result<Vec<(String, f32)>, LabelerError> {
+		let img = img
+			.resize_exact(
+				INPUT_SIZE,
+				INPUT_SIZE,
+				image::imageops::FilterType::Triangle,
+			)
+			.to_rgb8();
+		let scores = run_onnx(&self.model, &img, INPUT_MEAN, INPUT_STD)
+			.map_err(|e| LabelerError::Model(e.to_string()))?
+			.swap_remove(0);
+		if scores.len() != self.labels.len() {
+			return Err(LabelerError::LabelCount {
+				classes: scores.len(),
+				labels: self.labels.len(),
+			});
+		}
+
+		Ok(pick_labels(
+			&probabilities(scores),
+			&self.labels,
+			min_confidence,
+		))
+	}
+
+	#[cfg(not(feature = "ml"))]
+	pub fn label(&self, _: &DynamicImage, _: f32) -> Result<Vec<(String, f32)>, LabelerError> {
+		Err(LabelerError::NotAvailable)
+	}
+}
+
+/// parse_labels reads the label of each class of the model, a blank line for classes that aren't
+/// labeled
+#[cfg(any(feature = "ml", test))]
+fn parse_labels(text: &str) -> Vec<Option<String>> {
+	text.lines()
+		.map(|line| {
+			let label = line.trim().to_lowercase();
+			(!label.is_empty()).then_some(label)
+		})
+		.collect()
+}
+
+/// probabilities turns the scores of a model into probabilities, unless they already are
+#[cfg(any(feature = "ml", test))]
+fn probabilities(scores: Vec<f32>) -> Vec<f32> {
+	let sum = scores.iter().sum::<f32>();
+	if scores.iter().all(|score| (0.0..=1.0).contains(score)) && (sum - 1.0).abs() < 0.01 {
+		return scores;
+	}
+
+	let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+	let exps = scores
+		.iter()
+		.map(|score| (score - max).exp())
+		.collect::<Vec<_>>();
+	let sum = exps.iter().sum::<f32>();
+	exps.into_iter().map(|exp| exp / sum).collect()
+}
+
+/// pick_labels adds up the probabilities of the classes of each label, and returns the labels that
+/// reach `min_confidence`, the surest first
+#[cfg(any(feature = "ml", test))]
+fn pick_labels(
+	probabilities: &[f32],
+	labels: &[Option<String>],
+	min_confidence: f32,
+) -> Vec<(String, f32)> {
+	let mut confidences = HashMap::<&str, f32>::new();
+	for (probability, label) in probabilities.iter().zip(labels) {
+		if let Some(label) = label {
+			*confidences.entry(label).or_default() += probability;
+		}
+	}
+
+	let mut picked = confidences
+		.into_iter()
+		.filter(|(_, confidence)| *confidence >= min_confidence)
+		.map(|(label, confidence)| (label.to_string(), confidence))
+		.collect::<Vec<_>>();
+	picked.sort_by(|(a_label, a), (b_label, b)| b.total_cmp(a).then_with(|| a_label.cmp(b_label)));
+	picked.truncate(MAX_LABELS);
+	picked
+}
+
+/// system_tag returns the system tag of a label, creating it the first time
+async fn system_tag(library: &LibraryContext, label: &str) -> Result<tag::Data, LabelerError> {
+	if let Some(tag) = library
+		.db
+		.tag()
+		.find_first(vec![
+			tag::name::equals(Some(label.to_string())),
+			tag::is_system::equals(true),
+		])
+		.exec()
+		.await?
+	{
+		return Ok(tag);
+	}
+
+	let tag = library
+		.db
+		.tag()
+		.create(
+			Uuid::new_v4().as_bytes().to_vec(),
+			vec![
+				tag::name::set(Some(label.to_string())),
+				tag::is_system::set(true),
+			],
+		)
+		.exec()
+		.await?;
+	library
+		.sync
+		.write_ops(vec![library.sync.shared_create(
+			TAG,
+			uuid_from_pub_id(&tag.pub_id),
+			&TagData::from(&tag),
+		)])
+		.await?;
+
+	Ok(tag)
+}
+
+/// The image labeler tags the images of a location that have no labels yet with the labels the model
+/// finds in them
+pub struct ImageLabelerJob {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageLabelerJobInit {
+	pub location_id: i32,
+	pub min_confidence: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageLabelerJobState {
+	root_path: PathBuf,
+	/// ids and pub_ids of the system tags of the labels used so far
+	tags: HashMap<String, (i32, Vec<u8>)>,
+	labeled: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageLabelerJobStep {
+	object_id: i32,
+	materialized_path: String,
+	raw_path: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ImageLabelerJob {
+	type Init = ImageLabelerJobInit;
+	type Data = ImageLabelerJobState;
+	type Step = ImageLabelerJobStep;
+
+	fn name(&self) -> &'static str {
+		IMAGE_LABELER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		// the model is loaded first, so nodes without one don't go through every image for nothing
+		Labeler::get(&library.config().data_directory())?;
+
+		let location = library
+			.db
+			.location()
+			.find_unique(location::id::equals(state.init.location_id))
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+		let root_path = location
+			.local_path
+			.map(PathBuf::from)
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+
+		// images that were labeled before keep their labels, only objects without any are labeled
+		let mut object_ids = vec![];
+		let steps = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(location.id),
+				file_path::extension::in_vec(
+					THUMBNAIL_IMAGE_EXTENSIONS
+						.into_iter()
+						.map(|extension| Extension::Image(extension).to_string())
+						.collect(),
+				),
+				file_path::object_id::not(None),
+			])
+			.with(file_path::object::fetch().with(object::tags::fetch(vec![
+				tag_on_object::tag::is(vec![tag::is_system::equals(true)]),
+			])))
+			.exec()
+			.await?
+			.into_iter()
+			.filter_map(|file_path| {
+				let object = file_path.object().ok().flatten()?;
+				// an object is labeled once, whichever of its paths is found first
+				if !object.tags().map_or(true, |tags| tags.is_empty())
+					|| object_ids.contains(&object.id)
+				{
+					return None;
+				}
+				object_ids.push(object.id);
+
+				Some(ImageLabelerJobStep {
+					object_id: object.id,
+					materialized_path: file_path.materialized_path,
+					raw_path: file_path.raw_path,
+				})
+			})
+			.collect::<Vec<_>>();
+
+		info!(
+			"Labeling {} images in location {}",
+			steps.len(),
+			location.id
+		);
+		ctx.progress(vec![JobReportUpdate::TaskCount(steps.len())]);
+
+		state.steps = steps.into();
+		state.data = Some(ImageLabelerJobState {
+			root_path,
+			tags: HashMap::new(),
+			labeled: 0,
+		});
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state.data.as_mut().expect("fatal: missing job state");
+		let library = ctx.library_ctx();
+		let labeler = Labeler::get(&library.config().data_directory())?;
+		let min_confidence = state.init.min_confidence.unwrap_or(DEFAULT_MIN_CONFIDENCE);
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		let path = resolve_materialized_path(
+			&data.root_path,
+			&step.materialized_path,
+			step.raw_path.as_deref(),
+		);
+		let labels = {
+			let _hasher = ctx.governor().hasher().await;
+			block_in_place(|| match image::open(&path) {
+				Ok(img) => labeler.label(&img, min_confidence).map(Some),
+				Err(e) => {
+					warn!("Couldn't open image {:?} to label it: {}", path, e);
+					Ok(None)
+				}
+			})?
+		};
+		let labels = match labels {
+			Some(labels) if !labels.is_empty() => labels,
+			_ => return Ok(()),
+		};
+
+		let object = match library
+			.db
+			.object()
+			.find_unique(object::id::equals(step.object_id))
+			.exec()
+			.await?
+		{
+			Some(object) => object,
+			None => return Ok(()),
+		};
+		let object_pub_id = library
+			.sync
+			.ensure_object_pub_id(&object)
+			.await
+			.map_err(LabelerError::from)?;
+
+		let mut ops = vec![];
+		for (label, _) in &labels {
+			if !data.tags.contains_key(label) {
+				let tag = system_tag(&library, label).await?;
+				data.tags.insert(label.clone(), (tag.id, tag.pub_id));
+			}
+			let (tag_id, tag_pub_id) = &data.tags[label];
+			let tag_id = *tag_id;
+
+			library
+				.db
+				.tag_on_object()
+				.upsert(
+					tag_on_object::tag_id_object_id(tag_id, object.id),
+					(
+						tag::id::equals(tag_id),
+						object::id::equals(object.id),
+						vec![],
+					),
+					vec![],
+				)
+				.exec()
+				.await?;
+			ops.push(library.sync.relation_create(
+				TAG_ON_OBJECT,
+				object_pub_id,
+				uuid_from_pub_id(tag_pub_id),
+			));
+		}
+		library
+			.sync
+			.write_ops(ops)
+			.await
+			.map_err(LabelerError::from)?;
+
+		info!(
+			"Labeled {:?}: {}",
+			path,
+			labels
+				.iter()
+				.map(|(label, confidence)| format!("{label} ({:.0}%)", confidence * 100.0))
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+		data.labeled += 1;
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		let library = ctx.library_ctx();
+		invalidate_query!(library, "tags.list");
+		invalidate_query!(library, "tags.getForObject");
+
+		info!(
+			"Labeled {} images with {} labels",
+			data.labeled,
+			data.tags.len()
+		);
+
+		Ok(Some(json!({
+			"labeled": data.labeled,
+			"labels": data.tags.keys().collect::<Vec<_>>(),
+		})))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn classes_sharing_a_label_add_up() {
+		let labels = parse_labels("Beagle\n\nbeagle\nBeach\n  \ndocument\n");
+		assert_eq!(
+			labels,
+			vec![
+				Some("beagle".to_string()),
+				None,
+				Some("beagle".to_string()),
+				Some("beach".to_string()),
+				None,
+				Some("document".to_string()),
+			]
+		);
+
+		let picked = pick_labels(&[0.2, 0.5, 0.2, 0.05, 0.0, 0.05], &labels, 0.3);
+		assert_eq!(picked.len(), 1);
+		assert_eq!(picked[0].0, "beagle");
+		assert!((picked[0].1 - 0.4).abs() < 1e-6);
+	}
+
+	#[test]
+	fn scores_become_probabilities() {
+		let probabilities = probabilities(vec![2.0, 1.0, 0.1]);
+		assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+		assert!(probabilities[0] > probabilities[1] && probabilities[1] > probabilities[2]);
+
+		// outputs that are probabilities already are left as they are
+		assert_eq!(
+			super::probabilities(vec![0.7, 0.2, 0.1]),
+			vec![0.7, 0.2, 0.1]
+		);
+	}
+}
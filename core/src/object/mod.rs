@@ -1,8 +1,22 @@
+pub mod attributes;
+pub mod backup;
 pub mod cas;
+pub mod chunks;
+pub mod faces;
 pub mod fs;
+pub mod garbage_collector;
+pub mod geo;
 pub mod identifier_job;
+pub mod importer;
+pub mod labeler;
 pub mod preview;
+pub mod search;
+pub mod share;
+pub mod similar;
+pub mod sources;
+pub mod timeline;
 pub mod validation;
+pub mod versions;
 
 // Objects are primarily created by the identifier from Paths
 // Some Objects are purely virtual, unless they have one or more associated Paths, which refer to a file found in a Location
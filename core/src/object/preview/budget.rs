@@ -0,0 +1,72 @@
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::util::power::on_battery_power;
+
+/// ProcessingBudget caps the thumbnails and previews generated after a location is scanned.
+/// Only thumbnailing and preview warming apply it, files are still identified, validated and
+/// labeled in full. Explicit thumbnail requests for a folder ignore it.
+#[derive(Debug, Serialize, Deserialize, Clone, Type, Default)]
+pub struct ProcessingBudget {
+	/// images larger than this many bytes don't get a thumbnail
+	pub max_image_size: Option<u64>,
+	/// videos larger than this many bytes don't get a thumbnail
+	pub max_video_size: Option<u64>,
+	/// don't generate video thumbnails while the device is running on battery
+	pub skip_videos_on_battery: bool,
+	/// the maximum amount of thumbnails to generate in a single scan
+	pub max_thumbnails: Option<u32>,
+}
+
+/// The kind of thumbnail a file would get, so the budget can apply the matching limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessingKind {
+	Image,
+	Video,
+	/// documents and text files, only the start of which is read
+	Document,
+	Audio,
+}
+
+impl ProcessingBudget {
+	/// should_skip_videos returns true if videos can't get a thumbnail right now
+	pub fn should_skip_videos(&self) -> bool {
+		self.skip_videos_on_battery && on_battery_power()
+	}
+
+	/// allows checks whether a file of the given kind and size fits in the budget
+	pub fn allows(&self, kind: ProcessingKind, size: u64) -> bool {
+		let max_size = match kind {
+			ProcessingKind::Image => self.max_image_size,
+			ProcessingKind::Video => self.max_video_size,
+			ProcessingKind::Document | ProcessingKind::Audio => None,
+		};
+
+		max_size.map_or(true, |max_size| size <= max_size)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn size_limits_are_per_kind() {
+		let budget = ProcessingBudget {
+			max_image_size: Some(50 * 1024 * 1024),
+			..Default::default()
+		};
+
+		assert!(budget.allows(ProcessingKind::Image, 1024));
+		assert!(!budget.allows(ProcessingKind::Image, 51 * 1024 * 1024));
+		assert!(budget.allows(ProcessingKind::Video, u64::MAX));
+	}
+
+	#[test]
+	fn default_budget_allows_everything() {
+		let budget = ProcessingBudget::default();
+
+		assert!(budget.allows(ProcessingKind::Image, u64::MAX));
+		assert!(!budget.should_skip_videos());
+	}
+}
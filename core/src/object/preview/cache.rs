@@ -0,0 +1,408 @@
+use super::{CHAPTERS_CACHE_DIR_NAME, THUMBNAIL_CACHE_DIR_NAME};
+use crate::{
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+};
+
+use filetime::{set_file_mtime, FileTime};
+use prisma_client_rust::{raw::Raw, QueryError};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use std::{
+	fs, io,
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::info;
+
+pub const PREVIEW_CACHE_CLEANER_JOB_NAME: &str = "preview_cache_cleaner";
+/// Accesses to a preview closer together than this only update its access time once, so browsing
+/// doesn't write to disk for every preview shown
+const ACCESS_RESOLUTION: Duration = Duration::from_secs(60 * 60);
+/// How many objects have their previews removed in each step of the cleaner
+const CLEANER_BATCH_SIZE: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum PreviewCacheError {
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] QueryError),
+}
+
+impl From<PreviewCacheError> for rspc::Error {
+	fn from(err: PreviewCacheError) -> Self {
+		rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+	}
+}
+
+/// PreviewCacheLimit caps the space the previews of a library's objects take up on this node
+#[derive(Debug, Serialize, Deserialize, Clone, Type, Default)]
+pub struct PreviewCacheLimit {
+	/// once the previews take up more than this many bytes, the least recently used are evicted
+	pub max_size: Option<u64>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct PreviewCacheStats {
+	/// preview files on this node, of the objects of every library
+	pub previews: u32,
+	pub bytes: u64,
+	/// previews of the library's objects, which its limit applies to
+	pub library_previews: u32,
+	pub library_bytes: u64,
+	pub max_size: Option<u64>,
+}
+
+/// The thumbnail, text snippet and preview strip of an object, whichever it has
+#[derive(Debug)]
+struct CachedPreview {
+	paths: Vec<PathBuf>,
+	bytes: u64,
+	last_access: SystemTime,
+}
+
+/// touch_preview records that a preview was just shown, in its modification time, which is what
+/// eviction goes by
+pub fn touch_preview(path: &Path) {
+	let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+		Ok(modified) => modified,
+		Err(_) => return,
+	};
+
+	if modified
+		.elapsed()
+		.map_or(false, |age| age > ACCESS_RESOLUTION)
+	{
+		set_file_mtime(path, FileTime::now()).ok();
+	}
+}
+
+fn preview_paths(data_dir: &Path, cas_id: &str) -> [PathBuf; 3] {
+	let thumbnail_dir = data_dir.join(THUMBNAIL_CACHE_DIR_NAME);
+	[
+		thumbnail_dir.join(cas_id).with_extension("webp"),
+		thumbnail_dir.join(cas_id).with_extension("txt"),
+		data_dir.join(CHAPTERS_CACHE_DIR_NAME).join(cas_id),
+	]
+}
+
+/// cached_preview finds the previews of an object, with how much space they take up and when one of
+/// them was last shown
+fn cached_preview(data_dir: &Path, cas_id: &str) -> Option<CachedPreview> {
+	let mut preview = CachedPreview {
+		paths: vec![],
+		bytes: 0,
+		last_access: SystemTime::UNIX_EPOCH,
+	};
+
+	for path in preview_paths(data_dir, cas_id) {
+		let metadata = match fs::metadata(&path) {
+			Ok(metadata) => metadata,
+			Err(_) => continue,
+		};
+
+		let files = match metadata.is_dir() {
+			true => fs::read_dir(&path)
+				.map(|entries| {
+					entries
+						.filter_map(|entry| entry.and_then(|entry| entry.metadata()).ok())
+						.collect()
+				})
+				.unwrap_or_default(),
+			false => vec![metadata],
+		};
+		for file in files {
+			preview.bytes += file.len();
+			if let Ok(modified) = file.modified() {
+				preview.last_access = preview.last_access.max(modified);
+			}
+		}
+		preview.paths.push(path);
+	}
+
+	(!preview.paths.is_empty()).then_some(preview)
+}
+
+fn remove_preview(preview: &CachedPreview) -> io::Result<()> {
+	for path in &preview.paths {
+		let result = match path.is_dir() {
+			true => fs::remove_dir_all(path),
+			false => fs::remove_file(path),
+		};
+		match result {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+			_ => {}
+		}
+	}
+
+	Ok(())
+}
+
+/// remove_previews removes the previews of an object, returning how much space they took up, if
+/// it had any
+pub(crate) fn remove_previews(data_dir: &Path, cas_id: &str) -> io::Result<Option<u64>> {
+	match cached_preview(data_dir, cas_id) {
+		Some(preview) => {
+			remove_preview(&preview)?;
+			Ok(Some(preview.bytes))
+		}
+		None => Ok(None),
+	}
+}
+
+/// least_recently_used picks the previews to evict to bring them under `max_size` bytes, the ones
+/// shown longest ago first
+fn least_recently_used(mut previews: Vec<CachedPreview>, max_size: u64) -> Vec<CachedPreview> {
+	let mut bytes = previews.iter().map(|preview| preview.bytes).sum::<u64>();
+	previews.sort_by_key(|preview| preview.last_access);
+
+	previews
+		.into_iter()
+		.take_while(|preview| {
+			let over = bytes > max_size;
+			bytes = bytes.saturating_sub(preview.bytes);
+			over
+		})
+		.collect()
+}
+
+async fn library_previews(library: &LibraryContext) -> Result<Vec<CachedPreview>, QueryError> {
+	let data_dir = library.config().data_directory();
+	let objects = library.db.object().find_many(vec![]).exec().await?;
+
+	Ok(block_in_place(|| {
+		objects
+			.iter()
+			.filter_map(|object| cached_preview(&data_dir, &object.cas_id))
+			.collect()
+	}))
+}
+
+/// dir_usage counts the files in a directory and its subdirectories, and the bytes they take up
+fn dir_usage(path: &Path) -> (u32, u64) {
+	let entries = match fs::read_dir(path) {
+		Ok(entries) => entries,
+		Err(_) => return (0, 0),
+	};
+
+	entries
+		.filter_map(Result::ok)
+		.fold((0, 0), |(count, bytes), entry| match entry.metadata() {
+			Ok(metadata) if metadata.is_dir() => {
+				let (dir_count, dir_bytes) = dir_usage(&entry.path());
+				(count + dir_count, bytes + dir_bytes)
+			}
+			Ok(metadata) => (count + 1, bytes + metadata.len()),
+			Err(_) => (count, bytes),
+		})
+}
+
+/// preview_cache_stats returns how much space previews take up on this node, and how much of it is
+/// the library's
+pub async fn preview_cache_stats(
+	library: &LibraryContext,
+) -> Result<PreviewCacheStats, PreviewCacheError> {
+	let data_dir = library.config().data_directory();
+	let (previews, bytes) = block_in_place(|| {
+		let (thumbnails, thumbnail_bytes) = dir_usage(&data_dir.join(THUMBNAIL_CACHE_DIR_NAME));
+		let (chapters, chapter_bytes) = dir_usage(&data_dir.join(CHAPTERS_CACHE_DIR_NAME));
+		(thumbnails + chapters, thumbnail_bytes + chapter_bytes)
+	});
+
+	let library_previews = library_previews(library).await?;
+
+	Ok(PreviewCacheStats {
+		previews,
+		bytes,
+		library_previews: library_previews.len() as u32,
+		library_bytes: library_previews.iter().map(|preview| preview.bytes).sum(),
+		max_size: library.config.preview_cache.max_size,
+	})
+}
+
+/// evict_previews removes the least recently shown previews of the library's objects until they fit
+/// in its limit. Returns how many were removed and the bytes given back.
+///
+/// Libraries on a node share their previews, so evicting one can take it from another library with
+/// the same content too.
+pub async fn evict_previews(library: &LibraryContext) -> Result<(usize, u64), PreviewCacheError> {
+	let max_size = match library.config.preview_cache.max_size {
+		Some(max_size) => max_size,
+		None => return Ok((0, 0)),
+	};
+
+	let evicted = least_recently_used(library_previews(library).await?, max_size);
+	block_in_place(|| evicted.iter().try_for_each(remove_preview))?;
+
+	let bytes = evicted.iter().map(|preview| preview.bytes).sum();
+	if !evicted.is_empty() {
+		info!(
+			"Evicted {} previews ({bytes} bytes) of library '{}'",
+			evicted.len(),
+			library.id
+		);
+		invalidate_query!(library, "cache.stats");
+	}
+
+	Ok((evicted.len(), bytes))
+}
+
+#[derive(Deserialize)]
+struct UnreachableObject {
+	cas_id: String,
+}
+
+/// PreviewCacheCleanerJob removes the previews of objects whose files are all in offline locations,
+/// or which have no files left since their location was deleted, then evicts previews over the
+/// library's limit
+pub struct PreviewCacheCleanerJob {}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PreviewCacheCleanerJobInit {}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PreviewCacheCleanerJobState {
+	removed_previews: usize,
+	removed_bytes: u64,
+	evicted_previews: usize,
+	evicted_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreviewCacheCleanerJobStep {
+	cas_ids: Vec<String>,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for PreviewCacheCleanerJob {
+	type Init = PreviewCacheCleanerJobInit;
+	type Data = PreviewCacheCleanerJobState;
+	type Step = PreviewCacheCleanerJobStep;
+
+	fn name(&self) -> &'static str {
+		PREVIEW_CACHE_CLEANER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		let unreachable: Vec<UnreachableObject> = library
+			.db
+			._query_raw(Raw::new(
+				"SELECT cas_id FROM object WHERE NOT EXISTS (SELECT 1 FROM file_path \
+				INNER JOIN location ON location.id = file_path.location_id \
+				WHERE file_path.object_id = object.id AND location.is_online)",
+				vec![],
+			))
+			.exec()
+			.await?;
+
+		info!(
+			"Found {} objects whose previews can't be shown",
+			unreachable.len()
+		);
+
+		state.steps = unreachable
+			.chunks(CLEANER_BATCH_SIZE)
+			.map(|objects| PreviewCacheCleanerJobStep {
+				cas_ids: objects.iter().map(|object| object.cas_id.clone()).collect(),
+			})
+			.collect();
+		state.data = Some(PreviewCacheCleanerJobState::default());
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let data_dir = ctx.library_ctx().config().data_directory();
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		block_in_place(|| {
+			for cas_id in &state.steps[0].cas_ids {
+				if let Some(preview) = cached_preview(&data_dir, cas_id) {
+					remove_preview(&preview)?;
+					data.removed_previews += 1;
+					data.removed_bytes += preview.bytes;
+				}
+			}
+			Ok::<_, io::Error>(())
+		})?;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let library = ctx.library_ctx();
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		(data.evicted_previews, data.evicted_bytes) = evict_previews(&library).await?;
+
+		info!(
+			"Removed {} previews ({} bytes) of objects that can't be shown",
+			data.removed_previews, data.removed_bytes
+		);
+
+		invalidate_query!(library, "cache.stats");
+		invalidate_query!(library, "locations.getExplorerData");
+
+		Ok(Some(serde_json::to_value(&*data)?))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn preview(name: &str, bytes: u64, accessed_secs_ago: u64) -> CachedPreview {
+		CachedPreview {
+			paths: vec![PathBuf::from(name)],
+			bytes,
+			last_access: SystemTime::now() - Duration::from_secs(accessed_secs_ago),
+		}
+	}
+
+	#[test]
+	fn evicts_least_recently_used_first() {
+		let previews = vec![
+			preview("recent", 100, 10),
+			preview("oldest", 100, 3000),
+			preview("old", 100, 2000),
+		];
+
+		let evicted = least_recently_used(previews, 150);
+		let evicted = evicted
+			.iter()
+			.map(|preview| preview.paths[0].to_str().unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(evicted, vec!["oldest", "old"]);
+
+		assert!(least_recently_used(vec![preview("fits", 100, 10)], 100).is_empty());
+	}
+}
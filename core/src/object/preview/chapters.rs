@@ -0,0 +1,123 @@
+//! Preview strips of long videos, so the detail view can jump between their scenes like chapters.
+//! They're made with the video's thumbnail, each frame kept as `<cas_id>/<position>.webp` in the
+//! chapters directory and served as `chapter/<cas_id>/<position>`, with where it was taken from kept
+//! in the `video_chapter` table.
+
+#[cfg(feature = "ffmpeg")]
+use crate::library::LibraryContext;
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ffmpeg")]
+use std::{error::Error, path::Path};
+
+pub static CHAPTERS_CACHE_DIR_NAME: &str = "chapters";
+
+/// VideoChapters controls which videos get a preview strip
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct VideoChapters {
+	pub enabled: bool,
+	/// videos shorter than this many seconds only get a thumbnail
+	pub min_duration_secs: u64,
+	/// how many frames a strip has
+	pub frames: u8,
+}
+
+impl Default for VideoChapters {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			min_duration_secs: 10 * 60,
+			frames: 12,
+		}
+	}
+}
+
+/// generate_video_chapters makes the preview strip of a video if it's long enough, replacing the one it
+/// had before. Returns whether a strip was made.
+#[cfg(feature = "ffmpeg")]
+pub(super) async fn generate_video_chapters(
+	library: &LibraryContext,
+	settings: &VideoChapters,
+	file_path: impl AsRef<Path>,
+	object_id: i32,
+	cas_id: &str,
+) -> Result<bool, Box<dyn Error>> {
+	use super::thumb::{THUMBNAIL_QUALITY, VIDEO_THUMBNAIL_SIZE};
+	use crate::prisma::{object, video_chapter};
+	use sd_ffmpeg::{to_strip, video_duration};
+	use std::time::Duration;
+	use tokio::fs;
+	use tracing::info;
+
+	if !settings.enabled
+		|| video_duration(&file_path).await? < Duration::from_secs(settings.min_duration_secs)
+	{
+		return Ok(false);
+	}
+
+	let frames = to_strip(
+		&file_path,
+		settings.frames as usize,
+		VIDEO_THUMBNAIL_SIZE,
+		THUMBNAIL_QUALITY,
+	)
+	.await?;
+
+	let strip_dir = library
+		.config()
+		.data_directory()
+		.join(CHAPTERS_CACHE_DIR_NAME)
+		.join(cas_id);
+	if strip_dir.exists() {
+		fs::remove_dir_all(&strip_dir).await?;
+	}
+	fs::create_dir_all(&strip_dir).await?;
+
+	for (position, frame) in frames.iter().enumerate() {
+		fs::write(strip_dir.join(format!("{position}.webp")), &frame.webp).await?;
+	}
+
+	library
+		.db
+		.video_chapter()
+		.delete_many(vec![video_chapter::object_id::equals(object_id)])
+		.exec()
+		.await?;
+	library
+		.db
+		.video_chapter()
+		.create_many(
+			frames
+				.iter()
+				.enumerate()
+				.map(|(position, frame)| {
+					video_chapter::create_unchecked(
+						object_id,
+						position as i32,
+						frame.timestamp.as_millis() as i32,
+						vec![video_chapter::scene_change::set(frame.scene_change)],
+					)
+				})
+				.collect(),
+		)
+		.exec()
+		.await?;
+	library
+		.db
+		.object()
+		.update(
+			object::id::equals(object_id),
+			vec![object::has_thumbstrip::set(true)],
+		)
+		.exec()
+		.await?;
+
+	info!(
+		"Made a preview strip of {} frames for {}",
+		frames.len(),
+		file_path.as_ref().display()
+	);
+
+	Ok(true)
+}
@@ -0,0 +1,195 @@
+//! Short descriptions of images, worked out while their thumbnails are generated, so frontends can
+//! tell screen reader users what an image shows without loading it. Their perceptual hashes are
+//! worked out along with them, to find near-duplicates with, and where photos were taken is kept for
+//! map views.
+
+use crate::{
+	library::LibraryContext,
+	object::{
+		geo::{geohash, GEOHASH_PRECISION},
+		similar::{to_hex, ImageHashes},
+	},
+	prisma::{media_data, object},
+};
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use image::{DynamicImage, GenericImageView};
+use std::{collections::HashMap, path::Path, process::Command};
+use tokio::task::block_in_place;
+
+/// How many colors are kept in a description
+const DOMINANT_COLORS: usize = 3;
+/// Images are shrunk to at most this many pixels a side before their colors are counted
+const COLOR_SAMPLE_SIZE: u32 = 64;
+/// Colors covering less than 1/`MIN_COLOR_SHARE` of an image aren't dominant
+const MIN_COLOR_SHARE: usize = 20;
+/// Most characters of detected text kept
+const MAX_TEXT_LENGTH: usize = 1000;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImageDescription {
+	pub width: u32,
+	pub height: u32,
+	/// the most common colors, the most common first
+	pub dominant_colors: Vec<[u8; 3]>,
+	pub text: Option<String>,
+	pub hashes: Option<ImageHashes>,
+	/// where the photo was taken, its latitude and longitude
+	pub location: Option<(f64, f64)>,
+	/// when the photo was taken, as the camera's clock read
+	pub date_taken: Option<NaiveDateTime>,
+}
+
+impl ImageDescription {
+	pub fn new(img: &DynamicImage) -> Self {
+		let (width, height) = img.dimensions();
+		Self {
+			width,
+			height,
+			dominant_colors: dominant_colors(img),
+			text: None,
+			hashes: Some(ImageHashes::new(img)),
+			location: None,
+			date_taken: None,
+		}
+	}
+
+	/// save stores the description with the media data of an object
+	pub async fn save(
+		&self,
+		library: &LibraryContext,
+		object_id: i32,
+	) -> Result<(), prisma_client_rust::QueryError> {
+		let colors = self
+			.dominant_colors
+			.iter()
+			.map(|[r, g, b]| format!("#{r:02x}{g:02x}{b:02x}"))
+			.collect::<Vec<_>>()
+			.join(",");
+
+		let params = vec![
+			media_data::pixel_width::set(Some(self.width as i32)),
+			media_data::pixel_height::set(Some(self.height as i32)),
+			media_data::dominant_colors::set((!colors.is_empty()).then_some(colors)),
+			media_data::detected_text::set(self.text.clone()),
+			media_data::perceptual_hash::set(self.hashes.map(|hashes| to_hex(hashes.perceptual))),
+			media_data::difference_hash::set(self.hashes.map(|hashes| to_hex(hashes.difference))),
+			media_data::latitude::set(self.location.map(|(latitude, _)| latitude)),
+			media_data::longitude::set(self.location.map(|(_, longitude)| longitude)),
+			media_data::geohash::set(
+				self.location
+					.map(|(latitude, longitude)| geohash(latitude, longitude, GEOHASH_PRECISION)),
+			),
+			// kept as the camera's clock read, there's no telling which time zone it was set to
+			media_data::date_taken::set(
+				self.date_taken
+					.map(|date| Utc.from_utc_datetime(&date).into()),
+			),
+		];
+
+		let existing = library
+			.db
+			.media_data()
+			.find_unique(media_data::id::equals(object_id))
+			.exec()
+			.await?;
+
+		if existing.is_some() {
+			library
+				.db
+				.media_data()
+				.update(media_data::id::equals(object_id), params)
+				.exec()
+				.await?;
+		} else {
+			library
+				.db
+				.media_data()
+				.create(object::id::equals(object_id), params)
+				.exec()
+				.await?;
+		}
+
+		Ok(())
+	}
+}
+
+/// dominant_colors groups similar colors of an image together and returns the average of the largest
+/// groups
+pub fn dominant_colors(img: &DynamicImage) -> Vec<[u8; 3]> {
+	let (width, height) = img.dimensions();
+	let sample = if width > COLOR_SAMPLE_SIZE || height > COLOR_SAMPLE_SIZE {
+		img.thumbnail(COLOR_SAMPLE_SIZE, COLOR_SAMPLE_SIZE)
+			.to_rgba8()
+	} else {
+		img.to_rgba8()
+	};
+
+	let mut groups = HashMap::<[u8; 3], (usize, [usize; 3])>::new();
+	let mut counted = 0;
+	for pixel in sample.pixels() {
+		let [r, g, b, a] = pixel.0;
+		// mostly transparent pixels aren't seen
+		if a < 128 {
+			continue;
+		}
+
+		let (count, sums) = groups.entry([r >> 5, g >> 5, b >> 5]).or_default();
+		*count += 1;
+		sums[0] += r as usize;
+		sums[1] += g as usize;
+		sums[2] += b as usize;
+		counted += 1;
+	}
+
+	let mut groups = groups.into_values().collect::<Vec<_>>();
+	groups.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+	groups
+		.into_iter()
+		.enumerate()
+		.take_while(|(i, (count, _))| *i == 0 || count * MIN_COLOR_SHARE >= counted)
+		.take(DOMINANT_COLORS)
+		.map(|(_, (count, sums))| sums.map(|sum| (sum / count) as u8))
+		.collect()
+}
+
+/// recognize_text reads the text in an image with `tesseract`, if it's installed
+pub fn recognize_text(path: &Path) -> Option<String> {
+	let output =
+		block_in_place(|| Command::new("tesseract").arg(path).arg("stdout").output()).ok()?;
+	if !output.status.success() {
+		return None;
+	}
+
+	let text = String::from_utf8_lossy(&output.stdout)
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ");
+	if text.is_empty() {
+		return None;
+	}
+
+	Some(text.chars().take(MAX_TEXT_LENGTH).collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use image::{Rgba, RgbaImage};
+
+	#[test]
+	fn finds_dominant_colors() {
+		// three quarters blue, a quarter white and a single red pixel
+		let img = RgbaImage::from_fn(40, 40, |x, y| match (x, y) {
+			(0, 0) => Rgba([255, 0, 0, 255]),
+			(x, _) if x < 30 => Rgba([20, 40, 200, 255]),
+			_ => Rgba([250, 250, 250, 255]),
+		});
+
+		assert_eq!(
+			dominant_colors(&DynamicImage::ImageRgba8(img)),
+			vec![[20, 40, 200], [250, 250, 250]]
+		);
+	}
+}
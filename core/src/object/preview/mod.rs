@@ -1,5 +1,16 @@
+mod budget;
+mod cache;
+mod chapters;
+mod describe;
 mod metadata;
+mod render;
 mod thumb;
+mod warm;
 
+pub use budget::*;
+pub use cache::*;
+pub use chapters::*;
 pub use metadata::*;
+pub use render::{find_preview, stream_preview, PreviewChunk};
 pub use thumb::*;
+pub use warm::*;
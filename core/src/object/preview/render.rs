@@ -0,0 +1,322 @@
+//! Previews of files that aren't images or videos: the first page of PDFs and the waveform of audio
+//! files are rendered as webp images, kept as thumbnails so they're served like any other, and code
+//! and text files get a snippet of their first lines kept as `<cas_id>.txt` next to them. Pages are
+//! rendered with `pdftoppm` and audio is decoded with `ffmpeg`, files get no preview on nodes that
+//! don't have them.
+
+use super::{thumb::THUMBNAIL_QUALITY, touch_preview};
+
+use image::{DynamicImage, Rgba, RgbaImage};
+use rspc::Type;
+use serde::Serialize;
+use std::{
+	error::Error,
+	io::{self, Read},
+	ops::Deref,
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+};
+use tokio::{
+	fs::{self, File},
+	io::AsyncReadExt,
+	task::block_in_place,
+};
+use webp::Encoder;
+
+/// Size of the longest side of the first page of documents
+const DOCUMENT_PREVIEW_SIZE: u32 = 512;
+const WAVEFORM_WIDTH: u32 = 512;
+const WAVEFORM_HEIGHT: u32 = 128;
+/// How many bars a waveform has, each `WAVEFORM_WIDTH / WAVEFORM_BARS` pixels wide with a gap
+const WAVEFORM_BARS: usize = 128;
+const WAVEFORM_COLOR: Rgba<u8> = Rgba([160, 160, 170, 255]);
+/// Audio is decoded to mono at this rate, which is plenty to see how loud it is
+const WAVEFORM_SAMPLE_RATE: u32 = 8000;
+/// Samples whose loudest is kept together while audio is decoded, so long files don't take up memory
+const WAVEFORM_WINDOW: usize = 800;
+/// Most of a file read for its snippet
+const SNIPPET_MAX_BYTES: usize = 4096;
+const SNIPPET_MAX_LINES: usize = 40;
+/// Size of the parts previews are streamed in
+const PREVIEW_CHUNK_SIZE: usize = 64 * 1024;
+
+/// encode_webp writes an image as a webp
+async fn encode_webp(
+	img: DynamicImage,
+	output_path: impl AsRef<Path>,
+) -> Result<(), Box<dyn Error>> {
+	// WebPMemory is !Send, so it's copied out before anything is awaited
+	let webp = block_in_place(|| -> Result<_, Box<dyn Error>> {
+		Ok(Encoder::from_image(&img)?
+			.encode(THUMBNAIL_QUALITY)
+			.deref()
+			.to_owned())
+	})?;
+	fs::write(output_path, &webp).await?;
+
+	Ok(())
+}
+
+/// generate_document_preview renders the first page of a PDF
+pub(super) async fn generate_document_preview(
+	file_path: impl AsRef<Path>,
+	output_path: impl AsRef<Path>,
+) -> Result<(), Box<dyn Error>> {
+	let output = block_in_place(|| {
+		Command::new("pdftoppm")
+			.args(["-png", "-f", "1", "-l", "1", "-singlefile", "-scale-to"])
+			.arg(DOCUMENT_PREVIEW_SIZE.to_string())
+			.arg(file_path.as_ref())
+			.output()
+	})?;
+	if !output.status.success() {
+		return Err(String::from_utf8_lossy(&output.stderr).trim().into());
+	}
+
+	encode_webp(image::load_from_memory(&output.stdout)?, output_path).await
+}
+
+/// window_peaks reads mono 16 bit little endian samples, and returns the loudest of each window of them
+fn window_peaks(mut reader: impl Read) -> io::Result<Vec<u16>> {
+	let mut peaks = vec![];
+	let mut buffer = vec![0; WAVEFORM_WINDOW * 2];
+	loop {
+		// a window is filled up before it's measured, only the last one can be shorter
+		let mut filled = 0;
+		while filled < buffer.len() {
+			match reader.read(&mut buffer[filled..])? {
+				0 => break,
+				read => filled += read,
+			}
+		}
+		if filled < 2 {
+			return Ok(peaks);
+		}
+
+		peaks.push(
+			buffer[..filled - filled % 2]
+				.chunks_exact(2)
+				.map(|sample| i16::from_le_bytes([sample[0], sample[1]]).unsigned_abs())
+				.max()
+				.unwrap_or(0),
+		);
+	}
+}
+
+/// waveform_bars groups window peaks into `bars` bars, each as high as its loudest window relative to
+/// the loudest of all, from 0 to 1
+fn waveform_bars(peaks: &[u16], bars: usize) -> Vec<f32> {
+	if peaks.is_empty() || bars == 0 {
+		return vec![];
+	}
+
+	let per_bar = (peaks.len() + bars - 1) / bars;
+	let bars = peaks
+		.chunks(per_bar)
+		.map(|chunk| chunk.iter().copied().max().unwrap_or(0))
+		.collect::<Vec<_>>();
+	let loudest = bars.iter().copied().max().unwrap_or(0);
+	if loudest == 0 {
+		return vec![0.0; bars.len()];
+	}
+
+	bars.into_iter()
+		.map(|peak| peak as f32 / loudest as f32)
+		.collect()
+}
+
+/// generate_waveform draws the waveform of an audio file
+pub(super) async fn generate_waveform(
+	file_path: impl AsRef<Path>,
+	output_path: impl AsRef<Path>,
+) -> Result<(), Box<dyn Error>> {
+	let peaks = block_in_place(|| -> Result<_, Box<dyn Error>> {
+		let mut child = Command::new("ffmpeg")
+			.args(["-v", "error", "-i"])
+			.arg(file_path.as_ref())
+			.args(["-ac", "1", "-ar"])
+			.arg(WAVEFORM_SAMPLE_RATE.to_string())
+			.args(["-f", "s16le", "-"])
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.spawn()?;
+		let peaks = window_peaks(child.stdout.take().ok_or("ffmpeg has no output")?)?;
+		if !child.wait()?.success() {
+			return Err("ffmpeg couldn't decode the file".into());
+		}
+		Ok(peaks)
+	})?;
+
+	let bars = waveform_bars(&peaks, WAVEFORM_BARS);
+	if bars.is_empty() {
+		return Err("the file has no audio".into());
+	}
+
+	let bar_width = WAVEFORM_WIDTH / bars.len() as u32;
+	let mut img = RgbaImage::new(WAVEFORM_WIDTH, WAVEFORM_HEIGHT);
+	for (i, bar) in bars.iter().enumerate() {
+		// quiet parts still get a line, so silence doesn't look like a gap
+		let height = ((bar * WAVEFORM_HEIGHT as f32) as u32).max(1);
+		let top = (WAVEFORM_HEIGHT - height) / 2;
+		let left = i as u32 * bar_width;
+		for x in left..left + bar_width.saturating_sub(1).max(1) {
+			for y in top..top + height {
+				img.put_pixel(x, y, WAVEFORM_COLOR);
+			}
+		}
+	}
+
+	encode_webp(DynamicImage::ImageRgba8(img), output_path).await
+}
+
+/// text_snippet returns the first lines of a text file from its first bytes, none if it's binary or
+/// blank. `truncated` tells if the file goes on past them, so its last line may be cut short.
+fn text_snippet(bytes: &[u8], truncated: bool) -> Option<String> {
+	if bytes.contains(&0) {
+		return None;
+	}
+
+	let mut text = String::from_utf8_lossy(bytes).into_owned();
+	if truncated {
+		if let Some(end) = text.rfind('\n') {
+			text.truncate(end);
+		}
+	}
+
+	let snippet = text
+		.lines()
+		.take(SNIPPET_MAX_LINES)
+		.collect::<Vec<_>>()
+		.join("\n");
+	let snippet = snippet.trim_end();
+
+	(!snippet.trim().is_empty()).then(|| snippet.to_string())
+}
+
+/// generate_text_snippet keeps the first lines of a code or text file
+pub(super) async fn generate_text_snippet(
+	file_path: impl AsRef<Path>,
+	output_path: impl AsRef<Path>,
+) -> Result<(), Box<dyn Error>> {
+	let mut bytes = Vec::with_capacity(SNIPPET_MAX_BYTES + 1);
+	File::open(file_path)
+		.await?
+		.take(SNIPPET_MAX_BYTES as u64 + 1)
+		.read_to_end(&mut bytes)
+		.await?;
+	let truncated = bytes.len() > SNIPPET_MAX_BYTES;
+	bytes.truncate(SNIPPET_MAX_BYTES);
+
+	match text_snippet(&bytes, truncated) {
+		Some(snippet) => Ok(fs::write(output_path, snippet).await?),
+		None => Err("the file has no text to show".into()),
+	}
+}
+
+/// A part of a preview streamed with `files.getPreview`
+#[derive(Serialize, Type, Debug)]
+#[serde(tag = "type")]
+pub enum PreviewChunk {
+	/// sent before the preview's data
+	Start { content_type: String, size: u32 },
+	/// the next part of the preview, base64 encoded
+	Data { data: String },
+	/// the object has no preview, yet or at all
+	NotFound,
+}
+
+/// find_preview returns where the preview of the object with the given cas_id is and its content type
+pub fn find_preview(thumbnail_dir: &Path, cas_id: &str) -> Option<(PathBuf, &'static str)> {
+	// cas ids are hex, anything else could point out of the directory
+	if cas_id.is_empty() || !cas_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+		return None;
+	}
+
+	[("webp", "image/webp"), ("txt", "text/plain; charset=utf-8")]
+		.into_iter()
+		.map(|(extension, content_type)| {
+			(
+				thumbnail_dir.join(cas_id).with_extension(extension),
+				content_type,
+			)
+		})
+		.find(|(path, _)| path.exists())
+}
+
+/// stream_preview streams the preview of the object with the given cas_id in parts
+pub fn stream_preview(
+	thumbnail_dir: PathBuf,
+	cas_id: String,
+) -> impl futures::Stream<Item = PreviewChunk> {
+	async_stream::stream! {
+		let (path, content_type) = match find_preview(&thumbnail_dir, &cas_id) {
+			Some(preview) => preview,
+			None => {
+				yield PreviewChunk::NotFound;
+				return;
+			}
+		};
+		let mut file = match File::open(&path).await {
+			Ok(file) => file,
+			Err(_) => {
+				yield PreviewChunk::NotFound;
+				return;
+			}
+		};
+		touch_preview(&path);
+
+		yield PreviewChunk::Start {
+			content_type: content_type.to_string(),
+			size: file
+				.metadata()
+				.await
+				.map(|metadata| metadata.len() as u32)
+				.unwrap_or(0),
+		};
+
+		let mut buffer = vec![0; PREVIEW_CHUNK_SIZE];
+		loop {
+			match file.read(&mut buffer).await {
+				Ok(0) | Err(_) => break,
+				Ok(read) => yield PreviewChunk::Data {
+					data: base64::encode(&buffer[..read]),
+				},
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn waveforms_are_relative_to_the_loudest_part() {
+		let samples = [100i16, -200, 50, -1000, 0, 500]
+			.iter()
+			.flat_map(|sample| sample.to_le_bytes())
+			.cycle()
+			.take(WAVEFORM_WINDOW * 2 * 3)
+			.collect::<Vec<_>>();
+		let peaks = window_peaks(io::Cursor::new(samples)).unwrap();
+		assert_eq!(peaks, vec![1000, 1000, 1000]);
+
+		assert_eq!(waveform_bars(&[250, 500, 1000, 0], 2), vec![0.5, 1.0]);
+		assert_eq!(waveform_bars(&[0, 0], 4), vec![0.0, 0.0]);
+		assert!(waveform_bars(&[], 4).is_empty());
+	}
+
+	#[test]
+	fn snippets_keep_whole_lines() {
+		assert_eq!(
+			text_snippet(b"# Title\n\nSome text\nand a cut li", true).as_deref(),
+			Some("# Title\n\nSome text")
+		);
+		assert_eq!(
+			text_snippet(b"fn main() {}\n", false).as_deref(),
+			Some("fn main() {}")
+		);
+		assert_eq!(text_snippet(b"\x7fELF\0\0", false), None);
+		assert_eq!(text_snippet(b"  \n\n", false), None);
+	}
+}
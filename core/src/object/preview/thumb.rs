@@ -1,11 +1,35 @@
+#[cfg(feature = "ffmpeg")]
+use super::chapters::generate_video_chapters;
+use super::{
+	cache::evict_previews,
+	describe::{recognize_text, ImageDescription},
+	render::{generate_document_preview, generate_text_snippet, generate_waveform},
+	ProcessingBudget, ProcessingKind, VideoChapters,
+};
 use crate::{
 	api::CoreEvent,
 	invalidate_query,
 	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
 	library::LibraryContext,
-	prisma::{file_path, location},
+	location::{
+		catalog::mark_cataloged,
+		settings::LocationSettings,
+		storage::{fetch_to_cache, StorageConfig},
+		LocationError,
+	},
+	object::{
+		fs::organize::exif_date,
+		geo::exif_location,
+		similar::{save_hashes, ImageHashes},
+	},
+	prisma::{file_path, location, media_data},
+	util::{message::Message, os_path::resolve_materialized_path},
+};
+
+use sd_file_ext::extensions::{
+	AudioExtension, CodeExtension, DocumentExtension, Extension, ImageExtension, TextExtension,
+	VideoExtension, _ALL_AUDIO_EXTENSIONS, _ALL_CODE_EXTENSIONS, _ALL_TEXT_EXTENSIONS,
 };
-use sd_file_ext::extensions::{Extension, ImageExtension, VideoExtension};
 
 use image::{self, imageops, DynamicImage, GenericImageView};
 use serde::{Deserialize, Serialize};
@@ -14,16 +38,30 @@ use std::{
 	error::Error,
 	ops::Deref,
 	path::{Path, PathBuf},
+	str::FromStr,
 };
 use tokio::{fs, task::block_in_place};
 use tracing::{error, info, trace, warn};
 use webp::Encoder;
 
 static THUMBNAIL_SIZE_FACTOR: f32 = 0.2;
-static THUMBNAIL_QUALITY: f32 = 30.0;
+pub(super) static THUMBNAIL_QUALITY: f32 = 30.0;
+/// Size of the thumbnails of videos, and of the frames of their preview strips
+pub(super) const VIDEO_THUMBNAIL_SIZE: u32 = 256;
 pub static THUMBNAIL_CACHE_DIR_NAME: &str = "thumbnails";
 pub const THUMBNAIL_JOB_NAME: &str = "thumbnailer";
 
+pub const THUMBNAIL_IMAGE_EXTENSIONS: [ImageExtension; 5] = [
+	ImageExtension::Png,
+	ImageExtension::Jpeg,
+	ImageExtension::Jpg,
+	ImageExtension::Gif,
+	ImageExtension::Webp,
+];
+
+/// Documents whose first page is rendered as their thumbnail
+const PREVIEW_DOCUMENT_EXTENSIONS: [DocumentExtension; 1] = [DocumentExtension::Pdf];
+
 pub struct ThumbnailJob {}
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -31,21 +69,74 @@ pub struct ThumbnailJobInit {
 	pub location_id: i32,
 	pub path: PathBuf,
 	pub background: bool,
+	/// limits applied to scans, `None` for explicit requests which process everything
+	pub budget: Option<ProcessingBudget>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ThumbnailJobState {
 	thumbnail_dir: PathBuf,
 	root_path: PathBuf,
+	/// set for locations in object storage, whose files are downloaded one at a time to be processed
+	#[serde(default)]
+	storage: Option<StorageConfig>,
+	/// which videos get a preview strip along with their thumbnail
+	#[serde(default)]
+	video_chapters: VideoChapters,
 }
 
 file_path::include!(file_path_with_object { object });
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
-enum ThumbnailJobStepKind {
+pub(super) enum ThumbnailJobStepKind {
 	Image,
 	#[cfg(feature = "ffmpeg")]
 	Video,
+	/// PDFs, whose first page is rendered
+	Document,
+	/// audio files, whose waveform is drawn
+	Audio,
+	/// code and text files, which get a snippet of their first lines rather than a thumbnail
+	Text,
+}
+
+impl ThumbnailJobStepKind {
+	/// preview_path returns where the preview of an object of this kind is kept
+	pub(super) fn preview_path(&self, thumbnail_dir: &Path, cas_id: &str) -> PathBuf {
+		let extension = match self {
+			Self::Text => "txt",
+			_ => "webp",
+		};
+		thumbnail_dir.join(cas_id).with_extension(extension)
+	}
+
+	/// processing_kind returns the budget limits that apply to files of this kind
+	pub(super) fn processing_kind(&self) -> ProcessingKind {
+		match self {
+			Self::Image => ProcessingKind::Image,
+			#[cfg(feature = "ffmpeg")]
+			Self::Video => ProcessingKind::Video,
+			Self::Document | Self::Text => ProcessingKind::Document,
+			Self::Audio => ProcessingKind::Audio,
+		}
+	}
+}
+
+/// generate_rendered_preview makes the preview of a file of a kind that's rendered rather than scaled
+/// down, returns false for images and videos
+pub(super) async fn generate_rendered_preview(
+	kind: ThumbnailJobStepKind,
+	file_path: &Path,
+	output_path: &Path,
+) -> Result<bool, Box<dyn Error>> {
+	match kind {
+		ThumbnailJobStepKind::Document => generate_document_preview(file_path, output_path).await?,
+		ThumbnailJobStepKind::Audio => generate_waveform(file_path, output_path).await?,
+		ThumbnailJobStepKind::Text => generate_text_snippet(file_path, output_path).await?,
+		_ => return Ok(false),
+	}
+
+	Ok(true)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -89,25 +180,23 @@ impl StatefulJob for ThumbnailJob {
 			state.init.path.display()
 		);
 
+		let generate_thumbnails = LocationSettings::from(&location).generate_thumbnails;
+
 		// create all necessary directories if they don't exist
 		fs::create_dir_all(&thumbnail_dir).await?;
-		let root_path = location.local_path.map(PathBuf::from).unwrap();
+		let storage = StorageConfig::parse(location.storage_config.as_deref())
+			.map_err(LocationError::from)?;
+		let root_path = location.local_path.map(PathBuf::from).unwrap_or_default();
 
 		// query database for all image files in this location that need thumbnails
 		let image_files = get_files_by_extensions(
 			&library_ctx,
 			state.init.location_id,
 			&state.init.path,
-			[
-				ImageExtension::Png,
-				ImageExtension::Jpeg,
-				ImageExtension::Jpg,
-				ImageExtension::Gif,
-				ImageExtension::Webp,
-			]
-			.into_iter()
-			.map(Extension::Image)
-			.collect(),
+			THUMBNAIL_IMAGE_EXTENSIONS
+				.into_iter()
+				.map(|extension| Extension::Image(extension).to_string())
+				.collect(),
 			ThumbnailJobStepKind::Image,
 		)
 		.await?;
@@ -124,7 +213,7 @@ impl StatefulJob for ThumbnailJob {
 					.iter()
 					.map(Clone::clone)
 					.filter(can_generate_thumbnail_for_video)
-					.map(Extension::Video)
+					.map(|extension| Extension::Video(extension).to_string())
 					.collect(),
 				ThumbnailJobStepKind::Video,
 			)
@@ -139,14 +228,88 @@ impl StatefulJob for ThumbnailJob {
 		#[cfg(not(feature = "ffmpeg"))]
 		let all_files = { image_files.into_iter().collect::<VecDeque<_>>() };
 
+		// query database for the documents, audio and text files whose previews are rendered
+		let mut all_files = all_files;
+		for (extensions, kind) in [
+			(
+				PREVIEW_DOCUMENT_EXTENSIONS
+					.iter()
+					.map(ToString::to_string)
+					.collect::<Vec<_>>(),
+				ThumbnailJobStepKind::Document,
+			),
+			(
+				_ALL_AUDIO_EXTENSIONS
+					.iter()
+					.copied()
+					.map(|extension| Extension::Audio(extension).to_string())
+					.collect(),
+				ThumbnailJobStepKind::Audio,
+			),
+			(
+				_ALL_TEXT_EXTENSIONS
+					.iter()
+					.copied()
+					.filter(|extension| *extension != TextExtension::Rtf)
+					.map(|extension| Extension::Text(extension).to_string())
+					.chain(
+						_ALL_CODE_EXTENSIONS
+							.iter()
+							.map(|extension| Extension::Code(*extension).to_string()),
+					)
+					.collect(),
+				ThumbnailJobStepKind::Text,
+			),
+		] {
+			let files = get_files_by_extensions(
+				&library_ctx,
+				state.init.location_id,
+				&state.init.path,
+				extensions,
+				kind,
+			)
+			.await?;
+			info!("Found {:?} {:?} files", files.len(), kind);
+			all_files.extend(files);
+		}
+
+		let all_files = match &state.init.budget {
+			Some(budget) => {
+				let total_files = all_files.len();
+				let all_files = apply_budget(budget, all_files);
+				info!(
+					"Processing budget skipped {} files",
+					total_files - all_files.len()
+				);
+				all_files
+			}
+			None => all_files,
+		};
+
+		// the location's settings turn its previews off, even when they're asked for
+		let all_files = match generate_thumbnails {
+			true => all_files,
+			false => {
+				info!(
+					"Thumbnails are turned off in location {}",
+					state.init.location_id
+				);
+				VecDeque::new()
+			}
+		};
+
 		ctx.progress(vec![
 			JobReportUpdate::TaskCount(all_files.len()),
-			JobReportUpdate::Message(format!("Preparing to process {} files", all_files.len())),
+			JobReportUpdate::Message(Message::PreparingFiles {
+				count: all_files.len(),
+			}),
 		]);
 
 		state.data = Some(ThumbnailJobState {
 			thumbnail_dir,
 			root_path,
+			storage,
+			video_chapters: library_ctx.config.video_chapters.clone(),
 		});
 		state.steps = all_files;
 
@@ -159,23 +322,20 @@ impl StatefulJob for ThumbnailJob {
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> Result<(), JobError> {
 		let step = &state.steps[0];
-		ctx.progress(vec![JobReportUpdate::Message(format!(
-			"Processing {}",
-			step.file_path.materialized_path
-		))]);
+		ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+			path: step.file_path.materialized_path.clone(),
+		})]);
 
 		let data = state
 			.data
 			.as_ref()
 			.expect("critical error: missing data on job state");
 
-		// assemble the file path
-		let path = data.root_path.join(&step.file_path.materialized_path);
 		trace!("image_file {:?}", step);
 
 		// get cas_id, if none found skip
-		let cas_id = match &step.file_path.object {
-			Some(f) => f.cas_id.clone(),
+		let (cas_id, object_id) = match &step.file_path.object {
+			Some(f) => (f.cas_id.clone(), f.id),
 			_ => {
 				warn!(
 					"skipping thumbnail generation for {}",
@@ -185,32 +345,150 @@ impl StatefulJob for ThumbnailJob {
 			}
 		};
 
-		// Define and write the WebP-encoded file to a given path
-		let output_path = data.thumbnail_dir.join(&cas_id).with_extension("webp");
+		// Define and write the WebP-encoded file to a given path, or the snippet of a text file
+		let output_path = step.kind.preview_path(&data.thumbnail_dir, &cas_id);
+
+		let has_thumbnail = output_path.try_exists().unwrap();
+		// videos that already have a thumbnail still get a strip when one is explicitly asked for
+		#[cfg(feature = "ffmpeg")]
+		let needs_strip = matches!(step.kind, ThumbnailJobStepKind::Video)
+			&& data.video_chapters.enabled
+			&& (!has_thumbnail || state.init.budget.is_none())
+			&& !step
+				.file_path
+				.object
+				.as_ref()
+				.map_or(false, |object| object.has_thumbstrip);
+		#[cfg(not(feature = "ffmpeg"))]
+		let needs_strip = false;
 
 		// check if file exists at output path
-		if !output_path.try_exists().unwrap() {
+		if !has_thumbnail || needs_strip {
+			// thumbnails are made one after another when the node is throttled
+			let hasher = ctx.governor().hasher().await;
+
+			// assemble the file path, downloading the file first if it's in object storage
+			let path = match &data.storage {
+				Some(storage) => {
+					let library_ctx = ctx.library_ctx();
+					let storage = storage.open_in_job(&ctx).map_err(LocationError::from)?;
+					fetch_to_cache(
+						&library_ctx,
+						storage.as_ref(),
+						state.init.location_id,
+						&step.file_path.materialized_path,
+					)
+					.await
+					.map_err(LocationError::from)?
+				}
+				None => resolve_materialized_path(
+					&data.root_path,
+					&step.file_path.materialized_path,
+					step.file_path.raw_path.as_deref(),
+				),
+			};
+
 			info!("Writing {:?} to {:?}", path, output_path);
 
 			match step.kind {
 				ThumbnailJobStepKind::Image => {
-					if let Err(e) = generate_image_thumbnail(&path, &output_path).await {
-						error!("Error generating thumb for image {:#?}", e);
+					match generate_image_thumbnail(&path, &output_path).await {
+						Ok(mut description) => {
+							description.text = recognize_text(&path);
+							description.location = block_in_place(|| exif_location(&path));
+							description.date_taken = block_in_place(|| exif_date(&path));
+							if let Err(e) = description.save(&ctx.library_ctx(), object_id).await {
+								error!("Error saving description of image {:#?}", e);
+							}
+						}
+						Err(e) => error!("Error generating thumb for image {:#?}", e),
 					}
 				}
 				#[cfg(feature = "ffmpeg")]
 				ThumbnailJobStepKind::Video => {
-					if let Err(e) = generate_video_thumbnail(&path, &output_path).await {
-						error!("Error generating thumb for video: {:?} {:#?}", &path, e);
+					if !has_thumbnail {
+						if let Err(e) = generate_video_thumbnail(&path, &output_path).await {
+							error!("Error generating thumb for video: {:?} {:#?}", &path, e);
+						}
+					}
+
+					if needs_strip {
+						if let Err(e) = generate_video_chapters(
+							&ctx.library_ctx(),
+							&data.video_chapters,
+							&path,
+							object_id,
+							&cas_id,
+						)
+						.await
+						{
+							error!(
+								"Error generating preview strip for video: {:?} {:#?}",
+								&path, e
+							);
+						}
+					}
+				}
+				kind => {
+					if let Err(e) = generate_rendered_preview(kind, &path, &output_path).await {
+						error!(
+							"Error generating {:?} preview for {:?}: {:#?}",
+							kind, &path, e
+						);
 					}
 				}
 			}
 
-			if !state.init.background {
+			drop(hasher);
+			if let Ok(metadata) = fs::metadata(&path).await {
+				ctx.governor().throttle_read(metadata.len()).await;
+			}
+
+			if data.storage.is_some() {
+				if let Err(e) = fs::remove_file(&path).await {
+					warn!("Failed to remove cached file {}: {}", path.display(), e);
+				}
+			}
+
+			// snippets aren't thumbnails, they're only fetched with `files.getPreview`
+			if !state.init.background && !matches!(step.kind, ThumbnailJobStepKind::Text) {
 				ctx.library_ctx().emit(CoreEvent::NewThumbnail { cas_id });
 			};
 		} else {
 			info!("Thumb exists, skipping... {}", output_path.display());
+
+			// images thumbnailed before images were hashed are hashed once
+			if matches!(step.kind, ThumbnailJobStepKind::Image) && data.storage.is_none() {
+				let library_ctx = ctx.library_ctx();
+				let hashed = library_ctx
+					.db
+					.media_data()
+					.find_unique(media_data::id::equals(object_id))
+					.exec()
+					.await?
+					.map_or(false, |media_data| media_data.perceptual_hash.is_some());
+
+				if !hashed {
+					let path = resolve_materialized_path(
+						&data.root_path,
+						&step.file_path.materialized_path,
+						step.file_path.raw_path.as_deref(),
+					);
+					let hasher = ctx.governor().hasher().await;
+					let hashes =
+						block_in_place(|| image::open(&path).map(|img| ImageHashes::new(&img)));
+					drop(hasher);
+
+					match hashes {
+						Ok(hashes) => {
+							if let Err(e) = save_hashes(&library_ctx, object_id, hashes).await {
+								error!("Error saving hashes of image {:#?}", e);
+							}
+						}
+						Err(e) => error!("Error hashing image {:?}: {:#?}", path, e),
+					}
+				}
+			}
 		}
 
 		// With this invalidate query, we update the user interface to show each new thumbnail
@@ -226,7 +504,7 @@ impl StatefulJob for ThumbnailJob {
 
 	async fn finalize(
 		&self,
-		_ctx: WorkerContext,
+		ctx: WorkerContext,
 		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
 	) -> JobResult {
 		let data = state
@@ -239,19 +517,30 @@ impl StatefulJob for ThumbnailJob {
 			data.root_path.display()
 		);
 
+		// thumbnails come last in a scan, so a catalog is complete once its whole location has them
+		if state.init.path.as_os_str().is_empty() {
+			mark_cataloged(&ctx.library_ctx(), state.init.location_id).await?;
+		}
+
+		if let Err(e) = evict_previews(&ctx.library_ctx()).await {
+			error!("Failed to evict previews: {e:#?}");
+		}
+
 		// TODO: Serialize and return metadata here
 		Ok(None)
 	}
 }
 
-async fn generate_image_thumbnail<P: AsRef<Path>>(
+/// generate_image_thumbnail writes the thumbnail of an image and describes the image while it's loaded
+pub(super) async fn generate_image_thumbnail<P: AsRef<Path>>(
 	file_path: P,
 	output_path: P,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<ImageDescription, Box<dyn Error>> {
 	// Webp creation has blocking code
-	let webp = block_in_place(|| -> Result<Vec<u8>, Box<dyn Error>> {
+	let (webp, description) = block_in_place(|| -> Result<_, Box<dyn Error>> {
 		// Using `image` crate, open the included .jpg file
 		let img = image::open(file_path)?;
+		let description = ImageDescription::new(&img);
 		let (w, h) = img.dimensions();
 		// Optionally, resize the existing photo and convert back into DynamicImage
 		let img = DynamicImage::ImageRgba8(imageops::resize(
@@ -269,20 +558,31 @@ async fn generate_image_thumbnail<P: AsRef<Path>>(
 		// Type WebPMemory is !Send, which makes the Future in this function !Send,
 		// this make us `deref` to have a `&[u8]` and then `to_owned` to make a Vec<u8>
 		// which implies on a unwanted clone...
-		Ok(encoder.encode(THUMBNAIL_QUALITY).deref().to_owned())
+		Ok((
+			encoder.encode(THUMBNAIL_QUALITY).deref().to_owned(),
+			description,
+		))
 	})?;
 
-	fs::write(output_path, &webp).await.map_err(Into::into)
+	fs::write(output_path, &webp).await?;
+
+	Ok(description)
 }
 
 #[cfg(feature = "ffmpeg")]
-async fn generate_video_thumbnail<P: AsRef<Path>>(
+pub(super) async fn generate_video_thumbnail<P: AsRef<Path>>(
 	file_path: P,
 	output_path: P,
 ) -> Result<(), Box<dyn Error>> {
 	use sd_ffmpeg::to_thumbnail;
 
-	to_thumbnail(file_path, output_path, 256, THUMBNAIL_QUALITY).await?;
+	to_thumbnail(
+		file_path,
+		output_path,
+		VIDEO_THUMBNAIL_SIZE,
+		THUMBNAIL_QUALITY,
+	)
+	.await?;
 
 	Ok(())
 }
@@ -291,12 +591,12 @@ async fn get_files_by_extensions(
 	ctx: &LibraryContext,
 	location_id: i32,
 	path: impl AsRef<Path>,
-	extensions: Vec<Extension>,
+	extensions: Vec<String>,
 	kind: ThumbnailJobStepKind,
 ) -> Result<Vec<ThumbnailJobStep>, JobError> {
 	let mut params = vec![
 		file_path::location_id::equals(location_id),
-		file_path::extension::in_vec(extensions.iter().map(|e| e.to_string()).collect()),
+		file_path::extension::in_vec(extensions),
 	];
 
 	let path_str = path.as_ref().to_string_lossy().to_string();
@@ -317,6 +617,69 @@ async fn get_files_by_extensions(
 		.collect())
 }
 
+/// thumbnail_kind tells how a thumbnail is generated for files with the given extension, if one can be
+pub(super) fn thumbnail_kind(extension: &str) -> Option<ThumbnailJobStepKind> {
+	if let Ok(extension) = ImageExtension::from_str(extension) {
+		return THUMBNAIL_IMAGE_EXTENSIONS
+			.contains(&extension)
+			.then_some(ThumbnailJobStepKind::Image);
+	}
+
+	#[cfg(feature = "ffmpeg")]
+	if let Ok(extension) = VideoExtension::from_str(extension) {
+		return can_generate_thumbnail_for_video(&extension).then_some(ThumbnailJobStepKind::Video);
+	}
+
+	if let Ok(extension) = DocumentExtension::from_str(extension) {
+		return PREVIEW_DOCUMENT_EXTENSIONS
+			.contains(&extension)
+			.then_some(ThumbnailJobStepKind::Document);
+	}
+
+	if AudioExtension::from_str(extension).is_ok() {
+		return Some(ThumbnailJobStepKind::Audio);
+	}
+
+	match TextExtension::from_str(extension) {
+		Ok(TextExtension::Rtf) => None,
+		Ok(_) => Some(ThumbnailJobStepKind::Text),
+		Err(_) => CodeExtension::from_str(extension)
+			.ok()
+			.map(|_| ThumbnailJobStepKind::Text),
+	}
+}
+
+/// apply_budget drops the steps that don't fit in the given processing budget
+fn apply_budget(
+	budget: &ProcessingBudget,
+	steps: VecDeque<ThumbnailJobStep>,
+) -> VecDeque<ThumbnailJobStep> {
+	#[cfg(feature = "ffmpeg")]
+	let skip_videos = budget.should_skip_videos();
+
+	let steps = steps.into_iter().filter(|step| {
+		let kind = step.kind.processing_kind();
+		#[cfg(feature = "ffmpeg")]
+		if skip_videos && kind == ProcessingKind::Video {
+			return false;
+		}
+
+		let size = step
+			.file_path
+			.object
+			.as_ref()
+			.and_then(|object| object.size_in_bytes.parse::<u64>().ok())
+			.unwrap_or(0);
+
+		budget.allows(kind, size)
+	});
+
+	match budget.max_thumbnails {
+		Some(max_thumbnails) => steps.take(max_thumbnails as usize).collect(),
+		None => steps.collect(),
+	}
+}
+
 #[allow(unused)]
 pub fn can_generate_thumbnail_for_video(video_extension: &VideoExtension) -> bool {
 	use VideoExtension::*;
@@ -0,0 +1,377 @@
+use super::{
+	evict_previews, find_preview,
+	thumb::{
+		generate_image_thumbnail, generate_rendered_preview, thumbnail_kind, ThumbnailJobStepKind,
+	},
+	THUMBNAIL_CACHE_DIR_NAME,
+};
+use crate::{
+	api::CoreEvent,
+	invalidate_query,
+	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::{
+		storage::{fetch_to_cache, StorageConfig},
+		LocationError,
+	},
+	prisma::{file_path, location, object},
+	util::{message::Message, os_path::resolve_materialized_path},
+};
+
+use prisma_client_rust::{Direction, QueryError};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+use tokio::fs;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+pub const PREVIEW_WARMER_JOB_NAME: &str = "preview_warmer";
+/// How many of the objects brought by a sync get their previews warmed, most recent first
+const WARM_PREVIEWS_COUNT: u32 = 100;
+
+/// PreviewWarmerJob generates the thumbnails of objects that arrived through sync and have a copy
+/// on this node, so browsing newly synced content doesn't show placeholders
+pub struct PreviewWarmerJob {}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PreviewWarmerJobInit {
+	pub steps: Vec<PreviewWarmerJobStep>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreviewWarmerJobState {
+	thumbnail_dir: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PreviewWarmerJobStep {
+	cas_id: String,
+	location_id: i32,
+	materialized_path: String,
+	raw_path: Option<Vec<u8>>,
+	kind: ThumbnailJobStepKind,
+}
+
+/// The previews to warm for a set of synced objects
+#[derive(Debug, Default)]
+pub struct PreviewWarming {
+	/// objects with a copy on this node, whose thumbnails can be generated here
+	pub generate: Vec<PreviewWarmerJobStep>,
+	/// cas ids of objects only on other nodes, whose thumbnails have to be fetched from them
+	pub fetch: Vec<String>,
+}
+
+/// plan_preview_warming picks the most recent of the given objects that have no thumbnail yet and
+/// works out where each one's thumbnail can come from
+pub async fn plan_preview_warming(
+	library: &LibraryContext,
+	objects: &[Uuid],
+) -> Result<PreviewWarming, QueryError> {
+	if objects.is_empty() {
+		return Ok(PreviewWarming::default());
+	}
+
+	let budget = &library.config.processing_budget;
+	let count = budget
+		.max_thumbnails
+		.map_or(WARM_PREVIEWS_COUNT, |max| max.min(WARM_PREVIEWS_COUNT));
+	let thumbnail_dir = library
+		.config()
+		.data_directory()
+		.join(THUMBNAIL_CACHE_DIR_NAME);
+
+	let objects = library
+		.db
+		.object()
+		.find_many(vec![object::pub_id::in_vec(
+			objects.iter().map(|id| id.as_bytes().to_vec()).collect(),
+		)])
+		.order_by(object::date_created::order(Direction::Desc))
+		.take(count as i64)
+		.exec()
+		.await?
+		.into_iter()
+		.filter(|object| find_preview(&thumbnail_dir, &object.cas_id).is_none())
+		.collect::<Vec<_>>();
+
+	if objects.is_empty() {
+		return Ok(PreviewWarming::default());
+	}
+
+	let local_locations = library
+		.db
+		.location()
+		.find_many(vec![
+			location::node_id::equals(library.node_local_id),
+			location::is_online::equals(true),
+		])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|location| location.id)
+		.collect::<Vec<_>>();
+
+	let mut local_paths = HashMap::new();
+	for file_path in library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::object_id::in_vec(objects.iter().map(|object| object.id).collect()),
+			file_path::location_id::in_vec(local_locations),
+		])
+		.exec()
+		.await?
+	{
+		local_paths.entry(file_path.object_id).or_insert(file_path);
+	}
+
+	#[cfg(feature = "ffmpeg")]
+	let skip_videos = budget.should_skip_videos();
+
+	let mut warming = PreviewWarming::default();
+	for object in objects {
+		let file_path = match local_paths.remove(&Some(object.id)) {
+			Some(file_path) => file_path,
+			None => {
+				warming.fetch.push(object.cas_id);
+				continue;
+			}
+		};
+
+		let kind = match file_path.extension.as_deref().and_then(thumbnail_kind) {
+			Some(kind) => kind,
+			None => continue,
+		};
+
+		#[cfg(feature = "ffmpeg")]
+		if skip_videos && matches!(kind, ThumbnailJobStepKind::Video) {
+			continue;
+		}
+
+		if !budget.allows(
+			kind.processing_kind(),
+			object.size_in_bytes.parse().unwrap_or(0),
+		) {
+			continue;
+		}
+
+		warming.generate.push(PreviewWarmerJobStep {
+			cas_id: object.cas_id,
+			location_id: file_path.location_id,
+			materialized_path: file_path.materialized_path,
+			raw_path: file_path.raw_path,
+			kind,
+		});
+	}
+
+	Ok(warming)
+}
+
+/// read_thumbnails returns the thumbnails this node has for the given cas ids, to send to a node
+/// that's warming its previews
+pub async fn read_thumbnails(
+	library: &LibraryContext,
+	cas_ids: &[String],
+) -> Result<HashMap<String, Vec<u8>>, std::io::Error> {
+	let thumbnail_dir = library
+		.config()
+		.data_directory()
+		.join(THUMBNAIL_CACHE_DIR_NAME);
+
+	let mut thumbnails = HashMap::new();
+	for cas_id in cas_ids {
+		match fs::read(thumbnail_dir.join(cas_id).with_extension("webp")).await {
+			Ok(thumbnail) => {
+				thumbnails.insert(cas_id.clone(), thumbnail);
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+			Err(e) => return Err(e),
+		}
+	}
+
+	Ok(thumbnails)
+}
+
+/// write_thumbnails saves thumbnails fetched from another node into the thumbnail cache
+pub async fn write_thumbnails(
+	library: &LibraryContext,
+	thumbnails: HashMap<String, Vec<u8>>,
+) -> Result<usize, std::io::Error> {
+	let thumbnail_dir = library
+		.config()
+		.data_directory()
+		.join(THUMBNAIL_CACHE_DIR_NAME);
+	fs::create_dir_all(&thumbnail_dir).await?;
+
+	let mut written = 0;
+	for (cas_id, thumbnail) in thumbnails {
+		// cas ids come from another node, don't let them point outside of the cache
+		if cas_id.is_empty() || !cas_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+			warn!("Ignoring thumbnail with invalid cas_id {:?}", cas_id);
+			continue;
+		}
+
+		fs::write(
+			thumbnail_dir.join(&cas_id).with_extension("webp"),
+			thumbnail,
+		)
+		.await?;
+		library.emit(CoreEvent::NewThumbnail { cas_id });
+		written += 1;
+	}
+
+	Ok(written)
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for PreviewWarmerJob {
+	type Init = PreviewWarmerJobInit;
+	type Data = PreviewWarmerJobState;
+	type Step = PreviewWarmerJobStep;
+
+	fn name(&self) -> &'static str {
+		PREVIEW_WARMER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let thumbnail_dir = ctx
+			.library_ctx()
+			.config()
+			.data_directory()
+			.join(THUMBNAIL_CACHE_DIR_NAME);
+		fs::create_dir_all(&thumbnail_dir).await?;
+
+		info!(
+			"Warming previews of {} synced objects",
+			state.init.steps.len()
+		);
+
+		ctx.progress(vec![
+			JobReportUpdate::TaskCount(state.init.steps.len()),
+			JobReportUpdate::Message(Message::PreparingFiles {
+				count: state.init.steps.len(),
+			}),
+		]);
+
+		state.data = Some(PreviewWarmerJobState { thumbnail_dir });
+		state.steps = state.init.steps.clone().into();
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+			path: step.materialized_path.clone(),
+		})]);
+
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+		let library_ctx = ctx.library_ctx();
+
+		let output_path = step.kind.preview_path(&data.thumbnail_dir, &step.cas_id);
+		// the thumbnail may have been fetched from a peer since the job was queued
+		if output_path.exists() {
+			ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+				state.step_number + 1,
+			)]);
+			return Ok(());
+		}
+
+		let location = match library_ctx
+			.db
+			.location()
+			.find_unique(location::id::equals(step.location_id))
+			.exec()
+			.await?
+		{
+			Some(location) => location,
+			None => {
+				warn!("Location {} is gone, skipping", step.location_id);
+				return Ok(());
+			}
+		};
+
+		let storage = StorageConfig::parse(location.storage_config.as_deref())
+			.map_err(LocationError::from)?;
+		let path = match &storage {
+			Some(storage) => {
+				let storage = storage.open_in_job(&ctx).map_err(LocationError::from)?;
+				fetch_to_cache(
+					&library_ctx,
+					storage.as_ref(),
+					step.location_id,
+					&step.materialized_path,
+				)
+				.await
+				.map_err(LocationError::from)?
+			}
+			None => resolve_materialized_path(
+				location.local_path.unwrap_or_default(),
+				&step.materialized_path,
+				step.raw_path.as_deref(),
+			),
+		};
+
+		let result = match step.kind {
+			ThumbnailJobStepKind::Image => generate_image_thumbnail(&path, &output_path)
+				.await
+				.map(|_| ()),
+			#[cfg(feature = "ffmpeg")]
+			ThumbnailJobStepKind::Video => super::thumb::generate_video_thumbnail(&path, &output_path).await,
+			kind => generate_rendered_preview(kind, &path, &output_path)
+				.await
+				.map(|_| ()),
+		};
+
+		if storage.is_some() {
+			if let Err(e) = fs::remove_file(&path).await {
+				warn!("Failed to remove cached file {}: {}", path.display(), e);
+			}
+		}
+
+		match result {
+			Ok(()) if matches!(step.kind, ThumbnailJobStepKind::Text) => {}
+			Ok(()) => {
+				library_ctx.emit(CoreEvent::NewThumbnail {
+					cas_id: step.cas_id.clone(),
+				});
+				invalidate_query!(library_ctx, "locations.getExplorerData");
+			}
+			Err(e) => error!("Error warming preview of {:?}: {:#?}", path, e),
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		info!(
+			"Finished warming previews of {} synced objects",
+			state.init.steps.len()
+		);
+
+		if let Err(e) = evict_previews(&ctx.library_ctx()).await {
+			error!("Failed to evict previews: {e:#?}");
+		}
+
+		Ok(None)
+	}
+}
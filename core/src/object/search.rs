@@ -0,0 +1,144 @@
+use prisma_client_rust::Direction;
+use rspc::Type;
+use serde::Deserialize;
+
+use crate::{
+	library::{LibraryContext, SelectionItem},
+	prisma::{archive_entry, file_path, object},
+	util::collation::Collation,
+};
+
+/// How many file paths a search returns when it isn't told
+const DEFAULT_SEARCH_TAKE: i64 = 100;
+/// How many matches are read to be sorted in natural order, the first ones by their plain name
+const COLLATED_SEARCH_WINDOW: i64 = 5_000;
+
+#[derive(Deserialize, Type, Debug)]
+pub struct FileSearchArgs {
+	/// a part of the names of the files looked for
+	pub name: String,
+	pub location_id: Option<i32>,
+	pub take: Option<i32>,
+	/// sorts the file paths found by name in natural order. Only the matches first by their plain
+	/// name are sorted, so with a lot of them some that should come first can be missed.
+	#[serde(default)]
+	pub collation: Option<Collation>,
+}
+
+fn search_params(args: FileSearchArgs) -> Vec<file_path::WhereParam> {
+	let mut params = vec![file_path::name::contains(args.name)];
+	if let Some(location_id) = args.location_id {
+		params.push(file_path::location_id::equals(location_id));
+	}
+	params
+}
+
+/// search_files returns the file paths whose name contains the searched one, with their location
+pub async fn search_files(
+	library: &LibraryContext,
+	args: FileSearchArgs,
+) -> Result<Vec<file_path::Data>, prisma_client_rust::QueryError> {
+	let take = args.take.map_or(DEFAULT_SEARCH_TAKE, i64::from);
+	let collation = args.collation.clone();
+
+	let mut query = library
+		.db
+		.file_path()
+		.find_many(search_params(args))
+		.with(file_path::location::fetch());
+	// the first matches in natural order can only be told once they're sorted, which is only done
+	// for a window of them as sorting every one would read the whole index
+	query = match collation {
+		Some(_) => query
+			.order_by(file_path::name::order(Direction::Asc))
+			.take(take.max(COLLATED_SEARCH_WINDOW)),
+		None => query.take(take),
+	};
+	let mut file_paths = query.exec().await?;
+
+	if let Some(collation) = collation {
+		collation.sort_by_name(&mut file_paths, |file_path| {
+			match file_path.extension.as_deref() {
+				Some(extension) if !extension.is_empty() => {
+					format!("{}.{extension}", file_path.name)
+				}
+				_ => file_path.name.clone(),
+			}
+		});
+		file_paths.truncate(take.max(0) as usize);
+	}
+
+	Ok(file_paths)
+}
+
+/// search_archive_entries returns the entries of indexed archives and disk images whose name
+/// contains the searched one, with the file path of the archive they're in
+pub async fn search_archive_entries(
+	library: &LibraryContext,
+	args: FileSearchArgs,
+) -> Result<Vec<archive_entry::Data>, prisma_client_rust::QueryError> {
+	let mut params = vec![archive_entry::name::contains(args.name)];
+	if let Some(location_id) = args.location_id {
+		params.push(archive_entry::location_id::equals(location_id));
+	}
+
+	library
+		.db
+		.archive_entry()
+		.find_many(params)
+		.with(archive_entry::file_path::fetch())
+		.take(args.take.map_or(DEFAULT_SEARCH_TAKE, i64::from))
+		.exec()
+		.await
+}
+
+/// search_items returns every file path a search finds, unless it's told how many to take, for
+/// making a selection of the results without them going through the client
+pub async fn search_items(
+	library: &LibraryContext,
+	args: FileSearchArgs,
+) -> Result<Vec<SelectionItem>, prisma_client_rust::QueryError> {
+	let take = args.take;
+	let mut query = library.db.file_path().find_many(search_params(args));
+	if let Some(take) = take {
+		query = query.take(take.into());
+	}
+
+	Ok(query
+		.exec()
+		.await?
+		.into_iter()
+		.map(|file_path| SelectionItem {
+			location_id: file_path.location_id,
+			file_path_id: file_path.id,
+		})
+		.collect())
+}
+
+/// duplicate_objects returns the objects with more than one file path, the largest first, with
+/// their file paths. Given a location, only the objects with a copy in it are returned.
+pub async fn duplicate_objects(
+	library: &LibraryContext,
+	location_id: Option<i32>,
+) -> Result<Vec<object::Data>, prisma_client_rust::QueryError> {
+	let in_location = location_id
+		.map(|location_id| vec![file_path::location_id::equals(location_id)])
+		.unwrap_or_default();
+
+	let mut objects = library
+		.db
+		.object()
+		.find_many(vec![object::file_paths::some(in_location)])
+		.with(object::file_paths::fetch(vec![]))
+		.exec()
+		.await?
+		.into_iter()
+		.filter(|object| matches!(object.file_paths(), Ok(file_paths) if file_paths.len() > 1))
+		.collect::<Vec<_>>();
+
+	objects.sort_by_key(|object| {
+		std::cmp::Reverse(object.size_in_bytes.parse::<u64>().unwrap_or_default())
+	});
+
+	Ok(objects)
+}
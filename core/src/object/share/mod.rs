@@ -0,0 +1,686 @@
+//! Links to share a file or an album with anyone, for a limited time. The files are encrypted on
+//! the node with a key made for the share and uploaded to the node's [`ShareRelay`], a server that
+//! holds them until the share expires or is revoked. The key is only ever in the link, after the
+//! `#`, so neither the relay nor anyone reading its traffic can see what's shared.
+//!
+//! The relay is asked over HTTP, with the node's token for anything but reading a share:
+//! - `PUT <relay>/shares/<id>/<object>` uploads an object, `X-Expires-At` telling until when it's
+//!   served
+//! - `GET <relay>/shares/<id>/<object>` reads it back, with a 404 or a 410 once the share expired
+//!   or was revoked. The relay counts how many times each share's manifest is read.
+//! - `GET <relay>/shares/<id>/stats` returns `{ "accesses": <count>, "last_access": <date> }`
+//! - `DELETE <relay>/shares/<id>` revokes a share, removing everything uploaded for it
+//!
+//! A share is a manifest listing its files, and the parts of each file, each object encrypted on
+//! its own so files are uploaded and downloaded a part at a time.
+
+mod relay;
+
+pub use relay::ShareRelay;
+
+use std::{
+	fs::File,
+	io::{Read, Write},
+	path::{Component, Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use prisma_client_rust::Direction;
+use rspc::Type;
+use sd_crypto::{
+	crypto::stream::{Algorithm, StreamDecryption, StreamEncryption},
+	primitives::{generate_master_key, generate_nonce, to_array},
+	Error as CryptoError, Protected,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+	invalidate_query,
+	job::{TransferGovernor, TransferProvider},
+	library::LibraryContext,
+	location::LocationError,
+	node::NodeConfig,
+	prisma::{file_path, object_in_album, share},
+	util::os_path::resolve_materialized_path,
+};
+
+use super::fs::batch::local_location;
+
+use relay::RelayClient;
+
+/// Files are encrypted and uploaded in parts of this size
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const MANIFEST_OBJECT: &str = "manifest";
+const ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+/// Shares expire after a month at the most
+const MAX_EXPIRY_HOURS: u32 = 30 * 24;
+
+#[derive(Error, Debug)]
+pub enum ShareError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("{0}")]
+	LocationError(#[from] LocationError),
+	#[error("I/O error: {0}")]
+	IOError(#[from] std::io::Error),
+	#[error("Crypto error: {0}")]
+	CryptoError(#[from] CryptoError),
+	#[error("Request to the share relay failed: {0}")]
+	Request(#[from] reqwest::Error),
+	#[error("The share relay answered with status {0}: {1}")]
+	Relay(u16, String),
+	#[error("Invalid response from the share relay: {0}")]
+	InvalidResponse(String),
+	#[error("Invalid share relay: {0}")]
+	InvalidRelay(String),
+	#[error("No share relay is set up on this node")]
+	NoRelay,
+	#[error("Share not found (id: {0})")]
+	NotFound(i32),
+	#[error("Nothing to share, the files aren't on an online location of this node")]
+	NothingToShare,
+	#[error("Shares expire within 1 to {MAX_EXPIRY_HOURS} hours")]
+	InvalidExpiry,
+	#[error("Invalid share link: {0}")]
+	InvalidLink(String),
+	#[error("The share expired or was revoked")]
+	Unavailable,
+	#[error("'{0}' doesn't have the content it was shared with")]
+	ChecksumMismatch(String),
+}
+
+impl From<ShareError> for rspc::Error {
+	fn from(err: ShareError) -> Self {
+		match err {
+			ShareError::NotFound(_) | ShareError::Unavailable => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			ShareError::NoRelay
+			| ShareError::NothingToShare
+			| ShareError::InvalidExpiry
+			| ShareError::InvalidRelay(_)
+			| ShareError::InvalidLink(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			ShareError::LocationError(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// What a share is of
+#[derive(Deserialize, Type, Debug)]
+#[serde(tag = "type")]
+pub enum ShareTarget {
+	File {
+		location_id: i32,
+		file_path_id: i32,
+	},
+	/// every file of the album that's on an online location of this node
+	Album {
+		album_id: i32,
+	},
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct ShareCreateArgs {
+	pub target: ShareTarget,
+	/// the name of the share, the file's or the album's by default
+	pub name: Option<String>,
+	pub expires_in_hours: u32,
+}
+
+/// A share that was just made, the only time its link is known
+#[derive(Serialize, Type, Debug)]
+pub struct CreatedShare {
+	pub share: share::Data,
+	pub link: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SharedFile {
+	name: String,
+	size: u64,
+	/// blake3 of the whole content
+	checksum: String,
+	parts: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ShareManifest {
+	name: String,
+	date_expires: DateTime<Utc>,
+	files: Vec<SharedFile>,
+}
+
+/// part_object is the name of a part of a file on the relay
+fn part_object(file: usize, part: u32) -> String {
+	format!("{file}/{part}")
+}
+
+/// seal encrypts an object of a share with the nonce in front of it. The object's name is
+/// authenticated along with it, so the relay can't swap objects around.
+fn seal(key: &Protected<[u8; 32]>, object: &str, data: &[u8]) -> Result<Vec<u8>, ShareError> {
+	let nonce = generate_nonce(ALGORITHM);
+	let encrypted =
+		StreamEncryption::encrypt_bytes(key.clone(), &nonce, ALGORITHM, data, object.as_bytes())?;
+
+	Ok([nonce, encrypted].concat())
+}
+
+fn open(key: &Protected<[u8; 32]>, object: &str, data: &[u8]) -> Result<Vec<u8>, ShareError> {
+	if data.len() < ALGORITHM.nonce_len() {
+		return Err(ShareError::InvalidResponse(format!(
+			"'{object}' is too short to be encrypted"
+		)));
+	}
+
+	let (nonce, encrypted) = data.split_at(ALGORITHM.nonce_len());
+	Ok(StreamDecryption::decrypt_bytes(
+		key.clone(),
+		nonce,
+		ALGORITHM,
+		encrypted,
+		object.as_bytes(),
+	)?
+	.expose()
+	.clone())
+}
+
+/// share_link makes the link to a share, with its key after the `#`
+fn share_link(
+	relay: &RelayClient,
+	share_id: &str,
+	key: &Protected<[u8; 32]>,
+) -> Result<String, ShareError> {
+	let mut link = relay.share_url(share_id, None)?;
+	link.set_fragment(Some(&base64::encode_config(
+		key.expose(),
+		base64::URL_SAFE_NO_PAD,
+	)));
+
+	Ok(link.to_string())
+}
+
+/// parse_link splits a share link into the relay, the id of the share and its key
+fn parse_link(link: &str) -> Result<(RelayClient, String, Protected<[u8; 32]>), ShareError> {
+	let invalid = |reason: &str| ShareError::InvalidLink(reason.to_string());
+
+	let mut url = reqwest::Url::parse(link.trim()).map_err(|e| invalid(&e.to_string()))?;
+	let key = base64::decode_config(url.fragment().unwrap_or_default(), base64::URL_SAFE_NO_PAD)
+		.map_err(|_| invalid("the key is missing or malformed"))?;
+	let key = Protected::new(to_array(key).map_err(|_| invalid("the key has the wrong length"))?);
+
+	let segments = url
+		.path_segments()
+		.map(|segments| segments.collect::<Vec<_>>())
+		.unwrap_or_default();
+	let share_id = match segments.as_slice() {
+		[.., "shares", id] if Uuid::parse_str(id).is_ok() => id.to_string(),
+		_ => return Err(invalid("it doesn't point to a share")),
+	};
+
+	let relay_path = url
+		.path()
+		.trim_end_matches(&format!("shares/{share_id}"))
+		.to_string();
+	url.set_path(&relay_path);
+	url.set_fragment(None);
+
+	Ok((RelayClient::new(url.as_str(), None)?, share_id, key))
+}
+
+/// shared_files returns the paths of the files of a share
+async fn shared_files(
+	library: &LibraryContext,
+	target: &ShareTarget,
+) -> Result<Vec<PathBuf>, ShareError> {
+	let file_paths = match target {
+		ShareTarget::File {
+			location_id,
+			file_path_id,
+		} => library
+			.db
+			.file_path()
+			.find_unique(file_path::location_id_id(*location_id, *file_path_id))
+			.exec()
+			.await?
+			.into_iter()
+			.collect::<Vec<_>>(),
+		ShareTarget::Album { album_id } => {
+			let object_ids = library
+				.db
+				.object_in_album()
+				.find_many(vec![object_in_album::album_id::equals(*album_id)])
+				.exec()
+				.await?
+				.into_iter()
+				.map(|object_in_album| object_in_album.object_id)
+				.collect();
+
+			let mut file_paths = library
+				.db
+				.file_path()
+				.find_many(vec![file_path::object_id::in_vec(object_ids)])
+				.exec()
+				.await?;
+			// one copy of each object
+			file_paths.sort_by_key(|file_path| file_path.object_id);
+			file_paths.dedup_by_key(|file_path| file_path.object_id);
+			file_paths
+		}
+	};
+
+	let mut paths = Vec::with_capacity(file_paths.len());
+	for file_path in file_paths.into_iter().filter(|file_path| !file_path.is_dir) {
+		let location_path = match local_location(library, file_path.location_id).await {
+			Ok((location_path, _)) => location_path,
+			Err(e) => {
+				warn!("Not sharing '{}': {e}", file_path.materialized_path);
+				continue;
+			}
+		};
+
+		paths.push(resolve_materialized_path(
+			&location_path,
+			&file_path.materialized_path,
+			file_path.raw_path.as_deref(),
+		));
+	}
+
+	Ok(paths)
+}
+
+/// upload_file encrypts a file and uploads it a part at a time
+async fn upload_file(
+	relay: &RelayClient,
+	transfers: &TransferGovernor,
+	share_id: &str,
+	key: &Protected<[u8; 32]>,
+	index: usize,
+	path: &Path,
+	date_expires: DateTime<Utc>,
+) -> Result<SharedFile, ShareError> {
+	let name = path
+		.file_name()
+		.and_then(|name| name.to_str())
+		.ok_or_else(|| {
+			std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				format!("'{}' has no name", path.display()),
+			)
+		})?
+		.to_string();
+
+	let mut file = File::open(path)?;
+	let mut hasher = blake3::Hasher::new();
+	let (mut size, mut parts) = (0, 0);
+	let mut buffer = vec![0; PART_SIZE];
+	loop {
+		let read = block_in_place(|| read_part(&mut file, &mut buffer))?;
+		// empty files still get a part, so there's something to download
+		if read == 0 && parts > 0 {
+			break;
+		}
+
+		let object = part_object(index, parts);
+		hasher.update(&buffer[..read]);
+		let encrypted = block_in_place(|| seal(key, &object, &buffer[..read]))?;
+		transfers
+			.throttle(TransferProvider::Relay, encrypted.len() as u64)
+			.await;
+		relay
+			.put(share_id, &object, encrypted, date_expires)
+			.await?;
+
+		size += read as u64;
+		parts += 1;
+		if read < PART_SIZE {
+			break;
+		}
+	}
+
+	Ok(SharedFile {
+		name,
+		size,
+		checksum: hasher.finalize().to_hex().to_string(),
+		parts,
+	})
+}
+
+/// read_part fills the buffer unless the file ends first, returning how much was read
+fn read_part(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+	let mut read = 0;
+	while read < buffer.len() {
+		match file.read(&mut buffer[read..])? {
+			0 => break,
+			n => read += n,
+		}
+	}
+
+	Ok(read)
+}
+
+/// create_share encrypts and uploads the files of a share, returning it with its link
+pub async fn create_share(
+	library: &LibraryContext,
+	transfers: &TransferGovernor,
+	args: ShareCreateArgs,
+) -> Result<CreatedShare, ShareError> {
+	let relay = library
+		.config()
+		.get()
+		.await
+		.share_relay
+		.ok_or(ShareError::NoRelay)?;
+	if args.expires_in_hours == 0 || args.expires_in_hours > MAX_EXPIRY_HOURS {
+		return Err(ShareError::InvalidExpiry);
+	}
+
+	let paths = shared_files(library, &args.target).await?;
+	if paths.is_empty() {
+		return Err(ShareError::NothingToShare);
+	}
+	let name = match (args.name, &args.target) {
+		(Some(name), _) => name,
+		(None, ShareTarget::Album { album_id }) => library
+			.db
+			.album()
+			.find_unique(crate::prisma::album::id::equals(*album_id))
+			.exec()
+			.await?
+			.map(|album| album.name)
+			.unwrap_or_default(),
+		(None, ShareTarget::File { .. }) => paths[0]
+			.file_name()
+			.map(|name| name.to_string_lossy().to_string())
+			.unwrap_or_default(),
+	};
+
+	let client = RelayClient::new(&relay.url, relay.token)?;
+	let share_id = Uuid::new_v4().to_string();
+	let key = generate_master_key();
+	let date_expires = Utc::now() + Duration::hours(args.expires_in_hours as i64);
+
+	// the user is waiting on it, so it doesn't wait for the transfer window
+	let _permit = transfers.transfer_now(TransferProvider::Relay).await;
+	let mut files = Vec::with_capacity(paths.len());
+	for (index, path) in paths.iter().enumerate() {
+		match upload_file(
+			&client,
+			transfers,
+			&share_id,
+			&key,
+			index,
+			path,
+			date_expires,
+		)
+		.await
+		{
+			Ok(file) => files.push(file),
+			Err(e) => {
+				// what was uploaded so far is of no use without the manifest
+				if let Err(e) = client.delete(&share_id).await {
+					warn!("Failed to remove the parts of share {share_id} from the relay: {e}");
+				}
+				return Err(e);
+			}
+		}
+	}
+
+	let manifest = ShareManifest {
+		name: name.clone(),
+		date_expires,
+		files,
+	};
+	let json =
+		serde_json::to_vec(&manifest).map_err(|e| ShareError::InvalidResponse(e.to_string()))?;
+	client
+		.put(
+			&share_id,
+			MANIFEST_OBJECT,
+			seal(&key, MANIFEST_OBJECT, &json)?,
+			date_expires,
+		)
+		.await?;
+
+	let share = library
+		.db
+		.share()
+		.create(
+			share_id.clone(),
+			name,
+			client.url().to_string(),
+			manifest.files.len() as i32,
+			manifest.files.iter().map(|file| file.size).sum::<u64>() as i64,
+			date_expires.into(),
+			vec![],
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "shares.list");
+
+	info!(
+		"Shared {} files as share {share_id}, until {date_expires}",
+		manifest.files.len()
+	);
+
+	Ok(CreatedShare {
+		link: share_link(&client, &share_id, &key)?,
+		share,
+	})
+}
+
+/// relay_client returns a client for the relay a share was uploaded to, with the node's token if
+/// it's still the node's relay
+fn relay_client(config: &NodeConfig, share: &share::Data) -> Result<RelayClient, ShareError> {
+	let token = config
+		.share_relay
+		.as_ref()
+		.filter(|relay| {
+			RelayClient::new(&relay.url, None)
+				.map(|client| client.url().as_str() == share.relay_url)
+				.unwrap_or(false)
+		})
+		.and_then(|relay| relay.token.clone());
+
+	RelayClient::new(&share.relay_url, token)
+}
+
+/// list_shares lists the shares of the library, the latest first, with the access counts of those
+/// still open as the relay has them now
+pub async fn list_shares(library: &LibraryContext) -> Result<Vec<share::Data>, ShareError> {
+	let config = library.config().get().await;
+	let mut shares = library
+		.db
+		.share()
+		.find_many(vec![])
+		.order_by(share::date_created::order(Direction::Desc))
+		.exec()
+		.await?;
+
+	let now = Utc::now();
+	for share in shares
+		.iter_mut()
+		.filter(|share| share.date_revoked.is_none() && share.date_expires > now)
+	{
+		let stats = match relay_client(&config, share) {
+			Ok(client) => client.stats(&share.pub_id).await,
+			Err(e) => Err(e),
+		};
+		let stats = match stats {
+			Ok(stats) => stats,
+			// the counts from last time are shown, the relay may just be unreachable
+			Err(e) => {
+				warn!("Failed to get the access count of share {}: {e}", share.id);
+				continue;
+			}
+		};
+		if stats.accesses as i32 == share.access_count {
+			continue;
+		}
+
+		*share = library
+			.db
+			.share()
+			.update(
+				share::id::equals(share.id),
+				vec![
+					share::access_count::set(stats.accesses as i32),
+					share::last_access::set(stats.last_access.map(Into::into)),
+				],
+			)
+			.exec()
+			.await?;
+	}
+
+	Ok(shares)
+}
+
+/// revoke_share removes a share from the relay, so its link stops working
+pub async fn revoke_share(library: &LibraryContext, share_id: i32) -> Result<(), ShareError> {
+	let share = library
+		.db
+		.share()
+		.find_unique(share::id::equals(share_id))
+		.exec()
+		.await?
+		.ok_or(ShareError::NotFound(share_id))?;
+
+	relay_client(&library.config().get().await, &share)?
+		.delete(&share.pub_id)
+		.await?;
+
+	library
+		.db
+		.share()
+		.update(
+			share::id::equals(share_id),
+			vec![share::date_revoked::set(Some(Utc::now().into()))],
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "shares.list");
+
+	Ok(())
+}
+
+/// open_share downloads the files of a share link into `target_dir`, returning where they were put
+pub async fn open_share(
+	config: &NodeConfig,
+	transfers: &TransferGovernor,
+	link: &str,
+	target_dir: &Path,
+) -> Result<Vec<PathBuf>, ShareError> {
+	let (relay, share_id, key) = parse_link(link)?;
+
+	let manifest = relay.get(&share_id, MANIFEST_OBJECT).await?;
+	let manifest: ShareManifest =
+		serde_json::from_slice(&block_in_place(|| open(&key, MANIFEST_OBJECT, &manifest))?)
+			.map_err(|e| ShareError::InvalidResponse(e.to_string()))?;
+	if manifest.date_expires < Utc::now() {
+		return Err(ShareError::Unavailable);
+	}
+
+	let _permit = transfers.transfer_now(TransferProvider::Relay).await;
+	let mut paths = Vec::with_capacity(manifest.files.len());
+	for (index, file) in manifest.files.iter().enumerate() {
+		// names come from whoever made the link, and mustn't lead out of the target
+		let mut components = Path::new(&file.name).components();
+		let name = match (components.next(), components.next()) {
+			(Some(Component::Normal(name)), None) => name,
+			_ => {
+				return Err(ShareError::InvalidLink(format!(
+					"invalid file name '{}'",
+					file.name
+				)))
+			}
+		};
+		let path = config
+			.conflict_naming
+			.free_path(&target_dir.join(name), &config.name);
+		let partial = path.with_file_name(format!(".{}.sdshare", file.name));
+
+		let downloaded = async {
+			tokio::fs::create_dir_all(target_dir).await?;
+			let mut writer = File::create(&partial)?;
+			let mut hasher = blake3::Hasher::new();
+			for part in 0..file.parts {
+				let object = part_object(index, part);
+				let encrypted = relay.get(&share_id, &object).await?;
+				transfers
+					.throttle(TransferProvider::Relay, encrypted.len() as u64)
+					.await;
+
+				let data = block_in_place(|| open(&key, &object, &encrypted))?;
+				hasher.update(&data);
+				block_in_place(|| writer.write_all(&data))?;
+			}
+
+			if hasher.finalize().to_hex().as_str() != file.checksum {
+				return Err(ShareError::ChecksumMismatch(file.name.clone()));
+			}
+
+			Ok::<_, ShareError>(())
+		}
+		.await;
+
+		if let Err(e) = downloaded {
+			tokio::fs::remove_file(&partial).await.ok();
+			return Err(e);
+		}
+		tokio::fs::rename(&partial, &path).await?;
+		paths.push(path);
+	}
+
+	info!(
+		"Opened share '{}', {} files downloaded to {}",
+		manifest.name,
+		paths.len(),
+		target_dir.display()
+	);
+
+	Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn links_carry_the_relay_and_key() {
+		let relay = RelayClient::new("https://relay.example.com/sd", None).unwrap();
+		let share_id = Uuid::new_v4().to_string();
+		let key = generate_master_key();
+
+		let link = share_link(&relay, &share_id, &key).unwrap();
+		let (parsed_relay, parsed_id, parsed_key) = parse_link(&link).unwrap();
+
+		assert_eq!(parsed_relay.url().as_str(), "https://relay.example.com/sd/");
+		assert_eq!(parsed_id, share_id);
+		assert_eq!(parsed_key.expose(), key.expose());
+
+		let without_key = link.split('#').next().unwrap();
+		assert!(matches!(
+			parse_link(without_key),
+			Err(ShareError::InvalidLink(_))
+		));
+	}
+
+	#[test]
+	fn objects_only_open_under_their_name() {
+		let key = generate_master_key();
+		let sealed = seal(&key, &part_object(0, 1), b"part").unwrap();
+
+		assert_eq!(open(&key, &part_object(0, 1), &sealed).unwrap(), b"part");
+		assert!(open(&key, &part_object(0, 2), &sealed).is_err());
+	}
+}
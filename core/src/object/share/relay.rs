@@ -0,0 +1,141 @@
+use chrono::{DateTime, Utc};
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode, Url};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use super::ShareError;
+
+/// The header telling the relay when to stop serving what's uploaded
+const EXPIRES_HEADER: &str = "X-Expires-At";
+
+/// The relay the node uploads shares to
+#[derive(Debug, Serialize, Deserialize, Clone, Type, PartialEq, Eq)]
+pub struct ShareRelay {
+	pub url: String,
+	/// sent as a bearer token when uploading, revoking and reading access counts
+	pub token: Option<String>,
+}
+
+impl ShareRelay {
+	/// validate checks the relay's url can be uploaded to
+	pub fn validate(&self) -> Result<(), ShareError> {
+		RelayClient::new(&self.url, None).map(|_| ())
+	}
+}
+
+/// How many times a share was opened, as counted by the relay
+#[derive(Debug, Deserialize)]
+pub(super) struct RelayStats {
+	pub accesses: u32,
+	pub last_access: Option<DateTime<Utc>>,
+}
+
+pub(super) struct RelayClient {
+	client: Client,
+	url: Url,
+	token: Option<String>,
+}
+
+impl RelayClient {
+	pub fn new(url: &str, token: Option<String>) -> Result<Self, ShareError> {
+		let mut url = Url::parse(url).map_err(|e| ShareError::InvalidRelay(e.to_string()))?;
+		if !matches!(url.scheme(), "http" | "https") {
+			return Err(ShareError::InvalidRelay(format!(
+				"unsupported scheme '{}'",
+				url.scheme()
+			)));
+		}
+		// so joining keeps the relay's own path
+		if !url.path().ends_with('/') {
+			url.set_path(&format!("{}/", url.path()));
+		}
+
+		Ok(Self {
+			client: Client::new(),
+			url,
+			token,
+		})
+	}
+
+	pub fn url(&self) -> &Url {
+		&self.url
+	}
+
+	/// share_url returns where the relay serves a share, or one of its objects
+	pub fn share_url(&self, share_id: &str, object: Option<&str>) -> Result<Url, ShareError> {
+		let path = match object {
+			Some(object) => format!("shares/{share_id}/{object}"),
+			None => format!("shares/{share_id}"),
+		};
+
+		self.url
+			.join(&path)
+			.map_err(|e| ShareError::InvalidRelay(e.to_string()))
+	}
+
+	async fn send(&self, request: RequestBuilder) -> Result<Response, ShareError> {
+		let request = match &self.token {
+			Some(token) => request.bearer_auth(token),
+			None => request,
+		};
+		let response = request.send().await?;
+
+		let status = response.status();
+		match status {
+			// the relay stops serving shares once they expire or are revoked
+			StatusCode::NOT_FOUND | StatusCode::GONE => Err(ShareError::Unavailable),
+			_ if !status.is_success() => Err(ShareError::Relay(
+				status.as_u16(),
+				response.text().await.unwrap_or_default(),
+			)),
+			_ => Ok(response),
+		}
+	}
+
+	pub async fn put(
+		&self,
+		share_id: &str,
+		object: &str,
+		data: Vec<u8>,
+		expires: DateTime<Utc>,
+	) -> Result<(), ShareError> {
+		self.send(
+			self.client
+				.put(self.share_url(share_id, Some(object))?)
+				.header(EXPIRES_HEADER, expires.to_rfc3339())
+				.header(header::CONTENT_TYPE, "application/octet-stream")
+				.body(data),
+		)
+		.await?;
+
+		Ok(())
+	}
+
+	pub async fn get(&self, share_id: &str, object: &str) -> Result<Vec<u8>, ShareError> {
+		let response = self
+			.send(self.client.get(self.share_url(share_id, Some(object))?))
+			.await?;
+
+		Ok(response.bytes().await?.to_vec())
+	}
+
+	/// delete removes everything uploaded for a share, succeeding if it was already gone
+	pub async fn delete(&self, share_id: &str) -> Result<(), ShareError> {
+		match self
+			.send(self.client.delete(self.share_url(share_id, None)?))
+			.await
+		{
+			Ok(_) | Err(ShareError::Unavailable) => Ok(()),
+			Err(e) => Err(e),
+		}
+	}
+
+	pub async fn stats(&self, share_id: &str) -> Result<RelayStats, ShareError> {
+		let response = self
+			.send(self.client.get(self.share_url(share_id, Some("stats"))?))
+			.await?;
+
+		serde_json::from_str(&response.text().await?)
+			.map_err(|e| ShareError::InvalidResponse(e.to_string()))
+	}
+}
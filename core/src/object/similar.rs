@@ -0,0 +1,459 @@
+//! Near-duplicate images. Images get two perceptual hashes as they're described with their thumbnail,
+//! a pHash from the low frequencies of their DCT and a dHash from how brightness changes across them,
+//! which barely change when an image is resized, re-encoded or screenshotted. Images whose hashes are
+//! a few bits apart are clustered together, which finds the copies exact cas_id matches miss.
+
+use std::collections::HashMap;
+
+use image::{imageops::FilterType, DynamicImage};
+use prisma_client_rust::QueryError;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	library::LibraryContext,
+	prisma::{media_data, object},
+};
+
+/// Size images are shrunk to before their DCT
+const PHASH_SIZE: usize = 32;
+/// Size of the corner of lowest frequencies of the DCT the pHash is made of
+const PHASH_BITS_SIZE: usize = 8;
+/// How many bits of two hashes can differ for their images to be similar when not asked for
+const DEFAULT_MAX_DISTANCE: u32 = 8;
+/// Past this many bits, images have little in common but their colors
+const MAX_DISTANCE: u32 = 16;
+
+/// ImageHashes are the perceptual hashes of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHashes {
+	pub perceptual: u64,
+	pub difference: u64,
+}
+
+impl ImageHashes {
+	pub fn new(img: &DynamicImage) -> Self {
+		Self {
+			perceptual: perceptual_hash(img),
+			difference: difference_hash(img),
+		}
+	}
+
+	/// parse reads the hashes as they're stored with the media data of an object
+	pub fn parse(perceptual: &str, difference: &str) -> Option<Self> {
+		Some(Self {
+			perceptual: u64::from_str_radix(perceptual, 16).ok()?,
+			difference: u64::from_str_radix(difference, 16).ok()?,
+		})
+	}
+
+	/// distance returns how many bits the hashes of two images differ by, the most of either hash
+	pub fn distance(&self, other: &Self) -> u32 {
+		(self.perceptual ^ other.perceptual)
+			.count_ones()
+			.max((self.difference ^ other.difference).count_ones())
+	}
+}
+
+/// to_hex formats a hash as it's stored
+pub fn to_hex(hash: u64) -> String {
+	format!("{hash:016x}")
+}
+
+/// perceptual_hash sets a bit for each of the lowest frequencies of the image's DCT that's above
+/// their median
+fn perceptual_hash(img: &DynamicImage) -> u64 {
+	let pixels = img
+		.resize_exact(PHASH_SIZE as u32, PHASH_SIZE as u32, FilterType::Triangle)
+		.to_luma8()
+		.pixels()
+		.map(|pixel| pixel.0[0] as f64)
+		.collect::<Vec<_>>();
+
+	let cosines = (0..PHASH_BITS_SIZE)
+		.map(|u| {
+			(0..PHASH_SIZE)
+				.map(|x| {
+					((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * PHASH_SIZE) as f64)
+						.cos()
+				})
+				.collect::<Vec<_>>()
+		})
+		.collect::<Vec<_>>();
+
+	let mut frequencies = Vec::with_capacity(PHASH_BITS_SIZE * PHASH_BITS_SIZE);
+	for v in 0..PHASH_BITS_SIZE {
+		for u in 0..PHASH_BITS_SIZE {
+			let mut sum = 0.0;
+			for y in 0..PHASH_SIZE {
+				for x in 0..PHASH_SIZE {
+					sum += pixels[y * PHASH_SIZE + x] * cosines[u][x] * cosines[v][y];
+				}
+			}
+			frequencies.push(sum);
+		}
+	}
+
+	// the first is the average brightness, which says nothing of what's in the image
+	let mut sorted = frequencies[1..].to_vec();
+	sorted.sort_by(|a, b| a.total_cmp(b));
+	let median = sorted[sorted.len() / 2];
+
+	frequencies
+		.iter()
+		.enumerate()
+		.fold(0, |hash, (i, frequency)| {
+			if *frequency > median {
+				hash | (1 << i)
+			} else {
+				hash
+			}
+		})
+}
+
+/// difference_hash sets a bit for each pixel of the shrunk image that's darker than the one to its right
+fn difference_hash(img: &DynamicImage) -> u64 {
+	let img = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+	let mut hash = 0;
+	for y in 0..8 {
+		for x in 0..8 {
+			if img.get_pixel(x, y).0[0] < img.get_pixel(x + 1, y).0[0] {
+				hash |= 1 << (y * 8 + x);
+			}
+		}
+	}
+	hash
+}
+
+/// A BK-tree of perceptual hashes, to find the ones near a hash without comparing it with all of them
+#[derive(Default)]
+struct BkTree {
+	/// each node's hash, the index of its image and its children by their distance to it
+	nodes: Vec<(u64, usize, HashMap<u32, usize>)>,
+}
+
+impl BkTree {
+	fn insert(&mut self, hash: u64, index: usize) {
+		if self.nodes.is_empty() {
+			self.nodes.push((hash, index, HashMap::new()));
+			return;
+		}
+
+		let mut node = 0;
+		loop {
+			let distance = (self.nodes[node].0 ^ hash).count_ones();
+			match self.nodes[node].2.get(&distance) {
+				Some(child) => node = *child,
+				None => {
+					let child = self.nodes.len();
+					self.nodes.push((hash, index, HashMap::new()));
+					self.nodes[node].2.insert(distance, child);
+					return;
+				}
+			}
+		}
+	}
+
+	/// find returns the indexes of the images whose hashes are at most `max_distance` from `hash`
+	fn find(&self, hash: u64, max_distance: u32) -> Vec<usize> {
+		let mut found = vec![];
+		let mut pending = if self.nodes.is_empty() {
+			vec![]
+		} else {
+			vec![0]
+		};
+		while let Some(node) = pending.pop() {
+			let (node_hash, index, children) = &self.nodes[node];
+			let distance = (node_hash ^ hash).count_ones();
+			if distance <= max_distance {
+				found.push(*index);
+			}
+			pending.extend(
+				children
+					.iter()
+					.filter(|(child_distance, _)| {
+						distance.abs_diff(**child_distance) <= max_distance
+					})
+					.map(|(_, child)| *child),
+			);
+		}
+		found
+	}
+}
+
+/// cluster groups images whose hashes are at most `max_distance` apart, along with the ones similar to
+/// those, and returns the groups of more than one image by their indexes
+fn cluster(hashes: &[ImageHashes], max_distance: u32) -> Vec<Vec<usize>> {
+	let mut tree = BkTree::default();
+	for (index, hashes) in hashes.iter().enumerate() {
+		tree.insert(hashes.perceptual, index);
+	}
+
+	// union-find, each image pointing towards the first of its cluster
+	let mut parents = (0..hashes.len()).collect::<Vec<_>>();
+	fn root(parents: &mut [usize], mut index: usize) -> usize {
+		while parents[index] != index {
+			parents[index] = parents[parents[index]];
+			index = parents[index];
+		}
+		index
+	}
+
+	for (index, image) in hashes.iter().enumerate() {
+		for other in tree.find(image.perceptual, max_distance) {
+			if other != index && image.distance(&hashes[other]) <= max_distance {
+				let (a, b) = (root(&mut parents, index), root(&mut parents, other));
+				parents[a.max(b)] = a.min(b);
+			}
+		}
+	}
+
+	let mut clusters = HashMap::<usize, Vec<usize>>::new();
+	for index in 0..hashes.len() {
+		let root = root(&mut parents, index);
+		clusters.entry(root).or_default().push(index);
+	}
+
+	let mut clusters = clusters
+		.into_values()
+		.filter(|cluster| cluster.len() > 1)
+		.collect::<Vec<_>>();
+	clusters.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+	clusters
+}
+
+/// save_hashes stores the hashes of an image with the media data of its object, for images that were
+/// described before they were hashed
+pub async fn save_hashes(
+	library: &LibraryContext,
+	object_id: i32,
+	hashes: ImageHashes,
+) -> Result<(), QueryError> {
+	let params = vec![
+		media_data::perceptual_hash::set(Some(to_hex(hashes.perceptual))),
+		media_data::difference_hash::set(Some(to_hex(hashes.difference))),
+	];
+
+	let existing = library
+		.db
+		.media_data()
+		.find_unique(media_data::id::equals(object_id))
+		.exec()
+		.await?;
+
+	if existing.is_some() {
+		library
+			.db
+			.media_data()
+			.update(media_data::id::equals(object_id), params)
+			.exec()
+			.await?;
+	} else {
+		library
+			.db
+			.media_data()
+			.create(object::id::equals(object_id), params)
+			.exec()
+			.await?;
+	}
+
+	Ok(())
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct SimilarImagesArgs {
+	/// only the images similar to this object, every cluster of the library when not given
+	pub object_id: Option<i32>,
+	/// how many bits of their hashes can differ for images to be similar, up to 16
+	pub max_distance: Option<u32>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct SimilarImage {
+	pub object_id: i32,
+	pub cas_id: String,
+	pub pixel_width: Option<i32>,
+	pub pixel_height: Option<i32>,
+	/// how many bits its hashes differ from the first image of its cluster
+	pub distance: u32,
+}
+
+/// similar_images returns clusters of visually similar images, the largest first, each starting with
+/// its largest image. Objects are already exact duplicates of their other copies, so these are images
+/// with different content that look the same.
+pub async fn similar_images(
+	library: &LibraryContext,
+	args: SimilarImagesArgs,
+) -> Result<Vec<Vec<SimilarImage>>, QueryError> {
+	let max_distance = args
+		.max_distance
+		.unwrap_or(DEFAULT_MAX_DISTANCE)
+		.min(MAX_DISTANCE);
+
+	let media = library
+		.db
+		.media_data()
+		.find_many(vec![
+			media_data::perceptual_hash::not(None),
+			media_data::difference_hash::not(None),
+		])
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|data| {
+			let hashes = ImageHashes::parse(
+				data.perceptual_hash.as_deref()?,
+				data.difference_hash.as_deref()?,
+			)?;
+			Some((data, hashes))
+		})
+		.collect::<Vec<_>>();
+
+	let clusters = match args.object_id {
+		Some(object_id) => {
+			let hashes = match media.iter().find(|(data, _)| data.id == object_id) {
+				Some((_, hashes)) => *hashes,
+				None => return Ok(vec![]),
+			};
+			let mut similar = media
+				.iter()
+				.enumerate()
+				.filter(|(_, (data, other))| {
+					data.id != object_id && hashes.distance(other) <= max_distance
+				})
+				.map(|(index, _)| index)
+				.collect::<Vec<_>>();
+			if similar.is_empty() {
+				vec![]
+			} else {
+				let index = media
+					.iter()
+					.position(|(data, _)| data.id == object_id)
+					.unwrap_or_default();
+				similar.insert(0, index);
+				vec![similar]
+			}
+		}
+		None => cluster(
+			&media.iter().map(|(_, hashes)| *hashes).collect::<Vec<_>>(),
+			max_distance,
+		),
+	};
+	if clusters.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let cas_ids = library
+		.db
+		.object()
+		.find_many(vec![object::id::in_vec(
+			clusters
+				.iter()
+				.flatten()
+				.map(|index| media[*index].0.id)
+				.collect(),
+		)])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|object| (object.id, object.cas_id))
+		.collect::<HashMap<_, _>>();
+
+	let object_given = args.object_id.is_some();
+	Ok(clusters
+		.into_iter()
+		.map(|mut cluster| {
+			let pixels = |index: &usize| {
+				let data = &media[*index].0;
+				data.pixel_width.unwrap_or(0) as i64 * data.pixel_height.unwrap_or(0) as i64
+			};
+			// the object asked about stays first, its cluster is the images like it
+			if object_given {
+				cluster[1..].sort_by_key(|index| std::cmp::Reverse(pixels(index)));
+			} else {
+				cluster.sort_by_key(|index| std::cmp::Reverse(pixels(index)));
+			}
+
+			let first = media[cluster[0]].1;
+			cluster
+				.into_iter()
+				.filter_map(|index| {
+					let (data, hashes) = &media[index];
+					Some(SimilarImage {
+						object_id: data.id,
+						cas_id: cas_ids.get(&data.id)?.clone(),
+						pixel_width: data.pixel_width,
+						pixel_height: data.pixel_height,
+						distance: first.distance(hashes),
+					})
+				})
+				.collect()
+		})
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use image::{Rgb, RgbImage};
+
+	fn photo(width: u32, height: u32) -> DynamicImage {
+		// waves that are fainter the finer they are, like the detail of a photo
+		DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+			let (fx, fy) = (x as f32 / width as f32, y as f32 / height as f32);
+			let mut value = 128.0;
+			for u in 1..6 {
+				for v in 1..6 {
+					let phase = (u * 7 + v * 13) as f32;
+					value += 15.0 / (u + v) as f32
+						* (std::f32::consts::PI * (u as f32 * fx * 1.3 + v as f32 * fy * 0.9)
+							+ phase)
+							.cos();
+				}
+			}
+			let value = value.clamp(0.0, 255.0) as u8;
+			Rgb([value, value / 2 + 60, 255 - value])
+		}))
+	}
+
+	#[test]
+	fn similar_images_hash_alike() {
+		let original = ImageHashes::new(&photo(640, 480));
+		let resized = ImageHashes::new(&photo(320, 240));
+		let brighter = ImageHashes::new(&photo(640, 480).brighten(20));
+		let other = ImageHashes::new(&photo(640, 480).rotate180());
+
+		assert!(original.distance(&resized) <= DEFAULT_MAX_DISTANCE);
+		assert!(original.distance(&brighter) <= DEFAULT_MAX_DISTANCE);
+		assert!(original.distance(&other) > MAX_DISTANCE);
+
+		let hex = to_hex(original.perceptual);
+		assert_eq!(
+			ImageHashes::parse(&hex, &to_hex(original.difference)),
+			Some(original)
+		);
+	}
+
+	#[test]
+	fn clusters_near_hashes() {
+		let hashes = |perceptual: u64| ImageHashes {
+			perceptual,
+			difference: perceptual,
+		};
+		let clusters = cluster(
+			&[
+				hashes(0),
+				hashes(u64::MAX),
+				hashes(0b111),
+				hashes(u64::MAX >> 2),
+				hashes(0xF0F0_F0F0_F0F0_F0F0),
+				// only near the first through the third
+				hashes(0b111_1111),
+			],
+			4,
+		);
+
+		assert_eq!(clusters, vec![vec![0, 2, 5], vec![1, 3]]);
+	}
+}
@@ -0,0 +1,171 @@
+use std::path::Path;
+
+use int_enum::IntEnum;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	library::LibraryContext,
+	prisma::{object, object_source},
+};
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum SourceKind {
+	/// the url the file was downloaded from
+	Download = 0,
+	/// the page the download was started from
+	Referrer = 1,
+}
+
+/// read_sources reads where a file was downloaded from, out of the metadata browsers leave on their
+/// downloads: the `kMDItemWhereFroms` attribute on macOS, set alongside the quarantine attribute,
+/// the `xdg` attributes on Linux and the `Zone.Identifier` stream on Windows. Files without any
+/// return nothing.
+pub fn read_sources(path: &Path) -> Vec<(SourceKind, String)> {
+	let sources = platform::read_sources(path);
+
+	let mut unique = Vec::with_capacity(sources.len());
+	for (kind, url) in sources {
+		let url = url.trim();
+		// browsers leave the referrer empty, or the same as the download, when they don't have one
+		if !url.is_empty() && !unique.iter().any(|(_, seen)| seen == url) {
+			unique.push((kind, url.to_string()));
+		}
+	}
+	unique
+}
+
+/// record_sources stores where the object was downloaded from, keeping the sources it already has
+pub async fn record_sources(
+	library: &LibraryContext,
+	object_id: i32,
+	sources: Vec<(SourceKind, String)>,
+) -> Result<(), prisma_client_rust::QueryError> {
+	for (kind, url) in sources {
+		library
+			.db
+			.object_source()
+			.upsert(
+				object_source::object_id_url(object_id, url.clone()),
+				(object::id::equals(object_id), url, kind.int_value(), vec![]),
+				vec![],
+			)
+			.exec()
+			.await?;
+	}
+
+	Ok(())
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+	use super::SourceKind;
+	use std::path::Path;
+
+	const WHERE_FROMS_ATTR: &str = "com.apple.metadata:kMDItemWhereFroms";
+
+	/// The attribute is a binary plist with the download's url followed by the page it came from
+	pub fn read_sources(path: &Path) -> Vec<(SourceKind, String)> {
+		xattr::get(path, WHERE_FROMS_ATTR)
+			.ok()
+			.flatten()
+			.and_then(|value| plist::from_bytes::<Vec<String>>(&value).ok())
+			.unwrap_or_default()
+			.into_iter()
+			.zip([SourceKind::Download, SourceKind::Referrer])
+			.map(|(url, kind)| (kind, url))
+			.collect()
+	}
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+	use super::SourceKind;
+	use std::path::Path;
+
+	const ORIGIN_URL_ATTR: &str = "user.xdg.origin.url";
+	const REFERRER_URL_ATTR: &str = "user.xdg.referrer.url";
+
+	pub fn read_sources(path: &Path) -> Vec<(SourceKind, String)> {
+		[
+			(SourceKind::Download, ORIGIN_URL_ATTR),
+			(SourceKind::Referrer, REFERRER_URL_ATTR),
+		]
+		.into_iter()
+		.filter_map(|(kind, attr)| {
+			let value = xattr::get(path, attr).ok().flatten()?;
+			Some((kind, String::from_utf8_lossy(&value).to_string()))
+		})
+		.collect()
+	}
+}
+
+#[cfg(windows)]
+mod platform {
+	use super::{parse_zone_identifier, SourceKind};
+	use std::{ffi::OsString, fs, path::Path};
+
+	/// Alternate data streams are opened like files, by appending their name to the file's path
+	pub fn read_sources(path: &Path) -> Vec<(SourceKind, String)> {
+		let mut stream = OsString::from(path);
+		stream.push(":Zone.Identifier");
+
+		fs::read_to_string(stream)
+			.map(|zone_identifier| parse_zone_identifier(&zone_identifier))
+			.unwrap_or_default()
+	}
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+	use super::SourceKind;
+	use std::path::Path;
+
+	pub fn read_sources(_path: &Path) -> Vec<(SourceKind, String)> {
+		vec![]
+	}
+}
+
+/// parse_zone_identifier reads the urls out of a `Zone.Identifier` stream, which looks like an ini
+/// file with a `[ZoneTransfer]` section
+#[cfg(any(windows, test))]
+fn parse_zone_identifier(zone_identifier: &str) -> Vec<(SourceKind, String)> {
+	zone_identifier
+		.lines()
+		.filter_map(|line| {
+			let (key, value) = line.split_once('=')?;
+			match key.trim() {
+				"HostUrl" => Some((SourceKind::Download, value.trim().to_string())),
+				"ReferrerUrl" => Some((SourceKind::Referrer, value.trim().to_string())),
+				_ => None,
+			}
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_zone_identifier() {
+		let sources = parse_zone_identifier(
+			"[ZoneTransfer]\r\nZoneId=3\r\nReferrerUrl=https://example.com/downloads\r\nHostUrl=https://example.com/files/report.pdf\r\n",
+		);
+
+		assert_eq!(
+			sources,
+			vec![
+				(
+					SourceKind::Referrer,
+					"https://example.com/downloads".to_string()
+				),
+				(
+					SourceKind::Download,
+					"https://example.com/files/report.pdf".to_string()
+				),
+			]
+		);
+	}
+}
@@ -0,0 +1,365 @@
+//! The timeline of a library, files grouped by the day, month or year they're dated by: when their
+//! photo was taken if its EXIF says so, or when they were last modified otherwise. Capture dates are
+//! kept as the camera's clock read, so only modification dates are moved to the client's time zone.
+//! Buckets are counted by the database, and the files of one are read a page at a time.
+
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use itertools::Itertools;
+use prisma_client_rust::{raw::Raw, PrismaValue};
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{library::LibraryContext, prisma::file_path, util::db::raw_int};
+
+const DEFAULT_PAGE_SIZE: u8 = 100;
+/// How dates come back from SQLite's `datetime`
+const SQL_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Error, Debug)]
+pub enum TimelineError {
+	#[error("invalid bucket for this granularity: {0}")]
+	InvalidBucket(String),
+	#[error("invalid cursor: {0}")]
+	InvalidCursor(String),
+	#[error("database error: {0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+}
+
+impl From<TimelineError> for rspc::Error {
+	fn from(err: TimelineError) -> Self {
+		match err {
+			TimelineError::InvalidBucket(_) | TimelineError::InvalidCursor(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			TimelineError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(Deserialize, Type, Debug, Clone, Copy)]
+pub enum TimelineGranularity {
+	Day,
+	Month,
+	Year,
+}
+
+impl TimelineGranularity {
+	fn strftime(&self) -> &'static str {
+		match self {
+			Self::Day => "%Y-%m-%d",
+			Self::Month => "%Y-%m",
+			Self::Year => "%Y",
+		}
+	}
+
+	/// validate_bucket checks a bucket is one of this granularity, e.g. `2022-12` for months
+	fn validate_bucket(&self, bucket: &str) -> Result<(), TimelineError> {
+		// a day in the bucket, which has to be a date
+		let day = match self {
+			Self::Day => bucket.to_string(),
+			Self::Month => format!("{bucket}-01"),
+			Self::Year => format!("{bucket}-01-01"),
+		};
+		let expected_len = match self {
+			Self::Day => 10,
+			Self::Month => 7,
+			Self::Year => 4,
+		};
+
+		match chrono::NaiveDate::parse_from_str(&day, "%Y-%m-%d") {
+			Ok(_) if bucket.len() == expected_len => Ok(()),
+			_ => Err(TimelineError::InvalidBucket(bucket.to_string())),
+		}
+	}
+}
+
+/// Where a page of a bucket ends, to read the next one from: the date, location and id of its last
+/// file, as `<date>|<location_id>|<file_path_id>`
+#[derive(Debug, PartialEq)]
+struct TimelineCursor {
+	date: NaiveDateTime,
+	location_id: i32,
+	file_path_id: i32,
+}
+
+impl TimelineCursor {
+	fn parse(cursor: &str) -> Result<Self, TimelineError> {
+		let invalid = || TimelineError::InvalidCursor(cursor.to_string());
+		let (date, location_id, file_path_id) =
+			cursor.split('|').collect_tuple().ok_or_else(invalid)?;
+
+		Ok(Self {
+			date: NaiveDateTime::parse_from_str(date, SQL_DATE_FORMAT).map_err(|_| invalid())?,
+			location_id: location_id.parse().map_err(|_| invalid())?,
+			file_path_id: file_path_id.parse().map_err(|_| invalid())?,
+		})
+	}
+}
+
+impl std::fmt::Display for TimelineCursor {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{}|{}|{}",
+			self.date.format(SQL_DATE_FORMAT),
+			self.location_id,
+			self.file_path_id
+		)
+	}
+}
+
+/// sql_date reads a date column as SQLite's `datetime`, whether it was stored as milliseconds since
+/// the epoch or as text, moved by `modifier`
+fn sql_date(column: &str, modifier: &str) -> String {
+	format!(
+		"CASE WHEN typeof({column}) = 'integer' THEN datetime({column} / 1000, 'unixepoch', '{modifier}') \
+		ELSE datetime({column}, '{modifier}') END"
+	)
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct TimelineArgs {
+	pub granularity: TimelineGranularity,
+	pub location_id: Option<i32>,
+	/// the bucket to read files from, the latest when not given
+	pub bucket: Option<String>,
+	/// where the previous page of the bucket ended
+	pub cursor: Option<String>,
+	pub take: Option<u8>,
+	/// the client's offset from UTC, which modification dates are moved by
+	pub utc_offset_minutes: Option<i32>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct TimelineBucket {
+	/// e.g. `2022-12-24`, `2022-12` or `2022`
+	pub key: String,
+	pub count: i32,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct TimelineItem {
+	/// the date the file is placed at, as `YYYY-MM-DD HH:MM:SS`
+	pub date: String,
+	/// whether the date is when the photo was taken, rather than when the file was modified
+	pub captured: bool,
+	pub file_path: file_path::Data,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct Timeline {
+	/// every bucket, the latest first
+	pub buckets: Vec<TimelineBucket>,
+	/// the bucket the items are of
+	pub bucket: Option<String>,
+	pub items: Vec<TimelineItem>,
+	/// where to read the rest of the bucket from, if there's more of it
+	pub next_cursor: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BucketRow {
+	bucket: String,
+	#[serde(deserialize_with = "raw_int")]
+	count: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ItemRow {
+	#[serde(deserialize_with = "raw_int")]
+	location_id: i64,
+	#[serde(deserialize_with = "raw_int")]
+	id: i64,
+	date: String,
+	#[serde(deserialize_with = "raw_int")]
+	captured: i64,
+}
+
+/// timeline returns the buckets of the library's files, with a page of the files of one of them
+pub async fn timeline(
+	library: &LibraryContext,
+	args: TimelineArgs,
+) -> Result<Timeline, TimelineError> {
+	let format = args.granularity.strftime();
+	let modifier = format!("{:+} minutes", args.utc_offset_minutes.unwrap_or(0));
+	let take = args.take.unwrap_or(DEFAULT_PAGE_SIZE).max(1) as usize;
+
+	let dated = format!(
+		"WITH dated AS (SELECT fp.location_id AS location_id, fp.id AS id, \
+		COALESCE({}, {}) AS date, md.date_taken IS NOT NULL AS captured \
+		FROM file_path fp LEFT JOIN media_data md ON md.id = fp.object_id \
+		WHERE NOT fp.is_dir{})",
+		sql_date("md.date_taken", "+0 minutes"),
+		sql_date("fp.date_modified", &modifier),
+		args.location_id
+			.map(|location_id| format!(" AND fp.location_id = {location_id}"))
+			.unwrap_or_default()
+	);
+
+	let buckets: Vec<BucketRow> = library
+		.db
+		._query_raw(Raw::new(
+			&format!(
+				"{dated} SELECT strftime('{format}', date) AS bucket, COUNT(*) AS count \
+				FROM dated GROUP BY bucket ORDER BY bucket DESC"
+			),
+			vec![],
+		))
+		.exec()
+		.await?;
+
+	let bucket = match args.bucket {
+		Some(bucket) => {
+			args.granularity.validate_bucket(&bucket)?;
+			bucket
+		}
+		None => match buckets.first() {
+			Some(latest) => latest.bucket.clone(),
+			None => {
+				return Ok(Timeline {
+					buckets: vec![],
+					bucket: None,
+					items: vec![],
+					next_cursor: None,
+				})
+			}
+		},
+	};
+
+	let mut params = vec![PrismaValue::String(bucket.clone())];
+	let after = match args
+		.cursor
+		.as_deref()
+		.map(TimelineCursor::parse)
+		.transpose()?
+	{
+		Some(cursor) => {
+			params.push(PrismaValue::String(
+				cursor.date.format(SQL_DATE_FORMAT).to_string(),
+			));
+			params.push(PrismaValue::String(
+				cursor.date.format(SQL_DATE_FORMAT).to_string(),
+			));
+			format!(
+				" AND (date < {{}} OR (date = {{}} AND (location_id, id) < ({}, {})))",
+				cursor.location_id, cursor.file_path_id
+			)
+		}
+		None => String::new(),
+	};
+
+	let mut rows: Vec<ItemRow> = library
+		.db
+		._query_raw(Raw::new(
+			&format!(
+				"{dated} SELECT location_id, id, date, captured FROM dated \
+				WHERE strftime('{format}', date) = {{}}{after} \
+				ORDER BY date DESC, location_id DESC, id DESC LIMIT {}",
+				take + 1
+			),
+			params,
+		))
+		.exec()
+		.await?;
+
+	let next_cursor = if rows.len() > take {
+		rows.truncate(take);
+		rows.last().and_then(|last| {
+			Some(
+				TimelineCursor {
+					date: NaiveDateTime::parse_from_str(&last.date, SQL_DATE_FORMAT).ok()?,
+					location_id: last.location_id as i32,
+					file_path_id: last.id as i32,
+				}
+				.to_string(),
+			)
+		})
+	} else {
+		None
+	};
+
+	// file paths are only unique within their location
+	let mut ids_by_location = HashMap::<i32, Vec<i32>>::new();
+	for row in &rows {
+		ids_by_location
+			.entry(row.location_id as i32)
+			.or_default()
+			.push(row.id as i32);
+	}
+	let mut file_paths = HashMap::new();
+	for (location_id, ids) in ids_by_location {
+		for file_path in library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(location_id),
+				file_path::id::in_vec(ids),
+			])
+			.with(file_path::object::fetch())
+			.exec()
+			.await?
+		{
+			file_paths.insert((file_path.location_id, file_path.id), file_path);
+		}
+	}
+
+	Ok(Timeline {
+		buckets: buckets
+			.into_iter()
+			.map(|bucket| TimelineBucket {
+				key: bucket.bucket,
+				count: bucket.count as i32,
+			})
+			.collect(),
+		bucket: Some(bucket),
+		items: rows
+			.into_iter()
+			.filter_map(|row| {
+				Some(TimelineItem {
+					file_path: file_paths.remove(&(row.location_id as i32, row.id as i32))?,
+					date: row.date,
+					captured: row.captured != 0,
+				})
+			})
+			.collect(),
+		next_cursor,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cursors_round_trip() {
+		let cursor = TimelineCursor::parse("2022-12-24 18:30:00|3|1042").unwrap();
+		assert_eq!(cursor.location_id, 3);
+		assert_eq!(cursor.file_path_id, 1042);
+		assert_eq!(cursor.to_string(), "2022-12-24 18:30:00|3|1042");
+
+		assert!(TimelineCursor::parse("2022-12-24|3|1042").is_err());
+		assert!(TimelineCursor::parse("2022-12-24 18:30:00|3").is_err());
+	}
+
+	#[test]
+	fn buckets_match_their_granularity() {
+		assert!(TimelineGranularity::Day
+			.validate_bucket("2022-12-24")
+			.is_ok());
+		assert!(TimelineGranularity::Month
+			.validate_bucket("2022-12")
+			.is_ok());
+		assert!(TimelineGranularity::Year.validate_bucket("2022").is_ok());
+
+		assert!(TimelineGranularity::Month
+			.validate_bucket("2022-13")
+			.is_err());
+		assert!(TimelineGranularity::Day.validate_bucket("2022-12").is_err());
+		assert!(TimelineGranularity::Year.validate_bucket("22").is_err());
+	}
+}
@@ -0,0 +1,575 @@
+//! Checksum files in the formats of `sha256sum` and `md5sum`, so a location can be checked with tools
+//! outside of Spacedrive, and the other way around. The [`ChecksumExportJob`] hashes the files of a
+//! location into one, and the [`ChecksumVerifyJob`] checks a location against one, reporting the files
+//! that don't match it, are missing or can't be read. Both the GNU format, `<hash>  <path>`, and the
+//! BSD one, `SHA256 (<path>) = <hash>`, are read, and the GNU one is written. Paths in them are relative
+//! to the location.
+
+use std::{
+	fs::{self, File},
+	io,
+	path::{Path, PathBuf},
+};
+
+use md5::Md5;
+use prisma_client_rust::QueryError;
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::info;
+
+use crate::{
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	library::LibraryContext,
+	location::{fetch_location, LocationError},
+	prisma::file_path,
+	util::message::Message,
+};
+
+pub const CHECKSUM_EXPORT_JOB_NAME: &str = "checksum_exporter";
+pub const CHECKSUM_VERIFY_JOB_NAME: &str = "checksum_verifier";
+
+#[derive(Error, Debug)]
+pub enum ChecksumFileError {
+	#[error("no checksums found in {0}")]
+	Empty(PathBuf),
+	#[error("Location error: {0}")]
+	Location(#[from] LocationError),
+	#[error("Database error: {0}")]
+	Database(#[from] QueryError),
+	#[error("I/O error: {0}")]
+	IO(#[from] io::Error),
+}
+
+impl From<ChecksumFileError> for rspc::Error {
+	fn from(err: ChecksumFileError) -> Self {
+		match err {
+			ChecksumFileError::Empty(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			ChecksumFileError::Location(err) => err.into(),
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+	Sha256,
+	Md5,
+}
+
+impl ChecksumAlgorithm {
+	/// extension returns what checksum files of the algorithm usually end with, e.g. `photos.sha256`
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Self::Sha256 => "sha256",
+			Self::Md5 => "md5",
+		}
+	}
+
+	/// from_checksum tells which algorithm a hex checksum is of by its length
+	fn from_checksum(checksum: &str) -> Option<Self> {
+		if !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+			return None;
+		}
+
+		match checksum.len() {
+			64 => Some(Self::Sha256),
+			32 => Some(Self::Md5),
+			_ => None,
+		}
+	}
+
+	pub fn hash_file(&self, path: &Path) -> io::Result<String> {
+		match self {
+			Self::Sha256 => digest_file::<Sha256>(path),
+			Self::Md5 => digest_file::<Md5>(path),
+		}
+	}
+}
+
+fn digest_file<D: Digest + io::Write>(path: &Path) -> io::Result<String> {
+	let mut hasher = D::new();
+	io::copy(&mut File::open(path)?, &mut hasher)?;
+
+	Ok(hex::encode(hasher.finalize()))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumEntry {
+	/// relative to the location, separated by '/'
+	pub path: String,
+	pub checksum: String,
+	pub algorithm: ChecksumAlgorithm,
+}
+
+/// format_line writes an entry the way `sha256sum` does, which escapes names that have a backslash
+/// or a newline in them and marks their line with a backslash
+fn format_line(path: &str, checksum: &str) -> String {
+	match path.contains(['\\', '\n']) {
+		true => format!(
+			"\\{checksum}  {}",
+			path.replace('\\', "\\\\").replace('\n', "\\n")
+		),
+		false => format!("{checksum}  {path}"),
+	}
+}
+
+fn unescape(path: &str) -> String {
+	let mut unescaped = String::with_capacity(path.len());
+	let mut chars = path.chars();
+	while let Some(c) = chars.next() {
+		match (c, chars.clone().next()) {
+			('\\', Some('n')) => {
+				unescaped.push('\n');
+				chars.next();
+			}
+			('\\', Some('\\')) => {
+				unescaped.push('\\');
+				chars.next();
+			}
+			_ => unescaped.push(c),
+		}
+	}
+	unescaped
+}
+
+/// parse_line reads an entry in the GNU or BSD format. Entries whose path is absolute or goes above
+/// the location aren't read.
+fn parse_line(line: &str) -> Option<ChecksumEntry> {
+	let (escaped, line) = match line.strip_prefix('\\') {
+		Some(line) => (true, line),
+		None => (false, line),
+	};
+
+	let (checksum, path, bsd_name) = match line.split_once(" (") {
+		// `SHA256 (<path>) = <hash>`
+		Some((name, rest)) if !name.contains(' ') && rest.contains(") = ") => {
+			let (path, checksum) = rest.rsplit_once(") = ")?;
+			(checksum, path, Some(name))
+		}
+		// `<hash>  <path>`, or `<hash> *<path>` for files hashed in binary mode
+		_ => {
+			let (checksum, rest) = line.split_once(' ')?;
+			let path = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+			(checksum, path, None)
+		}
+	};
+
+	let checksum = checksum.to_ascii_lowercase();
+	let algorithm = ChecksumAlgorithm::from_checksum(&checksum)?;
+	if let Some(name) = bsd_name {
+		if !name.eq_ignore_ascii_case(algorithm.extension()) {
+			return None;
+		}
+	}
+
+	let path = match escaped {
+		true => unescape(path),
+		false => path.to_string(),
+	};
+	let path = path.strip_prefix("./").unwrap_or(&path);
+	if path.is_empty() || path.starts_with('/') || path.split('/').any(|part| part == "..") {
+		return None;
+	}
+
+	Some(ChecksumEntry {
+		path: path.to_string(),
+		checksum,
+		algorithm,
+	})
+}
+
+/// read_checksum_file returns the entries of a checksum file, and how many of its lines weren't
+/// entries. Blank lines and comments aren't counted.
+pub fn read_checksum_file(path: &Path) -> Result<(Vec<ChecksumEntry>, usize), ChecksumFileError> {
+	let contents = fs::read(path)?;
+
+	let mut entries = vec![];
+	let mut invalid_lines = 0;
+	for line in String::from_utf8_lossy(&contents).lines() {
+		if line.trim().is_empty() || line.starts_with('#') {
+			continue;
+		}
+		match parse_line(line) {
+			Some(entry) => entries.push(entry),
+			None => invalid_lines += 1,
+		}
+	}
+
+	if entries.is_empty() {
+		return Err(ChecksumFileError::Empty(path.to_path_buf()));
+	}
+
+	Ok((entries, invalid_lines))
+}
+
+/// location_path returns where a location is, if it's online on this node
+async fn location_path(
+	library: &LibraryContext,
+	location_id: i32,
+) -> Result<PathBuf, ChecksumFileError> {
+	let location = fetch_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	if !location.is_online {
+		return Err(LocationError::Offline(location.id).into());
+	}
+
+	Ok(location
+		.local_path
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingLocalPath(location.id))?)
+}
+
+/// hash reads a file with the algorithm, within the limits the governor puts on background reads
+async fn hash(
+	ctx: &WorkerContext,
+	path: &Path,
+	algorithm: ChecksumAlgorithm,
+) -> io::Result<String> {
+	let size = tokio::fs::metadata(path).await?.len();
+	let hashed = {
+		let _hasher = ctx.governor().hasher().await;
+		block_in_place(|| algorithm.hash_file(path))
+	};
+	ctx.governor().throttle_read(size).await;
+
+	hashed
+}
+
+/// The checksum exporter hashes every file of a location into a checksum file
+pub struct ChecksumExportJob {}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct ChecksumExportJobInit {
+	pub location_id: i32,
+	pub algorithm: ChecksumAlgorithm,
+	/// where the checksum file is written on this node
+	pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChecksumExportJobState {
+	location_path: PathBuf,
+	lines: Vec<String>,
+	skipped: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChecksumExportJobStep {
+	file_path_id: i32,
+	materialized_path: String,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ChecksumExportJob {
+	type Init = ChecksumExportJobInit;
+	type Data = ChecksumExportJobState;
+	type Step = ChecksumExportJobStep;
+
+	fn name(&self) -> &'static str {
+		CHECKSUM_EXPORT_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+		let location_path = location_path(&library, state.init.location_id).await?;
+
+		let (mut file_paths, not_utf8): (Vec<_>, Vec<_>) = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(state.init.location_id),
+				file_path::is_dir::equals(false),
+			])
+			.exec()
+			.await?
+			.into_iter()
+			// names that aren't valid UTF-8 can't be written to the file as they are
+			.partition(|file_path| file_path.raw_path.is_none());
+		file_paths.sort_by(|a, b| a.materialized_path.cmp(&b.materialized_path));
+
+		state.steps = file_paths
+			.into_iter()
+			.map(|file_path| ChecksumExportJobStep {
+				file_path_id: file_path.id,
+				materialized_path: file_path.materialized_path,
+			})
+			.collect();
+		state.data = Some(ChecksumExportJobState {
+			location_path,
+			lines: Vec::with_capacity(state.steps.len()),
+			skipped: not_utf8.len(),
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+			path: step.materialized_path.clone(),
+		})]);
+
+		let path = data.location_path.join(&step.materialized_path);
+		match hash(&ctx, &path, state.init.algorithm).await {
+			Ok(checksum) => data
+				.lines
+				.push(format_line(&step.materialized_path, &checksum)),
+			Err(e) => {
+				ctx.record_file_error(FileError {
+					location_id: Some(state.init.location_id),
+					file_path_id: Some(step.file_path_id),
+					path: step.materialized_path.clone(),
+					message: Message::from(&e),
+				})
+				.await;
+				data.skipped += 1;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		_ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		let mut contents = data.lines.join("\n");
+		contents.push('\n');
+		block_in_place(|| fs::write(&state.init.path, contents))
+			.map_err(ChecksumFileError::from)?;
+
+		info!(
+			"Exported the checksums of {} to {}: {} files hashed, {} skipped",
+			data.location_path.display(),
+			state.init.path.display(),
+			data.lines.len(),
+			data.skipped
+		);
+
+		Ok(Some(json!({
+			"init": state.init,
+			"hashed": data.lines.len(),
+			"skipped": data.skipped,
+		})))
+	}
+}
+
+/// A file whose content isn't what the checksum file says
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChecksumMismatch {
+	pub path: String,
+	pub expected: String,
+	pub actual: String,
+}
+
+/// What checking a location against a checksum file found
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ChecksumReport {
+	pub matched: usize,
+	pub mismatched: Vec<ChecksumMismatch>,
+	pub missing: Vec<String>,
+	pub unreadable: Vec<String>,
+	/// lines of the checksum file that weren't entries
+	pub invalid_lines: usize,
+}
+
+/// The checksum verifier checks the files of a location against a checksum file
+pub struct ChecksumVerifyJob {}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct ChecksumVerifyJobInit {
+	pub location_id: i32,
+	/// the checksum file on this node
+	pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChecksumVerifyJobState {
+	location_path: PathBuf,
+	report: ChecksumReport,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ChecksumVerifyJob {
+	type Init = ChecksumVerifyJobInit;
+	type Data = ChecksumVerifyJobState;
+	type Step = ChecksumEntry;
+
+	fn name(&self) -> &'static str {
+		CHECKSUM_VERIFY_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let location_path = location_path(&ctx.library_ctx(), state.init.location_id).await?;
+		let (entries, invalid_lines) = block_in_place(|| read_checksum_file(&state.init.path))?;
+
+		state.steps = entries.into();
+		state.data = Some(ChecksumVerifyJobState {
+			location_path,
+			report: ChecksumReport {
+				invalid_lines,
+				..Default::default()
+			},
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let entry = &state.steps[0];
+		let data = state
+			.data
+			.as_mut()
+			.expect("critical error: missing data on job state");
+
+		ctx.progress(vec![JobReportUpdate::Message(Message::Processing {
+			path: entry.path.clone(),
+		})]);
+
+		let file_error = |message| FileError {
+			location_id: Some(state.init.location_id),
+			file_path_id: None,
+			path: entry.path.clone(),
+			message,
+		};
+
+		let path = data.location_path.join(&entry.path);
+		match hash(&ctx, &path, entry.algorithm).await {
+			Ok(checksum) if checksum == entry.checksum => data.report.matched += 1,
+			Ok(checksum) => {
+				ctx.record_file_error(file_error(Message::ChecksumMismatch))
+					.await;
+				data.report.mismatched.push(ChecksumMismatch {
+					path: entry.path.clone(),
+					expected: entry.checksum.clone(),
+					actual: checksum,
+				});
+			}
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				data.report.missing.push(entry.path.clone())
+			}
+			Err(e) => {
+				ctx.record_file_error(file_error(Message::from(&e))).await;
+				data.report.unreadable.push(entry.path.clone());
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		_ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+		let report = &data.report;
+
+		info!(
+			"Verified {} against {}: {} matched, {} mismatched, {} missing, {} unreadable",
+			data.location_path.display(),
+			state.init.path.display(),
+			report.matched,
+			report.mismatched.len(),
+			report.missing.len(),
+			report.unreadable.len()
+		);
+
+		Ok(Some(json!({
+			"init": state.init,
+			"report": report,
+		})))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SHA256_EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+	const MD5_EMPTY: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+	#[test]
+	fn reads_gnu_and_bsd_lines() {
+		let entry = parse_line(&format!("{SHA256_EMPTY}  photos/beach.jpg")).unwrap();
+		assert_eq!(entry.path, "photos/beach.jpg");
+		assert_eq!(entry.algorithm, ChecksumAlgorithm::Sha256);
+
+		let entry = parse_line(&format!("{} *./notes.txt", MD5_EMPTY.to_uppercase())).unwrap();
+		assert_eq!(entry.path, "notes.txt");
+		assert_eq!(entry.checksum, MD5_EMPTY);
+		assert_eq!(entry.algorithm, ChecksumAlgorithm::Md5);
+
+		let entry = parse_line(&format!("MD5 (my (old) notes.txt) = {MD5_EMPTY}")).unwrap();
+		assert_eq!(entry.path, "my (old) notes.txt");
+
+		// the BSD name has to agree with the checksum
+		assert!(parse_line(&format!("SHA256 (notes.txt) = {MD5_EMPTY}")).is_none());
+		assert!(parse_line(&format!("{MD5_EMPTY}  ../outside.txt")).is_none());
+		assert!(parse_line(&format!("{MD5_EMPTY}  /etc/passwd")).is_none());
+		assert!(parse_line("not a checksum  notes.txt").is_none());
+	}
+
+	#[test]
+	fn escapes_names_like_coreutils() {
+		let line = format_line("odd\\name\nwith newline", SHA256_EMPTY);
+		assert_eq!(
+			line,
+			format!("\\{SHA256_EMPTY}  odd\\\\name\\nwith newline")
+		);
+		assert_eq!(parse_line(&line).unwrap().path, "odd\\name\nwith newline");
+
+		let line = format_line("plain.txt", SHA256_EMPTY);
+		assert_eq!(parse_line(&line).unwrap().path, "plain.txt");
+	}
+}
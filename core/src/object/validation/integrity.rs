@@ -0,0 +1,339 @@
+//! Integrity sampling, a lighter take on checking every file against its checksum. Once a week a random
+//! sample of the files that have a checksum are hashed again with the
+//! [`IntegritySamplerJob`](super::sampler_job::IntegritySamplerJob), and how many of them failed is kept
+//! per volume. A volume failing more than a little, or in sample after sample, is alerted about, as
+//! that's how a disk starting to go bad shows up long before it fails outright.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	time::{Duration, Instant},
+};
+
+use chrono::{Duration as ChronoDuration, Utc};
+use prisma_client_rust::{Direction, QueryError};
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use tokio::time::interval;
+use tracing::{error, info, warn};
+
+use crate::{
+	api::LibraryEvent,
+	invalidate_query,
+	job::Job,
+	library::LibraryContext,
+	prisma::{integrity_sample, location, object},
+};
+
+use super::sampler_job::{IntegritySamplerJob, IntegritySamplerJobInit, DEFAULT_SAMPLE_SIZE};
+
+/// How often a library is sampled
+const SAMPLING_INTERVAL_DAYS: i64 = 7;
+/// How often the sampler checks if a library is due
+const SAMPLING_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long samples are kept
+const SAMPLE_RETENTION_DAYS: i64 = 2 * 365;
+/// Share of a sample failing from which it's alerted about on its own
+const ALERT_ERROR_RATE: f64 = 0.01;
+
+/// How many files of a location were sampled and how many failed
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SampleTally {
+	pub sampled: i32,
+	pub corrupted: i32,
+	pub unreadable: i32,
+}
+
+impl SampleTally {
+	fn add(&mut self, other: &SampleTally) {
+		self.sampled += other.sampled;
+		self.corrupted += other.corrupted;
+		self.unreadable += other.unreadable;
+	}
+
+	fn failed(&self) -> i32 {
+		self.corrupted + self.unreadable
+	}
+}
+
+impl From<&integrity_sample::Data> for SampleTally {
+	fn from(sample: &integrity_sample::Data) -> Self {
+		Self {
+			sampled: sample.sampled,
+			corrupted: sample.corrupted,
+			unreadable: sample.unreadable,
+		}
+	}
+}
+
+/// error_rate returns the share of a sample that failed
+fn error_rate(tally: &SampleTally) -> f64 {
+	match tally.sampled {
+		0 => 0.0,
+		sampled => tally.failed() as f64 / sampled as f64,
+	}
+}
+
+/// is_failing_trend tells if the samples of a volume, the latest first, show it going bad: the latest
+/// failing more than a little, or failing again after the one before also did
+fn is_failing_trend(samples: &[SampleTally]) -> bool {
+	match samples {
+		[latest, ..] if error_rate(latest) >= ALERT_ERROR_RATE => true,
+		[latest, previous, ..] => latest.failed() > 0 && previous.failed() > 0,
+		_ => false,
+	}
+}
+
+/// What integrity sampling found on a volume over time
+#[derive(Serialize, Type, Debug)]
+pub struct VolumeIntegrity {
+	pub volume_serial: Option<String>,
+	/// set for volumes without a fingerprint, which are told apart by their location
+	pub location_id: Option<i32>,
+	/// oldest first
+	pub samples: Vec<integrity_sample::Data>,
+	/// the share of the latest sample that failed
+	pub error_rate: f64,
+	pub failing: bool,
+}
+
+/// The volume a location is on, as samples are kept by
+type VolumeKey = (Option<String>, Option<i32>);
+
+fn volume_key(location: &location::Data) -> VolumeKey {
+	match &location.volume_serial {
+		Some(serial) => (Some(serial.clone()), None),
+		None => (None, Some(location.id)),
+	}
+}
+
+/// record_samples keeps what a sampling run found on each location by volume, and alerts about the
+/// volumes that are going bad
+pub async fn record_samples(
+	library: &LibraryContext,
+	tallies: &HashMap<i32, SampleTally>,
+) -> Result<(), QueryError> {
+	let locations = library
+		.db
+		.location()
+		.find_many(vec![location::id::in_vec(
+			tallies.keys().copied().collect(),
+		)])
+		.exec()
+		.await?;
+
+	let mut by_volume = HashMap::<VolumeKey, SampleTally>::new();
+	for location in &locations {
+		by_volume
+			.entry(volume_key(location))
+			.or_default()
+			.add(&tallies[&location.id]);
+	}
+
+	for ((volume_serial, location_id), tally) in &by_volume {
+		library
+			.db
+			.integrity_sample()
+			.create_unchecked(
+				tally.sampled,
+				tally.corrupted,
+				tally.unreadable,
+				vec![
+					integrity_sample::volume_serial::set(volume_serial.clone()),
+					integrity_sample::location_id::set(*location_id),
+				],
+			)
+			.exec()
+			.await?;
+	}
+
+	library
+		.db
+		.integrity_sample()
+		.delete_many(vec![integrity_sample::date_created::lt(
+			(Utc::now() - ChronoDuration::days(SAMPLE_RETENTION_DAYS)).into(),
+		)])
+		.exec()
+		.await?;
+
+	for volume in integrity_trends(library).await? {
+		let key = (volume.volume_serial.clone(), volume.location_id);
+		let tally = match by_volume.get(&key) {
+			Some(tally) if volume.failing => tally,
+			_ => continue,
+		};
+
+		warn!(
+			"Volume {} is failing integrity checks: {} corrupted and {} unreadable of {} sampled",
+			volume
+				.volume_serial
+				.as_deref()
+				.unwrap_or("without a fingerprint"),
+			tally.corrupted,
+			tally.unreadable,
+			tally.sampled
+		);
+		library.emit_event(LibraryEvent::IntegrityAlert {
+			volume_serial: volume.volume_serial,
+			location_id: volume.location_id,
+			sampled: tally.sampled,
+			corrupted: tally.corrupted,
+			unreadable: tally.unreadable,
+		});
+	}
+
+	invalidate_query!(library, "library.getIntegrity");
+
+	Ok(())
+}
+
+/// integrity_trends returns what sampling found on each volume over time
+pub async fn integrity_trends(
+	library: &LibraryContext,
+) -> Result<Vec<VolumeIntegrity>, QueryError> {
+	let samples = library
+		.db
+		.integrity_sample()
+		.find_many(vec![])
+		.order_by(integrity_sample::date_created::order(Direction::Asc))
+		.exec()
+		.await?;
+
+	let mut by_volume = BTreeMap::<VolumeKey, Vec<integrity_sample::Data>>::new();
+	for sample in samples {
+		by_volume
+			.entry((sample.volume_serial.clone(), sample.location_id))
+			.or_default()
+			.push(sample);
+	}
+
+	Ok(by_volume
+		.into_iter()
+		.map(|((volume_serial, location_id), samples)| {
+			let latest_first = samples
+				.iter()
+				.rev()
+				.map(SampleTally::from)
+				.collect::<Vec<_>>();
+
+			VolumeIntegrity {
+				volume_serial,
+				location_id,
+				error_rate: latest_first.first().map_or(0.0, error_rate),
+				failing: is_failing_trend(&latest_first),
+				samples,
+			}
+		})
+		.collect())
+}
+
+/// sample_integrity starts sampling the files of a library, returning if there was anything to sample
+pub async fn sample_integrity(library: &LibraryContext) -> Result<bool, QueryError> {
+	let checksummed = library
+		.db
+		.object()
+		.count(vec![object::integrity_checksum::not(None)])
+		.exec()
+		.await?;
+	if checksummed == 0 {
+		return Ok(false);
+	}
+
+	library
+		.spawn_job(Job::new(
+			IntegritySamplerJobInit {
+				sample_size: DEFAULT_SAMPLE_SIZE,
+			},
+			Box::new(IntegritySamplerJob {}),
+		))
+		.await;
+
+	Ok(true)
+}
+
+/// is_due tells if a library hasn't been sampled for a while
+async fn is_due(library: &LibraryContext) -> Result<bool, QueryError> {
+	let latest = library
+		.db
+		.integrity_sample()
+		.find_first(vec![])
+		.order_by(integrity_sample::date_created::order(Direction::Desc))
+		.exec()
+		.await?;
+
+	Ok(latest.map_or(true, |sample| {
+		Utc::now() - ChronoDuration::days(SAMPLING_INTERVAL_DAYS) > sample.date_created
+	}))
+}
+
+/// spawn_integrity_sampling samples the files of a library once a week, for as long as the library is
+/// loaded
+pub fn spawn_integrity_sampling(library: LibraryContext) {
+	tokio::spawn(async move {
+		let mut interval = interval(SAMPLING_CHECK_INTERVAL);
+		// samples are only written once a run is done, so a run isn't started again in the meantime
+		let mut last_started: Option<Instant> = None;
+
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {}
+				_ = library.stopped() => break,
+			}
+
+			if library.process_lock.is_read_only() {
+				continue;
+			}
+
+			let started_recently = last_started.map_or(false, |started| {
+				started.elapsed()
+					< Duration::from_secs(SAMPLING_INTERVAL_DAYS as u64 * 24 * 60 * 60)
+			});
+			if started_recently {
+				continue;
+			}
+
+			match is_due(&library).await {
+				Ok(true) => match sample_integrity(&library).await {
+					Ok(true) => {
+						info!("Sampling the integrity of library '{}'", library.id);
+						last_started = Some(Instant::now());
+					}
+					Ok(false) => {}
+					Err(e) => error!(
+						"Failed to sample the integrity of library '{}': {e:#?}",
+						library.id
+					),
+				},
+				Ok(false) => {}
+				Err(e) => error!(
+					"Failed to check when library '{}' was last sampled: {e:#?}",
+					library.id
+				),
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tally(sampled: i32, corrupted: i32) -> SampleTally {
+		SampleTally {
+			sampled,
+			corrupted,
+			unreadable: 0,
+		}
+	}
+
+	#[test]
+	fn alerts_on_failing_trends() {
+		// a single bad file in a large sample is noise
+		assert!(!is_failing_trend(&[tally(200, 1), tally(200, 0)]));
+		// bad files sample after sample aren't
+		assert!(is_failing_trend(&[tally(200, 1), tally(200, 1)]));
+		// and neither is a sizable share of a sample
+		assert!(is_failing_trend(&[tally(200, 4)]));
+		assert!(!is_failing_trend(&[tally(200, 0), tally(200, 3)]));
+		assert!(!is_failing_trend(&[]));
+	}
+}
@@ -1,2 +1,5 @@
+pub mod checksum_file;
 pub mod hash;
+pub mod integrity;
+pub mod sampler_job;
 pub mod validator_job;
@@ -0,0 +1,233 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+use prisma_client_rust::raw::Raw;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+	job::{FileError, JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
+	object::versions::changed_since,
+	prisma::{file_path, location},
+	util::{db::raw_int, message::Message, os_path::resolve_materialized_path},
+};
+
+use super::{
+	hash::file_checksum,
+	integrity::{record_samples, SampleTally},
+};
+
+pub const INTEGRITY_SAMPLER_JOB_NAME: &str = "integrity_sampler";
+/// How many files are hashed again each time a library is sampled
+pub const DEFAULT_SAMPLE_SIZE: usize = 200;
+
+/// The integrity sampler hashes a random sample of the files that have a checksum again, and counts
+/// the ones that no longer match it or can't be read anymore by location
+pub struct IntegritySamplerJob {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IntegritySamplerJobInit {
+	pub sample_size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IntegritySamplerJobState {
+	location_paths: HashMap<i32, PathBuf>,
+	tallies: HashMap<i32, SampleTally>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IntegritySamplerJobStep {
+	#[serde(deserialize_with = "raw_int")]
+	location_id: i64,
+	#[serde(deserialize_with = "raw_int")]
+	file_path_id: i64,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for IntegritySamplerJob {
+	type Data = IntegritySamplerJobState;
+	type Init = IntegritySamplerJobInit;
+	type Step = IntegritySamplerJobStep;
+
+	fn name(&self) -> &'static str {
+		INTEGRITY_SAMPLER_JOB_NAME
+	}
+
+	async fn init(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let library = ctx.library_ctx();
+
+		// only files on locations that are there right now, the others can't be read to begin with
+		let steps: Vec<IntegritySamplerJobStep> = library
+			.db
+			._query_raw(Raw::new(
+				&format!(
+					"SELECT fp.location_id AS location_id, fp.id AS file_path_id FROM file_path fp \
+					JOIN object o ON o.id = fp.object_id JOIN location l ON l.id = fp.location_id \
+					WHERE o.integrity_checksum IS NOT NULL AND NOT fp.is_dir \
+					AND l.local_path IS NOT NULL AND l.is_online ORDER BY RANDOM() LIMIT {}",
+					state.init.sample_size
+				),
+				vec![],
+			))
+			.exec()
+			.await?;
+
+		let mut location_ids = steps
+			.iter()
+			.map(|step| step.location_id as i32)
+			.collect::<Vec<_>>();
+		location_ids.sort_unstable();
+		location_ids.dedup();
+
+		let location_paths = library
+			.db
+			.location()
+			.find_many(vec![location::id::in_vec(location_ids)])
+			.exec()
+			.await?
+			.into_iter()
+			.filter_map(|location| Some((location.id, PathBuf::from(location.local_path?))))
+			.collect();
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(steps.len())]);
+
+		state.steps = steps.into();
+		state.data = Some(IntegritySamplerJobState {
+			location_paths,
+			tallies: HashMap::new(),
+		});
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let location_id = step.location_id as i32;
+		let data = state.data.as_mut().expect("fatal: missing job state");
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		let file_path = ctx
+			.library_ctx()
+			.db
+			.file_path()
+			.find_unique(file_path::location_id_id(
+				location_id,
+				step.file_path_id as i32,
+			))
+			.with(file_path::object::fetch())
+			.exec()
+			.await?;
+		// the file may have been removed from the index, or identified again, since it was picked
+		let (file_path, checksum) = match file_path {
+			Some(file_path) => {
+				let checksum = file_path
+					.object()
+					.ok()
+					.flatten()
+					.and_then(|object| object.integrity_checksum.clone());
+				match checksum {
+					Some(checksum) => (file_path, checksum),
+					None => return Ok(()),
+				}
+			}
+			None => return Ok(()),
+		};
+		let location_path = match data.location_paths.get(&location_id) {
+			Some(location_path) => location_path,
+			None => return Ok(()),
+		};
+
+		let path = resolve_materialized_path(
+			location_path,
+			&file_path.materialized_path,
+			file_path.raw_path.as_deref(),
+		);
+		let tally = data.tallies.entry(location_id).or_default();
+
+		let metadata = match tokio::fs::metadata(&path).await {
+			Ok(metadata) => metadata,
+			// files that are gone are left to the sweep, they're no sign of the disk going bad
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => {
+				tally.sampled += 1;
+				tally.unreadable += 1;
+				ctx.record_file_error(FileError::new(&file_path, &e)).await;
+				return Ok(());
+			}
+		};
+
+		// a file that was changed on purpose doesn't match its old checksum either
+		if let Ok(modified) = metadata.modified() {
+			if changed_since(&file_path, modified.into()) {
+				return Ok(());
+			}
+		}
+
+		let hashed = {
+			let _hasher = ctx.governor().hasher().await;
+			file_checksum(path).await
+		};
+		ctx.governor().throttle_read(metadata.len()).await;
+
+		tally.sampled += 1;
+		match hashed {
+			Ok(hash) if hash == checksum => {}
+			Ok(_) => {
+				tally.corrupted += 1;
+				ctx.record_file_error(FileError::new(&file_path, Message::ChecksumMismatch))
+					.await;
+			}
+			Err(e) => {
+				tally.unreadable += 1;
+				ctx.record_file_error(FileError::new(&file_path, &e)).await;
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: WorkerContext,
+		state: &mut JobState<Self::Init, Self::Data, Self::Step>,
+	) -> JobResult {
+		let data = state
+			.data
+			.as_ref()
+			.expect("critical error: missing data on job state");
+
+		record_samples(&ctx.library_ctx(), &data.tallies).await?;
+
+		let total = data
+			.tallies
+			.values()
+			.fold(SampleTally::default(), |mut total, tally| {
+				total.sampled += tally.sampled;
+				total.corrupted += tally.corrupted;
+				total.unreadable += tally.unreadable;
+				total
+			});
+		info!(
+			"Integrity sampling done: {} corrupted and {} unreadable of {} sampled",
+			total.corrupted, total.unreadable, total.sampled
+		);
+
+		Ok(Some(json!({
+			"sampled": total.sampled,
+			"corrupted": total.corrupted,
+			"unreadable": total.unreadable,
+		})))
+	}
+}
@@ -5,12 +5,15 @@ use std::{collections::VecDeque, path::PathBuf};
 use crate::{
 	job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext},
 	prisma::{self, file_path, location, object},
+	util::os_path::resolve_materialized_path,
 };
 
 use tracing::info;
 
 use super::hash::file_checksum;
 
+pub const VALIDATOR_JOB_NAME: &str = "object_validator";
+
 // The Validator is able to:
 // - generate a full byte checksum for Objects in a Location
 // - generate checksums for all Objects missing without one
@@ -43,7 +46,7 @@ impl StatefulJob for ObjectValidatorJob {
 	type Step = ObjectValidatorJobStep;
 
 	fn name(&self) -> &'static str {
-		"object_validator"
+		VALIDATOR_JOB_NAME
 	}
 
 	async fn init(
@@ -91,7 +94,11 @@ impl StatefulJob for ObjectValidatorJob {
 
 		let data = state.data.as_ref().expect("fatal: missing job state");
 
-		let path = data.root_path.join(&step.path.materialized_path);
+		let path = resolve_materialized_path(
+			&data.root_path,
+			&step.path.materialized_path,
+			step.path.raw_path.as_deref(),
+		);
 
 		// skip directories
 		if path.is_dir() {
@@ -0,0 +1,390 @@
+//! The content files had before they changed. When a re-index finds a file whose content changed
+//! since it was identified, the cas_id, size and modification date it had are kept as a version of
+//! the file before it's identified again. Locations can also keep encrypted copies of their small
+//! files, so these earlier versions can be restored and not only listed.
+
+use std::{
+	fs::File,
+	io,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use prisma_client_rust::Direction;
+use sd_crypto::{
+	crypto::stream::{Algorithm, StreamDecryption, StreamEncryption},
+	header::{file::FileHeader, keyslot::Keyslot},
+	primitives::{generate_master_key, LATEST_FILE_HEADER, LATEST_KEYSLOT},
+	Error as CryptoError,
+};
+use thiserror::Error;
+use tokio::task::block_in_place;
+use tracing::{debug, warn};
+
+use crate::{
+	invalidate_query,
+	library::LibraryContext,
+	location::{fetch_location, LocationError},
+	prisma::{file_path, file_version, object},
+	util::os_path::resolve_materialized_path,
+};
+
+const VERSIONS_DIR_NAME: &str = "versions";
+/// Files up to this size get a copy kept in locations that keep copies
+pub const VERSION_COPY_MAX_SIZE: u64 = 1024 * 1024;
+/// How many earlier versions of a file are kept, the oldest are forgotten first
+const MAX_VERSIONS_PER_FILE: usize = 20;
+
+#[derive(Error, Debug)]
+pub enum VersionError {
+	#[error("Database error: {0}")]
+	DatabaseError(#[from] prisma_client_rust::QueryError),
+	#[error("{0}")]
+	LocationError(#[from] LocationError),
+	#[error("File version not found (id: {0})")]
+	NotFound(i32),
+	#[error("No copy of this version was kept (id: {0})")]
+	NoCopy(i32),
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("Crypto error: {0}")]
+	CryptoError(#[from] CryptoError),
+}
+
+impl From<VersionError> for rspc::Error {
+	fn from(err: VersionError) -> Self {
+		match err {
+			VersionError::NotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, err.to_string(), err)
+			}
+			VersionError::NoCopy(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			VersionError::LocationError(err) => err.into(),
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// versions_dir is where the library keeps copies of file versions, each stored under its cas_id
+pub fn versions_dir(library: &LibraryContext) -> PathBuf {
+	library
+		.config()
+		.data_directory()
+		.join(VERSIONS_DIR_NAME)
+		.join(library.id.to_string())
+}
+
+fn copy_path(library: &LibraryContext, cas_id: &str) -> PathBuf {
+	versions_dir(library).join(cas_id)
+}
+
+/// keep_copy keeps an encrypted copy of the file at `path`, whose content has the `cas_id`, with the
+/// library's default key. Copies are only made while that key is mounted, returns if one was made.
+pub fn keep_copy(
+	library: &LibraryContext,
+	path: &Path,
+	cas_id: &str,
+) -> Result<bool, VersionError> {
+	let target = copy_path(library, cas_id);
+	if target.exists() {
+		return Ok(true);
+	}
+
+	let key_uuid = match library.key_manager.get_default() {
+		Ok(key_uuid) => key_uuid,
+		Err(_) => return Ok(false),
+	};
+	let user_key = match library.key_manager.access_keymount(key_uuid) {
+		Ok(mounted) => mounted.hashed_key,
+		Err(_) => return Ok(false),
+	};
+	let user_key_details = library.key_manager.access_keystore(key_uuid)?;
+	library.key_lock.touch();
+
+	block_in_place(|| {
+		std::fs::create_dir_all(versions_dir(library))?;
+
+		let reader = File::open(path)?;
+		// written aside and renamed, so a copy that's there is always whole
+		let partial = target.with_extension("partial");
+		let mut writer = File::create(&partial)?;
+
+		let algorithm = Algorithm::XChaCha20Poly1305;
+		let master_key = generate_master_key();
+		let keyslots = vec![Keyslot::new(
+			LATEST_KEYSLOT,
+			algorithm,
+			user_key_details.hashing_algorithm,
+			user_key_details.content_salt,
+			user_key,
+			&master_key,
+		)?];
+		let header = FileHeader::new(LATEST_FILE_HEADER, algorithm, keyslots);
+		header.write(&mut writer)?;
+
+		StreamEncryption::new(master_key, &header.nonce, header.algorithm)?.encrypt_streams(
+			reader,
+			&mut writer,
+			&header.generate_aad(),
+		)?;
+
+		std::fs::rename(partial, target)?;
+
+		Ok(true)
+	})
+}
+
+/// record_version keeps the content `file_path` has in the index as one of its versions, before it's
+/// identified again. File paths that were never identified have no content to keep.
+pub async fn record_version(
+	library: &LibraryContext,
+	file_path: &file_path::Data,
+) -> Result<Option<file_version::Data>, prisma_client_rust::QueryError> {
+	let object = match file_path.object_id {
+		Some(object_id) => {
+			library
+				.db
+				.object()
+				.find_unique(object::id::equals(object_id))
+				.exec()
+				.await?
+		}
+		None => None,
+	};
+	let object = match object {
+		Some(object) => object,
+		None => return Ok(None),
+	};
+
+	let has_copy = copy_path(library, &object.cas_id).exists();
+	let version = library
+		.db
+		.file_version()
+		.create_unchecked(
+			file_path.location_id,
+			file_path.id,
+			object.cas_id,
+			object.size_in_bytes,
+			file_path.date_modified,
+			vec![file_version::has_copy::set(has_copy)],
+		)
+		.exec()
+		.await?;
+
+	forget_old_versions(library, file_path).await?;
+
+	Ok(Some(version))
+}
+
+/// forget_old_versions removes the versions of `file_path` past the ones kept, along with the copies
+/// no other version or object uses
+async fn forget_old_versions(
+	library: &LibraryContext,
+	file_path: &file_path::Data,
+) -> Result<(), prisma_client_rust::QueryError> {
+	let old = library
+		.db
+		.file_version()
+		.find_many(vec![
+			file_version::location_id::equals(file_path.location_id),
+			file_version::file_path_id::equals(file_path.id),
+		])
+		.order_by(file_version::id::order(Direction::Desc))
+		.skip(MAX_VERSIONS_PER_FILE as i64)
+		.exec()
+		.await?;
+
+	if old.is_empty() {
+		return Ok(());
+	}
+
+	library
+		.db
+		.file_version()
+		.delete_many(vec![file_version::id::in_vec(
+			old.iter().map(|version| version.id).collect(),
+		)])
+		.exec()
+		.await?;
+
+	for version in old.into_iter().filter(|version| version.has_copy) {
+		if !is_copy_used(library, &version.cas_id).await? {
+			remove_copy(library, &version.cas_id);
+		}
+	}
+
+	Ok(())
+}
+
+async fn is_copy_used(
+	library: &LibraryContext,
+	cas_id: &str,
+) -> Result<bool, prisma_client_rust::QueryError> {
+	let versions = library
+		.db
+		.file_version()
+		.count(vec![file_version::cas_id::equals(cas_id.to_string())])
+		.exec()
+		.await?;
+	if versions > 0 {
+		return Ok(true);
+	}
+
+	let objects = library
+		.db
+		.object()
+		.count(vec![object::cas_id::equals(cas_id.to_string())])
+		.exec()
+		.await?;
+
+	Ok(objects > 0)
+}
+
+fn remove_copy(library: &LibraryContext, cas_id: &str) {
+	if let Err(e) = std::fs::remove_file(copy_path(library, cas_id)) {
+		if e.kind() != io::ErrorKind::NotFound {
+			warn!("Failed to remove the copy of version {}: {:#?}", cas_id, e);
+		}
+	}
+}
+
+/// prune_copies removes the copies of content that no file has or had anymore, like the ones of files
+/// that were deleted
+pub async fn prune_copies(library: &LibraryContext) -> Result<usize, VersionError> {
+	let entries = match std::fs::read_dir(versions_dir(library)) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+		Err(e) => return Err(e.into()),
+	};
+
+	let mut removed = 0;
+	for entry in entries {
+		let cas_id = entry?.file_name().to_string_lossy().to_string();
+		if !is_copy_used(library, &cas_id).await? {
+			debug!("Removing unused copy of version {}", cas_id);
+			remove_copy(library, &cas_id);
+			removed += 1;
+		}
+	}
+
+	Ok(removed)
+}
+
+/// unlink_changed records the version `file_path` had before its content changed, and unlinks it from
+/// its object so the identifier picks it up again
+pub async fn unlink_changed(
+	library: &LibraryContext,
+	file_path: &file_path::Data,
+	modified: DateTime<FixedOffset>,
+) -> Result<(), prisma_client_rust::QueryError> {
+	if let Some(version) = record_version(library, file_path).await? {
+		debug!(
+			"{} changed, its previous content {} was kept as version {}",
+			file_path.materialized_path, version.cas_id, version.id
+		);
+		invalidate_query!(library, "files.getHistory");
+	}
+
+	library
+		.db
+		.file_path()
+		.update(
+			file_path::location_id_id(file_path.location_id, file_path.id),
+			vec![
+				file_path::date_modified::set(modified),
+				file_path::object::disconnect(),
+			],
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+/// file_history lists the earlier versions of a file path, the latest first
+pub async fn file_history(
+	library: &LibraryContext,
+	location_id: i32,
+	file_path_id: i32,
+) -> Result<Vec<file_version::Data>, VersionError> {
+	Ok(library
+		.db
+		.file_version()
+		.find_many(vec![
+			file_version::location_id::equals(location_id),
+			file_version::file_path_id::equals(file_path_id),
+		])
+		.order_by(file_version::id::order(Direction::Desc))
+		.exec()
+		.await?)
+}
+
+/// restore_version decrypts the kept copy of a version next to its file, with a conflict name so the
+/// file as it is now stays as it is, and returns where it was restored to
+pub async fn restore_version(
+	library: &LibraryContext,
+	version_id: i32,
+) -> Result<PathBuf, VersionError> {
+	let version = library
+		.db
+		.file_version()
+		.find_unique(file_version::id::equals(version_id))
+		.include(file_version::include!({ file_path }))
+		.exec()
+		.await?
+		.ok_or(VersionError::NotFound(version_id))?;
+
+	let copy = copy_path(library, &version.cas_id);
+	if !version.has_copy || !copy.exists() {
+		return Err(VersionError::NoCopy(version_id));
+	}
+
+	let location = fetch_location(library, version.location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(version.location_id))?;
+	let location_path = location
+		.local_path
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingLocalPath(version.location_id))?;
+
+	let path = resolve_materialized_path(
+		&location_path,
+		&version.file_path.materialized_path,
+		version.file_path.raw_path.as_deref(),
+	);
+	let node_config = library.config().get().await;
+	let target = node_config
+		.conflict_naming
+		.free_path(&path, &node_config.name);
+
+	let keys = library.key_manager.enumerate_hashed_keys();
+	library.key_lock.touch();
+
+	block_in_place(|| {
+		let mut reader = File::open(&copy)?;
+		let writer = File::create(&target)?;
+
+		let (header, aad) = FileHeader::deserialize(&mut reader)?;
+		let master_key = header.decrypt_master_key_from_prehashed(keys)?;
+
+		StreamDecryption::new(master_key, &header.nonce, header.algorithm)?
+			.decrypt_streams(reader, writer, &aad)?;
+
+		Ok::<_, VersionError>(())
+	})?;
+
+	invalidate_query!(library, "locations.getExplorerData");
+
+	Ok(target)
+}
+
+/// changed_since tells if a file modified at `modified` changed since it was indexed. Entries written
+/// by the indexer have when they were indexed as their modification date, so a file only changed when
+/// it was modified after both of its dates.
+pub fn changed_since(file_path: &file_path::Data, modified: DateTime<Utc>) -> bool {
+	modified > file_path.date_created && modified > file_path.date_modified
+}
@@ -0,0 +1,241 @@
+//! Sets up a library on a new node from a paired node that already has it. The peer sends an export of
+//! the library, the same one `library.export` writes, which is imported as is so its files don't have
+//! to be indexed or their thumbnails generated again. The operations logged since the export are then
+//! pulled from the peer with the sync engine, which is also how the library is kept up to date after.
+
+use std::{path::Path, time::Instant};
+
+use rspc::Type;
+use sd_codec::Artifact;
+use sd_p2p::quinn::{RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	fs::{self, File, OpenOptions},
+	io::{AsyncReadExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::{
+	library::{export_library, LibraryContext, LibraryManager},
+	prisma::paired_peer,
+	sync::{SyncBatch, SyncError},
+};
+
+use super::{
+	proto::write_message,
+	remote::{peer_trust, request, RemoteRequest, RemoteResponse},
+	spacedrop::{BLOCK_SIZE, PROGRESS_INTERVAL},
+	P2PError, P2PEvent, PeerTrust,
+};
+
+/// How many operations are sent at once when catching up, fewer than the sync API sends so each
+/// batch stays well within the size of a message
+pub(super) const CLONE_SYNC_BATCH_SIZE: i64 = 100;
+
+/// A library a peer can set this node up with
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct RemoteLibrary {
+	pub library_id: Uuid,
+	pub name: String,
+	pub description: String,
+}
+
+/// paired_library returns a library if `peer_id` is paired in it and trusted to sync it
+pub(super) async fn paired_library(
+	library_manager: &LibraryManager,
+	peer_id: &str,
+	library_id: Uuid,
+) -> Result<LibraryContext, String> {
+	let not_paired = || format!("library {library_id} isn't shared with this node");
+
+	let library = library_manager
+		.get_ctx(library_id)
+		.await
+		.ok_or_else(not_paired)?;
+
+	let trust = peer_trust(&library, peer_id)
+		.await
+		.map_err(|e| e.to_string())?;
+
+	match trust {
+		Some(trust) if trust.can_sync() => Ok(library),
+		_ => Err(not_paired()),
+	}
+}
+
+/// cloneable_libraries returns the libraries `peer_id` is paired in and trusted to sync, which it can
+/// set itself up with
+pub(super) async fn cloneable_libraries(
+	library_manager: &LibraryManager,
+	peer_id: &str,
+) -> Result<Vec<RemoteLibrary>, P2PError> {
+	let mut libraries = vec![];
+	for library in library_manager.get_all_libraries_ctx().await {
+		let trust = peer_trust(&library, peer_id).await?;
+
+		if trust.map_or(false, PeerTrust::can_sync) {
+			libraries.push(RemoteLibrary {
+				library_id: library.id,
+				name: library.config.name.clone(),
+				description: library.config.description.clone(),
+			});
+		}
+	}
+
+	Ok(libraries)
+}
+
+/// send_clone exports a library and streams the export to the peer setting itself up with it
+pub(super) async fn send_clone(
+	library: &LibraryContext,
+	tx: &mut SendStream,
+) -> Result<(), P2PError> {
+	// named uniquely, as more than one peer could be cloning the library at once
+	let path = library.config().data_directory().join(format!(
+		"{}.{}.clone.sdexport",
+		library.id,
+		Uuid::new_v4()
+	));
+
+	if let Err(e) = export_library(library, &path).await {
+		fs::remove_file(&path).await.ok();
+		write_message(tx, &RemoteResponse::Error(e.to_string())).await?;
+		return Err(P2PError::Remote(e.to_string()));
+	}
+
+	let result: Result<(), P2PError> = async {
+		let mut file = File::open(&path).await?;
+		let size = file.metadata().await?.len();
+		write_message(tx, &RemoteResponse::File { size }).await?;
+
+		let mut buffer = vec![0; BLOCK_SIZE];
+		let mut remaining = size;
+		while remaining > 0 {
+			let len = (remaining as usize).min(BLOCK_SIZE);
+			file.read_exact(&mut buffer[..len]).await?;
+			AsyncWriteExt::write_all(tx, &buffer[..len]).await?;
+			remaining -= len as u64;
+		}
+
+		Ok(())
+	}
+	.await;
+
+	fs::remove_file(&path).await.ok();
+	result
+}
+
+/// sync_batch returns the operations of a library a peer is missing, going by its clocks
+pub(super) async fn sync_batch(library: &LibraryContext, clocks: &str) -> Result<String, P2PError> {
+	let clocks = serde_json::from_str(clocks).map_err(SyncError::from)?;
+	let batch = library
+		.sync
+		.operations(&clocks, CLONE_SYNC_BATCH_SIZE)
+		.await?;
+
+	let batch = serde_json::to_vec(&batch).map_err(SyncError::from)?;
+	Ok(base64::encode(sd_codec::compress(
+		Artifact::SyncPayload.codec(),
+		&batch,
+	)?))
+}
+
+/// decode_sync_batch reads a batch sent by [`sync_batch`]
+pub(super) fn decode_sync_batch(batch: &str) -> Result<SyncBatch, P2PError> {
+	let batch = base64::decode(batch).map_err(|e| P2PError::Remote(e.to_string()))?;
+	Ok(serde_json::from_slice(&sd_codec::decompress(&batch)?).map_err(SyncError::from)?)
+}
+
+/// receive_clone downloads the export of a library from a peer to `target`
+pub(super) async fn receive_clone(
+	stream: &mut (SendStream, RecvStream),
+	library_id: Uuid,
+	target: &Path,
+	emit: impl Fn(P2PEvent),
+) -> Result<(), P2PError> {
+	let size = match request(stream, RemoteRequest::Clone { library_id }).await? {
+		RemoteResponse::File { size } => size,
+		_ => return Err(P2PError::Remote("unexpected response".into())),
+	};
+
+	let mut writer = OpenOptions::new()
+		.create(true)
+		.write(true)
+		.truncate(true)
+		.open(target)
+		.await?;
+	let (_, rx) = stream;
+	let mut buffer = vec![0; BLOCK_SIZE];
+	let mut transferred = 0;
+	let mut last_report = Instant::now();
+	while transferred < size {
+		let len = ((size - transferred) as usize).min(BLOCK_SIZE);
+		let read = AsyncReadExt::read(rx, &mut buffer[..len]).await?;
+		if read == 0 {
+			return Err(P2PError::Remote("the peer closed the stream".into()));
+		}
+
+		writer.write_all(&buffer[..read]).await?;
+		transferred += read as u64;
+
+		if last_report.elapsed() >= PROGRESS_INTERVAL || transferred == size {
+			last_report = Instant::now();
+			emit(P2PEvent::CloneProgress {
+				library_id,
+				transferred,
+				total: size,
+			});
+		}
+	}
+
+	writer.sync_all().await?;
+	Ok(())
+}
+
+/// adopt_clone drops what a cloned library only meant on the node it came from: the locations that
+/// node shared with its peers, and this node being one of its peers. The node it came from becomes a
+/// peer in it instead.
+pub(super) async fn adopt_clone(
+	library: &LibraryContext,
+	own_peer_id: &str,
+	peer: Option<paired_peer::Data>,
+) -> Result<(), P2PError> {
+	library
+		.db
+		.location_share()
+		.delete_many(vec![])
+		.exec()
+		.await?;
+	library
+		.db
+		.paired_peer()
+		.delete_many(vec![paired_peer::peer_id::equals(own_peer_id.to_string())])
+		.exec()
+		.await?;
+
+	if let Some(peer) = peer {
+		library
+			.db
+			.paired_peer()
+			.upsert(
+				paired_peer::peer_id::equals(peer.peer_id.clone()),
+				(
+					peer.peer_id,
+					peer.name.clone(),
+					vec![
+						paired_peer::os::set(peer.os.clone()),
+						paired_peer::trust::set(peer.trust),
+					],
+				),
+				vec![
+					paired_peer::name::set(peer.name),
+					paired_peer::os::set(peer.os),
+					paired_peer::trust::set(peer.trust),
+				],
+			)
+			.exec()
+			.await?;
+	}
+
+	Ok(())
+}
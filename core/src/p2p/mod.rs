@@ -0,0 +1,1034 @@
+//! Peer to peer connections between nodes on the same network. Nodes advertise themselves and
+//! discover each other over mDNS, and connect with QUIC, both through `sd-p2p`.
+//!
+//! Only paired nodes can connect to each other. The node starting a pairing shows a short code,
+//! also as a QR payload, which is entered on the other node and checked with PAKE so neither side
+//! can be impersonated. Each node then records the other as a trusted peer in its libraries, and
+//! reconnects to it whenever it's discovered again. How far that trust goes is the [`PeerTrust`]
+//! picked when pairing, a peer trusted with transfers only can send Spacedrops but can't see anything
+//! of the libraries.
+
+mod clone;
+mod pairing;
+mod proto;
+mod remote;
+mod spacedrop;
+
+pub use clone::RemoteLibrary;
+pub use pairing::*;
+pub use proto::ProtoError;
+pub use remote::{RemoteFilePath, RemoteLocation};
+pub use spacedrop::{SpacedropError, SpacedropFile};
+
+use clone::{adopt_clone, decode_sync_batch, receive_clone};
+use proto::{read_message, write_message, StreamHeader};
+use remote::{fetch_file, request, serve, RemoteRequest, RemoteResponse};
+use spacedrop::{
+	prepare_receive, receive, send, spacedrop_files, SpacedropRequest, SpacedropResponse,
+	SPACEDROP_REQUEST_TIMEOUT,
+};
+
+use std::{
+	collections::{HashMap, HashSet},
+	future::Future,
+	io,
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use int_enum::IntEnum;
+use rspc::{ErrorCode, Type};
+use sd_p2p::{
+	quinn::{RecvStream, SendStream},
+	Identity, NMError, NetworkManager, NetworkManagerConfig, NetworkManagerError, OperationSystem,
+	PairingParticipantType, Peer, PeerId, PeerMetadata,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+	fs,
+	sync::{broadcast, oneshot},
+	time::{sleep, timeout},
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+	api::CoreEvent,
+	invalidate_query,
+	job::{Job, TransferGovernor},
+	library::{LibraryImport, LibraryManager, LibraryManagerError},
+	node::NodeConfigManager,
+	object::preview::{plan_preview_warming, PreviewWarmerJob, PreviewWarmerJobInit},
+	prisma::{location_share, paired_peer},
+	sync::{SyncBatch, SyncError},
+};
+
+/// Name nodes advertise themselves under, the mDNS service is `_spacedrive._udp.local.`
+const P2P_APPLICATION_NAME: &str = "spacedrive";
+/// P2P_IDENTITY_FILE_NAME is the file in the data directory holding the node's p2p keys
+const P2P_IDENTITY_FILE_NAME: &str = "p2p_identity.sdconfig";
+/// How long a pairing request waits for the user to enter the code before it's rejected
+const PAIRING_REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Error, Debug)]
+pub enum P2PError {
+	#[error("Failed to start p2p networking: {0}")]
+	NetworkManager(#[from] NetworkManagerError),
+	#[error("I/O error: {0}")]
+	Io(#[from] io::Error),
+	#[error("Invalid p2p identity: {0}")]
+	IdentitySerialization(#[from] serde_json::Error),
+	#[error("Database error: {0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+	#[error("P2P networking isn't running on this node")]
+	NotRunning,
+	#[error("Invalid peer id '{0}'")]
+	InvalidPeerId(String),
+	#[error("Failed to pair with peer: {0}")]
+	Pairing(#[from] NMError),
+	#[error("Pairing request {0} doesn't exist or has expired")]
+	UnknownPairingRequest(Uuid),
+	#[error("The QR code scanned belongs to another peer")]
+	PairingPeerMismatch,
+	#[error("Peer '{0}' isn't connected")]
+	PeerNotConnected(String),
+	#[error("Spacedrop {0} doesn't exist or has expired")]
+	UnknownSpacedrop(Uuid),
+	#[error("Spacedrop failed: {0}")]
+	Spacedrop(#[from] SpacedropError),
+	#[error("Failed to talk to peer: {0}")]
+	Proto(#[from] ProtoError),
+	#[error("Peer answered with an error: {0}")]
+	Remote(String),
+	#[error("{0}")]
+	Library(#[from] LibraryManagerError),
+	#[error("Failed to sync with peer: {0}")]
+	Sync(#[from] SyncError),
+	#[error("Failed to compress or decompress data for a peer: {0}")]
+	Codec(#[from] sd_codec::Error),
+}
+
+impl From<P2PError> for rspc::Error {
+	fn from(err: P2PError) -> Self {
+		match err {
+			P2PError::UnknownPairingRequest(_) | P2PError::UnknownSpacedrop(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			P2PError::InvalidPeerId(_)
+			| P2PError::PairingPeerMismatch
+			| P2PError::PeerNotConnected(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// A node found on the network
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct DiscoveredPeer {
+	pub peer_id: String,
+	pub name: String,
+	pub os: Option<String>,
+	pub version: Option<String>,
+	/// whether the peer is paired with this node in any of its libraries
+	pub paired: bool,
+	pub connected: bool,
+}
+
+/// Changes in the peers around this node, which the frontend follows through `p2p.events`
+#[derive(Debug, Clone, Serialize, Type)]
+pub enum P2PEvent {
+	Discovered {
+		peer_id: String,
+		name: String,
+	},
+	Expired {
+		peer_id: String,
+	},
+	Connected {
+		peer_id: String,
+	},
+	Disconnected {
+		peer_id: String,
+	},
+	/// another node wants to pair, the user has to enter the code shown on it
+	PairingRequest {
+		id: Uuid,
+		peer_id: String,
+		name: String,
+	},
+	Paired {
+		peer_id: String,
+		name: String,
+	},
+	/// a peer wants to send files, the user has to accept them and pick where they go
+	SpacedropRequest {
+		id: Uuid,
+		peer_id: String,
+		name: String,
+		files: Vec<SpacedropFile>,
+	},
+	/// bytes transferred so far by a Spacedrop, on both the sending and the receiving node
+	SpacedropProgress {
+		id: Uuid,
+		transferred: u64,
+		total: u64,
+	},
+	SpacedropFinished {
+		id: Uuid,
+		error: Option<String>,
+	},
+	/// bytes received so far of the library this node is being set up with
+	CloneProgress {
+		library_id: Uuid,
+		transferred: u64,
+		total: u64,
+	},
+}
+
+/// The keys the node is known by to its peers, which its peer id is derived from
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+	cert: Vec<u8>,
+	key: Vec<u8>,
+}
+
+async fn load_identity(data_dir: &Path) -> Result<Identity, P2PError> {
+	let path = data_dir.join(P2P_IDENTITY_FILE_NAME);
+
+	match fs::read(&path).await {
+		Ok(bytes) => {
+			let stored: StoredIdentity = serde_json::from_slice(&bytes)?;
+			Ok(Identity::from_raw(stored.cert, stored.key).map_err(NetworkManagerError::from)?)
+		}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {
+			info!("Generating a new p2p identity");
+			let identity = Identity::new().map_err(NetworkManagerError::from)?;
+			let (cert, key) = identity.to_raw();
+			fs::write(&path, serde_json::to_vec(&StoredIdentity { cert, key })?).await?;
+			Ok(identity)
+		}
+		Err(e) => Err(e.into()),
+	}
+}
+
+fn os_name(metadata: &PeerMetadata) -> Option<String> {
+	metadata.operating_system.clone().map(String::from)
+}
+
+/// A pairing request waiting for the user to enter the code
+struct PendingPairing {
+	peer_id: String,
+	code_tx: oneshot::Sender<Result<String, ()>>,
+}
+
+/// SdP2PManager is how `sd-p2p` hooks into the core, it's called as peers come and go or pair
+#[derive(Clone)]
+pub struct SdP2PManager {
+	metadata: PeerMetadata,
+	config: Arc<NodeConfigManager>,
+	library_manager: Arc<LibraryManager>,
+	event_bus_tx: broadcast::Sender<CoreEvent>,
+	pairing_requests: Arc<Mutex<HashMap<Uuid, PendingPairing>>>,
+	/// the trust picked for the peers being paired with, by peer id, until the pairing is done
+	pairing_trust: Arc<Mutex<HashMap<String, PeerTrust>>>,
+	/// Spacedrops waiting for the user to pick a directory for them, or turn them down
+	spacedrop_requests: Arc<Mutex<HashMap<Uuid, oneshot::Sender<Option<PathBuf>>>>>,
+	/// the node's network throttling, which files sent to and received from peers go through
+	transfers: Arc<TransferGovernor>,
+}
+
+impl SdP2PManager {
+	fn emit(&self, event: P2PEvent) {
+		self.event_bus_tx.send(CoreEvent::P2P(event)).ok();
+	}
+
+	/// trust_peer records a peer as paired in every library of this node, trusted with `trust`
+	async fn trust_peer(
+		&self,
+		peer_id: &str,
+		metadata: &PeerMetadata,
+		trust: PeerTrust,
+	) -> Result<(), P2PError> {
+		for library in self.library_manager.get_all_libraries_ctx().await {
+			library
+				.db
+				.paired_peer()
+				.upsert(
+					paired_peer::peer_id::equals(peer_id.to_string()),
+					(
+						peer_id.to_string(),
+						metadata.name.clone(),
+						vec![
+							paired_peer::os::set(os_name(metadata)),
+							paired_peer::trust::set(trust.int_value()),
+						],
+					),
+					vec![
+						paired_peer::name::set(metadata.name.clone()),
+						paired_peer::os::set(os_name(metadata)),
+						paired_peer::trust::set(trust.int_value()),
+					],
+				)
+				.exec()
+				.await?;
+		}
+
+		Ok(())
+	}
+
+	/// forget_peer removes a peer from the paired peers of every library of this node, along with the
+	/// locations shared with it
+	async fn forget_peer(&self, peer_id: &str) -> Result<(), P2PError> {
+		for library in self.library_manager.get_all_libraries_ctx().await {
+			library
+				.db
+				.paired_peer()
+				.delete_many(vec![paired_peer::peer_id::equals(peer_id.to_string())])
+				.exec()
+				.await?;
+			library
+				.db
+				.location_share()
+				.delete_many(vec![location_share::peer_id::equals(peer_id.to_string())])
+				.exec()
+				.await?;
+		}
+
+		Ok(())
+	}
+
+	/// receive_spacedrop prompts the user for a Spacedrop sent by `peer` and receives its files once
+	/// accepted
+	async fn receive_spacedrop(
+		&self,
+		peer_id: String,
+		name: String,
+		request: SpacedropRequest,
+		(mut tx, mut rx): (SendStream, RecvStream),
+	) -> Result<(), P2PError> {
+		let id = request.id;
+
+		let (target_tx, target_rx) = oneshot::channel();
+		self.spacedrop_requests
+			.lock()
+			.unwrap()
+			.insert(id, target_tx);
+		self.emit(P2PEvent::SpacedropRequest {
+			id,
+			peer_id,
+			name: name.clone(),
+			files: request.files.clone(),
+		});
+
+		let target_dir = match timeout(SPACEDROP_REQUEST_TIMEOUT, target_rx).await {
+			Ok(Ok(Some(target_dir))) => target_dir,
+			result => {
+				self.spacedrop_requests.lock().unwrap().remove(&id);
+				let reason = match result {
+					Err(_) => "the request expired",
+					_ => "the user declined",
+				};
+				write_message(
+					&mut tx,
+					&SpacedropResponse::Rejected {
+						reason: reason.to_string(),
+					},
+				)
+				.await?;
+				tx.finish().await.map_err(SpacedropError::from)?;
+				return Ok(());
+			}
+		};
+
+		let naming = self.config.get().await.conflict_naming;
+		let (targets, offsets) =
+			match prepare_receive(&target_dir, &request.files, &naming, &name).await {
+				Ok(prepared) => prepared,
+				Err(e) => {
+					write_message(
+						&mut tx,
+						&SpacedropResponse::Rejected {
+							reason: e.to_string(),
+						},
+					)
+					.await?;
+					tx.finish().await.map_err(SpacedropError::from)?;
+					self.emit(P2PEvent::SpacedropFinished {
+						id,
+						error: Some(e.to_string()),
+					});
+					return Err(e.into());
+				}
+			};
+
+		write_message(
+			&mut tx,
+			&SpacedropResponse::Accepted {
+				offsets: offsets.clone(),
+			},
+		)
+		.await?;
+
+		let this = self.clone();
+		let result = receive(
+			id,
+			&mut rx,
+			&request.files,
+			&targets,
+			&offsets,
+			&self.transfers,
+			move |event| this.emit(event),
+		)
+		.await;
+		self.emit(P2PEvent::SpacedropFinished {
+			id,
+			error: result.as_ref().err().map(ToString::to_string),
+		});
+		result?;
+
+		info!(
+			"Received {} files by Spacedrop into {}",
+			request.files.len(),
+			target_dir.display()
+		);
+
+		Ok(())
+	}
+
+	/// paired_peers returns the ids of the peers paired with this node in any of its libraries
+	async fn paired_peers(&self) -> Result<HashSet<String>, P2PError> {
+		let mut peers = HashSet::new();
+		for library in self.library_manager.get_all_libraries_ctx().await {
+			peers.extend(
+				library
+					.db
+					.paired_peer()
+					.find_many(vec![])
+					.exec()
+					.await?
+					.into_iter()
+					.map(|peer| peer.peer_id),
+			);
+		}
+
+		Ok(peers)
+	}
+}
+
+impl sd_p2p::P2PManager for SdP2PManager {
+	const APPLICATION_NAME: &'static str = P2P_APPLICATION_NAME;
+
+	fn get_metadata(&self) -> PeerMetadata {
+		self.metadata.clone()
+	}
+
+	fn peer_discovered(&self, nm: &NetworkManager<Self>, peer_id: &PeerId) {
+		if let Some(peer) = nm.discovered_peers().remove(peer_id) {
+			self.emit(P2PEvent::Discovered {
+				peer_id: peer_id.to_string(),
+				name: peer.metadata.name,
+			});
+		}
+	}
+
+	fn peer_expired(&self, _nm: &NetworkManager<Self>, peer_id: PeerId) {
+		self.emit(P2PEvent::Expired {
+			peer_id: peer_id.to_string(),
+		});
+	}
+
+	fn peer_connected(&self, _nm: &NetworkManager<Self>, peer_id: PeerId) {
+		self.emit(P2PEvent::Connected {
+			peer_id: peer_id.to_string(),
+		});
+	}
+
+	fn peer_disconnected(&self, _nm: &NetworkManager<Self>, peer_id: PeerId) {
+		self.emit(P2PEvent::Disconnected {
+			peer_id: peer_id.to_string(),
+		});
+	}
+
+	fn peer_pairing_request(
+		&self,
+		_nm: &NetworkManager<Self>,
+		peer_id: &PeerId,
+		metadata: &PeerMetadata,
+		_extra_data: &HashMap<String, String>,
+		password_resp: oneshot::Sender<Result<String, ()>>,
+	) {
+		let id = Uuid::new_v4();
+		debug!("Pairing request {} from peer '{}'", id, peer_id);
+
+		self.pairing_requests.lock().unwrap().insert(
+			id,
+			PendingPairing {
+				peer_id: peer_id.to_string(),
+				code_tx: password_resp,
+			},
+		);
+		self.emit(P2PEvent::PairingRequest {
+			id,
+			peer_id: peer_id.to_string(),
+			name: metadata.name.clone(),
+		});
+
+		// sd-p2p waits for an answer to every request, so the ones left unanswered are rejected
+		let pairing_requests = Arc::clone(&self.pairing_requests);
+		tokio::spawn(async move {
+			sleep(PAIRING_REQUEST_TIMEOUT).await;
+			if let Some(pending) = pairing_requests.lock().unwrap().remove(&id) {
+				debug!("Pairing request {} expired", id);
+				pending.code_tx.send(Err(())).ok();
+			}
+		});
+	}
+
+	fn accept_stream(&self, peer: &Peer<Self>, stream: (SendStream, RecvStream)) {
+		let this = self.clone();
+		let peer_id = peer.id.to_string();
+		let name = peer.metadata.name.clone();
+
+		tokio::spawn(async move {
+			let (mut tx, mut rx) = stream;
+			let result = match read_message(&mut rx).await {
+				Ok(StreamHeader::Spacedrop(request)) => {
+					this.receive_spacedrop(peer_id.clone(), name, request, (tx, rx))
+						.await
+				}
+				Ok(StreamHeader::Remote(request)) => {
+					let result = serve(
+						&this.library_manager,
+						&this.transfers,
+						&peer_id,
+						request,
+						&mut tx,
+					)
+					.await;
+					tx.finish().await.ok();
+					result
+				}
+				Err(e) => Err(e.into()),
+			};
+
+			if let Err(e) = result {
+				error!("Failed to handle stream from '{}': {:#?}", peer_id, e);
+			}
+		});
+	}
+
+	fn peer_paired<'a>(
+		&'a self,
+		nm: &'a NetworkManager<Self>,
+		_direction: PairingParticipantType,
+		peer_id: &'a PeerId,
+		peer_metadata: &'a PeerMetadata,
+		_extra_data: &'a HashMap<String, String>,
+	) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + 'a>> {
+		Box::pin(async move {
+			let trust = self
+				.pairing_trust
+				.lock()
+				.unwrap()
+				.remove(&peer_id.to_string())
+				.unwrap_or_default();
+			if let Err(e) = self.trust_peer(peer_id, peer_metadata, trust).await {
+				error!("Failed to record paired peer '{}': {:#?}", peer_id, e);
+				return Err(());
+			}
+
+			nm.add_known_peer(peer_id.clone());
+			info!("Paired with peer '{}'", peer_id);
+			self.emit(P2PEvent::Paired {
+				peer_id: peer_id.to_string(),
+				name: peer_metadata.name.clone(),
+			});
+
+			Ok(())
+		})
+	}
+
+	fn peer_paired_rollback<'a>(
+		&'a self,
+		nm: &'a NetworkManager<Self>,
+		_direction: PairingParticipantType,
+		peer_id: &'a PeerId,
+		_peer_metadata: &'a PeerMetadata,
+		_extra_data: &'a HashMap<String, String>,
+	) -> Pin<Box<dyn Future<Output = ()> + Send + Sync + 'a>> {
+		nm.remove_known_peer(peer_id);
+		self.pairing_trust
+			.lock()
+			.unwrap()
+			.remove(&peer_id.to_string());
+
+		// the rollback has to be `Sync`, which database queries aren't, so it's done on its own task
+		let this = self.clone();
+		let peer_id = peer_id.to_string();
+		tokio::spawn(async move {
+			if let Err(e) = this.forget_peer(&peer_id).await {
+				error!("Failed to roll back pairing with '{}': {:#?}", peer_id, e);
+			}
+		});
+
+		Box::pin(async {})
+	}
+}
+
+/// P2PManager runs the node's p2p networking
+pub struct P2PManager {
+	nm: Arc<NetworkManager<SdP2PManager>>,
+	manager: SdP2PManager,
+	data_dir: PathBuf,
+}
+
+impl P2PManager {
+	pub async fn new(
+		config: &Arc<NodeConfigManager>,
+		library_manager: Arc<LibraryManager>,
+		event_bus_tx: broadcast::Sender<CoreEvent>,
+		transfers: Arc<TransferGovernor>,
+	) -> Result<Arc<Self>, P2PError> {
+		let node_config = config.get().await;
+		let identity = load_identity(&config.data_directory()).await?;
+
+		let manager = SdP2PManager {
+			metadata: PeerMetadata {
+				name: node_config.name,
+				operating_system: Some(OperationSystem::get_os()),
+				version: Some(env!("CARGO_PKG_VERSION").into()),
+			},
+			config: Arc::clone(config),
+			library_manager,
+			event_bus_tx,
+			pairing_requests: Default::default(),
+			pairing_trust: Default::default(),
+			spacedrop_requests: Default::default(),
+			transfers,
+		};
+
+		let mut known_peers = HashSet::new();
+		for peer_id in manager.paired_peers().await? {
+			match PeerId::from_string(peer_id.clone()) {
+				Ok(peer_id) => {
+					known_peers.insert(peer_id);
+				}
+				Err(_) => warn!("Ignoring paired peer with invalid id '{}'", peer_id),
+			}
+		}
+
+		let nm = NetworkManager::new(
+			identity,
+			manager.clone(),
+			NetworkManagerConfig {
+				known_peers,
+				listen_port: node_config.p2p_port.map(|port| port as u16),
+				spacetunnel_url: None,
+			},
+		)
+		.await?;
+		info!(
+			"Started p2p as peer '{}' listening on {}",
+			nm.peer_id(),
+			nm.listen_addr()
+		);
+
+		Ok(Arc::new(Self {
+			nm,
+			manager,
+			data_dir: config.data_directory(),
+		}))
+	}
+
+	pub fn peer_id(&self) -> String {
+		self.nm.peer_id().to_string()
+	}
+
+	/// discovered_peers returns the nodes currently found on the network
+	pub async fn discovered_peers(&self) -> Result<Vec<DiscoveredPeer>, P2PError> {
+		let paired = self.manager.paired_peers().await?;
+		let connected = self.nm.connected_peers();
+
+		Ok(self
+			.nm
+			.discovered_peers()
+			.into_iter()
+			.map(|(peer_id, candidate)| DiscoveredPeer {
+				paired: paired.contains(peer_id.as_str()),
+				connected: connected.contains_key(&peer_id),
+				peer_id: peer_id.to_string(),
+				os: os_name(&candidate.metadata),
+				name: candidate.metadata.name,
+				version: candidate.metadata.version,
+			})
+			.collect())
+	}
+
+	/// pair starts pairing with a discovered peer, which will be trusted with `trust`, returning the
+	/// code to enter on it
+	pub async fn pair(&self, peer_id: String, trust: PeerTrust) -> Result<PairingCode, P2PError> {
+		let peer_id =
+			PeerId::from_string(peer_id.clone()).map_err(|_| P2PError::InvalidPeerId(peer_id))?;
+		self.manager
+			.pairing_trust
+			.lock()
+			.unwrap()
+			.insert(peer_id.to_string(), trust);
+
+		let code = self
+			.nm
+			.initiate_pairing_with_peer(peer_id.clone(), HashMap::new())
+			.await
+			.map_err(|e| {
+				self.manager
+					.pairing_trust
+					.lock()
+					.unwrap()
+					.remove(&peer_id.to_string());
+				e
+			})?;
+
+		Ok(PairingCode::new(&self.peer_id(), code))
+	}
+
+	/// accept_pairing answers a pairing request with the code shown on the peer that sent it, or
+	/// the payload of its QR code, the peer being trusted with `trust` once paired
+	pub fn accept_pairing(
+		&self,
+		id: Uuid,
+		code: Option<String>,
+		qr_payload: Option<String>,
+		trust: PeerTrust,
+	) -> Result<(), P2PError> {
+		let pending = self
+			.manager
+			.pairing_requests
+			.lock()
+			.unwrap()
+			.remove(&id)
+			.ok_or(P2PError::UnknownPairingRequest(id))?;
+
+		let code = match qr_payload.as_deref().and_then(parse_qr_payload) {
+			Some((peer_id, _)) if peer_id != pending.peer_id => {
+				pending.code_tx.send(Err(())).ok();
+				return Err(P2PError::PairingPeerMismatch);
+			}
+			Some((_, code)) => code,
+			None => normalize_code(code.as_deref().unwrap_or_default()),
+		};
+		self.manager
+			.pairing_trust
+			.lock()
+			.unwrap()
+			.insert(pending.peer_id.clone(), trust);
+
+		// a wrong code fails the PAKE exchange, which sd-p2p reports to both sides
+		pending.code_tx.send(Ok(code)).ok();
+		Ok(())
+	}
+
+	/// reject_pairing turns down a pairing request
+	pub fn reject_pairing(&self, id: Uuid) -> Result<(), P2PError> {
+		let pending = self
+			.manager
+			.pairing_requests
+			.lock()
+			.unwrap()
+			.remove(&id)
+			.ok_or(P2PError::UnknownPairingRequest(id))?;
+
+		pending.code_tx.send(Err(())).ok();
+		Ok(())
+	}
+
+	/// spacedrop offers the files at `paths` to a connected peer, sending them in the background once
+	/// the peer accepts. Progress is reported with [`P2PEvent`]s under the returned id.
+	pub async fn spacedrop(&self, peer_id: String, paths: Vec<PathBuf>) -> Result<Uuid, P2PError> {
+		let files = spacedrop_files(&paths).await?;
+		let mut stream = self.remote_stream(peer_id.clone()).await?;
+
+		let id = Uuid::new_v4();
+		write_message(
+			&mut stream.0,
+			&StreamHeader::Spacedrop(SpacedropRequest {
+				id,
+				files: files.clone(),
+			}),
+		)
+		.await?;
+
+		let manager = self.manager.clone();
+		tokio::spawn(async move {
+			let emitter = manager.clone();
+			let result = send(id, stream, paths, files, &manager.transfers, move |event| {
+				emitter.emit(event)
+			})
+			.await;
+
+			if let Err(e) = &result {
+				warn!("Spacedrop {} to '{}' failed: {:#?}", id, peer_id, e);
+			}
+			manager.emit(P2PEvent::SpacedropFinished {
+				id,
+				error: result.err().map(|e| e.to_string()),
+			});
+		});
+
+		Ok(id)
+	}
+
+	/// accept_spacedrop receives the files of a Spacedrop into `target_dir`
+	pub fn accept_spacedrop(&self, id: Uuid, target_dir: PathBuf) -> Result<(), P2PError> {
+		self.manager
+			.spacedrop_requests
+			.lock()
+			.unwrap()
+			.remove(&id)
+			.ok_or(P2PError::UnknownSpacedrop(id))?
+			.send(Some(target_dir))
+			.ok();
+
+		Ok(())
+	}
+
+	/// reject_spacedrop turns down a Spacedrop
+	pub fn reject_spacedrop(&self, id: Uuid) -> Result<(), P2PError> {
+		self.manager
+			.spacedrop_requests
+			.lock()
+			.unwrap()
+			.remove(&id)
+			.ok_or(P2PError::UnknownSpacedrop(id))?
+			.send(None)
+			.ok();
+
+		Ok(())
+	}
+
+	/// remote_stream opens a stream to a connected peer
+	/// peer_name returns the name a peer goes by, its id if it isn't around
+	fn peer_name(&self, peer_id: &str) -> String {
+		PeerId::from_string(peer_id.to_string())
+			.ok()
+			.and_then(|id| self.nm.discovered_peers().remove(&id))
+			.map(|candidate| candidate.metadata.name)
+			.unwrap_or_else(|| peer_id.to_string())
+	}
+
+	async fn remote_stream(&self, peer_id: String) -> Result<(SendStream, RecvStream), P2PError> {
+		let peer_id =
+			PeerId::from_string(peer_id.clone()).map_err(|_| P2PError::InvalidPeerId(peer_id))?;
+
+		self.nm
+			.stream(&peer_id)
+			.await
+			.map_err(|_| P2PError::PeerNotConnected(peer_id.to_string()))
+	}
+
+	/// remote_locations returns the locations a peer shares with this node
+	pub async fn remote_locations(&self, peer_id: String) -> Result<Vec<RemoteLocation>, P2PError> {
+		let mut stream = self.remote_stream(peer_id).await?;
+		match request(&mut stream, RemoteRequest::Locations).await? {
+			RemoteResponse::Locations(locations) => Ok(locations),
+			_ => Err(P2PError::Remote("unexpected response".into())),
+		}
+	}
+
+	/// remote_browse returns the entries of a directory in a location shared by a peer
+	pub async fn remote_browse(
+		&self,
+		peer_id: String,
+		library_id: Uuid,
+		location_id: i32,
+		path: String,
+	) -> Result<Vec<RemoteFilePath>, P2PError> {
+		let mut stream = self.remote_stream(peer_id).await?;
+		let browse = RemoteRequest::Browse {
+			library_id,
+			location_id,
+			path,
+		};
+
+		match request(&mut stream, browse).await? {
+			RemoteResponse::FilePaths(file_paths) => Ok(file_paths),
+			_ => Err(P2PError::Remote("unexpected response".into())),
+		}
+	}
+
+	/// remote_search looks for file paths by name in the locations a peer shares with this node
+	pub async fn remote_search(
+		&self,
+		peer_id: String,
+		query: String,
+	) -> Result<Vec<RemoteFilePath>, P2PError> {
+		let mut stream = self.remote_stream(peer_id).await?;
+		match request(&mut stream, RemoteRequest::Search { query }).await? {
+			RemoteResponse::FilePaths(file_paths) => Ok(file_paths),
+			_ => Err(P2PError::Remote("unexpected response".into())),
+		}
+	}
+
+	/// remote_fetch downloads a file from a location shared by a peer to `target`, or next to it with a
+	/// conflict copy name if something's there already
+	pub async fn remote_fetch(
+		&self,
+		peer_id: String,
+		library_id: Uuid,
+		location_id: i32,
+		file_path_id: i32,
+		target: PathBuf,
+	) -> Result<PathBuf, P2PError> {
+		let peer_name = self.peer_name(&peer_id);
+		let target = self
+			.manager
+			.config
+			.get()
+			.await
+			.conflict_naming
+			.free_path(&target, &peer_name);
+
+		let mut stream = self.remote_stream(peer_id).await?;
+		fetch_file(
+			&mut stream,
+			&self.manager.transfers,
+			library_id,
+			location_id,
+			file_path_id,
+			&target,
+		)
+		.await
+	}
+
+	/// remote_libraries returns the libraries a peer can set this node up with
+	pub async fn remote_libraries(&self, peer_id: String) -> Result<Vec<RemoteLibrary>, P2PError> {
+		let mut stream = self.remote_stream(peer_id).await?;
+		match request(&mut stream, RemoteRequest::Libraries).await? {
+			RemoteResponse::Libraries(libraries) => Ok(libraries),
+			_ => Err(P2PError::Remote("unexpected response".into())),
+		}
+	}
+
+	/// clone_library sets this node up with a library from a peer, with its thumbnails, then catches up
+	/// on what changed since with [`Self::sync_library`]. Progress is reported with [`P2PEvent`]s.
+	pub async fn clone_library(
+		&self,
+		peer_id: String,
+		library_id: Uuid,
+	) -> Result<LibraryImport, P2PError> {
+		let library_manager = &self.manager.library_manager;
+		if library_manager.get_ctx(library_id).await.is_some() {
+			return Err(LibraryManagerError::LibraryAlreadyExists(library_id).into());
+		}
+
+		// how the peer is known in this node's other libraries, so it's known in the clone too
+		let mut peer = None;
+		for library in library_manager.get_all_libraries_ctx().await {
+			peer = library
+				.db
+				.paired_peer()
+				.find_unique(paired_peer::peer_id::equals(peer_id.clone()))
+				.exec()
+				.await?;
+			if peer.is_some() {
+				break;
+			}
+		}
+
+		let path = self.data_dir.join(format!("{library_id}.clone.sdexport"));
+		let mut stream = self.remote_stream(peer_id.clone()).await?;
+		let manager = self.manager.clone();
+		let import = match receive_clone(&mut stream, library_id, &path, move |event| {
+			manager.emit(event)
+		})
+		.await
+		{
+			Ok(()) => library_manager.import(&path).await.map_err(P2PError::from),
+			Err(e) => Err(e),
+		};
+		fs::remove_file(&path).await.ok();
+		let import = import?;
+
+		let library = library_manager
+			.get_ctx(library_id)
+			.await
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+		adopt_clone(&library, &self.peer_id(), peer).await?;
+		info!("Cloned library '{}' from peer '{}'", library_id, peer_id);
+
+		self.sync_library(peer_id, library_id).await?;
+
+		Ok(import)
+	}
+
+	/// sync_library pulls the operations of a library this node is missing from a peer, returning how
+	/// many were ingested
+	pub async fn sync_library(&self, peer_id: String, library_id: Uuid) -> Result<usize, P2PError> {
+		let library = self
+			.manager
+			.library_manager
+			.get_ctx(library_id)
+			.await
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		let mut ingested = 0;
+		loop {
+			let clocks =
+				serde_json::to_string(&library.sync.clocks().await?).map_err(SyncError::from)?;
+			let mut stream = self.remote_stream(peer_id.clone()).await?;
+			let batch: SyncBatch = match request(
+				&mut stream,
+				RemoteRequest::SyncOperations { library_id, clocks },
+			)
+			.await?
+			{
+				RemoteResponse::SyncBatch(batch) => decode_sync_batch(&batch)?,
+				_ => return Err(P2PError::Remote("unexpected response".into())),
+			};
+
+			let has_more = batch.has_more;
+			let batch = library.sync.ingest(batch).await?;
+			ingested += batch.operations;
+
+			// warm the previews of what the batch brought so it doesn't show up as placeholders
+			let warming = plan_preview_warming(&library, &batch.objects).await?;
+			if !warming.generate.is_empty() {
+				library
+					.spawn_job(Job::new(
+						PreviewWarmerJobInit {
+							steps: warming.generate,
+						},
+						Box::new(PreviewWarmerJob {}),
+					))
+					.await;
+			}
+
+			if !has_more || batch.operations == 0 {
+				break;
+			}
+		}
+
+		if ingested > 0 {
+			invalidate_query!(library, "tags.list");
+			invalidate_query!(library, "locations.list");
+		}
+
+		Ok(ingested)
+	}
+
+	/// unpair stops trusting a peer, which has to pair again before it can connect
+	pub async fn unpair(&self, peer_id: String) -> Result<(), P2PError> {
+		self.manager.forget_peer(&peer_id).await?;
+
+		match PeerId::from_string(peer_id.clone()) {
+			Ok(peer_id) => self.nm.remove_known_peer(&peer_id),
+			Err(_) => warn!("Unpaired peer with invalid id '{}'", peer_id),
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,120 @@
+use int_enum::IntEnum;
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the QR payloads shown while pairing
+const PAIRING_QR_SCHEME: &str = "spacedrive-pair:";
+
+/// The code shown on the node starting a pairing, to be entered on the other node
+#[derive(Debug, Serialize, Type)]
+pub struct PairingCode {
+	pub code: String,
+	/// the code along with the peer showing it, for nodes with a camera to scan instead
+	pub qr_payload: String,
+}
+
+impl PairingCode {
+	pub fn new(peer_id: &str, code: String) -> Self {
+		Self {
+			qr_payload: format!("{PAIRING_QR_SCHEME}{peer_id}/{code}"),
+			code,
+		}
+	}
+}
+
+/// How much a paired peer is trusted with, picked when pairing and stored with the peer in each
+/// library. Every level can send Spacedrops, the others only go as far as their level allows, which
+/// is checked on every request the peer makes.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq, IntEnum)]
+pub enum PeerTrust {
+	/// can browse and read the locations shared with it, and clone and sync the library
+	FullSync = 0,
+	/// can browse and search the locations shared with it, without reading their files
+	MetadataOnly = 1,
+	/// can only send and receive Spacedrops, without any access to the library
+	TransferOnly = 2,
+}
+
+impl Default for PeerTrust {
+	fn default() -> Self {
+		Self::FullSync
+	}
+}
+
+impl PeerTrust {
+	/// from_stored reads a trust level as stored in the database, anything unknown being the least
+	/// trusted level
+	pub fn from_stored(trust: i32) -> Self {
+		Self::from_int(trust).unwrap_or(Self::TransferOnly)
+	}
+
+	/// can_browse tells if the peer can list and search the locations shared with it
+	pub fn can_browse(self) -> bool {
+		matches!(self, Self::FullSync | Self::MetadataOnly)
+	}
+
+	/// can_read_files tells if the peer can download files from the locations shared with it
+	pub fn can_read_files(self) -> bool {
+		self == Self::FullSync
+	}
+
+	/// can_sync tells if the peer can clone the library and pull its operations
+	pub fn can_sync(self) -> bool {
+		self == Self::FullSync
+	}
+}
+
+/// parse_qr_payload returns the peer id and pairing code in a scanned QR payload
+pub fn parse_qr_payload(payload: &str) -> Option<(String, String)> {
+	let (peer_id, code) = payload
+		.trim()
+		.strip_prefix(PAIRING_QR_SCHEME)?
+		.split_once('/')?;
+
+	(!peer_id.is_empty() && !code.is_empty()).then(|| (peer_id.to_string(), normalize_code(code)))
+}
+
+/// normalize_code turns a code typed by the user into the form it's shown in, which is the words
+/// of the code in lowercase joined by dashes
+pub fn normalize_code(code: &str) -> String {
+	code.split(|c: char| c.is_whitespace() || c == '-')
+		.filter(|word| !word.is_empty())
+		.map(str::to_lowercase)
+		.collect::<Vec<_>>()
+		.join("-")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn qr_payload_round_trips() {
+		let code = PairingCode::new("a1b2c3", "apple-banana-cherry-date".into());
+
+		assert_eq!(
+			parse_qr_payload(&code.qr_payload),
+			Some(("a1b2c3".into(), "apple-banana-cherry-date".into()))
+		);
+		assert_eq!(parse_qr_payload("https://spacedrive.com"), None);
+		assert_eq!(parse_qr_payload("spacedrive-pair:a1b2c3/"), None);
+	}
+
+	#[test]
+	fn trust_levels_grant_less_as_they_go() {
+		assert!(PeerTrust::FullSync.can_sync() && PeerTrust::FullSync.can_read_files());
+		assert!(PeerTrust::MetadataOnly.can_browse());
+		assert!(!PeerTrust::MetadataOnly.can_read_files() && !PeerTrust::MetadataOnly.can_sync());
+		assert!(!PeerTrust::TransferOnly.can_browse());
+		assert_eq!(PeerTrust::from_stored(7), PeerTrust::TransferOnly);
+	}
+
+	#[test]
+	fn typed_codes_are_normalized() {
+		assert_eq!(
+			normalize_code(" Apple banana-CHERRY   date "),
+			"apple-banana-cherry-date"
+		);
+	}
+}
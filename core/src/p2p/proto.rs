@@ -0,0 +1,61 @@
+//! Every stream between nodes starts with a [`StreamHeader`] saying what it's for. The protocols then
+//! exchange messages framed by [`write_message`], and send file contents as raw bytes after them.
+
+use std::io;
+
+use sd_p2p::quinn::{RecvStream, SendStream};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{remote::RemoteRequest, spacedrop::SpacedropRequest};
+
+/// Largest message accepted, file contents aren't sent as messages
+const MAX_MESSAGE_SIZE: u32 = 16 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ProtoError {
+	#[error("I/O error: {0}")]
+	Io(#[from] io::Error),
+	#[error("Failed to encode message: {0}")]
+	Encode(#[from] rmp_serde::encode::Error),
+	#[error("Failed to decode message: {0}")]
+	Decode(#[from] rmp_serde::decode::Error),
+	#[error("Message of {0} bytes is too large")]
+	MessageTooLarge(u32),
+}
+
+/// The first message on every stream between nodes, saying what the stream is for
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum StreamHeader {
+	Spacedrop(SpacedropRequest),
+	Remote(RemoteRequest),
+}
+
+/// Messages are MessagePack prefixed with their length, so they can be told apart from the file
+/// contents following them on the stream.
+///
+/// quinn's streams have inherent `read` and `write_all` methods returning its own errors, which take
+/// precedence over tokio's, so the tokio ones are called explicitly wherever the streams are used.
+pub(super) async fn write_message<T: Serialize>(
+	tx: &mut SendStream,
+	value: &T,
+) -> Result<(), ProtoError> {
+	let data = rmp_serde::to_vec_named(value)?;
+	tx.write_u32(data.len() as u32).await?;
+	AsyncWriteExt::write_all(tx, &data).await?;
+	Ok(())
+}
+
+pub(super) async fn read_message<T: DeserializeOwned>(
+	rx: &mut RecvStream,
+) -> Result<T, ProtoError> {
+	let len = rx.read_u32().await?;
+	if len > MAX_MESSAGE_SIZE {
+		return Err(ProtoError::MessageTooLarge(len));
+	}
+
+	let mut data = vec![0; len as usize];
+	AsyncReadExt::read_exact(rx, &mut data).await?;
+	Ok(rmp_serde::from_slice(&data)?)
+}
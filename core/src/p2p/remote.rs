@@ -0,0 +1,506 @@
+//! Lets paired peers browse, search and read the locations this node shares with them. Each location
+//! is shared with specific peers, and every request is checked against those shares and the peer's
+//! [`PeerTrust`] in the library, so a peer only ever sees the locations it was given, and only as
+//! much of them as it's trusted with.
+
+use std::{
+	io::SeekFrom,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use rspc::Type;
+use sd_p2p::quinn::{RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	fs::{self, File, OpenOptions},
+	io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::{
+	job::{TransferGovernor, TransferProvider},
+	library::{LibraryContext, LibraryManager},
+	prisma::{file_path, location, location_share, paired_peer},
+	util::os_path::resolve_materialized_path,
+};
+
+use super::{
+	clone::{cloneable_libraries, paired_library, send_clone, sync_batch, RemoteLibrary},
+	proto::{read_message, write_message},
+	spacedrop::{part_path, BLOCK_SIZE},
+	P2PError, PeerTrust,
+};
+
+/// Most file paths returned by a remote search
+const REMOTE_SEARCH_LIMIT: i64 = 100;
+
+/// A location another node shares with this one
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct RemoteLocation {
+	pub library_id: Uuid,
+	pub library_name: String,
+	pub location_id: i32,
+	pub name: Option<String>,
+}
+
+/// A file path in a location shared by another node
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct RemoteFilePath {
+	pub library_id: Uuid,
+	pub location_id: i32,
+	pub id: i32,
+	pub materialized_path: String,
+	pub name: String,
+	pub extension: Option<String>,
+	pub is_dir: bool,
+	pub size: Option<u64>,
+	pub date_modified: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum RemoteRequest {
+	Locations,
+	/// the entries of the directory at `path` in a location, its root if empty
+	Browse {
+		library_id: Uuid,
+		location_id: i32,
+		path: String,
+	},
+	/// file paths whose name contains `query`, in every shared location
+	Search {
+		query: String,
+	},
+	/// the contents of a file, starting at `offset`
+	File {
+		library_id: Uuid,
+		location_id: i32,
+		file_path_id: i32,
+		offset: u64,
+	},
+	/// the libraries this node can be set up with
+	Libraries,
+	/// an export of a library, to set this node up with it
+	Clone {
+		library_id: Uuid,
+	},
+	/// the operations of a library this node is missing, going by its clocks
+	SyncOperations {
+		library_id: Uuid,
+		clocks: String,
+	},
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum RemoteResponse {
+	Locations(Vec<RemoteLocation>),
+	FilePaths(Vec<RemoteFilePath>),
+	Libraries(Vec<RemoteLibrary>),
+	/// a sync batch, as JSON like the sync API sends it, compressed and base64 encoded
+	SyncBatch(String),
+	/// followed by the contents of the file from the requested offset, `size` is the full size
+	File {
+		size: u64,
+	},
+	Error(String),
+}
+
+fn remote_file_path(library_id: Uuid, file_path: file_path::Data) -> RemoteFilePath {
+	let size = file_path
+		.object()
+		.ok()
+		.flatten()
+		.and_then(|object| object.size_in_bytes.parse().ok());
+
+	RemoteFilePath {
+		library_id,
+		location_id: file_path.location_id,
+		id: file_path.id,
+		materialized_path: file_path.materialized_path,
+		name: file_path.name,
+		extension: file_path.extension,
+		is_dir: file_path.is_dir,
+		size,
+		date_modified: file_path.date_modified.into(),
+	}
+}
+
+/// peer_trust returns how much `peer_id` is trusted with in a library, if it's paired in it
+pub(super) async fn peer_trust(
+	library: &LibraryContext,
+	peer_id: &str,
+) -> Result<Option<PeerTrust>, prisma_client_rust::QueryError> {
+	Ok(library
+		.db
+		.paired_peer()
+		.find_unique(paired_peer::peer_id::equals(peer_id.to_string()))
+		.exec()
+		.await?
+		.map(|peer| PeerTrust::from_stored(peer.trust)))
+}
+
+/// shared_locations returns the locations of a library shared with `peer_id`, none if it isn't trusted
+/// to browse them
+async fn shared_locations(
+	library: &LibraryContext,
+	peer_id: &str,
+) -> Result<Vec<location::Data>, P2PError> {
+	if !peer_trust(library, peer_id)
+		.await?
+		.map_or(false, PeerTrust::can_browse)
+	{
+		return Ok(vec![]);
+	}
+
+	let location_ids = library
+		.db
+		.location_share()
+		.find_many(vec![location_share::peer_id::equals(peer_id.to_string())])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|share| share.location_id)
+		.collect();
+
+	Ok(library
+		.db
+		.location()
+		.find_many(vec![location::id::in_vec(location_ids)])
+		.exec()
+		.await?)
+}
+
+/// shared_location returns a location if it's shared with `peer_id` and the peer's trust in its library
+/// is `allowed`, and the library it's in
+async fn shared_location(
+	library_manager: &LibraryManager,
+	peer_id: &str,
+	library_id: Uuid,
+	location_id: i32,
+	allowed: fn(PeerTrust) -> bool,
+) -> Result<(LibraryContext, location::Data), String> {
+	let not_shared = || format!("location {location_id} isn't shared with this node");
+
+	let library = library_manager
+		.get_ctx(library_id)
+		.await
+		.ok_or_else(not_shared)?;
+
+	let trust = peer_trust(&library, peer_id)
+		.await
+		.map_err(|e| e.to_string())?
+		.ok_or_else(not_shared)?;
+	if !allowed(trust) {
+		return Err(format!(
+			"this node is trusted with {trust:?} access, which doesn't allow this"
+		));
+	}
+
+	let share = library
+		.db
+		.location_share()
+		.find_unique(location_share::location_id_peer_id(
+			location_id,
+			peer_id.to_string(),
+		))
+		.with(location_share::location::fetch())
+		.exec()
+		.await
+		.map_err(|e| e.to_string())?
+		.ok_or_else(not_shared)?;
+
+	let location = share.location().map_err(|e| e.to_string())?.clone();
+	Ok((library, location))
+}
+
+/// serve answers a request from a paired peer, writing the response on `tx`
+pub(super) async fn serve(
+	library_manager: &LibraryManager,
+	transfers: &TransferGovernor,
+	peer_id: &str,
+	request: RemoteRequest,
+	tx: &mut SendStream,
+) -> Result<(), P2PError> {
+	let response = match request {
+		RemoteRequest::Locations => {
+			let mut locations = vec![];
+			for library in library_manager.get_all_libraries_ctx().await {
+				for location in shared_locations(&library, peer_id).await? {
+					locations.push(RemoteLocation {
+						library_id: library.id,
+						library_name: library.config.name.clone(),
+						location_id: location.id,
+						name: location.name,
+					});
+				}
+			}
+
+			RemoteResponse::Locations(locations)
+		}
+		RemoteRequest::Browse {
+			library_id,
+			location_id,
+			path,
+		} => match shared_location(
+			library_manager,
+			peer_id,
+			library_id,
+			location_id,
+			PeerTrust::can_browse,
+		)
+		.await
+		{
+			Ok((library, _)) => RemoteResponse::FilePaths(
+				browse(&library, location_id, path)
+					.await?
+					.into_iter()
+					.map(|file_path| remote_file_path(library_id, file_path))
+					.collect(),
+			),
+			Err(e) => RemoteResponse::Error(e),
+		},
+		RemoteRequest::Search { query } => {
+			let mut file_paths = vec![];
+			for library in library_manager.get_all_libraries_ctx().await {
+				let location_ids = shared_locations(&library, peer_id)
+					.await?
+					.into_iter()
+					.map(|location| location.id)
+					.collect::<Vec<_>>();
+				if location_ids.is_empty() {
+					continue;
+				}
+
+				file_paths.extend(
+					library
+						.db
+						.file_path()
+						.find_many(vec![
+							file_path::location_id::in_vec(location_ids),
+							file_path::name::contains(query.clone()),
+						])
+						.with(file_path::object::fetch())
+						.take(REMOTE_SEARCH_LIMIT)
+						.exec()
+						.await?
+						.into_iter()
+						.map(|file_path| remote_file_path(library.id, file_path)),
+				);
+			}
+			file_paths.truncate(REMOTE_SEARCH_LIMIT as usize);
+
+			RemoteResponse::FilePaths(file_paths)
+		}
+		RemoteRequest::File {
+			library_id,
+			location_id,
+			file_path_id,
+			offset,
+		} => match shared_location(
+			library_manager,
+			peer_id,
+			library_id,
+			location_id,
+			PeerTrust::can_read_files,
+		)
+		.await
+		{
+			Ok((library, location)) => {
+				return send_file(&library, transfers, &location, file_path_id, offset, tx).await
+			}
+			Err(e) => RemoteResponse::Error(e),
+		},
+		RemoteRequest::Libraries => {
+			RemoteResponse::Libraries(cloneable_libraries(library_manager, peer_id).await?)
+		}
+		RemoteRequest::Clone { library_id } => {
+			match paired_library(library_manager, peer_id, library_id).await {
+				Ok(library) => return send_clone(&library, tx).await,
+				Err(e) => RemoteResponse::Error(e),
+			}
+		}
+		RemoteRequest::SyncOperations { library_id, clocks } => {
+			match paired_library(library_manager, peer_id, library_id).await {
+				Ok(library) => match sync_batch(&library, &clocks).await {
+					Ok(batch) => RemoteResponse::SyncBatch(batch),
+					Err(e) => RemoteResponse::Error(e.to_string()),
+				},
+				Err(e) => RemoteResponse::Error(e),
+			}
+		}
+	};
+
+	write_message(tx, &response).await?;
+	Ok(())
+}
+
+/// browse returns the entries of the directory at `path` in a location
+async fn browse(
+	library: &LibraryContext,
+	location_id: i32,
+	path: String,
+) -> Result<Vec<file_path::Data>, P2PError> {
+	let dir = library
+		.db
+		.file_path()
+		.find_first(vec![
+			file_path::location_id::equals(location_id),
+			file_path::materialized_path::equals(path),
+			file_path::is_dir::equals(true),
+		])
+		.exec()
+		.await?;
+
+	Ok(match dir {
+		Some(dir) => {
+			library
+				.db
+				.file_path()
+				.find_many(vec![
+					file_path::location_id::equals(location_id),
+					file_path::parent_id::equals(Some(dir.id)),
+				])
+				.with(file_path::object::fetch())
+				.exec()
+				.await?
+		}
+		None => vec![],
+	})
+}
+
+/// send_file streams a file of a shared location from `offset`
+async fn send_file(
+	library: &LibraryContext,
+	transfers: &TransferGovernor,
+	location: &location::Data,
+	file_path_id: i32,
+	offset: u64,
+	tx: &mut SendStream,
+) -> Result<(), P2PError> {
+	let file_path = library
+		.db
+		.file_path()
+		.find_unique(file_path::location_id_id(location.id, file_path_id))
+		.exec()
+		.await?;
+
+	let (file_path, location_path) = match (file_path, &location.local_path) {
+		(Some(file_path), Some(location_path)) if !file_path.is_dir => (file_path, location_path),
+		_ => {
+			write_message(tx, &RemoteResponse::Error("file not found".into())).await?;
+			return Ok(());
+		}
+	};
+
+	let path = resolve_materialized_path(
+		location_path,
+		&file_path.materialized_path,
+		file_path.raw_path.as_deref(),
+	);
+	let mut file = match File::open(&path).await {
+		Ok(file) => file,
+		Err(e) => {
+			write_message(tx, &RemoteResponse::Error(e.to_string())).await?;
+			return Err(e.into());
+		}
+	};
+
+	let size = file.metadata().await?.len();
+	let offset = offset.min(size);
+	file.seek(SeekFrom::Start(offset)).await?;
+
+	write_message(tx, &RemoteResponse::File { size }).await?;
+
+	// the peer's user is waiting on it, so it doesn't wait for the transfer window
+	let _permit = transfers.transfer_now(TransferProvider::P2P).await;
+	let mut buffer = vec![0; BLOCK_SIZE];
+	let mut remaining = size - offset;
+	while remaining > 0 {
+		let len = (remaining as usize).min(BLOCK_SIZE);
+		file.read_exact(&mut buffer[..len]).await?;
+		AsyncWriteExt::write_all(tx, &buffer[..len]).await?;
+		remaining -= len as u64;
+		transfers.throttle(TransferProvider::P2P, len as u64).await;
+	}
+
+	Ok(())
+}
+
+/// request sends a request to a peer on a new stream and reads its response
+pub(super) async fn request(
+	(tx, rx): &mut (SendStream, RecvStream),
+	request: RemoteRequest,
+) -> Result<RemoteResponse, P2PError> {
+	write_message(tx, &super::proto::StreamHeader::Remote(request)).await?;
+
+	match read_message(rx).await? {
+		RemoteResponse::Error(e) => Err(P2PError::Remote(e)),
+		response => Ok(response),
+	}
+}
+
+/// fetch_file downloads a file from a peer to `target`. Downloads that are cut off are kept next to
+/// `target` and resumed by the next fetch of the same file.
+pub(super) async fn fetch_file(
+	stream: &mut (SendStream, RecvStream),
+	transfers: &TransferGovernor,
+	library_id: Uuid,
+	location_id: i32,
+	file_path_id: i32,
+	target: &Path,
+) -> Result<PathBuf, P2PError> {
+	let part = part_path(target);
+	let offset = match fs::metadata(&part).await {
+		Ok(metadata) => metadata.len(),
+		Err(_) => 0,
+	};
+
+	let size = match request(
+		stream,
+		RemoteRequest::File {
+			library_id,
+			location_id,
+			file_path_id,
+			offset,
+		},
+	)
+	.await?
+	{
+		RemoteResponse::File { size } => size,
+		_ => return Err(P2PError::Remote("unexpected response".into())),
+	};
+
+	// the file changed since the download started, so it starts over
+	if offset > size {
+		fs::remove_file(&part).await?;
+		return Err(P2PError::Remote(
+			"the file changed on the peer, fetch it again".into(),
+		));
+	}
+
+	let mut writer = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(&part)
+		.await?;
+	let (_, rx) = stream;
+	let _permit = transfers.transfer_now(TransferProvider::P2P).await;
+	let mut buffer = vec![0; BLOCK_SIZE];
+	let mut remaining = size - offset;
+	while remaining > 0 {
+		let len = (remaining as usize).min(BLOCK_SIZE);
+		let read = AsyncReadExt::read(rx, &mut buffer[..len]).await?;
+		if read == 0 {
+			return Err(P2PError::Remote("the peer closed the stream".into()));
+		}
+
+		writer.write_all(&buffer[..read]).await?;
+		remaining -= read as u64;
+		transfers.throttle(TransferProvider::P2P, read as u64).await;
+	}
+
+	writer.sync_all().await?;
+	fs::rename(&part, target).await?;
+
+	Ok(target.to_path_buf())
+}
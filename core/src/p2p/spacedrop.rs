@@ -0,0 +1,357 @@
+//! Spacedrop sends files straight to a paired peer over a QUIC stream, which is encrypted with the
+//! peers' identities like every connection between nodes.
+//!
+//! The sender opens a stream and describes the files, the receiver prompts the user and answers with
+//! how much of each file it already has, and the sender streams the rest of each file in order.
+//! Files are received into `.sdpart` files next to where they're going, so a transfer that's cut off
+//! picks up where it left off when the same files are sent to the same directory again.
+
+use std::{
+	io::{self, SeekFrom},
+	path::{Path, PathBuf},
+	time::{Duration, Instant},
+};
+
+use rspc::Type;
+use sd_p2p::quinn::{RecvStream, SendStream, WriteError};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+	fs::{self, File, OpenOptions},
+	io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::{
+	job::{TransferGovernor, TransferProvider},
+	util::conflict::ConflictNaming,
+	volume::{ensure_space, InsufficientSpace},
+};
+
+use super::{
+	proto::{read_message, ProtoError},
+	P2PEvent,
+};
+
+/// How long the receiver has to accept a Spacedrop before it's turned down
+pub(super) const SPACEDROP_REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+pub(super) const BLOCK_SIZE: usize = 64 * 1024;
+/// How often progress is reported while a file is being transferred
+pub(super) const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+const PART_EXTENSION: &str = "sdpart";
+
+#[derive(Error, Debug)]
+pub enum SpacedropError {
+	#[error("I/O error: {0}")]
+	Io(#[from] io::Error),
+	#[error("{0}")]
+	Proto(#[from] ProtoError),
+	#[error("Failed to finish sending: {0}")]
+	Finish(#[from] WriteError),
+	#[error("The peer turned down the Spacedrop: {0}")]
+	Rejected(String),
+	#[error("{0}")]
+	InsufficientSpace(#[from] InsufficientSpace),
+	#[error("Invalid file name '{0}'")]
+	InvalidFileName(String),
+}
+
+/// A file offered in a Spacedrop
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct SpacedropFile {
+	pub name: String,
+	pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct SpacedropRequest {
+	pub id: Uuid,
+	pub files: Vec<SpacedropFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum SpacedropResponse {
+	/// how many bytes of each file the receiver already has, which the sender skips
+	Accepted {
+		offsets: Vec<u64>,
+	},
+	Rejected {
+		reason: String,
+	},
+}
+
+/// Reports the progress of a transfer, at most every [`PROGRESS_INTERVAL`]
+struct Progress<F> {
+	id: Uuid,
+	transferred: u64,
+	total: u64,
+	last_report: Instant,
+	emit: F,
+}
+
+impl<F: Fn(P2PEvent)> Progress<F> {
+	fn new(id: Uuid, total: u64, emit: F) -> Self {
+		Self {
+			id,
+			transferred: 0,
+			total,
+			last_report: Instant::now(),
+			emit,
+		}
+	}
+
+	fn advance(&mut self, bytes: u64) {
+		self.transferred += bytes;
+		if self.last_report.elapsed() >= PROGRESS_INTERVAL || self.transferred == self.total {
+			self.last_report = Instant::now();
+			(self.emit)(P2PEvent::SpacedropProgress {
+				id: self.id,
+				transferred: self.transferred,
+				total: self.total,
+			});
+		}
+	}
+}
+
+/// spacedrop_files describes the files at `paths` for a Spacedrop request
+pub(super) async fn spacedrop_files(
+	paths: &[PathBuf],
+) -> Result<Vec<SpacedropFile>, SpacedropError> {
+	let mut files = Vec::with_capacity(paths.len());
+	for path in paths {
+		let name = path
+			.file_name()
+			.and_then(|name| name.to_str())
+			.ok_or_else(|| SpacedropError::InvalidFileName(path.display().to_string()))?;
+
+		files.push(SpacedropFile {
+			name: name.to_string(),
+			size: fs::metadata(path).await?.len(),
+		});
+	}
+
+	Ok(files)
+}
+
+/// send streams `paths` to the peer once it has accepted the request, which was already written to `tx`
+pub(super) async fn send(
+	id: Uuid,
+	(mut tx, mut rx): (SendStream, RecvStream),
+	paths: Vec<PathBuf>,
+	files: Vec<SpacedropFile>,
+	transfers: &TransferGovernor,
+	emit: impl Fn(P2PEvent),
+) -> Result<(), SpacedropError> {
+	let offsets = match read_message(&mut rx).await? {
+		SpacedropResponse::Accepted { offsets } => offsets,
+		SpacedropResponse::Rejected { reason } => return Err(SpacedropError::Rejected(reason)),
+	};
+
+	let total = files.iter().map(|file| file.size).sum();
+	let mut progress = Progress::new(id, total, emit);
+
+	// the user is waiting on it, so it doesn't wait for the transfer window
+	let _permit = transfers.transfer_now(TransferProvider::P2P).await;
+	let mut buffer = vec![0; BLOCK_SIZE];
+	for ((path, file), offset) in paths.iter().zip(&files).zip(offsets) {
+		let offset = offset.min(file.size);
+		progress.advance(offset);
+
+		let mut reader = File::open(path).await?;
+		reader.seek(SeekFrom::Start(offset)).await?;
+
+		let mut remaining = file.size - offset;
+		while remaining > 0 {
+			let len = (remaining as usize).min(BLOCK_SIZE);
+			reader.read_exact(&mut buffer[..len]).await?;
+			AsyncWriteExt::write_all(&mut tx, &buffer[..len]).await?;
+			remaining -= len as u64;
+			progress.advance(len as u64);
+			transfers.throttle(TransferProvider::P2P, len as u64).await;
+		}
+	}
+
+	// waits for the peer to have received everything
+	tx.finish().await?;
+
+	Ok(())
+}
+
+/// part_path is where a file is written to while it's being received
+pub(super) fn part_path(target: &Path) -> PathBuf {
+	let mut part = target.as_os_str().to_owned();
+	part.push(".");
+	part.push(PART_EXTENSION);
+	PathBuf::from(part)
+}
+
+/// prepare_receive works out where the files of a Spacedrop go in `target_dir` and how much of each
+/// was already received by an earlier attempt. Files that would replace something else get a conflict
+/// copy name, with the name of the `sender`.
+pub(super) async fn prepare_receive(
+	target_dir: &Path,
+	files: &[SpacedropFile],
+	naming: &ConflictNaming,
+	sender: &str,
+) -> Result<(Vec<PathBuf>, Vec<u64>), SpacedropError> {
+	let mut targets = Vec::with_capacity(files.len());
+	let mut offsets = Vec::with_capacity(files.len());
+
+	for file in files {
+		// names come from the peer, they can't point outside of the target directory
+		let name = Path::new(&file.name)
+			.file_name()
+			.filter(|name| name.to_str() == Some(file.name.as_str()))
+			.ok_or_else(|| SpacedropError::InvalidFileName(file.name.clone()))?;
+
+		let mut prepared = None;
+		for target in naming.candidates(target_dir.join(name), sender) {
+			let offset = match fs::metadata(&target).await {
+				// received in full by an earlier attempt
+				Ok(metadata) if metadata.len() == file.size => file.size,
+				Ok(_) => continue,
+				Err(_) => match fs::metadata(part_path(&target)).await {
+					Ok(metadata) if metadata.len() <= file.size => metadata.len(),
+					Ok(_) => {
+						fs::remove_file(part_path(&target)).await?;
+						0
+					}
+					Err(_) => 0,
+				},
+			};
+
+			prepared = Some((target, offset));
+			break;
+		}
+
+		let (target, offset) = prepared.expect("copies are numbered, there's always a free name");
+		targets.push(target);
+		offsets.push(offset);
+	}
+
+	let remaining = files
+		.iter()
+		.zip(&offsets)
+		.map(|(file, offset)| file.size - offset)
+		.sum();
+	fs::create_dir_all(target_dir).await?;
+	ensure_space(target_dir, remaining)?;
+
+	Ok((targets, offsets))
+}
+
+/// receive writes the files sent after accepting a Spacedrop into `targets`
+pub(super) async fn receive(
+	id: Uuid,
+	rx: &mut RecvStream,
+	files: &[SpacedropFile],
+	targets: &[PathBuf],
+	offsets: &[u64],
+	transfers: &TransferGovernor,
+	emit: impl Fn(P2PEvent),
+) -> Result<(), SpacedropError> {
+	let total = files.iter().map(|file| file.size).sum();
+	let mut progress = Progress::new(id, total, emit);
+
+	let _permit = transfers.transfer_now(TransferProvider::P2P).await;
+	let mut buffer = vec![0; BLOCK_SIZE];
+	for ((file, target), offset) in files.iter().zip(targets).zip(offsets) {
+		progress.advance(*offset);
+		if *offset == file.size && target.exists() {
+			continue;
+		}
+
+		let part = part_path(target);
+		let mut writer = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&part)
+			.await?;
+
+		let mut remaining = file.size - offset;
+		while remaining > 0 {
+			let len = (remaining as usize).min(BLOCK_SIZE);
+			let read = AsyncReadExt::read(rx, &mut buffer[..len]).await?;
+			if read == 0 {
+				return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+			}
+
+			writer.write_all(&buffer[..read]).await?;
+			remaining -= read as u64;
+			progress.advance(read as u64);
+			transfers.throttle(TransferProvider::P2P, read as u64).await;
+		}
+
+		writer.sync_all().await?;
+		fs::rename(&part, target).await?;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn resumes_from_partial_files() {
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::write(dir.path().join("done.txt"), b"hello").unwrap();
+		std::fs::write(dir.path().join("half.txt.sdpart"), b"he").unwrap();
+
+		let files = [
+			SpacedropFile {
+				name: "done.txt".into(),
+				size: 5,
+			},
+			SpacedropFile {
+				name: "half.txt".into(),
+				size: 5,
+			},
+			SpacedropFile {
+				name: "new.txt".into(),
+				size: 5,
+			},
+		];
+
+		let (targets, offsets) =
+			prepare_receive(dir.path(), &files, &ConflictNaming::default(), "Laptop")
+				.await
+				.unwrap();
+		assert_eq!(targets[1], dir.path().join("half.txt"));
+		assert_eq!(offsets, vec![5, 2, 0]);
+	}
+
+	#[tokio::test]
+	async fn receives_next_to_different_files() {
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::write(dir.path().join("notes.txt"), b"mine").unwrap();
+
+		let files = [SpacedropFile {
+			name: "notes.txt".into(),
+			size: 5,
+		}];
+
+		let (targets, _) =
+			prepare_receive(dir.path(), &files, &ConflictNaming::default(), "Laptop")
+				.await
+				.unwrap();
+		let name = targets[0].file_name().unwrap().to_string_lossy();
+		assert!(name.starts_with("notes (conflicted copy from Laptop "));
+		assert!(name.ends_with(").txt"));
+	}
+
+	#[tokio::test]
+	async fn rejects_names_outside_of_target() {
+		let dir = tempfile::tempdir().unwrap();
+		let files = [SpacedropFile {
+			name: "../escape.txt".into(),
+			size: 1,
+		}];
+
+		assert!(matches!(
+			prepare_receive(dir.path(), &files, &ConflictNaming::default(), "Laptop").await,
+			Err(SpacedropError::InvalidFileName(_))
+		));
+	}
+}
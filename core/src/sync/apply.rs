@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+
+use sd_sync::{
+	CRDTOperation, CRDTOperationType, OwnedOperation, OwnedOperationData, RelationOperation,
+	RelationOperationData, SharedOperation, SharedOperationCreateData, SharedOperationData,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::prisma::{
+	album, file_path, indexer_rules_in_location, location, node, object, object_in_album, tag,
+	tag_on_object, PrismaClient,
+};
+
+use super::{models::*, SyncError};
+
+/// apply makes the change an operation from another node describes to this node's database
+pub(super) async fn apply(
+	db: &PrismaClient,
+	node_local_id: i32,
+	node_ids: &HashMap<Uuid, i32>,
+	op: &CRDTOperation,
+) -> Result<(), SyncError> {
+	match &op.typ {
+		CRDTOperationType::Shared(shared) => match shared.model.as_str() {
+			OBJECT => apply_object(db, shared).await,
+			TAG => apply_tag(db, shared).await,
+			ALBUM => apply_album(db, shared).await,
+			model => Err(SyncError::UnknownModel(model.to_string())),
+		},
+		CRDTOperationType::Relation(relation) => apply_relation(db, relation).await,
+		CRDTOperationType::Owned(owned) => match owned.model.as_str() {
+			LOCATION => apply_locations(db, node_local_id, node_ids, owned).await,
+			FILE_PATH => apply_file_paths(db, node_local_id, owned).await,
+			model => Err(SyncError::UnknownModel(model.to_string())),
+		},
+	}
+}
+
+fn from_map<T: DeserializeOwned>(data: &Map<String, Value>) -> Result<T, SyncError> {
+	Ok(serde_json::from_value(Value::Object(data.clone()))?)
+}
+
+fn from_value<T: DeserializeOwned>(value: &Value) -> Result<T, SyncError> {
+	Ok(serde_json::from_value(value.clone())?)
+}
+
+fn pub_id_equals(pub_id: Uuid) -> Vec<object::WhereParam> {
+	vec![object::pub_id::equals(Some(pub_id.as_bytes().to_vec()))]
+}
+
+async fn find_object(db: &PrismaClient, pub_id: Uuid) -> Result<Option<i32>, SyncError> {
+	Ok(db
+		.object()
+		.find_first(pub_id_equals(pub_id))
+		.exec()
+		.await?
+		.map(|object| object.id))
+}
+
+async fn apply_object(db: &PrismaClient, op: &SharedOperation) -> Result<(), SyncError> {
+	match &op.data {
+		SharedOperationData::Create(SharedOperationCreateData::Unique(data)) => {
+			let data: ObjectData = from_map(data)?;
+			if find_object(db, op.record_id).await?.is_some() {
+				return Ok(());
+			}
+
+			let pub_id = Some(op.record_id.as_bytes().to_vec());
+			// the same content was identified on this node without having a pub id yet
+			if let Some(existing) = db
+				.object()
+				.find_unique(object::cas_id::equals(data.cas_id.clone()))
+				.exec()
+				.await?
+			{
+				db.object()
+					.update(
+						object::id::equals(existing.id),
+						vec![object::pub_id::set(pub_id)],
+					)
+					.exec()
+					.await?;
+				return Ok(());
+			}
+
+			db.object()
+				.create(
+					data.cas_id,
+					data.size_in_bytes,
+					vec![
+						object::pub_id::set(pub_id),
+						object::kind::set(data.kind),
+						object::name::set(data.name),
+						object::extension::set(data.extension),
+						object::note::set(data.note),
+						object::hidden::set(data.hidden),
+						object::favorite::set(data.favorite),
+						object::important::set(data.important),
+						object::date_created::set(data.date_created),
+					],
+				)
+				.exec()
+				.await?;
+		}
+		SharedOperationData::Create(SharedOperationCreateData::Atomic) => {}
+		SharedOperationData::Update { field, value } => {
+			let param = match field.as_str() {
+				"name" => object::name::set(from_value(value)?),
+				"extension" => object::extension::set(from_value(value)?),
+				"kind" => object::kind::set(from_value(value)?),
+				"note" => object::note::set(from_value(value)?),
+				"hidden" => object::hidden::set(from_value(value)?),
+				"favorite" => object::favorite::set(from_value(value)?),
+				"important" => object::important::set(from_value(value)?),
+				_ => return Err(SyncError::UnknownField(op.model.clone(), field.clone())),
+			};
+
+			db.object()
+				.update_many(pub_id_equals(op.record_id), vec![param])
+				.exec()
+				.await?;
+		}
+		SharedOperationData::Delete => {
+			if let Some(object_id) = find_object(db, op.record_id).await? {
+				db.tag_on_object()
+					.delete_many(vec![tag_on_object::object_id::equals(object_id)])
+					.exec()
+					.await?;
+				db.object_in_album()
+					.delete_many(vec![object_in_album::object_id::equals(object_id)])
+					.exec()
+					.await?;
+				db.object()
+					.delete(object::id::equals(object_id))
+					.exec()
+					.await?;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+async fn apply_tag(db: &PrismaClient, op: &SharedOperation) -> Result<(), SyncError> {
+	let pub_id = op.record_id.as_bytes().to_vec();
+
+	match &op.data {
+		SharedOperationData::Create(SharedOperationCreateData::Unique(data)) => {
+			let data: TagData = from_map(data)?;
+			db.tag()
+				.upsert(
+					tag::pub_id::equals(pub_id.clone()),
+					(
+						pub_id,
+						vec![
+							tag::name::set(data.name),
+							tag::color::set(data.color),
+							tag::is_system::set(data.is_system),
+						],
+					),
+					vec![],
+				)
+				.exec()
+				.await?;
+		}
+		SharedOperationData::Create(SharedOperationCreateData::Atomic) => {}
+		SharedOperationData::Update { field, value } => {
+			let param = match field.as_str() {
+				"name" => tag::name::set(from_value(value)?),
+				"color" => tag::color::set(from_value(value)?),
+				"is_system" => tag::is_system::set(from_value(value)?),
+				_ => return Err(SyncError::UnknownField(op.model.clone(), field.clone())),
+			};
+
+			db.tag()
+				.update_many(vec![tag::pub_id::equals(pub_id)], vec![param])
+				.exec()
+				.await?;
+		}
+		SharedOperationData::Delete => {
+			if let Some(tag) = db
+				.tag()
+				.find_unique(tag::pub_id::equals(pub_id))
+				.exec()
+				.await?
+			{
+				db.tag_on_object()
+					.delete_many(vec![tag_on_object::tag_id::equals(tag.id)])
+					.exec()
+					.await?;
+				db.tag().delete(tag::id::equals(tag.id)).exec().await?;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+async fn apply_album(db: &PrismaClient, op: &SharedOperation) -> Result<(), SyncError> {
+	let pub_id = op.record_id.as_bytes().to_vec();
+
+	match &op.data {
+		SharedOperationData::Create(SharedOperationCreateData::Unique(data)) => {
+			let data: AlbumData = from_map(data)?;
+			db.album()
+				.upsert(
+					album::pub_id::equals(pub_id.clone()),
+					(
+						pub_id,
+						data.name,
+						vec![album::is_hidden::set(data.is_hidden)],
+					),
+					vec![],
+				)
+				.exec()
+				.await?;
+		}
+		SharedOperationData::Create(SharedOperationCreateData::Atomic) => {}
+		SharedOperationData::Update { field, value } => {
+			let param = match field.as_str() {
+				"name" => album::name::set(from_value(value)?),
+				"is_hidden" => album::is_hidden::set(from_value(value)?),
+				_ => return Err(SyncError::UnknownField(op.model.clone(), field.clone())),
+			};
+
+			db.album()
+				.update_many(vec![album::pub_id::equals(pub_id)], vec![param])
+				.exec()
+				.await?;
+		}
+		SharedOperationData::Delete => {
+			if let Some(album) = db
+				.album()
+				.find_unique(album::pub_id::equals(pub_id))
+				.exec()
+				.await?
+			{
+				db.object_in_album()
+					.delete_many(vec![object_in_album::album_id::equals(album.id)])
+					.exec()
+					.await?;
+				db.album()
+					.delete(album::id::equals(album.id))
+					.exec()
+					.await?;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+async fn apply_relation(db: &PrismaClient, op: &RelationOperation) -> Result<(), SyncError> {
+	let object_id = match find_object(db, op.relation_item).await? {
+		Some(object_id) => object_id,
+		// the object was deleted after being added
+		None => return Ok(()),
+	};
+	let group_pub_id = op.relation_group.as_bytes().to_vec();
+
+	match op.relation.as_str() {
+		TAG_ON_OBJECT => {
+			let tag_id = match db
+				.tag()
+				.find_unique(tag::pub_id::equals(group_pub_id))
+				.exec()
+				.await?
+			{
+				Some(tag) => tag.id,
+				None => return Ok(()),
+			};
+
+			match op.data {
+				RelationOperationData::Create => {
+					db.tag_on_object()
+						.upsert(
+							tag_on_object::tag_id_object_id(tag_id, object_id),
+							(
+								tag::id::equals(tag_id),
+								object::id::equals(object_id),
+								vec![],
+							),
+							vec![],
+						)
+						.exec()
+						.await?;
+				}
+				RelationOperationData::Update { .. } => {}
+				RelationOperationData::Delete => {
+					db.tag_on_object()
+						.delete_many(vec![
+							tag_on_object::tag_id::equals(tag_id),
+							tag_on_object::object_id::equals(object_id),
+						])
+						.exec()
+						.await?;
+				}
+			}
+		}
+		OBJECT_IN_ALBUM => {
+			let album_id = match db
+				.album()
+				.find_unique(album::pub_id::equals(group_pub_id))
+				.exec()
+				.await?
+			{
+				Some(album) => album.id,
+				None => return Ok(()),
+			};
+
+			match op.data {
+				RelationOperationData::Create => {
+					db.object_in_album()
+						.upsert(
+							object_in_album::album_id_object_id(album_id, object_id),
+							(
+								album::id::equals(album_id),
+								object::id::equals(object_id),
+								vec![],
+							),
+							vec![],
+						)
+						.exec()
+						.await?;
+				}
+				RelationOperationData::Update { .. } => {}
+				RelationOperationData::Delete => {
+					db.object_in_album()
+						.delete_many(vec![
+							object_in_album::album_id::equals(album_id),
+							object_in_album::object_id::equals(object_id),
+						])
+						.exec()
+						.await?;
+				}
+			}
+		}
+		relation => return Err(SyncError::UnknownModel(relation.to_string())),
+	}
+
+	Ok(())
+}
+
+async fn apply_locations(
+	db: &PrismaClient,
+	node_local_id: i32,
+	node_ids: &HashMap<Uuid, i32>,
+	op: &OwnedOperation,
+) -> Result<(), SyncError> {
+	for item in &op.items {
+		let pub_id = from_value::<Uuid>(&item.id)?.as_bytes().to_vec();
+		let existing = db
+			.location()
+			.find_unique(location::pub_id::equals(pub_id.clone()))
+			.exec()
+			.await?;
+
+		// this node is the one that decides what happens to its own locations
+		if matches!(&existing, Some(location) if location.node_id == node_local_id) {
+			warn!("Ignoring sync operation on a location owned by this node");
+			continue;
+		}
+
+		match &item.data {
+			OwnedOperationData::Create(data) => {
+				let data: LocationData = from_map(data)?;
+				let node_id = *node_ids
+					.get(&data.node)
+					.ok_or(SyncError::UnknownNode(data.node))?;
+				let params = vec![
+					location::name::set(data.name),
+					location::local_path::set(data.local_path),
+					location::is_archived::set(data.is_archived),
+					location::is_catalog::set(data.is_catalog),
+					location::date_created::set(data.date_created),
+				];
+
+				match existing {
+					Some(location) => {
+						db.location()
+							.update(location::id::equals(location.id), params)
+							.exec()
+							.await?;
+					}
+					None => {
+						db.location()
+							.create(pub_id, node::id::equals(node_id), params)
+							.exec()
+							.await?;
+					}
+				}
+			}
+			OwnedOperationData::Update(data) => {
+				let mut params = vec![];
+				for (field, value) in data {
+					params.push(match field.as_str() {
+						"name" => location::name::set(from_value(value)?),
+						"local_path" => location::local_path::set(from_value(value)?),
+						"is_archived" => location::is_archived::set(from_value(value)?),
+						"is_catalog" => location::is_catalog::set(from_value(value)?),
+						_ => return Err(SyncError::UnknownField(op.model.clone(), field.clone())),
+					});
+				}
+
+				db.location()
+					.update_many(vec![location::pub_id::equals(pub_id)], params)
+					.exec()
+					.await?;
+			}
+			OwnedOperationData::Delete => {
+				if let Some(location) = existing {
+					db.indexer_rules_in_location()
+						.delete_many(vec![indexer_rules_in_location::location_id::equals(
+							location.id,
+						)])
+						.exec()
+						.await?;
+					db.file_path()
+						.delete_many(vec![file_path::location_id::equals(location.id)])
+						.exec()
+						.await?;
+					db.location()
+						.delete(location::id::equals(location.id))
+						.exec()
+						.await?;
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+async fn apply_file_paths(
+	db: &PrismaClient,
+	node_local_id: i32,
+	op: &OwnedOperation,
+) -> Result<(), SyncError> {
+	let mut locations = HashMap::new();
+	let mut objects = HashMap::new();
+
+	for item in &op.items {
+		let id: FilePathId = from_value(&item.id)?;
+
+		let location = match locations.get(&id.location) {
+			Some(location) => *location,
+			None => {
+				let location = db
+					.location()
+					.find_unique(location::pub_id::equals(id.location.as_bytes().to_vec()))
+					.exec()
+					.await?
+					.map(|location| (location.id, location.node_id));
+				locations.insert(id.location, location);
+				location
+			}
+		};
+		let location_id = match location {
+			Some((_, node_id)) if node_id == node_local_id => {
+				warn!("Ignoring sync operation on a location owned by this node");
+				continue;
+			}
+			Some((location_id, _)) => location_id,
+			// the location was deleted
+			None => continue,
+		};
+
+		match &item.data {
+			OwnedOperationData::Create(data) => {
+				let data: FilePathData = from_map(data)?;
+				let object_id = resolve_object(db, &mut objects, data.object).await?;
+				let params = vec![
+					file_path::is_dir::set(data.is_dir),
+					file_path::extension::set(data.extension),
+					file_path::parent_id::set(data.parent_id),
+					file_path::object_id::set(object_id),
+					file_path::date_created::set(data.date_created),
+					file_path::date_modified::set(data.date_modified),
+				];
+
+				let exists = db
+					.file_path()
+					.find_unique(file_path::location_id_id(location_id, id.id))
+					.exec()
+					.await?
+					.is_some();
+
+				if exists {
+					let mut params = params;
+					params.extend([
+						file_path::materialized_path::set(data.materialized_path),
+						file_path::name::set(data.name),
+					]);
+					db.file_path()
+						.update(file_path::location_id_id(location_id, id.id), params)
+						.exec()
+						.await?;
+				} else {
+					db.file_path()
+						.create_many(vec![file_path::create_unchecked(
+							id.id,
+							location_id,
+							data.materialized_path,
+							data.name,
+							params,
+						)])
+						.exec()
+						.await?;
+				}
+			}
+			OwnedOperationData::Update(data) => {
+				let mut params = vec![];
+				for (field, value) in data {
+					params.push(match field.as_str() {
+						"object" => file_path::object_id::set(
+							resolve_object(db, &mut objects, from_value(value)?).await?,
+						),
+						"materialized_path" => {
+							file_path::materialized_path::set(from_value(value)?)
+						}
+						"name" => file_path::name::set(from_value(value)?),
+						"extension" => file_path::extension::set(from_value(value)?),
+						"is_dir" => file_path::is_dir::set(from_value(value)?),
+						"parent_id" => file_path::parent_id::set(from_value(value)?),
+						"date_modified" => file_path::date_modified::set(from_value(value)?),
+						_ => return Err(SyncError::UnknownField(op.model.clone(), field.clone())),
+					});
+				}
+
+				db.file_path()
+					.update_many(
+						vec![
+							file_path::location_id::equals(location_id),
+							file_path::id::equals(id.id),
+						],
+						params,
+					)
+					.exec()
+					.await?;
+			}
+			OwnedOperationData::Delete => {
+				db.file_path()
+					.delete_many(vec![
+						file_path::location_id::equals(location_id),
+						file_path::id::equals(id.id),
+					])
+					.exec()
+					.await?;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// resolve_object finds the local id of the object a file path was identified as
+async fn resolve_object(
+	db: &PrismaClient,
+	cache: &mut HashMap<Uuid, Option<i32>>,
+	pub_id: Option<Uuid>,
+) -> Result<Option<i32>, SyncError> {
+	let pub_id = match pub_id {
+		Some(pub_id) => pub_id,
+		None => return Ok(None),
+	};
+
+	if let Some(object_id) = cache.get(&pub_id) {
+		return Ok(*object_id);
+	}
+
+	let object_id = find_object(db, pub_id).await?;
+	cache.insert(pub_id, object_id);
+	Ok(object_id)
+}
@@ -0,0 +1,839 @@
+//! The sync engine replicates a library's metadata between the nodes it's on. Every change to a
+//! synced record is logged in the `sync_event` table as a CRDT operation, stamped with a hybrid
+//! logical clock. Nodes exchange the operations the other is missing and merge them:
+//!
+//! - shared records (objects, tags and albums) can be changed on any node, the latest change to
+//!   each field wins
+//! - relations (tags and albums on objects) are created or deleted, whichever happened last wins
+//! - owned records (locations and their file paths) are only ever changed by the node the location
+//!   is on, so their operations are applied in order
+//!
+//! Batches of operations are encoded as JSON that's opaque to clients, which only carry them
+//! between nodes.
+
+use std::{
+	cmp::Ordering,
+	collections::{HashMap, HashSet},
+	sync::Arc,
+};
+
+use prisma_client_rust::{prisma_models::PrismaValue, Direction};
+use rspc::ErrorCode;
+use sd_sync::{
+	CRDTOperation, CRDTOperationType, OwnedOperation, OwnedOperationData, OwnedOperationItem,
+	RelationOperation, RelationOperationData, SharedOperation, SharedOperationCreateData,
+	SharedOperationData,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+use tracing::{debug, info};
+use uhlc::{HLCBuilder, Timestamp, HLC, NTP64};
+use uuid::Uuid;
+
+use crate::{
+	object::cas::bulk_update,
+	prisma::{
+		file_path, location, node, object, object_in_album, sync_event, tag_on_object, PrismaClient,
+	},
+};
+
+mod apply;
+pub mod models;
+
+use models::*;
+
+/// How many operations are sent in a batch, the rest follow in the next one
+pub const SYNC_BATCH_SIZE: i64 = 1000;
+/// How many file paths are in each operation when seeding a location
+const SEED_CHUNK_SIZE: usize = 500;
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+	#[error("Database error: {0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+	#[error("Failed to encode or decode sync data: {0}")]
+	Serialization(#[from] serde_json::Error),
+	#[error("I/O error: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Operation from unknown node {0}")]
+	UnknownNode(Uuid),
+	#[error("Unknown sync model '{0}'")]
+	UnknownModel(String),
+	#[error("Unknown field '{1}' on sync model '{0}'")]
+	UnknownField(String, String),
+}
+
+impl From<SyncError> for rspc::Error {
+	fn from(err: SyncError) -> Self {
+		match err {
+			SyncError::Serialization(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OperationKind {
+	Create = 0,
+	Update = 1,
+	Delete = 2,
+}
+
+/// A node the operations in a batch came from, so the receiving node can add it to the library
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyncNode {
+	pub id: Uuid,
+	pub name: String,
+	pub platform: i32,
+}
+
+/// The operations one node is missing from another, oldest first
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyncBatch {
+	pub nodes: Vec<SyncNode>,
+	pub operations: Vec<CRDTOperation>,
+	/// there are more operations to fetch, starting from the clocks after this batch is ingested
+	pub has_more: bool,
+}
+
+/// What a batch brought to the node ingesting it
+#[derive(Debug, Default)]
+pub struct Ingested {
+	pub operations: usize,
+	/// pub ids of the objects the batch created, oldest first
+	pub objects: Vec<Uuid>,
+}
+
+/// SyncManager records the library's changes as operations and merges the operations of other nodes
+pub struct SyncManager {
+	db: Arc<PrismaClient>,
+	node: Uuid,
+	node_local_id: i32,
+	clock: HLC,
+}
+
+impl SyncManager {
+	pub fn new(db: Arc<PrismaClient>, node: Uuid, node_local_id: i32) -> Self {
+		Self {
+			db,
+			node,
+			node_local_id,
+			clock: HLCBuilder::new().with_id(node.into()).build(),
+		}
+	}
+
+	fn new_op(&self, typ: CRDTOperationType) -> CRDTOperation {
+		CRDTOperation {
+			node: self.node,
+			timestamp: *self.clock.new_timestamp().get_time(),
+			id: Uuid::new_v4(),
+			typ,
+		}
+	}
+
+	pub fn shared_create(
+		&self,
+		model: &str,
+		record_id: Uuid,
+		data: &impl Serialize,
+	) -> CRDTOperation {
+		self.new_op(CRDTOperationType::Shared(SharedOperation {
+			record_id,
+			model: model.to_string(),
+			data: SharedOperationData::Create(SharedOperationCreateData::Unique(to_map(data))),
+		}))
+	}
+
+	pub fn shared_update(
+		&self,
+		model: &str,
+		record_id: Uuid,
+		field: &str,
+		value: impl Serialize,
+	) -> CRDTOperation {
+		self.new_op(CRDTOperationType::Shared(SharedOperation {
+			record_id,
+			model: model.to_string(),
+			data: SharedOperationData::Update {
+				field: field.to_string(),
+				value: serde_json::to_value(value).unwrap_or_default(),
+			},
+		}))
+	}
+
+	pub fn shared_delete(&self, model: &str, record_id: Uuid) -> CRDTOperation {
+		self.new_op(CRDTOperationType::Shared(SharedOperation {
+			record_id,
+			model: model.to_string(),
+			data: SharedOperationData::Delete,
+		}))
+	}
+
+	/// relation_create adds `item` (eg. an object) to `group` (eg. a tag)
+	pub fn relation_create(&self, relation: &str, item: Uuid, group: Uuid) -> CRDTOperation {
+		self.new_op(CRDTOperationType::Relation(RelationOperation {
+			relation_item: item,
+			relation_group: group,
+			relation: relation.to_string(),
+			data: RelationOperationData::Create,
+		}))
+	}
+
+	pub fn relation_delete(&self, relation: &str, item: Uuid, group: Uuid) -> CRDTOperation {
+		self.new_op(CRDTOperationType::Relation(RelationOperation {
+			relation_item: item,
+			relation_group: group,
+			relation: relation.to_string(),
+			data: RelationOperationData::Delete,
+		}))
+	}
+
+	/// owned_create creates records this node owns, all of them in the same location
+	pub fn owned_create<I: Serialize, T: Serialize>(
+		&self,
+		model: &str,
+		items: impl IntoIterator<Item = (I, T)>,
+	) -> CRDTOperation {
+		self.owned(
+			model,
+			items
+				.into_iter()
+				.map(|(id, data)| (id, OwnedOperationData::Create(to_map(&data)))),
+		)
+	}
+
+	pub fn owned_update<I: Serialize>(
+		&self,
+		model: &str,
+		items: impl IntoIterator<Item = (I, Map<String, Value>)>,
+	) -> CRDTOperation {
+		self.owned(
+			model,
+			items
+				.into_iter()
+				.map(|(id, data)| (id, OwnedOperationData::Update(data))),
+		)
+	}
+
+	pub fn owned_delete<I: Serialize>(
+		&self,
+		model: &str,
+		ids: impl IntoIterator<Item = I>,
+	) -> CRDTOperation {
+		self.owned(
+			model,
+			ids.into_iter().map(|id| (id, OwnedOperationData::Delete)),
+		)
+	}
+
+	fn owned<I: Serialize>(
+		&self,
+		model: &str,
+		items: impl Iterator<Item = (I, OwnedOperationData)>,
+	) -> CRDTOperation {
+		self.new_op(CRDTOperationType::Owned(OwnedOperation {
+			model: model.to_string(),
+			items: items
+				.map(|(id, data)| OwnedOperationItem {
+					id: serde_json::to_value(id).unwrap_or_default(),
+					data,
+				})
+				.collect(),
+		}))
+	}
+
+	/// ensure_object_pub_id returns the pub id of the object, giving it one and logging its creation
+	/// if it doesn't have one yet
+	pub async fn ensure_object_pub_id(&self, object: &object::Data) -> Result<Uuid, SyncError> {
+		if let Some(pub_id) = &object.pub_id {
+			return Ok(uuid_from_pub_id(pub_id));
+		}
+
+		let pub_id = object_pub_id(&object.cas_id);
+		self.db
+			.object()
+			.update(
+				object::id::equals(object.id),
+				vec![object::pub_id::set(Some(pub_id.as_bytes().to_vec()))],
+			)
+			.exec()
+			.await?;
+		self.write_ops(vec![self.shared_create(
+			OBJECT,
+			pub_id,
+			&ObjectData::from(object),
+		)])
+		.await?;
+
+		Ok(pub_id)
+	}
+
+	/// ensure_object_pub_ids is [`SyncManager::ensure_object_pub_id`] for many objects at once, giving
+	/// pub ids with a few statements instead of one per object. Returns the pub ids by object id.
+	pub async fn ensure_object_pub_ids(
+		&self,
+		objects: &[object::Data],
+	) -> Result<HashMap<i32, Uuid>, SyncError> {
+		let mut pub_ids = HashMap::with_capacity(objects.len());
+		let mut missing = vec![];
+		for object in objects {
+			match &object.pub_id {
+				Some(pub_id) => {
+					pub_ids.insert(object.id, uuid_from_pub_id(pub_id));
+				}
+				None => {
+					let pub_id = object_pub_id(&object.cas_id);
+					pub_ids.insert(object.id, pub_id);
+					missing.push((object, pub_id));
+				}
+			}
+		}
+
+		if missing.is_empty() {
+			return Ok(pub_ids);
+		}
+
+		bulk_update(
+			&self.db,
+			"object",
+			"pub_id",
+			&[],
+			missing
+				.iter()
+				.map(|(object, pub_id)| (object.id, PrismaValue::Bytes(pub_id.as_bytes().to_vec())))
+				.collect(),
+		)
+		.await?;
+		self.write_ops(
+			missing
+				.iter()
+				.map(|(object, pub_id)| {
+					self.shared_create(OBJECT, *pub_id, &ObjectData::from(*object))
+				})
+				.collect(),
+		)
+		.await?;
+
+		Ok(pub_ids)
+	}
+
+	/// is_location_logged tells if the location is in the log. Until it is, changes to its file paths
+	/// aren't logged either, as seeding the location logs them as they are by then.
+	pub async fn is_location_logged(&self, pub_id: &[u8]) -> Result<bool, SyncError> {
+		Ok(self
+			.db
+			.sync_event()
+			.find_first(vec![
+				sync_event::model::equals(LOCATION.to_string()),
+				sync_event::record_id::equals(pub_id.to_vec()),
+			])
+			.exec()
+			.await?
+			.is_some())
+	}
+
+	/// write_ops logs operations for changes already made to this node's database
+	pub async fn write_ops(&self, ops: Vec<CRDTOperation>) -> Result<(), SyncError> {
+		self.log(self.node_local_id, &ops).await
+	}
+
+	async fn log(&self, node_id: i32, ops: &[CRDTOperation]) -> Result<(), SyncError> {
+		let ops = ops
+			.iter()
+			.filter(
+				|op| !matches!(&op.typ, CRDTOperationType::Owned(owned) if owned.items.is_empty()),
+			)
+			.collect::<Vec<_>>();
+		if ops.is_empty() {
+			return Ok(());
+		}
+
+		let mut events = Vec::with_capacity(ops.len());
+		for op in ops {
+			let (model, record_id, kind, column) = describe(op);
+			events.push(sync_event::create_unchecked(
+				op.id.as_bytes().to_vec(),
+				node_id,
+				op.timestamp.as_u64() as i64,
+				model,
+				record_id,
+				kind as i32,
+				serde_json::to_string(op)?,
+				vec![sync_event::column::set(column)],
+			));
+		}
+
+		self.db.sync_event().create_many(events).exec().await?;
+
+		Ok(())
+	}
+
+	/// clocks returns the timestamp of the latest operation this node has from each node, which
+	/// another node sends operations after
+	pub async fn clocks(&self) -> Result<HashMap<Uuid, NTP64>, SyncError> {
+		let mut clocks = HashMap::new();
+		for node in self.db.node().find_many(vec![]).exec().await? {
+			if let Some(latest) = self
+				.db
+				.sync_event()
+				.find_first(vec![sync_event::node_id::equals(node.id)])
+				.order_by(sync_event::timestamp::order(Direction::Desc))
+				.exec()
+				.await?
+			{
+				clocks.insert(
+					uuid_from_pub_id(&node.pub_id),
+					NTP64(latest.timestamp as u64),
+				);
+			}
+		}
+
+		Ok(clocks)
+	}
+
+	/// operations returns the operations a node with the given clocks is missing
+	pub async fn operations(
+		&self,
+		clocks: &HashMap<Uuid, NTP64>,
+		limit: i64,
+	) -> Result<SyncBatch, SyncError> {
+		// operations for records changed in ways the log didn't catch
+		self.seed().await?;
+
+		let mut nodes = vec![];
+		let mut events = vec![];
+		let mut has_more = false;
+
+		for node in self.db.node().find_many(vec![]).exec().await? {
+			let id = uuid_from_pub_id(&node.pub_id);
+			let mut params = vec![sync_event::node_id::equals(node.id)];
+			if let Some(clock) = clocks.get(&id) {
+				params.push(sync_event::timestamp::gt(clock.as_u64() as i64));
+			}
+
+			let node_events = self
+				.db
+				.sync_event()
+				.find_many(params)
+				.order_by(sync_event::timestamp::order(Direction::Asc))
+				.take(limit)
+				.exec()
+				.await?;
+
+			if node_events.is_empty() {
+				continue;
+			}
+			has_more |= node_events.len() as i64 == limit;
+
+			nodes.push(SyncNode {
+				id,
+				name: node.name,
+				platform: node.platform,
+			});
+			events.extend(node_events);
+		}
+
+		// every node's operations up to the limit, so cutting the merged list keeps each node's
+		// operations contiguous from its clock
+		events.sort_by_key(|event| event.timestamp);
+		if events.len() as i64 > limit {
+			events.truncate(limit as usize);
+			has_more = true;
+		}
+
+		Ok(SyncBatch {
+			nodes,
+			operations: events
+				.into_iter()
+				.map(|event| serde_json::from_str(&event.data))
+				.collect::<Result<_, _>>()?,
+			has_more,
+		})
+	}
+
+	/// ingest merges operations from another node, returning what was new to this node
+	pub async fn ingest(&self, batch: SyncBatch) -> Result<Ingested, SyncError> {
+		for node in batch.nodes.iter().filter(|node| node.id != self.node) {
+			let pub_id = node.id.as_bytes().to_vec();
+			self.db
+				.node()
+				.upsert(
+					node::pub_id::equals(pub_id.clone()),
+					(
+						pub_id,
+						node.name.clone(),
+						vec![node::platform::set(node.platform)],
+					),
+					vec![node::name::set(node.name.clone())],
+				)
+				.exec()
+				.await?;
+		}
+
+		let node_ids = self
+			.db
+			.node()
+			.find_many(vec![])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|node| (uuid_from_pub_id(&node.pub_id), node.id))
+			.collect::<HashMap<_, _>>();
+
+		let known = self
+			.db
+			.sync_event()
+			.find_many(vec![sync_event::id::in_vec(
+				batch
+					.operations
+					.iter()
+					.map(|op| op.id.as_bytes().to_vec())
+					.collect(),
+			)])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|event| event.id)
+			.collect::<HashSet<_>>();
+
+		let mut ops = batch
+			.operations
+			.into_iter()
+			.filter(|op| op.node != self.node && !known.contains(op.id.as_bytes().as_slice()))
+			.collect::<Vec<_>>();
+		ops.sort_by_key(|op| op.timestamp);
+
+		let mut objects = Vec::new();
+		for op in &ops {
+			let node_id = *node_ids
+				.get(&op.node)
+				.ok_or(SyncError::UnknownNode(op.node))?;
+
+			self.clock
+				.update_with_timestamp(&Timestamp::new(op.timestamp, op.node.into()))
+				.ok();
+
+			if self.is_latest(op).await? {
+				apply::apply(&self.db, self.node_local_id, &node_ids, op).await?;
+
+				if let CRDTOperationType::Shared(shared) = &op.typ {
+					if shared.model == OBJECT
+						&& matches!(shared.data, SharedOperationData::Create(_))
+					{
+						objects.push(shared.record_id);
+					}
+				}
+			} else {
+				debug!("Skipping superseded sync operation {}", op.id);
+			}
+
+			self.log(node_id, std::slice::from_ref(op)).await?;
+		}
+
+		if !ops.is_empty() {
+			info!("Ingested {} sync operations", ops.len());
+		}
+
+		Ok(Ingested {
+			operations: ops.len(),
+			objects,
+		})
+	}
+
+	/// is_latest tells if no later operation than `op` changed the same field, or created or
+	/// deleted the same record, in which case `op` is superseded and isn't applied
+	async fn is_latest(&self, op: &CRDTOperation) -> Result<bool, SyncError> {
+		let (model, record_id, kind, column) = describe(op);
+		if matches!(op.typ, CRDTOperationType::Owned(_)) {
+			return Ok(true);
+		}
+
+		let mut params = vec![
+			sync_event::model::equals(model),
+			sync_event::record_id::equals(record_id),
+		];
+		if kind == OperationKind::Update {
+			params.push(sync_event::column::equals(column));
+		} else {
+			params.push(sync_event::kind::in_vec(vec![
+				OperationKind::Create as i32,
+				OperationKind::Delete as i32,
+			]));
+		}
+
+		let latest = self
+			.db
+			.sync_event()
+			.find_first(params)
+			.with(sync_event::node::fetch())
+			.order_by(sync_event::timestamp::order(Direction::Desc))
+			.exec()
+			.await?;
+
+		Ok(match latest {
+			Some(latest) => {
+				let latest_node = latest
+					.node()
+					.map(|node| uuid_from_pub_id(&node.pub_id))
+					.unwrap_or_default();
+				compare(
+					(NTP64(latest.timestamp as u64), latest_node),
+					(op.timestamp, op.node),
+				) == Ordering::Less
+			}
+			None => true,
+		})
+	}
+
+	/// seed logs operations for the records of this node the log doesn't have yet, because they
+	/// were there before the sync engine or were created without going through it
+	pub async fn seed(&self) -> Result<(), SyncError> {
+		let db = &self.db;
+		let mut ops = vec![];
+
+		let first_seed = db
+			.sync_event()
+			.find_first(vec![sync_event::node_id::equals(self.node_local_id)])
+			.exec()
+			.await?
+			.is_none();
+
+		self.ensure_object_pub_ids(
+			&db.object()
+				.find_many(vec![object::pub_id::equals(None)])
+				.exec()
+				.await?,
+		)
+		.await?;
+
+		if first_seed {
+			for tag in db.tag().find_many(vec![]).exec().await? {
+				ops.push(self.shared_create(
+					TAG,
+					uuid_from_pub_id(&tag.pub_id),
+					&TagData::from(&tag),
+				));
+			}
+
+			for album in db.album().find_many(vec![]).exec().await? {
+				ops.push(self.shared_create(
+					ALBUM,
+					uuid_from_pub_id(&album.pub_id),
+					&AlbumData::from(&album),
+				));
+			}
+
+			for tag_on_object in db
+				.tag_on_object()
+				.find_many(vec![])
+				.with(tag_on_object::tag::fetch())
+				.with(tag_on_object::object::fetch())
+				.exec()
+				.await?
+			{
+				if let (Ok(tag), Ok(object)) = (tag_on_object.tag(), tag_on_object.object()) {
+					if let Some(pub_id) = &object.pub_id {
+						ops.push(self.relation_create(
+							TAG_ON_OBJECT,
+							uuid_from_pub_id(pub_id),
+							uuid_from_pub_id(&tag.pub_id),
+						));
+					}
+				}
+			}
+
+			for object_in_album in db
+				.object_in_album()
+				.find_many(vec![])
+				.with(object_in_album::album::fetch())
+				.with(object_in_album::object::fetch())
+				.exec()
+				.await?
+			{
+				if let (Ok(album), Ok(object)) = (object_in_album.album(), object_in_album.object())
+				{
+					if let Some(pub_id) = &object.pub_id {
+						ops.push(self.relation_create(
+							OBJECT_IN_ALBUM,
+							uuid_from_pub_id(pub_id),
+							uuid_from_pub_id(&album.pub_id),
+						));
+					}
+				}
+			}
+		}
+
+		for location in db
+			.location()
+			.find_many(vec![location::node_id::equals(self.node_local_id)])
+			.exec()
+			.await?
+		{
+			if !self.is_location_logged(&location.pub_id).await? {
+				ops.extend(self.seed_location(&location).await?);
+			}
+		}
+
+		if !ops.is_empty() {
+			info!("Seeding sync log with {} operations", ops.len());
+			self.write_ops(ops).await?;
+		}
+
+		Ok(())
+	}
+
+	async fn seed_location(
+		&self,
+		location: &location::Data,
+	) -> Result<Vec<CRDTOperation>, SyncError> {
+		let location_id = uuid_from_pub_id(&location.pub_id);
+		let mut ops = vec![self.owned_create(
+			LOCATION,
+			[(location_id, LocationData::new(location, self.node))],
+		)];
+
+		let file_paths = self
+			.db
+			.file_path()
+			.find_many(vec![file_path::location_id::equals(location.id)])
+			.with(file_path::object::fetch())
+			.order_by(file_path::id::order(Direction::Asc))
+			.exec()
+			.await?;
+
+		for chunk in file_paths.chunks(SEED_CHUNK_SIZE) {
+			ops.push(self.owned_create(
+				FILE_PATH,
+				chunk.iter().map(|file_path| {
+					let object = file_path
+						.object()
+						.ok()
+						.flatten()
+						.and_then(|object| object.pub_id.as_deref())
+						.map(uuid_from_pub_id);
+
+					(
+						FilePathId {
+							location: location_id,
+							id: file_path.id,
+						},
+						FilePathData::new(file_path, object),
+					)
+				}),
+			));
+		}
+
+		Ok(ops)
+	}
+}
+
+/// describe returns the model, record id, kind and column an operation is logged under
+fn describe(op: &CRDTOperation) -> (String, Vec<u8>, OperationKind, Option<String>) {
+	match &op.typ {
+		CRDTOperationType::Shared(shared) => {
+			let (kind, column) = match &shared.data {
+				SharedOperationData::Create(_) => (OperationKind::Create, None),
+				SharedOperationData::Update { field, .. } => {
+					(OperationKind::Update, Some(field.clone()))
+				}
+				SharedOperationData::Delete => (OperationKind::Delete, None),
+			};
+			(
+				shared.model.clone(),
+				shared.record_id.as_bytes().to_vec(),
+				kind,
+				column,
+			)
+		}
+		CRDTOperationType::Relation(relation) => {
+			let (kind, column) = match &relation.data {
+				RelationOperationData::Create => (OperationKind::Create, None),
+				RelationOperationData::Update { field, .. } => {
+					(OperationKind::Update, Some(field.clone()))
+				}
+				RelationOperationData::Delete => (OperationKind::Delete, None),
+			};
+			let mut record_id = relation.relation_item.as_bytes().to_vec();
+			record_id.extend_from_slice(relation.relation_group.as_bytes());
+			(relation.relation.clone(), record_id, kind, column)
+		}
+		CRDTOperationType::Owned(owned) => {
+			let kind = match owned.items.first().map(|item| &item.data) {
+				Some(OwnedOperationData::Update(_)) => OperationKind::Update,
+				Some(OwnedOperationData::Delete) => OperationKind::Delete,
+				_ => OperationKind::Create,
+			};
+			// owned records are logged under the location they're in
+			let location = owned
+				.items
+				.first()
+				.and_then(|item| owned_location(&item.id))
+				.map(|location| location.as_bytes().to_vec())
+				.unwrap_or_default();
+			(owned.model.clone(), location, kind, None)
+		}
+	}
+}
+
+/// owned_location reads the location out of the id of an owned record, which is either the
+/// location's own pub id or a [`FilePathId`]
+fn owned_location(id: &Value) -> Option<Uuid> {
+	serde_json::from_value::<Uuid>(id.clone())
+		.or_else(|_| serde_json::from_value::<FilePathId>(id.clone()).map(|id| id.location))
+		.ok()
+}
+
+/// compare orders operations by their timestamp, breaking ties between nodes by their id so every
+/// node picks the same winner
+fn compare(a: (NTP64, Uuid), b: (NTP64, Uuid)) -> Ordering {
+	a.0.cmp(&b.0).then(a.1.cmp(&b.1))
+}
+
+fn to_map(data: &impl Serialize) -> Map<String, Value> {
+	match serde_json::to_value(data) {
+		Ok(Value::Object(map)) => map,
+		_ => Map::new(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn object_pub_ids_follow_content() {
+		assert_eq!(
+			object_pub_id("6e8f3c8b1a2d4e5f"),
+			object_pub_id("6e8f3c8b1a2d4e5f")
+		);
+		assert_ne!(
+			object_pub_id("6e8f3c8b1a2d4e5f"),
+			object_pub_id("0e8f3c8b1a2d4e5f")
+		);
+	}
+
+	#[test]
+	fn later_operations_win_and_ties_break_by_node() {
+		let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+		assert_eq!(compare((NTP64(1), b), (NTP64(2), a)), Ordering::Less);
+		assert_eq!(compare((NTP64(2), a), (NTP64(2), b)), Ordering::Less);
+		assert_eq!(compare((NTP64(2), b), (NTP64(2), b)), Ordering::Equal);
+	}
+
+	#[test]
+	fn owned_operations_are_logged_under_their_location() {
+		let location = Uuid::new_v4();
+		let file_path = serde_json::to_value(FilePathId { location, id: 4 }).unwrap();
+
+		assert_eq!(owned_location(&file_path), Some(location));
+		assert_eq!(
+			owned_location(&serde_json::to_value(location).unwrap()),
+			Some(location)
+		);
+		assert_eq!(owned_location(&Value::Null), None);
+	}
+}
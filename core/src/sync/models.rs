@@ -0,0 +1,151 @@
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::prisma::{album, file_path, location, object, tag};
+
+pub const LOCATION: &str = "Location";
+pub const FILE_PATH: &str = "FilePath";
+pub const OBJECT: &str = "Object";
+pub const TAG: &str = "Tag";
+pub const ALBUM: &str = "Album";
+pub const TAG_ON_OBJECT: &str = "TagOnObject";
+pub const OBJECT_IN_ALBUM: &str = "ObjectInAlbum";
+
+/// object_pub_id derives the id an object is known by on every node from its cas_id, so the same
+/// content indexed on two nodes ends up as the same object once they sync
+pub fn object_pub_id(cas_id: &str) -> Uuid {
+	let hash = blake3::hash(cas_id.as_bytes());
+	let mut bytes = [0; 16];
+	bytes.copy_from_slice(&hash.as_bytes()[..16]);
+	Uuid::from_bytes(bytes)
+}
+
+/// uuid_from_pub_id reads a pub id column, which is a uuid stored as bytes
+pub fn uuid_from_pub_id(pub_id: &[u8]) -> Uuid {
+	Uuid::from_slice(pub_id).unwrap_or_default()
+}
+
+/// Locations are owned by the node they're on, which is the only one to change them
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocationData {
+	pub node: Uuid,
+	pub name: Option<String>,
+	pub local_path: Option<String>,
+	pub is_archived: bool,
+	#[serde(default)]
+	pub is_catalog: bool,
+	pub date_created: DateTime<FixedOffset>,
+}
+
+impl LocationData {
+	pub fn new(location: &location::Data, node: Uuid) -> Self {
+		Self {
+			node,
+			name: location.name.clone(),
+			local_path: location.local_path.clone(),
+			is_archived: location.is_archived,
+			is_catalog: location.is_catalog,
+			date_created: location.date_created,
+		}
+	}
+}
+
+/// File paths keep the id given to them by the node that indexed their location
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FilePathId {
+	pub location: Uuid,
+	pub id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FilePathData {
+	pub is_dir: bool,
+	pub materialized_path: String,
+	pub name: String,
+	pub extension: Option<String>,
+	pub parent_id: Option<i32>,
+	/// pub id of the object the path was identified as
+	pub object: Option<Uuid>,
+	pub date_created: DateTime<FixedOffset>,
+	pub date_modified: DateTime<FixedOffset>,
+}
+
+impl FilePathData {
+	pub fn new(file_path: &file_path::Data, object: Option<Uuid>) -> Self {
+		Self {
+			is_dir: file_path.is_dir,
+			materialized_path: file_path.materialized_path.clone(),
+			name: file_path.name.clone(),
+			extension: file_path.extension.clone(),
+			parent_id: file_path.parent_id,
+			object,
+			date_created: file_path.date_created,
+			date_modified: file_path.date_modified,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ObjectData {
+	pub cas_id: String,
+	pub size_in_bytes: String,
+	pub kind: i32,
+	pub name: Option<String>,
+	pub extension: Option<String>,
+	pub note: Option<String>,
+	pub hidden: bool,
+	pub favorite: bool,
+	pub important: bool,
+	pub date_created: DateTime<FixedOffset>,
+}
+
+impl From<&object::Data> for ObjectData {
+	fn from(object: &object::Data) -> Self {
+		Self {
+			cas_id: object.cas_id.clone(),
+			size_in_bytes: object.size_in_bytes.clone(),
+			kind: object.kind,
+			name: object.name.clone(),
+			extension: object.extension.clone(),
+			note: object.note.clone(),
+			hidden: object.hidden,
+			favorite: object.favorite,
+			important: object.important,
+			date_created: object.date_created,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagData {
+	pub name: Option<String>,
+	pub color: Option<String>,
+	#[serde(default)]
+	pub is_system: bool,
+}
+
+impl From<&tag::Data> for TagData {
+	fn from(tag: &tag::Data) -> Self {
+		Self {
+			name: tag.name.clone(),
+			color: tag.color.clone(),
+			is_system: tag.is_system,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AlbumData {
+	pub name: String,
+	pub is_hidden: bool,
+}
+
+impl From<&album::Data> for AlbumData {
+	fn from(album: &album::Data) -> Self {
+		Self {
+			name: album.name.clone(),
+			is_hidden: album.is_hidden,
+		}
+	}
+}
@@ -0,0 +1,153 @@
+//! Sorting file names the way people read them, the same for every client: numbers in names by
+//! their value so `file2` comes before `file10`, case and accents folded so `Émile` sits with
+//! `emile`, and katakana with hiragana. Each name gets a [`Collation::key`] whose plain string
+//! order is that order. Some languages sort letters with accents as letters of their own, like `ñ` after
+//! `n` in Spanish, which the collation follows when it's given their locale. CJK ideographs are
+//! sorted by code point, which is their radical and stroke order.
+
+use std::fmt::Write;
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Sorts after every other character, placing letters tailored by a locale after the base letter
+/// they're sorted behind
+const AFTER: char = '\u{10FFFF}';
+/// Digit runs longer than this are compared by their first digits only
+const MAX_DIGITS: usize = 99;
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Collation {
+	/// the language names are sorted for, as a tag like `sv-SE`, the order shared by most
+	/// languages when not given
+	pub locale: Option<String>,
+}
+
+impl Collation {
+	/// language returns the language of the collation's locale, e.g. `sv` for `sv-SE`
+	fn language(&self) -> Option<String> {
+		self.locale
+			.as_deref()?
+			.split(['-', '_'])
+			.next()
+			.map(|language| language.to_ascii_lowercase())
+	}
+
+	/// tailoring returns where a lowercase letter sorts in a language, if it's a letter of its own
+	/// there rather than an accented one: behind which base letter, and in which place among the
+	/// letters behind it
+	fn tailoring(language: &str, letter: char) -> Option<(char, char)> {
+		match (language, letter) {
+			("es", 'ñ') => Some(('n', 'a')),
+			("sv" | "fi", 'å') => Some(('z', 'a')),
+			("sv" | "fi", 'ä') => Some(('z', 'b')),
+			("sv" | "fi", 'ö') => Some(('z', 'c')),
+			("da" | "nb" | "nn" | "no", 'æ') => Some(('z', 'a')),
+			("da" | "nb" | "nn" | "no", 'ø') => Some(('z', 'b')),
+			("da" | "nb" | "nn" | "no", 'å') => Some(('z', 'c')),
+			_ => None,
+		}
+	}
+
+	/// fold lowercases a name and takes the accents off its letters, except the letters the
+	/// collation's language sorts on their own
+	fn fold(&self, name: &str) -> Vec<char> {
+		let language = self.language().unwrap_or_default();
+
+		let mut folded = vec![];
+		for letter in name.chars().flat_map(char::to_lowercase) {
+			if let Some((base, place)) = Self::tailoring(&language, letter) {
+				folded.extend([base, AFTER, place]);
+				continue;
+			}
+
+			folded.extend(
+				letter
+					.to_string()
+					.nfkd()
+					.filter(|c| !is_combining_mark(*c))
+					.flat_map(char::to_lowercase)
+					.map(|c| match c {
+						// katakana sort with the hiragana they're written the same as
+						'\u{30A1}'..='\u{30F6}' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+						c => c,
+					}),
+			);
+		}
+		folded
+	}
+
+	/// key returns what a name is sorted by, names being in order when their keys are. Runs of
+	/// digits are written with their length first, so longer numbers come after shorter ones.
+	pub fn key(&self, name: &str) -> String {
+		let folded = self.fold(name);
+		let mut key = String::with_capacity(folded.len() + 2);
+
+		let mut rest = folded.as_slice();
+		while let Some(&c) = rest.first() {
+			if !c.is_ascii_digit() {
+				key.push(c);
+				rest = &rest[1..];
+				continue;
+			}
+
+			let digits = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+			let number = rest[..digits]
+				.iter()
+				.skip_while(|c| **c == '0')
+				.take(MAX_DIGITS)
+				.collect::<String>();
+			write!(key, "{:02}{number}", number.len()).ok();
+			rest = &rest[digits..];
+		}
+
+		key
+	}
+
+	/// sort_by_name sorts items by their name in the collation's order, keeping items with names
+	/// that sort the same in the order they were in
+	pub fn sort_by_name<T>(&self, items: &mut [T], name: impl Fn(&T) -> String) {
+		items.sort_by_cached_key(|item| self.key(&name(item)));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sorted(collation: &Collation, names: &[&str]) -> Vec<String> {
+		let mut names = names.iter().map(ToString::to_string).collect::<Vec<_>>();
+		collation.sort_by_name(&mut names, Clone::clone);
+		names
+	}
+
+	#[test]
+	fn sorts_naturally() {
+		let root = Collation::default();
+
+		assert_eq!(
+			sorted(&root, &["file10", "File2", "file1", "file02b", "file"]),
+			["file", "file1", "File2", "file02b", "file10"]
+		);
+		assert_eq!(
+			sorted(&root, &["Zebra", "émile", "Eve", "éclair"]),
+			["éclair", "émile", "Eve", "Zebra"]
+		);
+		// katakana and hiragana, and full-width digits
+		assert_eq!(root.key("カメラ"), root.key("かめら"));
+		assert_eq!(root.key("写真１０"), root.key("写真10"));
+
+		let swedish = Collation {
+			locale: Some("sv-SE".to_string()),
+		};
+		assert_eq!(
+			sorted(&swedish, &["öl", "zon", "ål", "ost"]),
+			["ost", "zon", "ål", "öl"]
+		);
+		assert_eq!(
+			sorted(&root, &["öl", "zon", "ål", "ost"]),
+			["ål", "öl", "ost", "zon"]
+		);
+	}
+}
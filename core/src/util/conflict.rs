@@ -0,0 +1,187 @@
+//! What copies of a file are named when another file by the same name is already where they go, e.g.
+//! "notes (conflicted copy from Laptop 2022-12-04).txt". Copies, Spacedrops and files fetched from peers
+//! are all named after the node's [`ConflictNaming`], so they can't end up named differently.
+
+use std::{
+	ffi::{OsStr, OsString},
+	iter,
+	path::{Path, PathBuf},
+};
+
+use chrono::{
+	format::{Item, StrftimeItems},
+	DateTime, Local,
+};
+use rspc::{ErrorCode, Type};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConflictNamingError {
+	#[error("the naming template must contain '{{name}}'")]
+	MissingName,
+	#[error("the naming template can't contain path separators")]
+	PathSeparator,
+	#[error("invalid date format '{0}'")]
+	InvalidDateFormat(String),
+}
+
+impl From<ConflictNamingError> for rspc::Error {
+	fn from(err: ConflictNamingError) -> Self {
+		rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+	}
+}
+
+/// ConflictNaming is how copies of a file are named, set in the node config
+#[derive(Debug, Serialize, Deserialize, Clone, Type, PartialEq, Eq)]
+pub struct ConflictNaming {
+	/// the name of a copy, without its extension: `{name}` is the name of the file, `{device}` the node
+	/// the copy comes from and `{date}` the day it's made
+	pub template: String,
+	/// how `{date}` is written, as a strftime format
+	pub date_format: String,
+}
+
+impl Default for ConflictNaming {
+	fn default() -> Self {
+		Self {
+			template: "{name} (conflicted copy from {device} {date})".to_string(),
+			date_format: "%Y-%m-%d".to_string(),
+		}
+	}
+}
+
+/// sanitize keeps a device name or date from adding directories to a file name
+fn sanitize(part: &str) -> String {
+	part.replace(['/', '\\'], "-")
+}
+
+impl ConflictNaming {
+	pub fn validate(&self) -> Result<(), ConflictNamingError> {
+		if !self.template.contains("{name}") {
+			return Err(ConflictNamingError::MissingName);
+		}
+		if self.template.contains(['/', '\\']) {
+			return Err(ConflictNamingError::PathSeparator);
+		}
+		if StrftimeItems::new(&self.date_format).any(|item| item == Item::Error) {
+			return Err(ConflictNamingError::InvalidDateFormat(
+				self.date_format.clone(),
+			));
+		}
+
+		Ok(())
+	}
+
+	/// copy_name returns the name of the `n`th copy of a file, counting from 1. Copies after the first
+	/// get their number after the template, e.g. "notes (conflicted copy from Laptop 2022-12-04) 2.txt".
+	/// Directories keep the whole of their name in place of `{name}`.
+	pub fn copy_name(
+		&self,
+		file_name: &OsStr,
+		is_dir: bool,
+		device: &str,
+		date: DateTime<Local>,
+		n: usize,
+	) -> OsString {
+		let path = Path::new(file_name);
+		let (stem, extension) = match is_dir {
+			true => (file_name, None),
+			false => (path.file_stem().unwrap_or(file_name), path.extension()),
+		};
+
+		let rendered = self
+			.template
+			.replace("{device}", &sanitize(device))
+			.replace(
+				"{date}",
+				&sanitize(&date.format(&self.date_format).to_string()),
+			);
+
+		let mut name = OsString::new();
+		for (i, part) in rendered.split("{name}").enumerate() {
+			if i > 0 {
+				name.push(stem);
+			}
+			name.push(part);
+		}
+		if n > 1 {
+			name.push(format!(" {n}"));
+		}
+		if let Some(extension) = extension {
+			name.push(".");
+			name.push(extension);
+		}
+
+		name
+	}
+
+	/// candidates yields `path`, then the paths of its copies next to it, for callers that need to do
+	/// more than check whether something's there already
+	pub fn candidates<'a>(
+		&'a self,
+		path: PathBuf,
+		device: &'a str,
+	) -> impl Iterator<Item = PathBuf> + 'a {
+		let date = Local::now();
+		let is_dir = path.is_dir();
+		let file_name = path.file_name().unwrap_or_default().to_os_string();
+
+		iter::once(path.clone()).chain(
+			(1..).map(move |n| {
+				path.with_file_name(self.copy_name(&file_name, is_dir, device, date, n))
+			}),
+		)
+	}
+
+	/// free_path returns `path` if nothing's there yet, or the path of its first copy that's free
+	pub fn free_path(&self, path: &Path, device: &str) -> PathBuf {
+		self.candidates(path.to_path_buf(), device)
+			.find(|candidate| !candidate.exists())
+			.unwrap_or_else(|| path.to_path_buf())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+
+	#[test]
+	fn names_copies_after_the_template() {
+		let naming = ConflictNaming::default();
+		let date = Local.ymd(2022, 12, 4).and_hms(10, 0, 0);
+
+		assert_eq!(
+			naming.copy_name(OsStr::new("notes.txt"), false, "Laptop", date, 1),
+			"notes (conflicted copy from Laptop 2022-12-04).txt"
+		);
+		assert_eq!(
+			naming.copy_name(OsStr::new("photos.2022"), true, "Work/PC", date, 2),
+			"photos.2022 (conflicted copy from Work-PC 2022-12-04) 2"
+		);
+
+		let naming = ConflictNaming {
+			template: "{date} {name}".to_string(),
+			date_format: "%d.%m".to_string(),
+		};
+		assert_eq!(
+			naming.copy_name(OsStr::new("notes.txt"), false, "Laptop", date, 1),
+			"04.12 notes.txt"
+		);
+	}
+
+	#[test]
+	fn rejects_templates_without_the_name() {
+		let naming = ConflictNaming {
+			template: "copy from {device}".to_string(),
+			..Default::default()
+		};
+
+		assert!(matches!(
+			naming.validate(),
+			Err(ConflictNamingError::MissingName)
+		));
+		assert!(ConflictNaming::default().validate().is_ok());
+	}
+}
@@ -1,7 +1,17 @@
 use crate::prisma::{self, PrismaClient};
-use prisma_client_rust::{migrations::*, NewClientError};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use prisma_client_rust::{
+	migrations::*, prisma_models::PrismaValue, raw::Raw, NewClientError, QueryError,
+};
+use serde::{
+	de::{self, DeserializeOwned},
+	Deserialize, Deserializer,
+};
 use thiserror::Error;
 
+/// The most placeholders a statement can have, the lowest limit SQLite builds have
+const MAX_VARIABLES: usize = 999;
+
 /// MigrationError represents an error that occurring while opening a initialising and running migrations on the database.
 #[derive(Error, Debug)]
 pub enum MigrationError {
@@ -40,3 +50,213 @@ pub async fn load_and_migrate(db_url: &str) -> Result<PrismaClient, MigrationErr
 
 	Ok(client)
 }
+
+/// What an [`Upsert`] does with rows that conflict with ones already in the table
+pub enum OnConflict<'a> {
+	/// skips the rows conflicting on the given columns, or on any unique constraint if there are none
+	DoNothing(&'a [&'a str]),
+	/// overwrites the columns in the second list of the rows conflicting on the columns in the first one
+	Update(&'a [&'a str], &'a [&'a str]),
+}
+
+/// Upsert inserts many rows into a table at once, with an `INSERT ... ON CONFLICT` statement per few
+/// hundred rows. Each row has a value for each of the `N` columns, so the placeholders always match
+/// the values.
+pub struct Upsert<'a, const N: usize> {
+	table: &'a str,
+	columns: [&'a str; N],
+	rows: Vec<[PrismaValue; N]>,
+	on_conflict: Option<OnConflict<'a>>,
+}
+
+impl<'a, const N: usize> Upsert<'a, N> {
+	pub fn new(table: &'a str, columns: [&'a str; N]) -> Self {
+		Self {
+			table,
+			columns,
+			rows: vec![],
+			on_conflict: None,
+		}
+	}
+
+	pub fn row(mut self, values: [PrismaValue; N]) -> Self {
+		self.rows.push(values);
+		self
+	}
+
+	pub fn rows(mut self, rows: impl IntoIterator<Item = [PrismaValue; N]>) -> Self {
+		self.rows.extend(rows);
+		self
+	}
+
+	pub fn on_conflict(mut self, on_conflict: OnConflict<'a>) -> Self {
+		self.on_conflict = Some(on_conflict);
+		self
+	}
+
+	/// sql returns the statement inserting `rows` rows, with a placeholder for each value
+	fn sql(&self, rows: usize, returning: &[&str]) -> String {
+		let row = format!("({})", vec!["{}"; N].join(", "));
+		let mut sql = format!(
+			"INSERT INTO {} ({}) VALUES {}",
+			self.table,
+			self.columns.join(", "),
+			vec![row.as_str(); rows].join(", ")
+		);
+
+		match &self.on_conflict {
+			Some(OnConflict::DoNothing([])) => sql.push_str(" ON CONFLICT DO NOTHING"),
+			Some(OnConflict::DoNothing(target)) => {
+				sql.push_str(&format!(" ON CONFLICT ({}) DO NOTHING", target.join(", ")))
+			}
+			Some(OnConflict::Update(target, columns)) => sql.push_str(&format!(
+				" ON CONFLICT ({}) DO UPDATE SET {}",
+				target.join(", "),
+				columns
+					.iter()
+					.map(|column| format!("{column} = excluded.{column}"))
+					.collect::<Vec<_>>()
+					.join(", ")
+			)),
+			None => {}
+		}
+
+		if !returning.is_empty() {
+			sql.push_str(&format!(" RETURNING {}", returning.join(", ")));
+		}
+
+		sql
+	}
+
+	/// batches splits the rows in statements that stay under SQLite's placeholder limit
+	fn batches(&self, returning: &[&str]) -> Vec<Raw> {
+		self.rows
+			.chunks((MAX_VARIABLES / N.max(1)).max(1))
+			.map(|batch| {
+				Raw::new(
+					&self.sql(batch.len(), returning),
+					batch.iter().flat_map(|row| row.iter().cloned()).collect(),
+				)
+			})
+			.collect()
+	}
+
+	/// exec runs the upsert, returning how many rows were inserted or updated
+	pub async fn exec(self, db: &PrismaClient) -> Result<i64, QueryError> {
+		let mut count = 0;
+		for batch in self.batches(&[]) {
+			count += db._execute_raw(batch).exec().await?;
+		}
+
+		Ok(count)
+	}
+
+	/// returning runs the upsert, returning the given columns of the rows inserted or updated
+	pub async fn returning<T: DeserializeOwned>(
+		self,
+		db: &PrismaClient,
+		columns: &[&str],
+	) -> Result<Vec<T>, QueryError> {
+		let mut rows = Vec::with_capacity(self.rows.len());
+		for batch in self.batches(columns) {
+			rows.extend(db._query_raw::<T>(batch).exec().await?);
+		}
+
+		Ok(rows)
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawValue {
+	Number(i64),
+	String(String),
+}
+
+/// Raw queries may return integers as numbers or strings, depending on their size
+pub fn raw_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+	match RawValue::deserialize(deserializer)? {
+		RawValue::Number(number) => Ok(number),
+		RawValue::String(string) => string.parse().map_err(de::Error::custom),
+	}
+}
+
+/// Dates lose their type when a raw query computes them, e.g. with `MIN`, and come back as they're
+/// stored: milliseconds since the epoch, or text
+pub fn raw_date<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+	let millis = match Option::<RawValue>::deserialize(deserializer)? {
+		None => return Ok(None),
+		Some(RawValue::Number(millis)) => millis,
+		Some(RawValue::String(string)) => match string.parse::<i64>() {
+			Ok(millis) => millis,
+			Err(_) => {
+				return DateTime::parse_from_rfc3339(&string)
+					.map(|date| date.with_timezone(&Utc))
+					.or_else(|_| {
+						NaiveDateTime::parse_from_str(&string, "%Y-%m-%d %H:%M:%S%.f")
+							.map(|date| Utc.from_utc_datetime(&date))
+					})
+					.map(Some)
+					.map_err(de::Error::custom)
+			}
+		},
+	};
+
+	Ok(Utc.timestamp_millis_opt(millis).single())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn builds_upserts_with_a_placeholder_per_value() {
+		let upsert = Upsert::new("object", ["cas_id", "kind"])
+			.on_conflict(OnConflict::DoNothing(&["cas_id"]));
+
+		assert_eq!(
+			upsert.sql(2, &["id", "cas_id"]),
+			"INSERT INTO object (cas_id, kind) VALUES ({}, {}), ({}, {}) \
+			ON CONFLICT (cas_id) DO NOTHING RETURNING id, cas_id"
+		);
+
+		let upsert = Upsert::new("tag", ["pub_id", "name", "color"])
+			.on_conflict(OnConflict::Update(&["pub_id"], &["name", "color"]));
+
+		assert_eq!(
+			upsert.sql(1, &[]),
+			"INSERT INTO tag (pub_id, name, color) VALUES ({}, {}, {}) \
+			ON CONFLICT (pub_id) DO UPDATE SET name = excluded.name, color = excluded.color"
+		);
+	}
+
+	#[test]
+	fn batches_stay_under_the_placeholder_limit() {
+		let upsert = Upsert::new("object", ["cas_id", "kind"])
+			.rows((0..1000).map(|i| [PrismaValue::String(i.to_string()), PrismaValue::Int(0)]));
+
+		assert_eq!(upsert.batches(&[]).len(), 3);
+	}
+
+	#[test]
+	fn reads_raw_dates_however_they_are_stored() {
+		#[derive(Deserialize)]
+		struct Row {
+			#[serde(deserialize_with = "raw_date")]
+			date: Option<DateTime<Utc>>,
+		}
+		let date = |json| serde_json::from_str::<Row>(json).unwrap().date;
+		let expected = Utc.ymd(2022, 12, 5).and_hms(10, 30, 0);
+
+		assert_eq!(date(r#"{"date": 1670236200000}"#), Some(expected));
+		assert_eq!(date(r#"{"date": "1670236200000"}"#), Some(expected));
+		assert_eq!(
+			date(r#"{"date": "2022-12-05T10:30:00+00:00"}"#),
+			Some(expected)
+		);
+		assert_eq!(date(r#"{"date": "2022-12-05 10:30:00"}"#), Some(expected));
+		assert_eq!(date(r#"{"date": null}"#), None);
+	}
+}
@@ -0,0 +1,147 @@
+use std::{fmt, path::Path};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+/// FileLockHolder describes the application that has a file open, as far as the platform lets us know
+#[derive(Debug, Serialize, Deserialize, Clone, Type, PartialEq, Eq)]
+pub struct FileLockHolder {
+	pub pid: Option<u32>,
+	pub process_name: Option<String>,
+}
+
+impl fmt::Display for FileLockHolder {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (&self.process_name, self.pid) {
+			(Some(name), Some(pid)) => write!(f, "in use by {name} (pid {pid})"),
+			(Some(name), None) => write!(f, "in use by {name}"),
+			(None, Some(pid)) => write!(f, "in use by process {pid}"),
+			(None, None) => write!(f, "in use by another application"),
+		}
+	}
+}
+
+/// find_lock_holder checks if another application has the file at `path` open.
+/// On Windows this detects sharing violations, elsewhere it asks `lsof` who has the file open.
+/// Directories aren't checked, and when the check itself fails the file is assumed to be free.
+pub async fn find_lock_holder(path: &Path) -> Option<FileLockHolder> {
+	if tokio::fs::metadata(path)
+		.await
+		.map_or(false, |metadata| metadata.is_dir())
+	{
+		return None;
+	}
+
+	#[cfg(target_os = "windows")]
+	{
+		use std::{fs::OpenOptions, os::windows::fs::OpenOptionsExt};
+		use tokio::task::block_in_place;
+
+		const ERROR_SHARING_VIOLATION: i32 = 32;
+		const ERROR_LOCK_VIOLATION: i32 = 33;
+
+		// opening without sharing anything fails if someone else has the file open
+		match block_in_place(|| OpenOptions::new().read(true).share_mode(0).open(path)) {
+			Err(e)
+				if matches!(
+					e.raw_os_error(),
+					Some(ERROR_SHARING_VIOLATION) | Some(ERROR_LOCK_VIOLATION)
+				) =>
+			{
+				Some(FileLockHolder {
+					pid: None,
+					process_name: None,
+				})
+			}
+			_ => None,
+		}
+	}
+
+	#[cfg(not(target_os = "windows"))]
+	{
+		// lsof exits with an error when no process has the file open
+		let output = tokio::process::Command::new("lsof")
+			.args(["-F", "pc", "--"])
+			.arg(path)
+			.output()
+			.await
+			.ok()?;
+
+		if !output.status.success() {
+			return None;
+		}
+
+		parse_lsof_output(&String::from_utf8_lossy(&output.stdout), std::process::id())
+	}
+}
+
+/// parse_lsof_output reads the first process other than `own_pid` from `lsof -F pc` output
+#[cfg_attr(target_os = "windows", allow(dead_code))]
+fn parse_lsof_output(output: &str, own_pid: u32) -> Option<FileLockHolder> {
+	let mut holder: Option<FileLockHolder> = None;
+
+	for line in output.lines() {
+		let (field, value) = match line.char_indices().nth(1) {
+			Some((idx, _)) => line.split_at(idx),
+			None => continue,
+		};
+
+		match field {
+			"p" => {
+				if let Some(holder) = holder.take() {
+					return Some(holder);
+				}
+
+				let pid = value.parse::<u32>().ok();
+				if pid != Some(own_pid) {
+					holder = Some(FileLockHolder {
+						pid,
+						process_name: None,
+					});
+				}
+			}
+			"c" => {
+				if let Some(holder) = holder.as_mut() {
+					holder.process_name = Some(value.to_string());
+				}
+			}
+			_ => {}
+		}
+	}
+
+	holder
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_first_other_process() {
+		let output = "p100\ncspacedrive\np4242\ncvlc\np5000\ncbash\n";
+
+		assert_eq!(
+			parse_lsof_output(output, 100),
+			Some(FileLockHolder {
+				pid: Some(4242),
+				process_name: Some("vlc".to_string()),
+			})
+		);
+	}
+
+	#[test]
+	fn own_process_is_not_a_holder() {
+		assert_eq!(parse_lsof_output("p100\ncspacedrive\n", 100), None);
+		assert_eq!(parse_lsof_output("", 100), None);
+	}
+
+	#[test]
+	fn describes_holder() {
+		let holder = FileLockHolder {
+			pid: Some(4242),
+			process_name: Some("vlc".to_string()),
+		};
+
+		assert_eq!(holder.to_string(), "in use by vlc (pid 4242)");
+	}
+}
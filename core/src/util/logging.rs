@@ -0,0 +1,374 @@
+//! Core logs go to the console and, for anything logged while working on a library, to a log file of
+//! that library. Jobs run in a `job` span carrying their id, so the lines a job logged can be picked
+//! out of its library's log file again when debugging it. The log level can be changed while the
+//! core is running.
+
+use std::{
+	collections::{hash_map::Entry, HashMap, VecDeque},
+	fmt::{self, Write as _},
+	fs::{self, File, OpenOptions},
+	io::{self, BufWriter, Write as _},
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use chrono::{SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use sd_codec::Artifact;
+use thiserror::Error;
+use tracing::{
+	field::{Field, Visit},
+	span::{Attributes, Id},
+	Event, Subscriber,
+};
+use tracing_subscriber::{
+	filter::ParseError,
+	layer::Context,
+	prelude::*,
+	registry::LookupSpan,
+	reload::{self, Handle},
+	EnvFilter, Layer, Registry,
+};
+use uuid::Uuid;
+
+/// The directives logs are filtered with until they're changed with [`set_log_level`]
+const DEFAULT_LOG_DIRECTIVES: &str =
+	"warn,sd-core=debug,sd-core-mobile=debug,server=debug,desktop=debug";
+/// A library log file is compressed into `<library_id>.log.1` once it grows past this size
+const MAX_LOG_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// Most lines returned by [`job_logs`]
+const MAX_JOB_LOG_LINES: usize = 1000;
+
+#[cfg(not(feature = "android"))]
+const CONSOLE_LOG_FILTER: tracing_subscriber::filter::LevelFilter = {
+	use tracing_subscriber::filter::LevelFilter;
+
+	match cfg!(debug_assertions) {
+		true => LevelFilter::DEBUG,
+		false => LevelFilter::INFO,
+	}
+};
+
+static LOGGER: OnceCell<Logger> = OnceCell::new();
+
+struct Logger {
+	logs_dir: PathBuf,
+	filter: Handle<EnvFilter, Registry>,
+	directives: Mutex<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum LoggingError {
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("Invalid log level '{0}': {1}")]
+	InvalidDirectives(String, ParseError),
+	#[error("Logging isn't set up")]
+	NotInitialized,
+	#[error("Failed to change the log level: {0}")]
+	Reload(#[from] reload::Error),
+	#[error("Failed to compress or decompress a log file: {0}")]
+	Codec(#[from] sd_codec::Error),
+}
+
+impl From<LoggingError> for rspc::Error {
+	fn from(err: LoggingError) -> Self {
+		match err {
+			LoggingError::InvalidDirectives(..) => {
+				rspc::Error::with_cause(rspc::ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => {
+				rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// init sets up logging to the console and to the library log files in `<data_dir>/logs`
+pub(crate) fn init(data_dir: &Path) {
+	let logs_dir = data_dir.join("logs");
+	let _ = fs::create_dir_all(&logs_dir);
+
+	let (filter, handle) = reload::Layer::new(
+		env_filter(DEFAULT_LOG_DIRECTIVES).expect("Error invalid tracing directive!"),
+	);
+
+	let subscriber = tracing_subscriber::registry()
+		.with(filter)
+		.with(LibraryLogLayer::new(logs_dir.clone()));
+	#[cfg(not(feature = "android"))]
+	let subscriber = subscriber.with(tracing_subscriber::fmt::layer().with_filter(CONSOLE_LOG_FILTER));
+	#[cfg(feature = "android")]
+	let subscriber = subscriber.with(tracing_android::layer("com.spacedrive.app").unwrap()); // TODO: This is not working
+	subscriber.init();
+
+	let _ = LOGGER.set(Logger {
+		logs_dir,
+		filter: handle,
+		directives: Mutex::new(DEFAULT_LOG_DIRECTIVES.to_string()),
+	});
+}
+
+fn env_filter(directives: &str) -> Result<EnvFilter, LoggingError> {
+	directives
+		.split(',')
+		.filter(|directive| !directive.is_empty())
+		.try_fold(EnvFilter::from_default_env(), |filter, directive| {
+			Ok(filter.add_directive(
+				directive
+					.parse()
+					.map_err(|e| LoggingError::InvalidDirectives(directives.to_string(), e))?,
+			))
+		})
+}
+
+/// log_level returns the directives logs are currently filtered with
+pub(crate) fn log_level() -> Result<String, LoggingError> {
+	let logger = LOGGER.get().ok_or(LoggingError::NotInitialized)?;
+	let directives = logger
+		.directives
+		.lock()
+		.unwrap_or_else(|e| e.into_inner())
+		.clone();
+	Ok(directives)
+}
+
+/// set_log_level changes what's logged, with directives like `RUST_LOG` takes, e.g.
+/// `warn,sd_core::job=trace`
+pub(crate) fn set_log_level(directives: &str) -> Result<(), LoggingError> {
+	let logger = LOGGER.get().ok_or(LoggingError::NotInitialized)?;
+	logger.filter.reload(env_filter(directives)?)?;
+	*logger.directives.lock().unwrap_or_else(|e| e.into_inner()) = directives.to_string();
+
+	Ok(())
+}
+
+/// job_logs returns the last lines a job logged, oldest first
+pub(crate) async fn job_logs(library_id: Uuid, job_id: Uuid) -> Result<Vec<String>, LoggingError> {
+	let logger = LOGGER.get().ok_or(LoggingError::NotInitialized)?;
+	let path = library_log_path(&logger.logs_dir, library_id);
+
+	Ok(
+		tokio::task::spawn_blocking(move || read_job_logs(&path, job_id))
+			.await
+			.map_err(|e| io::Error::new(io::ErrorKind::Other, e))??,
+	)
+}
+
+fn library_log_path(logs_dir: &Path, library_id: Uuid) -> PathBuf {
+	logs_dir.join(format!("{library_id}.log"))
+}
+
+fn rotated_log_path(path: &Path) -> PathBuf {
+	path.with_extension("log.1")
+}
+
+/// rotate compresses a library log file into the file kept before it, replacing what was there
+fn rotate(path: &Path) -> Result<(), LoggingError> {
+	let mut reader = File::open(path)?;
+	let mut encoder = Artifact::OperationLog
+		.codec()
+		.framed_encoder(BufWriter::new(File::create(rotated_log_path(path))?))?;
+	io::copy(&mut reader, &mut encoder)?;
+	encoder.finish()?.flush()?;
+
+	fs::remove_file(path)?;
+	Ok(())
+}
+
+/// read_job_logs picks the lines a job logged out of a library log file and the one rotated before it
+fn read_job_logs(path: &Path, job_id: Uuid) -> Result<Vec<String>, LoggingError> {
+	let job_field = format!(" job_id={job_id} ");
+	let mut lines = VecDeque::with_capacity(MAX_JOB_LOG_LINES);
+
+	for path in [rotated_log_path(path), path.to_path_buf()] {
+		let contents = match fs::read(&path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+			Err(e) => return Err(e.into()),
+		};
+		// log files rotated before they were compressed are plain text
+		let contents = match sd_codec::is_framed(&contents) {
+			true => sd_codec::decompress(&contents)?,
+			false => contents,
+		};
+		let contents = String::from_utf8_lossy(&contents);
+
+		for line in contents.lines().filter(|line| line.contains(&job_field)) {
+			if lines.len() == MAX_JOB_LOG_LINES {
+				lines.pop_front();
+			}
+			lines.push_back(line.to_string());
+		}
+	}
+
+	Ok(lines.into())
+}
+
+/// The library and job a span belongs to, kept in its extensions so the events inside it find them
+#[derive(Debug, Default, Clone, Copy)]
+struct SpanIds {
+	library_id: Option<Uuid>,
+	job_id: Option<Uuid>,
+}
+
+impl Visit for SpanIds {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		match field.name() {
+			"library_id" => self.library_id = Uuid::parse_str(&format!("{value:?}")).ok(),
+			"job_id" => self.job_id = Uuid::parse_str(&format!("{value:?}")).ok(),
+			_ => {}
+		}
+	}
+}
+
+/// The message and other fields of an event, written out as they go in a log line
+#[derive(Default)]
+struct EventFields {
+	message: String,
+	fields: String,
+}
+
+impl Visit for EventFields {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		if field.name() == "message" {
+			let _ = write!(self.message, "{value:?}");
+		} else {
+			let _ = write!(self.fields, " {}={value:?}", field.name());
+		}
+	}
+}
+
+/// A layer writing the events logged in a span with a `library_id` to the log file of that library
+struct LibraryLogLayer {
+	logs_dir: PathBuf,
+	files: Mutex<HashMap<Uuid, (File, u64)>>,
+}
+
+impl LibraryLogLayer {
+	fn new(logs_dir: PathBuf) -> Self {
+		Self {
+			logs_dir,
+			files: Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn write(&self, library_id: Uuid, line: &str) -> Result<(), LoggingError> {
+		let path = library_log_path(&self.logs_dir, library_id);
+		let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+
+		if let Some((_, size)) = files.get(&library_id) {
+			if size + line.len() as u64 > MAX_LOG_FILE_SIZE {
+				files.remove(&library_id);
+				rotate(&path)?;
+			}
+		}
+
+		let (file, size) = match files.entry(library_id) {
+			Entry::Occupied(entry) => entry.into_mut(),
+			Entry::Vacant(entry) => {
+				let file = OpenOptions::new().create(true).append(true).open(&path)?;
+				let size = file.metadata()?.len();
+				entry.insert((file, size))
+			}
+		};
+
+		file.write_all(line.as_bytes())?;
+		*size += line.len() as u64;
+
+		Ok(())
+	}
+}
+
+impl<S> Layer<S> for LibraryLogLayer
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+{
+	fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+		let span = match ctx.span(id) {
+			Some(span) => span,
+			None => return,
+		};
+
+		// spans inside a job's span belong to the job too
+		let mut ids = span
+			.parent()
+			.and_then(|parent| parent.extensions().get::<SpanIds>().copied())
+			.unwrap_or_default();
+		attrs.record(&mut ids);
+
+		if ids.library_id.is_some() {
+			span.extensions_mut().insert(ids);
+		}
+	}
+
+	fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+		let ids = match ctx
+			.event_span(event)
+			.and_then(|span| span.extensions().get::<SpanIds>().copied())
+		{
+			Some(ids) => ids,
+			None => return,
+		};
+		let library_id = match ids.library_id {
+			Some(library_id) => library_id,
+			None => return,
+		};
+
+		let mut fields = EventFields::default();
+		event.record(&mut fields);
+
+		let metadata = event.metadata();
+		let mut line = format!(
+			"{} {:>5}",
+			Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+			metadata.level()
+		);
+		if let Some(job_id) = ids.job_id {
+			let _ = write!(line, " job_id={job_id}");
+		}
+		let _ = writeln!(
+			line,
+			" {}: {}{}",
+			metadata.target(),
+			fields.message,
+			fields.fields
+		);
+
+		// there's nowhere left to log a failure to write a log
+		let _ = self.write(library_id, &line);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempfile::tempdir;
+	use tracing::{info, info_span};
+
+	#[test]
+	fn writes_job_logs_to_library_log() {
+		let dir = tempdir().unwrap();
+		let library_id = Uuid::new_v4();
+		let job_id = Uuid::new_v4();
+
+		let subscriber =
+			tracing_subscriber::registry().with(LibraryLogLayer::new(dir.path().to_path_buf()));
+		tracing::subscriber::with_default(subscriber, || {
+			info!("not in a library");
+			info_span!("job", %job_id, %library_id).in_scope(|| {
+				info!(count = 3, "indexed files");
+			});
+			info_span!("job", job_id = %Uuid::new_v4(), %library_id).in_scope(|| {
+				info!("another job");
+			});
+		});
+
+		let path = library_log_path(dir.path(), library_id);
+		assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+		let lines = read_job_logs(&path, job_id).unwrap();
+		assert_eq!(lines.len(), 1);
+		assert!(lines[0].ends_with("indexed files count=3"));
+	}
+}
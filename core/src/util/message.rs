@@ -0,0 +1,236 @@
+//! Messages the core shows to users, like a job's progress or why it failed, are sent to clients as a
+//! code and the values that go into it rather than as English text, so each client can translate them.
+//! `Display` gives the English text, which is what ends up in the logs.
+
+use std::{fmt, io};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::{job::JobError, location::LocationError, util::file_lock::FileLockHolder};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(tag = "code", content = "params", rename_all = "snake_case")]
+pub enum Message {
+	/// a file being worked on, with how far along it is when that's known
+	FileProgress {
+		action: FileAction,
+		name: String,
+		percent: Option<u8>,
+	},
+	Scanning {
+		path: String,
+	},
+	ListingStorage,
+	ReadingStorageChanges,
+	SavingEntries {
+		saved: usize,
+		total: usize,
+	},
+	CheckingDirectory {
+		path: String,
+	},
+	IdentifyingOrphans {
+		processed: usize,
+		total: usize,
+	},
+	PreparingFiles {
+		count: usize,
+	},
+	Processing {
+		path: String,
+	},
+	Compressing {
+		name: String,
+	},
+	WritingArchive {
+		path: String,
+	},
+	/// a file was left alone because another application kept using it
+	SkippedInUse {
+		name: String,
+		holder: FileLockHolder,
+	},
+	/// a file is in use by another application, so it's tried again at the end of the job
+	WaitingOnInUse {
+		name: String,
+		holder: FileLockHolder,
+	},
+	LocationNotFound {
+		location_id: i32,
+	},
+	LocationOffline {
+		location_id: i32,
+	},
+	InsufficientSpace {
+		path: String,
+		required: u64,
+		available: u64,
+	},
+	FileNotFound,
+	PermissionDenied,
+	/// a file's content no longer matches its checksum, though it wasn't modified
+	ChecksumMismatch,
+	/// a job failed with an error that should go away, and runs again after a while
+	Retrying {
+		attempt: u32,
+		max_attempts: u32,
+		delay_secs: u64,
+		reason: String,
+	},
+	/// a job stopped as most of the files it was going to add are already in the library
+	DuplicateImport {
+		duplicates: usize,
+		total: usize,
+	},
+	/// an error that has no code of its own, only English text
+	Error {
+		text: String,
+	},
+}
+
+/// What a job is doing to the file in a [`Message::FileProgress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(rename_all = "snake_case")]
+pub enum FileAction {
+	Copying,
+	Encrypting,
+	Decrypting,
+	Restoring,
+}
+
+impl fmt::Display for FileAction {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Copying => "Copying",
+			Self::Encrypting => "Encrypting",
+			Self::Decrypting => "Decrypting",
+			Self::Restoring => "Restoring",
+		})
+	}
+}
+
+impl fmt::Display for Message {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::FileProgress {
+				action,
+				name,
+				percent: Some(percent),
+			} => write!(f, "{action} {name} ({percent}%)"),
+			Self::FileProgress { action, name, .. } => write!(f, "{action} {name}"),
+			Self::Scanning { path } => write!(f, "Scanning {path}"),
+			Self::ListingStorage => write!(f, "Listing files in storage"),
+			Self::ReadingStorageChanges => write!(f, "Reading changes from storage"),
+			Self::SavingEntries { saved, total } => write!(f, "Writing {saved} of {total} to db"),
+			Self::CheckingDirectory { path } => write!(f, "Checking {path}"),
+			Self::IdentifyingOrphans { processed, total } => {
+				write!(f, "Processed {processed} of {total} orphan Paths")
+			}
+			Self::PreparingFiles { count } => write!(f, "Preparing to process {count} files"),
+			Self::Processing { path } => write!(f, "Processing {path}"),
+			Self::Compressing { name } => write!(f, "Compressing {name}"),
+			Self::WritingArchive { path } => write!(f, "Writing {path}"),
+			Self::SkippedInUse { name, holder } => write!(f, "Skipped {name}: {holder}"),
+			Self::WaitingOnInUse { name, holder } => write!(f, "Waiting on {name}: {holder}"),
+			Self::LocationNotFound { location_id } => {
+				write!(f, "Location not found (id: {location_id})")
+			}
+			Self::LocationOffline { location_id } => {
+				write!(f, "Location is offline (id: {location_id})")
+			}
+			Self::InsufficientSpace {
+				path,
+				required,
+				available,
+			} => write!(
+				f,
+				"not enough space on '{path}': {required} bytes needed but only {available} bytes available"
+			),
+			Self::FileNotFound => write!(f, "File not found"),
+			Self::PermissionDenied => write!(f, "Permission denied"),
+			Self::ChecksumMismatch => write!(f, "Content no longer matches its checksum"),
+			Self::Retrying {
+				attempt,
+				max_attempts,
+				delay_secs,
+				reason,
+			} => write!(
+				f,
+				"Attempt {attempt} of {max_attempts} failed ({reason}), retrying in {delay_secs}s"
+			),
+			Self::DuplicateImport { duplicates, total } => write!(
+				f,
+				"{duplicates} of {total} files are already in the library, waiting on what to do with them"
+			),
+			Self::Error { text } => f.write_str(text),
+		}
+	}
+}
+
+impl From<&JobError> for Message {
+	fn from(error: &JobError) -> Self {
+		match error {
+			JobError::LocationError(LocationError::IdNotFound(location_id)) => {
+				Self::LocationNotFound {
+					location_id: *location_id,
+				}
+			}
+			JobError::LocationError(LocationError::Offline(location_id)) => Self::LocationOffline {
+				location_id: *location_id,
+			},
+			JobError::IOError(e) => e.into(),
+			JobError::DuplicateImport { duplicates, total } => Self::DuplicateImport {
+				duplicates: *duplicates,
+				total: *total,
+			},
+			JobError::InsufficientSpace(e) => Self::InsufficientSpace {
+				path: e.path.display().to_string(),
+				required: e.required,
+				available: e.available,
+			},
+			e => Self::Error {
+				text: e.to_string(),
+			},
+		}
+	}
+}
+
+impl From<&io::Error> for Message {
+	fn from(error: &io::Error) -> Self {
+		match error.kind() {
+			io::ErrorKind::NotFound => Self::FileNotFound,
+			io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+			_ => Self::Error {
+				text: error.to_string(),
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn serializes_code_and_params() {
+		let message = Message::FileProgress {
+			action: FileAction::Copying,
+			name: "photo.jpg".into(),
+			percent: Some(40),
+		};
+
+		assert_eq!(
+			serde_json::to_value(&message).unwrap(),
+			serde_json::json!({
+				"code": "file_progress",
+				"params": { "action": "copying", "name": "photo.jpg", "percent": 40 }
+			})
+		);
+		assert_eq!(message.to_string(), "Copying photo.jpg (40%)");
+		assert_eq!(
+			serde_json::to_value(Message::ListingStorage).unwrap(),
+			serde_json::json!({ "code": "listing_storage" })
+		);
+	}
+}
@@ -1,2 +1,10 @@
+pub mod collation;
+pub mod conflict;
 pub mod db;
+pub mod file_lock;
+pub mod logging;
+pub mod message;
+pub mod os_path;
+pub mod platform_path;
+pub mod power;
 pub mod seeder;
@@ -0,0 +1,212 @@
+//! Paths on disk aren't always valid UTF-8, old archives in particular are full of names in legacy
+//! encodings. The index stores a lossy version of every path for display and search, and for the
+//! ones that aren't valid UTF-8 it also keeps the raw bytes the OS gave us, so the file can still be
+//! found on disk.
+
+use serde::{de, Deserializer, Serializer};
+use std::{
+	ffi::{OsStr, OsString},
+	fmt,
+	path::{Path, PathBuf},
+};
+
+/// raw_path returns the bytes of a path that isn't valid UTF-8, and `None` for the ones that are,
+/// which can be stored as a string without losing anything
+pub fn raw_path(path: impl AsRef<Path>) -> Option<Vec<u8>> {
+	let path = path.as_ref();
+	path.to_str()
+		.is_none()
+		.then(|| os_str_to_bytes(path.as_os_str()))
+}
+
+/// path_from_raw turns bytes stored by [`raw_path`] back into a path
+pub fn path_from_raw(raw: &[u8]) -> PathBuf {
+	PathBuf::from(os_string_from_bytes(raw))
+}
+
+/// lossy_name is the name shown for a file, with the bytes that aren't UTF-8 replaced
+pub fn lossy_name(name: Option<&OsStr>) -> String {
+	name.unwrap_or_default().to_string_lossy().to_string()
+}
+
+/// resolve_materialized_path builds the path on disk of a file path in a location, from its raw
+/// path if it has one
+pub fn resolve_materialized_path(
+	location_path: impl AsRef<Path>,
+	materialized_path: &str,
+	raw_path: Option<&[u8]>,
+) -> PathBuf {
+	let path = match raw_path {
+		Some(raw) => location_path.as_ref().join(path_from_raw(raw)),
+		None => location_path.as_ref().join(materialized_path),
+	};
+
+	long_path(path)
+}
+
+/// long_path makes paths longer than `MAX_PATH` usable on Windows, where they have to be prefixed
+/// with `\\?\` to get past the limit. Paths are left alone everywhere else.
+pub fn long_path(path: PathBuf) -> PathBuf {
+	#[cfg(windows)]
+	{
+		const MAX_PATH: usize = 260;
+
+		if path.as_os_str().len() < MAX_PATH || !path.is_absolute() {
+			return path;
+		}
+
+		let path = match path.to_str() {
+			Some(str) if str.starts_with(r"\\?\") => return path,
+			// verbatim paths aren't normalized by Windows, so they can't contain forward slashes
+			Some(str) => PathBuf::from(str.replace('/', "\\")),
+			None => path,
+		};
+
+		let mut prefixed = OsString::new();
+		match path.to_str().and_then(|path| path.strip_prefix(r"\\")) {
+			Some(unc) => {
+				prefixed.push(r"\\?\UNC\");
+				prefixed.push(unc);
+			}
+			None => {
+				prefixed.push(r"\\?\");
+				prefixed.push(path.as_os_str());
+			}
+		}
+		PathBuf::from(prefixed)
+	}
+
+	#[cfg(not(windows))]
+	path
+}
+
+#[cfg(unix)]
+fn os_str_to_bytes(os_str: &OsStr) -> Vec<u8> {
+	use std::os::unix::ffi::OsStrExt;
+	os_str.as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+	use std::os::unix::ffi::OsStrExt;
+	OsStr::from_bytes(bytes).to_os_string()
+}
+
+// names on Windows are UTF-16 that may have unpaired surrogates, stored as little endian pairs
+#[cfg(windows)]
+fn os_str_to_bytes(os_str: &OsStr) -> Vec<u8> {
+	use std::os::windows::ffi::OsStrExt;
+	os_str.encode_wide().flat_map(u16::to_le_bytes).collect()
+}
+
+#[cfg(windows)]
+fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+	use std::os::windows::ffi::OsStringExt;
+	let wide = bytes
+		.chunks_exact(2)
+		.map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+		.collect::<Vec<_>>();
+	OsString::from_wide(&wide)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn os_str_to_bytes(os_str: &OsStr) -> Vec<u8> {
+	os_str.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+	OsString::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Serializes paths as strings like serde does, falling back to their raw bytes when they aren't
+/// valid UTF-8 instead of failing. Use with `#[serde(with = "crate::util::os_path::serde_path")]`.
+pub mod serde_path {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+		match path.to_str() {
+			Some(path) => serializer.serialize_str(path),
+			None => serializer.serialize_bytes(&os_str_to_bytes(path.as_os_str())),
+		}
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+		struct PathVisitor;
+
+		impl<'de> de::Visitor<'de> for PathVisitor {
+			type Value = PathBuf;
+
+			fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+				formatter.write_str("a path as a string or raw bytes")
+			}
+
+			fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+				Ok(PathBuf::from(v))
+			}
+
+			fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+				Ok(path_from_raw(v))
+			}
+
+			fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+				let mut bytes = Vec::new();
+				while let Some(byte) = seq.next_element()? {
+					bytes.push(byte);
+				}
+				Ok(path_from_raw(&bytes))
+			}
+		}
+
+		deserializer.deserialize_any(PathVisitor)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn utf8_paths_have_no_raw_path() {
+		assert_eq!(raw_path("photos/été.jpg"), None);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn non_utf8_paths_round_trip() {
+		use std::os::unix::ffi::OsStrExt;
+
+		// "café.txt" in latin-1
+		let name = OsStr::from_bytes(b"caf\xe9.txt");
+		let raw = raw_path(Path::new(name)).unwrap();
+
+		assert_eq!(path_from_raw(&raw), Path::new(name));
+		assert_eq!(lossy_name(Some(name)), "caf\u{fffd}.txt");
+		assert_eq!(
+			resolve_materialized_path("/location", &lossy_name(Some(name)), Some(&raw)),
+			Path::new("/location").join(name)
+		);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn non_utf8_paths_survive_job_state() {
+		use std::os::unix::ffi::OsStrExt;
+
+		#[derive(serde::Serialize, serde::Deserialize)]
+		struct Step {
+			#[serde(with = "serde_path")]
+			path: PathBuf,
+		}
+
+		for path in [
+			PathBuf::from("/location/plain.txt"),
+			PathBuf::from(OsStr::from_bytes(b"/location/caf\xe9.txt")),
+		] {
+			let step = Step { path };
+			let encoded = rmp_serde::to_vec_named(&step).unwrap();
+			let decoded: Step = rmp_serde::from_slice(&encoded).unwrap();
+			assert_eq!(decoded.path, step.path);
+		}
+	}
+}
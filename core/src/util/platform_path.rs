@@ -0,0 +1,133 @@
+//! Paths are matched the way the platform's filesystems match them. Windows and macOS keep names in
+//! the casing they were created with but take `Photo.JPG` and `photo.jpg` for the same file, and
+//! macOS also takes the composed and decomposed (NFC and NFD) forms of a name for the same file,
+//! handing back either one depending on the filesystem and on what created the file. The index keeps
+//! names in the casing they're read in, composed on macOS, and compares paths by their [`PathKey`]
+//! so a file read back in another form isn't taken for a removed file and a new one. Paths longer
+//! than `MAX_PATH` on Windows are read with a `\\?\` prefix, which is stripped before comparing.
+
+use std::{
+	borrow::Cow,
+	path::{Path, PathBuf},
+};
+
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Whether the platform's filesystems match names whatever their casing
+pub const CASE_INSENSITIVE: bool = cfg!(any(windows, target_os = "macos"));
+/// Whether the platform's filesystems match names whatever their Unicode normalization
+pub const NORMALIZATION_INSENSITIVE: bool = cfg!(target_os = "macos");
+
+/// normalize_name returns a name or path the way the index stores it: composed on macOS, where both
+/// forms are the same file, and as it is everywhere else, where they're two files
+pub fn normalize_name(name: &str) -> Cow<'_, str> {
+	if NORMALIZATION_INSENSITIVE && !is_nfc(name) {
+		Cow::Owned(name.nfc().collect())
+	} else {
+		Cow::Borrowed(name)
+	}
+}
+
+/// strip_verbatim removes the `\\?\` prefix of a Windows long path, turning `\\?\UNC\server\share`
+/// back into `\\server\share`. Paths are left alone everywhere else.
+pub fn strip_verbatim(path: &Path) -> Cow<'_, Path> {
+	#[cfg(windows)]
+	if let Some(stripped) = path.to_str().and_then(strip_verbatim_prefix) {
+		return Cow::Owned(PathBuf::from(stripped));
+	}
+
+	Cow::Borrowed(path)
+}
+
+#[cfg(any(windows, test))]
+fn strip_verbatim_prefix(path: &str) -> Option<String> {
+	match path.strip_prefix(r"\\?\UNC\") {
+		Some(unc) => Some(format!(r"\\{unc}")),
+		None => path.strip_prefix(r"\\?\").map(ToString::to_string),
+	}
+}
+
+/// relative_path returns `path` relative to `root`, whether either of them is a long path or not
+pub fn relative_path(path: &Path, root: &Path) -> Option<PathBuf> {
+	strip_verbatim(path)
+		.strip_prefix(strip_verbatim(root))
+		.ok()
+		.map(Path::to_path_buf)
+}
+
+/// PathKey is what paths are matched by, two paths with the same key being the same file on this
+/// platform
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathKey {
+	Text(String),
+	/// paths that aren't valid UTF-8 are matched byte for byte, as they can't be case folded
+	Raw(PathBuf),
+}
+
+impl PathKey {
+	pub fn new(path: impl AsRef<Path>) -> Self {
+		let path = strip_verbatim(path.as_ref());
+		match path.to_str() {
+			Some(path) => Self::Text(text_key(path, CASE_INSENSITIVE, NORMALIZATION_INSENSITIVE)),
+			None => Self::Raw(path.into_owned()),
+		}
+	}
+}
+
+fn text_key(path: &str, case_insensitive: bool, normalization_insensitive: bool) -> String {
+	let path = if normalization_insensitive {
+		path.nfc().collect()
+	} else {
+		path.to_string()
+	};
+
+	if case_insensitive {
+		path.to_lowercase()
+	} else {
+		path
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn keys_match_like_the_filesystem() {
+		let composed = "photos/\u{e9}t\u{e9}.jpg";
+		let decomposed = "photos/e\u{301}te\u{301}.jpg";
+
+		// macOS
+		assert_eq!(
+			text_key(composed, true, true),
+			text_key(&decomposed.to_uppercase(), true, true)
+		);
+		// Windows
+		assert_eq!(
+			text_key("Photos/IMG.JPG", true, false),
+			text_key("photos/img.jpg", true, false)
+		);
+		assert_ne!(
+			text_key(composed, true, false),
+			text_key(decomposed, true, false)
+		);
+		// Linux
+		assert_ne!(
+			text_key("Photos/IMG.JPG", false, false),
+			text_key("photos/img.jpg", false, false)
+		);
+	}
+
+	#[test]
+	fn verbatim_prefixes_are_stripped() {
+		assert_eq!(
+			strip_verbatim_prefix(r"\\?\C:\Users\me\Photos").as_deref(),
+			Some(r"C:\Users\me\Photos")
+		);
+		assert_eq!(
+			strip_verbatim_prefix(r"\\?\UNC\server\share\Photos").as_deref(),
+			Some(r"\\server\share\Photos")
+		);
+		assert_eq!(strip_verbatim_prefix(r"C:\Users\me\Photos"), None);
+	}
+}
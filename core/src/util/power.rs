@@ -0,0 +1,47 @@
+/// on_battery_power returns true if the device is known to be running on battery.
+/// When the power source can't be determined this returns false, so nothing is skipped by mistake.
+pub fn on_battery_power() -> bool {
+	#[cfg(target_os = "linux")]
+	{
+		// a device is on battery when none of its mains power supplies are online
+		use std::fs;
+
+		let supplies = match fs::read_dir("/sys/class/power_supply") {
+			Ok(supplies) => supplies,
+			Err(_) => return false,
+		};
+
+		let mut has_mains = false;
+		for supply in supplies.filter_map(Result::ok) {
+			let path = supply.path();
+			let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+			if kind.trim() != "Mains" {
+				continue;
+			}
+
+			has_mains = true;
+			if fs::read_to_string(path.join("online"))
+				.unwrap_or_default()
+				.trim() == "1"
+			{
+				return false;
+			}
+		}
+
+		has_mains
+	}
+
+	#[cfg(target_os = "macos")]
+	{
+		std::process::Command::new("pmset")
+			.args(["-g", "batt"])
+			.output()
+			.map(|output| String::from_utf8_lossy(&output.stdout).contains("'Battery Power'"))
+			.unwrap_or(false)
+	}
+
+	#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+	{
+		false
+	}
+}
@@ -0,0 +1,261 @@
+//! The virtual filesystem presents a library as a drive other applications can open files from.
+//! There are no FUSE or WinFsp bindings among our dependencies, so the library is served over
+//! WebDAV on the loopback interface instead, which every platform can mount with the client it
+//! ships with.
+
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	process::Command,
+	sync::{Arc, Mutex},
+};
+
+use once_cell::sync::Lazy;
+use rspc::{ErrorCode, Type};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{io, sync::oneshot, task::block_in_place};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{library::LibraryContext, location::storage::StorageError};
+
+use self::tree::VirtualFs;
+
+mod tree;
+mod webdav;
+
+/// Directory in the node's data directory that libraries are mounted under
+const VFS_MOUNTS_DIR_NAME: &str = "vfs";
+
+/// The libraries currently mounted, by id
+static MOUNTS: Lazy<Mutex<HashMap<Uuid, VfsMount>>> = Lazy::new(Default::default);
+
+#[derive(Error, Debug)]
+pub enum VfsError {
+	#[error("not found")]
+	NotFound,
+	#[error("not a directory")]
+	NotADirectory,
+	#[error("not a file")]
+	NotAFile,
+	#[error("library is already mounted")]
+	AlreadyMounted,
+	#[error("library isn't mounted")]
+	NotMounted,
+	#[error("failed to start the server: {0}")]
+	Server(String),
+	#[error("failed to mount the library: {0}")]
+	Mount(String),
+	#[error("database error: {0}")]
+	Database(#[from] prisma_client_rust::QueryError),
+	#[error("object storage error: {0}")]
+	Storage(#[from] StorageError),
+	#[error("I/O error: {0}")]
+	IO(#[from] io::Error),
+}
+
+impl From<VfsError> for rspc::Error {
+	fn from(err: VfsError) -> Self {
+		match err {
+			VfsError::NotFound | VfsError::NotMounted => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			VfsError::AlreadyMounted => {
+				rspc::Error::with_cause(ErrorCode::Conflict, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+struct VfsMount {
+	info: VfsMountInfo,
+	vfs: Arc<VirtualFs>,
+	shutdown: oneshot::Sender<()>,
+}
+
+/// Where a mounted library can be found
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct VfsMountInfo {
+	/// the WebDAV url the library is served at, which can also be mounted by hand
+	pub url: String,
+	/// where the library is mounted, if the platform tells us
+	pub path: Option<PathBuf>,
+}
+
+/// mount serves the library and mounts it as a drive
+pub async fn mount(library: &LibraryContext) -> Result<VfsMountInfo, VfsError> {
+	if MOUNTS.lock().unwrap().contains_key(&library.id) {
+		return Err(VfsError::AlreadyMounted);
+	}
+
+	let vfs = Arc::new(VirtualFs::new(library.clone()));
+	let (shutdown, shutdown_rx) = oneshot::channel();
+	// the server is reachable by every process on the machine, not only the ones we hand the url to
+	let secret = Uuid::new_v4().simple().to_string();
+	let addr = webdav::serve(Arc::clone(&vfs), secret.clone(), shutdown_rx)?;
+
+	let mount_point = library
+		.config()
+		.data_directory()
+		.join(VFS_MOUNTS_DIR_NAME)
+		.join(library.id.to_string());
+
+	let path = match block_in_place(|| mount_os(addr, &secret, &mount_point)) {
+		Ok(path) => path,
+		Err(e) => {
+			shutdown.send(()).ok();
+			return Err(e);
+		}
+	};
+
+	let info = VfsMountInfo {
+		url: format!("http://{addr}/{secret}/"),
+		path,
+	};
+	info!("Mounted library {} from {}", library.id, info.url);
+
+	MOUNTS.lock().unwrap().insert(
+		library.id,
+		VfsMount {
+			info: info.clone(),
+			vfs,
+			shutdown,
+		},
+	);
+
+	Ok(info)
+}
+
+/// unmount unmounts the library and stops serving it
+pub async fn unmount(library: &LibraryContext) -> Result<(), VfsError> {
+	let mount = MOUNTS
+		.lock()
+		.unwrap()
+		.remove(&library.id)
+		.ok_or(VfsError::NotMounted)?;
+
+	let result = block_in_place(|| unmount_os(&mount.info));
+
+	mount.shutdown.send(()).ok();
+	mount.vfs.clear_cache();
+	info!("Unmounted library {}", library.id);
+
+	result
+}
+
+/// unmount_all unmounts every library mounted, for when the node shuts down
+pub fn unmount_all() {
+	for (id, mount) in MOUNTS.lock().unwrap().drain() {
+		if let Err(e) = unmount_os(&mount.info) {
+			error!("Failed to unmount library {id}: {e:#?}");
+		}
+		mount.shutdown.send(()).ok();
+		mount.vfs.clear_cache();
+	}
+}
+
+/// mount_info returns where the library is mounted, if it is
+pub fn mount_info(library: &LibraryContext) -> Option<VfsMountInfo> {
+	MOUNTS
+		.lock()
+		.unwrap()
+		.get(&library.id)
+		.map(|mount| mount.info.clone())
+}
+
+/// mount_os mounts the server with the platform's WebDAV client, returning where it was mounted
+fn mount_os(
+	addr: SocketAddr,
+	secret: &str,
+	mount_point: &Path,
+) -> Result<Option<PathBuf>, VfsError> {
+	let mut command;
+
+	if cfg!(target_os = "windows") {
+		// the WebClient service reaches servers by their UNC path, with the port after an `@`
+		command = Command::new("net");
+		command.args([
+			"use",
+			"*",
+			format!("\\\\localhost@{}\\DavWWWRoot\\{secret}", addr.port()).as_str(),
+			"/persistent:no",
+		]);
+	} else if cfg!(target_os = "macos") {
+		std::fs::create_dir_all(mount_point)?;
+		command = Command::new("mount_webdav");
+		command
+			.args(["-S", "-v", "Spacedrive"])
+			.arg(format!("http://{addr}/{secret}/"))
+			.arg(mount_point);
+	} else {
+		// mounting needs no privileges through gvfs, which puts it under the user's runtime directory
+		command = Command::new("gio");
+		command.args(["mount", format!("dav://{addr}/{secret}/").as_str()]);
+	}
+
+	let output = command.output()?;
+	if !output.status.success() {
+		return Err(VfsError::Mount(
+			String::from_utf8_lossy(&output.stderr).trim().to_string(),
+		));
+	}
+
+	Ok(if cfg!(target_os = "windows") {
+		// "Drive Z: is now connected to ..."
+		String::from_utf8_lossy(&output.stdout)
+			.split_whitespace()
+			.find(|word| word.len() == 2 && word.ends_with(':'))
+			.map(|drive| PathBuf::from(format!("{drive}\\")))
+	} else if cfg!(target_os = "macos") {
+		Some(mount_point.to_path_buf())
+	} else {
+		std::env::var_os("XDG_RUNTIME_DIR").map(|runtime_dir| {
+			PathBuf::from(runtime_dir).join(format!(
+				"gvfs/dav:host={},port={},ssl=false,prefix=%2F{secret}",
+				addr.ip(),
+				addr.port()
+			))
+		})
+	})
+}
+
+fn unmount_os(info: &VfsMountInfo) -> Result<(), VfsError> {
+	let mut command;
+
+	if cfg!(target_os = "windows") {
+		command = Command::new("net");
+		command.arg("use");
+		match &info.path {
+			Some(path) => command.arg(path.to_string_lossy().trim_end_matches('\\')),
+			None => return Ok(()),
+		};
+		command.args(["/delete", "/y"]);
+	} else if cfg!(target_os = "macos") {
+		match &info.path {
+			Some(path) => {
+				command = Command::new("umount");
+				command.arg(path);
+			}
+			None => return Ok(()),
+		}
+	} else {
+		command = Command::new("gio");
+		command.args([
+			"mount",
+			"-u",
+			info.url.replacen("http://", "dav://", 1).as_str(),
+		]);
+	}
+
+	let output = command.output()?;
+	if !output.status.success() {
+		return Err(VfsError::Mount(
+			String::from_utf8_lossy(&output.stderr).trim().to_string(),
+		));
+	}
+
+	Ok(())
+}
@@ -0,0 +1,492 @@
+use std::{
+	collections::HashMap,
+	fs,
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::Direction;
+use tokio::task::block_in_place;
+
+use crate::{
+	library::LibraryContext,
+	location::storage::{fetch_to_cache, StorageConfig},
+	prisma::{file_path, location, object, object_in_space, space, tag, tag_on_object},
+	util::os_path::resolve_materialized_path,
+};
+
+use super::VfsError;
+
+const LOCATIONS_DIR_NAME: &str = "Locations";
+const TAGS_DIR_NAME: &str = "Tags";
+const SPACES_DIR_NAME: &str = "Spaces";
+const VIEWS_DIR_NAME: &str = "Views";
+const FAVORITES_VIEW_NAME: &str = "Favorites";
+const IMPORTANT_VIEW_NAME: &str = "Important";
+
+object::include!(object_with_file_paths { file_paths });
+
+/// A file or directory as presented by the virtual filesystem
+#[derive(Debug, Clone)]
+pub struct VfsEntry {
+	pub name: String,
+	pub is_dir: bool,
+	pub size: u64,
+	pub modified: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+enum Node {
+	Root,
+	Category(Category),
+	/// a directory of a location, by its materialized path
+	LocationDir(location::Data, String),
+	Collection(Collection),
+	File(VfsFile),
+}
+
+#[derive(Clone, Copy)]
+enum Category {
+	Locations,
+	Tags,
+	Spaces,
+	Views,
+}
+
+#[derive(Clone, Copy)]
+enum Collection {
+	Tag(i32),
+	Space(i32),
+	Favorites,
+	Important,
+}
+
+#[derive(Clone)]
+struct VfsFile {
+	location: location::Data,
+	file_path: file_path::Data,
+	size_in_bytes: Option<u64>,
+}
+
+/// `VirtualFs` presents a library as a tree of directories: its locations with everything indexed in
+/// them, and its tags, spaces and views, each holding the files of its objects. Only this node's
+/// locations are in it, as files of locations on other nodes can't be read from here.
+pub struct VirtualFs {
+	library: LibraryContext,
+	/// files of storage locations downloaded for reading, by location and path
+	cached: Mutex<HashMap<(i32, String), PathBuf>>,
+}
+
+impl VirtualFs {
+	pub fn new(library: LibraryContext) -> Self {
+		Self {
+			library,
+			cached: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// read_dir lists the directory at `path`, given as its components
+	pub async fn read_dir(&self, path: &[String]) -> Result<Vec<VfsEntry>, VfsError> {
+		let node = self.resolve(path).await?;
+		Ok(self
+			.children(&node)
+			.await?
+			.into_iter()
+			.map(|(name, child)| entry(name, &child))
+			.collect())
+	}
+
+	pub async fn stat(&self, path: &[String]) -> Result<VfsEntry, VfsError> {
+		let name = path.last().cloned().unwrap_or_default();
+		Ok(entry(name, &self.resolve(path).await?))
+	}
+
+	/// open returns where the file at `path` can be read from: the file itself, or a copy downloaded
+	/// to the cache for files of storage locations
+	pub async fn open(&self, path: &[String]) -> Result<PathBuf, VfsError> {
+		let file = match self.resolve(path).await? {
+			Node::File(file) => file,
+			_ => return Err(VfsError::NotAFile),
+		};
+
+		if let Some(local_path) = &file.location.local_path {
+			return Ok(resolve_materialized_path(
+				local_path,
+				&file.file_path.materialized_path,
+				file.file_path.raw_path.as_deref(),
+			));
+		}
+
+		let key = (file.location.id, file.file_path.materialized_path.clone());
+		if let Some(cached) = self.cached.lock().unwrap().get(&key) {
+			if cached.exists() {
+				return Ok(cached.clone());
+			}
+		}
+
+		let storage = StorageConfig::parse(file.location.storage_config.as_deref())?
+			.ok_or(VfsError::NotFound)?
+			.open(&self.library)?;
+		let cached = fetch_to_cache(
+			&self.library,
+			storage.as_ref(),
+			file.location.id,
+			&file.file_path.materialized_path,
+		)
+		.await?;
+
+		self.cached.lock().unwrap().insert(key, cached.clone());
+
+		Ok(cached)
+	}
+
+	/// clear_cache removes the files downloaded for reading
+	pub fn clear_cache(&self) {
+		for (_, cached) in self.cached.lock().unwrap().drain() {
+			fs::remove_file(cached).ok();
+		}
+	}
+
+	async fn resolve(&self, path: &[String]) -> Result<Node, VfsError> {
+		let mut node = Node::Root;
+
+		for (i, name) in path.iter().enumerate() {
+			// paths in locations are looked up directly, rather than one directory at a time
+			if let Node::LocationDir(location, dir) = &node {
+				let materialized_path = match dir.as_str() {
+					"" => path[i..].join("/"),
+					dir => format!("{dir}/{}", path[i..].join("/")),
+				};
+				return self.resolve_in_location(location, materialized_path).await;
+			}
+
+			node = self
+				.children(&node)
+				.await?
+				.into_iter()
+				.find(|(child_name, _)| child_name == name)
+				.map(|(_, child)| child)
+				.ok_or(VfsError::NotFound)?;
+		}
+
+		Ok(node)
+	}
+
+	async fn resolve_in_location(
+		&self,
+		location: &location::Data,
+		materialized_path: String,
+	) -> Result<Node, VfsError> {
+		let file_path = self
+			.library
+			.db
+			.file_path()
+			.find_first(vec![
+				file_path::location_id::equals(location.id),
+				file_path::materialized_path::equals(materialized_path.clone()),
+			])
+			.with(file_path::object::fetch())
+			.exec()
+			.await?
+			.ok_or(VfsError::NotFound)?;
+
+		Ok(location_node(location, file_path))
+	}
+
+	async fn children(&self, node: &Node) -> Result<Vec<(String, Node)>, VfsError> {
+		let db = &self.library.db;
+
+		Ok(match node {
+			Node::Root => vec![
+				(
+					LOCATIONS_DIR_NAME.to_string(),
+					Node::Category(Category::Locations),
+				),
+				(TAGS_DIR_NAME.to_string(), Node::Category(Category::Tags)),
+				(
+					SPACES_DIR_NAME.to_string(),
+					Node::Category(Category::Spaces),
+				),
+				(VIEWS_DIR_NAME.to_string(), Node::Category(Category::Views)),
+			],
+			Node::Category(Category::Locations) => unique_names(
+				self.locations()
+					.await?
+					.into_iter()
+					.map(|location| {
+						let name = location
+							.name
+							.clone()
+							.unwrap_or_else(|| format!("Location {}", location.id));
+						(name, Node::LocationDir(location, String::new()))
+					})
+					.collect(),
+			),
+			Node::Category(Category::Tags) => unique_names(
+				db.tag()
+					.find_many(vec![])
+					.order_by(tag::id::order(Direction::Asc))
+					.exec()
+					.await?
+					.into_iter()
+					.map(|tag| {
+						let name = tag.name.unwrap_or_else(|| format!("Tag {}", tag.id));
+						(name, Node::Collection(Collection::Tag(tag.id)))
+					})
+					.collect(),
+			),
+			Node::Category(Category::Spaces) => unique_names(
+				db.space()
+					.find_many(vec![])
+					.order_by(space::id::order(Direction::Asc))
+					.exec()
+					.await?
+					.into_iter()
+					.map(|space| {
+						let name = space.name.unwrap_or_else(|| format!("Space {}", space.id));
+						(name, Node::Collection(Collection::Space(space.id)))
+					})
+					.collect(),
+			),
+			Node::Category(Category::Views) => vec![
+				(
+					FAVORITES_VIEW_NAME.to_string(),
+					Node::Collection(Collection::Favorites),
+				),
+				(
+					IMPORTANT_VIEW_NAME.to_string(),
+					Node::Collection(Collection::Important),
+				),
+			],
+			Node::LocationDir(location, dir) => {
+				let dir_id = db
+					.file_path()
+					.find_first(vec![
+						file_path::location_id::equals(location.id),
+						file_path::materialized_path::equals(dir.clone()),
+						file_path::is_dir::equals(true),
+					])
+					.exec()
+					.await?
+					.ok_or(VfsError::NotFound)?
+					.id;
+
+				db.file_path()
+					.find_many(vec![
+						file_path::location_id::equals(location.id),
+						file_path::parent_id::equals(Some(dir_id)),
+					])
+					.with(file_path::object::fetch())
+					.exec()
+					.await?
+					.into_iter()
+					.map(|file_path| {
+						let name = Path::new(&file_path.materialized_path)
+							.file_name()
+							.map(|name| name.to_string_lossy().to_string())
+							.unwrap_or_default();
+
+						(name, location_node(location, file_path))
+					})
+					.collect()
+			}
+			Node::Collection(collection) => self.collection_files(*collection).await?,
+			Node::File(_) => return Err(VfsError::NotADirectory),
+		})
+	}
+
+	async fn locations(&self) -> Result<Vec<location::Data>, VfsError> {
+		Ok(self
+			.library
+			.db
+			.location()
+			.find_many(vec![location::node_id::equals(self.library.node_local_id)])
+			.order_by(location::id::order(Direction::Asc))
+			.exec()
+			.await?)
+	}
+
+	/// collection_files lists a file for each object of the collection that has one in a location of
+	/// this node, preferring files on the filesystem over ones in object storage
+	async fn collection_files(
+		&self,
+		collection: Collection,
+	) -> Result<Vec<(String, Node)>, VfsError> {
+		let filter = match collection {
+			Collection::Tag(tag_id) => {
+				object::tags::some(vec![tag_on_object::tag_id::equals(tag_id)])
+			}
+			Collection::Space(space_id) => {
+				object::spaces::some(vec![object_in_space::space_id::equals(space_id)])
+			}
+			Collection::Favorites => object::favorite::equals(true),
+			Collection::Important => object::important::equals(true),
+		};
+
+		let locations = self
+			.locations()
+			.await?
+			.into_iter()
+			.map(|location| (location.id, location))
+			.collect::<HashMap<_, _>>();
+
+		let objects = self
+			.library
+			.db
+			.object()
+			.find_many(vec![filter])
+			.order_by(object::id::order(Direction::Asc))
+			.include(object_with_file_paths::include())
+			.exec()
+			.await?;
+
+		Ok(unique_names(
+			objects
+				.into_iter()
+				.filter_map(|object| {
+					let size_in_bytes = object.size_in_bytes.parse().ok();
+					let (location, file_path) = object
+						.file_paths
+						.into_iter()
+						.filter(|file_path| !file_path.is_dir)
+						.filter_map(|file_path| {
+							locations
+								.get(&file_path.location_id)
+								.map(|location| (location, file_path))
+						})
+						.min_by_key(|(location, _)| location.local_path.is_none())?;
+
+					let name = Path::new(&file_path.materialized_path)
+						.file_name()?
+						.to_string_lossy()
+						.to_string();
+
+					Some((
+						name,
+						Node::File(VfsFile {
+							location: location.clone(),
+							file_path,
+							size_in_bytes,
+						}),
+					))
+				})
+				.collect(),
+		))
+	}
+}
+
+fn location_node(location: &location::Data, file_path: file_path::Data) -> Node {
+	if file_path.is_dir {
+		return Node::LocationDir(location.clone(), file_path.materialized_path);
+	}
+
+	Node::File(VfsFile {
+		location: location.clone(),
+		size_in_bytes: file_path
+			.object()
+			.ok()
+			.flatten()
+			.and_then(|object| object.size_in_bytes.parse().ok()),
+		file_path,
+	})
+}
+
+fn entry(name: String, node: &Node) -> VfsEntry {
+	match node {
+		Node::File(file) => {
+			// files on the filesystem are looked at, as they may have changed since they were indexed
+			let metadata = file.location.local_path.as_ref().and_then(|local_path| {
+				block_in_place(|| {
+					fs::metadata(resolve_materialized_path(
+						local_path,
+						&file.file_path.materialized_path,
+						file.file_path.raw_path.as_deref(),
+					))
+					.ok()
+				})
+			});
+
+			VfsEntry {
+				name,
+				is_dir: false,
+				size: metadata
+					.as_ref()
+					.map(|metadata| metadata.len())
+					.or(file.size_in_bytes)
+					.unwrap_or(0),
+				modified: metadata
+					.and_then(|metadata| metadata.modified().ok())
+					.map(DateTime::<Utc>::from)
+					.unwrap_or_else(|| file.file_path.date_modified.into()),
+			}
+		}
+		_ => VfsEntry {
+			name,
+			is_dir: true,
+			size: 0,
+			modified: Utc::now(),
+		},
+	}
+}
+
+/// unique_names numbers entries that would otherwise share a name in the same directory, in the
+/// order given, so the same entry keeps its name from one listing to the next
+fn unique_names<T>(entries: Vec<(String, T)>) -> Vec<(String, T)> {
+	let mut seen = HashMap::<String, usize>::new();
+
+	entries
+		.into_iter()
+		.map(|(name, entry)| {
+			// names can't hold separators, which the index would have for locations named after paths
+			let name = name.replace('/', "_");
+
+			let count = seen.entry(name.clone()).or_insert(0);
+			*count += 1;
+			if *count == 1 {
+				return (name, entry);
+			}
+
+			let path = Path::new(&name);
+			let numbered = match (path.file_stem(), path.extension()) {
+				(Some(stem), Some(extension)) => format!(
+					"{} ({count}).{}",
+					stem.to_string_lossy(),
+					extension.to_string_lossy()
+				),
+				_ => format!("{name} ({count})"),
+			};
+			(numbered, entry)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn numbers_duplicate_names() {
+		let names = unique_names(vec![
+			("report.pdf".to_string(), ()),
+			("report.pdf".to_string(), ()),
+			("Photos".to_string(), ()),
+			("Photos".to_string(), ()),
+			("a/b".to_string(), ()),
+		])
+		.into_iter()
+		.map(|(name, _)| name)
+		.collect::<Vec<_>>();
+
+		assert_eq!(
+			names,
+			vec![
+				"report.pdf",
+				"report (2).pdf",
+				"Photos",
+				"Photos (2)",
+				"a_b"
+			]
+		);
+	}
+}
@@ -0,0 +1,403 @@
+use std::{convert::Infallible, net::SocketAddr, ops::Range, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use hyper::{
+	body::Bytes,
+	header,
+	service::{make_service_fn, service_fn},
+	Body, HeaderMap, Method, Request, Response, Server, StatusCode,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use tokio::{
+	fs::File,
+	io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
+	sync::oneshot,
+};
+use tracing::{debug, error};
+
+use super::{
+	tree::{VfsEntry, VirtualFs},
+	VfsError,
+};
+
+/// The mount is read only, so these are the only methods clients get to use
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+/// How much of a file is read at a time while sending it
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Characters escaped in the path segments of hrefs
+const SEGMENT: &AsciiSet = &CONTROLS
+	.add(b' ')
+	.add(b'"')
+	.add(b'#')
+	.add(b'%')
+	.add(b'/')
+	.add(b'<')
+	.add(b'>')
+	.add(b'?')
+	.add(b'[')
+	.add(b']')
+	.add(b'^')
+	.add(b'`')
+	.add(b'{')
+	.add(b'|')
+	.add(b'}');
+
+/// serve starts a read only WebDAV server for the virtual filesystem on a free port of the loopback
+/// interface, which the platform's WebDAV client can mount as a drive. It runs until `shutdown`.
+/// Every other process on the machine can reach the port, so the library is only served under
+/// `/<secret>/`.
+pub fn serve(
+	vfs: Arc<VirtualFs>,
+	secret: String,
+	shutdown: oneshot::Receiver<()>,
+) -> Result<SocketAddr, VfsError> {
+	let secret: Arc<str> = secret.into();
+	let make_service = make_service_fn(move |_| {
+		let vfs = Arc::clone(&vfs);
+		let secret = Arc::clone(&secret);
+		async move {
+			Ok::<_, Infallible>(service_fn(move |request| {
+				let vfs = Arc::clone(&vfs);
+				let secret = Arc::clone(&secret);
+				async move { Ok::<_, Infallible>(handle(&vfs, &secret, request).await) }
+			}))
+		}
+	});
+
+	let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+		.map_err(|e| VfsError::Server(e.to_string()))?
+		.serve(make_service);
+	let addr = server.local_addr();
+
+	tokio::spawn(async move {
+		if let Err(e) = server
+			.with_graceful_shutdown(async {
+				shutdown.await.ok();
+			})
+			.await
+		{
+			error!("Virtual filesystem server failed: {e:#?}");
+		}
+	});
+
+	Ok(addr)
+}
+
+async fn handle(vfs: &VirtualFs, secret: &str, request: Request<Body>) -> Response<Body> {
+	if !is_loopback_host(request.headers()) {
+		return status(StatusCode::FORBIDDEN);
+	}
+
+	let path = match decode_path(request.uri().path()) {
+		Some(path) => path,
+		None => return status(StatusCode::BAD_REQUEST),
+	};
+	let path = match path.split_first() {
+		Some((first, path)) if first == secret => path.to_vec(),
+		_ => return status(StatusCode::NOT_FOUND),
+	};
+
+	debug!("{} {}", request.method(), request.uri().path());
+
+	let response = match request.method() {
+		&Method::OPTIONS => Ok(Response::builder()
+			.header("DAV", "1")
+			.header(header::ALLOW, ALLOWED_METHODS)
+			.body(Body::empty())
+			.expect("valid response")),
+		&Method::GET | &Method::HEAD => {
+			get(
+				vfs,
+				&path,
+				request.headers(),
+				request.method() == Method::HEAD,
+			)
+			.await
+		}
+		method if method.as_str() == "PROPFIND" => {
+			propfind(vfs, secret, &path, request.headers()).await
+		}
+		_ => Ok(Response::builder()
+			.status(StatusCode::METHOD_NOT_ALLOWED)
+			.header(header::ALLOW, ALLOWED_METHODS)
+			.body(Body::empty())
+			.expect("valid response")),
+	};
+
+	response.unwrap_or_else(|e| match e {
+		VfsError::NotFound | VfsError::NotADirectory => status(StatusCode::NOT_FOUND),
+		VfsError::NotAFile => status(StatusCode::METHOD_NOT_ALLOWED),
+		e => {
+			error!("Error serving {}: {e:#?}", request.uri().path());
+			status(StatusCode::INTERNAL_SERVER_ERROR)
+		}
+	})
+}
+
+async fn propfind(
+	vfs: &VirtualFs,
+	secret: &str,
+	path: &[String],
+	headers: &HeaderMap,
+) -> Result<Response<Body>, VfsError> {
+	let entry = vfs.stat(path).await?;
+
+	let mut responses = vec![prop_response(&href(secret, path, true), &entry)];
+	// depth 0 asks for the entry itself, anything else lists a directory one level deep
+	let depth = headers
+		.get("Depth")
+		.and_then(|depth| depth.to_str().ok())
+		.unwrap_or("1");
+	if entry.is_dir && depth != "0" {
+		for child in vfs.read_dir(path).await? {
+			let mut child_path = path.to_vec();
+			child_path.push(child.name.clone());
+			responses.push(prop_response(
+				&href(secret, &child_path, child.is_dir),
+				&child,
+			));
+		}
+	}
+
+	Ok(Response::builder()
+		.status(StatusCode::MULTI_STATUS)
+		.header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+		.body(Body::from(format!(
+			r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">{}</D:multistatus>"#,
+			responses.join("")
+		)))
+		.expect("valid response"))
+}
+
+async fn get(
+	vfs: &VirtualFs,
+	path: &[String],
+	headers: &HeaderMap,
+	head: bool,
+) -> Result<Response<Body>, VfsError> {
+	let entry = vfs.stat(path).await?;
+	if entry.is_dir {
+		return Err(VfsError::NotAFile);
+	}
+
+	let mut file = File::open(vfs.open(path).await?).await?;
+	let size = file.metadata().await?.len();
+
+	let mut response = Response::builder()
+		.header(header::CONTENT_TYPE, "application/octet-stream")
+		.header(header::ACCEPT_RANGES, "bytes")
+		.header(header::LAST_MODIFIED, http_date(entry.modified));
+
+	let range = match headers
+		.get(header::RANGE)
+		.and_then(|range| range.to_str().ok())
+	{
+		Some(range) => match parse_range(range, size) {
+			Some(range) => {
+				response = response.status(StatusCode::PARTIAL_CONTENT).header(
+					header::CONTENT_RANGE,
+					format!("bytes {}-{}/{size}", range.start, range.end - 1),
+				);
+				range
+			}
+			None => {
+				return Ok(Response::builder()
+					.status(StatusCode::RANGE_NOT_SATISFIABLE)
+					.header(header::CONTENT_RANGE, format!("bytes */{size}"))
+					.body(Body::empty())
+					.expect("valid response"))
+			}
+		},
+		None => 0..size,
+	};
+
+	let response = response.header(header::CONTENT_LENGTH, range.end - range.start);
+	if head {
+		return Ok(response.body(Body::empty()).expect("valid response"));
+	}
+
+	file.seek(SeekFrom::Start(range.start)).await?;
+
+	let (mut sender, body) = Body::channel();
+	tokio::spawn(async move {
+		let mut remaining = range.end - range.start;
+		let mut buf = vec![0; READ_CHUNK_SIZE];
+
+		while remaining > 0 {
+			let to_read = remaining.min(READ_CHUNK_SIZE as u64) as usize;
+			let read = match file.read(&mut buf[..to_read]).await {
+				Ok(0) => break,
+				Ok(read) => read,
+				Err(e) => {
+					error!("Error reading file for the virtual filesystem: {e:#?}");
+					sender.abort();
+					return;
+				}
+			};
+
+			// the client went away
+			if sender
+				.send_data(Bytes::copy_from_slice(&buf[..read]))
+				.await
+				.is_err()
+			{
+				return;
+			}
+			remaining -= read as u64;
+		}
+	});
+
+	Ok(response.body(body).expect("valid response"))
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.body(Body::empty())
+		.expect("valid response")
+}
+
+fn prop_response(href: &str, entry: &VfsEntry) -> String {
+	let (resource_type, content_length) = if entry.is_dir {
+		("<D:collection/>".to_string(), String::new())
+	} else {
+		(
+			String::new(),
+			format!("<D:getcontentlength>{}</D:getcontentlength>", entry.size),
+		)
+	};
+
+	format!(
+		"<D:response><D:href>{href}</D:href><D:propstat><D:prop>\
+		<D:displayname>{}</D:displayname><D:resourcetype>{resource_type}</D:resourcetype>\
+		{content_length}<D:getlastmodified>{}</D:getlastmodified>\
+		</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+		xml_escape(&entry.name),
+		http_date(entry.modified)
+	)
+}
+
+/// decode_path splits a request path into its components, `None` if they don't make a valid path
+fn decode_path(path: &str) -> Option<Vec<String>> {
+	path.split('/')
+		.filter(|segment| !segment.is_empty())
+		.map(|segment| {
+			let segment = percent_decode_str(segment).decode_utf8().ok()?.to_string();
+			match segment.as_str() {
+				"." | ".." => None,
+				_ if segment.contains('/') => None,
+				_ => Some(segment),
+			}
+		})
+		.collect()
+}
+
+fn href(secret: &str, path: &[String], is_dir: bool) -> String {
+	let mut href = format!("/{secret}/");
+	for (i, segment) in path.iter().enumerate() {
+		if i > 0 {
+			href.push('/');
+		}
+		href.extend(utf8_percent_encode(segment, SEGMENT));
+	}
+	if is_dir && !path.is_empty() {
+		href.push('/');
+	}
+	href
+}
+
+/// is_loopback_host checks the request was addressed to the loopback interface, so a web page can't
+/// reach the server through a domain resolving to it
+fn is_loopback_host(headers: &HeaderMap) -> bool {
+	let host = match headers
+		.get(header::HOST)
+		.and_then(|host| host.to_str().ok())
+	{
+		Some(host) => host,
+		None => return false,
+	};
+
+	let host = match host.rsplit_once(':') {
+		Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+		_ => host,
+	};
+
+	matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+/// parse_range reads a single range `Range` header, `None` if it can't be satisfied
+fn parse_range(range: &str, size: u64) -> Option<Range<u64>> {
+	let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+
+	let range = match (start.trim(), end.trim()) {
+		// the last bytes of the file
+		("", suffix) => size.saturating_sub(suffix.parse().ok()?)..size,
+		(start, "") => start.parse().ok()?..size,
+		(start, end) => start.parse().ok()?..(end.parse::<u64>().ok()? + 1).min(size),
+	};
+
+	(range.start < range.end).then_some(range)
+}
+
+fn http_date(date: DateTime<Utc>) -> String {
+	date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn xml_escape(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_ranges() {
+		assert_eq!(parse_range("bytes=0-99", 1000), Some(0..100));
+		assert_eq!(parse_range("bytes=900-", 1000), Some(900..1000));
+		assert_eq!(parse_range("bytes=-100", 1000), Some(900..1000));
+		assert_eq!(parse_range("bytes=990-2000", 1000), Some(990..1000));
+		assert_eq!(parse_range("bytes=1000-", 1000), None);
+		assert_eq!(parse_range("items=0-1", 1000), None);
+	}
+
+	#[test]
+	fn maps_paths_and_hrefs() {
+		let path = decode_path("/Tags/Work%20stuff/report%231.pdf").unwrap();
+		assert_eq!(path, vec!["Tags", "Work stuff", "report#1.pdf"]);
+		assert_eq!(
+			href("s3cret", &path, false),
+			"/s3cret/Tags/Work%20stuff/report%231.pdf"
+		);
+		assert_eq!(
+			href("s3cret", &path[..2], true),
+			"/s3cret/Tags/Work%20stuff/"
+		);
+		assert_eq!(href("s3cret", &[], true), "/s3cret/");
+
+		assert_eq!(decode_path("/Locations/../secret"), None);
+		assert_eq!(decode_path("/a%2Fb"), None);
+	}
+
+	#[test]
+	fn accepts_only_loopback_hosts() {
+		let host = |host: &str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(header::HOST, host.parse().unwrap());
+			is_loopback_host(&headers)
+		};
+
+		assert!(host("127.0.0.1:4821"));
+		assert!(host("localhost:4821"));
+		assert!(host("[::1]:4821"));
+		assert!(!host("attacker.example:4821"));
+		assert!(!host("localhost.attacker.example"));
+		assert!(!is_loopback_host(&HeaderMap::new()));
+	}
+}
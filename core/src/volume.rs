@@ -2,7 +2,10 @@ use crate::{library::LibraryContext, prisma::volume::*};
 
 use rspc::Type;
 use serde::{Deserialize, Serialize};
-use std::process::Command;
+use std::{
+	path::{Path, PathBuf},
+	process::Command,
+};
 use sysinfo::{DiskExt, System, SystemExt};
 use thiserror::Error;
 
@@ -16,6 +19,158 @@ pub struct Volume {
 	pub disk_type: Option<String>,
 	pub file_system: Option<String>,
 	pub is_root_filesystem: bool,
+	/// the filesystem's serial number or UUID, if the platform lets us read it
+	pub serial: Option<String>,
+}
+
+/// VolumeFingerprint identifies a volume independently of where it's mounted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VolumeFingerprint {
+	#[serde(default)]
+	pub serial: Option<String>,
+	pub name: String,
+	pub total_capacity: u64,
+	pub file_system: Option<String>,
+}
+
+impl From<&Volume> for VolumeFingerprint {
+	fn from(volume: &Volume) -> Self {
+		Self {
+			serial: volume.serial.clone(),
+			name: volume.name.clone(),
+			total_capacity: volume.total_capacity,
+			file_system: volume.file_system.clone(),
+		}
+	}
+}
+
+impl VolumeFingerprint {
+	/// matches checks if both fingerprints are of the same volume, preferring the serial when both sides have one
+	pub fn matches(&self, other: &Self) -> bool {
+		match (&self.serial, &other.serial) {
+			(Some(serial), Some(other_serial)) => {
+				serial == other_serial && self.total_capacity == other.total_capacity
+			}
+			_ => {
+				self.name == other.name
+					&& self.total_capacity == other.total_capacity
+					&& self.file_system == other.file_system
+			}
+		}
+	}
+}
+
+/// find_volume returns the volume `path` is on, along with the path relative to its mount point
+pub fn find_volume<'a>(volumes: &'a [Volume], path: &Path) -> Option<(&'a Volume, PathBuf)> {
+	volumes
+		.iter()
+		.filter_map(|volume| {
+			path.strip_prefix(&volume.mount_point)
+				.ok()
+				.map(|relative_path| (volume, relative_path.to_path_buf()))
+		})
+		// the longest mount point is the most specific one
+		.max_by_key(|(volume, _)| volume.mount_point.len())
+}
+
+/// Space left free on top of what a job needs, so it doesn't fill the disk to the last byte
+const RESERVED_SPACE: u64 = 64 * 1024 * 1024;
+
+/// Returned by [`ensure_space`] when a volume can't fit what's about to be written to it
+#[derive(Error, Debug)]
+#[error(
+	"not enough space on '{}': {required} bytes needed but only {available} bytes available",
+	path.display()
+)]
+pub struct InsufficientSpace {
+	pub path: PathBuf,
+	pub required: u64,
+	pub available: u64,
+}
+
+/// volume_of returns the volume `path` would be written to. The path doesn't have to exist yet, its
+/// closest existing ancestor is used instead.
+pub fn volume_of(path: &Path) -> Option<Volume> {
+	let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+	let existing = std::fs::canonicalize(existing).ok()?;
+	let volumes = get_volumes().ok()?;
+
+	find_volume(&volumes, &existing).map(|(volume, _)| volume.clone())
+}
+
+/// available_space returns the free space of the volume `path` would be written to
+pub fn available_space(path: &Path) -> Option<u64> {
+	volume_of(path).map(|volume| volume.available_capacity)
+}
+
+/// ensure_space checks that `required` bytes can be written to `path` before a job starts writing
+/// them, so it fails right away instead of halfway through with a partial result. When the volume
+/// can't be found, the job is let through.
+pub fn ensure_space(path: &Path, required: u64) -> Result<(), InsufficientSpace> {
+	match available_space(path) {
+		Some(available) if available < required.saturating_add(RESERVED_SPACE) => {
+			Err(InsufficientSpace {
+				path: path.to_path_buf(),
+				required,
+				available,
+			})
+		}
+		_ => Ok(()),
+	}
+}
+
+/// volume_serial reads the serial number or UUID of the filesystem on `device`, mounted at `mount_point`
+fn volume_serial(device: &str, mount_point: &str) -> Option<String> {
+	#[cfg(target_os = "linux")]
+	{
+		// udev keeps a symlink to the device for every filesystem UUID
+		let device = std::fs::canonicalize(device).ok()?;
+		let _ = mount_point;
+		std::fs::read_dir("/dev/disk/by-uuid")
+			.ok()?
+			.filter_map(Result::ok)
+			.find(|entry| {
+				std::fs::canonicalize(entry.path())
+					.map(|target| target == device)
+					.unwrap_or(false)
+			})
+			.map(|entry| entry.file_name().to_string_lossy().to_string())
+	}
+
+	#[cfg(target_os = "macos")]
+	{
+		let _ = device;
+		let output = Command::new("diskutil")
+			.args(["info", mount_point])
+			.output()
+			.ok()?;
+		String::from_utf8_lossy(&output.stdout)
+			.lines()
+			.find_map(|line| line.trim().strip_prefix("Volume UUID:"))
+			.map(|uuid| uuid.trim().to_string())
+	}
+
+	#[cfg(target_os = "windows")]
+	{
+		let _ = device;
+		let drive = mount_point.trim_end_matches('\\');
+		let output = Command::new("cmd")
+			.args(["/C", "vol", drive])
+			.output()
+			.ok()?;
+		// "Volume Serial Number is 1234-ABCD"
+		String::from_utf8_lossy(&output.stdout)
+			.lines()
+			.find(|line| line.contains("Serial Number"))
+			.and_then(|line| line.split_whitespace().last())
+			.map(ToString::to_string)
+	}
+
+	#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+	{
+		let _ = (device, mount_point);
+		None
+	}
 }
 
 #[derive(Error, Debug)]
@@ -24,6 +179,10 @@ pub enum VolumeError {
 	DatabaseErr(#[from] prisma_client_rust::QueryError),
 	#[error("FromUtf8Error: {0}")]
 	FromUtf8Error(#[from] std::string::FromUtf8Error),
+	#[error("I/O error: {0}")]
+	IOError(#[from] std::io::Error),
+	#[error("Failed to eject volume '{0}': {1}")]
+	EjectFailed(String, String),
 }
 
 impl From<VolumeError> for rspc::Error {
@@ -71,6 +230,7 @@ pub async fn save_volume(ctx: &LibraryContext) -> Result<(), VolumeError> {
 	Ok(())
 }
 
+// Adapted from: https://github.com/kimlimjustin/xplorer/blob/f4f3590d06783d64949766cc2975205a3b689a56/src-tauri/src/drives.rs
 // TODO: Error handling in this function
 pub fn get_volumes() -> Result<Vec<Volume>, VolumeError> {
 	System::new_all()
@@ -118,7 +278,10 @@ pub fn get_volumes() -> Result<Vec<Volume>, VolumeError> {
 				}
 			}
 
+			let serial = volume_serial(disk.name().to_str().unwrap_or_default(), &mount_point);
+
 			(!mount_point.starts_with("/System")).then_some(Ok(Volume {
+				serial,
 				name,
 				is_root_filesystem: mount_point == "/",
 				mount_point,
@@ -132,11 +295,72 @@ pub fn get_volumes() -> Result<Vec<Volume>, VolumeError> {
 		.collect::<Result<Vec<_>, _>>()
 }
 
-// #[test]
-// fn test_get_volumes() {
-//   let volumes = get_volumes()?;
-//   dbg!(&volumes);
-//   assert!(volumes.len() > 0);
-// }
+/// eject_volume unmounts a removable volume and, where the platform can, powers off its drive so
+/// it can be unplugged
+pub fn eject_volume(volume: &Volume) -> Result<(), VolumeError> {
+	let mut commands = Vec::new();
 
-// Adapted from: https://github.com/kimlimjustin/xplorer/blob/f4f3590d06783d64949766cc2975205a3b689a56/src-tauri/src/drives.rs
+	if cfg!(target_os = "macos") {
+		let mut command = Command::new("diskutil");
+		command.args(["eject", &volume.mount_point]);
+		commands.push(command);
+	} else if cfg!(target_os = "windows") {
+		let drive = volume.mount_point.trim_end_matches('\\');
+		let mut command = Command::new("powershell");
+		command.args([
+			"-NoProfile",
+			"-Command",
+			&format!(
+				"(New-Object -ComObject Shell.Application).Namespace(17)\
+				.ParseName('{drive}').InvokeVerb('Eject')"
+			),
+		]);
+		commands.push(command);
+	} else {
+		// udisks lets the user unmount what they plugged in without being root
+		let mut unmount = Command::new("udisksctl");
+		unmount.args(["unmount", "--block-device", &volume.name]);
+		commands.push(unmount);
+		let mut power_off = Command::new("udisksctl");
+		power_off.args(["power-off", "--block-device", &volume.name]);
+		commands.push(power_off);
+	}
+
+	for mut command in commands {
+		let output = command.output()?;
+		if !output.status.success() {
+			return Err(VolumeError::EjectFailed(
+				volume.name.clone(),
+				String::from_utf8_lossy(&output.stderr).trim().to_string(),
+			));
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn volume(name: &str, mount_point: &str) -> Volume {
+		Volume {
+			name: name.to_string(),
+			mount_point: mount_point.to_string(),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn finds_most_specific_volume() {
+		let volumes = vec![volume("root", "/"), volume("photos", "/mnt/photos")];
+
+		let (found, relative_path) =
+			find_volume(&volumes, Path::new("/mnt/photos/2022/summer")).unwrap();
+		assert_eq!(found.name, "photos");
+		assert_eq!(relative_path, PathBuf::from("2022/summer"));
+
+		let (found, _) = find_volume(&volumes, Path::new("/home/user")).unwrap();
+		assert_eq!(found.name, "root");
+	}
+}
@@ -0,0 +1,80 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sd_codec::{compress, decompress, Codec};
+
+const KB: usize = 1024;
+
+const SIZE: usize = KB * 1024;
+
+const CODECS: [Codec; 6] = [
+	Codec::None,
+	Codec::Lz4,
+	Codec::Zstd(1),
+	Codec::Zstd(3),
+	Codec::Zstd(9),
+	Codec::Zstd(19),
+];
+
+/// Lines like the ones in log files and sync payloads, which repeat a lot
+fn text(size: usize) -> Vec<u8> {
+	let mut text = String::with_capacity(size);
+	let mut i = 0u64;
+	while text.len() < size {
+		text.push_str(&format!(
+			"{{\"id\":{i},\"model\":\"file_path\",\"field\":\"name\",\"value\":\"IMG_{:04}.jpg\",\"timestamp\":{}}}\n",
+			i % 9000,
+			1_670_000_000_000u64 + i * 37
+		));
+		i += 1;
+	}
+	text.truncate(size);
+	text.into_bytes()
+}
+
+/// Bytes that don't compress, like thumbnails or media that's already compressed
+fn noise(size: usize) -> Vec<u8> {
+	let mut state = 0x2545_f491_4f6c_dd1du64;
+	(0..size)
+		.map(|_| {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			state as u8
+		})
+		.collect()
+}
+
+fn bench(c: &mut Criterion) {
+	for (name, data) in [("text", text(SIZE)), ("noise", noise(SIZE))] {
+		let mut group = c.benchmark_group(format!("codecs-{name}"));
+		group.throughput(criterion::Throughput::Bytes(SIZE as u64));
+
+		for codec in CODECS {
+			let compressed = compress(codec, &data).unwrap();
+			let id = format!("{codec:?}");
+
+			println!(
+				"{name} {id}: {} of {SIZE} bytes ({:.1}%)",
+				compressed.len(),
+				compressed.len() as f64 / SIZE as f64 * 100.0
+			);
+
+			group.bench_function(BenchmarkId::new("compress", &id), |b| {
+				b.iter(|| compress(codec, &data).unwrap())
+			});
+
+			group.bench_function(BenchmarkId::new("decompress", &id), |b| {
+				b.iter(|| decompress(&compressed).unwrap())
+			});
+		}
+
+		group.finish();
+	}
+}
+
+criterion_group!(
+	name = benches;
+	config = Criterion::default();
+	targets = bench
+);
+
+criterion_main!(benches);
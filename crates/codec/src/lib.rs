@@ -0,0 +1,247 @@
+//! Compression for the artifacts Spacedrive stores or sends for itself, like library exports, sync
+//! payloads, rotated logs and archived data. Each kind of [`Artifact`] has a [`Codec`] picked for it,
+//! as they are written and read in very different ways: sync payloads are made while a peer waits on
+//! them, while archived data is written once and kept for a long time.
+//!
+//! [`compress`] and [`Decoder::framed`] put a short header in front of the compressed data naming the
+//! codec, so the codec an artifact uses can change without breaking what was stored before. The
+//! benchmarks in `benches/codecs.rs` compare the codecs on data like these artifacts, and are what the
+//! defaults in [`Artifact::codec`] should be checked against when they change.
+
+use std::io::{self, BufReader, Read, Write};
+
+use thiserror::Error;
+
+/// Comes before the id of the codec in framed data
+const MAGIC: &[u8; 3] = b"sdc";
+
+#[derive(Error, Debug)]
+pub enum Error {
+	#[error("I/O error: {0}")]
+	IOError(#[from] io::Error),
+	#[error("The data wasn't compressed with a known codec")]
+	UnknownFormat,
+	#[error("Unknown codec {0}")]
+	UnknownCodec(u8),
+	#[error("lz4 error: {0}")]
+	Lz4(#[from] lz4_flex::frame::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A way of compressing data. Levels only matter when compressing, anything compressed with zstd is
+/// decompressed the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+	feature = "serde",
+	derive(serde::Serialize, serde::Deserialize),
+	serde(tag = "codec", content = "level", rename_all = "snake_case")
+)]
+#[cfg_attr(feature = "rspc", derive(specta::Type))]
+pub enum Codec {
+	None,
+	Lz4,
+	/// zstd at a level from 1 to 22, higher levels are slower but smaller
+	Zstd(i32),
+}
+
+impl Codec {
+	fn id(&self) -> u8 {
+		match self {
+			Self::None => 0,
+			Self::Lz4 => 1,
+			Self::Zstd(_) => 2,
+		}
+	}
+
+	fn from_id(id: u8) -> Result<Self> {
+		match id {
+			0 => Ok(Self::None),
+			1 => Ok(Self::Lz4),
+			2 => Ok(Self::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)),
+			id => Err(Error::UnknownCodec(id)),
+		}
+	}
+
+	/// extension returns what goes at the end of the name of a file compressed with the codec
+	pub fn extension(&self) -> Option<&'static str> {
+		match self {
+			Self::None => None,
+			Self::Lz4 => Some("lz4"),
+			Self::Zstd(_) => Some("zst"),
+		}
+	}
+
+	/// encoder compresses what's written to it into `writer`, without a header, so files written with it
+	/// can be opened by other tools. [`Encoder::finish`] has to be called once everything is written.
+	pub fn encoder<W: Write>(self, writer: W) -> Result<Encoder<W>> {
+		Ok(match self {
+			Self::None => Encoder::None(writer),
+			Self::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(writer)),
+			Self::Zstd(level) => Encoder::Zstd(zstd::Encoder::new(writer, level)?),
+		})
+	}
+
+	/// framed_encoder is [`Codec::encoder`] with the header [`Decoder::framed`] reads the codec from
+	pub fn framed_encoder<W: Write>(self, mut writer: W) -> Result<Encoder<W>> {
+		writer.write_all(MAGIC)?;
+		writer.write_all(&[self.id()])?;
+		self.encoder(writer)
+	}
+
+	/// decoder decompresses what's read from `reader`, which was written by [`Codec::encoder`]
+	pub fn decoder<R: Read>(self, reader: R) -> Result<Decoder<R>> {
+		Ok(match self {
+			Self::None => Decoder::None(reader),
+			Self::Lz4 => Decoder::Lz4(lz4_flex::frame::FrameDecoder::new(reader)),
+			Self::Zstd(_) => Decoder::Zstd(zstd::Decoder::new(reader)?),
+		})
+	}
+}
+
+/// What Spacedrive compresses, each with the codec that suits how it's used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Artifact {
+	/// log files moved aside once they grow too big, text that compresses well and is rarely read again
+	OperationLog,
+	/// a library's database and thumbnails, made and read by the user now and then. Thumbnails are
+	/// already compressed, so higher levels mostly spend time on them for nothing.
+	LibraryExport,
+	/// operations sent to a peer catching up, made while it waits on them
+	SyncPayload,
+	/// files archived to free up space, written once and kept for a long time
+	ColdData,
+}
+
+impl Artifact {
+	/// codec returns the codec an artifact is compressed with
+	pub fn codec(&self) -> Codec {
+		match self {
+			Self::OperationLog => Codec::Zstd(9),
+			Self::LibraryExport => Codec::Zstd(3),
+			Self::SyncPayload => Codec::Lz4,
+			Self::ColdData => Codec::Zstd(19),
+		}
+	}
+}
+
+/// Compresses what's written to it, see [`Codec::encoder`]
+pub enum Encoder<W: Write> {
+	None(W),
+	Lz4(lz4_flex::frame::FrameEncoder<W>),
+	Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+	/// finish writes out the end of the compressed data, returning the writer
+	pub fn finish(self) -> Result<W> {
+		Ok(match self {
+			Self::None(writer) => writer,
+			Self::Lz4(encoder) => encoder.finish()?,
+			Self::Zstd(encoder) => encoder.finish()?,
+		})
+	}
+}
+
+impl<W: Write> Write for Encoder<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self {
+			Self::None(writer) => writer.write(buf),
+			Self::Lz4(encoder) => encoder.write(buf),
+			Self::Zstd(encoder) => encoder.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			Self::None(writer) => writer.flush(),
+			Self::Lz4(encoder) => encoder.flush(),
+			Self::Zstd(encoder) => encoder.flush(),
+		}
+	}
+}
+
+/// Decompresses what's read from it, see [`Codec::decoder`]
+pub enum Decoder<R: Read> {
+	None(R),
+	Lz4(lz4_flex::frame::FrameDecoder<R>),
+	Zstd(zstd::Decoder<'static, BufReader<R>>),
+}
+
+impl<R: Read> Decoder<R> {
+	/// framed reads the header written by [`Codec::framed_encoder`] and decompresses the rest with the
+	/// codec it names
+	pub fn framed(mut reader: R) -> Result<Self> {
+		let mut header = [0; 4];
+		reader.read_exact(&mut header).map_err(|e| match e.kind() {
+			io::ErrorKind::UnexpectedEof => Error::UnknownFormat,
+			_ => e.into(),
+		})?;
+		if &header[..3] != MAGIC {
+			return Err(Error::UnknownFormat);
+		}
+
+		Codec::from_id(header[3])?.decoder(reader)
+	}
+}
+
+impl<R: Read> Read for Decoder<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Self::None(reader) => reader.read(buf),
+			Self::Lz4(decoder) => decoder.read(buf),
+			Self::Zstd(decoder) => decoder.read(buf),
+		}
+	}
+}
+
+/// is_framed returns whether `data` starts with the header of [`Codec::framed_encoder`]
+pub fn is_framed(data: &[u8]) -> bool {
+	data.len() >= 4 && data.starts_with(MAGIC)
+}
+
+/// compress compresses `data` with `codec`, with the header [`decompress`] reads the codec from
+pub fn compress(codec: Codec, data: &[u8]) -> Result<Vec<u8>> {
+	let mut encoder = codec.framed_encoder(Vec::with_capacity(data.len() / 2))?;
+	encoder.write_all(data)?;
+	encoder.finish()
+}
+
+/// decompress decompresses data made by [`compress`], whichever codec it was made with
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+	let mut decompressed = Vec::with_capacity(data.len() * 2);
+	Decoder::framed(data)?.read_to_end(&mut decompressed)?;
+	Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trips_with_every_codec() {
+		let data = "id=1 kind=file_path name=photo.jpg\n".repeat(1000);
+
+		for codec in [Codec::None, Codec::Lz4, Codec::Zstd(3)] {
+			let compressed = compress(codec, data.as_bytes()).unwrap();
+			assert!(is_framed(&compressed));
+			if codec != Codec::None {
+				assert!(compressed.len() < data.len() / 4);
+			}
+
+			assert_eq!(decompress(&compressed).unwrap(), data.as_bytes());
+		}
+	}
+
+	#[test]
+	fn rejects_unknown_data() {
+		assert!(matches!(
+			decompress(b"plain text"),
+			Err(Error::UnknownFormat)
+		));
+		assert!(matches!(
+			decompress(b"sdc\x09data"),
+			Err(Error::UnknownCodec(9))
+		));
+	}
+}
@@ -4,16 +4,19 @@ use crate::{
 	video_frame::VideoFrame,
 };
 
-use std::path::Path;
+use std::{path::Path, time::Duration};
+use tokio::task::spawn_blocking;
 
 mod error;
 mod film_strip;
 mod movie_decoder;
+mod strip;
 mod thumbnailer;
 mod utils;
 mod video_frame;
 
 pub use error::ThumbnailerError;
+pub use strip::StripFrame;
 pub use thumbnailer::{Thumbnailer, ThumbnailerBuilder};
 
 /// Helper function to generate a thumbnail file from a video file with reasonable defaults
@@ -46,6 +49,34 @@ pub async fn to_webp_bytes(
 		.await
 }
 
+/// Helper function to get how long a video file is
+pub async fn video_duration(
+	video_file_path: impl AsRef<Path>,
+) -> Result<Duration, ThumbnailerError> {
+	let video_file_path = video_file_path.as_ref().to_path_buf();
+
+	spawn_blocking(move || -> Result<Duration, ThumbnailerError> {
+		Ok(MovieDecoder::new(video_file_path, false)?.get_video_duration())
+	})
+	.await?
+}
+
+/// Helper function to generate a preview strip of `frames` thumbnails from a video file with
+/// reasonable defaults, see [`Thumbnailer::process_to_strip`]
+pub async fn to_strip(
+	video_file_path: impl AsRef<Path>,
+	frames: usize,
+	size: u32,
+	quality: f32,
+) -> Result<Vec<StripFrame>, ThumbnailerError> {
+	ThumbnailerBuilder::new()
+		.size(size)
+		.quality(quality)?
+		.build()
+		.process_to_strip(video_file_path, frames, 0.15)
+		.await
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -0,0 +1,119 @@
+use crate::VideoFrame;
+
+use std::time::Duration;
+
+/// Side of the grid of brightness averages frames are compared with
+const SIGNATURE_SIDE: usize = 8;
+
+/// A frame of a video's preview strip
+#[derive(Debug, Clone)]
+pub struct StripFrame {
+	/// where in the video the frame was taken from
+	pub timestamp: Duration,
+	/// whether the frame was picked because the scene changed there, instead of at a fixed interval
+	pub scene_change: bool,
+	/// the frame, encoded as webp
+	pub webp: Vec<u8>,
+}
+
+/// candidate_timestamps spreads `count` timestamps, in seconds, evenly over a video, leaving out its
+/// very start and end which are often black
+pub(crate) fn candidate_timestamps(duration: Duration, count: usize) -> Vec<i64> {
+	let duration = duration.as_secs() as i64;
+	let mut timestamps = (1..=count as i64)
+		.map(|i| i * duration / (count as i64 + 1))
+		.collect::<Vec<_>>();
+	timestamps.dedup();
+	timestamps
+}
+
+/// signature reduces a frame to the average brightness of a small grid over it, which is enough to tell
+/// scenes apart while ignoring noise and small movements
+pub(crate) fn signature(frame: &VideoFrame) -> Vec<u8> {
+	let (width, height) = (frame.width as usize, frame.height as usize);
+	let mut sums = vec![(0u64, 0u64); SIGNATURE_SIDE * SIGNATURE_SIDE];
+
+	for y in 0..height {
+		let row = &frame.data[y * frame.line_size as usize..];
+		for x in 0..width {
+			let pixel = &row[x * 3..x * 3 + 3];
+			let brightness =
+				(pixel[0] as u64 * 299 + pixel[1] as u64 * 587 + pixel[2] as u64 * 114) / 1000;
+			let cell = &mut sums
+				[(y * SIGNATURE_SIDE / height) * SIGNATURE_SIDE + x * SIGNATURE_SIDE / width];
+			cell.0 += brightness;
+			cell.1 += 1;
+		}
+	}
+
+	sums.into_iter()
+		.map(|(sum, count)| if count == 0 { 0 } else { (sum / count) as u8 })
+		.collect()
+}
+
+/// difference tells how far apart two signatures are, from 0.0 for the same picture to 1.0
+pub(crate) fn difference(a: &[u8], b: &[u8]) -> f32 {
+	if a.is_empty() || a.len() != b.len() {
+		return 0.0;
+	}
+
+	let total = a
+		.iter()
+		.zip(b)
+		.map(|(a, b)| (*a as i32 - *b as i32).unsigned_abs())
+		.sum::<u32>();
+
+	total as f32 / (a.len() as f32 * 255.0)
+}
+
+/// pick_frames splits the candidates in `frames` equal parts and picks one from each: the one where the
+/// scene changed the most if it changed by more than `threshold`, or the one in the middle otherwise.
+/// `differences` has how much each candidate differs from the one before it. Returns the index of each
+/// picked candidate and whether it's a scene change.
+pub(crate) fn pick_frames(
+	differences: &[f32],
+	frames: usize,
+	threshold: f32,
+) -> Vec<(usize, bool)> {
+	let count = differences.len();
+	let frames = frames.min(count);
+
+	(0..frames)
+		.map(|part| {
+			let (start, end) = (part * count / frames, (part + 1) * count / frames);
+
+			differences[start..end]
+				.iter()
+				.enumerate()
+				.filter(|(_, difference)| **difference > threshold)
+				.max_by(|(_, a), (_, b)| a.total_cmp(b))
+				.map(|(i, _)| (start + i, true))
+				.unwrap_or(((start + end) / 2, false))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn picks_scene_changes_and_fills_in_with_intervals() {
+		let differences = [0.0, 0.01, 0.02, 0.4, 0.01, 0.02, 0.01, 0.03, 0.02];
+
+		assert_eq!(
+			pick_frames(&differences, 3, 0.15),
+			vec![(1, false), (3, true), (7, false)]
+		);
+		assert_eq!(pick_frames(&differences[..2], 3, 0.15).len(), 2);
+	}
+
+	#[test]
+	fn spreads_timestamps_over_the_video() {
+		assert_eq!(
+			candidate_timestamps(Duration::from_secs(100), 4),
+			vec![20, 40, 60, 80]
+		);
+		assert_eq!(candidate_timestamps(Duration::from_secs(2), 4), vec![0, 1]);
+	}
+}
@@ -1,6 +1,10 @@
-use crate::{film_strip_filter, MovieDecoder, ThumbnailSize, ThumbnailerError, VideoFrame};
+use crate::{
+	film_strip_filter,
+	strip::{candidate_timestamps, difference, pick_frames, signature, StripFrame},
+	MovieDecoder, ThumbnailSize, ThumbnailerError, VideoFrame,
+};
 
-use std::{ops::Deref, path::Path};
+use std::{ops::Deref, path::Path, time::Duration};
 use tokio::{fs, task::spawn_blocking};
 use webp::Encoder;
 
@@ -71,8 +75,81 @@ impl Thumbnailer {
 		})
 		.await?
 	}
+
+	/// Processes an video input file into a strip of `frames` webp encoded thumbnails spread along it.
+	/// Frames are taken where the scene changes by more than `scene_threshold` (between 0.0 and 1.0),
+	/// and at fixed intervals where it doesn't.
+	pub async fn process_to_strip(
+		&self,
+		video_file_path: impl AsRef<Path>,
+		frames: usize,
+		scene_threshold: f32,
+	) -> Result<Vec<StripFrame>, ThumbnailerError> {
+		let video_file_path = video_file_path.as_ref().to_path_buf();
+		let size = self.builder.size;
+		let maintain_aspect_ratio = self.builder.maintain_aspect_ratio;
+		let quality = self.builder.quality;
+
+		spawn_blocking(move || -> Result<Vec<StripFrame>, ThumbnailerError> {
+			// Embedded cover art is a single picture, so the strip always comes from the video stream
+			let mut decoder = MovieDecoder::new(video_file_path, false)?;
+			decoder.decode_video_frame()?;
+
+			let mut candidates = Vec::new();
+			for timestamp in candidate_timestamps(
+				decoder.get_video_duration(),
+				frames * CANDIDATES_PER_STRIP_FRAME,
+			) {
+				// A frame that can't be reached is left out, the strip is only a preview
+				if decoder.seek(timestamp).is_err() {
+					continue;
+				}
+
+				let mut video_frame = VideoFrame::default();
+				decoder.get_scaled_video_frame(
+					Some(size),
+					maintain_aspect_ratio,
+					&mut video_frame,
+				)?;
+				let signature = signature(&video_frame);
+				candidates.push((timestamp, video_frame, signature));
+			}
+
+			let differences = candidates
+				.iter()
+				.enumerate()
+				.map(|(i, (_, _, signature))| match i {
+					0 => 0.0,
+					i => difference(&candidates[i - 1].2, signature),
+				})
+				.collect::<Vec<_>>();
+
+			Ok(pick_frames(&differences, frames, scene_threshold)
+				.into_iter()
+				.map(|(i, scene_change)| {
+					let (timestamp, video_frame, _) = &candidates[i];
+					StripFrame {
+						timestamp: Duration::from_secs(*timestamp as u64),
+						scene_change,
+						webp: Encoder::from_rgb(
+							&video_frame.data,
+							video_frame.width,
+							video_frame.height,
+						)
+						.encode(quality)
+						.deref()
+						.to_vec(),
+					}
+				})
+				.collect())
+		})
+		.await?
+	}
 }
 
+/// How many frames are looked at for each frame of a strip, to find where scenes change
+const CANDIDATES_PER_STRIP_FRAME: usize = 4;
+
 /// `ThumbnailerBuilder` struct holds data to build a `Thumbnailer` struct, exposing many methods
 /// to configure how a thumbnail must be generated.
 #[derive(Debug, Clone)]
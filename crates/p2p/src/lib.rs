@@ -13,5 +13,5 @@ pub use utils::*;
 
 /// We reexport some types from `quinn` to avoid the user needing to add `quinn` and keep its version in sync with the p2p library.
 pub mod quinn {
-	pub use quinn::{RecvStream, SendStream};
+	pub use quinn::{RecvStream, SendStream, WriteError};
 }
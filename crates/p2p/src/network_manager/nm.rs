@@ -8,7 +8,7 @@ use std::{
 use bip39::{Language, Mnemonic};
 use dashmap::{DashMap, DashSet};
 use futures_util::future::join_all;
-use quinn::{Chunk, Endpoint, NewConnection, RecvStream, SendStream, ServerConfig};
+use quinn::{Chunk, Endpoint, NewConnection, RecvStream, SendStream, ServerConfig, VarInt};
 use rustls::{Certificate, PrivateKey};
 use sd_tunnel_utils::{quic, write_value, PeerId, UtilError};
 use spake2::{Ed25519Group, Password, Spake2};
@@ -167,6 +167,17 @@ impl<TP2PManager: P2PManager> NetworkManager<TP2PManager> {
 		}
 	}
 
+	/// removes a peer from the known peers list, so it has to pair again before it can connect.
+	pub fn remove_known_peer(&self, peer_id: &PeerId) {
+		debug!("Removing '{:?}' from the known peers", peer_id);
+		self.known_peers.remove(peer_id);
+
+		if let Some((_, peer)) = self.connected_peers.remove(peer_id) {
+			peer.conn.close(VarInt::from_u32(0), b"UNPAIRED");
+			self.manager.peer_disconnected(self, peer_id.clone());
+		}
+	}
+
 	/// send a single message to a peer and await a single response. This is good for quick one-off communications but any longer term communication should be done with a stream.
 	/// TODO: Error type
 	pub async fn send_to(&self, peer_id: PeerId, data: &[u8]) -> Result<Chunk, NMError> {
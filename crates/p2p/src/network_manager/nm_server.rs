@@ -10,7 +10,7 @@ use tracing::{debug, error, info, warn};
 
 use crate::{
 	ConnectionEstablishmentPayload, ConnectionType, NetworkManager, P2PManager,
-	PairingParticipantType, PairingPayload, Peer,
+	PairingParticipantType, PairingPayload, Peer, PeerMetadata,
 };
 
 impl<TP2PManager: P2PManager> NetworkManager<TP2PManager> {
@@ -105,7 +105,35 @@ impl<TP2PManager: P2PManager> NetworkManager<TP2PManager> {
 					match payload {
 						ConnectionEstablishmentPayload::ConnectionRequest => {
 							debug!("ConnectionRequest from peer '{}'", peer_id);
-							// TODO: Only allow peers we trust to get pass this point
+
+							// Only peers we have paired with can connect without pairing first
+							if !self.known_peers.contains(&peer_id) {
+								warn!("Rejecting connection from unknown peer '{}'", peer_id);
+								connection.close(VarInt::from_u32(0), b"UNKNOWN_PEER");
+								return;
+							}
+
+							let metadata = match self.get_discovered_peer(&peer_id) {
+								Some(candidate) => candidate.metadata,
+								None => PeerMetadata::from_hashmap(&peer_id, &Default::default()),
+							};
+
+							match Peer::new(
+								ConnectionType::Server,
+								peer_id.clone(),
+								connection,
+								metadata,
+								self,
+							)
+							.await
+							{
+								Ok(peer) => {
+									tokio::spawn(peer.handler(bi_streams));
+								}
+								Err(err) => {
+									error!("p2p warning: error creating peer: {:?}", err);
+								}
+							}
 						}
 						ConnectionEstablishmentPayload::PairingRequest {
 							pake_msg,